@@ -1,72 +1,306 @@
-use log::{error, info};
+use log::{error, info, warn};
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
+use simple_error::{bail, require_with, try_with};
+use stage1_interface::{DeviceAction, DeviceState, HotplugAction};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::mpsc::sync_channel;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::devices::control::control_thread;
 use crate::devices::use_ioregionfd;
-use crate::devices::DeviceSet;
+use crate::devices::virtio::block::{CacheMode, FaultOptions, ImageFormat};
+use crate::devices::virtio::console::{
+    window_size, RawTerminal, DETACH_KEY, FORWARD_INPUT, RAW_INPUT,
+};
+use crate::devices::virtio::p9::ShareOptions;
+use crate::devices::virtio::vsock::publish::{self, publish_thread, Publish};
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::devices::{Console, DeviceSet, IrqAffinity};
+use crate::hotplug::{alloc_hotplug_memory, HotplugOptions};
+use crate::kvm::memslots;
+use crate::metrics::{self, metrics_thread};
 use crate::result::Result;
 use crate::stage1::Stage1;
-use crate::{kvm, signal_handler};
+use crate::symbolizer::SymbolSource;
+use crate::{compat, gc, kvm, signal_handler};
 
 pub struct AttachOptions {
     pub pid: Pid,
     pub command: Vec<String>,
     pub backing: PathBuf,
+    /// Raw if not set, the format is never probed
+    pub backing_format: Option<ImageFormat>,
+    /// Guest cannot write to the block device
+    pub read_only: bool,
+    pub cache: CacheMode,
+    /// Faults injected into the block device
+    pub faults: Option<FaultOptions>,
+    pub hotplug: Option<HotplugOptions>,
+    pub vsock: Option<VsockOptions>,
+    pub share: Option<ShareOptions>,
+    /// Devices that get their own interrupt, pinned to a guest cpu
+    pub irq_affinity: Vec<IrqAffinity>,
+    /// `KEY=VALUE` pairs that override the environment the command inherits from the container
+    pub environment: Vec<String>,
+    /// Directory in the container the command is started in instead of `/`
+    pub cwd: Option<String>,
+    /// Pass stdin to the command
+    pub interactive: bool,
+    /// Put the terminal into raw mode and pass its size on to the guest. Defaults to whether
+    /// stdin is a terminal.
+    pub tty: Option<bool>,
+    /// Serve prometheus metrics of the session here
+    pub metrics_addr: Option<SocketAddr>,
+    /// Host ports forwarded over `vsock` to tcp ports in the network namespace of the command
+    pub publish: Vec<Publish>,
+    /// Used by stage1 if the exported kernel symbols cannot be found in guest memory
+    pub symbols: SymbolSource,
+}
+
+/// How often the size of the terminal is checked
+const WINDOW_SIZE_POLL: Duration = Duration::from_millis(250);
+
+fn check_environment(environment: &[String]) -> Result<()> {
+    for var in environment {
+        match var.find('=') {
+            Some(0) | None => bail!("invalid environment variable {}, expected KEY=VALUE", var),
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Waits until vmsh is stopped and passes changes of the terminal size on to the guest
+/// meanwhile.
+fn forward_window_size(receiver: &Receiver<()>, console: &Mutex<Console>) {
+    let mut notified = None;
+    while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(WINDOW_SIZE_POLL) {
+        let size = window_size(libc::STDOUT_FILENO);
+        if size.is_none() || size == notified {
+            continue;
+        }
+        let mut console = match console.lock() {
+            Ok(console) => console,
+            Err(_) => continue,
+        };
+        match console.resize(size.unwrap_or_default()) {
+            Ok(true) => notified = size,
+            Ok(false) => {}
+            Err(e) => warn!("cannot resize guest terminal: {:?}", e),
+        }
+    }
 }
 
 pub fn attach(opts: &AttachOptions) -> Result<()> {
+    check_environment(&opts.environment)?;
+    // the devices and stage1 are written into guest memory
+    memslots::check_writable()?;
+    let metrics_listener = match opts.metrics_addr {
+        Some(addr) => Some(metrics::bind(addr)?),
+        None => None,
+    };
+    let publish_listeners = match (&opts.vsock, opts.publish.is_empty()) {
+        (_, true) => vec![],
+        (Some(_), false) => publish::bind(&opts.publish)?,
+        (None, false) => bail!("publishing ports requires a vsock device"),
+    };
+    let stdin_is_tty = nix::unistd::isatty(libc::STDIN_FILENO).unwrap_or(false);
+    let tty = opts.tty.unwrap_or(stdin_is_tty);
+    if tty && !stdin_is_tty {
+        bail!("a terminal was requested, but stdin is not a terminal");
+    }
+    // read by the console device once the guest activates it
+    FORWARD_INPUT.store(opts.interactive, Ordering::Release);
+    RAW_INPUT.store(tty && opts.interactive, Ordering::Release);
     info!("attaching");
+    metrics::start_phases();
 
     let (sender, receiver) = sync_channel(1);
 
     signal_handler::setup(&sender)?;
 
+    // removed when we detach cleanly, otherwise `vmsh gc` cleans up after us
+    let _session = match gc::Session::create(opts.pid) {
+        Ok(session) => Some(session),
+        Err(e) => {
+            warn!("{}, vmsh gc will not know about this session", e);
+            None
+        }
+    };
+
     let vm = Arc::new(try_with!(
         kvm::hypervisor::get_hypervisor(opts.pid),
         "cannot get vms for process {}",
         opts.pid
     ));
     vm.stop()?;
+    metrics::phase_done("stop");
+
+    let compat_record = compat::probe(&vm);
+    if let Err(e) = compat::check(opts.pid, &compat_record) {
+        warn!("cannot compare with earlier attaches: {}", e);
+    }
 
     let mut allocator = try_with!(
         kvm::PhysMemAllocator::new(Arc::clone(&vm)),
         "cannot create allocator"
     );
 
+    let hotplug = match &opts.hotplug {
+        Some(hotplug) => Some(alloc_hotplug_memory(
+            &vm,
+            &mut allocator,
+            hotplug,
+            HotplugAction::Attach,
+        )?),
+        None => None,
+    };
+
     let devices = try_with!(
-        DeviceSet::new(&vm, &mut allocator, &opts.backing),
+        DeviceSet::new(
+            &vm,
+            &mut allocator,
+            &opts.backing,
+            opts.backing_format,
+            opts.read_only,
+            opts.cache,
+            opts.faults.as_ref(),
+            opts.vsock.as_ref(),
+            opts.share.as_ref(),
+            &opts.irq_affinity
+        ),
         "cannot create devices"
     );
+    metrics::phase_done("devices");
 
     if receiver.recv_timeout(Duration::from_millis(0)).is_ok() {
         return Ok(());
     }
 
-    let addrs = devices.mmio_addrs()?;
+    let mut command = opts.command.clone();
+    if let Some(share) = &opts.share {
+        // stage2 mounts the share before it runs the command
+        let dir = share.guest_dir.to_string_lossy().into_owned();
+        command.splice(1..1, vec![String::from("--share"), dir]);
+    }
+    if let Some(cwd) = &opts.cwd {
+        command.splice(1..1, vec![String::from("--cwd"), cwd.clone()]);
+    }
+    for p in &opts.publish {
+        // stage2 listens on the vsock port before it enters the network namespace
+        command.splice(
+            1..1,
+            vec![String::from("--publish"), p.guest_port.to_string()],
+        );
+    }
+
+    let stage1_devices = devices.stage1_devices()?;
     let mut stage1 = try_with!(
-        Stage1::new(allocator, &opts.command, addrs),
+        Stage1::new(
+            allocator,
+            &command,
+            &opts.environment,
+            stage1_devices,
+            DeviceAction::Attach,
+            hotplug.as_ref().map(|(_, region)| region),
+            opts.symbols.fallbacks()
+        ),
         "failed to initialize stage1"
     );
+    let hotplug = hotplug.map(|(mem, _)| (mem, stage1.hotplug_status.take()));
     let driver_status = require_with!(stage1.driver_status.take(), "no driver status set");
     let stage1_thread = try_with!(
         stage1.spawn(Arc::clone(&vm), driver_status.clone(), &sender),
         "failed to spawn stage1"
     );
+    metrics::phase_done("stage1");
     let device_status = require_with!(stage1.device_status.take(), "device status is not set");
+    let context = devices.context();
+    let blkdev = Arc::clone(&context.blkdev);
+    let console = Arc::clone(&context.console);
     let (threads, driver_notifier) = try_with!(
         devices.start(&vm, device_status, driver_status, &sender),
         "failed to start devices"
     );
+    metrics::phase_done("device_ready");
+    // devices added at runtime need the other device threads, so it is stopped first
+    let control = match control_thread(&vm, context, &sender, opts.symbols.clone()) {
+        Ok(thread) => Some(thread),
+        Err(e) => {
+            warn!("{}, vmsh device will not be able to add devices", e);
+            None
+        }
+    };
+
+    let metrics = match metrics_listener.map(|l| metrics_thread(l, opts.pid, &sender)) {
+        Some(Ok(thread)) => Some(thread),
+        Some(Err(e)) => {
+            warn!("{}, no metrics are served", e);
+            None
+        }
+        None => None,
+    };
+
+    let publish = match (&opts.vsock, publish_listeners.is_empty()) {
+        (Some(vsock), false) => {
+            match publish_thread(publish_listeners, vsock.uds_path.clone(), &sender) {
+                Ok(thread) => Some(thread),
+                Err(e) => {
+                    warn!("{}, no ports are published", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
 
     info!("blkdev queue ready.");
+    if let Err(e) = compat::remember(opts.pid, &compat_record) {
+        warn!("{}, this attach will not be compared with later ones", e);
+    }
     drop(sender);
 
+    // restored when we return
+    let _raw_terminal = if tty && opts.interactive {
+        info!(
+            "interactive session, press Ctrl-{} to detach",
+            (DETACH_KEY + b'@') as char
+        );
+        Some(try_with!(
+            RawTerminal::new(libc::STDIN_FILENO),
+            "cannot switch terminal to raw mode"
+        ))
+    } else {
+        None
+    };
+
     // termination wait or vmsh_stop()
-    let _ = receiver.recv();
+    if tty {
+        forward_window_size(&receiver, &console);
+    } else {
+        let _ = receiver.recv();
+    }
+    if let Some(control) = control {
+        control.shutdown();
+        if let Err(e) = control.join().and_then(|(res, _)| res) {
+            error!("{}", e);
+        }
+    }
+    if let Some(metrics) = metrics {
+        metrics.shutdown();
+        if let Err(e) = metrics.join().and_then(|(res, _)| res) {
+            error!("{}", e);
+        }
+    }
+    if let Some(publish) = publish {
+        publish.shutdown();
+        if let Err(e) = publish.join().and_then(|(res, _)| res) {
+            error!("{}", e);
+        }
+    }
     stage1_thread.shutdown();
     if let Err(e) = stage1_thread.join() {
         error!("{}", e);
@@ -88,6 +322,12 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
             ctx
         })
         .collect::<Vec<_>>();
+    // the guest cannot flush the image anymore
+    if let Ok(blkdev) = blkdev.lock() {
+        if let Err(e) = blkdev.sync() {
+            error!("cannot sync {}: {:?}", opts.backing.display(), e);
+        }
+    }
 
     // MMIO exit handler thread took over pthread control
     // We need ptrace the process again before we can finish.
@@ -96,6 +336,19 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
         vm.finish_thread_transfer()?;
     }
     // now that we got the tracer back, we can cleanup physical memory and file descriptors
+    if let Some((mem, status)) = hotplug {
+        let removed = status.map(|s| s.check(&vm));
+        if let Some(Ok(DeviceState::Terminating)) = removed {
+            drop(mem);
+        } else {
+            // removing the memslot of memory the guest still uses would crash it
+            warn!(
+                "guest did not remove the hotplugged memory, leave it at {:#x}",
+                mem.guest_phys_addr.value
+            );
+            std::mem::forget(mem);
+        }
+    }
     drop(stage1);
     drop(contexts);
     vm.resume()?;
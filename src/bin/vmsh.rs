@@ -1,17 +1,78 @@
 use log::*;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use clap::{
-    crate_authors, crate_version, value_t, value_t_or_exit, values_t, App, AppSettings, Arg,
-    ArgMatches, SubCommand,
+    crate_authors, crate_version, value_t, value_t_or_exit, values_t, values_t_or_exit, App,
+    AppSettings, Arg, ArgMatches, SubCommand,
 };
 use nix::unistd::Pid;
 
 use vmsh::attach::{self, AttachOptions};
-use vmsh::coredump::CoredumpOptions;
-use vmsh::devices::USE_IOREGIONFD;
+use vmsh::breakpoint::{self, BreakOptions, BreakpointKind};
+use vmsh::bundle::{self, BundleOptions};
+use vmsh::cert_scan::{self, CertScanOptions};
+use vmsh::compat::{self, CompatOptions};
+use vmsh::containers::{self, ContainersOptions};
+use vmsh::coredump::{CoreFormat, CoredumpOptions};
+use vmsh::cpu_report::{self, CpuReportOptions};
+use vmsh::crashdump::{self, CrashdumpOptions};
+use vmsh::daemon::{self, CtlOptions, CtlRequest, DaemonOptions, DEFAULT_SOCKET};
+use vmsh::devices::control::{self, AddBlkOptions, RemoveOptions};
+use vmsh::devices::virtio::block::host_device::is_block_device;
+use vmsh::devices::virtio::block::{CacheMode, FaultOptions, ImageFormat};
+use vmsh::devices::virtio::console::{self, STOP_ON_SESSION_END};
+use vmsh::devices::virtio::p9::ShareOptions;
+use vmsh::devices::virtio::vsock::publish::Publish;
+use vmsh::devices::virtio::vsock::VsockOptions;
+use vmsh::devices::{IrqAffinity, USE_IOREGIONFD};
+use vmsh::exec::{self, ExecOptions};
+use vmsh::expect::{self, ExpectOptions};
+use vmsh::fleet::{self, FleetOptions};
+use vmsh::fscheck::{self, FsCheckOptions};
+use vmsh::ftrace_dump::{self, FtraceDumpOptions};
+use vmsh::gc::{self, GcOptions};
+use vmsh::gdbstub::{self, GdbServerOptions};
+use vmsh::guest_access::GuestTarget;
+use vmsh::guest_trace::{self, GuestTraceOptions};
+use vmsh::hotplug::{self, HotplugOptions, MemOptions};
 use vmsh::inspect::InspectOptions;
+use vmsh::irq::{self, IrqOptions};
+use vmsh::irqstorm::{self, IrqStormOptions};
+use vmsh::kvm::memslots::{set_memslot_source, MemslotSource};
+use vmsh::kvmclock::{self, ClockOptions};
+use vmsh::lockstat::{self, LockStatOptions};
+use vmsh::manifest;
+use vmsh::memreport::{self, MemreportOptions};
+use vmsh::memwatch::{self, MemWatchOptions};
+use vmsh::net_check::{self, NetCheckOptions};
+use vmsh::pmu::{self, PmuOptions, DEFAULT_EVENTS};
+use vmsh::poke::{self, PeekOptions, PokeOptions};
+use vmsh::process_dump::{self, ProcessDumpOptions};
+use vmsh::profile::{self, ProfileOptions};
+use vmsh::ps::{self, PsOptions};
+use vmsh::remote::agent::{self, AgentOptions, Listen};
+use vmsh::remote::client::{self, RemoteOptions};
+use vmsh::remote::protocol;
+use vmsh::rewind::{self, RecordOptions, RewindInspectOptions};
+use vmsh::route::{self, ArpOptions, RouteOptions};
+use vmsh::sched_diag::{self, SchedDiagOptions};
+use vmsh::screenshot::{self, Framebuffer, ScreenshotOptions};
+use vmsh::scrub::ScrubOptions;
+use vmsh::security_audit::{self, SecurityAuditOptions};
+use vmsh::selftest;
+use vmsh::snapshot::{self, SnapshotOptions};
+use vmsh::stats::{self, StatsOptions};
+use vmsh::step::{self, StepOptions};
+use vmsh::swap::{self, SwapOptions};
+use vmsh::symbolizer::SymbolSource;
+use vmsh::vcat::{self, VcatOptions};
+use vmsh::vcpu_pin::{self, VcpuPinOptions};
+use vmsh::virtio_ls::{self, VirtioLsOptions};
+use vmsh::vmi::profiles::DEFAULT_PROFILE_DIR;
+use vmsh::watchdog::{self, Action, WatchOptions};
 use vmsh::{coredump, inspect};
 
 fn pid_arg(index: u64) -> Arg<'static, 'static> {
@@ -21,9 +82,21 @@ fn pid_arg(index: u64) -> Arg<'static, 'static> {
         .index(index)
 }
 
+/// Like `pid_arg`, but the pid can be replaced by `--core`.
+fn target_args(index: u64) -> [Arg<'static, 'static>; 2] {
+    [
+        pid_arg(index).required_unless("core"),
+        Arg::with_name("core")
+            .long("core")
+            .takes_value(true)
+            .conflicts_with("pid")
+            .help("Operate on a coredump written by `vmsh coredump` instead of a running VM"),
+    ]
+}
+
 fn command_args(index: u64) -> Arg<'static, 'static> {
     Arg::with_name("command")
-        .help("Command to run in the VM")
+        .help("Command to run in the VM. vmsh exits with its exit code once it finishes")
         .multiple(true)
         .required(false)
         .index(index)
@@ -33,9 +106,126 @@ fn parse_pid_arg(args: &ArgMatches) -> Pid {
     Pid::from_raw(value_t_or_exit!(args, "pid", i32))
 }
 
+fn parse_target_args(args: &ArgMatches) -> GuestTarget {
+    match args.value_of("core") {
+        Some(path) => GuestTarget::Core(PathBuf::from(path)),
+        None => GuestTarget::Pid(parse_pid_arg(args)),
+    }
+}
+
+fn cache_arg() -> Arg<'static, 'static> {
+    Arg::with_name("cache")
+        .long("cache")
+        .takes_value(true)
+        .possible_values(&["none", "writeback", "unsafe"])
+        .default_value("none")
+        .help("none: writes of the guest are on disk when they complete. writeback: writes go to the page cache of the host, until the guest flushes. unsafe: flushes of the guest are ignored, data is lost if the host crashes")
+}
+
+fn parse_cache_arg(args: &ArgMatches) -> CacheMode {
+    // checked by possible_values
+    args.value_of("cache")
+        .and_then(CacheMode::from_name)
+        .unwrap_or_default()
+}
+
+fn symbols_arg() -> Arg<'static, 'static> {
+    Arg::with_name("symbols")
+        .long("symbols")
+        .takes_value(true)
+        .value_name("SOURCE")
+        .help("Kernel symbols: ksymtab (exported symbols in guest memory), system-map:PATH, vmlinux:PATH, kallsyms:PATH or none")
+}
+
+/// None if `--symbols` is not given.
+fn parse_symbols_arg(args: &ArgMatches) -> Option<SymbolSource> {
+    let arg = args.value_of("symbols")?;
+    match SymbolSource::parse(arg) {
+        Ok(source) => Some(source),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn max_host_pressure_arg() -> Arg<'static, 'static> {
+    Arg::with_name("max-host-pressure")
+        .long("max-host-pressure")
+        .takes_value(true)
+        .value_name("PERCENT")
+        .help("Pause while host cpu, io or memory pressure (PSI avg10) is above this percentage")
+}
+
+/// None if `--max-host-pressure` is not given.
+fn parse_max_host_pressure(args: &ArgMatches) -> Option<f64> {
+    args.value_of("max-host-pressure")?;
+    match value_t!(args, "max-host-pressure", f64) {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Some(percent),
+        _ => {
+            error!("--max-host-pressure expects a percentage between 0 and 100");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `--all` and `--jobs` for read-only commands that accept multiple pids.
+fn fleet_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("all")
+            .long("all")
+            .help("Run on every KVM process of the host, results are printed as a json array"),
+        Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .takes_value(true)
+            .default_value("4")
+            .help("Number of VMs that are inspected in parallel"),
+    ]
+}
+
+/// None if the command runs on a single VM.
+fn parse_fleet_args(args: &ArgMatches) -> Option<FleetOptions> {
+    let pids = values_t!(args, "pid", i32).unwrap_or_else(|_| vec![]);
+    let all = args.is_present("all");
+    if !all && pids.len() < 2 {
+        return None;
+    }
+    Some(FleetOptions {
+        pids: pids.into_iter().map(Pid::from_raw).collect(),
+        all,
+        jobs: value_t_or_exit!(args, "jobs", usize),
+    })
+}
+
+fn run_fleet(opts: &FleetOptions, f: fn(Pid) -> vmsh::result::Result<String>) {
+    if let Err(err) = fleet::run(opts, f) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
 fn inspect(args: &ArgMatches) {
+    if let Some(fleet_opts) = parse_fleet_args(args) {
+        if ["sched", "msrs", "cpuid", "backing"]
+            .iter()
+            .any(|arg| args.is_present(arg))
+        {
+            error!("--sched, --msrs, --cpuid and --backing only work with a single VM");
+            std::process::exit(1);
+        }
+        run_fleet(&fleet_opts, |pid| {
+            inspect::summary_pid(pid).map(|s| s.to_json())
+        });
+        return;
+    }
     let opts = InspectOptions {
-        pid: parse_pid_arg(args),
+        target: parse_target_args(args),
+        sched: args.is_present("sched"),
+        json: args.value_of("output") == Some("json"),
+        msrs: args.is_present("msrs"),
+        cpuid: args.is_present("cpuid"),
+        backing: args.is_present("backing"),
     };
 
     if let Err(err) = inspect::inspect(&opts) {
@@ -44,105 +234,2508 @@ fn inspect(args: &ArgMatches) {
     };
 }
 
-fn attach(args: &ArgMatches) {
-    let mut command = values_t!(args, "command", String).unwrap_or_else(|_| vec![]);
-    let stage2_path = value_t_or_exit!(args, "stage2-path", String);
-    command.insert(0, stage2_path);
+fn attach(args: &ArgMatches) {
+    let mut command = values_t!(args, "command", String).unwrap_or_else(|_| vec![]);
+    let stage2_path = value_t_or_exit!(args, "stage2-path", String);
+    command.insert(0, stage2_path);
+
+    let backing =
+        PathBuf::from(value_t!(args, "backing-file", String).unwrap_or_else(|e| e.exit()));
+    // a partition of the host is easily destroyed by a guest that also mounts it elsewhere
+    let read_only = if args.is_present("read-write") {
+        false
+    } else {
+        args.is_present("read-only") || is_block_device(&backing)
+    };
+
+    let opts = AttachOptions {
+        pid: parse_pid_arg(args),
+        command,
+        backing,
+        backing_format: args.value_of("format").and_then(ImageFormat::from_name),
+        read_only,
+        cache: parse_cache_arg(args),
+        faults: args.value_of("inject-faults").map(|faults| {
+            FaultOptions::parse(faults).unwrap_or_else(|e| {
+                error!("invalid --inject-faults: {}", e);
+                std::process::exit(1);
+            })
+        }),
+        hotplug: args.value_of("hotplug-memory").map(|size| HotplugOptions {
+            size: parse_size(size) as usize,
+            node: value_t_or_exit!(args, "hotplug-node", i32),
+        }),
+        vsock: args.value_of("vsock").map(|path| VsockOptions {
+            uds_path: PathBuf::from(path),
+            guest_cid: value_t_or_exit!(args, "vsock-cid", u64),
+        }),
+        share: args.value_of("share").map(|share| {
+            ShareOptions::parse(share).unwrap_or_else(|e| {
+                error!("invalid --share: {}", e);
+                std::process::exit(1);
+            })
+        }),
+        irq_affinity: args
+            .values_of("irq-affinity")
+            .into_iter()
+            .flatten()
+            .map(|arg| {
+                IrqAffinity::parse(arg).unwrap_or_else(|e| {
+                    error!("invalid --irq-affinity: {}", e);
+                    std::process::exit(1);
+                })
+            })
+            .collect(),
+        environment: values_t!(args, "env", String).unwrap_or_else(|_| vec![]),
+        cwd: value_t!(args, "cwd", String).ok(),
+        interactive: true,
+        tty: None,
+        metrics_addr: args
+            .value_of("metrics-addr")
+            .map(|_| value_t_or_exit!(args, "metrics-addr", SocketAddr)),
+        publish: args
+            .values_of("publish")
+            .into_iter()
+            .flatten()
+            .map(|arg| {
+                Publish::parse(arg).unwrap_or_else(|e| {
+                    error!("invalid --publish: {}", e);
+                    std::process::exit(1);
+                })
+            })
+            .collect(),
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+    };
+
+    USE_IOREGIONFD.store(
+        value_t_or_exit!(args, "mmio", String) == "ioregionfd",
+        Ordering::Release,
+    );
+    // a command is run for its result, scripts wait for it and check its exit code
+    if opts.command.len() > 1 {
+        STOP_ON_SESSION_END.store(true, Ordering::Release);
+    }
+
+    if let Err(err) = attach::attach(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+    exit_with_command_code();
+}
+
+/// Exit with the exit code of the command stage2 ran in the guest, if it finished.
+fn exit_with_command_code() {
+    if let Some(code) = console::session_exit_code() {
+        std::process::exit(code);
+    }
+}
+
+fn exec(args: &ArgMatches) {
+    let opts = ExecOptions {
+        pid: parse_pid_arg(args),
+        command: values_t!(args, "command", String).unwrap_or_else(|_| vec![]),
+        interactive: args.is_present("interactive"),
+        tty: args.is_present("tty"),
+        environment: values_t!(args, "env", String).unwrap_or_else(|_| vec![]),
+        cwd: value_t!(args, "cwd", String).ok(),
+        stage2_path: value_t_or_exit!(args, "stage2-path", String),
+    };
+
+    if let Err(err) = exec::exec(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+    exit_with_command_code();
+}
+
+fn coredump(args: &ArgMatches) {
+    if args.is_present("group") {
+        let pids = values_t_or_exit!(args, "group", i32);
+        let dir = value_t_or_exit!(args, "group-dir", PathBuf);
+        let group = pids
+            .into_iter()
+            .map(|pid| {
+                coredump_options(args, Pid::from_raw(pid), dir.join(format!("core.{}", pid)))
+            })
+            .collect();
+        if let Err(err) = coredump::generate_group_coredump(group, &dir) {
+            error!("{}", err);
+            std::process::exit(1);
+        };
+        return;
+    }
+
+    let pid = parse_pid_arg(args);
+    let path =
+        value_t!(args, "PATH", PathBuf).unwrap_or_else(|_| PathBuf::from(format!("core.{}", pid)));
+    let opts = coredump_options(args, pid, path);
+
+    if let Err(err) = coredump::generate_coredump(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn coredump_options(args: &ArgMatches, pid: Pid, path: PathBuf) -> CoredumpOptions {
+    let scrub_list = value_t!(args, "scrub-list", PathBuf).ok();
+    let scrub_known = args.is_present("scrub-known");
+    let scrub = if scrub_list.is_some() || scrub_known {
+        Some(ScrubOptions {
+            list: scrub_list,
+            known_secrets: scrub_known,
+        })
+    } else {
+        None
+    };
+
+    CoredumpOptions {
+        pid,
+        path,
+        skip_swapped: args.is_present("skip-swapped"),
+        dedup_store: value_t!(args, "dedup-store", PathBuf).ok(),
+        encrypt_to: value_t!(args, "encrypt-to", String).ok(),
+        scrub,
+        adaptive: args.is_present("adaptive"),
+        max_host_pressure: parse_max_host_pressure(args),
+        symbols: parse_symbols_arg(args),
+        format: args
+            .value_of("format")
+            .and_then(CoreFormat::from_name)
+            .unwrap_or(CoreFormat::Elf),
+    }
+}
+
+fn crashdump(args: &ArgMatches) {
+    let opts = CrashdumpOptions {
+        pid: parse_pid_arg(args),
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+        timeout: parse_interval(&value_t_or_exit!(args, "timeout", String)),
+        wait_capture: args.is_present("wait-capture"),
+        fallback: value_t!(args, "fallback-coredump", PathBuf).ok(),
+    };
+    if let Err(err) = crashdump::crashdump(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn process_dump(args: &ArgMatches) {
+    let process = value_t_or_exit!(args, "process", i32);
+    let opts = ProcessDumpOptions {
+        target: parse_target_args(args),
+        process,
+        path: value_t!(args, "output", PathBuf)
+            .unwrap_or_else(|_| PathBuf::from(format!("core.{}", process))),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+    };
+
+    if let Err(err) = process_dump::process_dump(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn ps(args: &ArgMatches) {
+    let opts = PsOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+    };
+
+    if let Err(err) = ps::ps(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn virtio_ls(args: &ArgMatches) {
+    let opts = VirtioLsOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+    };
+
+    if let Err(err) = virtio_ls::virtio_ls(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn compat(args: &ArgMatches) {
+    let opts = CompatOptions {
+        pid: parse_pid_arg(args),
+    };
+
+    if let Err(err) = compat::compat(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn containers(args: &ArgMatches) {
+    let opts = ContainersOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+        no_trunc: args.is_present("no-trunc"),
+    };
+
+    if let Err(err) = containers::list_containers(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn swap(args: &ArgMatches) {
+    let opts = SwapOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+        top: value_t_or_exit!(args, "top", usize),
+    };
+
+    if let Err(err) = swap::swap(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn route(args: &ArgMatches) {
+    let opts = RouteOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+        all_tables: args.is_present("all"),
+    };
+
+    if let Err(err) = route::route(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn arp(args: &ArgMatches) {
+    let opts = ArpOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+    };
+
+    if let Err(err) = route::arp(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn ftrace_dump(args: &ArgMatches) {
+    let opts = FtraceDumpOptions {
+        target: parse_target_args(args),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+        cpu: value_t!(args, "cpu", usize).ok(),
+    };
+
+    if let Err(err) = ftrace_dump::ftrace_dump(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn read(args: &ArgMatches) {
+    let opts = || -> vmsh::result::Result<PeekOptions> {
+        Ok(PeekOptions {
+            pid: parse_pid_arg(args),
+            addr: poke::parse_addr(&value_t_or_exit!(args, "ADDR", String))?,
+            len: parse_size(&value_t_or_exit!(args, "LEN", String)) as usize,
+            raw: args.is_present("raw"),
+        })
+    };
+
+    if let Err(err) = opts().and_then(|opts| poke::peek(&opts)) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn write(args: &ArgMatches) {
+    let opts = || -> vmsh::result::Result<PokeOptions> {
+        Ok(PokeOptions {
+            pid: parse_pid_arg(args),
+            addr: poke::parse_addr(&value_t_or_exit!(args, "ADDR", String))?,
+            data: poke::parse_hex_bytes(&value_t_or_exit!(args, "BYTES", String))?,
+            virt: args.is_present("virt"),
+            cr3: match args.value_of("cr3") {
+                Some(cr3) => Some(poke::parse_addr(cr3)? as u64),
+                None => None,
+            },
+        })
+    };
+
+    if let Err(err) = opts().and_then(|opts| poke::poke(&opts)) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn vcat(args: &ArgMatches) {
+    let opts = VcatOptions {
+        pid: parse_pid_arg(args),
+        path: value_t_or_exit!(args, "PATH", String),
+        profile: value_t_or_exit!(args, "profile", PathBuf),
+    };
+
+    if let Err(err) = vcat::vcat(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn expect(args: &ArgMatches) {
+    let command = values_t!(args, "command", String).unwrap_or_else(|_| vec![]);
+    let mut attach_args = vec![];
+    if !command.is_empty() {
+        attach_args.push(String::from("--"));
+        attach_args.extend(command);
+    }
+    let opts = ExpectOptions {
+        pid: parse_pid_arg(args),
+        script: value_t_or_exit!(args, "SCRIPT", PathBuf),
+        attach_args,
+    };
+
+    if let Err(err) = expect::expect(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn irq(args: &ArgMatches) {
+    let opts = IrqOptions {
+        pid: parse_pid_arg(args),
+        gsi: value_t_or_exit!(args, "GSI", u32),
+        level: args.value_of("level").map(|level| level == "high"),
+    };
+
+    if let Err(err) = irq::irq(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn irqstorm(args: &ArgMatches) {
+    let opts = IrqStormOptions {
+        pid: parse_pid_arg(args),
+        interval: parse_interval(&value_t_or_exit!(args, "interval", String)),
+        threshold: value_t_or_exit!(args, "threshold", u64),
+        mask: args
+            .value_of("mask")
+            .map(|_| Duration::from_secs(value_t_or_exit!(args, "mask", u64))),
+    };
+
+    if let Err(err) = irqstorm::irqstorm(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn screenshot(args: &ArgMatches) {
+    let framebuffer = args.value_of("address").map(|addr| {
+        let base = poke::parse_addr(addr).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        let mode = args.value_of("mode").unwrap_or_default();
+        let (width, height) = screenshot::parse_mode(mode).unwrap_or_else(|| {
+            error!("invalid mode '{}', expected WIDTHxHEIGHT", mode);
+            std::process::exit(1);
+        });
+        Framebuffer::xrgb8888(base, width, height)
+    });
+    let opts = ScreenshotOptions {
+        pid: parse_pid_arg(args),
+        path: value_t_or_exit!(args, "PATH", PathBuf),
+        framebuffer,
+    };
+
+    if let Err(err) = screenshot::screenshot(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn snapshot(args: &ArgMatches) {
+    let opts = SnapshotOptions {
+        pid: parse_pid_arg(args),
+        path: value_t_or_exit!(args, "PATH", PathBuf),
+        encrypt_to: value_t!(args, "encrypt-to", String).ok(),
+    };
+
+    if let Err(err) = snapshot::snapshot(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn mem(args: &ArgMatches) {
+    let (action, args) = match args.subcommand() {
+        (action, Some(sub_matches)) => (action, sub_matches),
+        _ => unreachable!(), // because of AppSettings::SubcommandRequiredElseHelp
+    };
+    let opts = MemOptions {
+        pid: parse_pid_arg(args),
+        size: parse_size(&value_t_or_exit!(args, "SIZE", String)) as usize,
+        node: value_t_or_exit!(args, "node", i32),
+    };
+
+    let res = match action {
+        "add" => hotplug::mem_add(&opts),
+        "remove" => hotplug::mem_remove(&opts),
+        _ => unreachable!(),
+    };
+    if let Err(err) = res {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn device(args: &ArgMatches) {
+    let res = match args.subcommand() {
+        ("add-blk", Some(args)) => control::add_blk(&AddBlkOptions {
+            pid: parse_pid_arg(args),
+            image: value_t_or_exit!(args, "IMAGE", PathBuf),
+            format: args.value_of("format").and_then(ImageFormat::from_name),
+            read_only: args.is_present("read-only"),
+            cache: parse_cache_arg(args),
+        }),
+        ("remove", Some(args)) => control::remove(&RemoveOptions {
+            pid: parse_pid_arg(args),
+            name: value_t_or_exit!(args, "NAME", String),
+        }),
+        _ => unreachable!(), // because of AppSettings::SubcommandRequiredElseHelp
+    };
+    if let Err(err) = res {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn restore(args: &ArgMatches) {
+    let opts = SnapshotOptions {
+        pid: parse_pid_arg(args),
+        path: value_t_or_exit!(args, "PATH", PathBuf),
+        encrypt_to: None,
+    };
+
+    if let Err(err) = snapshot::restore(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn record(args: &ArgMatches) {
+    let opts = RecordOptions {
+        pid: parse_pid_arg(args),
+        dir: value_t_or_exit!(args, "DIR", PathBuf),
+        interval: parse_interval(&value_t_or_exit!(args, "interval", String)),
+        checkpoints: value_t_or_exit!(args, "checkpoints", u64),
+    };
+
+    if let Err(err) = rewind::record(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn rewind_inspect(args: &ArgMatches) {
+    let opts = || -> vmsh::result::Result<RewindInspectOptions> {
+        Ok(RewindInspectOptions {
+            dir: value_t_or_exit!(args, "DIR", PathBuf),
+            checkpoint: if args.is_present("checkpoint") {
+                Some(value_t_or_exit!(args, "checkpoint", u64))
+            } else {
+                None
+            },
+            read: match args.value_of("read") {
+                Some(addr) => Some((
+                    poke::parse_addr(addr)?,
+                    parse_size(&value_t_or_exit!(args, "len", String)) as usize,
+                )),
+                None => None,
+            },
+        })
+    };
+
+    if let Err(err) = opts().and_then(|opts| rewind::rewind_inspect(&opts)) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn verify_core(args: &ArgMatches) {
+    let path = value_t_or_exit!(args, "PATH", PathBuf);
+    let manifest_path =
+        value_t!(args, "manifest", PathBuf).unwrap_or_else(|_| manifest::manifest_path(&path));
+
+    match manifest::verify(&path, &manifest_path) {
+        Ok(problems) if problems.is_empty() => {
+            println!("{}: OK", path.display());
+        }
+        Ok(problems) => {
+            for p in problems {
+                println!("{}", p);
+            }
+            error!(
+                "{} does not match {}",
+                path.display(),
+                manifest_path.display()
+            );
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn memreport(args: &ArgMatches) {
+    let opts = MemreportOptions {
+        pid: parse_pid_arg(args),
+        max_host_pressure: parse_max_host_pressure(args),
+    };
+
+    if let Err(err) = memreport::memreport(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn agent(args: &ArgMatches) {
+    let listen = match args.value_of("listen") {
+        Some(addr) => {
+            let token_file = value_t_or_exit!(args, "token-file", PathBuf);
+            let token = match protocol::read_token(&token_file) {
+                Ok(token) => token,
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            };
+            Listen::Tcp {
+                addr: addr.to_string(),
+                token,
+            }
+        }
+        None => Listen::Stdio,
+    };
+    let opts = AgentOptions { listen };
+
+    if let Err(err) = agent::run(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn daemon(args: &ArgMatches) {
+    let opts = DaemonOptions {
+        socket: value_t_or_exit!(args, "socket", PathBuf),
+    };
+
+    if let Err(err) = daemon::daemon(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn ctl(args: &ArgMatches) {
+    let request = match args.subcommand() {
+        ("attach", Some(args)) => CtlRequest::Attach {
+            pid: parse_pid_arg(args),
+            args: values_t!(args, "ARGS", String).unwrap_or_else(|_| vec![]),
+        },
+        ("detach", Some(args)) => CtlRequest::Detach {
+            id: value_t_or_exit!(args, "ID", u64),
+        },
+        ("list", Some(_)) => CtlRequest::List,
+        ("stats", Some(_)) => CtlRequest::Stats,
+        _ => unreachable!(), // because of AppSettings::SubcommandRequiredElseHelp
+    };
+    let opts = CtlOptions {
+        socket: value_t_or_exit!(args, "socket", PathBuf),
+        request,
+    };
+
+    if let Err(err) = daemon::ctl(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+/// Parse sizes like `512`, `10K`, `100M` or `1G` (powers of 1024).
+fn try_parse_size(s: &str) -> Result<u64, String> {
+    let (num, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n = num.parse::<u64>().map_err(|e| e.to_string())?;
+    if n > u64::MAX >> shift {
+        return Err(String::from("size is too large"));
+    }
+    n.checked_shl(shift)
+        .ok_or_else(|| String::from("size is too large"))
+}
+
+fn parse_size(s: &str) -> u64 {
+    match try_parse_size(s) {
+        Ok(size) => size,
+        Err(e) => {
+            error!("invalid size {}: {}", s, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse durations like `5` (seconds), `2s` or `100ms`.
+fn parse_interval(s: &str) -> Duration {
+    let (num, millis) = if let Some(num) = s.strip_suffix("ms") {
+        (num, true)
+    } else {
+        (s.strip_suffix('s').unwrap_or(s), false)
+    };
+    match num.parse::<u64>() {
+        Ok(n) if millis => Duration::from_millis(n),
+        Ok(n) => Duration::from_secs(n),
+        Err(e) => {
+            error!("invalid interval {}: {}", s, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn remote(args: &ArgMatches) {
+    let opts = RemoteOptions {
+        destination: value_t_or_exit!(args, "destination", String),
+        remote_vmsh: value_t_or_exit!(args, "remote-vmsh", String),
+        command: values_t!(args, "command", String).unwrap_or_else(|e| e.exit()),
+        rate_limit: parse_size(args.value_of("rate-limit").unwrap_or("0")),
+        resume: args.is_present("resume"),
+        token_file: args.value_of("token-file").map(PathBuf::from),
+    };
+
+    match client::run(&opts) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+}
+
+fn watch(args: &ArgMatches) {
+    let pid = parse_pid_arg(args);
+    let interval = parse_interval(&value_t_or_exit!(args, "interval", String));
+    if let Some(addr) = args.value_of("ADDR") {
+        let opts = || -> vmsh::result::Result<MemWatchOptions> {
+            Ok(MemWatchOptions {
+                pid,
+                addr: poke::parse_addr(addr)?,
+                len: parse_size(&value_t_or_exit!(args, "LEN", String)) as usize,
+                interval,
+                command: args.value_of("exec").map(String::from),
+            })
+        };
+        if let Err(err) = opts().and_then(|opts| memwatch::watch_memory(&opts)) {
+            error!("{}", err);
+            std::process::exit(1);
+        };
+        return;
+    }
+
+    let actions = values_t!(args, "action", String)
+        .unwrap_or_else(|_| vec![])
+        .iter()
+        .map(|a| match a.as_str() {
+            "coredump" => Action::Coredump(
+                value_t!(args, "core-path", PathBuf)
+                    .unwrap_or_else(|_| PathBuf::from(format!("core.{}", pid))),
+            ),
+            "nmi" => Action::Nmi,
+            "webhook" => Action::Webhook(value_t_or_exit!(args, "webhook", String)),
+            _ => unreachable!(), // because of possible_values
+        })
+        .collect();
+    let opts = WatchOptions {
+        pid,
+        interval,
+        actions,
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+    };
+
+    if let Err(err) = watchdog::watch(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn gdbserver(args: &ArgMatches) {
+    let opts = GdbServerOptions {
+        pid: parse_pid_arg(args),
+        port: value_t_or_exit!(args, "port", u16),
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+    };
+
+    if let Err(err) = gdbstub::gdbserver(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn vcpu_pin(args: &ArgMatches) {
+    let cpus = args.value_of("cpus").map(|list| {
+        vcpu_pin::parse_cpu_list(list).unwrap_or_else(|| {
+            error!("invalid cpu list: {}", list);
+            std::process::exit(1);
+        })
+    });
+    let opts = VcpuPinOptions {
+        pid: parse_pid_arg(args),
+        vcpus: values_t!(args, "vcpu", usize).unwrap_or_else(|_| vec![]),
+        cpus,
+        spread: args.is_present("spread"),
+        nice: value_t!(args, "nice", i32).ok(),
+    };
+
+    if let Err(err) = vcpu_pin::vcpu_pin(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn step(args: &ArgMatches) {
+    let opts = StepOptions {
+        pid: parse_pid_arg(args),
+        vcpu: value_t_or_exit!(args, "vcpu", usize),
+        count: value_t_or_exit!(args, "count", usize),
+    };
+
+    if let Err(err) = step::step(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn break_cmd(args: &ArgMatches) {
+    let kind = if args.is_present("hw") {
+        BreakpointKind::Hardware
+    } else {
+        BreakpointKind::Software
+    };
+    let opts = BreakOptions {
+        pid: parse_pid_arg(args),
+        locations: values_t_or_exit!(args, "locations", String),
+        kind,
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+    };
+
+    if let Err(err) = breakpoint::break_on(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn stats(args: &ArgMatches) {
+    let opts = StatsOptions {
+        pid: parse_pid_arg(args),
+        watch: if args.is_present("watch") {
+            Some(Duration::from_secs(value_t_or_exit!(args, "watch", u64)))
+        } else {
+            None
+        },
+    };
+
+    if let Err(err) = stats::stats(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn pmu(args: &ArgMatches) {
+    let opts = PmuOptions {
+        pid: parse_pid_arg(args),
+        events: values_t!(args, "event", String)
+            .unwrap_or_else(|_| DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect()),
+        interval: Duration::from_secs(value_t_or_exit!(args, "interval", u64)),
+        count: value_t!(args, "count", usize).ok(),
+        per_vcpu: args.is_present("per-vcpu"),
+    };
+
+    if let Err(err) = pmu::pmu(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn clock(args: &ArgMatches) {
+    let opts = ClockOptions {
+        pid: parse_pid_arg(args),
+    };
+
+    if let Err(err) = kvmclock::clock(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn lockstat(args: &ArgMatches) {
+    let opts = LockStatOptions {
+        pid: parse_pid_arg(args),
+        duration: parse_interval(&value_t_or_exit!(args, "duration", String)),
+        interval: parse_interval(&value_t_or_exit!(args, "interval", String)),
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+        top: value_t_or_exit!(args, "top", usize),
+    };
+
+    if let Err(err) = lockstat::lockstat(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn profile(args: &ArgMatches) {
+    let opts = ProfileOptions {
+        pid: parse_pid_arg(args),
+        frequency: value_t_or_exit!(args, "freq", u32),
+        duration: parse_interval(&value_t_or_exit!(args, "duration", String)),
+        symbols: parse_symbols_arg(args).unwrap_or(SymbolSource::Ksymtab),
+        per_vcpu: args.is_present("per-vcpu"),
+        output: args.value_of("output").map(PathBuf::from),
+        svg: args.value_of("svg").map(PathBuf::from),
+    };
+
+    if let Err(err) = profile::profile(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn sched_diag(args: &ArgMatches) {
+    let opts = SchedDiagOptions {
+        pid: parse_pid_arg(args),
+        interval: Duration::from_secs(value_t_or_exit!(args, "interval", u64)),
+    };
+
+    if let Err(err) = sched_diag::sched_diag(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn cpu_report(args: &ArgMatches) {
+    if let Some(fleet_opts) = parse_fleet_args(args) {
+        run_fleet(&fleet_opts, |pid| {
+            cpu_report::collect_pid(pid).map(|r| r.to_json())
+        });
+        return;
+    }
+    let opts = CpuReportOptions {
+        pid: parse_pid_arg(args),
+    };
+
+    if let Err(err) = cpu_report::cpu_report(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn security_audit(args: &ArgMatches) {
+    let opts = SecurityAuditOptions {
+        pids: values_t_or_exit!(args, "pid", i32)
+            .into_iter()
+            .map(Pid::from_raw)
+            .collect(),
+        profile: value_t!(args, "profile", PathBuf).ok(),
+        json: args.is_present("json"),
+    };
+
+    match security_audit::security_audit(&opts) {
+        Ok(0) => {}
+        Ok(_) => std::process::exit(2),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn net_check(args: &ArgMatches) {
+    let opts = NetCheckOptions {
+        pid: parse_pid_arg(args),
+        targets: values_t_or_exit!(args, "target", String),
+        stage2_path: value_t_or_exit!(args, "stage2-path", String),
+    };
+
+    if let Err(err) = net_check::net_check(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn guest_trace(args: &ArgMatches) {
+    let opts = GuestTraceOptions {
+        pid: parse_pid_arg(args),
+        guest_pid: args
+            .value_of("guest-pid")
+            .map(|_| value_t_or_exit!(args, "guest-pid", u32)),
+        syscalls: args.is_present("syscalls"),
+        kprobes: values_t!(args, "kprobe", String).unwrap_or_else(|_| vec![]),
+        events: values_t!(args, "event", String).unwrap_or_else(|_| vec![]),
+        duration: value_t_or_exit!(args, "duration", u64),
+        stage2_path: value_t_or_exit!(args, "stage2-path", String),
+    };
+
+    if let Err(err) = guest_trace::guest_trace(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn fscheck(args: &ArgMatches) {
+    let opts = FsCheckOptions {
+        pid: parse_pid_arg(args),
+        devices: values_t!(args, "device", String).unwrap_or_else(|_| vec![]),
+        backing: PathBuf::from(value_t_or_exit!(args, "backing-file", String)),
+        backing_format: args.value_of("format").and_then(ImageFormat::from_name),
+        stage2_path: value_t_or_exit!(args, "stage2-path", String),
+    };
+
+    if let Err(err) = fscheck::fscheck(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn cert_scan(args: &ArgMatches) {
+    let opts = CertScanOptions {
+        pid: parse_pid_arg(args),
+        paths: values_t!(args, "path", String).unwrap_or_else(|_| vec![]),
+        warn_days: value_t_or_exit!(args, "warn-days", u32),
+        stage2_path: value_t_or_exit!(args, "stage2-path", String),
+    };
+
+    if let Err(err) = cert_scan::cert_scan(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+    // 2 if anything expired or expires soon
+    exit_with_command_code();
+}
+
+fn gc(args: &ArgMatches) {
+    let opts = GcOptions {
+        dry_run: args.is_present("dry-run"),
+        remove_memslots: args.is_present("remove-memslots"),
+        max_age: Duration::from_secs(value_t_or_exit!(args, "max-age", u64) * 60 * 60),
+    };
+
+    if let Err(err) = gc::gc(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn bundle(args: &ArgMatches) {
+    let opts = BundleOptions {
+        session: value_t_or_exit!(args, "SESSION", String),
+        output: args.value_of("output").map(PathBuf::from),
+        logs: args
+            .values_of("log")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect(),
+    };
+
+    if let Err(err) = bundle::bundle(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn selftest() {
+    if let Err(err) = selftest::selftest() {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn setup_logging(matches: &clap::ArgMatches) {
+    if matches.is_present("verbose") {
+        env_logger::Builder::new().parse_filters("debug").init();
+        return;
+    }
+
+    let loglevel = matches.value_of("loglevel");
+    if let Some(level) = loglevel {
+        env_logger::Builder::new().parse_filters(level).init();
+        return;
+    }
+
+    // default
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+}
+
+fn main() {
+    let [inspect_pid, inspect_core] = target_args(1);
+    let inspect_command = SubCommand::with_name("inspect")
+        .about("Inspect one or more virtual machines.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            inspect_pid
+                .multiple(true)
+                .required_unless_one(&["core", "all"]),
+        )
+        .arg(inspect_core.conflicts_with("all"))
+        .args(&fleet_args())
+        .arg(
+            Arg::with_name("sched")
+                .long("sched")
+                .help("Show host threads and scheduling latency/steal time of each vcpu"),
+        )
+        .arg(
+            Arg::with_name("msrs")
+                .long("msrs")
+                .help("Show model specific registers of each vcpu, i.e. IA32_EFER, IA32_LSTAR and IA32_GS_BASE"),
+        )
+        .arg(
+            Arg::with_name("cpuid")
+                .long("cpuid")
+                .help("Show the cpuid leaves the guest sees, for vcpus other than 0 only those that differ"),
+        )
+        .arg(
+            Arg::with_name("backing")
+                .long("backing")
+                .help("Show the host file, inode and offset behind each memslot, i.e. for hugetlbfs, memfd or shared memory paths"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("json prints memslots, vcpu fds, mappings and kvm capabilities in a stable schema"),
+        );
+
+    let attach_command = SubCommand::with_name("attach")
+        .about("Attach (a block device) to a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value("/dev/.vmsh")
+                .help("Path where Stage2 is written to in the VM"),
+        )
+        .arg(command_args(2))
+        .arg(symbols_arg())
+        .arg(
+            Arg::with_name("backing-file")
+                .short("f")
+                .long("backing-file")
+                .alias("backing")
+                .takes_value(true)
+                .default_value("/dev/null")
+                .help("File which shall be served as a block device, a block device of the host such as /dev/nvme0n1p3, or an export of a nbd server as nbd://host[:port][/export]."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["raw", "qcow2"])
+                .help(
+                    "Image format of the backing file. Images are raw unless qcow2 is given explicitly, the format is never detected from the image header.",
+                ),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("Do not let the guest write to the backing file. The default for block devices of the host."),
+        )
+        .arg(
+            Arg::with_name("read-write")
+                .long("read-write")
+                .conflicts_with("read-only")
+                .help("Let the guest write to a block device of the host. Make sure it is not mounted on the host or in the guest."),
+        )
+        .arg(cache_arg())
+        .arg(
+            Arg::with_name("inject-faults")
+                .long("inject-faults")
+                .takes_value(true)
+                .value_name("FAULTS")
+                .help("Make the block device fail to test the guest against a faulty disk, i.e. read-error=0.01,write-error=0.01,torn-write=0.001,latency=0.05:200ms. Rates are probabilities per access, torn writes write only some sectors before they fail. Add seed=N to repeat a run, the seed of every run is logged."),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Serve prometheus metrics of the attached devices on http://ADDR/metrics, i.e. 127.0.0.1:9100: block i/o, injected interrupts, mmio exits and how long attaching took"),
+        )
+        .arg(
+            Arg::with_name("mmio")
+                .long("mmio")
+                .takes_value(true)
+                .possible_values(&["wrap_syscall", "ioregionfd"])
+                .default_value("wrap_syscall")
+                .help("Backend used to serve Virtio MMIO memory of devices."),
+        )
+        .arg(
+            Arg::with_name("hotplug-memory")
+                .long("hotplug-memory")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Add memory to the guest as hotplugged memory while attached, i.e. 4G. Must be a multiple of 128M. Requires a guest kernel with CONFIG_MEMORY_HOTPLUG; the memory is onlined according to the guest's auto-online policy."),
+        )
+        .arg(
+            Arg::with_name("hotplug-node")
+                .long("hotplug-node")
+                .takes_value(true)
+                .value_name("NODE")
+                .default_value("0")
+                .help("Numa node of the hotplugged memory. The node must be possible in the guest, i.e. declared in its SRAT."),
+        )
+        .arg(
+            Arg::with_name("vsock")
+                .long("vsock")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Add a virtio-vsock device. Connect to the unix socket at PATH and send `CONNECT <port>\\n` to open a connection to a vsock port in the guest. Connections of the guest to the host are forwarded to PATH_<port>. Requires a guest kernel with CONFIG_VIRTIO_VSOCKETS and no other vsock device."),
+        )
+        .arg(
+            Arg::with_name("vsock-cid")
+                .long("vsock-cid")
+                .takes_value(true)
+                .value_name("CID")
+                .default_value("3")
+                .help("Context id of the guest on the vsock device."),
+        )
+        .arg(
+            Arg::with_name("publish")
+                .long("publish")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("[ADDR:]HOSTPORT:GUESTPORT")
+                .requires("vsock")
+                .help("Forward tcp connections to HOSTPORT on the host to GUESTPORT on localhost in the network namespace of the command, i.e. 8080:80 or [::1]:2222:22. ADDR defaults to 127.0.0.1. Uses the vsock device, so the network of the guest is not changed. Can be given multiple times."),
+        )
+        .arg(
+            Arg::with_name("share")
+                .long("share")
+                .takes_value(true)
+                .value_name("HOST_DIR:GUEST_DIR")
+                .help("Share HOST_DIR with the command via virtio-9p, mounted at GUEST_DIR. Requires a guest kernel with CONFIG_NET_9P_VIRTIO and CONFIG_9P_FS."),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help("Set an environment variable of the command, overriding the one inherited from the container. Can be repeated."),
+        )
+        .arg(
+            Arg::with_name("cwd")
+                .long("cwd")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Working directory of the command in the container"),
+        )
+        .arg(
+            Arg::with_name("irq-affinity")
+                .long("irq-affinity")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DEVICE=CPU")
+                .help("Give DEVICE (block, console, vsock or 9p) its own interrupt and deliver it only to guest cpu CPU, to keep interrupts of vmsh off latency-critical cpus. Can be given multiple times. Uses legacy interrupts that are unused in the guest."),
+        );
+
+    let exec_command = SubCommand::with_name("exec")
+        .about("Run a command in a virtual machine until it exits, like kubectl exec.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(command_args(2))
+        .arg(
+            Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .help("Pass stdin to the command"),
+        )
+        .arg(
+            Arg::with_name("tty")
+                .short("t")
+                .long("tty")
+                .help("Use the local terminal in raw mode and pass its size on to the guest"),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .short("e")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help("Set an environment variable of the command. Can be repeated."),
+        )
+        .arg(
+            Arg::with_name("cwd")
+                .long("cwd")
+                .short("w")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Working directory of the command in the container"),
+        )
+        .arg(
+            Arg::with_name("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value("/dev/.vmsh")
+                .help("Path where Stage2 is written to in the VM"),
+        );
+
+    let coredump_command = SubCommand::with_name("coredump")
+        .about("Get a coredump of a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1).required_unless("group"))
+        .arg(
+            Arg::with_name("PATH")
+                .help("path to coredump. Defaults to core.${pid}")
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .value_name("PIDS")
+                .use_delimiter(true)
+                .conflicts_with_all(&["pid", "PATH", "adaptive"])
+                .help("Pause the VMs of these comma-separated hypervisor pids together and dump them with the same timestamp"),
+        )
+        .arg(
+            Arg::with_name("group-dir")
+                .long("group-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .default_value(".")
+                .help("Directory for the core.${pid} files and the core.group index of --group"),
+        )
+        .arg(
+            Arg::with_name("skip-swapped")
+                .long("skip-swapped")
+                .help("Leave host-swapped guest memory out instead of swapping it in"),
+        )
+        .arg(
+            Arg::with_name("dedup-store")
+                .long("dedup-store")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Store KSM-shared pages once in this directory instead of in the coredump"),
+        )
+        .arg(
+            Arg::with_name("encrypt-to")
+                .long("encrypt-to")
+                .takes_value(true)
+                .value_name("RECIPIENT")
+                .conflicts_with("dedup-store")
+                .help("Encrypt the coredump for an age (age1..., ssh-...) or gpg recipient"),
+        )
+        .arg(
+            Arg::with_name("scrub-list")
+                .long("scrub-list")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Redact the memory locations listed in this file (phys/virt/symbol lines)"),
+        )
+        .arg(
+            Arg::with_name("scrub-known")
+                .long("scrub-known")
+                .help("Search guest memory for private keys and redact them"),
+        )
+        .arg(Arg::with_name("adaptive").long("adaptive").help(
+            "Keep the guest running and copy memory while it is idle (not a consistent snapshot)",
+        ))
+        .arg(max_host_pressure_arg().help(
+            "Pause while host cpu, io or memory pressure (PSI avg10) is above this percentage. Without --adaptive the guest stays stopped meanwhile",
+        ))
+        .arg(symbols_arg().help(
+            "Write the kernel functions the vcpus execute to ${PATH}.symbols, using these symbols: ksymtab, system-map:PATH, vmlinux:PATH or none",
+        ))
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["elf", "kdump"])
+                .default_value("elf")
+                .help("elf is for gdb and --core of other vmsh commands. kdump writes a vmcore for crash and makedumpfile, which needs a guest kernel with CONFIG_CRASH_CORE"),
+        );
+
+    let crashdump_command = SubCommand::with_name("crashdump")
+        .about("Panic a virtual machine so that its kdump captures a vmcore, with a coredump as fallback.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .default_value("60s")
+                .help("How long to wait for the crash kernel to start, i.e. 60s or 500ms"),
+        )
+        .arg(
+            Arg::with_name("wait-capture")
+                .long("wait-capture")
+                .help("Also wait until the crash kernel has saved the vmcore and left its memory"),
+        )
+        .arg(
+            Arg::with_name("fallback-coredump")
+                .long("fallback-coredump")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write a coredump here if kdump is not set up or the crash kernel does not start"),
+        )
+        .arg(symbols_arg().help(
+            "Where to find kexec_crash_image and unknown_nmi_panic, which are not exported: ksymtab, system-map:PATH, vmlinux:PATH or kallsyms:PATH",
+        ));
+    let process_dump_command = SubCommand::with_name("process-dump")
+        .about("Dump a single process of a virtual machine into a coredump for gdb.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("process")
+                .long("process")
+                .short("p")
+                .takes_value(true)
+                .value_name("GUEST_PID")
+                .required(true)
+                .help("Pid of the process in the VM"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .required(true)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("path to coredump. Defaults to core.${guest_pid}"),
+        );
+
+    let ps_command = SubCommand::with_name("ps")
+        .about("List the processes of a virtual machine from its kernel memory.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        );
+
+    let virtio_ls_command = SubCommand::with_name("virtio-ls")
+        .about("List the virtio devices of a virtual machine, its own and those of vmsh, with their regions and features.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        );
+
+    let compat_command = SubCommand::with_name("compat")
+        .about("Show the host kernel, guest kernel and KVM capabilities of earlier attaches to a virtual machine and what was lost in between.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1));
+
+    let containers_command = SubCommand::with_name("containers")
+        .about("List the containers of a virtual machine from the cgroups of its processes.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        )
+        .arg(
+            Arg::with_name("no-trunc")
+                .long("no-trunc")
+                .help("Print full container ids"),
+        );
+
+    let ftrace_dump_command = SubCommand::with_name("ftrace-dump")
+        .about("Recover the ftrace ring buffers of a hung virtual machine from its memory.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        )
+        .arg(
+            Arg::with_name("cpu")
+                .long("cpu")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only dump the ring buffer of this cpu"),
+        );
+
+    let swap_command = SubCommand::with_name("swap")
+        .about("Show the swap areas of a virtual machine, the compression of its zram devices and the processes with the most swapped out memory.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        )
+        .arg(
+            Arg::with_name("top")
+                .long("top")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("10")
+                .help("Number of processes to show"),
+        );
+
+    let route_command = SubCommand::with_name("route")
+        .about("Show the IPv4 routes of a virtual machine from its kernel, like `ip route`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        )
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .help("Show all routing tables instead of only main, like `ip route show table all`"),
+        );
+
+    let arp_command = SubCommand::with_name("arp")
+        .about("Show the ARP cache of a virtual machine from its kernel, like `ip neigh`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .args(&target_args(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value(DEFAULT_PROFILE_DIR)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        );
+
+    let vcat_command = SubCommand::with_name("vcat")
+        .about("Print a file of a virtual machine from its page cache.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("PATH")
+                .help("Absolute path of the file in the VM")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("PATH")
+                .required(true)
+                .help("Struct offsets of the guest kernel as text or BTF, or a directory of profiles named after kernel releases"),
+        );
+
+    let expect_command = SubCommand::with_name("expect")
+        .about(
+            "Attach to a virtual machine and interact with a command on its console as scripted.",
+        )
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("SCRIPT")
+                .help("YAML list of send, expect and sleep steps")
+                .required(true)
+                .index(2),
+        )
+        .arg(command_args(3));
+
+    let irq_command = SubCommand::with_name("irq")
+        .about("Inject an interrupt into a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("GSI")
+                .help("Interrupt line of the irqchip, i.e. an ioapic pin")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("level")
+                .long("level")
+                .takes_value(true)
+                .possible_values(&["high", "low"])
+                .help("Leave the line at this level instead of raising and lowering it"),
+        );
+
+    let irqstorm_command = SubCommand::with_name("irqstorm")
+        .about("Watch the interrupt rates of a virtual machine and report interrupt storms.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("1s")
+                .help("Report interval, i.e. 1s or 500ms"),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .long("threshold")
+                .takes_value(true)
+                .default_value("10000")
+                .help("Interrupts per second of a gsi that are reported as storm"),
+        )
+        .arg(
+            Arg::with_name("mask")
+                .long("mask")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Mask the interrupts of storming devices added by vmsh for this long"),
+        );
+
+    let screenshot_command = SubCommand::with_name("screenshot")
+        .about("Save the framebuffer of a virtual machine as PNG.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("PATH")
+                .help("Where to write the PNG image")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .takes_value(true)
+                .requires("mode")
+                .help("Guest physical address of a 32 bit xrgb framebuffer, instead of the one the guest booted with"),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .value_name("WIDTHxHEIGHT")
+                .requires("address")
+                .help("Resolution of the framebuffer given with --address"),
+        );
+
+    let read_command = SubCommand::with_name("read")
+        .about("Hexdump the memory of a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("ADDR")
+                .help("Guest physical address in hex")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("LEN")
+                .help("Number of bytes to read, i.e. 256 or 4K")
+                .required(true)
+                .index(3),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .help("Write the bytes to stdout instead of a hexdump"),
+        );
+
+    let write_command = SubCommand::with_name("write")
+        .about("Write bytes into the memory of a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("ADDR")
+                .help("Guest physical address in hex")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("BYTES")
+                .help("Bytes to write as hex digits, i.e. 9090")
+                .required(true)
+                .index(3),
+        )
+        .arg(Arg::with_name("virt").long("virt").help(
+            "ADDR is a guest virtual address, translated with the page tables of the first vcpu",
+        ))
+        .arg(
+            Arg::with_name("cr3")
+                .long("cr3")
+                .takes_value(true)
+                .requires("virt")
+                .help(
+                    "Translate with the page tables at this guest physical address (hex) instead",
+                ),
+        );
+
+    let snapshot_command = SubCommand::with_name("snapshot")
+        .about("Save memory, vcpu and kvm device state of a virtual machine to a file.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("PATH")
+                .help("path to snapshot")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("encrypt-to")
+                .long("encrypt-to")
+                .takes_value(true)
+                .value_name("RECIPIENT")
+                .help("Encrypt the snapshot for an age (age1..., ssh-...) or gpg recipient. It has to be decrypted before `vmsh restore`"),
+        );
+
+    let restore_command = SubCommand::with_name("restore")
+        .about("Write a snapshot back into a virtual machine with the same memory layout.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("PATH")
+                .help("path to snapshot written by `vmsh snapshot`")
+                .required(true)
+                .index(2),
+        );
+
+    let record_command = SubCommand::with_name("record")
+        .about("Record periodic checkpoints of registers and dirtied memory to look at the state before a crash with `vmsh rewind-inspect`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("DIR")
+                .help("Directory for the recording, must not exist yet. Needs as much space as guest memory")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("100ms")
+                .help("Time between checkpoints, i.e. 100ms or 1s"),
+        )
+        .arg(
+            Arg::with_name("checkpoints")
+                .long("checkpoints")
+                .takes_value(true)
+                .default_value("50")
+                .help("Number of checkpoints to keep, older ones are merged into the recorded memory"),
+        );
+
+    let rewind_inspect_command = SubCommand::with_name("rewind-inspect")
+        .about("List the checkpoints of a recording of `vmsh record` or show registers and memory at one of them.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::with_name("DIR")
+                .help("Directory of the recording")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .value_name("SEQ")
+                .help("Show the registers at this checkpoint, or with --read the memory. Defaults to the latest one with --read"),
+        )
+        .arg(
+            Arg::with_name("read")
+                .long("read")
+                .takes_value(true)
+                .value_name("ADDR")
+                .requires("len")
+                .help("Hexdump guest memory at this physical address (hex)"),
+        )
+        .arg(
+            Arg::with_name("len")
+                .long("len")
+                .takes_value(true)
+                .requires("read")
+                .help("Number of bytes to read, i.e. 64 or 4K"),
+        );
+
+    let mem_size_arg = Arg::with_name("SIZE")
+        .help("Size of the memory, i.e. 4G. Must be a multiple of 128M.")
+        .required(true)
+        .index(2);
+    let mem_node_arg = Arg::with_name("node")
+        .long("node")
+        .takes_value(true)
+        .default_value("0")
+        .help("Numa node of the memory, must be possible in the guest. `mem remove` only needs it before linux 5.15.");
+    let mem_command = SubCommand::with_name("mem")
+        .about("Add memory to a virtual machine or remove it again, as if it was hotplugged.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Add memory to the guest. It is onlined according to the guest's auto-online policy.")
+                .arg(pid_arg(1))
+                .arg(mem_size_arg.clone())
+                .arg(mem_node_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Offline and remove memory added with `vmsh mem add`. Requires bcc.")
+                .arg(pid_arg(1))
+                .arg(mem_size_arg)
+                .arg(mem_node_arg),
+        );
+
+    let device_command = SubCommand::with_name("device")
+        .about("Add block devices to a running `vmsh attach` session or remove them again.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("add-blk")
+                .about("Add a block device to the guest and print its name.")
+                .arg(pid_arg(1))
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .help("File which shall be served as a block device, or nbd://host[:port][/export].")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["raw", "qcow2"])
+                        .help(
+                            "Image format of the file. Images are raw unless qcow2 is given explicitly, the format is never detected from the image header.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("read-only")
+                        .long("read-only")
+                        .help("Do not let the guest write to the image"),
+                )
+                .arg(cache_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Remove a block device added with `vmsh device add-blk` from the guest.")
+                .arg(pid_arg(1))
+                .arg(
+                    Arg::with_name("NAME")
+                        .help("Name printed by `vmsh device add-blk`, i.e. blk1")
+                        .required(true)
+                        .index(2),
+                ),
+        );
+
+    let agent_command = SubCommand::with_name("agent")
+        .about("Serve requests of `vmsh remote` on the VM host.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::with_name("stdio")
+                .long("stdio")
+                .conflicts_with("listen")
+                .help("Serve a single session on stdin/stdout (default, used via ssh)"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .requires("token-file")
+                .help("Listen on tcp address i.e. 127.0.0.1:7777. Clients have to present the token of --token-file. The connection, including the token and coredumps, is not encrypted"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .takes_value(true)
+                .help("File with the token tcp clients authenticate with, at least 16 characters and only readable by its owner"),
+        );
+
+    let socket_arg = Arg::with_name("socket")
+        .long("socket")
+        .takes_value(true)
+        .default_value(DEFAULT_SOCKET)
+        .help("Control socket of vmsh daemon");
+
+    let daemon_command = SubCommand::with_name("daemon")
+        .about("Keep `vmsh attach` sessions open in the background, controlled with `vmsh ctl`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(socket_arg.clone());
+
+    let ctl_command = SubCommand::with_name("ctl")
+        .about("Attach to, detach from and list the VMs of a running `vmsh daemon`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(socket_arg)
+        .subcommand(
+            SubCommand::with_name("attach")
+                .about("Start `vmsh attach` in the daemon and print the id of the attachment.")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(pid_arg(1))
+                .arg(
+                    Arg::with_name("ARGS")
+                        .help("Arguments of vmsh attach after the pid, i.e. `-f tools.img -- sleep infinity`")
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("detach")
+                .about("Stop an attachment of the daemon like Ctrl-C stops `vmsh attach`.")
+                .arg(
+                    Arg::with_name("ID")
+                        .help("Id printed by `vmsh ctl attach` and `vmsh ctl list`")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list").about("List the attachments of the daemon and their state."),
+        )
+        .subcommand(
+            SubCommand::with_name("stats").about("Show counters of the daemon."),
+        );
+
+    let remote_command = SubCommand::with_name("remote")
+        .about("Run inspect or coredump on a remote VM host through `vmsh agent`.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("remote-vmsh")
+                .long("remote-vmsh")
+                .takes_value(true)
+                .default_value("vmsh")
+                .help("Path of vmsh on the remote host when connecting via ssh"),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .takes_value(true)
+                .help("Limit coredump transfers to this many bytes per second, i.e. 100M"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Continue an interrupted coredump transfer instead of taking a new coredump"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .takes_value(true)
+                .help("File with the token of the agent, required for tcp:// destinations"),
+        )
+        .arg(
+            Arg::with_name("destination")
+                .help("ssh://[user@]host[:port], tcp://host:port or an ssh destination. Ipv6 addresses with a port need brackets, i.e. [::1]:22")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("command")
+                .help("vmsh command to run on the remote host, i.e. `coredump 1234 core.1234`")
+                .multiple(true)
+                .required(true)
+                .index(2),
+        );
+
+    let verify_core_command = SubCommand::with_name("verify-core")
+        .about("Verify a coredump or snapshot against the manifest written alongside it.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::with_name("PATH")
+                .required(true)
+                .help("path to coredump or snapshot")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .takes_value(true)
+                .help("path to manifest. Defaults to ${PATH}.manifest"),
+        );
+
+    let memreport_command = SubCommand::with_name("memreport")
+        .about("Report how guest physical memory is backed by host memory.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(max_host_pressure_arg());
+
+    let gdbserver_command = SubCommand::with_name("gdbserver")
+        .about("Debug the guest kernel with gdb over the remote serial protocol.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .default_value("1234")
+                .help("TCP port on localhost to wait for gdb on"),
+        )
+        .arg(symbols_arg());
+
+    let vcpu_pin_command = SubCommand::with_name("vcpu-pin")
+        .about("Change cpu affinity and priority of vcpu threads.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("vcpu")
+                .long("vcpu")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Vcpu to change. Can be given multiple times. Defaults to all vcpus"),
+        )
+        .arg(
+            Arg::with_name("cpus")
+                .long("cpus")
+                .takes_value(true)
+                .value_name("LIST")
+                .required_unless("nice")
+                .help("Host cpus to run the vcpus on, i.e. 0-3,8"),
+        )
+        .arg(
+            Arg::with_name("spread")
+                .long("spread")
+                .requires("cpus")
+                .help("Pin each vcpu to its own cpu from --cpus"),
+        )
+        .arg(
+            Arg::with_name("nice")
+                .long("nice")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Nice value of the vcpu threads"),
+        );
+
+    let step_command = SubCommand::with_name("step")
+        .about("Single-step a vcpu and print its registers after each instruction.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("vcpu")
+                .long("vcpu")
+                .takes_value(true)
+                .default_value("0")
+                .help("Vcpu to step"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .short("n")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of instructions to step"),
+        );
 
-    let opts = AttachOptions {
-        pid: parse_pid_arg(args),
-        command,
-        backing: PathBuf::from(value_t!(args, "backing-file", String).unwrap_or_else(|e| e.exit())),
-    };
+    let break_command = SubCommand::with_name("break")
+        .about(
+            "Wait until a vcpu reaches one of the given guest addresses and print its registers.",
+        )
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("locations")
+                .required(true)
+                .multiple(true)
+                .index(2)
+                .help("Guest virtual addresses in hex or kernel symbols"),
+        )
+        .arg(
+            Arg::with_name("hw")
+                .long("hw")
+                .help("Use debug registers instead of patching int3 into guest memory (at most 4)"),
+        )
+        .arg(symbols_arg());
 
-    USE_IOREGIONFD.store(
-        value_t_or_exit!(args, "mmio", String) == "ioregionfd",
-        Ordering::Release,
-    );
+    let sched_diag_command = SubCommand::with_name("sched-diag")
+        .about("Diagnose preemption and halt polling of vcpus.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5")
+                .help("Seconds to collect statistics for"),
+        );
 
-    if let Err(err) = attach::attach(&opts) {
-        error!("{}", err);
-        std::process::exit(1);
-    };
-}
+    let stats_command = SubCommand::with_name("stats")
+        .about(
+            "Print exits, injections and halt polling statistics KVM keeps for a vm and its vcpus.",
+        )
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Print how the statistics changed every SECONDS until interrupted"),
+        );
 
-fn coredump(args: &ArgMatches) {
-    let pid = parse_pid_arg(args);
-    let path =
-        value_t!(args, "PATH", PathBuf).unwrap_or_else(|_| PathBuf::from(format!("core.{}", pid)));
+    let pmu_command = SubCommand::with_name("pmu")
+        .about("Count cycles, instructions and cache misses of a vm with its virtual PMU.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("event")
+                .long("event")
+                .short("e")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("EVENT")
+                .help("Event to count: cycles, instructions, cache-references, cache-misses, branches or branch-misses. Defaults to the first four"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("1")
+                .help("Print the counts every SECONDS"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .takes_value(true)
+                .value_name("N")
+                .help("Stop after N intervals instead of when interrupted"),
+        )
+        .arg(
+            Arg::with_name("per-vcpu")
+                .long("per-vcpu")
+                .help("Show the counts of each vcpu instead of their sum"),
+        );
 
-    let opts = CoredumpOptions { pid, path };
+    let clock_command = SubCommand::with_name("clock")
+        .about("Report the kvmclock, guest wall clock, tsc offsets and their drift to host time.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1));
 
-    if let Err(err) = coredump::generate_coredump(&opts) {
-        error!("{}", err);
-        std::process::exit(1);
-    };
-}
+    let lockstat_command = SubCommand::with_name("lockstat")
+        .about("Sample the vcpus and report which guest kernel locks they spend time waiting for.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("10")
+                .help("How long to sample, i.e. 10 (seconds) or 500ms"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("10ms")
+                .help("Time between samples. Each sample stops the guest briefly"),
+        )
+        .arg(
+            Arg::with_name("top")
+                .long("top")
+                .takes_value(true)
+                .default_value("20")
+                .help("Number of locks to show"),
+        )
+        .arg(symbols_arg());
 
-fn setup_logging(matches: &clap::ArgMatches) {
-    if matches.is_present("verbose") {
-        env_logger::Builder::new().parse_filters("debug").init();
-        return;
-    }
+    let profile_command = SubCommand::with_name("profile")
+        .about("Sample the stacks of the vcpus and write them as folded stacks for flamegraphs.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("freq")
+                .long("freq")
+                .takes_value(true)
+                .default_value("99")
+                .help("Samples per second. Each sample stops the guest briefly"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("30s")
+                .help("How long to sample, i.e. 30s or 500ms"),
+        )
+        .arg(
+            Arg::with_name("per-vcpu")
+                .long("per-vcpu")
+                .help("Start each stack with the vcpu it was sampled on"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write the folded stacks to PATH instead of stdout, i.e. for flamegraph.pl or inferno-flamegraph"),
+        )
+        .arg(
+            Arg::with_name("svg")
+                .long("svg")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Also write a flamegraph svg to PATH"),
+        )
+        .arg(symbols_arg());
 
-    let loglevel = matches.value_of("loglevel");
-    if let Some(level) = loglevel {
-        env_logger::Builder::new().parse_filters(level).init();
-        return;
-    }
+    let cpu_report_command = SubCommand::with_name("cpu-report")
+        .about("Report cpu features, vulnerability msrs and active mitigations of the guest.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1).multiple(true).required_unless("all"))
+        .args(&fleet_args());
 
-    // default
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-}
+    let security_audit_command = SubCommand::with_name("security-audit")
+        .about("Check that KASLR, stack canaries and page table isolation of guests are effective. Exits with 2 if problems were found.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1).multiple(true).help("Pids of the hypervisors to audit"))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Profile with the per-cpu offset of the stack canary, needed for linux 6.2 and newer"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print one json object per VM"),
+        );
 
-fn main() {
-    let inspect_command = SubCommand::with_name("inspect")
-        .about("Inspect a virtual machine.")
+    let net_check_command = SubCommand::with_name("net-check")
+        .about("Check dns, routing and reachability of remote hosts from inside the guest.")
         .version(crate_version!())
         .author(crate_authors!("\n"))
-        .arg(pid_arg(1));
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("HOST:PORT")
+                .default_value("example.com:443")
+                .help("Host to resolve and connect to. Can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value("/dev/.vmsh")
+                .help("Path where Stage2 is written to in the VM"),
+        );
 
-    let attach_command = SubCommand::with_name("attach")
-        .about("Attach (a block device) to a virtual machine.")
+    let guest_trace_command = SubCommand::with_name("guest-trace")
+        .about("Trace syscalls, kernel functions and tracepoints inside the guest with ftrace.")
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("syscalls")
+                .long("syscalls")
+                .help("Trace syscalls with their arguments and return values. Needs CONFIG_FTRACE_SYSCALLS in the guest"),
+        )
+        .arg(
+            Arg::with_name("kprobe")
+                .long("kprobe")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("FUNCTION")
+                .help("Trace calls of this guest kernel function. Can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("event")
+                .long("event")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("SUBSYSTEM/EVENT")
+                .help("Trace this tracepoint, i.e. sched/sched_process_exec. Can be given multiple times"),
+        )
+        .arg(
+            Arg::with_name("guest-pid")
+                .long("pid")
+                .takes_value(true)
+                .help("Only trace this thread of the guest"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds to trace before the guest is left as it was"),
+        )
         .arg(
             Arg::with_name("stage2-path")
                 .long("stage2-path")
                 .takes_value(true)
                 .default_value("/dev/.vmsh")
                 .help("Path where Stage2 is written to in the VM"),
+        );
+
+    let fscheck_command = SubCommand::with_name("fscheck")
+        .about("Check the filesystems of the guest read-only with tools from a vmsh image.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Guest block device to check, i.e. /dev/vda1. Can be given multiple times. Defaults to all devices"),
         )
-        .arg(command_args(2))
         .arg(
             Arg::with_name("backing-file")
                 .short("f")
                 .long("backing-file")
                 .takes_value(true)
-                .default_value("/dev/null")
-                .help("File which shall be served as a block device."),
+                .required(true)
+                .help("Image that contains e2fsck, xfs_repair, btrfs or fsck.vfat"),
         )
         .arg(
-            Arg::with_name("mmio")
-                .long("mmio")
+            Arg::with_name("format")
+                .long("format")
                 .takes_value(true)
-                .possible_values(&["wrap_syscall", "ioregionfd"])
-                .default_value("wrap_syscall")
-                .help("Backend used to serve Virtio MMIO memory of devices."),
+                .possible_values(&["raw", "qcow2"])
+                .help("Image format of the backing file. Images are raw unless qcow2 is given explicitly, the format is never detected from the image header."),
+        )
+        .arg(
+            Arg::with_name("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value("/dev/.vmsh")
+                .help("Path where Stage2 is written to in the VM"),
         );
 
-    let coredump_command = SubCommand::with_name("coredump")
-        .about("Get a coredump of a virtual machine.")
+    let cert_scan_command = SubCommand::with_name("cert-scan")
+        .about("Report certificates and tokens in the guest that expired or expire soon. Exits with 2 if any are found.")
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .arg(pid_arg(1))
         .arg(
-            Arg::with_name("PATH")
-                .help("path to coredump. Defaults to core.${pid}")
-                .index(2),
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("File or directory in the guest to scan. Can be given multiple times. Defaults to the usual locations of kubernetes, kubelet, letsencrypt, web servers, docker and mounted secrets, without the trust store in /etc/ssl/certs"),
+        )
+        .arg(
+            Arg::with_name("warn-days")
+                .long("warn-days")
+                .takes_value(true)
+                .default_value("30")
+                .help("Report certificates and tokens that expire within this many days"),
+        )
+        .arg(
+            Arg::with_name("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value("/dev/.vmsh")
+                .help("Path where Stage2 is written to in the VM"),
+        );
+
+    let gc_command = SubCommand::with_name("gc")
+        .about("Remove sockets, memslots and files that crashed vmsh sessions left behind.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .short("n")
+                .help("Only report what would be removed"),
+        )
+        .arg(
+            Arg::with_name("remove-memslots")
+                .long("remove-memslots")
+                .help("Also remove memslots of crashed sessions from running VMs. The guest crashes if it still uses them"),
+        )
+        .arg(
+            Arg::with_name("max-age")
+                .long("max-age")
+                .takes_value(true)
+                .value_name("HOURS")
+                .default_value("24")
+                .help("Remove transfer files of vmsh agent that were not fetched within this time"),
+        );
+
+    let bundle_command = SubCommand::with_name("bundle")
+        .about("Collect the state of a vmsh session into a tarball for bug reports.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::with_name("SESSION")
+                .help("Pid of the vmsh process or path of its session file, also of crashed sessions")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Tarball to write, defaults to vmsh-bundle-<pid>.tar.gz"),
+        )
+        .arg(
+            Arg::with_name("log")
+                .long("log")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("FILE")
+                .help("Log file to include, i.e. the stderr of vmsh attach. Can be given multiple times."),
         );
 
+    let selftest_command = SubCommand::with_name("selftest")
+        .about("Check whether this host supports vmsh by attaching to a scratch VM that vmsh starts itself.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"));
+
+    let watch_command = SubCommand::with_name("watch")
+        .about("Watch a virtual machine for kernel panics and oom kills, or a range of its memory for changes.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(pid_arg(1))
+        .arg(
+            Arg::with_name("ADDR")
+                .help("Watch guest memory at this physical address (hex) instead")
+                .requires("LEN")
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("LEN")
+                .help("Number of bytes to watch, i.e. 8 or 4K")
+                .requires("ADDR")
+                .index(3),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5")
+                .help("Time between checks, i.e. 5 (seconds), 2s or 100ms"),
+        )
+        .arg(
+            Arg::with_name("exec")
+                .long("exec")
+                .takes_value(true)
+                .requires("ADDR")
+                .help("Shell command to run when the watched memory changes. Gets the old and new content as hex in $VMSH_WATCH_OLD and $VMSH_WATCH_NEW"),
+        )
+        .arg(
+            Arg::with_name("action")
+                .long("action")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("ADDR")
+                .possible_values(&["coredump", "nmi", "webhook"])
+                .help(
+                    "Action to run once a panic or oom is detected. Can be given multiple times.",
+                ),
+        )
+        .arg(
+            Arg::with_name("core-path")
+                .long("core-path")
+                .takes_value(true)
+                .help("Path for --action coredump. Defaults to core.${pid}"),
+        )
+        .arg(
+            Arg::with_name("webhook")
+                .long("webhook")
+                .takes_value(true)
+                .required_if("action", "webhook")
+                .help("URL that receives a json POST request for --action webhook"),
+        )
+        .arg(symbols_arg());
+
     let main_app = App::new("vmsh")
         .about("Enter and execute in a virtual machine.")
         .version(crate_version!())
@@ -156,17 +2749,145 @@ fn main() {
              .short("l")
              .takes_value(true)
              .help("Finegrained verbosity control. See docs.rs/env_logger. Examples: [error, warn, info, debug, trace]"))
+        .arg(Arg::with_name("memslots")
+             .long("memslots")
+             .takes_value(true)
+             .possible_values(&["bcc", "maps"])
+             .default_value("bcc")
+             .help("How to find the guest memory of the hypervisor. bcc reads it from the kernel and needs kernel headers. maps guesses it from /proc/<pid>/maps and is only allowed for commands that do not write guest memory"))
         .subcommand(inspect_command)
         .subcommand(attach_command)
-        .subcommand(coredump_command);
+        .subcommand(exec_command)
+        .subcommand(coredump_command)
+        .subcommand(crashdump_command)
+        .subcommand(verify_core_command)
+        .subcommand(process_dump_command)
+        .subcommand(ps_command)
+        .subcommand(virtio_ls_command)
+        .subcommand(containers_command)
+        .subcommand(compat_command)
+        .subcommand(swap_command)
+        .subcommand(route_command)
+        .subcommand(arp_command)
+        .subcommand(ftrace_dump_command)
+        .subcommand(vcat_command)
+        .subcommand(screenshot_command)
+        .subcommand(irq_command)
+        .subcommand(irqstorm_command)
+        .subcommand(expect_command)
+        .subcommand(stats_command)
+        .subcommand(pmu_command)
+        .subcommand(clock_command)
+        .subcommand(read_command)
+        .subcommand(write_command)
+        .subcommand(snapshot_command)
+        .subcommand(restore_command)
+        .subcommand(record_command)
+        .subcommand(rewind_inspect_command)
+        .subcommand(mem_command)
+        .subcommand(device_command)
+        .subcommand(memreport_command)
+        .subcommand(agent_command)
+        .subcommand(remote_command)
+        .subcommand(daemon_command)
+        .subcommand(ctl_command)
+        .subcommand(watch_command)
+        .subcommand(gdbserver_command)
+        .subcommand(vcpu_pin_command)
+        .subcommand(step_command)
+        .subcommand(break_command)
+        .subcommand(sched_diag_command)
+        .subcommand(lockstat_command)
+        .subcommand(profile_command)
+        .subcommand(cpu_report_command)
+        .subcommand(security_audit_command)
+        .subcommand(net_check_command)
+        .subcommand(guest_trace_command)
+        .subcommand(fscheck_command)
+        .subcommand(cert_scan_command)
+        .subcommand(gc_command)
+        .subcommand(bundle_command)
+        .subcommand(selftest_command);
 
     let matches = main_app.get_matches();
     setup_logging(&matches);
+    if let Some(source) = matches
+        .value_of("memslots")
+        .and_then(MemslotSource::from_name)
+    {
+        set_memslot_source(source);
+    }
     match matches.subcommand() {
         ("inspect", Some(sub_matches)) => inspect(sub_matches),
         ("attach", Some(sub_matches)) => attach(sub_matches),
+        ("exec", Some(sub_matches)) => exec(sub_matches),
         ("coredump", Some(sub_matches)) => coredump(sub_matches),
+        ("crashdump", Some(sub_matches)) => crashdump(sub_matches),
+        ("verify-core", Some(sub_matches)) => verify_core(sub_matches),
+        ("process-dump", Some(sub_matches)) => process_dump(sub_matches),
+        ("ps", Some(sub_matches)) => ps(sub_matches),
+        ("virtio-ls", Some(sub_matches)) => virtio_ls(sub_matches),
+        ("containers", Some(sub_matches)) => containers(sub_matches),
+        ("compat", Some(sub_matches)) => compat(sub_matches),
+        ("swap", Some(sub_matches)) => swap(sub_matches),
+        ("route", Some(sub_matches)) => route(sub_matches),
+        ("arp", Some(sub_matches)) => arp(sub_matches),
+        ("ftrace-dump", Some(sub_matches)) => ftrace_dump(sub_matches),
+        ("vcat", Some(sub_matches)) => vcat(sub_matches),
+        ("screenshot", Some(sub_matches)) => screenshot(sub_matches),
+        ("irq", Some(sub_matches)) => irq(sub_matches),
+        ("irqstorm", Some(sub_matches)) => irqstorm(sub_matches),
+        ("expect", Some(sub_matches)) => expect(sub_matches),
+        ("stats", Some(sub_matches)) => stats(sub_matches),
+        ("pmu", Some(sub_matches)) => pmu(sub_matches),
+        ("clock", Some(sub_matches)) => clock(sub_matches),
+        ("read", Some(sub_matches)) => read(sub_matches),
+        ("write", Some(sub_matches)) => write(sub_matches),
+        ("snapshot", Some(sub_matches)) => snapshot(sub_matches),
+        ("restore", Some(sub_matches)) => restore(sub_matches),
+        ("record", Some(sub_matches)) => record(sub_matches),
+        ("rewind-inspect", Some(sub_matches)) => rewind_inspect(sub_matches),
+        ("mem", Some(sub_matches)) => mem(sub_matches),
+        ("device", Some(sub_matches)) => device(sub_matches),
+        ("memreport", Some(sub_matches)) => memreport(sub_matches),
+        ("agent", Some(sub_matches)) => agent(sub_matches),
+        ("remote", Some(sub_matches)) => remote(sub_matches),
+        ("daemon", Some(sub_matches)) => daemon(sub_matches),
+        ("ctl", Some(sub_matches)) => ctl(sub_matches),
+        ("watch", Some(sub_matches)) => watch(sub_matches),
+        ("gdbserver", Some(sub_matches)) => gdbserver(sub_matches),
+        ("vcpu-pin", Some(sub_matches)) => vcpu_pin(sub_matches),
+        ("step", Some(sub_matches)) => step(sub_matches),
+        ("break", Some(sub_matches)) => break_cmd(sub_matches),
+        ("sched-diag", Some(sub_matches)) => sched_diag(sub_matches),
+        ("lockstat", Some(sub_matches)) => lockstat(sub_matches),
+        ("profile", Some(sub_matches)) => profile(sub_matches),
+        ("cpu-report", Some(sub_matches)) => cpu_report(sub_matches),
+        ("security-audit", Some(sub_matches)) => security_audit(sub_matches),
+        ("net-check", Some(sub_matches)) => net_check(sub_matches),
+        ("guest-trace", Some(sub_matches)) => guest_trace(sub_matches),
+        ("fscheck", Some(sub_matches)) => fscheck(sub_matches),
+        ("cert-scan", Some(sub_matches)) => cert_scan(sub_matches),
+        ("gc", Some(sub_matches)) => gc(sub_matches),
+        ("bundle", Some(sub_matches)) => bundle(sub_matches),
+        ("selftest", Some(_)) => selftest(),
         ("", None) => unreachable!(), // beause of AppSettings::SubCommandRequiredElseHelp
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(try_parse_size("512"), Ok(512));
+        assert_eq!(try_parse_size("10k"), Ok(10 << 10));
+        assert_eq!(try_parse_size("1G"), Ok(1 << 30));
+        assert_eq!(try_parse_size("17179869183G"), Ok(17_179_869_183 << 30));
+        assert!(try_parse_size("17179869184G").is_err());
+        assert!(try_parse_size("100000000000T").is_err());
+        assert!(try_parse_size("M").is_err());
+    }
+}
@@ -0,0 +1,381 @@
+//! Breakpoints in guest code, see `vmsh break` and the `Z`/`z` packets of `vmsh gdbserver`.
+//!
+//! Software breakpoints replace the first byte of an instruction with int3, found through the
+//! page tables of the first vcpu. Hardware breakpoints use the debug registers DR0-DR3 of all
+//! vcpus and leave guest memory alone, but there are only four of them. Both are enabled with
+//! KVM_SET_GUEST_DEBUG, so that KVM reports them to userspace instead of the guest.
+//!
+//! While waiting for a hit, vmsh wraps ioctl(KVM_RUN) of the hypervisor like for its devices.
+//! The debug exit of a hit is hidden from the hypervisor, which would inject the exception into
+//! the guest otherwise. Breakpoints are one-shot: all of them are removed after the first hit and
+//! the vcpu executes the instruction at the breakpoint once the vm continues. Other vcpus that
+//! hit a breakpoint at the same moment are caught as well, apart from a short window in which
+//! their exit still reaches the hypervisor, which then injects the exception into the guest.
+use kvm_bindings as kvmb;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+
+use crate::gdbstub::open_symbols;
+use crate::kvm;
+use crate::kvm::hypervisor::memory::{process_read, process_write};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::poke;
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+use crate::step::format_regs;
+use crate::symbolizer::{self, SymbolSource, Symbolizer};
+use crate::tracer::wrap_syscall::DebugExit;
+
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+const INT3: u8 = 0xcc;
+/// Exception vectors of debug exits
+const DB_VECTOR: u32 = 1;
+const BP_VECTOR: u32 = 3;
+/// Reserved bit of DR7 that is always set
+const DR7_FIXED: u64 = 1 << 10;
+/// Exact breakpoint enable, recommended by the SDM when using breakpoints
+const DR7_GE: u64 = 1 << 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakpointKind {
+    /// int3 patched into guest memory
+    Software,
+    /// Debug register
+    Hardware,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Hit {
+    pub vcpu: usize,
+    pub addr: usize,
+    pub kind: BreakpointKind,
+}
+
+/// DR7 for execution breakpoints in the first `count` debug registers. Breakpoints are enabled
+/// globally, so that they survive task switches of the guest.
+fn dr7(count: usize) -> u64 {
+    // R/W and LEN of 0 break on instruction execution
+    (0..count).fold(DR7_FIXED | DR7_GE, |dr7, i| dr7 | 2 << (i * 2))
+}
+
+/// Guest virtual addresses of breakpoints.
+#[derive(Default)]
+pub struct Breakpoints {
+    software: Vec<usize>,
+    hardware: Vec<usize>,
+}
+
+impl Breakpoints {
+    pub fn insert(&mut self, addr: usize, kind: BreakpointKind) -> Result<()> {
+        let list = match kind {
+            BreakpointKind::Software => &mut self.software,
+            BreakpointKind::Hardware => &mut self.hardware,
+        };
+        if list.contains(&addr) {
+            return Ok(());
+        }
+        if kind == BreakpointKind::Hardware && list.len() == MAX_HW_BREAKPOINTS {
+            bail!(
+                "cannot set more than {} hardware breakpoints",
+                MAX_HW_BREAKPOINTS
+            );
+        }
+        list.push(addr);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, addr: usize, kind: BreakpointKind) {
+        match kind {
+            BreakpointKind::Software => self.software.retain(|a| *a != addr),
+            BreakpointKind::Hardware => self.hardware.retain(|a| *a != addr),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.software.is_empty() && self.hardware.is_empty()
+    }
+
+    fn guest_debug(&self) -> kvmb::kvm_guest_debug {
+        let mut debug = kvmb::kvm_guest_debug {
+            control: kvmb::KVM_GUESTDBG_ENABLE,
+            ..Default::default()
+        };
+        if !self.software.is_empty() {
+            debug.control |= kvmb::KVM_GUESTDBG_USE_SW_BP;
+        }
+        if !self.hardware.is_empty() {
+            debug.control |= kvmb::KVM_GUESTDBG_USE_HW_BP;
+            for (i, addr) in self.hardware.iter().enumerate() {
+                debug.arch.debugreg[i] = *addr as u64;
+            }
+            debug.arch.debugreg[7] = dr7(self.hardware.len());
+        }
+        debug
+    }
+
+    /// The breakpoint that caused `exit`, None for int3 of the guest itself.
+    fn hit_by(&self, exit: &DebugExit) -> Option<(usize, BreakpointKind)> {
+        match exit.exception {
+            BP_VECTOR => self
+                .software
+                .iter()
+                .find(|addr| **addr as u64 == exit.pc)
+                .map(|addr| (*addr, BreakpointKind::Software)),
+            DB_VECTOR => self
+                .hardware
+                .iter()
+                .enumerate()
+                .find(|(i, _)| exit.dr6 & (1 << i) != 0)
+                .map(|(_, addr)| (*addr, BreakpointKind::Hardware)),
+            _ => None,
+        }
+    }
+
+    /// Write int3 at all software breakpoints and enable guest debugging on all vcpus. The host
+    /// addresses and original bytes are added to `patched` as soon as they are replaced, so that
+    /// they can be restored without translating the addresses again.
+    fn arm(
+        &self,
+        vm: &Hypervisor,
+        sregs: &kvmb::kvm_sregs,
+        patched: &mut Vec<(usize, u8)>,
+    ) -> Result<()> {
+        for addr in &self.software {
+            let host_addr = vm.guest_virt_to_host(sregs, *addr)?;
+            let orig: u8 = process_read(vm.pid, host_addr as *const libc::c_void)?;
+            if orig == INT3 {
+                bail!("{:#x} already contains an int3 of the guest", addr);
+            }
+            process_write(vm.pid, host_addr as *mut libc::c_void, &INT3)?;
+            patched.push((host_addr, orig));
+        }
+        let debug = self.guest_debug();
+        for vcpu in &vm.vcpus {
+            vm.set_guest_debug(vcpu, &debug)?;
+        }
+        Ok(())
+    }
+
+    /// Resume the guest until a breakpoint is hit. Returns None if `cancelled` got true first.
+    fn run_until_hit(
+        &self,
+        vm: &Hypervisor,
+        patched: &[(usize, u8)],
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Option<Hit>> {
+        let vcpus = vm
+            .vcpus
+            .iter()
+            .map(|vcpu| {
+                let map = require_with!(
+                    vm.vcpu_maps.get(vcpu.idx),
+                    "no kvm_run mapping for vcpu {}",
+                    vcpu.idx
+                );
+                Ok((vcpu.fd_num, map.start))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ours = |exit: &DebugExit| self.hit_by(exit).is_some();
+        let mut exit = None;
+        vm.kvmrun_wrapped(|wrapper| {
+            let mut wrapper = try_with!(wrapper.lock(), "cannot obtain wrapper mutex");
+            let wrapper = require_with!(wrapper.as_mut(), "KvmRunWrapper not initialized");
+            exit = wrapper.wait_for_debug_exit(&vcpus, &ours, cancelled)?;
+            if exit.is_some() {
+                // before other vcpus run into them
+                restore(vm.pid, patched)?;
+                for other in wrapper.drain_debug_exits(&vcpus, &ours)? {
+                    info!(
+                        "breakpoint at {:#x} was hit by another vcpu as well",
+                        other.pc
+                    );
+                }
+            }
+            Ok(())
+        })?;
+        let exit = match exit {
+            Some(exit) => exit,
+            None => return Ok(None),
+        };
+        let vcpu = require_with!(
+            vm.vcpus.iter().position(|v| v.fd_num == exit.vcpu_fd),
+            "debug exit of unknown vcpu fd {}",
+            exit.vcpu_fd
+        );
+        let (addr, kind) = require_with!(self.hit_by(&exit), "unexpected debug exit");
+        Ok(Some(Hit { vcpu, addr, kind }))
+    }
+
+    /// Set the breakpoints, resume the guest until one of them is hit and remove them again.
+    /// The vm must be stopped and is stopped again on return. Returns None if `cancelled` got
+    /// true before a hit.
+    pub fn wait_for_hit(
+        &self,
+        vm: &Hypervisor,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Option<Hit>> {
+        let vcpu = require_with!(vm.vcpus.first(), "vm has no vcpus");
+        let sregs = vm.get_sregs(vcpu)?;
+        let mut patched = vec![];
+        let res = self
+            .arm(vm, &sregs, &mut patched)
+            .and_then(|_| self.run_until_hit(vm, &patched, cancelled));
+        let disarmed = disarm(vm, &patched);
+        let hit = res?;
+        try_with!(disarmed, "cannot remove breakpoints");
+        Ok(hit)
+    }
+}
+
+fn restore(pid: Pid, patched: &[(usize, u8)]) -> Result<()> {
+    for (host_addr, orig) in patched {
+        process_write(pid, *host_addr as *mut libc::c_void, orig)?;
+    }
+    Ok(())
+}
+
+/// Restore the original bytes and disable guest debugging on all vcpus.
+fn disarm(vm: &Hypervisor, patched: &[(usize, u8)]) -> Result<()> {
+    restore(vm.pid, patched)?;
+    for vcpu in &vm.vcpus {
+        vm.set_guest_debug(vcpu, &kvmb::kvm_guest_debug::default())?;
+    }
+    Ok(())
+}
+
+pub struct BreakOptions {
+    pub pid: Pid,
+    /// Hexadecimal guest virtual addresses or kernel symbols
+    pub locations: Vec<String>,
+    pub kind: BreakpointKind,
+    pub symbols: SymbolSource,
+}
+
+fn resolve(location: &str, symbolizer: &dyn Symbolizer) -> Result<usize> {
+    if let Ok(addr) = poke::parse_addr(location) {
+        return Ok(addr);
+    }
+    Ok(require_with!(
+        symbolizer.address(location),
+        "{} is neither an address nor a known kernel symbol",
+        location
+    ))
+}
+
+/// Wait for the guest to execute one of `opts.locations` and print the registers of the vcpu.
+pub fn break_on(opts: &BreakOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let stopped = vm.stop_guard()?;
+    let symbolizer = match open_symbols(&vm, &opts.symbols) {
+        Ok(symbolizer) => symbolizer,
+        Err(e) => {
+            warn!("cannot load kernel symbols: {}", e);
+            Box::new(symbolizer::NoSymbols)
+        }
+    };
+    let mut breakpoints = Breakpoints::default();
+    for location in &opts.locations {
+        let addr = resolve(location, symbolizer.as_ref())?;
+        breakpoints.insert(addr, opts.kind)?;
+        info!(
+            "break at {:#x} ({})",
+            addr,
+            symbolizer::describe(symbolizer.as_ref(), addr)
+        );
+    }
+
+    let cancel = Cancellation::setup()?;
+    let hit = match breakpoints.wait_for_hit(&vm, &|| cancel.is_cancelled())? {
+        Some(hit) => hit,
+        None => {
+            info!("interrupted before a breakpoint was hit");
+            return Ok(());
+        }
+    };
+    let regs = vm.get_regs(&vm.vcpus[hit.vcpu])?;
+    println!(
+        "vcpu {} hit {} breakpoint at {:#x} ({})",
+        hit.vcpu,
+        match hit.kind {
+            BreakpointKind::Software => "software",
+            BreakpointKind::Hardware => "hardware",
+        },
+        hit.addr,
+        symbolizer::describe(symbolizer.as_ref(), hit.addr)
+    );
+    println!("{}", format_regs(&regs));
+    drop(stopped);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exit(exception: u32, pc: u64, dr6: u64) -> DebugExit {
+        DebugExit {
+            vcpu_fd: 10,
+            exception,
+            pc,
+            dr6,
+        }
+    }
+
+    #[test]
+    fn test_dr7() {
+        assert_eq!(dr7(0), 0x600);
+        assert_eq!(dr7(1), 0x602);
+        assert_eq!(dr7(4), 0x6aa);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut bps = Breakpoints::default();
+        assert!(bps.is_empty());
+        for i in 0..MAX_HW_BREAKPOINTS {
+            bps.insert(0x1000 + i, BreakpointKind::Hardware).unwrap();
+        }
+        // inserting twice is fine, a fifth is not
+        bps.insert(0x1000, BreakpointKind::Hardware).unwrap();
+        assert!(bps.insert(0x2000, BreakpointKind::Hardware).is_err());
+        bps.remove(0x1000, BreakpointKind::Hardware);
+        bps.insert(0x2000, BreakpointKind::Hardware).unwrap();
+
+        let debug = bps.guest_debug();
+        assert_eq!(
+            debug.control,
+            kvmb::KVM_GUESTDBG_ENABLE | kvmb::KVM_GUESTDBG_USE_HW_BP
+        );
+        assert_eq!(debug.arch.debugreg[3], 0x2000);
+        assert_eq!(debug.arch.debugreg[7], dr7(4));
+    }
+
+    #[test]
+    fn test_hit_by() {
+        let mut bps = Breakpoints::default();
+        bps.insert(0xffff_ffff_8100_0000, BreakpointKind::Software)
+            .unwrap();
+        bps.insert(0xffff_ffff_8200_0000, BreakpointKind::Hardware)
+            .unwrap();
+        bps.insert(0xffff_ffff_8300_0000, BreakpointKind::Hardware)
+            .unwrap();
+
+        assert_eq!(
+            bps.hit_by(&exit(BP_VECTOR, 0xffff_ffff_8100_0000, 0)),
+            Some((0xffff_ffff_8100_0000, BreakpointKind::Software))
+        );
+        // int3 of the guest, i.e. a jump label being patched
+        assert_eq!(bps.hit_by(&exit(BP_VECTOR, 0xffff_ffff_8100_0010, 0)), None);
+        assert_eq!(
+            bps.hit_by(&exit(DB_VECTOR, 0xffff_ffff_8300_0000, 0xffff_0ff2)),
+            Some((0xffff_ffff_8300_0000, BreakpointKind::Hardware))
+        );
+        // single step
+        assert_eq!(bps.hit_by(&exit(DB_VECTOR, 0x1000, 0xffff_4ff0)), None);
+    }
+}
@@ -0,0 +1,392 @@
+//! Collect everything about a vmsh session into a tarball for bug reports, see `vmsh bundle`.
+//!
+//! The bundle is meant for attaches that failed, so nothing in it is required: what cannot be
+//! collected, i.e. because the vm is gone as well, is noted in `errors.txt` instead. It contains
+//!
+//! - `session`: the session file of the vmsh process, see `gc`
+//! - `timeline.txt`: when the hypervisor and vmsh started and what vmsh recorded in which order
+//! - `inspect.txt` and `stats.txt`: `vmsh inspect` and `vmsh stats` of the vm
+//! - `process.txt`: status and threads of the vmsh process, if it still runs
+//! - `environment.txt`: vmsh version, host kernel and kvm module parameters
+//! - `dmesg.txt`: the kernel log of the host, where kvm reports problems
+//! - `logs/`: log files passed with `--log`, vmsh itself only logs to stderr
+//!
+//! The tarball is created with `tar`, so it can be unpacked anywhere.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::coredump;
+use crate::gc::{self, Artifact};
+use crate::result::Result;
+
+pub struct BundleOptions {
+    /// pid of the vmsh process or path of its session file
+    pub session: String,
+    pub output: Option<PathBuf>,
+    /// Log files to include, i.e. the redirected stderr of `vmsh attach`.
+    pub logs: Vec<PathBuf>,
+}
+
+/// Session file and vmsh pid of `session`, which is either the pid or the path of the file.
+fn session_file(session: &str) -> Result<(PathBuf, Pid)> {
+    if let Ok(pid) = session.parse::<i32>() {
+        let pid = Pid::from_raw(pid);
+        return Ok((gc::session_path(pid)?, pid));
+    }
+    let path = PathBuf::from(session);
+    let pid = require_with!(
+        gc::session_pid(&path),
+        "{} is not a session file, expected <pid>.session",
+        session
+    );
+    Ok((path, pid))
+}
+
+/// Start time of a process in seconds since boot from the content of /proc/<pid>/stat.
+fn parse_start_time(stat: &str, ticks_per_second: u64) -> Option<f64> {
+    // the command name might contain spaces and parentheses
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    // starttime is field 22, the fields after the command name start with field 3
+    let ticks: u64 = fields.get(22 - 3)?.parse().ok()?;
+    Some(ticks as f64 / ticks_per_second as f64)
+}
+
+/// Boot time of the host in seconds since the epoch from the content of /proc/stat.
+fn parse_boot_time(stat: &str) -> Option<f64> {
+    stat.lines()
+        .find_map(|l| l.strip_prefix("btime "))
+        .and_then(|t| t.trim().parse().ok())
+}
+
+/// Start time of a running process in seconds since the epoch.
+fn process_start(pid: Pid) -> Option<f64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let boot = parse_boot_time(&fs::read_to_string("/proc/stat").ok()?)?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return None;
+    }
+    Some(boot + parse_start_time(&stat, ticks as u64)?)
+}
+
+/// Events with a time are printed with it, the artifacts only have an order: they were
+/// recorded between the start of vmsh and the last write of the session file.
+struct Timeline {
+    vmsh: Pid,
+    hypervisor: Option<Pid>,
+    hypervisor_start: Option<f64>,
+    vmsh_start: Option<f64>,
+    artifacts: Vec<Artifact>,
+    last_write: Option<f64>,
+    now: f64,
+}
+
+impl Timeline {
+    fn format(&self) -> String {
+        let stamp = |time: Option<f64>| match time {
+            Some(t) => format!("{:>16.3}", t),
+            None => format!("{:>16}", "?"),
+        };
+        let mut out = String::new();
+        if let Some(hypervisor) = self.hypervisor {
+            let _ = writeln!(
+                out,
+                "{} hypervisor {} {}",
+                stamp(self.hypervisor_start),
+                hypervisor,
+                if self.hypervisor_start.is_some() {
+                    "started"
+                } else {
+                    "is not running"
+                }
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{} vmsh {} {}",
+            stamp(self.vmsh_start),
+            self.vmsh,
+            if self.vmsh_start.is_some() {
+                "started"
+            } else {
+                "is not running, it crashed or was killed"
+            }
+        );
+        for artifact in &self.artifacts {
+            let _ = writeln!(out, "{:>16} recorded {}", "", artifact.to_line());
+        }
+        let _ = writeln!(out, "{} session file last written", stamp(self.last_write));
+        let _ = writeln!(out, "{} bundle created", stamp(Some(self.now)));
+        out
+    }
+}
+
+fn timeline(vmsh: Pid, session: &Path, artifacts: Vec<Artifact>) -> Timeline {
+    let hypervisor = artifacts.iter().find_map(|a| match a {
+        Artifact::Hypervisor(pid) => Some(*pid),
+        _ => None,
+    });
+    Timeline {
+        vmsh,
+        hypervisor,
+        hypervisor_start: hypervisor.and_then(process_start),
+        vmsh_start: process_start(vmsh),
+        artifacts,
+        last_write: fs::metadata(session)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|t| coredump::since_epoch(t).as_secs_f64()),
+        now: coredump::since_epoch(SystemTime::now()).as_secs_f64(),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    Ok(try_with!(
+        fs::read_to_string(path),
+        "cannot read {}",
+        path.display()
+    ))
+}
+
+/// pid of the hypervisor as argument for vmsh commands, if it still runs.
+fn running_hypervisor(timeline: &Timeline) -> Result<String> {
+    match timeline.hypervisor {
+        Some(pid) if timeline.hypervisor_start.is_some() => Ok(pid.to_string()),
+        Some(pid) => bail!("hypervisor {} is not running anymore", pid),
+        None => bail!("the session file names no hypervisor"),
+    }
+}
+
+/// Output of a command with the command line in front, stdout and stderr together.
+fn run(cmd: &mut Command) -> Result<String> {
+    let output = try_with!(cmd.output(), "cannot run {:?}", cmd);
+    let mut out = format!("$ {:?}\n", cmd);
+    out.push_str(&String::from_utf8_lossy(&output.stdout));
+    out.push_str(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        let _ = writeln!(out, "{:?} failed: {}", cmd, output.status);
+    }
+    Ok(out)
+}
+
+fn run_vmsh(args: &[&str]) -> Result<String> {
+    let exe = try_with!(std::env::current_exe(), "cannot find vmsh executable");
+    run(Command::new(exe).args(args))
+}
+
+fn process_info(pid: Pid) -> Result<String> {
+    let dir = PathBuf::from(format!("/proc/{}", pid));
+    let mut out = try_with!(
+        fs::read_to_string(dir.join("status")),
+        "vmsh process {} is not running",
+        pid
+    );
+    out.push_str("\nthreads:\n");
+    let tasks = try_with!(fs::read_dir(dir.join("task")), "cannot list threads");
+    for task in tasks.flatten() {
+        let comm = fs::read_to_string(task.path().join("comm")).unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "{} {}",
+            task.file_name().to_string_lossy(),
+            comm.trim_end()
+        );
+    }
+    Ok(out)
+}
+
+fn environment() -> String {
+    let mut out = format!("vmsh {}\n", env!("CARGO_PKG_VERSION"));
+    for file in &["/proc/version", "/proc/cmdline"] {
+        let _ = write!(
+            out,
+            "{}: {}",
+            file,
+            fs::read_to_string(file).unwrap_or_else(|e| format!("{}\n", e))
+        );
+    }
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    if let Some(model) = cpuinfo.lines().find(|l| l.starts_with("model name")) {
+        let _ = writeln!(out, "{}", model);
+    }
+    let _ = writeln!(out, "euid: {}", nix::unistd::geteuid());
+    for module in &["kvm", "kvm_intel", "kvm_amd"] {
+        let dir = PathBuf::from(format!("/sys/module/{}/parameters", module));
+        let params = match fs::read_dir(&dir) {
+            Ok(params) => params,
+            Err(_) => continue,
+        };
+        let mut params = params.flatten().map(|p| p.path()).collect::<Vec<_>>();
+        params.sort();
+        let _ = writeln!(out, "\n{} parameters:", module);
+        for param in params {
+            let value = fs::read_to_string(&param).unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "{} = {}",
+                param.file_name().unwrap_or_default().to_string_lossy(),
+                value.trim_end()
+            );
+        }
+    }
+    out
+}
+
+/// Writes the files of the bundle to `dir`. Returns what could not be collected.
+fn collect(opts: &BundleOptions, dir: &Path, session: &Path, vmsh: Pid) -> Result<Vec<String>> {
+    let mut errors = vec![];
+    let mut add = |name: &str, content: Result<String>| {
+        let content = match content {
+            Ok(content) => content,
+            Err(e) => {
+                errors.push(format!("{}: {}", name, e));
+                return;
+            }
+        };
+        if let Err(e) = fs::write(dir.join(name), content) {
+            errors.push(format!("{}: {}", name, e));
+        }
+    };
+
+    let content = read_file(session);
+    let artifacts = match &content {
+        Ok(content) => gc::parse_session(content).unwrap_or_else(|e| {
+            warn!("invalid {}: {}", session.display(), e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    };
+    add("session", content);
+    let timeline = timeline(vmsh, session, artifacts);
+    add("timeline.txt", Ok(timeline.format()));
+
+    add(
+        "inspect.txt",
+        running_hypervisor(&timeline)
+            .and_then(|pid| run_vmsh(&["inspect", &pid, "--msrs", "--cpuid"])),
+    );
+    add(
+        "stats.txt",
+        running_hypervisor(&timeline).and_then(|pid| run_vmsh(&["stats", &pid])),
+    );
+    add("process.txt", process_info(vmsh));
+    add("environment.txt", Ok(environment()));
+    add("dmesg.txt", run(&mut Command::new("dmesg")));
+
+    if !opts.logs.is_empty() {
+        try_with!(
+            fs::create_dir(dir.join("logs")),
+            "cannot create logs directory"
+        );
+    }
+    for (i, log) in opts.logs.iter().enumerate() {
+        let name = log
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("log"));
+        // prefix with the index, logs of different directories might have the same name
+        let target = dir.join("logs").join(format!("{}-{}", i, name));
+        if let Err(e) = fs::copy(log, &target) {
+            errors.push(format!("{}: {}", log.display(), e));
+        }
+    }
+    Ok(errors)
+}
+
+pub fn bundle(opts: &BundleOptions) -> Result<()> {
+    let (session, vmsh) = session_file(&opts.session)?;
+    let name = format!("vmsh-bundle-{}", vmsh);
+    let output = opts
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{}.tar.gz", name)));
+
+    let staging = std::env::temp_dir().join(format!("vmsh-bundle.{}", std::process::id()));
+    let dir = staging.join(&name);
+    try_with!(fs::create_dir_all(&dir), "cannot create {}", dir.display());
+    let res = collect(opts, &dir, &session, vmsh).and_then(|errors| {
+        for e in &errors {
+            warn!("not included: {}", e);
+        }
+        if !errors.is_empty() {
+            let mut content = errors.join("\n");
+            content.push('\n');
+            try_with!(
+                fs::write(dir.join("errors.txt"), content),
+                "cannot write errors.txt"
+            );
+        }
+        let status = try_with!(
+            Command::new("tar")
+                .arg("-czf")
+                .arg(&output)
+                .arg("-C")
+                .arg(&staging)
+                .arg(&name)
+                .status(),
+            "cannot run tar"
+        );
+        if !status.success() {
+            bail!("tar failed: {}", status);
+        }
+        Ok(())
+    });
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        warn!("cannot remove {}: {}", staging.display(), e);
+    }
+    res?;
+    info!("wrote {}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_time() {
+        let stat = "1234 (qemu (vm) 1) S 1 1234 1234 0 -1 4194560 5 0 0 0 1 2 0 0 20 0 4 0 \
+                    12345 1000 200 18446744073709551615";
+        assert_eq!(parse_start_time(stat, 100), Some(123.45));
+        assert_eq!(parse_start_time("1234 (qemu) S 1", 100), None);
+        assert_eq!(
+            parse_boot_time("cpu  1 2 3\nbtime 1700000000\nprocesses 5\n"),
+            Some(1_700_000_000.0)
+        );
+    }
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline {
+            vmsh: Pid::from_raw(20),
+            hypervisor: Some(Pid::from_raw(10)),
+            hypervisor_start: Some(100.0),
+            vmsh_start: None,
+            artifacts: vec![
+                Artifact::Hypervisor(Pid::from_raw(10)),
+                Artifact::Irq {
+                    device: String::from("block"),
+                    gsi: 5,
+                },
+            ],
+            last_write: Some(150.5),
+            now: 200.0,
+        };
+        let expected = "         100.000 hypervisor 10 started
+               ? vmsh 20 is not running, it crashed or was killed
+                 recorded hypervisor 10
+                 recorded irq block 5
+         150.500 session file last written
+         200.000 bundle created
+";
+        assert_eq!(timeline.format(), expected);
+    }
+}
@@ -0,0 +1,34 @@
+//! Little endian integers in raw structures read from the guest or from KVM. vmsh only runs
+//! x86_64 guests on x86_64 hosts, so this is also the native byte order.
+
+pub fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn u64_at(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset.checked_add(8)?)?;
+    let mut le = [0u8; 8];
+    le.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(le))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_int_at() {
+        let buf = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(u16_at(&buf, 1), Some(0x0302));
+        assert_eq!(u32_at(&buf, 0), Some(0x0403_0201));
+        assert_eq!(u64_at(&buf, 1), Some(0x0908_0706_0504_0302));
+        assert_eq!(u32_at(&buf, 6), None);
+        assert_eq!(u64_at(&buf, usize::MAX), None);
+    }
+}
@@ -0,0 +1,52 @@
+//! Find certificates and tokens in the guest that expired or expire soon by running stage2 in
+//! its certificate scan mode.
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::attach::{self, AttachOptions};
+use crate::devices::virtio::block::CacheMode;
+use crate::devices::virtio::console::STOP_ON_SESSION_END;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+pub struct CertScanOptions {
+    pub pid: Pid,
+    /// Files and directories in the guest to scan, the usual locations if empty.
+    pub paths: Vec<String>,
+    /// Report certificates that expire within this many days.
+    pub warn_days: u32,
+    pub stage2_path: String,
+}
+
+pub fn cert_scan(opts: &CertScanOptions) -> Result<()> {
+    // results are printed by stage2 to the console, we are done once it exits
+    STOP_ON_SESSION_END.store(true, Ordering::Release);
+
+    let mut command = vec![
+        opts.stage2_path.clone(),
+        String::from("--cert-scan"),
+        opts.warn_days.to_string(),
+    ];
+    command.extend(opts.paths.iter().cloned());
+    attach::attach(&AttachOptions {
+        pid: opts.pid,
+        command,
+        backing: PathBuf::from("/dev/null"),
+        backing_format: None,
+        read_only: false,
+        cache: CacheMode::default(),
+        faults: None,
+        hotplug: None,
+        vsock: None,
+        share: None,
+        irq_affinity: vec![],
+        environment: vec![],
+        cwd: None,
+        interactive: true,
+        tty: None,
+        metrics_addr: None,
+        publish: vec![],
+        symbols: SymbolSource::Ksymtab,
+    })
+}
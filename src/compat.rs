@@ -0,0 +1,341 @@
+//! Remembers what host and guest offered every time vmsh attached to a vm, so that a later attach
+//! can tell what got lost in between, e.g. that the vm attached with ioregionfd last week but the
+//! host kernel was downgraded since.
+//!
+//! Each vm has a file `<id>.compat` in `DB_DIR` that starts with the header of `format::COMPAT`
+//! and has one line per successful attach: the unix time followed by `key=value` pairs, i.e.
+//! `host_kernel`, `guest_kernel`, `mmio` and `cap.<name>` with the KVM_CHECK_EXTENSION results as
+//! named by `vmsh inspect`. The pid of a vm changes when it is restarted, so vms are identified
+//! by a hash of the command line of their hypervisor instead.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::devices::use_ioregionfd;
+use crate::format::COMPAT;
+use crate::inspect::KVM_CAPABILITIES;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::sha256;
+use crate::tracer::proc::parse_kernel_release;
+use crate::vmi::KernelMemory;
+
+/// Kept across reboots of the host, unlike the session files of `gc`.
+const DB_DIR: &str = "/var/lib/vmsh/compat";
+
+pub struct CompatOptions {
+    pub pid: Pid,
+}
+
+/// Probe results of one attach.
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    /// Unix time of the attach
+    pub time: u64,
+    pub entries: Vec<(String, String)>,
+}
+
+impl Record {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = self.time.to_string();
+        for (key, value) in &self.entries {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+
+    fn parse_line(line: &str) -> Result<Record> {
+        let mut fields = line.split_whitespace();
+        let time = match fields.next().map(|t| t.parse()) {
+            Some(Ok(time)) => time,
+            _ => bail!("invalid time in line '{}'", line),
+        };
+        let mut entries = vec![];
+        for field in fields {
+            match field.split_once('=') {
+                Some((key, value)) => entries.push((key.to_string(), value.to_string())),
+                None => bail!("expected key=value, got '{}'", field),
+            }
+        }
+        Ok(Record { time, entries })
+    }
+}
+
+pub(crate) fn parse_records(content: &str) -> Result<Vec<Record>> {
+    let mut lines = content.lines();
+    match lines.next().and_then(|l| COMPAT.header_version(l)) {
+        Some(version) => COMPAT.check(version)?,
+        None => bail!("missing {} header", COMPAT.name),
+    }
+    lines
+        .filter(|l| !l.is_empty())
+        .map(Record::parse_line)
+        .collect()
+}
+
+/// Stays the same when the vm is restarted with the same command line.
+fn vm_id(pid: Pid) -> Result<String> {
+    let path = format!("/proc/{}/cmdline", pid);
+    let cmdline = try_with!(fs::read(&path), "cannot read {}", path);
+    Ok(sha256::to_hex(&sha256::digest(&cmdline))[..16].to_string())
+}
+
+fn db_path(pid: Pid) -> Result<PathBuf> {
+    Ok(Path::new(DB_DIR).join(format!("{}.compat", vm_id(pid)?)))
+}
+
+fn read_records(path: &Path) -> Result<Vec<Record>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(try_with!(
+            parse_records(&content),
+            "cannot parse {}",
+            path.display()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => bail!("cannot read {}: {}", path.display(), e),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Host kernel, guest kernel and KVM capabilities as seen by this attach.
+pub fn probe(vm: &Hypervisor) -> Record {
+    let mut entries = vec![(
+        String::from("host_kernel"),
+        nix::sys::utsname::uname().release().to_string(),
+    )];
+    match KernelMemory::new(vm).and_then(|kernel| kernel.release()) {
+        Ok(release) => entries.push((String::from("guest_kernel"), release)),
+        Err(e) => info!("cannot get guest kernel release: {}", e),
+    }
+    let mmio = if use_ioregionfd() {
+        "ioregionfd"
+    } else {
+        "wrap_syscall"
+    };
+    entries.push((String::from("mmio"), mmio.to_string()));
+    for (name, cap) in KVM_CAPABILITIES {
+        if let Ok(value) = vm.check_extension(*cap as i32) {
+            entries.push((format!("cap.{}", name), value.to_string()));
+        }
+    }
+    Record {
+        time: unix_now(),
+        entries,
+    }
+}
+
+fn downgrade(what: &str, old: Option<&str>, new: Option<&str>) -> Option<String> {
+    let (old, new) = (old?, new?);
+    if parse_kernel_release(new)? < parse_kernel_release(old)? {
+        Some(format!("{} was downgraded from {} to {}", what, old, new))
+    } else {
+        None
+    }
+}
+
+/// What `new` lacks compared to `old`: kernel downgrades and KVM capabilities that went away.
+pub fn regressions(old: &Record, new: &Record) -> Vec<String> {
+    let mut found = vec![];
+    found.extend(downgrade(
+        "host kernel",
+        old.get("host_kernel"),
+        new.get("host_kernel"),
+    ));
+    found.extend(downgrade(
+        "guest kernel",
+        old.get("guest_kernel"),
+        new.get("guest_kernel"),
+    ));
+    let available =
+        |value: Option<&str>| value.and_then(|v| v.parse::<i64>().ok()).unwrap_or(0) > 0;
+    for (key, value) in &old.entries {
+        let name = match key.strip_prefix("cap.") {
+            Some(name) => name,
+            None => continue,
+        };
+        if available(Some(value)) && !available(new.get(key)) {
+            let mut message = format!("KVM capability {} is no longer available", name);
+            if name == "ioregionfd" && old.get("mmio") == Some("ioregionfd") {
+                message.push_str(", --mmio ioregionfd will fail");
+            }
+            found.push(message);
+        }
+    }
+    found
+}
+
+/// Warns about everything that got lost since the last successful attach to the vm.
+pub fn check(pid: Pid, record: &Record) -> Result<()> {
+    let path = db_path(pid)?;
+    let last = match read_records(&path)?.pop() {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    for regression in regressions(&last, record) {
+        warn!(
+            "{} since the last attach to this vm at {}",
+            regression,
+            format_time(last.time)
+        );
+    }
+    Ok(())
+}
+
+/// Adds `record` of a successful attach to the history of the vm.
+pub fn remember(pid: Pid, record: &Record) -> Result<()> {
+    let path = db_path(pid)?;
+    try_with!(fs::create_dir_all(DB_DIR), "cannot create {}", DB_DIR);
+    let exists = path.exists();
+    let mut file = try_with!(
+        OpenOptions::new().create(true).append(true).open(&path),
+        "cannot open {}",
+        path.display()
+    );
+    let mut content = String::new();
+    if !exists {
+        content.push_str(&COMPAT.text_header());
+        content.push('\n');
+    }
+    content.push_str(&record.to_line());
+    content.push('\n');
+    try_with!(
+        file.write_all(content.as_bytes()),
+        "cannot write {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC`
+fn format_time(time: u64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (time / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let secs = time % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Prints the attach history of a vm and what each attach lost compared to the one before.
+pub fn compat(opts: &CompatOptions) -> Result<()> {
+    let path = db_path(opts.pid)?;
+    let records = read_records(&path)?;
+    if records.is_empty() {
+        println!("vmsh never attached to this vm ({})", path.display());
+        return Ok(());
+    }
+    for (i, record) in records.iter().enumerate() {
+        println!(
+            "{}  host {}  guest {}  mmio {}",
+            format_time(record.time),
+            record.get("host_kernel").unwrap_or("-"),
+            record.get("guest_kernel").unwrap_or("-"),
+            record.get("mmio").unwrap_or("-")
+        );
+        if i > 0 {
+            for regression in regressions(&records[i - 1], record) {
+                println!("  regression: {}", regression);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: u64, entries: &[(&str, &str)]) -> Record {
+        Record {
+            time,
+            entries: entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let old = record(
+            1_760_000_000,
+            &[("host_kernel", "6.1.0-13-amd64"), ("cap.irqfd", "1")],
+        );
+        let content = format!("{}\n{}\n", COMPAT.text_header(), old.to_line());
+        assert_eq!(parse_records(&content).unwrap(), vec![old]);
+        assert!(parse_records("1760000000 mmio=ioregionfd\n").is_err());
+        let content = format!("{}\n1760000000 mmio\n", COMPAT.text_header());
+        assert!(parse_records(&content).is_err());
+    }
+
+    #[test]
+    fn test_regressions() {
+        let old = record(
+            0,
+            &[
+                ("host_kernel", "6.1.0"),
+                ("guest_kernel", "5.10.0"),
+                ("mmio", "ioregionfd"),
+                ("cap.ioregionfd", "1"),
+                ("cap.irqfd", "1"),
+                ("cap.signal_msi", "0"),
+            ],
+        );
+        let new = record(
+            1,
+            &[
+                ("host_kernel", "5.15.0"),
+                ("guest_kernel", "5.10.1"),
+                ("mmio", "wrap_syscall"),
+                ("cap.ioregionfd", "0"),
+                ("cap.signal_msi", "1"),
+            ],
+        );
+        assert_eq!(
+            regressions(&old, &new),
+            vec![
+                "host kernel was downgraded from 6.1.0 to 5.15.0",
+                "KVM capability ioregionfd is no longer available, --mmio ioregionfd will fail",
+                "KVM capability irqfd is no longer available",
+            ]
+        );
+        assert!(regressions(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951_827_696), "2000-02-29 12:34:56 UTC");
+    }
+}
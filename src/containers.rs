@@ -0,0 +1,368 @@
+//! List the containers of a guest from the cgroups of its processes, see `vmsh containers`.
+//!
+//! Container runtimes put each container into a cgroup of its own that is named after the
+//! container. The cgroup of every task is read from the guest kernel and matched against the
+//! names docker, podman, containerd (also as used by kubernetes), cri-o and lxc use with the
+//! systemd and the cgroupfs driver:
+//!
+//! ```text
+//! /system.slice/docker-<id>.scope      /docker/<id>
+//! /machine.slice/libpod-<id>.scope     /kubepods/besteffort/pod<uid>/<id>
+//! .../cri-containerd-<id>.scope        .../crio-<id>.scope
+//! /lxc.payload.<name>                  /lxc/<name>
+//! ```
+//!
+//! Nested containers are reported as part of the outermost one. The init pid of a container is
+//! its first process whose parent is outside of the container, pids are those of the initial pid
+//! namespace. Besides the offsets `vmsh ps` needs, the profile (see `vmi`) needs:
+//!
+//! ```text
+//! task_struct.cgroups 0xb38
+//! css_set.dfl_cgrp 0x88
+//! cgroup.kn 0x100
+//! kernfs_node.name 0x10
+//! # linux >= 6.15 calls it kernfs_node.__parent
+//! kernfs_node.parent 0x8
+//! # optional, for guests that only use cgroup v1
+//! css_set.subsys 0x0
+//! cgroup_subsys_state.cgroup 0x0
+//! ```
+use simple_error::{bail, require_with, try_with};
+use std::path::PathBuf;
+
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::ps;
+use crate::result::Result;
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct ContainersOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+    /// Print full container ids instead of their first `SHORT_ID_LEN` characters
+    pub no_trunc: bool,
+}
+
+const NAME_MAX: usize = 255;
+/// Stop walking cgroup paths that do not reach the root.
+const MAX_DEPTH: usize = 64;
+/// Like `docker ps`
+const SHORT_ID_LEN: usize = 12;
+
+/// Container scopes of the systemd cgroup driver: `<prefix><id>.scope`
+const SCOPE_PREFIXES: &[(&str, &str)] = &[
+    ("docker-", "docker"),
+    ("libpod-", "podman"),
+    ("cri-containerd-", "containerd"),
+    ("crio-", "cri-o"),
+];
+
+/// What the name of a cgroup tells about a container
+#[derive(Debug, PartialEq)]
+struct CgroupMatch {
+    runtime: &'static str,
+    id: String,
+    /// Cgroup of the container, its processes might be in cgroups below it
+    cgroup: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Container {
+    pub runtime: &'static str,
+    /// Container id, the name of the container for lxc
+    pub id: String,
+    pub cgroup: String,
+    pub init_pid: i32,
+    /// All processes of the container, in the order of the task list
+    pub pids: Vec<i32>,
+}
+
+fn is_hex_id(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Runtime and id of a container named `name` in the cgroup `parent`.
+fn match_component(parent: Option<&str>, name: &str) -> Option<(&'static str, String)> {
+    if let Some(scope) = name.strip_suffix(".scope") {
+        // the conmon scopes of podman and cri-o do not end with an id
+        return SCOPE_PREFIXES.iter().find_map(|(prefix, runtime)| {
+            scope
+                .strip_prefix(prefix)
+                .filter(|id| is_hex_id(id))
+                .map(|id| (*runtime, id.to_string()))
+        });
+    }
+    if let Some(name) = name.strip_prefix("lxc.payload.") {
+        return Some(("lxc", name.to_string()));
+    }
+    match parent {
+        Some("docker") if is_hex_id(name) => Some(("docker", name.to_string())),
+        Some("libpod_parent") => name
+            .strip_prefix("libpod-")
+            .filter(|id| is_hex_id(id))
+            .map(|id| ("podman", id.to_string())),
+        Some("lxc") => Some(("lxc", name.to_string())),
+        Some(pod) if pod.starts_with("pod") && is_hex_id(name) => {
+            Some(("kubernetes", name.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// The outermost container in the cgroup `path`, i.e. `/system.slice/docker-<id>.scope`.
+fn match_cgroup(path: &str) -> Option<CgroupMatch> {
+    let names = path
+        .split('/')
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<_>>();
+    names.iter().enumerate().find_map(|(i, name)| {
+        let parent = i.checked_sub(1).map(|p| names[p]);
+        match_component(parent, name).map(|(runtime, id)| CgroupMatch {
+            runtime,
+            id,
+            cgroup: format!("/{}", names[..=i].join("/")),
+        })
+    })
+}
+
+/// Groups processes given as (container, pid, ppid) in task list order by container.
+fn group_containers(members: Vec<(CgroupMatch, i32, Option<i32>)>) -> Vec<Container> {
+    let mut containers: Vec<(Container, Vec<Option<i32>>)> = vec![];
+    for (m, pid, ppid) in members {
+        match containers
+            .iter_mut()
+            .find(|(c, _)| c.runtime == m.runtime && c.id == m.id)
+        {
+            Some((container, ppids)) => {
+                container.pids.push(pid);
+                ppids.push(ppid);
+            }
+            None => containers.push((
+                Container {
+                    runtime: m.runtime,
+                    id: m.id,
+                    cgroup: m.cgroup,
+                    init_pid: pid,
+                    pids: vec![pid],
+                },
+                vec![ppid],
+            )),
+        }
+    }
+    containers
+        .into_iter()
+        .map(|(mut container, ppids)| {
+            let outside = container
+                .pids
+                .iter()
+                .zip(ppids.iter())
+                .find(|(_, ppid)| ppid.map_or(false, |ppid| !container.pids.contains(&ppid)));
+            if let Some((pid, _)) = outside {
+                container.init_pid = *pid;
+            }
+            container
+        })
+        .collect()
+}
+
+/// The container with the id `id` or the only one whose id starts with it, as `docker` accepts
+/// abbreviated ids.
+pub fn find_container<'a>(containers: &'a [Container], id: &str) -> Result<&'a Container> {
+    if let Some(container) = containers.iter().find(|c| c.id == id) {
+        return Ok(container);
+    }
+    let mut matches = containers.iter().filter(|c| c.id.starts_with(id));
+    match (matches.next(), matches.next()) {
+        (Some(container), None) => Ok(container),
+        (Some(_), Some(_)) => bail!("container id {} is ambiguous", id),
+        (None, _) => bail!("no container {} in the guest", id),
+    }
+}
+
+/// Path of the cgroup at `cgroup` from the names of its kernfs nodes.
+fn cgroup_path(k: &KernelMemory, p: &Profile, cgroup: usize) -> Result<String> {
+    let parent_offset = require_with!(
+        p.optional("kernfs_node.parent")
+            .or_else(|| p.optional("kernfs_node.__parent")),
+        "profile has no offset for kernfs_node.parent"
+    );
+    let name_offset = p.get("kernfs_node.name")?;
+    let mut names = vec![];
+    let mut kn = k.read_ptr(cgroup + p.get("cgroup.kn")?)?;
+    loop {
+        let parent = k.read_ptr(kn + parent_offset)?;
+        // the root of the hierarchy is not part of the path
+        if parent == 0 {
+            break;
+        }
+        if names.len() == MAX_DEPTH {
+            bail!(
+                "cgroup at {:#x} is nested too deep, is the offset of kernfs_node.parent right?",
+                cgroup
+            );
+        }
+        names.push(k.read_str(k.read_ptr(kn + name_offset)?, NAME_MAX)?);
+        kn = parent;
+    }
+    names.reverse();
+    Ok(format!("/{}", names.join("/")))
+}
+
+/// Cgroup of `task` in the unified hierarchy. If that is the root and the profile allows it, the
+/// cgroup in the hierarchy of the first v1 controller instead.
+fn task_cgroup(k: &KernelMemory, p: &Profile, task: usize) -> Result<String> {
+    let cset = k.read_ptr(task + p.get("task_struct.cgroups")?)?;
+    let path = cgroup_path(k, p, k.read_ptr(cset + p.get("css_set.dfl_cgrp")?)?)?;
+    if path != "/" {
+        return Ok(path);
+    }
+    match (
+        p.optional("css_set.subsys"),
+        p.optional("cgroup_subsys_state.cgroup"),
+    ) {
+        (Some(subsys), Some(cgroup)) => match k.read_ptr(cset + subsys)? {
+            0 => Ok(path),
+            css => cgroup_path(k, p, k.read_ptr(css + cgroup)?),
+        },
+        _ => Ok(path),
+    }
+}
+
+/// Containers in the order their first process appears in the task list.
+pub fn containers(k: &KernelMemory, p: &Profile) -> Result<Vec<Container>> {
+    let mut members = vec![];
+    for process in ps::processes(k, p)? {
+        if process.kernel_thread {
+            continue;
+        }
+        let cgroup = try_with!(
+            task_cgroup(k, p, process.task),
+            "cannot read cgroup of pid {}",
+            process.pid
+        );
+        if let Some(m) = match_cgroup(&cgroup) {
+            members.push((m, process.pid, process.ppid));
+        }
+    }
+    Ok(group_containers(members))
+}
+
+fn print_containers(containers: &[Container], no_trunc: bool) {
+    println!(
+        "{:<12} {:<12} {:>7} {:>5} CGROUP",
+        "RUNTIME", "CONTAINER ID", "INIT", "TASKS"
+    );
+    for c in containers {
+        let id = if no_trunc || c.runtime == "lxc" {
+            c.id.as_str()
+        } else {
+            &c.id[..std::cmp::min(SHORT_ID_LEN, c.id.len())]
+        };
+        println!(
+            "{:<12} {:<12} {:>7} {:>5} {}",
+            c.runtime,
+            id,
+            c.init_pid,
+            c.pids.len(),
+            c.cgroup
+        );
+    }
+}
+
+fn containers_guest(src: &dyn GuestAccess, opts: &ContainersOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = Profile::load_for_kernel(&opts.profile, &k)?;
+    print_containers(&containers(&k, &p)?, opts.no_trunc);
+    Ok(())
+}
+
+pub fn list_containers(opts: &ContainersOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            containers_guest(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            containers_guest(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f2a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8";
+
+    fn matched(runtime: &'static str, id: &str, cgroup: &str) -> Option<CgroupMatch> {
+        Some(CgroupMatch {
+            runtime,
+            id: id.to_string(),
+            cgroup: cgroup.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_match_cgroup() {
+        let docker = format!("/system.slice/docker-{}.scope", ID);
+        assert_eq!(
+            match_cgroup(&format!("{}/init.scope", docker)),
+            matched("docker", ID, &docker)
+        );
+        let docker = format!("/docker/{}", ID);
+        assert_eq!(match_cgroup(&docker), matched("docker", ID, &docker));
+        let pod = format!(
+            "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1234.slice/cri-containerd-{}.scope",
+            ID
+        );
+        assert_eq!(match_cgroup(&pod), matched("containerd", ID, &pod));
+        let pod = format!("/kubepods/besteffort/pod8d2c-11ee/{}", ID);
+        assert_eq!(match_cgroup(&pod), matched("kubernetes", ID, &pod));
+        let podman = format!("/machine.slice/libpod-{}.scope/container", ID);
+        assert_eq!(
+            match_cgroup(&podman),
+            matched("podman", ID, &format!("/machine.slice/libpod-{}.scope", ID))
+        );
+        assert_eq!(
+            match_cgroup("/lxc.payload.web/system.slice"),
+            matched("lxc", "web", "/lxc.payload.web")
+        );
+        assert_eq!(
+            match_cgroup(&format!("/machine.slice/libpod-conmon-{}.scope", ID)),
+            None
+        );
+        assert_eq!(
+            match_cgroup("/user.slice/user-1000.slice/session-1.scope"),
+            None
+        );
+        assert_eq!(match_cgroup("/"), None);
+    }
+
+    #[test]
+    fn test_group_containers() {
+        let docker = || matched("docker", ID, "/docker/x").unwrap();
+        let lxc = || matched("lxc", "web", "/lxc.payload.web").unwrap();
+        let containers = group_containers(vec![
+            (docker(), 120, Some(110)),
+            (lxc(), 130, Some(1)),
+            (docker(), 100, Some(90)),
+            (docker(), 110, Some(100)),
+            (lxc(), 131, Some(130)),
+        ]);
+        assert_eq!(containers.len(), 2);
+        assert_eq!(containers[0].pids, vec![120, 100, 110]);
+        assert_eq!(containers[0].init_pid, 100);
+        assert_eq!(containers[1].init_pid, 130);
+
+        assert_eq!(find_container(&containers, "3f2a").unwrap().init_pid, 100);
+        assert_eq!(find_container(&containers, "web").unwrap().init_pid, 130);
+        assert!(find_container(&containers, "4").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Reader for coredumps written by `vmsh coredump`, used to run introspection offline.
+use kvm_bindings as kvmb;
+use libc::{c_void, PT_LOAD, PT_NOTE};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use simple_error::{bail, require_with, try_with};
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::prelude::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::{ptr, slice};
+
+use crate::coredump::core_user;
+use crate::cpu::Regs;
+use crate::dedup::{self, DedupPage, PageStore};
+use crate::elf::{
+    elf_prstatus, Ehdr, Nhdr, Phdr, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ET_CORE, NT_PRSTATUS,
+    NT_PRXREG, PF_W, PF_X,
+};
+use crate::guest_access::GuestAccess;
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+struct CoreVcpu {
+    regs: Regs,
+    sregs: Option<kvmb::kvm_sregs>,
+}
+
+/// A coredump mapped privately into our address space. Host addresses of its mappings point
+/// into this mapping. Pages of deduplicated coredumps are restored from their page store.
+pub struct CoreFile {
+    path: PathBuf,
+    base: *mut c_void,
+    len: usize,
+    maps: Vec<Mapping>,
+    vcpus: Vec<CoreVcpu>,
+}
+
+fn protection_flags(flags: u32) -> ProtFlags {
+    // coredump writes PF_X for readable segments
+    let mut f = ProtFlags::PROT_READ;
+    if flags & PF_W != 0 {
+        f |= ProtFlags::PROT_WRITE;
+    }
+    if flags & PF_X != 0 {
+        f |= ProtFlags::PROT_EXEC;
+    }
+    f
+}
+
+fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+impl CoreFile {
+    pub fn open(path: &Path) -> Result<CoreFile> {
+        let file = try_with!(File::open(path), "cannot open {}", path.display());
+        let meta = try_with!(file.metadata(), "cannot stat {}", path.display());
+        let len = meta.len() as usize;
+        if len < size_of::<Ehdr>() {
+            bail!("{} is too small to be a coredump", path.display());
+        }
+        let dedup = dedup::read_index(path)?;
+        // restored pages are written into a copy-on-write mapping, the file stays untouched
+        let prot = if dedup.is_some() {
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+        } else {
+            ProtFlags::PROT_READ
+        };
+        let base = try_with!(
+            unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    len,
+                    prot,
+                    MapFlags::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            },
+            "cannot mmap {}",
+            path.display()
+        );
+        let mut core = CoreFile {
+            path: path.to_path_buf(),
+            base,
+            len,
+            maps: vec![],
+            vcpus: vec![],
+        };
+        core.parse()?;
+        if let Some((store, pages)) = dedup {
+            core.restore_pages(&store, &pages)?;
+        }
+        Ok(core)
+    }
+
+    fn restore_pages(&self, store: &PageStore, pages: &[DedupPage]) -> Result<()> {
+        for page in pages {
+            let m = require_with!(
+                self.maps
+                    .iter()
+                    .find(|m| m.phys_addr <= page.phys_addr && page.phys_addr < m.phys_end()),
+                "deduplicated page {:#x} is not part of {}",
+                page.phys_addr,
+                self.path.display()
+            );
+            let addr = m.start + (page.phys_addr - m.phys_addr);
+            if addr + page_size() > m.end {
+                bail!("deduplicated page {:#x} is truncated", page.phys_addr);
+            }
+            let buf = unsafe { slice::from_raw_parts_mut(addr as *mut u8, page_size()) };
+            store.read(&page.digest, buf)?;
+        }
+        Ok(())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.base as *const u8, self.len) }
+    }
+
+    fn read_at<T: Copy>(&self, offset: usize) -> Result<T> {
+        match offset.checked_add(size_of::<T>()) {
+            Some(end) if end <= self.len => {}
+            _ => bail!(
+                "{}: offset {:#x} is out of bounds",
+                self.path.display(),
+                offset
+            ),
+        }
+        Ok(unsafe { ptr::read_unaligned(self.bytes()[offset..].as_ptr() as *const T) })
+    }
+
+    fn parse(&mut self) -> Result<()> {
+        let ehdr: Ehdr = self.read_at(0)?;
+        if ehdr.e_ident[..4] != [ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3] || ehdr.e_type != ET_CORE {
+            bail!("{} is not an elf coredump", self.path.display());
+        }
+        for i in 0..ehdr.e_phnum as usize {
+            let phdr: Phdr = self.read_at(ehdr.e_phoff as usize + i * size_of::<Phdr>())?;
+            let offset = phdr.p_offset as usize;
+            let size = phdr.p_filesz as usize;
+            if offset.checked_add(size).map_or(true, |end| end > self.len) {
+                bail!(
+                    "segment {} of {} is truncated: {:#x}+{:#x} exceeds file size",
+                    i,
+                    self.path.display(),
+                    offset,
+                    size
+                );
+            }
+            match phdr.p_type {
+                // memory left out by `vmsh coredump --skip-swapped`
+                PT_LOAD if size == 0 => {}
+                PT_LOAD => {
+                    let start = self.base as usize + offset;
+                    self.maps.push(Mapping {
+                        start,
+                        end: start + size,
+                        prot_flags: protection_flags(phdr.p_flags),
+                        map_flags: MapFlags::MAP_PRIVATE,
+                        offset: phdr.p_offset,
+                        major_dev: 0,
+                        minor_dev: 0,
+                        inode: 0,
+                        pathname: self.path.display().to_string(),
+                        phys_addr: phdr.p_paddr as usize,
+                    })
+                }
+                PT_NOTE => self.parse_notes(offset, size)?,
+                _ => {}
+            }
+        }
+        if self.maps.is_empty() {
+            bail!("{} contains no memory", self.path.display());
+        }
+        if self.vcpus.is_empty() {
+            bail!("{} contains no vcpu registers", self.path.display());
+        }
+        Ok(())
+    }
+
+    fn parse_notes(&mut self, start: usize, size: usize) -> Result<()> {
+        let mut offset = start;
+        while offset + size_of::<Nhdr>() <= start + size {
+            let nhdr: Nhdr = self.read_at(offset)?;
+            let desc = offset + size_of::<Nhdr>() + align4(nhdr.n_namesz as usize);
+            match nhdr.n_type {
+                NT_PRSTATUS => {
+                    let status: elf_prstatus = self.read_at(desc)?;
+                    let regs = unsafe { ptr::read(&status.pr_reg as *const _ as *const Regs) };
+                    self.vcpus.push(CoreVcpu { regs, sregs: None });
+                }
+                NT_PRXREG if nhdr.n_descsz as usize == size_of::<core_user>() => {
+                    let user: core_user = self.read_at(desc)?;
+                    let vcpu = require_with!(
+                        self.vcpus.get_mut(user.vcpu),
+                        "special registers for unknown vcpu {}",
+                        user.vcpu
+                    );
+                    vcpu.sregs = Some(user.sregs);
+                }
+                _ => {}
+            }
+            offset = desc + align4(nhdr.n_descsz as usize);
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CoreFile {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.base, self.len) };
+    }
+}
+
+impl GuestAccess for CoreFile {
+    fn read_bytes(&self, host_addr: usize, buf: &mut [u8]) -> Result<()> {
+        let start = self.base as usize;
+        let in_bounds = host_addr >= start
+            && host_addr
+                .checked_add(buf.len())
+                .map_or(false, |end| end <= start + self.len);
+        if !in_bounds {
+            bail!(
+                "address {:#x} is not part of {}",
+                host_addr,
+                self.path.display()
+            );
+        }
+        let offset = host_addr - start;
+        buf.copy_from_slice(&self.bytes()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn memory_maps(&self) -> Result<Vec<Mapping>> {
+        Ok(self.maps.clone())
+    }
+
+    fn vcpu_count(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    fn vcpu_regs(&self, idx: usize) -> Result<Regs> {
+        let vcpu = require_with!(self.vcpus.get(idx), "vcpu {} not found in coredump", idx);
+        Ok(vcpu.regs)
+    }
+
+    fn vcpu_sregs(&self, idx: usize) -> Result<kvmb::kvm_sregs> {
+        let vcpu = require_with!(self.vcpus.get(idx), "vcpu {} not found in coredump", idx);
+        Ok(*require_with!(
+            vcpu.sregs.as_ref(),
+            "no special registers for vcpu {} in coredump",
+            idx
+        ))
+    }
+}
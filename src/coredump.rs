@@ -1,41 +1,92 @@
 use crate::kvm::hypervisor::VCPU;
 use kvm_bindings as kvmb;
 use libc::{c_void, off_t, timeval, PT_LOAD, PT_NOTE};
+use log::{info, warn};
 use nix::sys::{
     mman::{mmap, MapFlags, ProtFlags},
     uio::{process_vm_readv, IoVec, RemoteIoVec},
 };
 use nix::unistd::Pid;
-use simple_error::try_with;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
+use simple_error::{bail, try_with};
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs::File, io::Write, ptr, slice::from_raw_parts_mut};
 use std::{mem::size_of, os::unix::prelude::AsRawFd};
 
 use crate::cpu::{FpuRegs, Regs};
+use crate::dedup::{self, PageStore};
 use crate::elf::{
     elf_prpsinfo, elf_prstatus, elf_siginfo, Ehdr, Elf_Addr, Elf_Half, Elf_Off, Elf_Word, Nhdr,
     Phdr, Shdr, ELFARCH, ELFCLASS, ELFDATA2, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELF_NGREG,
     ET_CORE, EV_CURRENT, NT_PRPSINFO, NT_PRSTATUS, NT_PRXREG, PF_W, PF_X, SHN_UNDEF,
 };
+use crate::encrypt::Encryptor;
+use crate::guest_mem::GuestMem;
+use crate::host_pressure::PressureMonitor;
+use crate::kdump::Vmcore;
+use crate::kernel::find_kernel;
 use crate::kvm::hypervisor::Hypervisor;
+use crate::manifest::{self, Provenance};
+use crate::pacing::Pacer;
 use crate::page_math::{page_align, page_size};
+use crate::pagemap::PageMap;
 use crate::result::Result;
+use crate::scrub::{self, ScrubOptions};
+use crate::signal_handler::Cancellation;
+use crate::symbolizer::{self, SymbolSource};
 use crate::{kvm, tracer::proc::Mapping};
 
 pub struct CoredumpOptions {
     pub pid: Pid,
     pub path: PathBuf,
+    /// Do not read swapped-out guest memory but record it as hole without file content.
+    pub skip_swapped: bool,
+    /// Store KSM pages in this page store instead of the coredump, see `dedup`.
+    pub dedup_store: Option<PathBuf>,
+    /// Encrypt the coredump for this age or gpg recipient, see `encrypt`.
+    pub encrypt_to: Option<String>,
+    /// Leave key material out of the coredump, see `scrub`.
+    pub scrub: Option<ScrubOptions>,
+    /// Let the guest run while its memory is copied and read only while it is idle, see `pacing`.
+    /// The memory in the coredump is not a consistent snapshot in this case.
+    pub adaptive: bool,
+    /// Pause reads while the pressure of the host is above this percentage, see `host_pressure`.
+    pub max_host_pressure: Option<f64>,
+    /// Write the kernel symbols the vcpus are executing next to the coredump, see `symbolizer`.
+    pub symbols: Option<SymbolSource>,
+    pub format: CoreFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreFormat {
+    /// Physical addresses and vcpu state for gdb and the `--core` option of vmsh commands.
+    Elf,
+    /// A vmcore for crash and makedumpfile, see `kdump`.
+    Kdump,
+}
+
+impl CoreFormat {
+    pub fn from_name(name: &str) -> Option<CoreFormat> {
+        match name {
+            "elf" => Some(CoreFormat::Elf),
+            "kdump" => Some(CoreFormat::Kdump),
+            _ => None,
+        }
+    }
 }
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct core_user {
-    vcpu: usize,
+    pub(crate) vcpu: usize,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    sregs: kvmb::kvm_sregs,
+    pub(crate) sregs: kvmb::kvm_sregs,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    msrs: [kvmb::kvm_msr_entry; 1],
+    pub(crate) msrs: [kvmb::kvm_msr_entry; 1],
 }
 
 fn protection_flags(f: &ProtFlags) -> Elf_Word {
@@ -54,16 +105,52 @@ fn protection_flags(f: &ProtFlags) -> Elf_Word {
     })
 }
 
-unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
+pub(crate) unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
     std::slice::from_raw_parts((p as *const T) as *const u8, size_of::<T>())
 }
 
+/// Maximum number of iovecs passed to process_vm_readv (IOV_MAX)
+const MAX_IOVECS: usize = 1024;
+
+/// Parts of `maps` that go into the coredump as (offset in the dumped memory, host address,
+/// length). Pages at the host addresses in `skip` are left out, `skip` must be sorted in the
+/// order of `maps`.
+fn dump_pieces(maps: &[Mapping], skip: &[usize]) -> Vec<(usize, usize, usize)> {
+    let mut pieces = vec![];
+    let mut buf_offset = 0;
+    let mut skip = skip.iter().peekable();
+    for m in maps {
+        let mut start = m.start;
+        while let Some(&&addr) = skip.peek() {
+            if addr < m.start || addr >= m.end {
+                break;
+            }
+            skip.next();
+            if addr > start {
+                pieces.push((buf_offset + start - m.start, start, addr - start));
+            }
+            start = addr + page_size();
+        }
+        if start < m.end {
+            pieces.push((buf_offset + start - m.start, start, m.end - start));
+        }
+        buf_offset += m.size();
+    }
+    pieces
+}
+
+/// Copy `maps` into the core file, except for pages at the host addresses in `skip`, which must
+/// be sorted in the order of `maps`.
 fn dump_mappings(
     pid: Pid,
     core_file: &mut File,
     core_size: off_t,
     file_offset: off_t,
     maps: &[Mapping],
+    skip: &[usize],
+    mut pacer: Option<&mut Pacer>,
+    prefetch: Option<&Prefetch>,
+    cancel: &Cancellation,
 ) -> Result<()> {
     let buf_size = core_size - file_offset;
     let res = unsafe {
@@ -77,25 +164,138 @@ fn dump_mappings(
         )
     };
     let raw_buf = try_with!(res, "cannot mmap core file");
-    let buf = unsafe { from_raw_parts_mut(raw_buf as *mut u8, buf_size as usize) };
+    let raw_buf = raw_buf as *mut u8;
 
-    let dst_iovs = vec![IoVec::from_mut_slice(buf)];
-    let src_iovs = maps
-        .iter()
-        .map(|m| RemoteIoVec {
-            base: m.start,
-            len: m.size(),
+    let pieces = dump_pieces(maps, skip);
+    // a paced dump reads small chunks, so that it can pause whenever the guest gets busy
+    let (pieces, iovecs) = match pacer {
+        Some(_) => (split_pieces(pieces, STREAM_CHUNK_SIZE), 1),
+        None => (split_pieces(pieces, CANCEL_CHUNK_SIZE), MAX_IOVECS),
+    };
+    let batches = batches(&pieces, iovecs, CANCEL_CHUNK_SIZE);
+    for (i, chunk) in batches.iter().enumerate() {
+        cancel.check()?;
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait_idle();
+        }
+        if let (Some(prefetch), Some(next)) = (prefetch, batches.get(i + 1)) {
+            for (_, base, len) in next.iter() {
+                prefetch.advise(*base, *len);
+            }
+        }
+        let dst_iovs = chunk
+            .iter()
+            .map(|(offset, _, len)| {
+                IoVec::from_mut_slice(unsafe { from_raw_parts_mut(raw_buf.add(*offset), *len) })
+            })
+            .collect::<Vec<_>>();
+        let src_iovs = chunk
+            .iter()
+            .map(|(_, base, len)| RemoteIoVec {
+                base: *base,
+                len: *len,
+            })
+            .collect::<Vec<_>>();
+
+        try_with!(
+            process_vm_readv(pid, dst_iovs.as_slice(), src_iovs.as_slice()),
+            "cannot read hypervisor memory"
+        );
+    }
+    Ok(())
+}
+
+/// Size of reads from the hypervisor when streaming a coredump.
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Memory copied between two cancellation points.
+const CANCEL_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Group `pieces` into runs of at most `max_iovecs` pieces with at most `max_bytes` bytes in
+/// total. A single piece larger than `max_bytes` forms its own run.
+fn batches(
+    pieces: &[(usize, usize, usize)],
+    max_iovecs: usize,
+    max_bytes: usize,
+) -> Vec<&[(usize, usize, usize)]> {
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut bytes = 0;
+    for (i, (_, _, len)) in pieces.iter().enumerate() {
+        if i > start && (i - start == max_iovecs || bytes + len > max_bytes) {
+            batches.push(&pieces[start..i]);
+            start = i;
+            bytes = 0;
+        }
+        bytes += len;
+    }
+    if start < pieces.len() {
+        batches.push(&pieces[start..]);
+    }
+    batches
+}
+
+/// Split pieces returned by `dump_pieces` into pieces of at most `max` bytes.
+fn split_pieces(pieces: Vec<(usize, usize, usize)>, max: usize) -> Vec<(usize, usize, usize)> {
+    pieces
+        .into_iter()
+        .flat_map(|(offset, base, len)| {
+            (0..len)
+                .step_by(max)
+                .map(move |pos| (offset + pos, base + pos, std::cmp::min(max, len - pos)))
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    try_with!(
-        process_vm_readv(pid, dst_iovs.as_slice(), src_iovs.as_slice()),
-        "cannot read hypervisor memory"
-    );
+fn write_zeros(out: &mut dyn Write, mut len: usize) -> Result<()> {
+    let zeros = [0u8; 4096];
+    while len > 0 {
+        let n = std::cmp::min(len, zeros.len());
+        try_with!(out.write_all(&zeros[..n]), "cannot write coredump");
+        len -= n;
+    }
     Ok(())
 }
 
-fn elf_header(phnum: Elf_Half) -> Ehdr {
+/// Like `dump_mappings`, but writes the memory sequentially to a stream.
+fn stream_mappings(
+    pid: Pid,
+    out: &mut dyn Write,
+    maps: &[Mapping],
+    skip: &[usize],
+    mut pacer: Option<&mut Pacer>,
+    prefetch: Option<&Prefetch>,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let total = maps.iter().map(|m| m.size()).sum::<usize>();
+    let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+    let mut written = 0;
+    let chunks = split_pieces(dump_pieces(maps, skip), STREAM_CHUNK_SIZE);
+    for (i, (offset, base, len)) in chunks.iter().enumerate() {
+        write_zeros(out, offset - written)?;
+        cancel.check()?;
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait_idle();
+        }
+        if let (Some(prefetch), Some((_, next_base, next_len))) = (prefetch, chunks.get(i + 1)) {
+            prefetch.advise(*next_base, *next_len);
+        }
+        let dst_iovs = [IoVec::from_mut_slice(&mut buf[..*len])];
+        let src_iovs = [RemoteIoVec {
+            base: *base,
+            len: *len,
+        }];
+        try_with!(
+            process_vm_readv(pid, &dst_iovs, &src_iovs),
+            "cannot read hypervisor memory"
+        );
+        try_with!(out.write_all(&buf[..*len]), "cannot write coredump");
+        written = offset + len;
+    }
+    write_zeros(out, total - written)
+}
+
+pub(crate) fn elf_header(phnum: Elf_Half) -> Ehdr {
     Ehdr {
         e_ident: [
             ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELFCLASS, ELFDATA2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -129,12 +329,13 @@ fn pt_note_header(core_size: Elf_Off, file_size: Elf_Off) -> Phdr {
     }
 }
 
-fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
+/// `page_offset` is the virtual address of physical address 0, see `kdump`.
+fn pt_load_header(m: &Mapping, offset: Elf_Off, page_offset: usize) -> Phdr {
     Phdr {
         p_type: PT_LOAD,
         p_flags: protection_flags(&m.prot_flags),
         p_offset: offset,
-        p_vaddr: m.phys_addr as Elf_Addr,
+        p_vaddr: (page_offset + m.phys_addr) as Elf_Addr,
         p_paddr: m.phys_addr as Elf_Addr,
         p_filesz: m.size() as Elf_Addr,
         p_memsz: m.size() as Elf_Addr,
@@ -142,52 +343,94 @@ fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
     }
 }
 
-fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload: &T) -> Result<()> {
+/// Memory that is part of the guest but not contained in the coredump.
+fn pt_load_hole_header(m: &Mapping, offset: Elf_Off, page_offset: usize) -> Phdr {
+    Phdr {
+        p_filesz: 0,
+        ..pt_load_header(m, offset, page_offset)
+    }
+}
+
+fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+/// `name` includes the terminating NUL. Name and `desc` are padded to 4 bytes, see `note_len`.
+fn write_note(core_file: &mut dyn Write, name: &[u8], ntype: Elf_Word, desc: &[u8]) -> Result<()> {
     let hdr = &Nhdr {
-        n_namesz: 5,
-        n_descsz: size_of::<T>() as Elf_Word,
+        n_namesz: name.len() as Elf_Word,
+        n_descsz: desc.len() as Elf_Word,
         n_type: ntype,
     };
     try_with!(
         core_file.write_all(unsafe { any_as_bytes(hdr) }),
         "cannot write elf note header"
     );
+    let padding = [0u8; 3];
+    try_with!(core_file.write_all(name), "cannot write note name");
     try_with!(
-        core_file.write_all(b"CORE\0\0\0\0"),
+        core_file.write_all(&padding[..align4(name.len()) - name.len()]),
         "cannot write note name"
     );
+    try_with!(core_file.write_all(desc), "cannot write elf note");
     try_with!(
-        core_file.write_all(unsafe { any_as_bytes(payload) }),
-        "cannot write elf note header"
+        core_file.write_all(&padding[..align4(desc.len()) - desc.len()]),
+        "cannot write elf note"
     );
     Ok(())
 }
 
+fn note_len(name_len: usize, desc_len: usize) -> usize {
+    size_of::<Nhdr>() + align4(name_len) + align4(desc_len)
+}
+
+fn write_note_section<T: Sized>(
+    core_file: &mut dyn Write,
+    ntype: Elf_Word,
+    payload: &T,
+) -> Result<()> {
+    write_note(core_file, b"CORE\0", ntype, unsafe {
+        any_as_bytes(payload)
+    })
+}
+
+/// NT_FPREGSET, the fxsave layout gdb expects on x86_64.
 #[cfg(target_arch = "x86_64")]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
-    use crate::elf::NT_PRXFPREG;
-    let hdr = &Nhdr {
-        n_namesz: 5,
-        n_descsz: size_of::<FpuRegs>() as Elf_Word,
-        n_type: NT_PRXFPREG,
-    };
-    try_with!(
-        core_file.write_all(unsafe { any_as_bytes(hdr) }),
-        "cannot write elf note header"
-    );
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
+    use crate::elf::NT_PRFPREG;
     try_with!(
-        core_file.write_all(b"LINUX\0\0\0"),
-        "cannot write note name"
+        write_note_section(core_file, NT_PRFPREG, regs),
+        "failed to write NT_FPREGSET"
     );
+    Ok(())
+}
+
+/// Offset of XCR0 in the software reserved bytes of the fxsave part of an XSAVE area. Linux
+/// stores it there in core dumps and gdb reads it to know which state components are valid.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const XSAVE_XCR0_OFFSET: usize = 464;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_xsave_xcr0(xsave: &mut kvmb::kvm_xsave, xcr0: u64) {
+    let idx = XSAVE_XCR0_OFFSET / size_of::<u32>();
+    xsave.region[idx] = xcr0 as u32;
+    xsave.region[idx + 1] = (xcr0 >> 32) as u32;
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_xstate(core_file: &mut dyn Write, xsave: &kvmb::kvm_xsave) -> Result<()> {
+    use crate::elf::NT_X86_XSTATE;
     try_with!(
-        core_file.write_all(unsafe { any_as_bytes(regs) }),
-        "cannot write elf note header"
+        write_note(core_file, b"LINUX\0", NT_X86_XSTATE, unsafe {
+            any_as_bytes(xsave)
+        }),
+        "failed to write NT_X86_XSTATE"
     );
     Ok(())
 }
 
 #[cfg(not(target_arch = "x86_64"))]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRFPREG;
     try_with!(
         write_note_section(
@@ -200,7 +443,43 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
     Ok(())
 }
 
-fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()> {
+fn write_prstatus(core_file: &mut dyn Write, i: usize, vcpu: &VcpuState) -> Result<()> {
+    let zero = timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let pr_reg = unsafe { ptr::read(&vcpu.regs as *const Regs as *const [u64; ELF_NGREG]) };
+    try_with!(
+        write_note_section(
+            core_file,
+            NT_PRSTATUS,
+            &elf_prstatus {
+                pr_info: elf_siginfo {
+                    si_signo: 0,
+                    si_code: 0,
+                    si_errno: 0,
+                },
+                pr_cursig: 0,
+                pr_sigpend: 0,
+                pr_sighold: 0,
+                pr_pid: (i + 1) as i32,
+                pr_ppid: 1,
+                pr_pgrp: 0,
+                pr_sid: 0,
+                pr_utime: zero,
+                pr_stime: zero,
+                pr_cutime: zero,
+                pr_cstime: zero,
+                pr_reg,
+                pr_fpvalid: 1,
+            }
+        ),
+        "failed to write NT_PRSTATUS"
+    );
+    Ok(())
+}
+
+fn write_note_sections(core_file: &mut dyn Write, vcpus: &[VcpuState]) -> Result<()> {
     try_with!(
         write_note_section(
             core_file,
@@ -224,39 +503,8 @@ fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()>
         "failed to write NT_PRPSINFO"
     );
 
-    let zero = timeval {
-        tv_sec: 0,
-        tv_usec: 0,
-    };
     for (i, vcpu) in vcpus.iter().enumerate() {
-        let pr_reg = unsafe { ptr::read(&vcpu.regs as *const Regs as *const [u64; ELF_NGREG]) };
-        try_with!(
-            write_note_section(
-                core_file,
-                NT_PRSTATUS,
-                &elf_prstatus {
-                    pr_info: elf_siginfo {
-                        si_signo: 0,
-                        si_code: 0,
-                        si_errno: 0,
-                    },
-                    pr_cursig: 0,
-                    pr_sigpend: 0,
-                    pr_sighold: 0,
-                    pr_pid: (i + 1) as i32,
-                    pr_ppid: 1,
-                    pr_pgrp: 0,
-                    pr_sid: 0,
-                    pr_utime: zero,
-                    pr_stime: zero,
-                    pr_cutime: zero,
-                    pr_cstime: zero,
-                    pr_reg,
-                    pr_fpvalid: 1,
-                }
-            ),
-            "failed to write NT_PRSTATUS"
-        );
+        write_prstatus(core_file, i, vcpu)?;
 
         try_with!(
             write_note_section(
@@ -272,66 +520,138 @@ fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()>
         );
 
         write_fpu_registers(core_file, &vcpu.fpu_regs)?;
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(xsave) = &vcpu.xsave {
+            write_xstate(core_file, xsave)?;
+        }
     }
     Ok(())
 }
 
 pub fn note_size<T>() -> usize {
-    // name is 4 bit aligned, we write CORE\0 to it
-    let name_size = 8;
-    size_of::<Nhdr>() + name_size + size_of::<T>()
+    // we write CORE\0 or LINUX\0 as name
+    note_len(8, size_of::<T>())
+}
+
+const VMCOREINFO_NOTE_NAME: &[u8] = b"VMCOREINFO\0";
+
+/// Like /proc/vmcore: NT_PRSTATUS of each vcpu followed by VMCOREINFO.
+fn write_kdump_notes(
+    core_file: &mut dyn Write,
+    vcpus: &[VcpuState],
+    vmcore: &Vmcore,
+) -> Result<()> {
+    for (i, vcpu) in vcpus.iter().enumerate() {
+        write_prstatus(core_file, i, vcpu)?;
+    }
+    try_with!(
+        write_note(core_file, VMCOREINFO_NOTE_NAME, 0, vmcore.info.as_bytes()),
+        "failed to write VMCOREINFO"
+    );
+    Ok(())
+}
+
+fn kdump_notes_size(vcpus: &[VcpuState], vmcore: &Vmcore) -> usize {
+    vcpus.len() * note_size::<elf_prstatus>()
+        + note_len(VMCOREINFO_NOTE_NAME.len(), vmcore.info.len())
+}
+
+/// Where a coredump is written to.
+enum CoreOutput {
+    /// A plain file, which is filled via mmap.
+    File(File),
+    /// Plaintext never touches the disk, memory is streamed sequentially into the encryption tool.
+    Encrypted(Encryptor),
+}
+
+fn write_metadata(
+    out: &mut dyn Write,
+    ehdr: &Ehdr,
+    section_headers: &[Phdr],
+    vcpus: &[VcpuState],
+    vmcore: Option<&Vmcore>,
+) -> Result<()> {
+    try_with!(
+        out.write_all(unsafe { any_as_bytes(ehdr) }),
+        "cannot write elf header"
+    );
+    for header in section_headers {
+        try_with!(
+            out.write_all(unsafe { any_as_bytes(header) }),
+            "cannot write elf header"
+        );
+    }
+    match vmcore {
+        Some(vmcore) => write_kdump_notes(out, vcpus, vmcore),
+        None => write_note_sections(out, vcpus),
+    }
 }
 
 fn write_corefile(
     pid: Pid,
-    core_file: &mut File,
+    out: &mut CoreOutput,
     maps: &[Mapping],
+    holes: &[Mapping],
+    skip: &[usize],
     vcpus: &[VcpuState],
+    vmcore: Option<&Vmcore>,
+    pacer: Option<&mut Pacer>,
+    prefetch: Option<&Prefetch>,
+    cancel: &Cancellation,
 ) -> Result<()> {
     // +1 == PT_NOTE section
-    let ehdr = elf_header((maps.len() + 1) as Elf_Half);
+    let ehdr = elf_header((maps.len() + holes.len() + 1) as Elf_Half);
 
     let metadata_size = size_of::<Ehdr>() + (size_of::<Phdr>() * ehdr.e_phnum as usize);
     let mut core_size = metadata_size;
 
-    let pt_note_size = note_size::<elf_prpsinfo>()
-        + vcpus.len()
-            * (note_size::<core_user>() + note_size::<elf_prstatus>() + note_size::<FpuRegs>());
+    let pt_note_size = match vmcore {
+        Some(vmcore) => kdump_notes_size(vcpus, vmcore),
+        None => note_size::<elf_prpsinfo>() + vcpus.iter().map(|v| v.notes_size()).sum::<usize>(),
+    };
+    let page_offset = vmcore.map_or(0, |v| v.page_offset);
     let mut section_headers = vec![pt_note_header(core_size as Elf_Off, pt_note_size as u64)];
     core_size += pt_note_size;
     core_size = page_align(core_size);
 
     for m in maps {
-        let phdr = pt_load_header(m, core_size as Elf_Off);
+        let phdr = pt_load_header(m, core_size as Elf_Off, page_offset);
         core_size += m.size();
         section_headers.push(phdr);
     }
-
-    try_with!(
-        core_file.set_len(core_size as u64),
-        "cannot truncate core file"
-    );
-    try_with!(
-        core_file.write_all(unsafe { any_as_bytes(&ehdr) }),
-        "cannot write elf header"
-    );
-    for header in section_headers {
-        try_with!(
-            core_file.write_all(unsafe { any_as_bytes(&header) }),
-            "cannot write elf header"
-        );
+    for m in holes {
+        section_headers.push(pt_load_hole_header(m, core_size as Elf_Off, page_offset));
     }
-    write_note_sections(core_file, vcpus)?;
+    let memory_offset = page_align(metadata_size + pt_note_size);
 
-    try_with!(core_file.flush(), "cannot flush core file");
+    match out {
+        CoreOutput::File(core_file) => {
+            try_with!(
+                core_file.set_len(core_size as u64),
+                "cannot truncate core file"
+            );
+            write_metadata(core_file, &ehdr, &section_headers, vcpus, vmcore)?;
+            try_with!(core_file.flush(), "cannot flush core file");
 
-    dump_mappings(
-        pid,
-        core_file,
-        core_size as off_t,
-        page_align(metadata_size + pt_note_size) as off_t,
-        maps,
-    )
+            dump_mappings(
+                pid,
+                core_file,
+                core_size as off_t,
+                memory_offset as off_t,
+                maps,
+                skip,
+                pacer,
+                prefetch,
+                cancel,
+            )
+        }
+        CoreOutput::Encrypted(encryptor) => {
+            let stream = encryptor.stdin()?;
+            write_metadata(stream, &ehdr, &section_headers, vcpus, vmcore)?;
+            write_zeros(stream, memory_offset - metadata_size - pt_note_size)?;
+            stream_mappings(pid, stream, maps, skip, pacer, prefetch, cancel)
+        }
+    }
 }
 
 const MSR_EFER: u32 = 0xc0000080;
@@ -342,6 +662,9 @@ struct VcpuState {
     fpu_regs: FpuRegs,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     msrs: [kvmb::kvm_msr_entry; 1],
+    /// None if kvm cannot save the xsave state
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    xsave: Option<kvmb::kvm_xsave>,
 }
 
 impl VcpuState {
@@ -356,42 +679,529 @@ impl VcpuState {
             ..Default::default()
         };
         let msr = hv.get_msr(vcpu, &entry)?;
+        let xsave = hv.get_xsave(vcpu).and_then(|mut xsave| {
+            set_xsave_xcr0(&mut xsave, hv.get_xcr0(vcpu)?);
+            Ok(xsave)
+        });
+        let xsave = match xsave {
+            Ok(xsave) => Some(xsave),
+            Err(e) => {
+                warn!("vcpu {}: leave out avx registers: {}", vcpu.idx, e);
+                None
+            }
+        };
         Ok(VcpuState {
             regs,
             sregs,
             fpu_regs,
             msrs: [msr],
+            xsave,
         })
     }
+
+    /// Size of the notes written by `write_note_sections` for this vcpu.
+    fn notes_size(&self) -> usize {
+        let size = note_size::<core_user>() + note_size::<elf_prstatus>() + note_size::<FpuRegs>();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.xsave.is_some() {
+            return size + note_size::<kvmb::kvm_xsave>();
+        }
+        size
+    }
+}
+
+/// Split `maps` into resident memory and swapped-out ranges.
+fn split_swapped(maps: Vec<Mapping>, pagemap: &PageMap) -> Result<(Vec<Mapping>, Vec<Mapping>)> {
+    let mut resident = vec![];
+    let mut swapped = vec![];
+    for m in maps {
+        let mut pos = m.start;
+        for range in pagemap.swapped_ranges(m.start, m.end)? {
+            let piece = |start: usize, end: usize| Mapping {
+                start,
+                end,
+                phys_addr: m.phys_addr + (start - m.start),
+                ..m.clone()
+            };
+            if pos < range.start {
+                resident.push(piece(pos, range.start));
+            }
+            swapped.push(piece(range.start, range.end));
+            pos = range.end;
+        }
+        if pos < m.end {
+            resident.push(Mapping {
+                start: pos,
+                phys_addr: m.phys_addr + (pos - m.start),
+                ..m
+            });
+        }
+    }
+    Ok((resident, swapped))
+}
+
+/// Swapped-out memory of the hypervisor that is read in with MADV_WILLNEED one chunk ahead of
+/// the copy, so that the kernel reads it in while the current chunk is copied.
+struct Prefetch<'a> {
+    vm: &'a Hypervisor,
+    /// Sorted by address
+    swapped: Vec<Mapping>,
+}
+
+impl<'a> Prefetch<'a> {
+    /// Start reading in the swapped-out parts of `len` bytes at host address `base`.
+    fn advise(&self, base: usize, len: usize) {
+        let end = base + len;
+        for m in self
+            .swapped
+            .iter()
+            .filter(|m| m.start < end && base < m.end)
+        {
+            let start = std::cmp::max(base, m.start);
+            let end = std::cmp::min(end, m.end);
+            if let Err(e) = self.vm.madvise(start, end - start, libc::MADV_WILLNEED) {
+                warn!("cannot prefetch {:#x}-{:#x}: {}", start, end, e);
+            }
+        }
+    }
+}
+
+/// Reading swapped-out memory with process_vm_readv swaps it in synchronously page by page.
+/// Detect such memory upfront and either leave it out as holes or return it for prefetching.
+/// Returns the maps to dump, the holes and the swapped-out ranges to prefetch.
+fn handle_swapped(
+    vm: &Hypervisor,
+    maps: Vec<Mapping>,
+    skip_swapped: bool,
+) -> Result<(Vec<Mapping>, Vec<Mapping>, Vec<Mapping>)> {
+    let pagemap = PageMap::open(vm.pid)?;
+    let (resident, mut swapped) = split_swapped(maps.clone(), &pagemap)?;
+    if swapped.is_empty() {
+        return Ok((maps, vec![], vec![]));
+    }
+    let swapped_size = swapped.iter().map(|m| m.size()).sum::<usize>();
+    if skip_swapped {
+        info!(
+            "skip {} kib of swapped guest memory in {} ranges",
+            swapped_size / 1024,
+            swapped.len()
+        );
+        return Ok((resident, swapped, vec![]));
+    }
+    info!(
+        "prefetch {} kib of swapped guest memory in {} ranges while dumping",
+        swapped_size / 1024,
+        swapped.len()
+    );
+    swapped.sort_by_key(|m| m.start);
+    Ok((maps, vec![], swapped))
+}
+
+/// Time between the epoch and `time`, zero for times before the epoch.
+pub fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+pub fn unix_time() -> u64 {
+    since_epoch(SystemTime::now()).as_secs()
+}
+
+/// Open `path` for writing. Also returns whether it was created by us: only then it may be
+/// removed when writing fails, `path` might as well be /dev/stdout or a file of someone else.
+pub fn create_output(path: &Path) -> Result<(File, bool)> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true);
+    match options.clone().create_new(true).open(path) {
+        Ok(file) => return Ok((file, true)),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => bail!("cannot create {}: {}", path.display(), e),
+    }
+    let file = try_with!(
+        options.truncate(true).open(path),
+        "cannot open {}",
+        path.display()
+    );
+    Ok((file, false))
+}
+
+/// Opens the core file or spawns the encryption tool writing it, see `create_output`.
+fn open_output(opts: &CoredumpOptions) -> Result<(CoreOutput, bool)> {
+    match &opts.encrypt_to {
+        Some(recipient) => {
+            let created = !opts.path.exists();
+            let encryptor = Encryptor::spawn(recipient, &opts.path)?;
+            Ok((CoreOutput::Encrypted(encryptor), created))
+        }
+        None => {
+            let (file, created) = create_output(&opts.path)?;
+            Ok((CoreOutput::File(file), created))
+        }
+    }
+}
+
+/// Remove everything a failed coredump may have written, it would be mistaken for a complete one.
+/// The core file itself is only removed if `created` by this run.
+fn remove_partial_output(path: &Path, created: bool) {
+    let mut files = vec![
+        scrub::report_path(path),
+        dedup::index_path(path),
+        symbolizer::annotations_path(path),
+        manifest::manifest_path(path),
+    ];
+    if created {
+        files.push(path.to_path_buf());
+    }
+    for file in &files {
+        match fs::remove_file(file) {
+            Ok(()) => info!("removed partial output {}", file.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("cannot remove {}: {}", file.display(), e),
+        }
+    }
 }
 
 pub fn generate_coredump(opts: &CoredumpOptions) -> Result<()> {
     println!("Write {}", opts.path.display());
-    let mut core_file = try_with!(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&opts.path),
-        "cannot open core_file: {}",
-        opts.path.display()
-    );
+    // Ctrl-C stops the copy at the next chunk instead of killing vmsh with the guest stopped
+    let cancel = Cancellation::setup()?;
     let vm = try_with!(
         kvm::hypervisor::get_hypervisor(opts.pid),
         "cannot get vms for process {}",
         opts.pid
     );
-    vm.stop()?;
-    let maps = vm.get_maps()?;
+    let stopped = vm.stop_guard()?;
+    let (out, created) = open_output(opts)?;
+    let res = write_coredump(opts, &vm, out, &cancel, unix_time());
+    drop(stopped);
+    if res.is_err() {
+        if cancel.is_cancelled() {
+            warn!("coredump cancelled");
+        }
+        remove_partial_output(&opts.path, created);
+    }
+    res
+}
+
+/// When one vm of `vmsh coredump --group` was paused, in nanoseconds since the unix epoch. The
+/// pause happened somewhere in between.
+struct PauseTime {
+    before: u128,
+    after: u128,
+}
+
+fn unix_time_ns() -> u128 {
+    since_epoch(SystemTime::now()).as_nanos()
+}
+
+/// Index of the coredumps of a group: the path and pause time of each vm and the window all
+/// pauses happened in.
+fn group_index(cores: &[(&CoredumpOptions, Option<&PauseTime>)]) -> String {
+    let (first, window) = pause_window(cores);
+    let mut index = format!(
+        "# vmsh coredump group\npaused {}\nwindow_ns {}\n",
+        first, window
+    );
+    for (opts, time) in cores {
+        match time {
+            Some(t) => index.push_str(&format!(
+                "core {} {} {} {}\n",
+                opts.pid,
+                opts.path.display(),
+                t.before,
+                t.after
+            )),
+            None => index.push_str(&format!("failed {} {}\n", opts.pid, opts.path.display())),
+        }
+    }
+    index
+}
+
+/// First pause of a group and the nanoseconds until the last one finished.
+fn pause_window(cores: &[(&CoredumpOptions, Option<&PauseTime>)]) -> (u128, u128) {
+    let paused = cores.iter().filter_map(|(_, t)| *t);
+    let first = paused.clone().map(|t| t.before).min().unwrap_or(0);
+    let last = paused.map(|t| t.after).max().unwrap_or(0);
+    (first, last.saturating_sub(first))
+}
+
+pub fn group_index_path(dir: &Path) -> PathBuf {
+    dir.join("core.group")
+}
+
+/// Dumps one vm of a group, in lockstep with the other threads at `barrier`.
+fn dump_group_member(
+    opts: &CoredumpOptions,
+    barrier: &Barrier,
+    cancel: &Cancellation,
+) -> (Option<PauseTime>, Result<()>) {
+    let vm = kvm::hypervisor::get_hypervisor(opts.pid);
+    let output = open_output(opts);
+    // everything that takes time is done, so the pauses are close together
+    barrier.wait();
+    let before = unix_time_ns();
+    let stopped = match &vm {
+        Ok(vm) => vm.stop_guard().map(|guard| (vm, guard)),
+        Err(e) => Err(simple_error::SimpleError::new(format!(
+            "cannot get vms for process {}: {}",
+            opts.pid, e
+        ))),
+    };
+    let time = PauseTime {
+        before,
+        after: unix_time_ns(),
+    };
+    barrier.wait();
+    let (time, res, created) = match (&stopped, output) {
+        (Ok((vm, _)), Ok((out, created))) => {
+            let started = (time.before / 1_000_000_000) as u64;
+            let res = write_coredump(opts, vm, out, cancel, started);
+            (Some(time), res, created)
+        }
+        (Ok(_), Err(e)) => (Some(time), Err(e), false),
+        (Err(e), output) => (
+            None,
+            Err(simple_error::SimpleError::new(e.to_string())),
+            output.map(|(_, created)| created).unwrap_or(false),
+        ),
+    };
+    if res.is_err() {
+        remove_partial_output(&opts.path, created);
+    }
+    // resume all vms together, so none of them sees the others paused
+    barrier.wait();
+    drop(stopped);
+    (time, res)
+}
+
+/// Pauses all vms of `group` at nearly the same time, dumps them and resumes them together, see
+/// `vmsh coredump --group`. Each vm is traced by its own thread, because a tracer cannot be
+/// passed on to another thread without resuming the vm.
+pub fn generate_group_coredump(group: Vec<CoredumpOptions>, dir: &Path) -> Result<()> {
+    if group.iter().any(|opts| opts.adaptive) {
+        bail!(
+            "--adaptive resumes the vms while they are dumped, they would not stay paused together"
+        );
+    }
+    let cancel = Arc::new(Cancellation::setup()?);
+    let barrier = Arc::new(Barrier::new(group.len()));
+    let group = group.into_iter().map(Arc::new).collect::<Vec<_>>();
+    let threads = group
+        .iter()
+        .map(|opts| {
+            println!("Write {}", opts.path.display());
+            let (opts, barrier, cancel) =
+                (Arc::clone(opts), Arc::clone(&barrier), Arc::clone(&cancel));
+            thread::spawn(move || dump_group_member(&opts, &barrier, &cancel))
+        })
+        .collect::<Vec<_>>();
+    let mut results = vec![];
+    for thread in threads {
+        results.push(match thread.join() {
+            Ok(result) => result,
+            Err(_) => (
+                None,
+                Err(simple_error::SimpleError::new("coredump thread panicked")),
+            ),
+        });
+    }
+
+    let cores = group
+        .iter()
+        .zip(&results)
+        .map(|(opts, (time, _))| (opts.as_ref(), time.as_ref()))
+        .collect::<Vec<_>>();
+    let index = group_index(&cores);
+    let index_path = group_index_path(dir);
+    try_with!(
+        fs::write(&index_path, &index),
+        "cannot write {}",
+        index_path.display()
+    );
+    let (_, window) = pause_window(&cores);
+    info!("paused {} vms within {}ns", cores.len(), window);
+    let mut failed = 0;
+    for (opts, (_, res)) in group.iter().zip(results) {
+        if let Err(e) = res {
+            warn!("coredump of {} failed: {}", opts.pid, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} coredumps failed", failed, group.len());
+    }
+    Ok(())
+}
+
+/// Requires the hypervisor to be stopped. `started` is the unix time the guest was stopped at.
+fn write_coredump(
+    opts: &CoredumpOptions,
+    vm: &Hypervisor,
+    mut out: CoreOutput,
+    cancel: &Cancellation,
+    started: u64,
+) -> Result<()> {
+    if opts.format == CoreFormat::Kdump && opts.dedup_store.is_some() {
+        bail!("crash and makedumpfile cannot read pages from a dedup store, use --format elf");
+    }
+    let (mut maps, holes, swapped) = handle_swapped(vm, vm.get_maps()?, opts.skip_swapped)?;
+    // skipped pages are sorted by address
+    maps.sort_by_key(|m| m.start);
+    let mut extra_files = vec![];
+    let scrubbed = match &opts.scrub {
+        Some(scrub_opts) => {
+            let redactions = scrub::find_redactions(vm, &maps, scrub_opts, cancel)?;
+            extra_files.push(scrub::write_report(&opts.path, &redactions)?);
+            scrub::host_pages(&maps, &redactions)
+        }
+        None => {
+            let _ = fs::remove_file(scrub::report_path(&opts.path));
+            vec![]
+        }
+    };
+    let mut skip = match &opts.dedup_store {
+        Some(dir) => {
+            let store = PageStore::open(dir)?;
+            // secrets must not end up in the page store either
+            let (pages, skip) = dedup::dedup_pages(opts.pid, &maps, &store, &scrubbed)?;
+            dedup::write_index(&opts.path, &store, &pages)?;
+            extra_files.push(dedup::index_path(&opts.path));
+            skip
+        }
+        None => {
+            // an index of an earlier coredump would corrupt this one
+            let _ = fs::remove_file(dedup::index_path(&opts.path));
+            vec![]
+        }
+    };
+    skip.extend(scrubbed);
+    skip.sort_unstable();
+    skip.dedup();
     let res = vm
         .vcpus
         .iter()
-        .map(|vcpu| VcpuState::new(vcpu, &vm))
+        .map(|vcpu| VcpuState::new(vcpu, vm))
         .collect::<Result<Vec<VcpuState>>>();
     let vcpu_states = try_with!(res, "fail to dump vcpu registers");
+    match &opts.symbols {
+        Some(source) => {
+            let mem = GuestMem::new(vm)?;
+            let kernel = try_with!(
+                find_kernel(&mem, vm, source.fallbacks()),
+                "could not find kernel"
+            );
+            let symbolizer = source.open(&kernel)?;
+            let rips = vcpu_states
+                .iter()
+                .map(|s| s.regs.rip as usize)
+                .collect::<Vec<_>>();
+            extra_files.push(symbolizer::write_annotations(
+                &opts.path,
+                symbolizer.as_ref(),
+                &rips,
+            )?);
+        }
+        None => {
+            let _ = fs::remove_file(symbolizer::annotations_path(&opts.path));
+        }
+    }
+    let vmcore = match opts.format {
+        CoreFormat::Kdump => Some(try_with!(
+            Vmcore::new(vm, &maps, started, cancel),
+            "cannot write kdump format"
+        )),
+        CoreFormat::Elf => None,
+    };
+    let pressure = match opts.max_host_pressure {
+        Some(threshold) => Some(PressureMonitor::new(opts.pid, threshold)?),
+        None => None,
+    };
+    let mut prefetch = Some(Prefetch { vm, swapped });
+    let mut pacer = if opts.adaptive {
+        // madvise is injected into the hypervisor, which needs it to be stopped
+        if let Some(prefetch) = prefetch.take() {
+            for m in &prefetch.swapped {
+                prefetch.advise(m.start, m.size());
+            }
+        }
+        vm.resume()?;
+        let pacer = Pacer::new(vm)?;
+        Some(match pressure {
+            Some(monitor) => pacer.with_host_pressure(monitor),
+            None => pacer,
+        })
+    } else {
+        pressure.map(Pacer::host_only)
+    };
     try_with!(
-        write_corefile(opts.pid, &mut core_file, &maps, vcpu_states.as_slice()),
+        write_corefile(
+            opts.pid,
+            &mut out,
+            &maps,
+            &holes,
+            &skip,
+            vcpu_states.as_slice(),
+            vmcore.as_ref(),
+            pacer.as_mut(),
+            prefetch.as_ref(),
+            cancel,
+        ),
         "cannot write core file"
     );
-    Ok(())
+    if let Some(pacer) = pacer {
+        pacer.report();
+    }
+    if let CoreOutput::Encrypted(encryptor) = out {
+        encryptor.finish()?;
+    }
+    let provenance = Provenance {
+        pid: opts.pid,
+        started,
+        finished: unix_time(),
+    };
+    manifest::write_manifest(&opts.path, &extra_files, &provenance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pid: i32, path: &str) -> CoredumpOptions {
+        CoredumpOptions {
+            pid: Pid::from_raw(pid),
+            path: PathBuf::from(path),
+            skip_swapped: false,
+            dedup_store: None,
+            encrypt_to: None,
+            scrub: None,
+            adaptive: false,
+            max_host_pressure: None,
+            symbols: None,
+            format: CoreFormat::Elf,
+        }
+    }
+
+    #[test]
+    fn test_group_index() {
+        let (a, b, c) = (
+            options(1, "core.1"),
+            options(2, "core.2"),
+            options(3, "core.3"),
+        );
+        let (ta, tb) = (
+            PauseTime {
+                before: 1_000,
+                after: 1_400,
+            },
+            PauseTime {
+                before: 1_100,
+                after: 1_900,
+            },
+        );
+        let index = group_index(&[(&a, Some(&ta)), (&b, Some(&tb)), (&c, None)]);
+        assert_eq!(
+            index,
+            "# vmsh coredump group\npaused 1000\nwindow_ns 900\n\
+             core 1 core.1 1000 1400\ncore 2 core.2 1100 1900\nfailed 3 core.3\n"
+        );
+    }
 }
@@ -0,0 +1,574 @@
+//! Audit which mitigations against cpu vulnerabilities a guest can use and which it actually
+//! uses, see `vmsh cpu-report`.
+//!
+//! The report combines the cpuid the hypervisor exposes to the guest, the mitigation related
+//! msrs and control registers of each vcpu and the state of the guest kernel, read from the
+//! symbols it exports.
+use kvm_bindings as kvmb;
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{require_with, try_with};
+
+use crate::json::json_escape;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::kvm::ioctls;
+use crate::kvm::tracee::kvm_msrs;
+use crate::result::Result;
+use crate::security_audit::{json_bool, json_string_list};
+use crate::vmi::KernelMemory;
+
+pub struct CpuReportOptions {
+    pub pid: Pid,
+}
+
+const MSR_IA32_SPEC_CTRL: u32 = 0x48;
+const MSR_IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+
+#[derive(Clone, Copy)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+struct CpuidBit {
+    function: u32,
+    index: u32,
+    reg: Reg,
+    bit: u32,
+    name: &'static str,
+}
+
+const fn cpuid_bit(function: u32, reg: Reg, bit: u32, name: &'static str) -> CpuidBit {
+    CpuidBit {
+        function,
+        index: 0,
+        reg,
+        bit,
+        name,
+    }
+}
+
+/// Names as in /proc/cpuinfo
+const CPUID_BITS: &[CpuidBit] = &[
+    cpuid_bit(1, Reg::Ecx, 17, "pcid"),
+    cpuid_bit(7, Reg::Ebx, 7, "smep"),
+    cpuid_bit(7, Reg::Ebx, 10, "invpcid"),
+    cpuid_bit(7, Reg::Ebx, 20, "smap"),
+    cpuid_bit(7, Reg::Ecx, 2, "umip"),
+    cpuid_bit(7, Reg::Ecx, 3, "pku"),
+    cpuid_bit(7, Reg::Edx, 10, "md_clear"),
+    cpuid_bit(7, Reg::Edx, 26, "spec_ctrl"),
+    cpuid_bit(7, Reg::Edx, 27, "intel_stibp"),
+    cpuid_bit(7, Reg::Edx, 28, "flush_l1d"),
+    cpuid_bit(7, Reg::Edx, 29, "arch_capabilities"),
+    cpuid_bit(7, Reg::Edx, 31, "spec_ctrl_ssbd"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 12, "amd_ibpb"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 14, "amd_ibrs"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 15, "amd_stibp"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 24, "amd_ssbd"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 25, "virt_ssbd"),
+    cpuid_bit(0x8000_0008, Reg::Ebx, 26, "amd_ssb_no"),
+];
+
+const ARCH_CAPABILITIES_BITS: &[(u32, &str)] = &[
+    (0, "rdcl_no"),
+    (1, "ibrs_all"),
+    (2, "rsba"),
+    (3, "skip_l1dfl_vmentry"),
+    (4, "ssb_no"),
+    (5, "mds_no"),
+    (6, "pschange_mc_no"),
+    (7, "tsx_ctrl"),
+    (8, "taa_no"),
+    (13, "sbdr_ssdp_no"),
+    (14, "fbsdp_no"),
+    (15, "psdp_no"),
+    (17, "fb_clear"),
+    (19, "rrsba"),
+    (20, "bhi_no"),
+    (24, "pbrsb_no"),
+    (26, "gds_no"),
+    (27, "rfds_no"),
+];
+
+const SPEC_CTRL_BITS: &[(u32, &str)] = &[(0, "ibrs"), (1, "stibp"), (2, "ssbd"), (10, "bhi_dis_s")];
+
+const CR4_BITS: &[(u32, &str)] = &[
+    (11, "umip"),
+    (17, "pcide"),
+    (20, "smep"),
+    (21, "smap"),
+    (22, "pke"),
+];
+
+const EFER_NX: u64 = 1 << 11;
+
+/// Static keys the guest kernel enables for mitigations. Older or newer kernels might not have
+/// or export them.
+const KERNEL_STATIC_KEYS: &[(&str, &str)] = &[
+    (
+        "mds_user_clear",
+        "clear cpu buffers on return to user space",
+    ),
+    ("mds_idle_clear", "clear cpu buffers before idle"),
+    (
+        "mmio_stale_data_clear",
+        "clear cpu buffers for mmio stale data",
+    ),
+];
+
+/// None if the hypervisor does not provide the cpuid leaf.
+fn lookup_cpuid(entries: &[kvmb::kvm_cpuid_entry2], bit: &CpuidBit) -> Option<bool> {
+    let entry = entries
+        .iter()
+        .find(|e| e.function == bit.function && e.index == bit.index)?;
+    let reg = match bit.reg {
+        Reg::Ebx => entry.ebx,
+        Reg::Ecx => entry.ecx,
+        Reg::Edx => entry.edx,
+    };
+    Some(reg & (1 << bit.bit) != 0)
+}
+
+pub(crate) fn has_feature(entries: &[kvmb::kvm_cpuid_entry2], name: &str) -> bool {
+    CPUID_BITS
+        .iter()
+        .find(|b| b.name == name)
+        .and_then(|b| lookup_cpuid(entries, b))
+        .unwrap_or(false)
+}
+
+fn decode_bits(value: u64, bits: &[(u32, &'static str)]) -> Vec<&'static str> {
+    bits.iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn format_bits(value: u64, bits: &[(u32, &'static str)]) -> String {
+    format!("{:#x} ({})", value, decode_bits(value, bits).join(","))
+}
+
+/// Vendor, family, model and stepping as in /proc/cpuinfo
+pub(crate) fn cpu_model(entries: &[kvmb::kvm_cpuid_entry2]) -> Option<(String, u32, u32, u32)> {
+    let leaf0 = entries.iter().find(|e| e.function == 0)?;
+    let leaf1 = entries.iter().find(|e| e.function == 1)?;
+    let mut vendor = vec![];
+    for reg in &[leaf0.ebx, leaf0.edx, leaf0.ecx] {
+        vendor.extend_from_slice(&reg.to_le_bytes());
+    }
+    let vendor = String::from_utf8_lossy(&vendor).into_owned();
+    let eax = leaf1.eax;
+    let mut family = (eax >> 8) & 0xf;
+    let mut model = (eax >> 4) & 0xf;
+    if family == 0xf {
+        family += (eax >> 20) & 0xff;
+    }
+    if family >= 6 {
+        model |= ((eax >> 16) & 0xf) << 4;
+    }
+    Some((vendor, family, model, eax & 0xf))
+}
+
+/// None if kvm does not know the msr.
+pub(crate) fn read_msr(vm: &Hypervisor, vcpu: &VCPU, index: u32) -> Result<Option<u64>> {
+    let msrs = kvm_msrs {
+        nmsrs: 1,
+        pad: 0,
+        entries: [kvmb::kvm_msr_entry {
+            index,
+            ..Default::default()
+        }],
+    };
+    // returns the number of msrs read
+    let (n, msrs) = vm.ioctl_with_copy(Some(vcpu), ioctls::KVM_GET_MSRS(), &msrs)?;
+    Ok(if n == 1 {
+        Some(msrs.entries[0].data)
+    } else {
+        None
+    })
+}
+
+/// IA32_ARCH_CAPABILITIES of the guest, None if the hypervisor does not expose it.
+pub(crate) fn arch_capabilities(
+    vm: &Hypervisor,
+    entries: &[kvmb::kvm_cpuid_entry2],
+) -> Result<Option<u64>> {
+    if has_feature(entries, "arch_capabilities") {
+        let vcpu = require_with!(vm.vcpus.first(), "vm has no vcpus");
+        read_msr(vm, vcpu, MSR_IA32_ARCH_CAPABILITIES)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether a bit of IA32_ARCH_CAPABILITIES like `rdcl_no` is set.
+pub(crate) fn has_arch_capability(caps: Option<u64>, name: &str) -> bool {
+    match caps {
+        Some(caps) => decode_bits(caps, ARCH_CAPABILITIES_BITS).contains(&name),
+        None => false,
+    }
+}
+
+struct VcpuState {
+    idx: usize,
+    /// None if kvm does not know the msr
+    spec_ctrl: Option<u64>,
+    cr4: u64,
+    nx: bool,
+}
+
+struct KernelState {
+    retpoline: bool,
+    return_thunk: bool,
+    spec_ctrl_base: Option<u64>,
+    /// name, description and whether the key is enabled, None if the kernel does not export it
+    static_keys: Vec<(&'static str, &'static str, Option<bool>)>,
+}
+
+/// What `vmsh cpu-report` found out about one vm.
+pub struct CpuReport {
+    model: Option<(String, u32, u32, u32)>,
+    /// None if the hypervisor does not provide the cpuid leaf
+    features: Vec<(&'static str, Option<bool>)>,
+    arch_caps: Option<u64>,
+    vcpus: Vec<VcpuState>,
+    /// None if the guest kernel could not be inspected
+    kernel: Option<KernelState>,
+    pub findings: Vec<String>,
+}
+
+/// Mitigation state of the guest kernel. `needs_retpoline` is set for intel cpus without
+/// enhanced ibrs.
+fn read_kernel(
+    kernel: &KernelMemory,
+    needs_retpoline: bool,
+    findings: &mut Vec<String>,
+) -> Result<KernelState> {
+    let has_symbol = |name: &str| kernel.kernel.symbols.contains_key(name);
+    let retpoline = has_symbol("__x86_indirect_thunk_rax");
+    let spec_ctrl_base = match kernel.symbol("x86_spec_ctrl_base") {
+        Ok(addr) => Some(kernel.read_u64(addr)?),
+        Err(_) => None,
+    };
+    let mut static_keys = vec![];
+    for (name, description) in KERNEL_STATIC_KEYS {
+        let enabled = match kernel.symbol(name) {
+            // `struct static_key` starts with the reference count of the key
+            Ok(addr) => Some(kernel.read_i32(addr)? > 0),
+            Err(_) => None,
+        };
+        static_keys.push((*name, *description, enabled));
+    }
+    if !retpoline && needs_retpoline {
+        findings.push(String::from(
+            "guest kernel has no retpolines and the cpu does not offer enhanced ibrs, it is likely exposed to spectre v2",
+        ));
+    }
+    Ok(KernelState {
+        retpoline,
+        return_thunk: has_symbol("__x86_return_thunk"),
+        spec_ctrl_base,
+        static_keys,
+    })
+}
+
+fn yes_no(v: bool) -> &'static str {
+    if v {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Stops the vm while the report is collected.
+pub fn collect_pid(pid: Pid) -> Result<CpuReport> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(pid),
+        "cannot get vms for process {}",
+        pid
+    );
+    let _stopped = vm.stop_guard()?;
+    collect(&vm)
+}
+
+pub fn cpu_report(opts: &CpuReportOptions) -> Result<()> {
+    collect_pid(opts.pid)?.print_text();
+    Ok(())
+}
+
+fn collect(vm: &Hypervisor) -> Result<CpuReport> {
+    let vcpu0 = require_with!(vm.vcpus.first(), "vm has no vcpus");
+    let cpuid = try_with!(vm.get_cpuid2(vcpu0), "cannot get cpuid of the guest");
+    let entries = &cpuid.entries[..(cpuid.nent as usize).min(cpuid.entries.len())];
+    let mut findings = vec![];
+
+    let model = cpu_model(entries);
+    let intel = matches!(&model, Some((vendor, _, _, _)) if vendor == "GenuineIntel");
+    let features = CPUID_BITS
+        .iter()
+        .map(|bit| (bit.name, lookup_cpuid(entries, bit)))
+        .collect();
+
+    let arch_caps = arch_capabilities(vm, entries)?;
+    let arch_cap = |name: &str| has_arch_capability(arch_caps, name);
+
+    if !has_feature(entries, "spec_ctrl") && !has_feature(entries, "amd_ibrs") {
+        findings.push(String::from(
+            "ibrs/ibpb are not exposed, the guest cannot protect itself against spectre v2 with them",
+        ));
+    }
+    if !has_feature(entries, "spec_ctrl_ssbd")
+        && !has_feature(entries, "amd_ssbd")
+        && !has_feature(entries, "virt_ssbd")
+        && !arch_cap("ssb_no")
+        && !has_feature(entries, "amd_ssb_no")
+    {
+        findings.push(String::from(
+            "ssbd is not exposed, the guest cannot mitigate speculative store bypass",
+        ));
+    }
+    if intel && !has_feature(entries, "md_clear") && !arch_cap("mds_no") {
+        findings.push(String::from(
+            "md_clear is not exposed, the guest cannot clear cpu buffers against mds and taa",
+        ));
+    }
+    if intel && arch_caps.is_none() {
+        findings.push(String::from(
+            "IA32_ARCH_CAPABILITIES is not exposed, the guest assumes to be vulnerable to all known issues",
+        ));
+    }
+
+    let mut vcpus = vec![];
+    for vcpu in &vm.vcpus {
+        let sregs = vm.get_sregs(vcpu)?;
+        if has_feature(entries, "smep") && sregs.cr4 & (1 << 20) == 0 {
+            findings.push(format!("vcpu{} runs without smep", vcpu.idx));
+        }
+        vcpus.push(VcpuState {
+            idx: vcpu.idx,
+            spec_ctrl: read_msr(vm, vcpu, MSR_IA32_SPEC_CTRL)?,
+            cr4: sregs.cr4,
+            nx: sregs.efer & EFER_NX != 0,
+        });
+    }
+
+    let kernel = match KernelMemory::new(vm) {
+        Ok(kernel) => Some(read_kernel(
+            &kernel,
+            intel && !arch_cap("ibrs_all"),
+            &mut findings,
+        )?),
+        Err(e) => {
+            warn!("cannot inspect guest kernel: {}", e);
+            None
+        }
+    };
+
+    Ok(CpuReport {
+        model,
+        features,
+        arch_caps,
+        vcpus,
+        kernel,
+        findings,
+    })
+}
+
+fn json_hex(v: Option<u64>) -> String {
+    match v {
+        Some(v) => format!("\"{:#x}\"", v),
+        None => String::from("null"),
+    }
+}
+
+impl CpuReport {
+    pub fn print_text(&self) {
+        if let Some((vendor, family, model, stepping)) = &self.model {
+            println!(
+                "cpu: {} family {} model {} stepping {}",
+                vendor, family, model, stepping
+            );
+        }
+
+        println!("cpuid features exposed to the guest:");
+        for (name, state) in &self.features {
+            let state = match state {
+                Some(v) => yes_no(*v),
+                None => "-",
+            };
+            println!("  {:<28} {}", name, state);
+        }
+
+        match self.arch_caps {
+            Some(caps) => println!(
+                "IA32_ARCH_CAPABILITIES: {}",
+                format_bits(caps, ARCH_CAPABILITIES_BITS)
+            ),
+            None => println!("IA32_ARCH_CAPABILITIES: not exposed"),
+        }
+
+        println!(
+            "{:>4} {:<24} {:<32} {:<4}",
+            "VCPU", "SPEC_CTRL", "CR4", "NX"
+        );
+        for vcpu in &self.vcpus {
+            let spec_ctrl = match vcpu.spec_ctrl {
+                Some(v) => format_bits(v, SPEC_CTRL_BITS),
+                None => String::from("-"),
+            };
+            println!(
+                "{:>4} {:<24} {:<32} {:<4}",
+                vcpu.idx,
+                spec_ctrl,
+                format_bits(vcpu.cr4, CR4_BITS),
+                yes_no(vcpu.nx)
+            );
+        }
+
+        if let Some(kernel) = &self.kernel {
+            println!("guest kernel:");
+            println!(
+                "  {:<28} {}",
+                "built with retpolines",
+                yes_no(kernel.retpoline)
+            );
+            println!(
+                "  {:<28} {}",
+                "built with return thunks",
+                yes_no(kernel.return_thunk)
+            );
+            match kernel.spec_ctrl_base {
+                Some(base) => println!(
+                    "  {:<28} {}",
+                    "x86_spec_ctrl_base",
+                    format_bits(base, SPEC_CTRL_BITS)
+                ),
+                None => println!("  {:<28} unknown", "x86_spec_ctrl_base"),
+            }
+            for (name, description, enabled) in &kernel.static_keys {
+                let state = match enabled {
+                    Some(true) => "enabled",
+                    Some(false) => "disabled",
+                    None => "unknown",
+                };
+                println!("  {:<28} {} ({})", name, state, description);
+            }
+        }
+
+        println!();
+        if self.findings.is_empty() {
+            println!("no missing mitigations found");
+        }
+        for f in &self.findings {
+            println!("{}", f);
+        }
+    }
+
+    /// Registers are hex strings, they don't fit into the numbers of most json parsers.
+    pub fn to_json(&self) -> String {
+        let cpu = match &self.model {
+            Some((vendor, family, model, stepping)) => format!(
+                "{{\"vendor\": \"{}\", \"family\": {}, \"model\": {}, \"stepping\": {}}}",
+                json_escape(vendor),
+                family,
+                model,
+                stepping
+            ),
+            None => String::from("null"),
+        };
+        let features = self
+            .features
+            .iter()
+            .map(|(name, state)| format!("\"{}\": {}", name, json_bool(*state)))
+            .collect::<Vec<_>>();
+        let vcpus = self
+            .vcpus
+            .iter()
+            .map(|v| {
+                format!(
+                    "{{\"idx\": {}, \"spec_ctrl\": {}, \"cr4\": \"{:#x}\", \"nx\": {}}}",
+                    v.idx,
+                    json_hex(v.spec_ctrl),
+                    v.cr4,
+                    v.nx
+                )
+            })
+            .collect::<Vec<_>>();
+        let kernel = match &self.kernel {
+            Some(k) => {
+                let keys = k
+                    .static_keys
+                    .iter()
+                    .map(|(name, _, enabled)| format!("\"{}\": {}", name, json_bool(*enabled)))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"retpoline\": {}, \"return_thunk\": {}, \"spec_ctrl_base\": {}, \"static_keys\": {{{}}}}}",
+                    k.retpoline,
+                    k.return_thunk,
+                    json_hex(k.spec_ctrl_base),
+                    keys.join(", ")
+                )
+            }
+            None => String::from("null"),
+        };
+        format!(
+            "{{\"cpu\": {}, \"features\": {{{}}}, \"arch_capabilities\": {}, \"vcpus\": [{}], \"kernel\": {}, \"findings\": {}}}",
+            cpu,
+            features.join(", "),
+            json_hex(self.arch_caps),
+            vcpus.join(", "),
+            kernel,
+            json_string_list(self.findings.iter().map(|f| f.as_str()))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> kvmb::kvm_cpuid_entry2 {
+        kvmb::kvm_cpuid_entry2 {
+            function,
+            eax,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cpuid() {
+        let entries = vec![
+            entry(0, 0xd, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            // family 6 model 85 (0x55) stepping 7
+            entry(1, 0x0005_0657, 0, 0, 0),
+            entry(7, 0, 1 << 7, 0, (1 << 10) | (1 << 29)),
+        ];
+        assert_eq!(
+            cpu_model(&entries),
+            Some((String::from("GenuineIntel"), 6, 85, 7))
+        );
+        assert!(has_feature(&entries, "smep"));
+        assert!(has_feature(&entries, "md_clear"));
+        assert!(has_feature(&entries, "arch_capabilities"));
+        assert!(!has_feature(&entries, "smap"));
+        assert!(!has_feature(&entries, "amd_ibrs"));
+        let amd_ibrs = CPUID_BITS.iter().find(|b| b.name == "amd_ibrs").unwrap();
+        assert_eq!(lookup_cpuid(&entries, amd_ibrs), None);
+    }
+
+    #[test]
+    fn test_decode_bits() {
+        assert_eq!(decode_bits(0b101, SPEC_CTRL_BITS), vec!["ibrs", "ssbd"]);
+        assert_eq!(
+            format_bits((1 << 20) | (1 << 21), CR4_BITS),
+            "0x300000 (smep,smap)"
+        );
+        assert_eq!(format_bits(0, ARCH_CAPABILITIES_BITS), "0x0 ()");
+    }
+}
@@ -0,0 +1,258 @@
+//! Crash the guest on purpose so that its own kdump captures a vmcore. An NMI makes the guest
+//! panic, which boots the crash kernel loaded with `kexec -p`. If that does not happen, a host
+//! side coredump is the last resort.
+use kvm_bindings as kvmb;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::coredump::{self, CoreFormat, CoredumpOptions};
+use crate::guest_mem::get_page_table_addr;
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::page_table;
+use crate::result::Result;
+use crate::symbolizer::{SymbolSource, Symbolizer};
+use crate::vmi::{GuestMemory, KernelMemory, PTI_USER_PGTABLE};
+
+/// Name of the memory reserved with `crashkernel=` in the guest's `/proc/iomem`.
+const CRASH_KERNEL_RESOURCE: &str = "Crash kernel";
+
+const X86_CR0_PG: u64 = 1 << 31;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct CrashdumpOptions {
+    pub pid: Pid,
+    /// Where to find `kexec_crash_image` and `unknown_nmi_panic`, which are not exported.
+    pub symbols: SymbolSource,
+    /// How long to wait for the crash kernel to start and, with `wait_capture`, to finish.
+    pub timeout: Duration,
+    /// Also wait until the crash kernel has left its reserved memory again, which it does once
+    /// it has saved the vmcore and reboots.
+    pub wait_capture: bool,
+    /// Write a host side coredump here if kdump does not take over.
+    pub fallback: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    /// The NMI was injected, the guest did not reach the crash kernel yet.
+    Triggered,
+    /// vcpu 0 executes in the memory reserved for the crash kernel.
+    CrashKernel,
+    /// The crash kernel ran and vcpu 0 left its memory again.
+    Captured,
+}
+
+fn advance(progress: Progress, in_crash_kernel: bool) -> Progress {
+    match (progress, in_crash_kernel) {
+        (Progress::Triggered, true) => Progress::CrashKernel,
+        (Progress::CrashKernel, false) => Progress::Captured,
+        (p, _) => p,
+    }
+}
+
+/// Physical address of the instruction `rip` points to, `None` if it is not mapped.
+fn phys_rip(
+    vm: &Hypervisor,
+    mem: &GuestMemory,
+    rip: usize,
+    sregs: &kvmb::kvm_sregs,
+) -> Result<Option<usize>> {
+    if sregs.cr0 & X86_CR0_PG == 0 {
+        return Ok(Some(rip));
+    }
+    let pml4 = mem.page_table(get_page_table_addr(sregs) & !PTI_USER_PGTABLE)?;
+    page_table::translate(vm, &pml4, page_table::paging_levels(sregs.cr4), rip)
+}
+
+fn in_crash_kernel(
+    vm: &Hypervisor,
+    mem: &GuestMemory,
+    crash_kernel: &Range<usize>,
+) -> Result<bool> {
+    let vcpu = &vm.vcpus[0];
+    let _stopped = vm.stop_guard()?;
+    let regs = vm.get_regs(vcpu)?;
+    let sregs = vm.get_sregs(vcpu)?;
+    let phys = phys_rip(vm, mem, regs.rip as usize, &sregs)?;
+    Ok(phys.map_or(false, |p| crash_kernel.contains(&p)))
+}
+
+/// Whether a crash kernel is loaded. `None` if `kexec_crash_image` is not in the symbols.
+fn crash_image_loaded(k: &KernelMemory, symbolizer: &dyn Symbolizer) -> Result<Option<bool>> {
+    match symbolizer.address("kexec_crash_image") {
+        Some(addr) => Ok(Some(k.read_ptr(addr)? != 0)),
+        None => Ok(None),
+    }
+}
+
+/// Make an unknown NMI panic the guest, like `sysctl kernel.unknown_nmi_panic=1`.
+fn enable_nmi_panic(vm: &Hypervisor, k: &KernelMemory, symbolizer: &dyn Symbolizer) -> Result<()> {
+    let addr = match symbolizer.address("unknown_nmi_panic") {
+        Some(addr) => addr,
+        None => {
+            warn!("unknown_nmi_panic not found in kernel symbols, the guest needs kernel.unknown_nmi_panic=1 to panic");
+            return Ok(());
+        }
+    };
+    let phys = k.virt_to_phys(addr)?;
+    try_with!(
+        vm.write_guest_phys(phys, &1i32.to_ne_bytes()),
+        "cannot set unknown_nmi_panic"
+    );
+    Ok(())
+}
+
+/// Reserved crash kernel memory and whether a crash kernel is loaded into it.
+fn prepare(vm: &Hypervisor, symbols: &SymbolSource) -> Result<Option<Range<usize>>> {
+    let _stopped = vm.stop_guard()?;
+    let k = KernelMemory::with_fallbacks(vm, symbols.fallbacks())?;
+    let symbolizer = symbols.open(&k.kernel)?;
+    let crash_kernel = k
+        .iomem_resources()?
+        .into_iter()
+        .find(|r| r.name == CRASH_KERNEL_RESOURCE);
+    let crash_kernel = match crash_kernel {
+        Some(r) if !r.range.is_empty() => r.range,
+        _ => {
+            warn!("guest has no memory reserved for a crash kernel, boot it with crashkernel=");
+            return Ok(None);
+        }
+    };
+    match crash_image_loaded(&k, symbolizer.as_ref())? {
+        Some(true) => {}
+        Some(false) => {
+            warn!("no crash kernel loaded in the guest, load one with kexec -p");
+            return Ok(None);
+        }
+        None => {
+            warn!("kexec_crash_image not found in kernel symbols, assume a crash kernel is loaded")
+        }
+    }
+    enable_nmi_panic(vm, &k, symbolizer.as_ref())?;
+    Ok(Some(crash_kernel))
+}
+
+fn trigger(vm: &Hypervisor) -> Result<()> {
+    info!("inject nmi into all vcpus");
+    let _stopped = vm.stop_guard()?;
+    vm.vcpus.iter().try_for_each(|vcpu| vm.nmi(vcpu))
+}
+
+/// Poll vcpu 0 until `target` is reached or `timeout` passed.
+fn wait_for(
+    vm: &Hypervisor,
+    crash_kernel: &Range<usize>,
+    mut progress: Progress,
+    target: Progress,
+    timeout: Duration,
+) -> Result<Progress> {
+    let mem = GuestMemory::new(vm)?;
+    let deadline = Instant::now() + timeout;
+    while progress != target && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+        progress = advance(progress, in_crash_kernel(vm, &mem, crash_kernel)?);
+    }
+    Ok(progress)
+}
+
+fn fallback_coredump(opts: &CrashdumpOptions, reason: &str) -> Result<()> {
+    let path = match &opts.fallback {
+        Some(path) => path,
+        None => bail!("{}", reason),
+    };
+    warn!("{}, write coredump to {} instead", reason, path.display());
+    coredump::generate_coredump(&CoredumpOptions {
+        pid: opts.pid,
+        path: path.clone(),
+        skip_swapped: false,
+        dedup_store: None,
+        encrypt_to: None,
+        scrub: None,
+        adaptive: false,
+        max_host_pressure: None,
+        // show where the vcpus got stuck
+        symbols: Some(opts.symbols.clone()),
+        format: CoreFormat::Elf,
+    })
+}
+
+/// Panic the guest to capture a vmcore with its kdump, or a coredump if that fails.
+pub fn crashdump(opts: &CrashdumpOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    // without a crash kernel panicking the guest only loses it
+    let crash_kernel = match prepare(&vm, &opts.symbols)? {
+        Some(range) => range,
+        None => return fallback_coredump(opts, "kdump is not set up in the guest"),
+    };
+    info!(
+        "crash kernel reserved at {:#x}-{:#x}",
+        crash_kernel.start, crash_kernel.end
+    );
+    trigger(&vm)?;
+
+    let progress = wait_for(
+        &vm,
+        &crash_kernel,
+        Progress::Triggered,
+        Progress::CrashKernel,
+        opts.timeout,
+    )?;
+    if progress == Progress::Triggered {
+        return fallback_coredump(
+            opts,
+            &format!(
+                "guest did not start its crash kernel within {:?}",
+                opts.timeout
+            ),
+        );
+    }
+    info!("guest runs its crash kernel");
+    if !opts.wait_capture {
+        return Ok(());
+    }
+
+    // the hypervisor may exit instead of rebooting the guest after the capture
+    match wait_for(
+        &vm,
+        &crash_kernel,
+        progress,
+        Progress::Captured,
+        opts.timeout,
+    ) {
+        Ok(Progress::Captured) => info!("crash kernel finished"),
+        Ok(_) => bail!("crash kernel still runs after {:?}", opts.timeout),
+        Err(e) => info!("crash kernel finished, vm is gone: {}", e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance() {
+        let mut p = Progress::Triggered;
+        for (in_crash_kernel, expected) in &[
+            (false, Progress::Triggered),
+            (true, Progress::CrashKernel),
+            (true, Progress::CrashKernel),
+            (false, Progress::Captured),
+            (true, Progress::Captured),
+        ] {
+            p = advance(p, *in_crash_kernel);
+            assert_eq!(p, *expected);
+        }
+    }
+}
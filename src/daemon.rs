@@ -0,0 +1,987 @@
+//! Keep `vmsh attach` sessions open in the background, see `vmsh daemon` and `vmsh ctl`.
+//!
+//! The daemon runs each attachment as a `vmsh attach` child process. Its output goes to a log
+//! file next to the socket, `<socket>.<id>.log`, which is kept after the attachment is detached.
+//! While an attachment runs, its devices can be changed with `vmsh device` like the ones of any
+//! other session. On SIGINT or SIGTERM the daemon detaches everything before it exits.
+//!
+//! `vmsh ctl` sends one request per connection. Fields are separated by tabs, so that arguments
+//! may contain spaces. The reply is `ok <message>` followed by the lines of `list` and `stats`,
+//! or `error <message>`:
+//!
+//! ```text
+//! attach <pid>[\t<argument of vmsh attach>...]
+//! detach <id>
+//! list
+//! stats
+//! ```
+//!
+//! Programs talk json-rpc 2.0 on the same socket instead: a line that starts with `{` is one
+//! request object and gets one reply line. Methods are versioned, `version` returns the api
+//! version and all others are prefixed with it:
+//!
+//! ```text
+//! version                                             {"api": 1, "vmsh": "0.1.0"}
+//! v1.list_vms                                         [{"pid": 1234, "attachment": 1}]
+//! v1.attach {"pid": 1234, "args": ["-f", "x"]}        {"id": 1}
+//! v1.detach {"id": 1}                                 {"id": 1, "state": "exited(0)"}
+//! v1.list_attachments                                 [{"id": 1, "pid": 1234, "state": ...}]
+//! v1.stats                                            {"uptime": 3, "requests": 2, ...}
+//! v1.exec {"pid": 1234, "command": ["ls", "/"]}       {"exit_code": 0, "stdout": "...", ...}
+//! v1.read_mem {"pid": 1234, "addr": 4096, "len": 16}  {"addr": 4096, "data": "<hex>"}
+//! v1.coredump {"pid": 1234, "path": "/tmp/core"}      {"path": "/tmp/core"}
+//! ```
+//!
+//! `exec`, `read_mem` and `coredump` run `vmsh exec`, `vmsh read` and `vmsh coredump` in a
+//! thread, so they do not hold up other requests.
+use log::{info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{geteuid, Pid};
+use simple_error::{bail, require_with, try_with};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::json::{self, object, Value};
+use crate::kvm::hypervisor::find_hypervisors;
+use crate::result::Result;
+use crate::sha256;
+use crate::signal_handler::Cancellation;
+
+pub struct DaemonOptions {
+    pub socket: PathBuf,
+}
+
+pub struct CtlOptions {
+    pub socket: PathBuf,
+    pub request: CtlRequest,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CtlRequest {
+    /// Run `vmsh attach <pid> <args>` in the daemon
+    Attach {
+        pid: Pid,
+        args: Vec<String>,
+    },
+    /// Stop the attachment with this id as printed by `attach` and `list`
+    Detach {
+        id: u64,
+    },
+    List,
+    Stats,
+}
+
+/// Where `vmsh daemon` listens and `vmsh ctl` connects to by default
+pub const DEFAULT_SOCKET: &str = "/run/vmsh.sock";
+
+/// How often the daemon checks for requests, exited attachments and signals
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a detaching `vmsh attach` may take to clean up the guest
+const DETACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Version of the json-rpc api, which prefixes the method names, i.e. `v1.attach`
+pub const API_VERSION: u64 = 1;
+/// Largest `read_mem` request, the bytes are sent as hex in a single line
+const MAX_READ_MEM: u64 = 1 << 20;
+
+// json-rpc 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+impl CtlRequest {
+    fn parse(line: &str) -> Result<CtlRequest> {
+        let mut fields = line.split('\t');
+        let mut words = fields.next().unwrap_or("").splitn(2, ' ');
+        let request = match (words.next(), words.next()) {
+            (Some("attach"), Some(pid)) => {
+                let pid = try_with!(pid.parse::<i32>(), "invalid pid {}", pid);
+                return Ok(CtlRequest::Attach {
+                    pid: Pid::from_raw(pid),
+                    args: fields.map(String::from).collect(),
+                });
+            }
+            (Some("detach"), Some(id)) => CtlRequest::Detach {
+                id: try_with!(id.parse::<u64>(), "invalid attachment id {}", id),
+            },
+            (Some("list"), None) => CtlRequest::List,
+            (Some("stats"), None) => CtlRequest::Stats,
+            _ => bail!("invalid request '{}'", line),
+        };
+        if fields.next().is_some() {
+            bail!("invalid request '{}'", line);
+        }
+        Ok(request)
+    }
+
+    fn to_line(&self) -> Result<String> {
+        Ok(match self {
+            CtlRequest::Attach { pid, args } => {
+                if let Some(arg) = args.iter().find(|a| a.contains(&['\t', '\n'][..])) {
+                    bail!("argument '{}' contains a tab or newline", arg);
+                }
+                let mut line = format!("attach {}", pid);
+                for arg in args {
+                    line.push('\t');
+                    line.push_str(arg);
+                }
+                line
+            }
+            CtlRequest::Detach { id } => format!("detach {}", id),
+            CtlRequest::List => String::from("list"),
+            CtlRequest::Stats => String::from("stats"),
+        })
+    }
+}
+
+/// Message and following lines of an `ok` reply, the message of an `error` reply as error.
+fn parse_reply(reply: &str) -> Result<(String, Vec<String>)> {
+    let mut lines = reply.lines();
+    let first = lines.next().unwrap_or("");
+    if let Some(msg) = first.strip_prefix("error ") {
+        bail!("{}", msg);
+    }
+    let msg = match (first, first.strip_prefix("ok ")) {
+        ("ok", _) => "",
+        (_, Some(msg)) => msg,
+        ("", _) => bail!("vmsh daemon closed the connection without a reply"),
+        _ => bail!("invalid reply '{}'", first),
+    };
+    Ok((msg.to_string(), lines.map(String::from).collect()))
+}
+
+#[derive(Debug, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+type RpcResult<T = Value> = std::result::Result<T, RpcError>;
+
+#[derive(Debug, PartialEq)]
+enum RpcCall {
+    Version,
+    ListVms,
+    Attach { pid: Pid, args: Vec<String> },
+    Detach { id: u64 },
+    ListAttachments,
+    Stats,
+    Exec { pid: Pid, command: Vec<String> },
+    ReadMem { pid: Pid, addr: u64, len: u64 },
+    Coredump { pid: Pid, path: PathBuf },
+}
+
+impl RpcCall {
+    /// Calls that run another vmsh, which may take long.
+    fn is_slow(&self) -> bool {
+        matches!(
+            self,
+            RpcCall::Exec { .. } | RpcCall::ReadMem { .. } | RpcCall::Coredump { .. }
+        )
+    }
+}
+
+fn param<'a>(params: &'a Value, key: &str) -> RpcResult<&'a Value> {
+    params
+        .get(key)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter {}", key)))
+}
+
+fn u64_param(params: &Value, key: &str) -> RpcResult<u64> {
+    param(params, key)?.as_u64().ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("parameter {} is not an unsigned integer", key),
+        )
+    })
+}
+
+fn pid_param(params: &Value) -> RpcResult<Pid> {
+    match u64_param(params, "pid")? {
+        pid if pid > 0 && pid <= i32::MAX as u64 => Ok(Pid::from_raw(pid as i32)),
+        pid => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("invalid pid {}", pid),
+        )),
+    }
+}
+
+/// A missing optional list is empty.
+fn strings_param(params: &Value, key: &str, required: bool) -> RpcResult<Vec<String>> {
+    let items = match params.get(key) {
+        None if !required => return Ok(vec![]),
+        _ => param(params, key)?.as_array(),
+    };
+    items
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|i| i.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            RpcError::new(
+                INVALID_PARAMS,
+                format!("parameter {} is not a list of strings", key),
+            )
+        })
+}
+
+fn parse_call(request: &Value) -> RpcResult<RpcCall> {
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_REQUEST, "method is missing"))?;
+    let no_params = Value::Object(vec![]);
+    let params = request.get("params").unwrap_or(&no_params);
+    if method == "version" {
+        return Ok(RpcCall::Version);
+    }
+    let not_found = || RpcError::new(METHOD_NOT_FOUND, format!("no method {}", method));
+    let name = match method.split_once('.') {
+        Some((version, name)) if version == format!("v{}", API_VERSION) => name,
+        Some((version, _)) if version.starts_with('v') => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!(
+                    "api {} is not supported, this daemon speaks v{}",
+                    version, API_VERSION
+                ),
+            ))
+        }
+        _ => return Err(not_found()),
+    };
+    Ok(match name {
+        "list_vms" => RpcCall::ListVms,
+        "attach" => RpcCall::Attach {
+            pid: pid_param(params)?,
+            args: strings_param(params, "args", false)?,
+        },
+        "detach" => RpcCall::Detach {
+            id: u64_param(params, "id")?,
+        },
+        "list_attachments" => RpcCall::ListAttachments,
+        "stats" => RpcCall::Stats,
+        "exec" => {
+            let command = strings_param(params, "command", true)?;
+            if command.is_empty() {
+                return Err(RpcError::new(INVALID_PARAMS, "command is empty"));
+            }
+            RpcCall::Exec {
+                pid: pid_param(params)?,
+                command,
+            }
+        }
+        "read_mem" => {
+            let len = u64_param(params, "len")?;
+            if len == 0 || len > MAX_READ_MEM {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("len must be between 1 and {}", MAX_READ_MEM),
+                ));
+            }
+            RpcCall::ReadMem {
+                pid: pid_param(params)?,
+                addr: u64_param(params, "addr")?,
+                len,
+            }
+        }
+        "coredump" => {
+            let path = param(params, "path")?
+                .as_str()
+                .map(PathBuf::from)
+                .filter(|p| p.is_absolute())
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "path must be absolute"))?;
+            RpcCall::Coredump {
+                pid: pid_param(params)?,
+                path,
+            }
+        }
+        _ => return Err(not_found()),
+    })
+}
+
+/// The id of the request, `None` for notifications, and the call. Unparsable requests are
+/// answered with id null.
+fn parse_rpc(line: &str) -> (Option<Value>, RpcResult<RpcCall>) {
+    let request = match json::parse(line) {
+        Ok(request @ Value::Object(_)) => request,
+        Ok(_) => {
+            let e = RpcError::new(
+                INVALID_REQUEST,
+                "request is not an object, batches are not supported",
+            );
+            return (Some(Value::Null), Err(e));
+        }
+        Err(e) => return (Some(Value::Null), Err(RpcError::new(PARSE_ERROR, e))),
+    };
+    (request.get("id").cloned(), parse_call(&request))
+}
+
+fn rpc_reply(id: Value, result: RpcResult) -> String {
+    let outcome = match result {
+        Ok(value) => ("result", value),
+        Err(e) => (
+            "error",
+            object(vec![
+                ("code", Value::from(e.code)),
+                ("message", Value::from(e.message)),
+            ]),
+        ),
+    };
+    format!(
+        "{}\n",
+        object(vec![("jsonrpc", Value::from("2.0")), ("id", id), outcome])
+    )
+}
+
+fn send_rpc_reply(mut stream: &UnixStream, id: Option<Value>, result: RpcResult) -> Result<()> {
+    // notifications are not answered
+    if let Some(id) = id {
+        try_with!(
+            stream.write_all(rpc_reply(id, result).as_bytes()),
+            "cannot send reply"
+        );
+    }
+    Ok(())
+}
+
+fn run_vmsh(args: &[String]) -> RpcResult<Output> {
+    let exe = std::env::current_exe()
+        .map_err(|e| RpcError::new(SERVER_ERROR, format!("cannot find vmsh executable: {}", e)))?;
+    Command::new(exe)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| RpcError::new(SERVER_ERROR, format!("cannot run vmsh {}: {}", args[0], e)))
+}
+
+/// The last line vmsh logged is its error.
+fn check_output(output: &Output) -> RpcResult<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let msg = match stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+        Some(line) => line.trim().to_string(),
+        None => format!("vmsh failed with {}", output.status),
+    };
+    Err(RpcError::new(SERVER_ERROR, msg))
+}
+
+/// Runs calls that `is_slow` in a child vmsh.
+fn run_slow(call: RpcCall) -> RpcResult {
+    match call {
+        RpcCall::Exec { pid, command } => {
+            let mut args = vec![String::from("exec"), pid.to_string(), String::from("--")];
+            args.extend(command);
+            let output = run_vmsh(&args)?;
+            let exit_code = output
+                .status
+                .code()
+                .map_or(Value::Null, |c| Value::from(c as i64));
+            Ok(object(vec![
+                ("exit_code", exit_code),
+                (
+                    "stdout",
+                    Value::from(String::from_utf8_lossy(&output.stdout).into_owned()),
+                ),
+                (
+                    "stderr",
+                    Value::from(String::from_utf8_lossy(&output.stderr).into_owned()),
+                ),
+            ]))
+        }
+        RpcCall::ReadMem { pid, addr, len } => {
+            let output = run_vmsh(&[
+                String::from("read"),
+                pid.to_string(),
+                format!("{:x}", addr),
+                len.to_string(),
+                String::from("--raw"),
+            ])?;
+            check_output(&output)?;
+            Ok(object(vec![
+                ("addr", Value::from(addr)),
+                ("data", Value::from(sha256::to_hex(&output.stdout))),
+            ]))
+        }
+        RpcCall::Coredump { pid, path } => {
+            let output = run_vmsh(&[
+                String::from("coredump"),
+                pid.to_string(),
+                path.display().to_string(),
+            ])?;
+            check_output(&output)?;
+            Ok(object(vec![(
+                "path",
+                Value::from(path.display().to_string()),
+            )]))
+        }
+        _ => Err(RpcError::new(SERVER_ERROR, "call is not slow")),
+    }
+}
+
+/// A `vmsh attach` run by the daemon
+struct Attachment {
+    id: u64,
+    pid: Pid,
+    args: Vec<String>,
+    child: Child,
+    started: Instant,
+    log: PathBuf,
+    /// Set once `vmsh attach` exited
+    status: Option<ExitStatus>,
+}
+
+impl Attachment {
+    fn state(&self) -> String {
+        match self.status.map(|s| s.code()) {
+            None => String::from("attached"),
+            Some(Some(code)) => format!("exited({})", code),
+            Some(None) => String::from("killed"),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        object(vec![
+            ("id", Value::from(self.id)),
+            ("pid", Value::from(self.pid.as_raw() as i64)),
+            ("state", Value::from(self.state())),
+            ("uptime", Value::from(self.started.elapsed().as_secs())),
+            (
+                "args",
+                Value::Array(self.args.iter().map(|a| Value::from(a.as_str())).collect()),
+            ),
+            ("log", Value::from(self.log.display().to_string())),
+        ])
+    }
+}
+
+fn log_path(socket: &Path, id: u64) -> PathBuf {
+    let mut name = socket.as_os_str().to_os_string();
+    name.push(format!(".{}.log", id));
+    PathBuf::from(name)
+}
+
+struct Daemon {
+    socket: PathBuf,
+    attachments: Vec<Attachment>,
+    next_id: u64,
+    started: Instant,
+    requests: u64,
+}
+
+impl Daemon {
+    fn attach(&mut self, pid: Pid, args: Vec<String>) -> Result<u64> {
+        if self
+            .attachments
+            .iter()
+            .any(|a| a.pid == pid && a.status.is_none())
+        {
+            bail!(
+                "{} is already attached, use vmsh device to change its devices",
+                pid
+            );
+        }
+        let id = self.next_id;
+        let log = log_path(&self.socket, id);
+        let output = try_with!(File::create(&log), "cannot create {}", log.display());
+        let errors = try_with!(output.try_clone(), "cannot duplicate {}", log.display());
+        let exe = try_with!(std::env::current_exe(), "cannot find vmsh executable");
+        let child = try_with!(
+            Command::new(exe)
+                .arg("attach")
+                .arg(pid.to_string())
+                .args(&args)
+                .stdin(Stdio::null())
+                .stdout(output)
+                .stderr(errors)
+                .spawn(),
+            "cannot spawn vmsh attach"
+        );
+        info!("attachment {}: vmsh attach {} {}", id, pid, args.join(" "));
+        self.next_id += 1;
+        self.attachments.push(Attachment {
+            id,
+            pid,
+            args,
+            child,
+            started: Instant::now(),
+            log,
+            status: None,
+        });
+        Ok(id)
+    }
+
+    /// Stops `vmsh attach` like Ctrl-C does and waits until it cleaned up the guest. Returns
+    /// the final state of the attachment.
+    fn detach(&mut self, id: u64) -> Result<String> {
+        let pos = require_with!(
+            self.attachments.iter().position(|a| a.id == id),
+            "no attachment {}",
+            id
+        );
+        // an attachment that does not stop stays in the list, so it can be detached again
+        if self.attachments[pos].status.is_none() {
+            stop_child(&mut self.attachments[pos])?;
+        }
+        Ok(self.attachments.remove(pos).state())
+    }
+
+    fn list(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:>4} {:>8} {:<12} {:>8} ARGS",
+            "ID", "PID", "STATE", "UPTIME"
+        )];
+        for a in &self.attachments {
+            lines.push(format!(
+                "{:>4} {:>8} {:<12} {:>7}s {}",
+                a.id,
+                a.pid,
+                a.state(),
+                a.started.elapsed().as_secs(),
+                a.args.join(" ")
+            ));
+        }
+        lines
+    }
+
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        let running = self
+            .attachments
+            .iter()
+            .filter(|a| a.status.is_none())
+            .count();
+        let log_bytes = self
+            .attachments
+            .iter()
+            .filter_map(|a| fs::metadata(&a.log).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+        vec![
+            ("uptime", self.started.elapsed().as_secs()),
+            ("requests", self.requests),
+            ("attachments", self.attachments.len() as u64),
+            ("attached", running as u64),
+            ("exited", (self.attachments.len() - running) as u64),
+            ("log_bytes", log_bytes),
+        ]
+    }
+
+    fn stats(&self) -> Vec<String> {
+        self.counters()
+            .iter()
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect()
+    }
+
+    fn execute(&mut self, request: CtlRequest) -> Result<(String, Vec<String>)> {
+        self.requests += 1;
+        match request {
+            CtlRequest::Attach { pid, args } => Ok((self.attach(pid, args)?.to_string(), vec![])),
+            CtlRequest::Detach { id } => Ok((format!("{} {}", id, self.detach(id)?), vec![])),
+            CtlRequest::List => Ok((String::new(), self.list())),
+            CtlRequest::Stats => Ok((String::new(), self.stats())),
+        }
+    }
+
+    fn handle(&mut self, stream: UnixStream) -> Result<()> {
+        check_peer(&stream)?;
+        try_with!(
+            stream.set_nonblocking(false),
+            "cannot make control connection blocking"
+        );
+        try_with!(
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT)),
+            "cannot set timeout of control connection"
+        );
+        let mut line = String::new();
+        try_with!(
+            BufReader::new(&stream).read_line(&mut line),
+            "cannot read request"
+        );
+        let line = line.trim_end_matches('\n');
+        if line.starts_with('{') || line.starts_with('[') {
+            return self.handle_rpc(stream, line);
+        }
+        let reply = match CtlRequest::parse(line).and_then(|r| self.execute(r)) {
+            Ok((msg, lines)) => {
+                let mut reply = if msg.is_empty() {
+                    String::from("ok\n")
+                } else {
+                    format!("ok {}\n", msg)
+                };
+                for l in lines {
+                    reply.push_str(&l);
+                    reply.push('\n');
+                }
+                reply
+            }
+            Err(e) => format!("error {}\n", e.to_string().replace('\n', " ")),
+        };
+        try_with!((&stream).write_all(reply.as_bytes()), "cannot send reply");
+        Ok(())
+    }
+
+    fn call(&mut self, call: RpcCall) -> RpcResult {
+        let server_error = |e: simple_error::SimpleError| RpcError::new(SERVER_ERROR, e);
+        Ok(match call {
+            RpcCall::Version => object(vec![
+                ("api", Value::from(API_VERSION)),
+                ("vmsh", Value::from(env!("CARGO_PKG_VERSION"))),
+            ]),
+            RpcCall::ListVms => {
+                let pids = find_hypervisors().map_err(server_error)?;
+                Value::Array(
+                    pids.iter()
+                        .map(|pid| {
+                            let attachment = self
+                                .attachments
+                                .iter()
+                                .find(|a| a.pid == *pid && a.status.is_none())
+                                .map_or(Value::Null, |a| Value::from(a.id));
+                            object(vec![
+                                ("pid", Value::from(pid.as_raw() as i64)),
+                                ("attachment", attachment),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            RpcCall::Attach { pid, args } => {
+                let id = self.attach(pid, args).map_err(server_error)?;
+                object(vec![("id", Value::from(id))])
+            }
+            RpcCall::Detach { id } => {
+                let state = self.detach(id).map_err(server_error)?;
+                object(vec![("id", Value::from(id)), ("state", Value::from(state))])
+            }
+            RpcCall::ListAttachments => {
+                Value::Array(self.attachments.iter().map(Attachment::to_json).collect())
+            }
+            RpcCall::Stats => Value::Object(
+                self.counters()
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), Value::from(value)))
+                    .collect(),
+            ),
+            call => return run_slow(call),
+        })
+    }
+
+    fn handle_rpc(&mut self, stream: UnixStream, line: &str) -> Result<()> {
+        self.requests += 1;
+        let (id, call) = parse_rpc(line);
+        let result = match call {
+            Ok(call) if call.is_slow() => {
+                // the connection is answered from the thread once the child vmsh is done
+                thread::spawn(move || {
+                    if let Err(e) = send_rpc_reply(&stream, id, run_slow(call)) {
+                        warn!("{}", e);
+                    }
+                });
+                return Ok(());
+            }
+            Ok(call) => self.call(call),
+            Err(e) => Err(e),
+        };
+        send_rpc_reply(&stream, id, result)
+    }
+
+    /// Records the exit of attachments that ended on their own, i.e. when their command exited.
+    fn reap(&mut self) {
+        for a in self.attachments.iter_mut().filter(|a| a.status.is_none()) {
+            match a.child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("attachment {} to {} ended with {}", a.id, a.pid, status);
+                    a.status = Some(status);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("cannot check attachment {}: {}", a.id, e),
+            }
+        }
+    }
+
+    fn serve(&mut self, listener: &UnixListener, cancel: &Cancellation) -> Result<()> {
+        while !cancel.is_cancelled() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle(stream) {
+                        warn!("{}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => bail!("cannot accept control connection: {}", e),
+            }
+            self.reap();
+        }
+        Ok(())
+    }
+
+    fn detach_all(&mut self) {
+        let ids = self.attachments.iter().map(|a| a.id).collect::<Vec<_>>();
+        for id in ids {
+            if let Err(e) = self.detach(id) {
+                warn!("cannot detach attachment {}: {}", id, e);
+            }
+        }
+    }
+}
+
+fn stop_child(attachment: &mut Attachment) -> Result<()> {
+    let pid = Pid::from_raw(attachment.child.id() as libc::pid_t);
+    try_with!(kill(pid, Signal::SIGINT), "cannot stop vmsh attach {}", pid);
+    let deadline = Instant::now() + DETACH_TIMEOUT;
+    loop {
+        if let Some(status) = try_with!(
+            attachment.child.try_wait(),
+            "cannot wait for vmsh attach {}",
+            pid
+        ) {
+            attachment.status = Some(status);
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            // killing it would leave its devices in the guest
+            bail!(
+                "vmsh attach {} did not detach within {}s",
+                pid,
+                DETACH_TIMEOUT.as_secs()
+            );
+        }
+        thread::sleep(ACCEPT_INTERVAL);
+    }
+}
+
+/// Refuses connections of other users than the one running the daemon, in case the socket was
+/// made accessible to them.
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    let cred = try_with!(
+        getsockopt(stream.as_raw_fd(), PeerCredentials),
+        "cannot get credentials of control connection"
+    );
+    if cred.uid() != 0 && cred.uid() != geteuid().as_raw() {
+        bail!("refusing control connection of uid {}", cred.uid());
+    }
+    Ok(())
+}
+
+/// A socket nobody listens on is left over from a daemon that did not exit cleanly.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let meta = try_with!(fs::symlink_metadata(path), "cannot stat {}", path.display());
+    if !meta.file_type().is_socket() {
+        bail!("refusing to remove non-socket file {}", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("another vmsh daemon listens on {}", path.display());
+    }
+    try_with!(fs::remove_file(path), "cannot remove {}", path.display());
+    Ok(())
+}
+
+pub fn daemon(opts: &DaemonOptions) -> Result<()> {
+    let cancel = Cancellation::setup()?;
+    remove_stale_socket(&opts.socket)?;
+    // attachments run as root, so only root may request them. The socket is created with
+    // mode 0600 rather than changed afterwards, so nobody else can connect in between.
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(&opts.socket);
+    umask(old_umask);
+    let listener = try_with!(listener, "cannot listen on {}", opts.socket.display());
+    try_with!(
+        listener.set_nonblocking(true),
+        "cannot make {} non-blocking",
+        opts.socket.display()
+    );
+    info!("listening on {}", opts.socket.display());
+
+    let mut daemon = Daemon {
+        socket: opts.socket.clone(),
+        attachments: vec![],
+        next_id: 1,
+        started: Instant::now(),
+        requests: 0,
+    };
+    let res = daemon.serve(&listener, &cancel);
+    daemon.detach_all();
+    if let Err(e) = fs::remove_file(&opts.socket) {
+        warn!("cannot remove {}: {}", opts.socket.display(), e);
+    }
+    res
+}
+
+pub fn ctl(opts: &CtlOptions) -> Result<()> {
+    let mut stream = try_with!(
+        UnixStream::connect(&opts.socket),
+        "cannot connect to {}, is vmsh daemon running?",
+        opts.socket.display()
+    );
+    try_with!(
+        stream.write_all(format!("{}\n", opts.request.to_line()?).as_bytes()),
+        "cannot send request to {}",
+        opts.socket.display()
+    );
+    // detaching waits for vmsh attach, so there is no timeout for the reply
+    let mut reply = String::new();
+    try_with!(
+        stream.read_to_string(&mut reply),
+        "cannot read reply from {}",
+        opts.socket.display()
+    );
+    let (msg, lines) = parse_reply(&reply)?;
+    if !msg.is_empty() {
+        println!("{}", msg);
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let requests = vec![
+            CtlRequest::Attach {
+                pid: Pid::from_raw(1234),
+                args: vec![
+                    String::from("-f"),
+                    String::from("/var/lib/images/tools disk.img"),
+                    String::from("--"),
+                    String::from("sleep"),
+                    String::from("infinity"),
+                ],
+            },
+            CtlRequest::Attach {
+                pid: Pid::from_raw(1),
+                args: vec![],
+            },
+            CtlRequest::Detach { id: 3 },
+            CtlRequest::List,
+            CtlRequest::Stats,
+        ];
+        for request in requests {
+            let line = request.to_line().unwrap();
+            assert_eq!(CtlRequest::parse(&line).unwrap(), request);
+        }
+        assert!(CtlRequest::parse("detach x").is_err());
+        assert!(CtlRequest::parse("list\textra").is_err());
+        let tab = CtlRequest::Attach {
+            pid: Pid::from_raw(1),
+            args: vec![String::from("a\tb")],
+        };
+        assert!(tab.to_line().is_err());
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+
+        let socket = dir.path().join("socket");
+        drop(UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("ok 1\n").unwrap(), (String::from("1"), vec![]));
+        assert_eq!(
+            parse_reply("ok\nuptime 3\nrequests 2\n").unwrap(),
+            (
+                String::new(),
+                vec![String::from("uptime 3"), String::from("requests 2")]
+            )
+        );
+        assert!(parse_reply("error no attachment 4\n").is_err());
+        assert!(parse_reply("").is_err());
+    }
+
+    #[test]
+    fn test_parse_rpc() {
+        let (id, call) = parse_rpc(
+            r#"{"jsonrpc": "2.0", "id": 7, "method": "v1.attach", "params": {"pid": 1234, "args": ["-f", "a b"]}}"#,
+        );
+        assert_eq!(id, Some(Value::Number(7.0)));
+        assert_eq!(
+            call,
+            Ok(RpcCall::Attach {
+                pid: Pid::from_raw(1234),
+                args: vec![String::from("-f"), String::from("a b")],
+            })
+        );
+        let (id, call) = parse_rpc(r#"{"jsonrpc": "2.0", "method": "version"}"#);
+        assert_eq!((id, call), (None, Ok(RpcCall::Version)));
+
+        let error = |line| parse_rpc(line).1.unwrap_err().code;
+        assert_eq!(error("{\"jsonrpc\""), PARSE_ERROR);
+        assert_eq!(error("[]"), INVALID_REQUEST);
+        assert_eq!(error(r#"{"method": "version"}"#), INVALID_REQUEST);
+        assert_eq!(
+            error(r#"{"jsonrpc": "2.0", "id": 1, "method": "v2.list_vms"}"#),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            error(
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "v1.exec", "params": {"pid": 1, "command": []}}"#
+            ),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error(
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "v1.coredump", "params": {"pid": 1, "path": "core"}}"#
+            ),
+            INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn test_rpc_reply() {
+        assert_eq!(
+            rpc_reply(
+                Value::from("a"),
+                Ok(object(vec![("id", Value::from(1u64))]))
+            ),
+            "{\"jsonrpc\":\"2.0\",\"id\":\"a\",\"result\":{\"id\":1}}\n"
+        );
+        assert_eq!(
+            rpc_reply(Value::Null, Err(RpcError::new(PARSE_ERROR, "x"))),
+            "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32700,\"message\":\"x\"}}\n"
+        );
+    }
+}
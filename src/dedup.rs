@@ -0,0 +1,252 @@
+//! Content-addressed storage for guest pages that the host shares between VMs.
+//!
+//! KSM merges identical pages of many similar guests into a single host page. When dumping such
+//! guests, these pages are written once into a page store shared by all dumps and left out of
+//! the coredump itself. A `<core>.dedup` index next to the coredump lists which guest physical
+//! pages have to be restored from the store. `CoreFile::open` does this transparently.
+use log::info;
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::format::DEDUP_INDEX;
+use crate::page_math::page_size;
+use crate::pagemap::{KPageFlags, PageMap, KPF_KSM, PM_PFN_MASK, PM_PRESENT};
+use crate::result::Result;
+use crate::sha256::{self, Digest};
+use crate::tracer::proc::Mapping;
+
+pub struct PageStore {
+    dir: PathBuf,
+}
+
+impl PageStore {
+    pub fn open(dir: &Path) -> Result<PageStore> {
+        try_with!(
+            fs::create_dir_all(dir.join("objects")),
+            "cannot create page store in {}",
+            dir.display()
+        );
+        let dir = try_with!(dir.canonicalize(), "cannot resolve {}", dir.display());
+        Ok(PageStore { dir })
+    }
+
+    fn object_path(&self, digest: &Digest) -> PathBuf {
+        let hex = sha256::to_hex(digest);
+        self.dir.join("objects").join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Store `page` unless the store already contains it.
+    pub fn insert(&self, page: &[u8]) -> Result<Digest> {
+        let digest = sha256::digest(page);
+        let path = self.object_path(&digest);
+        if path.exists() {
+            return Ok(digest);
+        }
+        let parent = path.parent().expect("object path has a parent");
+        try_with!(
+            fs::create_dir_all(parent),
+            "cannot create {}",
+            parent.display()
+        );
+        // other dumps might write the same page concurrently
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        try_with!(fs::write(&tmp, page), "cannot write {}", tmp.display());
+        try_with!(
+            fs::rename(&tmp, &path),
+            "cannot rename {} to {}",
+            tmp.display(),
+            path.display()
+        );
+        Ok(digest)
+    }
+
+    pub fn read(&self, digest: &Digest, buf: &mut [u8]) -> Result<()> {
+        let path = self.object_path(digest);
+        let data = try_with!(fs::read(&path), "cannot read {}", path.display());
+        if data.len() != buf.len() || sha256::digest(&data) != *digest {
+            bail!("{} is corrupted", path.display());
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// A guest page stored in the page store instead of the coredump.
+pub struct DedupPage {
+    pub phys_addr: usize,
+    pub digest: Digest,
+}
+
+pub fn index_path(core_path: &Path) -> PathBuf {
+    let mut path = core_path.as_os_str().to_owned();
+    path.push(".dedup");
+    PathBuf::from(path)
+}
+
+fn read_page(pid: Pid, host_addr: usize, buf: &mut [u8]) -> Result<()> {
+    let local = [IoVec::from_mut_slice(buf)];
+    let remote = [RemoteIoVec {
+        base: host_addr,
+        len: page_size(),
+    }];
+    try_with!(
+        process_vm_readv(pid, &local, &remote),
+        "cannot read hypervisor memory at {:#x}",
+        host_addr
+    );
+    Ok(())
+}
+
+/// Move all KSM pages of `maps` into `store`, except for pages at the host addresses in the sorted
+/// `exclude` list. Returns the pages and their host addresses in the order of `maps`. These
+/// addresses must be left out of the coredump.
+pub fn dedup_pages(
+    pid: Pid,
+    maps: &[Mapping],
+    store: &PageStore,
+    exclude: &[usize],
+) -> Result<(Vec<DedupPage>, Vec<usize>)> {
+    let pagemap = PageMap::open(pid)?;
+    let kpageflags = KPageFlags::open()?;
+    let mut ksm_pages = vec![];
+    let mut error = None;
+    for m in maps {
+        pagemap.scan(m.start, m.end, |addr, entry| {
+            let pfn = entry & PM_PFN_MASK;
+            if entry & PM_PRESENT == 0
+                || pfn == 0
+                || error.is_some()
+                || exclude.binary_search(&addr).is_ok()
+            {
+                return;
+            }
+            match kpageflags.get(pfn) {
+                Ok(flags) if flags & KPF_KSM != 0 => ksm_pages.push((m, addr, pfn)),
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        })?;
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    // pages with the same pfn have the same content, so each one is only hashed once
+    let mut digests: HashMap<u64, Digest> = HashMap::new();
+    let mut pages = vec![];
+    let mut host_addrs = vec![];
+    let mut buf = vec![0u8; page_size()];
+    for (m, addr, pfn) in ksm_pages {
+        let digest = match digests.get(&pfn) {
+            Some(d) => *d,
+            None => {
+                read_page(pid, addr, &mut buf)?;
+                let d = store.insert(&buf)?;
+                digests.insert(pfn, d);
+                d
+            }
+        };
+        pages.push(DedupPage {
+            phys_addr: m.phys_addr + (addr - m.start),
+            digest,
+        });
+        host_addrs.push(addr);
+    }
+    info!(
+        "{} guest pages are backed by {} KSM pages",
+        pages.len(),
+        digests.len()
+    );
+    Ok((pages, host_addrs))
+}
+
+pub fn write_index(core_path: &Path, store: &PageStore, pages: &[DedupPage]) -> Result<()> {
+    let path = index_path(core_path);
+    let mut content = format!("{}\n{}\n", DEDUP_INDEX.text_header(), store.dir.display());
+    for p in pages {
+        content.push_str(&format!(
+            "{:x} {}\n",
+            p.phys_addr,
+            sha256::to_hex(&p.digest)
+        ));
+    }
+    let mut file = try_with!(File::create(&path), "cannot create {}", path.display());
+    try_with!(
+        file.write_all(content.as_bytes()),
+        "cannot write {}",
+        path.display()
+    );
+    Ok(())
+}
+
+fn parse_digest(hex: &str) -> Option<Digest> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Read the index of `core_path` if the coredump was written with a page store.
+pub fn read_index(core_path: &Path) -> Result<Option<(PageStore, Vec<DedupPage>)>> {
+    let path = index_path(core_path);
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("cannot open {}: {}", path.display(), e),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let mut next_line = || -> Result<Option<String>> {
+        match lines.next() {
+            Some(l) => Ok(Some(try_with!(l, "cannot read {}", path.display()))),
+            None => Ok(None),
+        }
+    };
+    let header = next_line()?.unwrap_or_default();
+    let version = require_with!(
+        DEDUP_INDEX.header_version(&header),
+        "{} is not a dedup index",
+        path.display()
+    );
+    try_with!(DEDUP_INDEX.check(version), "cannot read {}", path.display());
+    let store = match next_line()? {
+        Some(dir) => PageStore {
+            dir: PathBuf::from(dir),
+        },
+        None => bail!("{} does not name a page store", path.display()),
+    };
+    let mut pages = vec![];
+    while let Some(line) = next_line()? {
+        let page = line.split_once(' ').and_then(|(addr, hex)| {
+            Some(DedupPage {
+                phys_addr: usize::from_str_radix(addr, 16).ok()?,
+                digest: parse_digest(hex)?,
+            })
+        });
+        match page {
+            Some(p) => pages.push(p),
+            None => bail!("invalid line in {}: {}", path.display(), line),
+        }
+    }
+    Ok(Some((store, pages)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_digest() {
+        let digest = sha256::digest(b"vmsh");
+        assert_eq!(parse_digest(&sha256::to_hex(&digest)), Some(digest));
+        assert_eq!(parse_digest("abc"), None);
+    }
+}
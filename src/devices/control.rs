@@ -0,0 +1,645 @@
+//! Add and remove block devices of a running `vmsh attach` session, see `vmsh device`.
+//!
+//! The session listens on `gc::control_path()` for one request per connection and answers with
+//! `ok <name>` or `error <message>`:
+//!
+//! ```text
+//! add-blk <raw|qcow2|auto> <ro|rw> <absolute path of the image>
+//! remove <name>
+//! ```
+//!
+//! Added devices get one of `HOTPLUG_SLOTS` pages of mmio space that the session reserved while
+//! attaching and share the interrupt of the other devices. Like `vmsh mem add`, a fresh stage1
+//! registers or unregisters the platform device in the guest and is removed from the guest again
+//! once it reported back, together with its memslot. The first vcpu has to be in the kernel at
+//! that point, otherwise the request fails and can be repeated. Without ioregionfd, vmsh can only
+//! touch the hypervisor in between vm exits, so requests wait for the next exit of the guest.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use stage1_interface::{DeviceAction, DeviceState};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_device::bus::{MmioAddress, MmioRange};
+use vm_device::device_manager::MmioManager;
+
+use crate::devices::threads::{
+    event_thread, ioregion_handler_thread, SubscriberEventManager, Threads,
+};
+use crate::devices::virtio::block::{nbd, BlockArgs, CacheMode, ImageFormat};
+use crate::devices::virtio::{CommonArgs, MmioConfig};
+use crate::devices::{
+    use_ioregionfd, Block, DeviceContext, DEVICE_NAMES, HOTPLUG_SLOTS, SHARED_IRQ,
+};
+use crate::gc::{self, Artifact};
+use crate::interrutable_thread::InterrutableThread;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::PhysMemAllocator;
+use crate::result::Result;
+use crate::stage1::{Stage1, Stage1Device};
+use crate::symbolizer::SymbolSource;
+
+pub struct AddBlkOptions {
+    pub pid: Pid,
+    pub image: PathBuf,
+    /// Raw if not set, the format is never probed
+    pub format: Option<ImageFormat>,
+    pub read_only: bool,
+    pub cache: CacheMode,
+}
+
+pub struct RemoveOptions {
+    pub pid: Pid,
+    /// As printed by `vmsh device add-blk`
+    pub name: String,
+}
+
+/// mmio space of each added device
+const SLOT_SIZE: usize = 0x1000;
+/// How often the control thread checks if it should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+enum Request {
+    AddBlk {
+        image: PathBuf,
+        format: Option<ImageFormat>,
+        read_only: bool,
+        cache: CacheMode,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Request> {
+        let mut fields = line.splitn(5, ' ');
+        match fields.next() {
+            Some("add-blk") => {
+                let (format, mode, cache, image) =
+                    match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                        (Some(format), Some(mode), Some(cache), Some(image))
+                            if !image.is_empty() =>
+                        {
+                            (format, mode, cache, image)
+                        }
+                        _ => bail!("invalid add-blk request '{}'", line),
+                    };
+                let format = match format {
+                    "auto" => None,
+                    name => Some(require_with!(
+                        ImageFormat::from_name(name),
+                        "unknown image format {}",
+                        name
+                    )),
+                };
+                let read_only = match mode {
+                    "ro" => true,
+                    "rw" => false,
+                    _ => bail!("invalid mode {}, expected ro or rw", mode),
+                };
+                let cache =
+                    require_with!(CacheMode::from_name(cache), "unknown cache mode {}", cache);
+                Ok(Request::AddBlk {
+                    image: PathBuf::from(image),
+                    format,
+                    read_only,
+                    cache,
+                })
+            }
+            Some("remove") => match (fields.next(), fields.next()) {
+                (Some(name), None) if !name.is_empty() => Ok(Request::Remove {
+                    name: name.to_string(),
+                }),
+                _ => bail!("invalid remove request '{}'", line),
+            },
+            _ => bail!("unknown request '{}'", line),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            Request::AddBlk {
+                image,
+                format,
+                read_only,
+                cache,
+            } => format!(
+                "add-blk {} {} {} {}",
+                format.map_or("auto", ImageFormat::name),
+                if *read_only { "ro" } else { "rw" },
+                cache.name(),
+                image.display()
+            ),
+            Request::Remove { name } => format!("remove {}", name),
+        }
+    }
+}
+
+/// Name of the device from an `ok` reply, the message of an `error` reply as error.
+fn parse_reply(line: &str) -> Result<String> {
+    if let Some(name) = line.strip_prefix("ok ") {
+        return Ok(name.to_string());
+    }
+    if let Some(msg) = line.strip_prefix("error ") {
+        bail!("{}", msg);
+    }
+    if line.is_empty() {
+        bail!("vmsh session closed the connection without a reply");
+    }
+    bail!("invalid reply '{}'", line)
+}
+
+/// A block device added with `vmsh device add-blk`
+struct AddedBlock {
+    name: String,
+    slot: usize,
+    mmio_cfg: MmioConfig,
+    block: Arc<Mutex<Block>>,
+    /// Platform device in the guest, 0 if it is unknown whether stage1 registered it
+    handle: u64,
+    threads: Threads,
+}
+
+struct Server {
+    vm: Arc<Hypervisor>,
+    context: Arc<DeviceContext>,
+    err_sender: SyncSender<()>,
+    devices: Vec<AddedBlock>,
+    /// Passed on to stage1, see `AttachOptions::symbols`
+    symbols: SymbolSource,
+}
+
+/// Waits until stage1 is done, returns the driver status and the handle of the device.
+fn wait_for_stage1(vm: &Hypervisor, stage1: &mut Stage1) -> Result<(DeviceState, u64)> {
+    match stage1.wait(vm)? {
+        DeviceState::Terminating => {
+            let handles = stage1.device_handles.read(vm)?;
+            Ok((
+                DeviceState::Terminating,
+                handles.first().copied().unwrap_or(0),
+            ))
+        }
+        state => Ok((state, 0)),
+    }
+}
+
+impl Server {
+    fn stage1_device(&self, dev: &AddedBlock) -> Stage1Device {
+        Stage1Device {
+            mmio_addr: dev.mmio_cfg.range.base().0,
+            irq: dev.mmio_cfg.gsi,
+            cpu: None,
+            id: (DEVICE_NAMES.len() + dev.slot) as u32,
+            handle: dev.handle,
+        }
+    }
+
+    /// Runs stage1 in the guest to add or remove `device`. Returns the driver status stage1
+    /// reported and the handle of the device. On error, the state of the device in the guest is
+    /// unknown.
+    fn run_stage1(&self, device: Stage1Device, action: DeviceAction) -> Result<(DeviceState, u64)> {
+        let vm = Arc::clone(&self.vm);
+        let symbols = self.symbols.clone();
+        let mut stage1 = self.context.run_traced(&self.vm, move || {
+            let allocator = try_with!(
+                PhysMemAllocator::new(Arc::clone(&vm)),
+                "cannot create allocator"
+            );
+            // stage1 does not start stage2 for device changes, but expects a stage2 path in argv
+            let command = vec![String::from("/dev/.vmsh")];
+            let stage1 = try_with!(
+                Stage1::new(
+                    allocator,
+                    &command,
+                    &[],
+                    vec![device],
+                    action,
+                    None,
+                    symbols.fallbacks()
+                ),
+                "failed to initialize stage1"
+            );
+            stage1.start(&vm)?;
+            Ok(stage1)
+        })?;
+        let res = wait_for_stage1(&self.vm, &mut stage1);
+        if res.is_ok() {
+            self.context.run_traced(&self.vm, move || {
+                drop(stage1);
+                Ok(())
+            })?;
+        } else {
+            // stage1 might still be running
+            warn!("leave stage1 in guest memory");
+            std::mem::forget(stage1);
+        }
+        res
+    }
+
+    /// Stops the threads of `dev` and removes it from the vm. The guest must not use it anymore.
+    fn release(&self, dev: AddedBlock) -> Result<()> {
+        dev.threads.iter().for_each(|t| t.shutdown());
+        for thread in dev.threads {
+            if let Err(e) = thread.join().and_then(|(res, _)| res) {
+                warn!("{}", e);
+            }
+        }
+        if let Err(e) = try_with!(dev.block.lock(), "cannot lock block device").sync() {
+            warn!("cannot sync {}: {:?}", dev.name, e);
+        }
+        let context = Arc::clone(&self.context);
+        let block = dev.block;
+        let base = dev.mmio_cfg.range.base();
+        self.context.run_traced(&self.vm, move || {
+            try_with!(context.mmio_mgr.lock(), "cannot lock mmio manager").deregister_mmio(base);
+            let res = try_with!(block.lock(), "cannot lock block device").remove_irqfd();
+            // also removes the ioeventfd and ioregionfd of the device from the vm
+            drop(block);
+            match res {
+                Ok(()) => Ok(()),
+                Err(e) => bail!("cannot remove irqfd: {:?}", e),
+            }
+        })
+    }
+
+    fn start(&self, dev: &mut AddedBlock, event_mgr: SubscriberEventManager) -> Result<()> {
+        let ack_handler = {
+            let block = try_with!(dev.block.lock(), "cannot lock block device");
+            block.irq_ack_handler.clone()
+        };
+        dev.threads
+            .push(event_thread(event_mgr, ack_handler, &self.err_sender)?);
+        if use_ioregionfd() {
+            dev.threads.push(try_with!(
+                ioregion_handler_thread(
+                    Arc::clone(&self.context),
+                    dev.block.clone(),
+                    Arc::clone(&self.context.mmio_mgr),
+                    &self.err_sender,
+                ),
+                "cannot spawn ioregion handler of {}",
+                dev.name
+            ));
+        }
+        Ok(())
+    }
+
+    fn add_blk(
+        &mut self,
+        image: PathBuf,
+        format: Option<ImageFormat>,
+        read_only: bool,
+        cache: CacheMode,
+    ) -> Result<String> {
+        let slot = require_with!(
+            (0..HOTPLUG_SLOTS).find(|s| !self.devices.iter().any(|d| d.slot == *s)),
+            "all {} devices that can be added are in use",
+            HOTPLUG_SLOTS
+        );
+        let base = self.context.hotplug_mmio.base().0 + (slot * SLOT_SIZE) as u64;
+        let mmio_cfg = MmioConfig {
+            range: try_with!(
+                MmioRange::new(MmioAddress(base), SLOT_SIZE as u64),
+                "invalid mmio range"
+            ),
+            gsi: SHARED_IRQ,
+        };
+
+        let mut event_mgr = try_with!(SubscriberEventManager::new(), "cannot create event manager");
+        let vm = Arc::clone(&self.vm);
+        let context = Arc::clone(&self.context);
+        let (block, event_mgr) = self.context.run_traced(&self.vm, move || {
+            let block = {
+                let guard = try_with!(context.mmio_mgr.lock(), "cannot lock mmio manager");
+                let common = CommonArgs {
+                    mem: Arc::clone(&context.mem),
+                    vmm: vm,
+                    event_mgr: &mut event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                };
+                let args = BlockArgs {
+                    common,
+                    file_path: image,
+                    format,
+                    read_only,
+                    cache,
+                    faults: None,
+                    root_device: false,
+                    advertise_flush: true,
+                };
+                match Block::new(args) {
+                    Ok(v) => v,
+                    Err(e) => bail!("cannot create block device: {:?}", e),
+                }
+            };
+            Ok((block, event_mgr))
+        })?;
+
+        let mut dev = AddedBlock {
+            name: format!("blk{}", slot + 1),
+            slot,
+            mmio_cfg,
+            block,
+            handle: 0,
+            threads: vec![],
+        };
+        if let Err(e) = self.start(&mut dev, event_mgr) {
+            if let Err(e) = self.release(dev) {
+                warn!("{}", e);
+            }
+            return Err(e);
+        }
+        let name = dev.name.clone();
+        match self.run_stage1(self.stage1_device(&dev), DeviceAction::Add) {
+            Ok((DeviceState::Terminating, handle)) => dev.handle = handle,
+            Ok(_) => {
+                self.release(dev)?;
+                bail!("guest failed to add the device, see the kernel log of the guest");
+            }
+            Err(e) => {
+                // the guest might still register the device later on
+                self.devices.push(dev);
+                bail!("{}, keeping {} until it is removed", e, name);
+            }
+        }
+        gc::record(&Artifact::Irq {
+            device: name.clone(),
+            gsi: dev.mmio_cfg.gsi,
+        });
+        info!("added {} at {:#x}", name, base);
+        self.devices.push(dev);
+        Ok(name)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        let idx = require_with!(
+            self.devices.iter().position(|d| d.name == name),
+            "no device {} was added",
+            name
+        );
+        let device = self.stage1_device(&self.devices[idx]);
+        let (state, _) = self.run_stage1(device, DeviceAction::Remove)?;
+        if state != DeviceState::Terminating {
+            bail!(
+                "guest failed to remove {}, see the kernel log of the guest",
+                name
+            );
+        }
+        let dev = self.devices.remove(idx);
+        let gsi = dev.mmio_cfg.gsi;
+        self.release(dev)?;
+        gc::forget(&Artifact::Irq {
+            device: name.to_string(),
+            gsi,
+        });
+        info!("removed {}", name);
+        Ok(())
+    }
+
+    fn remove_all(&mut self) {
+        let names = self
+            .devices
+            .iter()
+            .rev()
+            .map(|d| d.name.clone())
+            .collect::<Vec<_>>();
+        for name in names {
+            if let Err(e) = self.remove(&name) {
+                warn!("cannot remove {}: {}", name, e);
+            }
+        }
+    }
+
+    fn execute(&mut self, request: Request) -> Result<String> {
+        match request {
+            Request::AddBlk {
+                image,
+                format,
+                read_only,
+                cache,
+            } => self.add_blk(image, format, read_only, cache),
+            Request::Remove { name } => {
+                self.remove(&name)?;
+                Ok(name)
+            }
+        }
+    }
+
+    fn handle(&mut self, stream: UnixStream) -> Result<()> {
+        try_with!(
+            stream.set_nonblocking(false),
+            "cannot make control connection blocking"
+        );
+        try_with!(
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT)),
+            "cannot set timeout of control connection"
+        );
+        let mut line = String::new();
+        try_with!(
+            BufReader::new(&stream).read_line(&mut line),
+            "cannot read request"
+        );
+        let reply = match Request::parse(line.trim_end_matches('\n')).and_then(|r| self.execute(r))
+        {
+            Ok(name) => format!("ok {}\n", name),
+            Err(e) => format!("error {}\n", e.to_string().replace('\n', " ")),
+        };
+        try_with!((&stream).write_all(reply.as_bytes()), "cannot send reply");
+        Ok(())
+    }
+
+    fn serve(&mut self, listener: &UnixListener, should_stop: &AtomicBool) -> Result<()> {
+        while !should_stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle(stream) {
+                        warn!("{}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => bail!("cannot accept control connection: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves `vmsh device` requests for the devices of `context` until it is shut down, then
+/// removes the devices it added. Must be shut down before the threads of the devices.
+pub fn control_thread(
+    vm: &Arc<Hypervisor>,
+    context: Arc<DeviceContext>,
+    err_sender: &SyncSender<()>,
+    symbols: SymbolSource,
+) -> Result<InterrutableThread<(), ()>> {
+    let path = gc::control_path(Pid::this())?;
+    let listener = try_with!(
+        UnixListener::bind(&path),
+        "cannot listen on {}",
+        path.display()
+    );
+    try_with!(
+        listener.set_nonblocking(true),
+        "cannot make {} non-blocking",
+        path.display()
+    );
+    gc::record(&Artifact::Socket(path.clone()));
+
+    let mut server = Server {
+        vm: Arc::clone(vm),
+        context,
+        err_sender: err_sender.clone(),
+        devices: vec![],
+        symbols,
+    };
+    let res = InterrutableThread::spawn(
+        "device-control",
+        err_sender,
+        move |_ctx: &(), should_stop: Arc<AtomicBool>| {
+            let res = server.serve(&listener, &should_stop);
+            server.remove_all();
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("cannot remove {}: {}", path.display(), e);
+            }
+            res
+        },
+        (),
+    );
+    Ok(try_with!(res, "cannot spawn device control thread"))
+}
+
+/// Control socket of the `vmsh attach` session of the hypervisor `pid`
+fn find_session(pid: Pid) -> Result<PathBuf> {
+    let mut sockets = vec![];
+    for (vmsh, _) in gc::running_sessions(pid)? {
+        let path = gc::control_path(vmsh)?;
+        if path.exists() {
+            sockets.push(path);
+        }
+    }
+    match sockets.len() {
+        0 => bail!("no vmsh session is attached to {}", pid),
+        1 => Ok(sockets.remove(0)),
+        n => bail!("{} vmsh sessions are attached to {}", n, pid),
+    }
+}
+
+fn send(pid: Pid, request: &Request) -> Result<String> {
+    let path = find_session(pid)?;
+    let mut stream = try_with!(
+        UnixStream::connect(&path),
+        "cannot connect to {}",
+        path.display()
+    );
+    try_with!(
+        stream.write_all(format!("{}\n", request.to_line()).as_bytes()),
+        "cannot send request to {}",
+        path.display()
+    );
+    let mut reply = String::new();
+    try_with!(
+        BufReader::new(&stream).read_line(&mut reply),
+        "cannot read reply from {}",
+        path.display()
+    );
+    parse_reply(reply.trim_end_matches('\n'))
+}
+
+pub fn add_blk(opts: &AddBlkOptions) -> Result<()> {
+    // the session opens the image in its own working directory
+    let image = match nbd::parse_url(&opts.image) {
+        Some(_) => opts.image.clone(),
+        None => try_with!(
+            opts.image.canonicalize(),
+            "cannot find {}",
+            opts.image.display()
+        ),
+    };
+    let name = send(
+        opts.pid,
+        &Request::AddBlk {
+            image,
+            format: opts.format,
+            read_only: opts.read_only,
+            cache: opts.cache,
+        },
+    )?;
+    println!("{}", name);
+    Ok(())
+}
+
+pub fn remove(opts: &RemoveOptions) -> Result<()> {
+    send(
+        opts.pid,
+        &Request::Remove {
+            name: opts.name.clone(),
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let requests = vec![
+            Request::AddBlk {
+                image: PathBuf::from("/var/lib/images/data disk.qcow2"),
+                format: Some(ImageFormat::Qcow2),
+                read_only: true,
+                cache: CacheMode::Unsafe,
+            },
+            Request::AddBlk {
+                image: PathBuf::from("/tmp/disk.img"),
+                format: None,
+                read_only: false,
+                cache: CacheMode::Writeback,
+            },
+            Request::Remove {
+                name: String::from("blk1"),
+            },
+        ];
+        for request in requests {
+            assert_eq!(Request::parse(&request.to_line()).unwrap(), request);
+        }
+        assert_eq!(
+            Request::AddBlk {
+                image: PathBuf::from("/tmp/disk.img"),
+                format: Some(ImageFormat::Raw),
+                read_only: false,
+                cache: CacheMode::None,
+            }
+            .to_line(),
+            "add-blk raw rw none /tmp/disk.img"
+        );
+        assert!(Request::parse("add-blk vmdk rw none /tmp/disk.img").is_err());
+        assert!(Request::parse("add-blk raw rx none /tmp/disk.img").is_err());
+        assert!(Request::parse("add-blk raw rw directsync /tmp/disk.img").is_err());
+        assert!(Request::parse("add-blk raw rw none").is_err());
+        assert!(Request::parse("remove").is_err());
+        assert!(Request::parse("remove blk1 blk2").is_err());
+        assert!(Request::parse("detach").is_err());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("ok blk2").unwrap(), "blk2");
+        let err = parse_reply("error no device blk3 was added").unwrap_err();
+        assert_eq!(err.to_string(), "no device blk3 was added");
+        assert!(parse_reply("").is_err());
+        assert!(parse_reply("maybe").is_err());
+    }
+}
@@ -3,24 +3,32 @@ use crate::kvm::kvm_ioregionfd::{ioregionfd_cmd, Cmd};
 use crate::result::Result;
 use crate::tracer::wrap_syscall::{MmioRw, MMIO_RW_DATA_MAX};
 use simple_error::{map_err_with, try_with};
+use std::collections::HashMap;
 use std::sync::Arc;
 use vm_device::bus::{Bus, BusManager, MmioAddress};
 use vm_device::device_manager::MmioManager;
 use vm_device::DeviceMmio;
 
 type MmioPirateBus<D> = Bus<MmioAddress, D>;
+type MmioDevice = Arc<dyn DeviceMmio + Send + Sync>;
 
 /// Replacement for vm_device::device_manager::IoManager.
 /// Can implement MmioManager via vm_device::device_manager::MmioManager.
 pub struct IoPirate {
     /// mmio device spaces typically accessed by VM exit mmio
-    mmio_bus: MmioPirateBus<Arc<dyn DeviceMmio + Send + Sync>>,
+    mmio_bus: MmioPirateBus<MmioDevice>,
+    /// Base address and device of each address and access size that was accessed before.
+    /// Drivers access the same few registers over and over, so this skips looking them up in
+    /// `mmio_bus` on most exits. The exit does not tell the guest instruction that caused it,
+    /// getting its rip would cost a KVM_GET_REGS, so accesses are told apart by address only.
+    dispatch_cache: HashMap<(u64, usize), (MmioAddress, MmioDevice)>,
 }
 
 impl Default for IoPirate {
     fn default() -> IoPirate {
         IoPirate {
             mmio_bus: Bus::new(),
+            dispatch_cache: HashMap::new(),
         }
     }
 }
@@ -38,23 +46,32 @@ impl IoPirate {
     //    Ok(())
     //}
 
+    /// Base address and device that handle an access of `len` bytes at `addr`.
+    fn dispatch(&mut self, addr: u64, len: usize) -> Result<(MmioAddress, MmioDevice)> {
+        if let Some((base, device)) = self.dispatch_cache.get(&(addr, len)) {
+            return Ok((*base, Arc::clone(device)));
+        }
+        let (range, device) = map_err_with!(
+            self.mmio_bus.check_access(MmioAddress(addr), len),
+            "no mmio device at {:#x}",
+            addr
+        )?;
+        let entry = (range.base(), Arc::clone(device));
+        self.dispatch_cache.insert((addr, len), entry.clone());
+        Ok(entry)
+    }
+
     /// Used with MmioExitWrapper.
     pub fn handle_mmio_rw(&mut self, mmio_rw: &mut MmioRw) -> Result<()> {
+        let len = mmio_rw.data().len();
+        let (base, device) = self.dispatch(mmio_rw.addr, len)?;
+        let offset = mmio_rw.addr - base.0;
         if mmio_rw.is_write {
-            map_err_with!(
-                self.mmio_write(MmioAddress(mmio_rw.addr), mmio_rw.data()),
-                "write to mmio device ({:#x}) failed",
-                mmio_rw.addr
-            )?;
+            device.mmio_write(base, offset, mmio_rw.data());
         } else {
             let mut data = [0u8; MMIO_RW_DATA_MAX];
-            let len = mmio_rw.data().len();
             let slice = &mut data[0..len];
-            map_err_with!(
-                self.mmio_read(MmioAddress(mmio_rw.addr), slice),
-                "read from mmio device ({:#x}) failed",
-                mmio_rw.addr
-            )?;
+            device.mmio_read(base, offset, slice);
             mmio_rw.answer_read(slice)?;
         }
         Ok(())
@@ -102,6 +119,8 @@ impl BusManager<MmioAddress> for IoPirate {
     }
 
     fn bus_mut(&mut self) -> &mut MmioPirateBus<Arc<dyn DeviceMmio + Send + Sync>> {
+        // devices might be registered or removed
+        self.dispatch_cache.clear();
         &mut self.mmio_bus
     }
 }
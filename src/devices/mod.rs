@@ -1,23 +1,30 @@
+pub mod control;
 pub mod mmio;
 mod threads;
 pub mod virtio;
 
 use crate::devices::mmio::IoPirate;
 use crate::devices::threads::SubscriberEventManager;
-use crate::devices::virtio::block::{self, BlockArgs};
+use crate::devices::virtio::block::{self, BlockArgs, CacheMode, FaultOptions, ImageFormat};
 use crate::devices::virtio::console::{self, ConsoleArgs};
+use crate::devices::virtio::p9::{self, P9Args, ShareOptions};
+use crate::devices::virtio::vsock::{self, VsockArgs, VsockOptions};
 use crate::devices::virtio::{CommonArgs, MmioConfig};
+use crate::gc::{self, Artifact};
 use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
 use crate::result::Result;
+use crate::stage1::Stage1Device;
 use crate::tracer::proc::Mapping;
 use libc::pid_t;
-use simple_error::{bail, try_with};
+use simple_error::{bail, require_with, try_with};
 use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
 use std::sync::{Arc, Mutex};
+use vm_device::bus::MmioRange;
 use vm_device::device_manager::MmioManager;
 use vm_memory::guest_memory::GuestAddress;
 use vm_memory::mmap::MmapRegion;
@@ -33,8 +40,116 @@ pub fn use_ioregionfd() -> bool {
     USE_IOREGIONFD.load(Ordering::Relaxed)
 }
 
+/// Names of the devices in `vmsh attach --irq-affinity`
+pub const DEVICE_NAMES: &[&str] = &["block", "console", "vsock", "9p"];
+
+/// Interrupt that all devices without an affinity share.
+const SHARED_IRQ: u32 = 5;
+/// Number of devices that `vmsh device add-blk` can add to a running session
+const HOTPLUG_SLOTS: usize = 4;
+/// Legacy isa interrupts that devices with an affinity get for themselves, in order of
+/// preference. Only those that no guest driver has unmasked in the ioapic are used.
+const DEDICATED_IRQS: &[u32] = &[7, 6, 3, 10, 11];
+/// Mask bit of an ioapic redirection table entry
+const IOAPIC_MASKED: u64 = 1 << 16;
+
+/// Pins the interrupt of a device to a guest cpu, see `vmsh attach --irq-affinity`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrqAffinity {
+    /// One of `DEVICE_NAMES`
+    pub device: String,
+    pub cpu: u32,
+}
+
+impl IrqAffinity {
+    /// Parses `DEVICE=CPU`
+    pub fn parse(arg: &str) -> Result<IrqAffinity> {
+        let (device, cpu) = match arg.split_once('=') {
+            Some(v) => v,
+            None => bail!("expected DEVICE=CPU, got {}", arg),
+        };
+        if !DEVICE_NAMES.contains(&device) {
+            bail!(
+                "unknown device {}, expected one of {}",
+                device,
+                DEVICE_NAMES.join(", ")
+            );
+        }
+        Ok(IrqAffinity {
+            device: device.to_string(),
+            cpu: try_with!(cpu.parse::<u32>(), "invalid cpu '{}' in {}", cpu, arg),
+        })
+    }
+}
+
+/// The first `count` of `DEDICATED_IRQS` that are masked in the ioapic redirection table
+/// `redirtbl`.
+fn free_irqs(redirtbl: &[u64], count: usize) -> Result<Vec<u32>> {
+    let free = DEDICATED_IRQS
+        .iter()
+        .copied()
+        .filter(|irq| {
+            redirtbl
+                .get(*irq as usize)
+                .map_or(false, |entry| entry & IOAPIC_MASKED != 0)
+        })
+        .take(count)
+        .collect::<Vec<_>>();
+    if free.len() < count {
+        bail!(
+            "only {} of the interrupts {:?} are unused by the guest, cannot give {} devices their own interrupt",
+            free.len(),
+            DEDICATED_IRQS,
+            count
+        );
+    }
+    Ok(free)
+}
+
+/// Interrupt of each device, devices with an affinity get one of `DEDICATED_IRQS`.
+fn assign_irqs(
+    vmm: &Hypervisor,
+    irq_affinity: &[IrqAffinity],
+    vsock: bool,
+    share: bool,
+) -> Result<Vec<(&'static str, u32)>> {
+    for (i, a) in irq_affinity.iter().enumerate() {
+        if irq_affinity[..i].iter().any(|b| b.device == a.device) {
+            bail!("--irq-affinity is given twice for {}", a.device);
+        }
+        if (a.device == "vsock" && !vsock) || (a.device == "9p" && !share) {
+            bail!("--irq-affinity {} is given without the device", a.device);
+        }
+        if a.cpu as usize >= vmm.vcpus.len() {
+            bail!(
+                "cannot pin {} to cpu {}, the vm has {} vcpus",
+                a.device,
+                a.cpu,
+                vmm.vcpus.len()
+            );
+        }
+    }
+    let dedicated = if irq_affinity.is_empty() {
+        vec![]
+    } else {
+        free_irqs(&vmm.get_ioapic_redirtbl()?, irq_affinity.len())?
+    };
+    Ok(DEVICE_NAMES
+        .iter()
+        .map(|name| {
+            let irq = irq_affinity
+                .iter()
+                .position(|a| a.device == *name)
+                .map_or(SHARED_IRQ, |i| dedicated[i]);
+            (*name, irq)
+        })
+        .collect())
+}
+
 pub type Block = block::Block<Arc<GuestMemoryMmap>>;
 pub type Console = console::Console<Arc<GuestMemoryMmap>>;
+pub type Vsock = vsock::Vsock<Arc<GuestMemoryMmap>>;
+pub type P9 = p9::P9<Arc<GuestMemoryMmap>>;
 
 fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
     let mut regions: Vec<Arc<GuestRegionMmap>> = vec![];
@@ -75,54 +190,182 @@ trait MaybeIoRegionFd {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd>;
 }
 
+/// Work that needs ptrace control of the hypervisor, see `DeviceContext::run_traced`.
+type TracedJob = Box<dyn FnOnce() + Send>;
+
 pub struct DeviceContext {
+    pub mem: Arc<GuestMemoryMmap>,
     pub blkdev: Arc<Mutex<Block>>,
     pub console: Arc<Mutex<Console>>,
+    /// only created with `vmsh attach --vsock`
+    pub vsock: Option<Arc<Mutex<Vsock>>>,
+    /// only created with `vmsh attach --share`
+    pub p9: Option<Arc<Mutex<P9>>>,
     pub mmio_mgr: Arc<Mutex<IoPirate>>,
     /// start address of mmio space
     pub first_mmio_addr: u64,
     /// start address of mmio space
     pub last_mmio_addr: u64,
+    /// `HOTPLUG_SLOTS` pages of mmio space for devices added with `vmsh device add-blk`
+    pub hotplug_mmio: MmioRange,
+    pub irq_affinity: Vec<IrqAffinity>,
+    /// Run by the mmio exit handler thread in between vm exits
+    /// None once it stopped.
+    traced_jobs: Mutex<Option<Vec<TracedJob>>>,
 }
 
 impl DeviceContext {
-    pub fn mmio_addrs(&self) -> Result<Vec<u64>> {
-        Ok(vec![
-            try_with!(self.blkdev.lock(), "cannot lock block device")
-                .mmio_cfg
-                .range
-                .base()
-                .0,
-            try_with!(self.console.lock(), "cannot lock console device")
-                .mmio_cfg
-                .range
-                .base()
-                .0,
-        ])
+    /// Runs `f` while vmsh can make ioctls in the hypervisor. With ioregionfd the vm is stopped
+    /// meanwhile. Otherwise the mmio exit handler thread owns ptrace, so `f` is handed to it and
+    /// runs after the next vm exit, which might take a while if the guest is idle.
+    pub fn run_traced<T: Send + 'static>(
+        &self,
+        vm: &Hypervisor,
+        f: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        if use_ioregionfd() {
+            let _stopped = vm.stop_guard()?;
+            return f();
+        }
+        let (sender, receiver) = sync_channel(1);
+        {
+            let mut jobs = try_with!(self.traced_jobs.lock(), "cannot lock traced jobs");
+            let jobs = require_with!(jobs.as_mut(), "mmio exit handler is not running");
+            jobs.push(Box::new(move || {
+                let _ = sender.send(f());
+            }));
+        }
+        match receiver.recv() {
+            Ok(res) => res,
+            Err(_) => bail!("mmio exit handler stopped before it ran the job"),
+        }
+    }
+
+    /// Jobs queued by `run_traced`
+    fn take_traced_jobs(&self) -> Result<Vec<TracedJob>> {
+        let mut jobs = try_with!(self.traced_jobs.lock(), "cannot lock traced jobs");
+        Ok(jobs.as_mut().map(std::mem::take).unwrap_or_default())
+    }
+
+    /// Called when the mmio exit handler stops: pending and later jobs fail.
+    fn close_traced_jobs(&self) {
+        if let Ok(mut jobs) = self.traced_jobs.lock() {
+            *jobs = None;
+        }
+    }
+
+    /// The devices in the order of `DEVICE_NAMES`, as stage1 registers them in the guest.
+    pub fn stage1_devices(&self) -> Result<Vec<Stage1Device>> {
+        let mut cfgs = vec![
+            (
+                "block",
+                try_with!(self.blkdev.lock(), "cannot lock block device").mmio_cfg,
+            ),
+            (
+                "console",
+                try_with!(self.console.lock(), "cannot lock console device").mmio_cfg,
+            ),
+        ];
+        if let Some(vsock) = &self.vsock {
+            cfgs.push((
+                "vsock",
+                try_with!(vsock.lock(), "cannot lock vsock device").mmio_cfg,
+            ));
+        }
+        if let Some(p9) = &self.p9 {
+            cfgs.push(("9p", try_with!(p9.lock(), "cannot lock 9p device").mmio_cfg));
+        }
+        Ok(cfgs
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, cfg))| Stage1Device {
+                mmio_addr: cfg.range.base().0,
+                irq: cfg.gsi,
+                cpu: self
+                    .irq_affinity
+                    .iter()
+                    .find(|a| a.device == name)
+                    .map(|a| a.cpu),
+                id: i as u32,
+                handle: 0,
+            })
+            .collect())
     }
     pub fn new(
         vmm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
         event_mgr: &mut SubscriberEventManager,
         backing: &Path,
+        format: Option<ImageFormat>,
+        read_only: bool,
+        cache: CacheMode,
+        faults: Option<&FaultOptions>,
+        vsock_opts: Option<&VsockOptions>,
+        share_opts: Option<&ShareOptions>,
+        irq_affinity: &[IrqAffinity],
     ) -> Result<DeviceContext> {
         let guest_memory = try_with!(vmm.get_maps(), "cannot get guests memory");
         let mem = Arc::new(try_with!(
             convert(vmm.pid.as_raw(), &guest_memory),
             "cannot convert Mapping to GuestMemoryMmap"
         ));
+        let irqs = assign_irqs(
+            vmm,
+            irq_affinity,
+            vsock_opts.is_some(),
+            share_opts.is_some(),
+        )?;
+        let irq = |name: &str| {
+            irqs.iter()
+                .find(|(n, _)| *n == name)
+                .map_or(SHARED_IRQ, |(_, irq)| *irq)
+        };
 
         let block_mmio_cfg = MmioConfig {
             range: allocator.alloc_mmio_range(0x1000)?,
-            gsi: 5,
+            gsi: irq("block"),
         };
 
         let console_mmio_cfg = MmioConfig {
             range: allocator.alloc_mmio_range(0x1000)?,
-            gsi: 5,
+            gsi: irq("console"),
         };
 
-        let first_mmio_addr = console_mmio_cfg.range.base().0;
+        let vsock_mmio_cfg = match vsock_opts {
+            Some(_) => Some(MmioConfig {
+                range: allocator.alloc_mmio_range(0x1000)?,
+                gsi: irq("vsock"),
+            }),
+            None => None,
+        };
+
+        let p9_mmio_cfg = match share_opts {
+            Some(_) => Some(MmioConfig {
+                range: allocator.alloc_mmio_range(0x1000)?,
+                gsi: irq("9p"),
+            }),
+            None => None,
+        };
+
+        let hotplug_mmio = allocator.alloc_mmio_range(0x1000 * HOTPLUG_SLOTS)?;
+
+        let devices = [
+            ("block", Some(block_mmio_cfg)),
+            ("console", Some(console_mmio_cfg)),
+            ("vsock", vsock_mmio_cfg),
+            ("9p", p9_mmio_cfg),
+        ];
+        for (name, cfg) in devices.iter() {
+            if let Some(cfg) = cfg {
+                gc::record(&Artifact::Irq {
+                    device: name.to_string(),
+                    gsi: cfg.gsi,
+                });
+            }
+        }
+
+        // mmio ranges are allocated top-down
+        let first_mmio_addr = hotplug_mmio.base().0;
         let last_mmio_addr = block_mmio_cfg.range.last().0;
 
         // IoManager replacement:
@@ -141,7 +384,10 @@ impl DeviceContext {
             let args = BlockArgs {
                 common,
                 file_path: backing.to_path_buf(),
-                read_only: false,
+                format,
+                read_only,
+                cache,
+                faults: faults.cloned(),
                 root_device: true,
                 advertise_flush: true,
             };
@@ -155,7 +401,7 @@ impl DeviceContext {
             guard.mmio_device(console_mmio_cfg.range.base());
 
             let common = CommonArgs {
-                mem,
+                mem: Arc::clone(&mem),
                 vmm: vmm.clone(),
                 event_mgr,
                 mmio_mgr: guard,
@@ -168,15 +414,99 @@ impl DeviceContext {
                 Err(e) => bail!("cannot create console device: {:?}", e),
             }
         };
+        let vsock = match (vsock_opts, vsock_mmio_cfg) {
+            (Some(opts), Some(mmio_cfg)) => {
+                let guard = device_manager.lock().unwrap();
+                guard.mmio_device(mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                };
+                let args = VsockArgs {
+                    common,
+                    opts: opts.clone(),
+                };
+
+                match Vsock::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create vsock device: {:?}", e),
+                }
+            }
+            _ => None,
+        };
+        let p9 = match (share_opts, p9_mmio_cfg) {
+            (Some(opts), Some(mmio_cfg)) => {
+                let guard = device_manager.lock().unwrap();
+                guard.mmio_device(mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                };
+                let args = P9Args {
+                    common,
+                    opts: opts.clone(),
+                };
+
+                match P9::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create 9p device: {:?}", e),
+                }
+            }
+            _ => None,
+        };
 
         let device = DeviceContext {
+            mem,
             blkdev,
             console,
+            vsock,
+            p9,
             mmio_mgr: device_manager,
             first_mmio_addr,
             last_mmio_addr,
+            hotplug_mmio,
+            irq_affinity: irq_affinity.to_vec(),
+            traced_jobs: Mutex::new(Some(vec![])),
         };
 
         Ok(device)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_irq_affinity() {
+        assert_eq!(
+            IrqAffinity::parse("block=3").unwrap(),
+            IrqAffinity {
+                device: String::from("block"),
+                cpu: 3
+            }
+        );
+        assert!(IrqAffinity::parse("block").is_err());
+        assert!(IrqAffinity::parse("net=0").is_err());
+        assert!(IrqAffinity::parse("9p=-1").is_err());
+    }
+
+    #[test]
+    fn test_free_irqs() {
+        let mut redirtbl = vec![IOAPIC_MASKED; 24];
+        assert_eq!(free_irqs(&redirtbl, 2).unwrap(), vec![7, 6]);
+        redirtbl[7] = 0x27;
+        redirtbl[3] = 0x23;
+        assert_eq!(free_irqs(&redirtbl, 3).unwrap(), vec![6, 10, 11]);
+        assert!(free_irqs(&redirtbl, 4).is_err());
+        assert_eq!(free_irqs(&redirtbl, 0).unwrap(), vec![]);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::devices::mmio::IoPirate;
 use crate::stage1::DeviceStatus;
 use crate::stage1::DriverStatus;
+use crate::stage1::Stage1Device;
 use event_manager::EventManager;
 use event_manager::MutEventSubscriber;
 use log::error;
@@ -15,11 +16,17 @@ use std::sync::{Condvar, Mutex};
 use virtio_device::{VirtioDevice, WithDriverSelect};
 
 use crate::devices;
+use crate::devices::virtio::block::{CacheMode, FaultOptions, ImageFormat};
+use crate::devices::virtio::p9::ShareOptions;
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::devices::virtio::{with_injector, IrqAckHandler};
 use crate::devices::DeviceContext;
+use crate::devices::IrqAffinity;
 use crate::devices::MaybeIoRegionFd;
 use crate::interrutable_thread::InterrutableThread;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
+use crate::metrics;
 use crate::result::Result;
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
@@ -123,16 +130,12 @@ impl Drop for DriverNotifier {
     }
 }
 
-fn event_thread(
+/// Runs `event_mgr` and handles the interrupt acknowledgement timeouts of `ack_handler`.
+pub(super) fn event_thread(
     mut event_mgr: SubscriberEventManager,
-    device_space: &DeviceContext,
+    ack_handler: Arc<Mutex<IrqAckHandler>>,
     err_sender: &SyncSender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let blkdev = device_space.blkdev.clone();
-    let ack_handler = {
-        let blkdev = try_with!(blkdev.lock(), "cannot unlock thread");
-        blkdev.irq_ack_handler.clone()
-    };
     log::debug!("event thread started");
 
     let res = InterrutableThread::spawn(
@@ -222,14 +225,15 @@ fn blkdev_monitor_thread(
     Ok(try_with!(res, "failed to spawn blkdev-monitor"))
 }
 
-/// Traps KVM_MMIO_EXITs with ptrace and forward them as needed to our block and console device driver
+/// Traps KVM_MMIO_EXITs with ptrace and forward them as needed to our block and console device
+/// driver. In between exits it runs the jobs of `DeviceContext::run_traced`.
 fn handle_mmio_exits(
+    vm: &Arc<Hypervisor>,
     wrapper_mo: &Mutex<Option<KvmRunWrapper>>,
     should_stop: &Arc<AtomicBool>,
     ctx: &DeviceContext,
     driver_notifier: &Arc<DriverNotifier>,
 ) -> Result<()> {
-    let mut mmio_mgr = try_with!(ctx.mmio_mgr.lock(), "cannot lock mmio manager");
     {
         let mut wrapper_go = try_with!(wrapper_mo.lock(), "cannot obtain wrapper mutex");
         let wrapper_g = require_with!(wrapper_go.as_mut(), "KvmRunWrapper not initialized");
@@ -256,13 +260,25 @@ fn handle_mmio_exits(
             if ctx.first_mmio_addr <= mmio_rw.addr && mmio_rw.addr < ctx.last_mmio_addr {
                 // intercept op
                 trace!("mmio access: {:#x}", mmio_rw.addr);
+                metrics::MMIO_EXITS.inc();
+                // not held across exits: devices are added and removed in between
+                let mut mmio_mgr = try_with!(ctx.mmio_mgr.lock(), "cannot lock mmio manager");
                 try_with!(mmio_mgr.handle_mmio_rw(mmio_rw), "failed to handle MmioRw");
             } else {
                 // do nothing, just continue to ignore and pass to hv
-                trace!("ignore addr: {:#x}", mmio_rw.addr)
+                trace!("ignore addr: {:#x}", mmio_rw.addr);
+                metrics::MMIO_EXITS_PASSED.inc();
             }
         }
 
+        let jobs = ctx.take_traced_jobs()?;
+        if !jobs.is_empty() {
+            with_injector(vm, || {
+                jobs.into_iter().for_each(|job| job());
+                Ok(())
+            })?;
+        }
+
         if should_stop.load(Ordering::Relaxed) {
             break;
         }
@@ -296,13 +312,14 @@ fn mmio_exit_handler_thread(
 
             let res = vm.kvmrun_wrapped(|wrapper_mo: &Mutex<Option<KvmRunWrapper>>| {
                 // Signal that our blockdevice driver is ready now
-                let res = handle_mmio_exits(wrapper_mo, &should_stop, dev, &driver_notifier);
+                let res = handle_mmio_exits(&vm, wrapper_mo, &should_stop, dev, &driver_notifier);
                 if res.is_err() {
                     // don't shadow error here
                     let _ = driver_notifier.notify(DeviceState::Error);
                 }
                 res
             });
+            dev.close_traced_jobs();
 
             // we need to return ptrace control before returning to the main thread
             vm.prepare_thread_transfer()?;
@@ -340,6 +357,7 @@ fn ioregion_event_loop(
             ioregionfd
         );
         if let Some(cmd) = cmd {
+            metrics::IOREGION_ACCESSES.inc();
             let mut mmio_mgr = try_with!(
                 mmio_mgr.lock(),
                 "cannot lock mmio manager to handle mmio command"
@@ -356,7 +374,7 @@ fn ioregion_event_loop(
 }
 
 /// see handle_mmio_exits
-fn ioregion_handler_thread(
+pub(super) fn ioregion_handler_thread(
     devices: Arc<DeviceContext>,
     device: Arc<Mutex<dyn MaybeIoRegionFd + Send>>,
     mmio_mgr: Arc<Mutex<IoPirate>>,
@@ -382,20 +400,44 @@ fn ioregion_handler_thread(
 pub type Threads = Vec<InterrutableThread<(), Option<Arc<DeviceContext>>>>;
 
 impl DeviceSet {
-    pub fn mmio_addrs(&self) -> Result<Vec<u64>> {
-        self.context.mmio_addrs()
+    /// Shared with the threads of `start`, i.e. to add devices later on.
+    pub fn context(&self) -> Arc<DeviceContext> {
+        Arc::clone(&self.context)
+    }
+
+    pub fn stage1_devices(&self) -> Result<Vec<Stage1Device>> {
+        self.context.stage1_devices()
     }
 
     pub fn new(
         vm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
         backing_file: &Path,
+        format: Option<ImageFormat>,
+        read_only: bool,
+        cache: CacheMode,
+        faults: Option<&FaultOptions>,
+        vsock: Option<&VsockOptions>,
+        share: Option<&ShareOptions>,
+        irq_affinity: &[IrqAffinity],
     ) -> Result<DeviceSet> {
         let mut event_manager =
             try_with!(SubscriberEventManager::new(), "cannot create event manager");
         // instantiate blkdev
         let context = Arc::new(try_with!(
-            DeviceContext::new(vm, allocator, &mut event_manager, backing_file),
+            DeviceContext::new(
+                vm,
+                allocator,
+                &mut event_manager,
+                backing_file,
+                format,
+                read_only,
+                cache,
+                faults,
+                vsock,
+                share,
+                irq_affinity
+            ),
             "cannot create vm"
         ));
         Ok(DeviceSet {
@@ -416,7 +458,11 @@ impl DeviceSet {
             driver_status,
             Arc::clone(vm),
         ));
-        let mut threads = vec![event_thread(self.event_manager, &self.context, err_sender)?];
+        let ack_handler = {
+            let blkdev = try_with!(self.context.blkdev.lock(), "cannot unlock thread");
+            blkdev.irq_ack_handler.clone()
+        };
+        let mut threads = vec![event_thread(self.event_manager, ack_handler, err_sender)?];
 
         if log_enabled!(Level::Debug) {
             threads.push(blkdev_monitor_thread(&self.context, err_sender)?);
@@ -448,6 +494,28 @@ impl DeviceSet {
                 ),
                 "cannot spawn console ioregion handler"
             ));
+            if let Some(vsock) = &self.context.vsock {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        vsock.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn vsock ioregion handler"
+                ));
+            }
+            if let Some(p9) = &self.context.p9 {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        p9.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn 9p ioregion handler"
+                ));
+            }
         } else {
             threads.push(mmio_exit_handler_thread(
                 vm,
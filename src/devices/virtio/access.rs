@@ -0,0 +1,174 @@
+//! How guest accesses to the mmio region of a device reach `VirtioMmioDevice`, shared by all
+//! devices.
+//!
+//! virtio-mmio registers are 32 bits wide and little-endian, the config space behind them is
+//! accessed with any width. `VirtioMmioDevice` only answers aligned 32-bit register accesses and
+//! leaves the data of everything else untouched, so a guest that reads a register byte-wise sees
+//! whatever happened to be in the buffer of the exit. Here register reads of any width are
+//! assembled from the 32-bit registers they cover, 64-bit writes are split in their two halves
+//! and other partial writes, which the virtio specification forbids, are dropped with a warning.
+//! All byte order conversions of registers happen in `le_u32`/`put_le_u32`.
+use std::borrow::Borrow;
+use virtio_device::{VirtioConfig, VirtioMmioDevice};
+use vm_memory::GuestAddressSpace;
+
+/// Offset of the device-specific config space in the mmio region
+const CONFIG_SPACE_OFFSET: u64 = 0x100;
+const REGISTER_SIZE: u64 = 4;
+
+fn le_u32(data: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*data)
+}
+
+fn put_le_u32(data: &mut [u8; 4], value: u32) {
+    *data = value.to_le_bytes();
+}
+
+/// Reads `data.len()` bytes at `offset` from 32-bit registers read by `read_register`, each
+/// register at most once.
+fn read_registers(offset: u64, data: &mut [u8], mut read_register: impl FnMut(u64) -> u32) {
+    let mut current: Option<(u64, [u8; 4])> = None;
+    for (i, byte) in data.iter_mut().enumerate() {
+        let addr = offset + i as u64;
+        let register = addr & !(REGISTER_SIZE - 1);
+        let word = match current {
+            Some((r, word)) if r == register => word,
+            _ => {
+                let mut word = [0u8; 4];
+                put_le_u32(&mut word, read_register(register));
+                current = Some((register, word));
+                word
+            }
+        };
+        *byte = word[(addr - register) as usize];
+    }
+}
+
+/// Writes aligned 32 and 64-bit accesses to `write_register` one register at a time. Returns
+/// false for other accesses, the device ignores them.
+fn write_registers(offset: u64, data: &[u8], mut write_register: impl FnMut(u64, u32)) -> bool {
+    if offset % REGISTER_SIZE != 0 || (data.len() != 4 && data.len() != 8) {
+        return false;
+    }
+    for (i, chunk) in data.chunks_exact(REGISTER_SIZE as usize).enumerate() {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(chunk);
+        write_register(offset + i as u64 * REGISTER_SIZE, le_u32(&word));
+    }
+    true
+}
+
+/// Bytes of the config space at `offset`, zero beyond its end.
+fn read_config(config: &[u8], offset: u64, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = offset
+            .checked_add(i as u64)
+            .and_then(|o| config.get(o as usize))
+            .copied()
+            .unwrap_or(0);
+    }
+}
+
+/// Answers a read of the guest at `offset` in the mmio region of `device`.
+pub fn read<M, D>(device: &mut D, offset: u64, data: &mut [u8])
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    if offset >= CONFIG_SPACE_OFFSET {
+        let config: &VirtioConfig<M> = (*device).borrow();
+        read_config(&config.config_space, offset - CONFIG_SPACE_OFFSET, data);
+    } else if offset % REGISTER_SIZE == 0 && data.len() == REGISTER_SIZE as usize {
+        device.read(offset, data);
+    } else {
+        read_registers(offset, data, |register| {
+            let mut word = [0u8; 4];
+            device.read(register, &mut word);
+            le_u32(&word)
+        });
+    }
+}
+
+/// Applies a write of the guest at `offset` in the mmio region of `device`.
+pub fn write<M, D>(device: &mut D, offset: u64, data: &[u8])
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    if offset >= CONFIG_SPACE_OFFSET {
+        device.write(offset, data);
+        return;
+    }
+    let written = write_registers(offset, data, |register, value| {
+        let mut word = [0u8; 4];
+        put_le_u32(&mut word, value);
+        device.write(register, &word);
+    });
+    if !written {
+        log::warn!(
+            "ignore {} byte write to register {:#x}, registers are written 32 bits at a time",
+            data.len(),
+            offset
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_registers() {
+        // MagicValue "virt" and Version 2
+        let registers = |r: u64| match r {
+            0x0 => 0x7472_6976,
+            0x4 => 2,
+            _ => 0,
+        };
+        let mut reads = vec![];
+        let mut data = [0u8; 2];
+        read_registers(0x1, &mut data, |r| {
+            reads.push(r);
+            registers(r)
+        });
+        assert_eq!(&data, b"ir");
+        assert_eq!(reads, vec![0x0]);
+
+        // straddles two registers
+        let mut data = [0u8; 4];
+        read_registers(0x2, &mut data, registers);
+        assert_eq!(data, [b'r', b't', 2, 0]);
+
+        let mut data = [0u8; 8];
+        read_registers(0x0, &mut data, registers);
+        assert_eq!(&data, b"virt\x02\0\0\0");
+    }
+
+    #[test]
+    fn test_write_registers() {
+        let mut writes = vec![];
+        // QueueDescLow and QueueDescHigh at once
+        let value = 0x1_2345_6780u64.to_le_bytes();
+        assert!(write_registers(0x80, &value, |r, v| writes.push((r, v))));
+        assert_eq!(writes, vec![(0x80, 0x2345_6780), (0x84, 0x1)]);
+
+        writes.clear();
+        assert!(!write_registers(0x70, &[0xf], |r, v| writes.push((r, v))));
+        assert!(!write_registers(0x72, &[0, 0, 0, 0], |r, v| writes.push((r, v))));
+        assert!(writes.is_empty());
+    }
+
+    #[test]
+    fn test_read_config() {
+        // struct virtio_9p_config with the tag "ab"
+        let config = [2, 0, b'a', b'b'];
+        let mut data = [0xffu8; 1];
+        read_config(&config, 2, &mut data);
+        assert_eq!(data, [b'a']);
+        let mut data = [0xffu8; 4];
+        read_config(&config, 2, &mut data);
+        assert_eq!(data, [b'a', b'b', 0, 0]);
+        read_config(&config, u64::MAX, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+}
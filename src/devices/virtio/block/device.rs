@@ -3,8 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
 use std::ops::DerefMut;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use virtio_device::{VirtioDevice, VirtioDeviceType};
@@ -24,16 +24,20 @@ use crate::devices::virtio::block::{BLOCK_DEVICE_ID, VIRTIO_BLK_F_FLUSH, VIRTIO_
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::{
+    access, IrqAckHandler, MmioConfig, NotifyBatch, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
 use crate::devices::MaybeIoRegionFd;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
 
+use super::faults::Faults;
+use super::image::{self, CacheMode, CachedImage, Image, ImageFormat};
 use super::inorder_handler::InOrderQueueHandler;
 use super::queue_handler::QueueHandler;
-use super::{build_config_space, BlockArgs, Error, Result};
+use super::{build_config_space, BlockArgs, Error, FaultOptions, Result};
 
 // This Block device can only use the MMIO transport for now, but we plan to reuse large parts of
 // the functionality when we implement virtio PCI as well, for example by having a base generic
@@ -45,11 +49,18 @@ pub struct Block<M: GuestAddressSpace> {
     pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
     vmm: Arc<Hypervisor>,
     irqfd: Arc<EventFd>,
+    /// fd of `irqfd` in the hypervisor
+    hv_irqfd: RawFd,
     pub ioregionfd: Option<IoRegionFd>,
     pub uioefd: UserspaceIoEventFd,
     /// only used when ioregionfd != None
     file_path: PathBuf,
+    format: Option<ImageFormat>,
     read_only: bool,
+    cache: CacheMode,
+    faults: Option<FaultOptions>,
+    /// Image of the active device, synced on reset and removal
+    sync_image: Option<Image>,
     sub_id: Option<SubscriberId>,
 
     // Before resetting we return the handler to the mmio thread for cleanup
@@ -86,18 +97,18 @@ where
 
         // A block device has a single queue.
         let queues = vec![Queue::new(args.common.mem, QUEUE_MAX_SIZE)];
-        let config_space = build_config_space(&args.file_path)?;
+        let config_space = build_config_space(&args.file_path, args.format)?;
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
         //let irqfd = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
         log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
-        let irqfd = Arc::new(
-            args.common
-                .vmm
-                .irqfd(args.common.mmio_cfg.gsi)
-                .map_err(Error::Simple)?,
-        );
+        let (irqfd, hv_irqfd) = args
+            .common
+            .vmm
+            .irqfd_with_remote(args.common.mmio_cfg.gsi)
+            .map_err(Error::Simple)?;
+        let irqfd = Arc::new(irqfd);
 
         let mmio_cfg = args.common.mmio_cfg;
 
@@ -123,10 +134,15 @@ where
             irq_ack_handler,
             vmm: args.common.vmm.clone(),
             irqfd,
+            hv_irqfd,
             ioregionfd,
             uioefd: UserspaceIoEventFd::default(),
             file_path: args.file_path,
+            format: args.format,
             read_only: args.read_only,
+            cache: args.cache,
+            faults: args.faults,
+            sync_image: None,
             sub_id: None,
             handler: None,
             _root_device: args.root_device,
@@ -151,11 +167,8 @@ where
             return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
         }
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(!self.read_only)
-            .open(&self.file_path)
-            .map_err(Error::OpenFile)?;
+        let file =
+            image::open(&self.file_path, self.format, self.read_only).map_err(Error::Simple)?;
 
         let mut features = self.virtio_cfg.driver_features;
         if self.read_only {
@@ -164,8 +177,14 @@ where
             features |= 1 << VIRTIO_BLK_F_RO;
         }
 
+        if !self.read_only {
+            self.sync_image = Some(file.sync_handle().map_err(Error::OpenFile)?);
+        }
+
         // TODO: Create the backend earlier (as part of `Block::new`)?
-        let disk = StdIoBackend::new(file, features)
+        let image =
+            CachedImage::new(file, self.cache).with_faults(self.faults.clone().map(Faults::new));
+        let disk = StdIoBackend::new(image, features)
             .map_err(Error::Backend)?
             .with_device_id(*b"vmsh0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
 
@@ -173,11 +192,15 @@ where
             irqfd: self.irqfd.clone(),
             interrupt_status: self.virtio_cfg.interrupt_status.clone(),
             ack_handler: self.irq_ack_handler.clone(),
+            batch: NotifyBatch::default(),
         };
 
+        let mut queue = self.virtio_cfg.queues[0].clone();
+        queue.set_event_idx(self.virtio_cfg.driver_features & (1 << VIRTIO_F_RING_EVENT_IDX) != 0);
+
         let inner = InOrderQueueHandler {
             driver_notify,
-            queue: self.virtio_cfg.queues[0].clone(),
+            queue,
             disk,
         };
 
@@ -216,7 +239,26 @@ where
                 })?;
             self.handler = Some(handler);
         }
-        Ok(())
+        self.sync()
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Block<M> {
+    /// Writes what the guest wrote to the image to disk, regardless of the cache mode. Needed
+    /// before the device goes away, the guest cannot flush anymore then.
+    pub fn sync(&self) -> Result<()> {
+        match &self.sync_image {
+            Some(image) => image.sync_data().map_err(Error::Sync),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the irqfd of the device from the vm, needed when the device is removed while the
+    /// vm keeps running.
+    pub fn remove_irqfd(&self) -> Result<()> {
+        self.vmm
+            .remove_irqfd(self.hv_irqfd, self.mmio_cfg.gsi)
+            .map_err(Error::Simple)
     }
 }
 
@@ -279,10 +321,10 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for Bloc
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Block<M> {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.read(offset, data);
+        access::read::<M, _>(self, offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
-        self.write(offset, data);
+        access::write::<M, _>(self, offset, data);
     }
 }
@@ -0,0 +1,237 @@
+//! Fault injection for the block device, see `vmsh attach --inject-faults`, to test how the
+//! storage stack of a guest copes with a failing disk.
+//!
+//! Every read and write of the image that a guest request causes can fail with an I/O error,
+//! which the guest sees as VIRTIO_BLK_S_IOERR, be delayed, or for writes be torn: a prefix of
+//! whole sectors reaches the image before the write fails. The faults are drawn from a
+//! pseudo-random generator whose seed is logged, so that a run can be repeated with `seed=`.
+use log::{debug, info};
+use simple_error::{bail, SimpleError};
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultOptions {
+    /// Probability of each read to fail
+    pub read_error: f64,
+    /// Probability of each write to fail without writing anything
+    pub write_error: f64,
+    /// Probability of each write to fail after writing a part
+    pub torn_write: f64,
+    /// Probability of each access to be delayed by `latency`
+    pub latency_rate: f64,
+    pub latency: Duration,
+    /// None to pick one at random
+    pub seed: Option<u64>,
+}
+
+fn parse_rate(key: &str, value: &str) -> std::result::Result<f64, SimpleError> {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => bail!(
+            "{} must be a probability between 0 and 1, got {}",
+            key,
+            value
+        ),
+    }
+}
+
+fn parse_latency(value: &str) -> std::result::Result<Duration, SimpleError> {
+    let (num, millis) = match value.strip_suffix("ms") {
+        Some(num) => (num, true),
+        None => (value.strip_suffix('s').unwrap_or(value), false),
+    };
+    match num.parse::<u64>() {
+        Ok(n) if millis => Ok(Duration::from_millis(n)),
+        Ok(n) => Ok(Duration::from_secs(n)),
+        Err(_) => bail!("invalid latency {}, expected i.e. 200ms or 2s", value),
+    }
+}
+
+impl FaultOptions {
+    /// Parses comma separated `read-error=RATE`, `write-error=RATE`, `torn-write=RATE`,
+    /// `latency=RATE:DURATION` and `seed=N`, i.e. `write-error=0.01,latency=0.05:200ms`.
+    pub fn parse(arg: &str) -> std::result::Result<FaultOptions, SimpleError> {
+        let mut opts = FaultOptions {
+            read_error: 0.0,
+            write_error: 0.0,
+            torn_write: 0.0,
+            latency_rate: 0.0,
+            latency: Duration::from_secs(0),
+            seed: None,
+        };
+        for option in arg.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = match option.split_once('=') {
+                Some(v) => v,
+                None => bail!("expected KEY=VALUE, got {}", option),
+            };
+            match key {
+                "read-error" => opts.read_error = parse_rate(key, value)?,
+                "write-error" => opts.write_error = parse_rate(key, value)?,
+                "torn-write" => opts.torn_write = parse_rate(key, value)?,
+                "latency" => {
+                    let (rate, latency) = match value.split_once(':') {
+                        Some(v) => v,
+                        None => bail!("expected latency=RATE:DURATION, got {}", option),
+                    };
+                    opts.latency_rate = parse_rate(key, rate)?;
+                    opts.latency = parse_latency(latency)?;
+                }
+                "seed" => match value.parse() {
+                    Ok(seed) => opts.seed = Some(seed),
+                    Err(_) => bail!("invalid seed {}", value),
+                },
+                _ => bail!(
+                    "unknown fault {}, expected read-error, write-error, torn-write, latency or seed",
+                    key
+                ),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// What happens to a write
+#[derive(Debug, PartialEq)]
+pub enum WriteFault {
+    None,
+    Fail,
+    /// Only this many bytes reach the image
+    Torn(usize),
+}
+
+pub struct Faults {
+    opts: FaultOptions,
+    /// xorshift64* state, never 0
+    state: u64,
+}
+
+fn injected(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("injected {}", what))
+}
+
+impl Faults {
+    pub fn new(opts: FaultOptions) -> Faults {
+        let seed = opts.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1)
+        });
+        info!("injecting block device faults with seed={}", seed);
+        Faults {
+            opts,
+            state: (seed ^ 0x9e37_79b9_7f4a_7c15).max(1),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn happens(&mut self, rate: f64) -> bool {
+        // 53 random bits, all that fit into the mantissa
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        rate > 0.0 && sample < rate
+    }
+
+    fn delay(&mut self) {
+        if self.happens(self.opts.latency_rate) {
+            debug!("injected latency of {:?}", self.opts.latency);
+            thread::sleep(self.opts.latency);
+        }
+    }
+
+    /// Delays or fails a read.
+    pub fn before_read(&mut self) -> io::Result<()> {
+        self.delay();
+        if self.happens(self.opts.read_error) {
+            debug!("injected read error");
+            return Err(injected("read error"));
+        }
+        Ok(())
+    }
+
+    /// Delays a write and decides how much of its `len` bytes are written.
+    pub fn before_write(&mut self, len: usize) -> WriteFault {
+        self.delay();
+        if self.happens(self.opts.write_error) {
+            debug!("injected write error");
+            return WriteFault::Fail;
+        }
+        if self.happens(self.opts.torn_write) {
+            // whole sectors, unless the write is shorter than one
+            let sectors = len / SECTOR_SIZE;
+            let written = if sectors > 1 {
+                (self.next_u64() % sectors as u64) as usize * SECTOR_SIZE
+            } else {
+                len / 2
+            };
+            debug!("injected torn write of {} of {} bytes", written, len);
+            return WriteFault::Torn(written);
+        }
+        WriteFault::None
+    }
+
+    /// Error of a write that `before_write` failed or tore.
+    pub fn write_error(fault: &WriteFault) -> io::Error {
+        match fault {
+            WriteFault::Torn(_) => injected("torn write"),
+            _ => injected("write error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fault_options() {
+        let opts = FaultOptions::parse("write-error=0.01,latency=0.5:200ms,seed=7").unwrap();
+        assert_eq!(opts.write_error, 0.01);
+        assert_eq!(opts.read_error, 0.0);
+        assert_eq!(opts.latency_rate, 0.5);
+        assert_eq!(opts.latency, Duration::from_millis(200));
+        assert_eq!(opts.seed, Some(7));
+        assert!(FaultOptions::parse("read-error=2").is_err());
+        assert!(FaultOptions::parse("latency=0.1").is_err());
+        assert!(FaultOptions::parse("bitflip=0.1").is_err());
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut opts = FaultOptions::parse("seed=1").unwrap();
+        let mut faults = Faults::new(opts.clone());
+        assert!((0..1000).all(|_| faults.before_read().is_ok()));
+        assert!((0..1000).all(|_| faults.before_write(4096) == WriteFault::None));
+
+        opts.torn_write = 1.0;
+        let mut faults = Faults::new(opts.clone());
+        for _ in 0..100 {
+            match faults.before_write(4096) {
+                WriteFault::Torn(n) => assert!(n < 4096 && n % SECTOR_SIZE == 0),
+                fault => panic!("expected torn write, got {:?}", fault),
+            }
+        }
+
+        // the same seed injects the same faults
+        opts.torn_write = 0.0;
+        opts.read_error = 0.5;
+        let draw = |opts: &FaultOptions| {
+            let mut faults = Faults::new(opts.clone());
+            (0..64)
+                .map(|_| faults.before_read().is_err())
+                .collect::<Vec<_>>()
+        };
+        let first = draw(&opts);
+        assert_eq!(first, draw(&opts));
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+}
@@ -0,0 +1,231 @@
+//! Block devices of the host, i.e. a partition like `/dev/nvme0n1p3`, passed through to the guest.
+//!
+//! The device is opened with `O_DIRECT` so that its data is cached by the guest only and not a
+//! second time in the page cache of the host. `O_DIRECT` requires offsets, lengths and buffers to
+//! be aligned to the logical block size of the device, while the guest reads and writes arbitrary
+//! ranges into its own memory. Every access hence goes through an aligned bounce buffer, partial
+//! blocks are read before they are written.
+use simple_error::try_with;
+use std::cmp::min;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use super::qcow2::checked_add_signed;
+use crate::result::Result;
+
+/// Largest access to the device, larger requests of the guest are split.
+const BOUNCE_SIZE: usize = 1 << 20;
+/// Buffers are aligned to pages, which is enough for all logical block sizes up to 4K.
+const BUFFER_ALIGN: usize = 4096;
+
+mod ioctl {
+    nix::ioctl_read!(blkgetsize64, 0x12, 114, libc::size_t);
+    nix::ioctl_read_bad!(blksszget, nix::request_code_none!(0x12, 104), libc::c_int);
+}
+
+/// True if `path` is a block device of the host.
+pub fn is_block_device(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Memory aligned to `BUFFER_ALIGN`, carved out of a larger vector.
+struct AlignedBuffer {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> AlignedBuffer {
+        let data = vec![0u8; len + BUFFER_ALIGN];
+        let offset = data.as_ptr().align_offset(BUFFER_ALIGN);
+        AlignedBuffer { data, offset }
+    }
+
+    fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + len]
+    }
+}
+
+pub struct HostDevice {
+    file: File,
+    /// In bytes as reported by BLKGETSIZE64
+    size: u64,
+    /// Logical block size, the unit of `O_DIRECT` accesses
+    block_size: u64,
+    pos: u64,
+    bounce: AlignedBuffer,
+}
+
+impl HostDevice {
+    pub fn open(path: &Path, read_only: bool) -> Result<HostDevice> {
+        let file = try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .custom_flags(libc::O_DIRECT)
+                .open(path),
+            "cannot open {}",
+            path.display()
+        );
+        let mut size: libc::size_t = 0;
+        try_with!(
+            unsafe { ioctl::blkgetsize64(file.as_raw_fd(), &mut size) },
+            "cannot get size of {}",
+            path.display()
+        );
+        let mut block_size: libc::c_int = 0;
+        try_with!(
+            unsafe { ioctl::blksszget(file.as_raw_fd(), &mut block_size) },
+            "cannot get logical block size of {}",
+            path.display()
+        );
+        Ok(HostDevice::new(file, size as u64, block_size as u64))
+    }
+
+    fn new(file: File, size: u64, block_size: u64) -> HostDevice {
+        HostDevice {
+            file,
+            size,
+            block_size,
+            pos: 0,
+            bounce: AlignedBuffer::new(BOUNCE_SIZE),
+        }
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// The device itself, i.e. to sync it
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Aligned range that covers up to `len` bytes at the current position: its start, the offset
+    /// of the current position in it, its length and how many of the `len` bytes it covers.
+    fn span(&self, len: usize) -> (u64, usize, usize, usize) {
+        let start = self.pos - self.pos % self.block_size;
+        let head = (self.pos - start) as usize;
+        let wanted = min(len as u64, self.size - self.pos) as usize;
+        let end = min(head + wanted, BOUNCE_SIZE);
+        let block_size = self.block_size as usize;
+        let aligned_len = (end + block_size - 1) / block_size * block_size;
+        (start, head, aligned_len, end - head)
+    }
+}
+
+impl Read for HostDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, head, len, n) = self.span(buf.len());
+        let bounce = self.bounce.as_mut_slice(len);
+        self.file.read_exact_at(bounce, start)?;
+        buf[..n].copy_from_slice(&bounce[head..head + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for HostDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, head, len, n) = self.span(buf.len());
+        let block_size = self.block_size as usize;
+        let bounce = self.bounce.as_mut_slice(len);
+        // keep the rest of partially written blocks
+        if head != 0 {
+            self.file.read_exact_at(&mut bounce[..block_size], start)?;
+        }
+        let end = head + n;
+        if end % block_size != 0 {
+            let tail = end / block_size * block_size;
+            self.file
+                .read_exact_at(&mut bounce[tail..len], start + tail as u64)?;
+        }
+        bounce[head..end].copy_from_slice(&buf[..n]);
+        self.file.write_all_at(bounce, start)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for HostDevice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_add_signed(self.size, offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.pos, offset),
+        };
+        match new {
+            Some(new) => {
+                self.pos = new;
+                Ok(new)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn device(blocks: usize) -> HostDevice {
+        let tmp = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..blocks * 512).map(|i| (i / 512) as u8).collect();
+        tmp.as_file().write_all(&data).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        HostDevice::new(file, data.len() as u64, 512)
+    }
+
+    #[test]
+    fn test_unaligned_read() {
+        let mut dev = device(4);
+        dev.seek(SeekFrom::Start(510)).unwrap();
+        let mut buf = [0u8; 4];
+        dev.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 1, 1]);
+
+        dev.seek(SeekFrom::End(-1)).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(dev.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+        assert_eq!(dev.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unaligned_write() {
+        let mut dev = device(4);
+        dev.seek(SeekFrom::Start(511)).unwrap();
+        dev.write_all(&[0xff; 514]).unwrap();
+
+        let mut data = vec![0u8; 4 * 512];
+        dev.seek(SeekFrom::Start(0)).unwrap();
+        dev.read_exact(&mut data).unwrap();
+        assert_eq!(data[510], 0);
+        assert!(data[511..1025].iter().all(|b| *b == 0xff));
+        assert_eq!(data[1025], 2);
+        assert_eq!(data[2047], 3);
+
+        // the device does not grow
+        dev.seek(SeekFrom::End(-1)).unwrap();
+        assert!(dev.write_all(&[0xff; 2]).is_err());
+    }
+}
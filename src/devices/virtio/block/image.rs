@@ -0,0 +1,244 @@
+//! Disk image formats that can back the block device.
+use simple_error::{bail, try_with};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::faults::{Faults, WriteFault};
+use super::host_device::{self, HostDevice};
+use super::nbd::{self, Nbd};
+use super::qcow2::Qcow2;
+use crate::result::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+impl ImageFormat {
+    /// Parse format names as used by qemu.
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "raw" => Some(ImageFormat::Raw),
+            "qcow2" => Some(ImageFormat::Qcow2),
+            _ => None,
+        }
+    }
+
+    /// Inverse of `from_name`
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+        }
+    }
+}
+
+/// How writes of the guest reach the disk of the host, named as the qemu `cache` options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Every write is on disk before it completes, flush requests have nothing left to do.
+    None,
+    /// Writes go to the page cache of the host, flush requests sync the image.
+    Writeback,
+    /// Like `Writeback` but flush requests are ignored. The image is only synced when the device
+    /// is reset or removed, data written since is lost if the host crashes.
+    Unsafe,
+}
+
+impl Default for CacheMode {
+    fn default() -> Self {
+        CacheMode::None
+    }
+}
+
+impl CacheMode {
+    pub fn from_name(name: &str) -> Option<CacheMode> {
+        match name {
+            "none" => Some(CacheMode::None),
+            "writeback" => Some(CacheMode::Writeback),
+            "unsafe" => Some(CacheMode::Unsafe),
+            _ => None,
+        }
+    }
+
+    /// Inverse of `from_name`
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheMode::None => "none",
+            CacheMode::Writeback => "writeback",
+            CacheMode::Unsafe => "unsafe",
+        }
+    }
+}
+
+pub enum Image {
+    Raw(File),
+    Qcow2(Box<Qcow2>),
+    /// Block device of the host, always raw
+    HostDevice(Box<HostDevice>),
+    /// Export of a nbd server, always raw
+    Nbd(Nbd),
+}
+
+/// Open an image, which is raw if `format` is not given. The format is never probed: a guest
+/// could write a qcow2 header to a raw image and make us follow a backing file of its choice
+/// the next time it is attached. `path` can also be a `nbd://` url, see `nbd`, or a block
+/// device of the host, see `host_device`.
+pub fn open(path: &Path, format: Option<ImageFormat>, read_only: bool) -> Result<Image> {
+    if let Some(url) = nbd::parse_url(path) {
+        if format == Some(ImageFormat::Qcow2) {
+            bail!("qcow2 images cannot be used over nbd, export them with qemu-nbd instead");
+        }
+        return Ok(Image::Nbd(Nbd::connect(&url, read_only)?));
+    }
+    match format.unwrap_or(ImageFormat::Raw) {
+        ImageFormat::Raw if host_device::is_block_device(path) => Ok(Image::HostDevice(Box::new(
+            HostDevice::open(path, read_only)?,
+        ))),
+        ImageFormat::Raw => {
+            let file = try_with!(
+                OpenOptions::new().read(true).write(!read_only).open(path),
+                "cannot open {}",
+                path.display()
+            );
+            Ok(Image::Raw(file))
+        }
+        ImageFormat::Qcow2 => Ok(Image::Qcow2(Box::new(Qcow2::open(path, read_only)?))),
+    }
+}
+
+impl Image {
+    /// Waits until everything written to the image is on disk.
+    pub fn sync_data(&self) -> io::Result<()> {
+        match self {
+            Image::Raw(f) => f.sync_data(),
+            Image::Qcow2(q) => q.sync_data(),
+            Image::HostDevice(d) => d.sync_data(),
+            Image::Nbd(n) => n.sync_data(),
+        }
+    }
+
+    /// Another handle that syncs the same image, i.e. while the image is in use elsewhere.
+    pub fn sync_handle(&self) -> io::Result<Image> {
+        match self {
+            Image::Raw(f) => f.try_clone().map(Image::Raw),
+            Image::Qcow2(q) => q.try_clone_file().map(Image::Raw),
+            Image::HostDevice(d) => d.try_clone_file().map(Image::Raw),
+            Image::Nbd(n) => Ok(Image::Nbd(n.try_clone())),
+        }
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+}
+
+impl Read for Image {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Image::Raw(f) => f.read(buf),
+            Image::Qcow2(q) => q.read(buf),
+            Image::HostDevice(d) => d.read(buf),
+            Image::Nbd(n) => n.read(buf),
+        }
+    }
+}
+
+impl Write for Image {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Image::Raw(f) => f.write(buf),
+            Image::Qcow2(q) => q.write(buf),
+            Image::HostDevice(d) => d.write(buf),
+            Image::Nbd(n) => n.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            // File::flush does not reach the disk
+            Image::Raw(f) => f.sync_data(),
+            Image::Qcow2(q) => q.flush(),
+            Image::HostDevice(d) => d.flush(),
+            Image::Nbd(n) => n.flush(),
+        }
+    }
+}
+
+impl Seek for Image {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Image::Raw(f) => f.seek(pos),
+            Image::Qcow2(q) => q.seek(pos),
+            Image::HostDevice(d) => d.seek(pos),
+            Image::Nbd(n) => n.seek(pos),
+        }
+    }
+}
+
+/// Image as the block device uses it: flush requests of the guest end up in `flush`.
+pub struct CachedImage {
+    image: Image,
+    cache: CacheMode,
+    faults: Option<Faults>,
+}
+
+impl CachedImage {
+    pub fn new(image: Image, cache: CacheMode) -> CachedImage {
+        CachedImage {
+            image,
+            cache,
+            faults: None,
+        }
+    }
+
+    /// Injects `faults` into the reads and writes of the guest.
+    pub fn with_faults(mut self, faults: Option<Faults>) -> CachedImage {
+        self.faults = faults;
+        self
+    }
+}
+
+impl Read for CachedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(faults) = &mut self.faults {
+            faults.before_read()?;
+        }
+        self.image.read(buf)
+    }
+}
+
+impl Write for CachedImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(faults) = &mut self.faults {
+            let fault = faults.before_write(buf.len());
+            if let WriteFault::Torn(len) = fault {
+                self.image.write_all(&buf[..len])?;
+            }
+            if fault != WriteFault::None {
+                return Err(Faults::write_error(&fault));
+            }
+        }
+        let written = self.image.write(buf)?;
+        if self.cache == CacheMode::None {
+            self.image.sync_data()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.cache {
+            CacheMode::Writeback => self.image.sync_data(),
+            CacheMode::None | CacheMode::Unsafe => Ok(()),
+        }
+    }
+}
+
+impl Seek for CachedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.image.seek(pos)
+    }
+}
@@ -2,16 +2,17 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::fs::File;
 use std::result;
 
 use log::warn;
-use virtio_blk::request::Request;
+use virtio_blk::request::{Request, RequestType};
 use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{self, Bytes, GuestAddressSpace};
 
+use super::image::CachedImage;
 use crate::devices::virtio::SignalUsedQueue;
+use crate::metrics;
 
 #[derive(Debug)]
 pub enum Error {
@@ -31,6 +32,26 @@ impl From<virtio_queue::Error> for Error {
     }
 }
 
+fn count_request(request: &Request) {
+    let bytes = request
+        .data()
+        .iter()
+        .map(|(_, len)| u64::from(*len))
+        .sum::<u64>();
+    match request.request_type() {
+        RequestType::In => {
+            metrics::BLOCK_READS.inc();
+            metrics::BLOCK_READ_BYTES.add(bytes);
+        }
+        RequestType::Out => {
+            metrics::BLOCK_WRITES.inc();
+            metrics::BLOCK_WRITE_BYTES.add(bytes);
+        }
+        RequestType::Flush => metrics::BLOCK_FLUSHES.inc(),
+        _ => metrics::BLOCK_OTHER.inc(),
+    }
+}
+
 // This object is used to process the queue of a block device without making any assumptions
 // about the notification mechanism. We're using a specific backend for now (the `StdIoBackend`
 // object), but the aim is to have a way of working with generic backends and turn this into
@@ -39,7 +60,7 @@ impl From<virtio_queue::Error> for Error {
 pub struct InOrderQueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub driver_notify: S,
     pub queue: Queue<M>,
-    pub disk: StdIoBackend<File>,
+    pub disk: StdIoBackend<CachedImage>,
 }
 
 impl<M, S> InOrderQueueHandler<M, S>
@@ -54,6 +75,7 @@ where
         match Request::parse(&mut chain) {
             Ok(request) => {
                 log::trace!("request: {:?}", request);
+                count_request(&request);
                 let status = match self.disk.execute(chain.memory(), &request) {
                     Ok(l) => {
                         // TODO: Using `saturating_add` until we consume the recent changes
@@ -64,6 +86,7 @@ where
                     }
                     Err(e) => {
                         warn!("failed to execute block request: {:?}", e);
+                        metrics::BLOCK_ERRORS.inc();
                         len = 1;
                         // TODO: add `status` or similar method to executor error.
                         if let stdio_executor::Error::Unsupported(_) = e {
@@ -83,19 +106,29 @@ where
             Err(e) => {
                 len = 0;
                 warn!("block request parse error: {:?}", e);
+                metrics::BLOCK_ERRORS.inc();
             }
         }
 
         self.queue.add_used(chain.head_index(), len)?;
 
+        if self.driver_notify.descriptor_used() {
+            self.notify()?;
+        }
+
+        log::trace!("process_chain done");
+        Ok(())
+    }
+
+    /// Signals the used descriptors of the last batch unless the driver does not want to hear
+    /// about them yet.
+    fn notify(&mut self) -> result::Result<(), Error> {
         if self.queue.needs_notification()? {
             log::trace!("notification needed: yes");
             self.driver_notify.signal_used_queue(0);
         } else {
             log::trace!("notification needed: no");
         }
-
-        log::trace!("process_chain done");
         Ok(())
     }
 
@@ -114,6 +147,10 @@ where
             }
         }
 
+        if self.driver_notify.end_batch() {
+            self.notify()?;
+        }
+
         Ok(())
     }
 }
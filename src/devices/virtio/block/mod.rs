@@ -3,10 +3,14 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 mod device;
+pub mod faults;
+pub mod host_device;
+pub mod image;
 mod inorder_handler;
+pub mod nbd;
+mod qcow2;
 mod queue_handler;
 
-use std::fs::File;
 use std::io::{self, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
@@ -19,6 +23,8 @@ use crate::devices::virtio::CommonArgs;
 use simple_error::SimpleError;
 
 pub use device::Block;
+pub use faults::FaultOptions;
+pub use image::{CacheMode, ImageFormat};
 
 // TODO: Move relevant defines to vm-virtio crate.
 
@@ -50,6 +56,7 @@ pub enum Error {
     RegisterIrqfd(errno::Error),
     Seek(io::Error),
     Simple(SimpleError),
+    Sync(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -57,11 +64,11 @@ pub type Result<T> = std::result::Result<T, Error>;
 // TODO: Add a helper abstraction to rust-vmm for building the device configuration space.
 // The one we build below for the block device contains the minimally required `capacity` member,
 // but other fields can be present as well depending on the negotiated features.
-fn build_config_space<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+fn build_config_space(path: &Path, format: Option<ImageFormat>) -> Result<Vec<u8>> {
     // TODO: right now, the file size is computed by the StdioBackend as well. Maybe we should
     // create the backend as early as possible, and get the size information from there.
-    let file_size = File::open(path)
-        .map_err(Error::OpenFile)?
+    let file_size = image::open(path, format, true)
+        .map_err(Error::Simple)?
         .seek(SeekFrom::End(0))
         .map_err(Error::Seek)?;
     // If the file size is actually not a multiple of sector size, then data at the very end
@@ -75,7 +82,12 @@ fn build_config_space<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
 pub struct BlockArgs<'a, M, B> {
     pub common: CommonArgs<'a, M, B>,
     pub file_path: PathBuf,
+    /// Raw if not set, the format is never probed
+    pub format: Option<ImageFormat>,
     pub read_only: bool,
+    pub cache: CacheMode,
+    /// Faults injected into reads and writes of the image
+    pub faults: Option<FaultOptions>,
     pub root_device: bool,
     pub advertise_flush: bool,
 }
@@ -101,7 +113,7 @@ mod tests {
         }
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(tmp.as_path(), None).unwrap();
 
             // The config space is only populated with the `capacity` field for now.
             assert_eq!(config_space.len(), size_of::<u64>());
@@ -113,7 +125,7 @@ mod tests {
         tmp.as_file().write_all(&[1u8, 2, 3]).unwrap();
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(tmp.as_path(), None).unwrap();
             // We should get the same value of capacity, as the extra bytes are ignored.
             assert_eq!(config_space[..8], num_sectors.to_le_bytes());
         }
@@ -0,0 +1,369 @@
+//! Client for exports of a network block device server, so that images can also be given as
+//! `nbd://host[:port][/export]`, i.e. `nbd://localhost/disk` for `qemu-nbd -x disk disk.qcow2`.
+//!
+//! Only the fixed newstyle handshake and simple replies are used, which every server supports.
+//! Requests are sent one at a time by the thread that handles the queue of the device, like reads
+//! and writes of a local image.
+use simple_error::{bail, try_with};
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::qcow2::checked_add_signed;
+use crate::result::Result;
+
+pub const DEFAULT_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+/// Servers may refuse larger requests.
+const MAX_REQUEST: usize = 32 << 20;
+/// Sent by old servers after the export flags
+const ZEROES: usize = 124;
+/// A server that does not reply within this time fails the request of the guest instead of
+/// blocking the device forever.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub struct NbdUrl {
+    /// `host:port` as accepted by `TcpStream::connect`
+    pub addr: String,
+    pub export: String,
+}
+
+/// Parses `nbd://host[:port][/export]`, None for everything else i.e. paths of local images.
+pub fn parse_url(path: &Path) -> Option<NbdUrl> {
+    let rest = path.to_str()?.strip_prefix("nbd://")?;
+    let (authority, export) = match rest.split_once('/') {
+        Some((authority, export)) => (authority, export),
+        None => (rest, ""),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    // the port comes after the brackets of ipv6 addresses
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
+    };
+    let addr = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, DEFAULT_PORT)
+    };
+    Some(NbdUrl {
+        addr,
+        export: export.to_string(),
+    })
+}
+
+fn request_header(command: u16, handle: u64, offset: u64, len: u32) -> [u8; 28] {
+    let mut buf = [0u8; 28];
+    buf[0..4].copy_from_slice(&REQUEST_MAGIC.to_be_bytes());
+    // buf[4..6] are the command flags
+    buf[6..8].copy_from_slice(&command.to_be_bytes());
+    buf[8..16].copy_from_slice(&handle.to_be_bytes());
+    buf[16..24].copy_from_slice(&offset.to_be_bytes());
+    buf[24..28].copy_from_slice(&len.to_be_bytes());
+    buf
+}
+
+/// Error code of a simple reply to the request `handle`
+fn parse_reply(buf: &[u8; 16], handle: u64) -> io::Result<u32> {
+    let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if magic != SIMPLE_REPLY_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply magic {:#x}", magic),
+        ));
+    }
+    let mut reply_handle = [0u8; 8];
+    reply_handle.copy_from_slice(&buf[8..16]);
+    if u64::from_be_bytes(reply_handle) != handle {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "reply to an unexpected request",
+        ));
+    }
+    Ok(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]))
+}
+
+struct Connection {
+    stream: TcpStream,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl Connection {
+    /// Sends a request with the data in `out` and reads the reply into `data`.
+    fn request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        out: &[u8],
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let len = out.len() + data.len();
+        self.stream
+            .write_all(&request_header(command, handle, offset, len as u32))?;
+        self.stream.write_all(out)?;
+        let mut reply = [0u8; 16];
+        self.stream.read_exact(&mut reply)?;
+        match parse_reply(&reply, handle)? {
+            0 => self.stream.read_exact(data),
+            // errno values of the server
+            errno => Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let header = request_header(NBD_CMD_DISC, self.next_handle, 0, 0);
+        // the server does not reply
+        let _ = self.stream.write_all(&header);
+    }
+}
+
+fn handshake(stream: &mut TcpStream, export: &str) -> io::Result<(u64, u16)> {
+    let mut buf = [0u8; 18];
+    stream.read_exact(&mut buf)?;
+    let mut magic = [0u8; 8];
+    magic.copy_from_slice(&buf[0..8]);
+    let mut opt_magic = [0u8; 8];
+    opt_magic.copy_from_slice(&buf[8..16]);
+    if u64::from_be_bytes(magic) != NBDMAGIC || u64::from_be_bytes(opt_magic) != IHAVEOPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a newstyle nbd server",
+        ));
+    }
+    let server_flags = u16::from_be_bytes([buf[16], buf[17]]);
+    let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = 0;
+    if server_flags & NBD_FLAG_FIXED_NEWSTYLE != 0 {
+        client_flags |= NBD_FLAG_C_FIXED_NEWSTYLE;
+    }
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream.write_all(&client_flags.to_be_bytes())?;
+
+    let mut option = Vec::with_capacity(16 + export.len());
+    option.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    option.extend_from_slice(&NBD_OPT_EXPORT_NAME.to_be_bytes());
+    option.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    option.extend_from_slice(export.as_bytes());
+    stream.write_all(&option)?;
+
+    // the server closes the connection if there is no such export
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply)?;
+    if !no_zeroes {
+        stream.read_exact(&mut [0u8; ZEROES])?;
+    }
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&reply[0..8]);
+    Ok((
+        u64::from_be_bytes(size),
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ))
+}
+
+/// An export of a nbd server that reads and writes like a file
+pub struct Nbd {
+    conn: Arc<Mutex<Connection>>,
+    size: u64,
+    pos: u64,
+}
+
+impl Nbd {
+    pub fn connect(url: &NbdUrl, read_only: bool) -> Result<Nbd> {
+        let mut stream = try_with!(
+            TcpStream::connect(&url.addr),
+            "cannot connect to nbd server {}",
+            url.addr
+        );
+        try_with!(
+            stream
+                .set_read_timeout(Some(TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(TIMEOUT))),
+            "cannot set timeout"
+        );
+        // requests are small and latency bound
+        try_with!(stream.set_nodelay(true), "cannot set TCP_NODELAY");
+        let (size, flags) = try_with!(
+            handshake(&mut stream, &url.export),
+            "cannot open export '{}' of nbd server {}",
+            url.export,
+            url.addr
+        );
+        if !read_only && flags & NBD_FLAG_READ_ONLY != 0 {
+            bail!(
+                "export '{}' of nbd server {} is read-only",
+                url.export,
+                url.addr
+            );
+        }
+        Ok(Nbd {
+            conn: Arc::new(Mutex::new(Connection {
+                stream,
+                flags,
+                next_handle: 0,
+            })),
+            size,
+            pos: 0,
+        })
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "nbd connection is poisoned"))
+    }
+
+    /// Waits until the server wrote everything to disk, if it supports flushing.
+    pub fn sync_data(&self) -> io::Result<()> {
+        let mut conn = self.lock()?;
+        if conn.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        conn.request(NBD_CMD_FLUSH, 0, &[], &mut [])
+    }
+
+    /// Shares the connection, like `File::try_clone` shares the file.
+    pub fn try_clone(&self) -> Nbd {
+        Nbd {
+            conn: Arc::clone(&self.conn),
+            size: self.size,
+            pos: self.pos,
+        }
+    }
+
+    fn chunk_len(&self, remaining: usize) -> usize {
+        min(
+            min(remaining as u64, self.size - self.pos) as usize,
+            MAX_REQUEST,
+        )
+    }
+}
+
+impl Read for Nbd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+        let len = self.chunk_len(buf.len());
+        self.lock()?
+            .request(NBD_CMD_READ, self.pos, &[], &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for Nbd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos >= self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write beyond the end of the export",
+            ));
+        }
+        let len = self.chunk_len(buf.len());
+        self.lock()?
+            .request(NBD_CMD_WRITE, self.pos, &buf[..len], &mut [])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Nbd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => checked_add_signed(self.size, delta),
+            SeekFrom::Current(delta) => checked_add_signed(self.pos, delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url(Path::new("nbd://localhost/disk")),
+            Some(NbdUrl {
+                addr: String::from("localhost:10809"),
+                export: String::from("disk"),
+            })
+        );
+        assert_eq!(
+            parse_url(Path::new("nbd://[::1]:10900")),
+            Some(NbdUrl {
+                addr: String::from("[::1]:10900"),
+                export: String::new(),
+            })
+        );
+        assert_eq!(
+            parse_url(Path::new("nbd://[::1]/a/b")).map(|u| (u.addr, u.export)),
+            Some((String::from("[::1]:10809"), String::from("a/b")))
+        );
+        assert_eq!(parse_url(Path::new("nbd:///disk")), None);
+        assert_eq!(parse_url(Path::new("/var/lib/disk.img")), None);
+    }
+
+    #[test]
+    fn test_reply() {
+        let header = request_header(NBD_CMD_READ, 7, 4096, 512);
+        assert_eq!(&header[0..4], &REQUEST_MAGIC.to_be_bytes());
+        assert_eq!(&header[16..24], &4096u64.to_be_bytes());
+
+        let mut reply = [0u8; 16];
+        reply[0..4].copy_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        reply[4..8].copy_from_slice(&5u32.to_be_bytes());
+        reply[8..16].copy_from_slice(&7u64.to_be_bytes());
+        assert_eq!(parse_reply(&reply, 7).unwrap(), 5);
+        assert!(parse_reply(&reply, 8).is_err());
+        reply[0] = 0;
+        assert!(parse_reply(&reply, 7).is_err());
+    }
+}
@@ -0,0 +1,735 @@
+//! Minimal qcow2 implementation, so that disk images of virtual machines can be attached without
+//! converting them first. Version 2 and 3 images with backing files are supported, compressed
+//! clusters, encryption and writing to images with internal snapshots are not. Images are only
+//! opened as qcow2, and their backing files followed, if the format was given explicitly.
+//!
+//! See docs/interop/qcow2.txt in the qemu source for the format.
+use log::debug;
+use simple_error::{bail, try_with};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::image::{self, Image, ImageFormat};
+use super::nbd::parse_url;
+use crate::result::Result;
+
+/// "QFI\xfb"
+pub const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+/// Refcount of the cluster is exactly one, set in L1 and L2 entries.
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeros (version 3 only).
+const OFLAG_ZERO: u64 = 1;
+
+/// Header extension with the format of the backing file.
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+/// Incompatible feature bit: refcounts might be wrong.
+const INCOMPAT_DIRTY: u64 = 1;
+
+const V2_HEADER_LENGTH: u64 = 72;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Limits of qemu for the l1 and refcount table and the backing file name, so that a corrupt
+/// header cannot make us allocate arbitrary amounts of memory.
+const MAX_L1_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+/// L2 tables are dropped from the cache once this many are loaded.
+const MAX_CACHED_L2_TABLES: usize = 64;
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+    header_length: u64,
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl Header {
+    /// Parse the fixed part of the header. `buf` has to hold at least 104 bytes.
+    fn parse(buf: &[u8]) -> Result<Header> {
+        if be_u32(buf, 0) != QCOW2_MAGIC {
+            bail!("not a qcow2 image");
+        }
+        let version = be_u32(buf, 4);
+        let mut header = Header {
+            backing_file_offset: be_u64(buf, 8),
+            backing_file_size: be_u32(buf, 16),
+            cluster_bits: be_u32(buf, 20),
+            size: be_u64(buf, 24),
+            crypt_method: be_u32(buf, 32),
+            l1_size: be_u32(buf, 36),
+            l1_table_offset: be_u64(buf, 40),
+            refcount_table_offset: be_u64(buf, 48),
+            refcount_table_clusters: be_u32(buf, 56),
+            nb_snapshots: be_u32(buf, 60),
+            incompatible_features: 0,
+            refcount_order: 4,
+            header_length: V2_HEADER_LENGTH,
+        };
+        match version {
+            2 => {}
+            3 => {
+                header.incompatible_features = be_u64(buf, 72);
+                header.refcount_order = be_u32(buf, 96);
+                header.header_length = u64::from(be_u32(buf, 100));
+            }
+            _ => bail!("unsupported qcow2 version {}", version),
+        }
+        if header.cluster_bits < MIN_CLUSTER_BITS || header.cluster_bits > MAX_CLUSTER_BITS {
+            bail!("invalid cluster size 2^{}", header.cluster_bits);
+        }
+        if header.crypt_method != 0 {
+            bail!("encrypted qcow2 images are not supported");
+        }
+        if header.incompatible_features & !INCOMPAT_DIRTY != 0 {
+            bail!(
+                "unsupported qcow2 features: {:#x}",
+                header.incompatible_features & !INCOMPAT_DIRTY
+            );
+        }
+        if header.refcount_order > 6 {
+            bail!("invalid refcount order {}", header.refcount_order);
+        }
+        if u64::from(header.l1_size) * 8 > MAX_L1_SIZE {
+            bail!("l1 table with {} entries is too large", header.l1_size);
+        }
+        if u64::from(header.refcount_table_clusters) << header.cluster_bits
+            > MAX_REFCOUNT_TABLE_SIZE
+        {
+            bail!(
+                "refcount table with {} clusters is too large",
+                header.refcount_table_clusters
+            );
+        }
+        if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+            bail!(
+                "backing file name with {} bytes is too long",
+                header.backing_file_size
+            );
+        }
+        Ok(header)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Number of entries in a L2 table
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+}
+
+/// Format name from the header extensions following the header in `buf`.
+fn backing_format(buf: &[u8], header_length: u64) -> Option<String> {
+    let mut offset = header_length as usize;
+    while offset + 8 <= buf.len() {
+        let ext_type = be_u32(buf, offset);
+        let len = be_u32(buf, offset + 4) as usize;
+        let data = buf.get(offset + 8..offset + 8 + len)?;
+        match ext_type {
+            0 => return None,
+            EXT_BACKING_FORMAT => return Some(String::from_utf8_lossy(data).into_owned()),
+            _ => {}
+        }
+        // extensions are padded to 8 bytes
+        offset += 8 + ((len + 7) & !7);
+    }
+    None
+}
+
+fn other_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+pub struct Qcow2 {
+    file: File,
+    header: Header,
+    l1_table: Vec<u64>,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    refcount_table: Vec<u64>,
+    backing: Option<Box<Image>>,
+    backing_size: u64,
+    /// Clusters are allocated at the end of the file.
+    next_free: u64,
+    read_only: bool,
+    pos: u64,
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries * 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf.chunks_exact(8).map(|c| be_u64(c, 0)).collect())
+}
+
+impl Qcow2 {
+    pub fn open(path: &Path, read_only: bool) -> Result<Qcow2> {
+        let file = try_with!(
+            OpenOptions::new().read(true).write(!read_only).open(path),
+            "cannot open {}",
+            path.display()
+        );
+        let file_size = try_with!(file.metadata(), "cannot stat {}", path.display()).len();
+
+        let mut buf = vec![0u8; 104];
+        try_with!(
+            file.read_exact_at(&mut buf, 0),
+            "cannot read qcow2 header of {}",
+            path.display()
+        );
+        let header = try_with!(Header::parse(&buf), "invalid image {}", path.display());
+        let cluster_size = header.cluster_size();
+
+        // header extensions are in the first cluster
+        let mut first_cluster = vec![0u8; min(cluster_size, file_size) as usize];
+        try_with!(
+            file.read_exact_at(&mut first_cluster, 0),
+            "cannot read qcow2 header of {}",
+            path.display()
+        );
+
+        if !read_only {
+            if header.incompatible_features & INCOMPAT_DIRTY != 0 {
+                bail!(
+                    "refcounts of {} are inconsistent, run `qemu-img check -r all` first",
+                    path.display()
+                );
+            }
+            if header.nb_snapshots != 0 {
+                bail!(
+                    "{} has internal snapshots, which are only supported read-only",
+                    path.display()
+                );
+            }
+            if header.refcount_order < 3 {
+                bail!(
+                    "refcounts with {} bits are only supported read-only",
+                    1 << header.refcount_order
+                );
+            }
+        }
+
+        let clusters = (header.size + cluster_size - 1) / cluster_size;
+        let l1_needed = (clusters + header.l2_entries() - 1) / header.l2_entries();
+        if u64::from(header.l1_size) < l1_needed {
+            bail!("l1 table of {} is too small", path.display());
+        }
+        let l1_table = try_with!(
+            read_table(&file, header.l1_table_offset, header.l1_size as usize),
+            "cannot read l1 table of {}",
+            path.display()
+        );
+        let refcount_table = try_with!(
+            read_table(
+                &file,
+                header.refcount_table_offset,
+                (u64::from(header.refcount_table_clusters) * cluster_size / 8) as usize
+            ),
+            "cannot read refcount table of {}",
+            path.display()
+        );
+
+        let mut backing = None;
+        let mut backing_size = 0;
+        if header.backing_file_offset != 0 {
+            let mut name = vec![0u8; header.backing_file_size as usize];
+            try_with!(
+                file.read_exact_at(&mut name, header.backing_file_offset),
+                "cannot read backing file name of {}",
+                path.display()
+            );
+            let name = String::from_utf8_lossy(&name).into_owned();
+            // relative paths are relative to the image
+            let backing_path = match parse_url(Path::new(&name)) {
+                Some(_) => PathBuf::from(&name),
+                None => path.parent().unwrap_or_else(|| Path::new("")).join(&name),
+            };
+            // without a backing format extension the backing file is raw, it is never probed
+            let format = match backing_format(&first_cluster, header.header_length) {
+                Some(f) => match ImageFormat::from_name(&f) {
+                    Some(format) => Some(format),
+                    None => bail!("backing file {} has unsupported format {}", name, f),
+                },
+                None => None,
+            };
+            debug!("open backing file {}", backing_path.display());
+            let mut image = image::open(&backing_path, format, true)?;
+            backing_size = try_with!(
+                image.seek(SeekFrom::End(0)),
+                "cannot get size of {}",
+                backing_path.display()
+            );
+            backing = Some(Box::new(image));
+        }
+
+        Ok(Qcow2 {
+            file,
+            l1_table,
+            l2_cache: HashMap::new(),
+            refcount_table,
+            backing,
+            backing_size,
+            next_free: (file_size + cluster_size - 1) / cluster_size * cluster_size,
+            read_only,
+            pos: 0,
+            header,
+        })
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// The image file itself, i.e. to sync it
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    fn l1_index(&self, guest: u64) -> usize {
+        ((guest >> self.header.cluster_bits) / self.header.l2_entries()) as usize
+    }
+
+    fn l2_index(&self, guest: u64) -> usize {
+        ((guest >> self.header.cluster_bits) % self.header.l2_entries()) as usize
+    }
+
+    fn l2_table(&mut self, offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&offset) {
+            if self.l2_cache.len() >= MAX_CACHED_L2_TABLES {
+                self.l2_cache.clear();
+            }
+            let table = read_table(&self.file, offset, self.header.l2_entries() as usize)?;
+            self.l2_cache.insert(offset, table);
+        }
+        Ok(self.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    /// L2 entry of the cluster containing `guest`, 0 if no L2 table is allocated.
+    fn l2_entry(&mut self, guest: u64) -> io::Result<u64> {
+        let l1_entry = self
+            .l1_table
+            .get(self.l1_index(guest))
+            .copied()
+            .unwrap_or(0);
+        let offset = l1_entry & L1_OFFSET_MASK;
+        if offset == 0 {
+            return Ok(0);
+        }
+        let idx = self.l2_index(guest);
+        Ok(self.l2_table(offset)?[idx])
+    }
+
+    fn read_backing(&mut self, guest: u64, buf: &mut [u8]) -> io::Result<()> {
+        let n = match &mut self.backing {
+            Some(backing) if guest < self.backing_size => {
+                let n = min(buf.len() as u64, self.backing_size - guest) as usize;
+                backing.read_at(guest, &mut buf[..n])?;
+                n
+            }
+            _ => 0,
+        };
+        buf[n..].iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+
+    /// `buf` must not cross a cluster boundary.
+    fn read_cluster(&mut self, guest: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(guest)?;
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(other_error("compressed clusters are not supported"));
+        }
+        let host = entry & L2_OFFSET_MASK;
+        if entry & OFLAG_ZERO != 0 {
+            buf.iter_mut().for_each(|b| *b = 0);
+            Ok(())
+        } else if host == 0 {
+            self.read_backing(guest, buf)
+        } else {
+            let in_cluster = guest & (self.header.cluster_size() - 1);
+            self.file.read_exact_at(buf, host + in_cluster)
+        }
+    }
+
+    fn set_refcount(&mut self, host: u64, value: u64) -> io::Result<()> {
+        let cluster_size = self.header.cluster_size();
+        let bits = 1u64 << self.header.refcount_order;
+        let per_block = cluster_size * 8 / bits;
+        let cluster = host >> self.header.cluster_bits;
+        let table_idx = (cluster / per_block) as usize;
+        let mut block = match self.refcount_table.get(table_idx) {
+            Some(entry) => entry & REFCOUNT_OFFSET_MASK,
+            None => return Err(other_error("refcount table of qcow2 image is full")),
+        };
+        if block == 0 {
+            block = self.next_free;
+            self.next_free += cluster_size;
+            self.file
+                .write_all_at(&vec![0u8; cluster_size as usize], block)?;
+            self.refcount_table[table_idx] = block;
+            self.file.write_all_at(
+                &block.to_be_bytes(),
+                self.header.refcount_table_offset + table_idx as u64 * 8,
+            )?;
+            // the new refcount block is refcounted as well
+            self.set_refcount(block, 1)?;
+        }
+        let offset = block + (cluster % per_block) * bits / 8;
+        match bits {
+            8 => self.file.write_all_at(&[value as u8], offset),
+            16 => self
+                .file
+                .write_all_at(&(value as u16).to_be_bytes(), offset),
+            32 => self
+                .file
+                .write_all_at(&(value as u32).to_be_bytes(), offset),
+            _ => self.file.write_all_at(&value.to_be_bytes(), offset),
+        }
+    }
+
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.next_free;
+        self.next_free += self.header.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Offset of the L2 table for `guest`, which is allocated if needed.
+    fn allocate_l2_table(&mut self, guest: u64) -> io::Result<u64> {
+        let l1_idx = self.l1_index(guest);
+        let offset = match self.l1_table.get(l1_idx) {
+            Some(entry) => entry & L1_OFFSET_MASK,
+            None => return Err(other_error("write beyond the l1 table")),
+        };
+        if offset != 0 {
+            return Ok(offset);
+        }
+        let offset = self.allocate_cluster()?;
+        let cluster_size = self.header.cluster_size() as usize;
+        self.file.write_all_at(&vec![0u8; cluster_size], offset)?;
+        self.l2_cache
+            .insert(offset, vec![0; self.header.l2_entries() as usize]);
+        let entry = offset | OFLAG_COPIED;
+        self.l1_table[l1_idx] = entry;
+        self.file.write_all_at(
+            &entry.to_be_bytes(),
+            self.header.l1_table_offset + l1_idx as u64 * 8,
+        )?;
+        Ok(offset)
+    }
+
+    /// `buf` must not cross a cluster boundary.
+    fn write_cluster(&mut self, guest: u64, buf: &[u8]) -> io::Result<()> {
+        let cluster_size = self.header.cluster_size();
+        let in_cluster = guest & (cluster_size - 1);
+        let entry = self.l2_entry(guest)?;
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(other_error("compressed clusters are not supported"));
+        }
+        let host = entry & L2_OFFSET_MASK;
+        if host != 0 && entry & OFLAG_ZERO == 0 {
+            return self.file.write_all_at(buf, host + in_cluster);
+        }
+
+        // Copy on write: the rest of the cluster comes from the backing file or is zero.
+        let cluster_start = guest - in_cluster;
+        let mut data = vec![0u8; cluster_size as usize];
+        if entry & OFLAG_ZERO == 0 {
+            self.read_backing(cluster_start, &mut data)?;
+        }
+        data[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        let l2_offset = self.allocate_l2_table(guest)?;
+        // preallocated zero clusters keep their place
+        let host = if host != 0 {
+            host
+        } else {
+            self.allocate_cluster()?
+        };
+        self.file.write_all_at(&data, host)?;
+
+        let entry = host | OFLAG_COPIED;
+        let idx = self.l2_index(guest);
+        self.l2_table(l2_offset)?[idx] = entry;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + idx as u64 * 8)
+    }
+
+    /// Length of the next access at the current position that stays within one cluster.
+    fn chunk_len(&self, remaining: usize) -> usize {
+        let cluster_size = self.header.cluster_size();
+        let in_cluster = self.pos & (cluster_size - 1);
+        min(remaining as u64, cluster_size - in_cluster) as usize
+    }
+}
+
+impl Read for Qcow2 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.header.size {
+            return Ok(0);
+        }
+        let len = min(buf.len() as u64, self.header.size - self.pos) as usize;
+        let mut done = 0;
+        while done < len {
+            let n = self.chunk_len(len - done);
+            self.read_cluster(self.pos, &mut buf[done..done + n])?;
+            done += n;
+            self.pos += n as u64;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Qcow2 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "qcow2 image is read-only",
+            ));
+        }
+        if self.pos >= self.header.size {
+            return Ok(0);
+        }
+        let len = min(buf.len() as u64, self.header.size - self.pos) as usize;
+        let mut done = 0;
+        while done < len {
+            let n = self.chunk_len(len - done);
+            self.write_cluster(self.pos, &buf[done..done + n])?;
+            done += n;
+            self.pos += n as u64;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // File::flush does not reach the disk
+        self.sync_data()
+    }
+}
+
+impl Seek for Qcow2 {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => checked_add_signed(self.header.size, delta),
+            SeekFrom::Current(delta) => checked_add_signed(self.pos, delta),
+        };
+        match new {
+            Some(new) => {
+                self.pos = new;
+                Ok(new)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+pub(super) fn checked_add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const TEST_CLUSTER: u64 = 512;
+    const TEST_SIZE: u64 = 64 * 1024;
+    const REFCOUNT_BLOCK: u64 = 2 * TEST_CLUSTER;
+
+    /// Empty version 3 image with 512 byte clusters and 16 bit refcounts: header, refcount table,
+    /// refcount block and l1 table take one cluster each.
+    fn test_image(backing: Option<&Path>) -> TempFile {
+        let mut buf = vec![0u8; 4 * TEST_CLUSTER as usize];
+        buf[0..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        buf[4..8].copy_from_slice(&3u32.to_be_bytes());
+        buf[20..24].copy_from_slice(&9u32.to_be_bytes());
+        buf[24..32].copy_from_slice(&TEST_SIZE.to_be_bytes());
+        buf[36..40].copy_from_slice(&2u32.to_be_bytes());
+        buf[40..48].copy_from_slice(&(3 * TEST_CLUSTER).to_be_bytes());
+        buf[48..56].copy_from_slice(&TEST_CLUSTER.to_be_bytes());
+        buf[56..60].copy_from_slice(&1u32.to_be_bytes());
+        buf[96..100].copy_from_slice(&4u32.to_be_bytes());
+        buf[100..104].copy_from_slice(&104u32.to_be_bytes());
+        if let Some(backing) = backing {
+            buf[104..108].copy_from_slice(&EXT_BACKING_FORMAT.to_be_bytes());
+            buf[108..112].copy_from_slice(&3u32.to_be_bytes());
+            buf[112..115].copy_from_slice(b"raw");
+            let name = backing.to_str().unwrap().as_bytes();
+            buf[8..16].copy_from_slice(&128u64.to_be_bytes());
+            buf[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+            buf[128..128 + name.len()].copy_from_slice(name);
+        }
+        let table = TEST_CLUSTER as usize;
+        buf[table..table + 8].copy_from_slice(&REFCOUNT_BLOCK.to_be_bytes());
+        for cluster in 0..4 {
+            let offset = (REFCOUNT_BLOCK + cluster * 2) as usize;
+            buf[offset..offset + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().write_all(&buf).unwrap();
+        tmp
+    }
+
+    /// Every cluster of the image file is in use and has to be referenced exactly once.
+    fn assert_refcounts(file: &File) {
+        let len = file.metadata().unwrap().len();
+        assert_eq!(len % TEST_CLUSTER, 0);
+        for cluster in 0..len / TEST_CLUSTER {
+            let mut refcount = [0u8; 2];
+            file.read_exact_at(&mut refcount, REFCOUNT_BLOCK + cluster * 2)
+                .unwrap();
+            assert_eq!(u16::from_be_bytes(refcount), 1, "cluster {}", cluster);
+        }
+    }
+
+    fn read_at(image: &mut Qcow2, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        image.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_read_write() {
+        let tmp = test_image(None);
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8 + 1).collect();
+        {
+            let mut image = Qcow2::open(tmp.as_path(), false).unwrap();
+            assert_eq!(read_at(&mut image, 0, 2048), vec![0u8; 2048]);
+            // crosses two cluster boundaries
+            image.seek(SeekFrom::Start(300)).unwrap();
+            image.write_all(&data).unwrap();
+            // second l2 table
+            image.seek(SeekFrom::Start(TEST_SIZE - 10)).unwrap();
+            image.write_all(&data[..10]).unwrap();
+            image.flush().unwrap();
+            assert_eq!(read_at(&mut image, 300, data.len()), data);
+        }
+
+        let mut image = Qcow2::open(tmp.as_path(), true).unwrap();
+        assert_eq!(read_at(&mut image, 0, 300), vec![0u8; 300]);
+        assert_eq!(read_at(&mut image, 300, data.len()), data);
+        assert_eq!(read_at(&mut image, TEST_SIZE - 10, 10), &data[..10]);
+        // 3 data clusters for the first write, 1 for the second and 2 l2 tables
+        assert_eq!(tmp.as_file().metadata().unwrap().len(), 10 * TEST_CLUSTER);
+        assert_refcounts(tmp.as_file());
+
+        image.seek(SeekFrom::Start(0)).unwrap();
+        assert!(image.write(&data).is_err());
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let backing = TempFile::new().unwrap();
+        let backing_data: Vec<u8> = (0..4096).map(|i| (i / 7) as u8).collect();
+        backing.as_file().write_all(&backing_data).unwrap();
+        let tmp = test_image(Some(backing.as_path()));
+
+        let mut image = Qcow2::open(tmp.as_path(), false).unwrap();
+        assert_eq!(read_at(&mut image, 0, 4096), backing_data);
+        // the image is larger than its backing file
+        assert_eq!(read_at(&mut image, 4096, 512), vec![0u8; 512]);
+
+        image.seek(SeekFrom::Start(1100)).unwrap();
+        image.write_all(&[0xff; 20]).unwrap();
+        let mut expected = backing_data.clone();
+        expected[1100..1120].copy_from_slice(&[0xff; 20]);
+        assert_eq!(read_at(&mut image, 0, 4096), expected);
+
+        let mut unchanged = vec![0u8; 4096];
+        backing.as_file().read_exact_at(&mut unchanged, 0).unwrap();
+        assert_eq!(unchanged, backing_data);
+        assert_refcounts(tmp.as_file());
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let mut buf = v3_header();
+        buf[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Header::parse(&buf).is_err());
+
+        let mut buf = v3_header();
+        buf[56..60].copy_from_slice(&1024u32.to_be_bytes());
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    fn v3_header() -> Vec<u8> {
+        let mut buf = vec![0u8; 112];
+        buf[0..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        buf[4..8].copy_from_slice(&3u32.to_be_bytes());
+        buf[20..24].copy_from_slice(&16u32.to_be_bytes());
+        buf[24..32].copy_from_slice(&(1u64 << 30).to_be_bytes());
+        buf[36..40].copy_from_slice(&2u32.to_be_bytes());
+        buf[40..48].copy_from_slice(&0x30000u64.to_be_bytes());
+        buf[48..56].copy_from_slice(&0x10000u64.to_be_bytes());
+        buf[56..60].copy_from_slice(&1u32.to_be_bytes());
+        buf[96..100].copy_from_slice(&4u32.to_be_bytes());
+        buf[100..104].copy_from_slice(&112u32.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut buf = v3_header();
+        let header = Header::parse(&buf).unwrap();
+        assert_eq!(header.cluster_size(), 0x10000);
+        assert_eq!(header.size, 1 << 30);
+        assert_eq!(header.l1_table_offset, 0x30000);
+        assert_eq!(header.header_length, 112);
+
+        // compression type is not supported
+        buf[79] = 1 << 3;
+        assert!(Header::parse(&buf).is_err());
+        buf[0] = 0;
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn test_backing_format() {
+        let mut buf = v3_header();
+        buf.extend_from_slice(&EXT_BACKING_FORMAT.to_be_bytes());
+        buf.extend_from_slice(&3u32.to_be_bytes());
+        buf.extend_from_slice(b"raw\0\0\0\0\0");
+        buf.extend_from_slice(&[0u8; 8]);
+        assert_eq!(backing_format(&buf, 112).as_deref(), Some("raw"));
+        assert_eq!(backing_format(&v3_header(), 112), None);
+    }
+}
@@ -3,8 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
+use std::fs::File;
 use std::ops::DerefMut;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use virtio_device::{VirtioDevice, VirtioDeviceType};
 
@@ -18,19 +20,25 @@ use vm_memory::GuestAddressSpace;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::devices::use_ioregionfd;
-use crate::devices::virtio::console::log_handler::LogQueueHandler;
-use crate::devices::virtio::console::VIRTIO_CONSOLE_F_SIZE;
+use crate::devices::virtio::console::stdin_stdout_handler::StdinStdoutHandler;
+use crate::devices::virtio::console::{
+    window_size, WindowSize, FORWARD_INPUT, RAW_INPUT, VIRTIO_CONSOLE_F_SIZE,
+};
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::{
+    access, IrqAckHandler, MmioConfig, NotifyBatch, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+    VIRTIO_MMIO_INT_CONFIG,
+};
 use crate::devices::MaybeIoRegionFd;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd, Hypervisor,
 };
+use crate::metrics;
 
 //use super::queue_handler::QueueHandler;
-use super::{build_config_space, ConsoleArgs, Error, Result, CONSOLE_DEVICE_ID};
+use super::{build_config_space, ConsoleArgs, Error, Result, CONSOLE_DEVICE_ID, DETACH_KEY};
 use simple_error::map_err_with;
 
 pub struct Console<M: GuestAddressSpace> {
@@ -71,7 +79,8 @@ where
         // A console device has two queue.
         let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 2];
 
-        let config_space = build_config_space();
+        let size = window_size(libc::STDOUT_FILENO).unwrap_or_default();
+        let config_space = build_config_space(size);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
@@ -136,28 +145,48 @@ where
             irqfd: self.irqfd.clone(),
             interrupt_status: self.virtio_cfg.interrupt_status.clone(),
             ack_handler: self.irq_ack_handler.clone(),
+            batch: NotifyBatch::default(),
         };
 
-        // FIXME replace with actual console
-        let console = map_err_with!(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open("/proc/self/fd/0"),
-            "could not open console"
-        )
+        let input = if FORWARD_INPUT.load(Ordering::Acquire) {
+            map_err_with!(dup_file(libc::STDIN_FILENO), "could not open stdin")
+        } else {
+            map_err_with!(File::open("/dev/null"), "could not open /dev/null")
+        }
         .map_err(Error::Simple)?;
+        let output = map_err_with!(dup_file(libc::STDOUT_FILENO), "could not open stdout")
+            .map_err(Error::Simple)?;
+        // Only interactive sessions can be left with the detach key, everything else ends with
+        // a signal.
+        let detach_key = if RAW_INPUT.load(Ordering::Acquire) {
+            Some(DETACH_KEY)
+        } else {
+            None
+        };
 
-        //let rx_fd = register_ioeventfd(&self.vmm, &self.mmio_cfg, 0).map_err(Error::Simple)?;
+        let rx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 0)
+            .map_err(Error::Simple)?;
         let tx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 1)
             .map_err(Error::Simple)?;
 
-        let handler = Arc::new(Mutex::new(LogQueueHandler {
+        let event_idx = self.virtio_cfg.driver_features & (1 << VIRTIO_F_RING_EVENT_IDX) != 0;
+        let mut rxq = self.virtio_cfg.queues[0].clone();
+        rxq.set_event_idx(event_idx);
+        let mut txq = self.virtio_cfg.queues[1].clone();
+        txq.set_event_idx(event_idx);
+
+        let handler = Arc::new(Mutex::new(StdinStdoutHandler {
             driver_notify,
+            rx_fd,
             tx_fd,
-            rxq: self.virtio_cfg.queues[0].clone(),
-            txq: self.virtio_cfg.queues[1].clone(),
-            console,
+            rxq,
+            txq,
+            input,
+            output,
+            detach_key,
+            pending: vec![],
+            input_paused: false,
+            marker: vec![],
         }));
 
         // Register the queue handler with the `EventManager`. We record the `sub_id`
@@ -195,6 +224,29 @@ where
     }
 }
 
+impl<M: GuestAddressSpace> Console<M> {
+    /// Tells the guest that the terminal was resized. The guest only learns about the size once
+    /// the driver is up, returns whether it was notified.
+    pub fn resize(&mut self, size: WindowSize) -> Result<bool> {
+        self.virtio_cfg.config_space = build_config_space(size);
+        self.virtio_cfg.config_generation = self.virtio_cfg.config_generation.wrapping_add(1);
+        if !self.virtio_cfg.device_activated {
+            return Ok(false);
+        }
+        self.virtio_cfg
+            .interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
+        self.irqfd.write(1).map_err(Error::EventFd)?;
+        metrics::IRQ_INJECTIONS.inc();
+        Ok(true)
+    }
+}
+
+/// Our own copy of a standard stream, so that the event manager can own it.
+fn dup_file(fd: RawFd) -> nix::Result<File> {
+    Ok(unsafe { File::from_raw_fd(nix::unistd::dup(fd)?) })
+}
+
 impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for Console<M> {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
         &mut self.ioregionfd
@@ -254,10 +306,10 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for Cons
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Console<M> {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.read(offset, data);
+        access::read::<M, _>(self, offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
-        self.write(offset, data);
+        access::write::<M, _>(self, offset, data);
     }
 }
@@ -1,9 +1,9 @@
 mod device;
-mod log_handler;
-mod stdin_stdout_device;
+mod raw_terminal;
 mod stdin_stdout_handler;
 
 use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
 use event_manager::Error as EvmgrError;
 use vm_device::bus;
@@ -13,6 +13,36 @@ use crate::devices::virtio::CommonArgs;
 use simple_error::SimpleError;
 
 pub use device::Console;
+pub use raw_terminal::{window_size, RawTerminal, WindowSize};
+pub use stdin_stdout_handler::DETACH_KEY;
+
+/// Written by stage2 once it is done, followed by `;<exit code of the command>` and a BEL.
+/// Terminals ignore it as an unknown operating system command.
+pub const SESSION_END_MARKER: &[u8] = b"\x1b]vmsh;exit";
+
+/// Stop vmsh when stage2 writes `SESSION_END_MARKER`.
+pub static STOP_ON_SESSION_END: AtomicBool = AtomicBool::new(false);
+
+/// Exit code stage2 reported with `SESSION_END_MARKER`, -1 until then.
+static SESSION_EXIT_CODE: AtomicI32 = AtomicI32::new(-1);
+
+/// Exit code of the command in the guest, if its session ended already.
+pub fn session_exit_code() -> Option<i32> {
+    match SESSION_EXIT_CODE.load(Ordering::Acquire) {
+        -1 => None,
+        code => Some(code),
+    }
+}
+
+fn set_session_exit_code(code: i32) {
+    SESSION_EXIT_CODE.store(code, Ordering::Release);
+}
+
+/// Pass stdin to the guest, otherwise the guest sees the end of its input right away.
+pub static FORWARD_INPUT: AtomicBool = AtomicBool::new(true);
+
+/// stdin is a terminal in raw mode, so the session can be left with `DETACH_KEY`.
+pub static RAW_INPUT: AtomicBool = AtomicBool::new(false);
 
 /// Console device ID as defined by the standard.
 pub const CONSOLE_DEVICE_ID: u32 = 3;
@@ -60,37 +90,10 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
 }
 
-//fn get_winsize(term_fd: RawFd) -> winsize {
-//    use std::mem::zeroed;
-//    unsafe {
-//        let mut ws: winsize = zeroed();
-//        match libc::ioctl(term_fd, libc::TIOCGWINSZ, &mut ws) {
-//            0 => ws,
-//            _ => winsize {
-//                ws_row: 80,
-//                ws_col: 25,
-//                ws_xpixel: 0,
-//                ws_ypixel: 0,
-//            },
-//        }
-//    }
-//}
-//
-//fn resize_pty(pty_master: RawFd) {
-//    unsafe {
-//        libc::ioctl(
-//            pty_master,
-//            libc::TIOCSWINSZ,
-//            &mut get_winsize(libc::STDOUT_FILENO),
-//        );
-//    }
-//}
-
-fn build_config_space() -> Vec<u8> {
-    // FIXME think about terminal size
+fn build_config_space(size: WindowSize) -> Vec<u8> {
     let config = virtio_console_config {
-        cols: 80,
-        rows: 24,
+        cols: size.cols,
+        rows: size.rows,
         max_nr_ports: 2,
         emerg_wr: 0,
     };
@@ -101,3 +104,18 @@ fn build_config_space() -> Vec<u8> {
 pub struct ConsoleArgs<'a, M, B> {
     pub common: CommonArgs<'a, M, B>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_config_space() {
+        let config = build_config_space(WindowSize {
+            cols: 132,
+            rows: 43,
+        });
+        assert_eq!(config.len(), 12);
+        assert_eq!(&config[..4], &[132, 0, 43, 0]);
+    }
+}
@@ -0,0 +1,60 @@
+use nix::sys::termios::{self, OutputFlags, SetArg, Termios};
+use simple_error::try_with;
+use std::os::unix::io::RawFd;
+
+use crate::result::Result;
+
+/// Puts a terminal into raw mode so that keystrokes are passed to the guest unmodified, i.e.
+/// Ctrl-C interrupts the command in the guest and not vmsh. Restores the old mode on drop.
+pub struct RawTerminal {
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl RawTerminal {
+    pub fn new(fd: RawFd) -> Result<RawTerminal> {
+        let orig = try_with!(termios::tcgetattr(fd), "cannot get terminal attributes");
+        let mut raw = orig.clone();
+        termios::cfmakeraw(&mut raw);
+        // the guest terminal does not translate newlines for us
+        raw.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+        try_with!(
+            termios::tcsetattr(fd, SetArg::TCSANOW, &raw),
+            "cannot set terminal attributes"
+        );
+        Ok(RawTerminal { fd, orig })
+    }
+}
+
+/// Size of a terminal in characters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> WindowSize {
+        WindowSize { cols: 80, rows: 24 }
+    }
+}
+
+/// None if `fd` is not a terminal or the terminal does not know its size.
+pub fn window_size(fd: RawFd) -> Option<WindowSize> {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) } != 0 || ws.ws_col == 0 {
+        return None;
+    }
+    Some(WindowSize {
+        cols: ws.ws_col,
+        rows: ws.ws_row,
+    })
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.orig) {
+            log::warn!("cannot restore terminal attributes: {}", e);
+        }
+    }
+}
@@ -1,12 +1,62 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// Author of further modifications: Peter Okelmann
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::cmp::min;
 use std::fs::File;
+use std::io::{Read, Write};
+use std::result;
+use std::sync::atomic::Ordering;
 
+use event_manager::EventOps;
+use event_manager::EventSet;
+use event_manager::Events;
 use event_manager::MutEventSubscriber;
-use virtio_queue::Queue;
-use vm_memory::GuestAddressSpace;
+use log::error;
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::Bytes;
+use vm_memory::{self, GuestAddressSpace};
+
+use crate::devices::virtio::console::{
+    set_session_exit_code, SESSION_END_MARKER, STOP_ON_SESSION_END,
+};
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+use crate::signal_handler;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+const RX_IOEVENT_DATA: u32 = 0;
+const TX_IOEVENT_DATA: u32 = 1;
+const INPUT_DATA: u32 = 2;
 
-use crate::{devices::virtio::SignalUsedQueue, kvm::hypervisor::ioevent::IoEvent};
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
 
-pub struct StdinStdoutHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+/// Ctrl-], the same as in telnet
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// Connects the console queues to a host side stream: the guest reads `input` from the rx queue
+/// and everything it writes to the tx queue goes to `output`.
+pub(crate) struct StdinStdoutHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    /// ioevent fd to indicate new buffers added to the rx queue
+    pub rx_fd: IoEvent,
     /// ioevent fd to indicate new data added to the tx queue
     pub tx_fd: IoEvent,
     /// Notify driver about used buffers
@@ -15,14 +65,325 @@ pub struct StdinStdoutHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub rxq: Queue<M>,
     /// tx queue for receiving data from the guest
     pub txq: Queue<M>,
-    /// host side stream
-    pub console: File,
+    pub input: File,
+    pub output: File,
+    /// Stop vmsh when this byte is read from `input`. Only set for interactive terminals.
+    pub detach_key: Option<u8>,
+    /// Input that did not fit into the buffers of the guest yet
+    pub pending: Vec<u8>,
+    /// We stop reading input while the guest has no buffers for it.
+    pub input_paused: bool,
+    /// Start of a `SESSION_END_MARKER` seen at the end of the last output
+    pub marker: Vec<u8>,
+}
+
+/// Terminates `SESSION_END_MARKER` and the exit code after it
+const BEL: u8 = 0x07;
+/// Digits of an exit code, which is at most 255
+const MAX_EXIT_CODE_LEN: usize = 3;
+
+enum Marker {
+    /// Could still become a marker with the next bytes
+    Partial,
+    /// A whole marker with the exit code it carries, if any
+    Complete(Option<i32>),
+    Invalid,
+}
+
+/// Whether `m` is a `SESSION_END_MARKER`, optionally with `;<exit code>`, before the BEL.
+fn parse_marker(m: &[u8]) -> Marker {
+    if m.len() <= SESSION_END_MARKER.len() {
+        return if SESSION_END_MARKER.starts_with(m) {
+            Marker::Partial
+        } else {
+            Marker::Invalid
+        };
+    }
+    if !m.starts_with(SESSION_END_MARKER) {
+        return Marker::Invalid;
+    }
+    let rest = &m[SESSION_END_MARKER.len()..];
+    if rest == [BEL] {
+        return Marker::Complete(None);
+    }
+    if rest[0] != b';' {
+        return Marker::Invalid;
+    }
+    let (digits, terminated) = match rest[1..].split_last() {
+        Some((&BEL, digits)) => (digits, true),
+        _ => (&rest[1..], false),
+    };
+    if digits.len() > MAX_EXIT_CODE_LEN || !digits.iter().all(|d| d.is_ascii_digit()) {
+        return Marker::Invalid;
+    }
+    if !terminated {
+        return Marker::Partial;
+    }
+    match std::str::from_utf8(digits)
+        .ok()
+        .and_then(|d| d.parse().ok())
+    {
+        Some(code) => Marker::Complete(Some(code)),
+        None => Marker::Invalid,
+    }
+}
+
+/// The end of the session was found in the output.
+#[derive(Debug, PartialEq)]
+struct SessionEnd {
+    exit_code: Option<i32>,
 }
 
-impl<M: GuestAddressSpace, S: SignalUsedQueue> StdinStdoutHandler<M, S> {}
+/// Remove `SESSION_END_MARKER` from `buf`. `marker` carries the start of a marker over to the
+/// next buffer. Returns the remaining output and the end of the session if the marker was found.
+fn strip_marker(buf: &[u8], marker: &mut Vec<u8>) -> (Vec<u8>, Option<SessionEnd>) {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut end = None;
+    for b in buf {
+        if marker.is_empty() && *b != SESSION_END_MARKER[0] {
+            out.push(*b);
+            continue;
+        }
+        marker.push(*b);
+        match parse_marker(marker) {
+            Marker::Partial => {}
+            Marker::Complete(exit_code) => {
+                end = Some(SessionEnd { exit_code });
+                marker.clear();
+            }
+            Marker::Invalid => {
+                // the marker starts with the only escape character in it, so we can restart here
+                out.extend_from_slice(&marker[..marker.len() - 1]);
+                marker.clear();
+                if *b == SESSION_END_MARKER[0] {
+                    marker.push(*b);
+                } else {
+                    out.push(*b);
+                }
+            }
+        }
+    }
+    (out, end)
+}
+
+impl<M, S> StdinStdoutHandler<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    fn handle_error<Msg: AsRef<str>>(&self, s: Msg, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(&self.tx_fd))
+            .expect("Failed to remove tx ioevent");
+        ops.remove(Events::empty(&self.rx_fd))
+            .expect("Failed to remove rx ioevent");
+    }
+
+    fn process_tx_chain(&mut self, mut chain: DescriptorChain<M>) -> result::Result<(), Error> {
+        log::trace!("process_tx_chain");
+
+        let mut i = 0;
+        while let Some(desc) = chain.next() {
+            let mut buf = vec![0u8; desc.len() as usize];
+            chain.memory().read_slice(&mut buf, desc.addr())?;
+            let (out, end) = strip_marker(&buf, &mut self.marker);
+            if let Err(e) = self.output.write_all(&out) {
+                error!("error writing console output: {}", e)
+            }
+            if let Some(end) = end {
+                if let Some(code) = end.exit_code {
+                    set_session_exit_code(code);
+                }
+                if STOP_ON_SESSION_END.load(Ordering::Acquire) {
+                    signal_handler::stop_vmsh();
+                }
+            }
+            i += 1;
+        }
+        self.txq.add_used(chain.head_index(), i as u32)?;
+
+        if self.txq.needs_notification()? {
+            log::trace!("notification needed: yes");
+            self.driver_notify.signal_used_queue(TX_QUEUE);
+        } else {
+            log::trace!("notification needed: no");
+        }
+
+        Ok(())
+    }
+
+    pub fn process_txq(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.txq.disable_notification()?;
+
+            while let Some(chain) = self.txq.iter()?.next() {
+                self.process_tx_chain(chain)?;
+            }
+
+            if !self.txq.enable_notification()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move pending input into the buffers the guest has made available.
+    pub fn process_rxq(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let mut chain = match self.rxq.iter()?.next() {
+                Some(chain) => chain,
+                None => break,
+            };
+            let mut written = 0;
+            while let Some(desc) = chain.next() {
+                if !desc.is_write_only() {
+                    continue;
+                }
+                let n = min(desc.len() as usize, self.pending.len() - written);
+                if n == 0 {
+                    break;
+                }
+                chain
+                    .memory()
+                    .write_slice(&self.pending[written..written + n], desc.addr())?;
+                written += n;
+            }
+            self.pending.drain(..written);
+            self.rxq.add_used(chain.head_index(), written as u32)?;
+            used = true;
+        }
+        if used && self.rxq.needs_notification()? {
+            self.driver_notify.signal_used_queue(RX_QUEUE);
+        }
+        Ok(())
+    }
+
+    fn read_input(&mut self, ops: &mut EventOps) -> result::Result<(), Error> {
+        let mut buf = [0u8; 4096];
+        let mut n = match self.input.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("cannot read console input: {}", e);
+                0
+            }
+        };
+        if n == 0 {
+            // end of input, i.e. stdin was a pipe
+            let _ = ops.remove(Events::empty(&self.input));
+            return Ok(());
+        }
+        if let Some(key) = self.detach_key {
+            if let Some(pos) = buf[..n].iter().position(|b| *b == key) {
+                n = pos;
+                signal_handler::stop_vmsh();
+            }
+        }
+        self.pending.extend_from_slice(&buf[..n]);
+        self.process_rxq()?;
+        if !self.pending.is_empty() {
+            let _ = ops.remove(Events::empty(&self.input));
+            self.input_paused = true;
+        }
+        Ok(())
+    }
+
+    fn resume_input(&mut self, ops: &mut EventOps) {
+        if self.input_paused && self.pending.is_empty() {
+            self.input_paused = false;
+            if let Err(e) = ops.add(Events::with_data(&self.input, INPUT_DATA, EventSet::IN)) {
+                error!("cannot resume reading console input: {}", e);
+            }
+        }
+    }
+}
 
 impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for StdinStdoutHandler<M, S> {
-    fn process(&mut self, events: event_manager::Events, ops: &mut event_manager::EventOps) {}
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        match events.data() {
+            INPUT_DATA => {
+                if let Err(e) = self.read_input(ops) {
+                    self.handle_error(format!("Process rx error {:?}", e), ops);
+                }
+            }
+            RX_IOEVENT_DATA => {
+                if events.event_set() != EventSet::IN || self.rx_fd.read().is_err() {
+                    self.handle_error("Rx ioevent read", ops);
+                    return;
+                }
+                if let Err(e) = self.process_rxq() {
+                    self.handle_error(format!("Process rx error {:?}", e), ops);
+                }
+                self.resume_input(ops);
+            }
+            TX_IOEVENT_DATA => {
+                if events.event_set() != EventSet::IN || self.tx_fd.read().is_err() {
+                    self.handle_error("Tx ioevent read", ops);
+                    return;
+                }
+                if let Err(e) = self.process_txq() {
+                    self.handle_error(format!("Process tx error {:?}", e), ops);
+                }
+            }
+            _ => self.handle_error("Unexpected data", ops),
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            &self.rx_fd,
+            RX_IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to register rx ioeventfd for console queue handler");
+        ops.add(Events::with_data(
+            &self.tx_fd,
+            TX_IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to register tx ioeventfd for console queue handler");
+        ops.add(Events::with_data(&self.input, INPUT_DATA, EventSet::IN))
+            .expect("Failed to register console input");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_marker() {
+        let mut marker = vec![];
+        let (out, end) = strip_marker(b"done\x1b[0m\x1b]vmsh;", &mut marker);
+        assert_eq!(out, b"done\x1b[0m");
+        assert_eq!(end, None);
+        let (out, end) = strip_marker(b"exit\x07\n", &mut marker);
+        assert_eq!(out, b"\n");
+        assert_eq!(end, Some(SessionEnd { exit_code: None }));
+        let (out, end) = strip_marker(b"\x1b]vmx", &mut marker);
+        assert_eq!(out, b"\x1b]vmx");
+        assert_eq!(end, None);
+    }
 
-    fn init(&mut self, ops: &mut event_manager::EventOps) {}
+    #[test]
+    fn test_strip_marker_exit_code() {
+        let mut marker = vec![];
+        let (out, end) = strip_marker(b"FAIL\n\x1b]vmsh;exit;4", &mut marker);
+        assert_eq!(out, b"FAIL\n");
+        assert_eq!(end, None);
+        let (out, end) = strip_marker(b"2\x07", &mut marker);
+        assert!(out.is_empty());
+        assert_eq!(
+            end,
+            Some(SessionEnd {
+                exit_code: Some(42)
+            })
+        );
+        // not an exit code, passed through as output
+        let (out, end) = strip_marker(b"\x1b]vmsh;exit;x\x07", &mut marker);
+        assert_eq!(out, b"\x1b]vmsh;exit;x\x07");
+        assert_eq!(end, None);
+    }
 }
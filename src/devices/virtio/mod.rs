@@ -4,14 +4,18 @@
 
 // We're only providing virtio over MMIO devices for now, but we aim to add PCI support as well.
 
+mod access;
 pub mod block;
 pub mod console;
+pub mod p9;
+pub mod vsock;
 
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::kvm::hypervisor::{ioeventfd::IoEventFd, Hypervisor};
+use crate::metrics;
 use crate::result::Result;
 use crate::tracer::inject_syscall;
 use crate::tracer::wrap_syscall::KvmRunWrapper;
@@ -39,6 +43,9 @@ mod features {
 // disabled. Let's figure out at some point if having MMIO as part of the name is necessary.
 const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
 
+// Set on the device interrupt status when the configuration space of the device changed.
+const VIRTIO_MMIO_INT_CONFIG: u8 = 0x02;
+
 // The driver will write to the register at this offset in the MMIO region to notify the device
 // about available queue events.
 const VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET: u64 = 0x50;
@@ -81,6 +88,55 @@ pub trait SignalUsedQueue {
     // TODO: Should this return an error? This failing is not really recoverable at the interface
     // level so the expectation is the implementation handles that transparently somehow.
     fn signal_used_queue(&self, index: u16);
+
+    /// Records that a descriptor was added to the used ring. Returns whether the driver should
+    /// be signalled now rather than together with the descriptors that are used next.
+    fn descriptor_used(&mut self) -> bool {
+        true
+    }
+
+    /// Ends the current batch of used descriptors once the device has nothing left to process.
+    /// Returns whether descriptors of the batch still need to be signalled.
+    fn end_batch(&mut self) -> bool {
+        false
+    }
+}
+
+/// A batch of used descriptors is signalled once it has this many descriptors...
+const NOTIFY_BATCH_SIZE: u16 = 16;
+/// ...or once its first descriptor is this old.
+const NOTIFY_BATCH_DELAY: Duration = Duration::from_micros(200);
+
+/// Used descriptors the driver was not signalled about yet. Together with VIRTIO_F_RING_EVENT_IDX
+/// this saves an interrupt, and the vmexits of the driver acking it, for most completions under
+/// load. Whether a batch is signalled at all is still up to `Queue::needs_notification`, i.e.
+/// the used event index of the driver.
+#[derive(Default)]
+pub struct NotifyBatch {
+    pending: u16,
+    /// When the first descriptor of the batch was used
+    started: Option<Instant>,
+}
+
+impl NotifyBatch {
+    /// Adds a used descriptor. Returns true and starts a new batch if the current one is full or
+    /// old enough.
+    fn add(&mut self, now: Instant) -> bool {
+        self.pending += 1;
+        let started = *self.started.get_or_insert(now);
+        if self.pending < NOTIFY_BATCH_SIZE && now.duration_since(started) < NOTIFY_BATCH_DELAY {
+            return false;
+        }
+        *self = NotifyBatch::default();
+        true
+    }
+
+    /// Starts a new batch, returns whether the current one has used descriptors.
+    fn take(&mut self) -> bool {
+        let pending = self.pending > 0;
+        *self = NotifyBatch::default();
+        pending
+    }
 }
 
 /// Uses a single irqfd as the basis of signalling any queue (useful for the MMIO transport,
@@ -89,6 +145,7 @@ pub struct SingleFdSignalQueue {
     pub irqfd: Arc<EventFd>,
     pub interrupt_status: Arc<AtomicU8>,
     pub ack_handler: Arc<Mutex<IrqAckHandler>>,
+    pub batch: NotifyBatch,
 }
 
 impl SignalUsedQueue for SingleFdSignalQueue {
@@ -99,12 +156,21 @@ impl SignalUsedQueue for SingleFdSignalQueue {
         if let Err(e) = self.irqfd.write(1) {
             error!("Failed write to eventfd when signalling queue: {}", e);
         } else {
+            metrics::IRQ_INJECTIONS.inc();
             match self.ack_handler.lock() {
                 Ok(mut handler) => handler.irq_sent(),
                 Err(e) => error!("Failed to lock IrqAckHandler: {}", e),
             }
         }
     }
+
+    fn descriptor_used(&mut self) -> bool {
+        self.batch.add(Instant::now())
+    }
+
+    fn end_batch(&mut self) -> bool {
+        self.batch.take()
+    }
 }
 
 /// Note: `device::threads::EVENT_LOOP_TIMEOUT_MS` typically determines how often the irq ack
@@ -150,6 +216,7 @@ impl IrqAckHandler {
                 log::error!("Failed write to eventfd when signalling queue: {}", e);
             } else {
                 self.total_ack_timeouted += 1;
+                metrics::IRQ_RESENDS.inc();
                 self.resent = Instant::now();
                 log::debug!(
                     "re-sending lost interrupt after {:.1}ms. Total lost {:.0}% ({}/{})",
@@ -179,6 +246,18 @@ pub fn register_ioeventfd(
     //         0u32,
     //     )
     //     .map_err(Error::RegisterIoevent)?;
+    with_injector(vmm, || {
+        vmm.ioeventfd_(
+            mmio_cfg.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
+            4,
+            Some(queue_idx),
+        )
+    })
+}
+
+/// Runs `f` with the syscall injector attached instead of the KvmRunWrapper, so that `f` can
+/// make ioctls in the hypervisor while the mmio exit handler owns ptrace.
+pub fn with_injector<T>(vmm: &Arc<Hypervisor>, f: impl FnOnce() -> Result<T>) -> Result<T> {
     let mut wrapper_go = try_with!(vmm.wrapper.lock(), "cannot obtain wrapper mutex");
 
     // wrapper -> injector
@@ -191,12 +270,8 @@ pub fn register_ioeventfd(
         try_with!(tracee.attach_to(injector), &err);
     }
 
-    // we need to drop tracee for ioeventfd_
-    let ioeventfd = vmm.ioeventfd_(
-        mmio_cfg.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
-        4,
-        Some(queue_idx),
-    )?;
+    // we need to drop tracee for f
+    let res = f();
 
     // injector -> wrapper
     {
@@ -209,5 +284,28 @@ pub fn register_ioeventfd(
         )?)?;
         let _ = wrapper_go.replace(wrapper);
     }
-    Ok(ioeventfd)
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_batch() {
+        let start = Instant::now();
+        let mut batch = NotifyBatch::default();
+        assert!(!batch.take());
+        for _ in 1..NOTIFY_BATCH_SIZE {
+            assert!(!batch.add(start));
+        }
+        assert!(batch.add(start));
+        assert!(!batch.take());
+
+        assert!(!batch.add(start));
+        assert!(batch.add(start + NOTIFY_BATCH_DELAY));
+        assert!(!batch.add(start + NOTIFY_BATCH_DELAY));
+        assert!(batch.take());
+        assert!(!batch.take());
+    }
 }
@@ -1,35 +1,48 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::borrow::{Borrow, BorrowMut};
+use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
+use virtio_device::{VirtioDevice, VirtioDeviceType};
 
-use std::fs::OpenOptions;
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
 use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
 
-use super::{build_config_space, ConsoleArgs, Error, Result, CONSOLE_DEVICE_ID};
 use crate::devices::use_ioregionfd;
-use crate::devices::virtio::console::stdin_stdout_handler::StdinStdoutHandler;
-use crate::devices::virtio::console::VIRTIO_CONSOLE_F_SIZE;
+use crate::devices::virtio::features::{VIRTIO_F_IN_ORDER, VIRTIO_F_VERSION_1};
+use crate::devices::virtio::p9::handler::RequestHandler;
+use crate::devices::virtio::p9::server::Server;
 use crate::devices::virtio::{
-    features::{VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1},
-    register_ioeventfd,
+    access, IrqAckHandler, MmioConfig, NotifyBatch, SingleFdSignalQueue, QUEUE_MAX_SIZE,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
 use crate::devices::MaybeIoRegionFd;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd, Hypervisor,
 };
-use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
-use std::borrow::{Borrow, BorrowMut};
-use std::ops::DerefMut;
-use virtio_device::{VirtioConfig, VirtioDevice, VirtioDeviceType};
-use virtio_device::{VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
-use vm_device::bus::MmioAddress;
-use vm_device::device_manager::MmioManager;
-use vm_device::{DeviceMmio, MutDeviceMmio};
-use vmm_sys_util::eventfd::EventFd;
 
+use super::{
+    build_config_space, Error, P9Args, Result, ShareOptions, MOUNT_TAG, P9_DEVICE_ID,
+    VIRTIO_9P_MOUNT_TAG,
+};
 use simple_error::map_err_with;
 
-pub struct StdConsole<M: GuestAddressSpace> {
+fn open_server(opts: &ShareOptions) -> Result<Server> {
+    map_err_with!(
+        Server::new(&opts.host_dir),
+        "cannot open shared directory {}",
+        opts.host_dir.display()
+    )
+    .map_err(Error::Simple)
+}
+
+pub struct P9<M: GuestAddressSpace> {
     virtio_cfg: VirtioConfig<M>,
     pub mmio_cfg: MmioConfig,
     endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
@@ -38,6 +51,7 @@ pub struct StdConsole<M: GuestAddressSpace> {
     irqfd: Arc<EventFd>,
     pub ioregionfd: Option<IoRegionFd>,
     pub uioefd: UserspaceIoEventFd,
+    opts: ShareOptions,
     /// only used when ioregionfd != None
     sub_id: Option<SubscriberId>,
 
@@ -46,26 +60,30 @@ pub struct StdConsole<M: GuestAddressSpace> {
     handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
 }
 
-impl<M> StdConsole<M>
+impl<M> P9<M>
 where
     M: GuestAddressSpace + Clone + Send + 'static,
 {
-    pub fn new<B>(mut args: ConsoleArgs<M, B>) -> Result<Arc<Mutex<Self>>>
+    pub fn new<B>(mut args: P9Args<M, B>) -> Result<Arc<Mutex<Self>>>
     where
         // We're using this (more convoluted) bound so we can pass both references and smart
         // pointers such as mutex guards here.
         B: DerefMut,
         B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
     {
-        let device_features = 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_F_IN_ORDER
-            | 1 << VIRTIO_F_RING_EVENT_IDX
-            | 1 << VIRTIO_CONSOLE_F_SIZE;
+        // Requests are answered in order. Event idx is not supported, so the driver notifies us
+        // about every buffer.
+        let device_features =
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IN_ORDER | 1 << VIRTIO_9P_MOUNT_TAG;
 
-        let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 2];
-        let config_space = build_config_space();
+        // A 9p device has a single request queue.
+        let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 1];
+
+        let config_space = build_config_space(MOUNT_TAG);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
+        // Used to send notifications to the driver.
+        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
         let irqfd = Arc::new(
             args.common
                 .vmm
@@ -75,6 +93,9 @@ where
 
         let mmio_cfg = args.common.mmio_cfg;
 
+        // fail early if the directory is missing
+        open_server(&args.opts)?;
+
         let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
             virtio_cfg.interrupt_status.clone(),
             Arc::clone(&irqfd),
@@ -90,7 +111,7 @@ where
             );
         }
 
-        let console = Arc::new(Mutex::new(StdConsole {
+        let p9 = Arc::new(Mutex::new(P9 {
             virtio_cfg,
             mmio_cfg,
             endpoint: args.common.event_mgr.remote_endpoint(),
@@ -99,6 +120,7 @@ where
             irqfd,
             ioregionfd,
             uioefd: UserspaceIoEventFd::default(),
+            opts: args.opts,
             sub_id: None,
             handler: None,
         }));
@@ -106,10 +128,10 @@ where
         // Register the device on the MMIO bus.
         args.common
             .mmio_mgr
-            .register_mmio(mmio_cfg.range, console.clone())
+            .register_mmio(mmio_cfg.range, p9.clone())
             .map_err(Error::Bus)?;
 
-        Ok(console)
+        Ok(p9)
     }
 
     fn _activate(&mut self) -> Result<()> {
@@ -126,30 +148,19 @@ where
             irqfd: self.irqfd.clone(),
             interrupt_status: self.virtio_cfg.interrupt_status.clone(),
             ack_handler: self.irq_ack_handler.clone(),
+            batch: NotifyBatch::default(),
         };
 
-        // TODO: replace with pty or tty
-        // stat /proc/self/fd/0 to get /dev/pts/X
-        let console = map_err_with!(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open("/proc/self/fd/0"),
-            "could not open console"
-        )
-        .map_err(Error::Simple)?;
-
-        let rx_fd = register_ioeventfd(&self.vmm, &self.mmio_cfg, 0).map_err(Error::Simple)?;
-        let tx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 1)
+        let ioeventfd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 0)
             .map_err(Error::Simple)?;
 
-        let handler = Arc::new(Mutex::new(StdinStdoutHandler {
+        let request_handler = RequestHandler {
+            ioeventfd,
             driver_notify,
-            tx_fd,
-            rxq: self.virtio_cfg.queues[0].clone(),
-            txq: self.virtio_cfg.queues[1].clone(),
-            console,
-        }));
+            queue: self.virtio_cfg.queues[0].clone(),
+            server: open_server(&self.opts)?,
+        };
+        let handler = Arc::new(Mutex::new(request_handler));
 
         // Register the queue handler with the `EventManager`. We record the `sub_id`
         // (and/or keep a handler clone) to remove the subscriber when resetting the device
@@ -171,11 +182,23 @@ where
     }
 
     fn _reset(&mut self) -> Result<()> {
+        // we remove the handler here, since we need to free up the ioeventfd resources
+        // in the mmio thread rather the eventmanager thread.
+        if let Some(sub_id) = self.sub_id.take() {
+            let handler = self
+                .endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.handler = Some(handler);
+        }
         Ok(())
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for P9<M> {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
         &mut self.ioregionfd
     }
@@ -183,25 +206,25 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for StdConso
 
 // We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
 // for `VirtioDevice`.
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for P9<M> {
     fn device_type(&self) -> u32 {
-        CONSOLE_DEVICE_ID
+        P9_DEVICE_ID
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for P9<M> {
     fn borrow(&self) -> &VirtioConfig<M> {
         &self.virtio_cfg
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for P9<M> {
     fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
         &mut self.virtio_cfg
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for P9<M> {
     type E = Error;
 
     /// make sure to set self.vmm.wrapper to Some() before activating. Typically this is done by
@@ -209,7 +232,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for StdC
     fn activate(&mut self) -> Result<()> {
         let ret = self._activate();
         if let Err(ref e) = ret {
-            log::warn!("failed to activate console device: {:?}", e);
+            log::warn!("failed to activate 9p device: {:?}", e);
         }
         ret
     }
@@ -221,7 +244,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for StdC
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for P9<M> {
     fn queue_notify(&mut self, val: u32) {
         if use_ioregionfd() {
             self.uioefd.queue_notify(val);
@@ -230,14 +253,14 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for St
     }
 }
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for StdConsole<M> {}
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for P9<M> {}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for StdConsole<M> {
+impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for P9<M> {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.read(offset, data);
+        access::read::<M, _>(self, offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
-        self.write(offset, data);
+        access::write::<M, _>(self, offset, data);
     }
 }
@@ -0,0 +1,138 @@
+use std::cmp::min;
+use std::result;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{self, Bytes, GuestAddressSpace};
+use vmm_sys_util::epoll::EventSet;
+
+use crate::devices::virtio::p9::server::Server;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+const IOEVENT_DATA: u32 = 0;
+
+const REQUEST_QUEUE: u16 = 0;
+
+/// Answers the 9p requests of the guest. Every buffer holds a request in its readable part and
+/// has room for the response in its writable part.
+pub(crate) struct RequestHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    pub ioeventfd: IoEvent,
+    pub driver_notify: S,
+    pub queue: Queue<M>,
+    pub server: Server,
+}
+
+impl<M, S> RequestHandler<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    fn process_chain(&mut self, mut chain: DescriptorChain<M>) -> result::Result<(), Error> {
+        let mut request = vec![];
+        let mut response_bufs = vec![];
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                response_bufs.push((desc.addr(), desc.len() as usize));
+                continue;
+            }
+            let mut buf = vec![0u8; desc.len() as usize];
+            chain.memory().read_slice(&mut buf, desc.addr())?;
+            request.extend_from_slice(&buf);
+        }
+
+        let response = self.server.handle(&request);
+
+        let mut written = 0;
+        for (addr, len) in response_bufs {
+            let n = min(len, response.len() - written);
+            if n == 0 {
+                break;
+            }
+            chain
+                .memory()
+                .write_slice(&response[written..written + n], addr)?;
+            written += n;
+        }
+        if written < response.len() {
+            warn!(
+                "9p response of {} bytes does not fit into the {} bytes buffer of the guest",
+                response.len(),
+                written
+            );
+        }
+        self.queue.add_used(chain.head_index(), written as u32)?;
+        Ok(())
+    }
+
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.queue.disable_notification()?;
+
+            while let Some(chain) = self.queue.iter()?.next() {
+                self.process_chain(chain)?;
+            }
+
+            if !self.queue.enable_notification()? {
+                break;
+            }
+        }
+        if self.queue.needs_notification()? {
+            self.driver_notify.signal_used_queue(REQUEST_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for RequestHandler<M, S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let mut error = true;
+
+        if events.event_set() != EventSet::IN {
+            error!("unexpected event_set");
+        } else if events.data() != IOEVENT_DATA {
+            error!("unexpected events data {}", events.data());
+        } else if self.ioeventfd.read().is_err() {
+            error!("ioeventfd read error")
+        } else if let Err(e) = self.process_queue() {
+            error!("error processing 9p queue {:?}", e);
+        } else {
+            error = false;
+        }
+
+        if error {
+            ops.remove(events)
+                .expect("Failed to remove fd from event handling loop");
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            &self.ioeventfd,
+            IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to init 9p queue handler");
+    }
+}
@@ -0,0 +1,110 @@
+//! virtio-9p device to share a host directory with the guest.
+//!
+//! vmsh answers 9P2000.L requests itself (see `server`), stage2 mounts the share with
+//! `mount -t 9p -o trans=virtio,version=9p2000.L vmsh-share <guest dir>`. The guest needs a kernel
+//! with CONFIG_NET_9P_VIRTIO and CONFIG_9P_FS.
+
+mod device;
+mod handler;
+mod protocol;
+mod server;
+
+use std::io;
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use simple_error::{bail, SimpleError};
+use vm_device::bus;
+use vmm_sys_util::errno;
+
+use crate::devices::virtio::CommonArgs;
+
+pub use device::P9;
+
+/// 9p device ID as defined by the standard.
+pub const P9_DEVICE_ID: u32 = 9;
+
+/// The mount tag is in the config space
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+/// Tag that stage2 mounts
+pub const MOUNT_TAG: &str = "vmsh-share";
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    EventFd(io::Error),
+    #[allow(dead_code)] // FIXME
+    QueuesNotValid,
+    #[allow(dead_code)] // FIXME
+    RegisterIoevent(errno::Error),
+    #[allow(dead_code)] // FIXME
+    RegisterIrqfd(errno::Error),
+    Simple(SimpleError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn build_config_space(tag: &str) -> Vec<u8> {
+    // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+    let mut config = (tag.len() as u16).to_le_bytes().to_vec();
+    config.extend_from_slice(tag.as_bytes());
+    config
+}
+
+/// Options of `vmsh attach --share`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareOptions {
+    pub host_dir: PathBuf,
+    /// where stage2 mounts the share
+    pub guest_dir: PathBuf,
+}
+
+impl ShareOptions {
+    /// Parses `HOST_DIR:GUEST_DIR`
+    pub fn parse(arg: &str) -> std::result::Result<ShareOptions, SimpleError> {
+        let (host, guest) = match arg.rsplit_once(':') {
+            Some(v) => v,
+            None => bail!("expected HOST_DIR:GUEST_DIR, got {}", arg),
+        };
+        if host.is_empty() {
+            bail!("host directory of {} is empty", arg);
+        }
+        if !guest.starts_with('/') {
+            bail!("guest directory {} is not an absolute path", guest);
+        }
+        Ok(ShareOptions {
+            host_dir: PathBuf::from(host),
+            guest_dir: PathBuf::from(guest),
+        })
+    }
+}
+
+// Arguments required when building a 9p device.
+pub struct P9Args<'a, M, B> {
+    pub common: CommonArgs<'a, M, B>,
+    pub opts: ShareOptions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_options() {
+        assert_eq!(
+            ShareOptions::parse("/home/user/tools:/mnt/tools").unwrap(),
+            ShareOptions {
+                host_dir: PathBuf::from("/home/user/tools"),
+                guest_dir: PathBuf::from("/mnt/tools"),
+            }
+        );
+        assert!(ShareOptions::parse("/home/user/tools").is_err());
+        assert!(ShareOptions::parse(":/mnt").is_err());
+        assert!(ShareOptions::parse("tools:mnt").is_err());
+        assert_eq!(build_config_space("ab"), vec![2, 0, b'a', b'b']);
+    }
+}
@@ -0,0 +1,180 @@
+//! Wire format of 9P2000.L, see
+//! https://github.com/chaos/diod/blob/master/protocol.md for the messages.
+//! All integers are little endian, strings are prefixed with their length as u16.
+
+/// Size of `size[4] type[1] tag[2]`
+pub const HEADER_SIZE: usize = 7;
+/// Size of a qid on the wire
+pub const QID_SIZE: usize = 13;
+
+pub const P9_RLERROR: u8 = 7;
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TSYMLINK: u8 = 16;
+pub const P9_TMKNOD: u8 = 18;
+pub const P9_TRENAME: u8 = 20;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TXATTRWALK: u8 = 30;
+pub const P9_TXATTRCREATE: u8 = 32;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TLOCK: u8 = 52;
+pub const P9_TGETLOCK: u8 = 54;
+pub const P9_TLINK: u8 = 70;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TAUTH: u8 = 102;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+pub const P9_TREMOVE: u8 = 122;
+
+/// The only protocol version we speak
+pub const VERSION_9P2000_L: &str = "9P2000.L";
+
+// Types of a qid
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// `valid` bits of Rgetattr that we fill in: everything except btime, gen and data_version.
+pub const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+// `valid` bits of Tsetattr
+pub const P9_SETATTR_MODE: u32 = 0x1;
+pub const P9_SETATTR_UID: u32 = 0x2;
+pub const P9_SETATTR_GID: u32 = 0x4;
+pub const P9_SETATTR_SIZE: u32 = 0x8;
+pub const P9_SETATTR_ATIME: u32 = 0x10;
+pub const P9_SETATTR_MTIME: u32 = 0x20;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x80;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Unique id of a file on the server
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+/// Parses the body of a request. Every getter fails with EINVAL once the message is exhausted.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+type Result<T> = std::result::Result<T, i32>;
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(libc::EINVAL);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from(self.u32()?) | u64::from(self.u32()?) << 32)
+    }
+
+    pub fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len as usize)
+    }
+}
+
+/// Builds a response message, the header is filled in by `finish`.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(type_: u8, tag: u16) -> Writer {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        buf[4] = type_;
+        buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        Writer { buf }
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    /// Strings longer than u16::MAX are truncated.
+    pub fn string(&mut self, v: &[u8]) -> &mut Self {
+        let v = &v[..v.len().min(u16::MAX as usize)];
+        self.u16(v.len() as u16).bytes(v)
+    }
+
+    pub fn qid(&mut self, qid: &Qid) -> &mut Self {
+        self.u8(qid.type_).u32(qid.version).u64(qid.path)
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Size, type and tag of a message
+pub fn parse_header(buf: &[u8]) -> Result<(u32, u8, u16)> {
+    let mut r = Reader::new(buf);
+    Ok((r.u32()?, r.u8()?, r.u16()?))
+}
+
+/// `Rlerror` for a failed request
+pub fn error_response(tag: u16, errno: i32) -> Vec<u8> {
+    let mut w = Writer::new(P9_RLERROR, tag);
+    w.u32(errno as u32);
+    w.finish()
+}
@@ -0,0 +1,870 @@
+//! 9P2000.L file server for a host directory.
+//!
+//! vmsh runs as root, so the guest must never reach files outside of the shared directory. Every
+//! fid holds an `O_PATH` descriptor that was opened component by component with `O_NOFOLLOW`
+//! starting from the shared directory: symlinks can be created and read, but are never followed
+//! on the host. Files are only opened through these descriptors (`/proc/self/fd`), and `..` is
+//! resolved by walking again from the shared directory.
+
+use libc::{c_int, EBADF, EBUSY, EINVAL, EIO, ELOOP, EOPNOTSUPP, EPERM};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use super::protocol::*;
+
+/// Largest message we negotiate, the guest allocates buffers of this size for every request.
+const MAX_MSIZE: u32 = 128 * 1024;
+/// Size of `size[4] type[1] tag[2] count[4]` in front of the data of Rread
+const RREAD_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+/// Walks are limited to this many names by the protocol.
+const MAXWELEM: usize = 16;
+/// n_uname of Tattach if the client did not set it
+const NONUNAME: u32 = u32::MAX;
+/// Status of Rlock
+const P9_LOCK_SUCCESS: u8 = 0;
+
+type Result<T> = std::result::Result<T, i32>;
+
+fn last_errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(EIO)
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn cvt(ret: c_int) -> Result<c_int> {
+    if ret < 0 {
+        Err(last_errno())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Names that create or remove directory entries must be a single path component.
+fn entry_name(name: &[u8]) -> Result<CString> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(EINVAL);
+    }
+    CString::new(name).map_err(|_| EINVAL)
+}
+
+/// Only permission bits and the sticky bit: the guest must not create setuid files owned by
+/// root on the host.
+fn permissions(mode: u32) -> libc::mode_t {
+    (mode & 0o1777) as libc::mode_t
+}
+
+fn open_path(dir: &File, name: &CStr) -> Result<File> {
+    let fd = cvt(unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    })?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Path that refers to exactly the file of `fd`, without resolving its name again.
+fn proc_path(fd: &File) -> CString {
+    CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap()
+}
+
+fn stat(fd: &File) -> Result<libc::stat> {
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    cvt(unsafe { libc::fstat(fd.as_raw_fd(), st.as_mut_ptr()) })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+fn file_type(mode: libc::mode_t) -> libc::mode_t {
+    mode & libc::S_IFMT
+}
+
+fn qid_type(mode: libc::mode_t) -> u8 {
+    match file_type(mode) {
+        libc::S_IFDIR => QTDIR,
+        libc::S_IFLNK => QTSYMLINK,
+        _ => QTFILE,
+    }
+}
+
+fn qid(st: &libc::stat) -> Qid {
+    Qid {
+        type_: qid_type(st.st_mode),
+        version: 0,
+        path: st.st_ino,
+    }
+}
+
+/// Sets the owner of a new file to the user that created it in the guest.
+fn chown_entry(dir: &File, name: &CStr, uid: u32, gid: u32) -> Result<()> {
+    cvt(unsafe {
+        libc::fchownat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            uid,
+            gid,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(())
+}
+
+struct DirEntry {
+    qid: Qid,
+    type_: u8,
+    name: Vec<u8>,
+}
+
+impl DirEntry {
+    /// qid[13] offset[8] type[1] name[s]
+    fn size(&self) -> usize {
+        QID_SIZE + 8 + 1 + 2 + self.name.len()
+    }
+}
+
+fn dirent_type(ft: fs::FileType) -> u8 {
+    let t = if ft.is_dir() {
+        libc::DT_DIR
+    } else if ft.is_symlink() {
+        libc::DT_LNK
+    } else if ft.is_file() {
+        libc::DT_REG
+    } else if ft.is_fifo() {
+        libc::DT_FIFO
+    } else if ft.is_socket() {
+        libc::DT_SOCK
+    } else if ft.is_char_device() {
+        libc::DT_CHR
+    } else if ft.is_block_device() {
+        libc::DT_BLK
+    } else {
+        libc::DT_UNKNOWN
+    };
+    t as u8
+}
+
+/// Entries that fit into `count` bytes of Rreaddir, starting at entry `offset`.
+fn pack_dir_entries(entries: &[DirEntry], offset: u64, count: u32) -> Vec<u8> {
+    let mut w = Writer::new(0, 0);
+    let mut size = 0;
+    for (i, e) in entries.iter().enumerate().skip(offset as usize) {
+        size += e.size();
+        if size > count as usize {
+            break;
+        }
+        // the offset of an entry is where the next readdir continues
+        w.qid(&e.qid).u64(i as u64 + 1).u8(e.type_).string(&e.name);
+    }
+    w.finish().split_off(HEADER_SIZE)
+}
+
+struct Fid {
+    /// `O_PATH` descriptor, never follows symlinks
+    path: File,
+    /// names below the shared directory, to resolve `..`
+    components: Vec<CString>,
+    /// set by Tlopen and Tlcreate
+    file: Option<File>,
+    /// read by the first Treaddir of an opened directory
+    dir_entries: Option<Vec<DirEntry>>,
+    /// user of the Tattach this fid was walked from
+    uid: u32,
+}
+
+impl Fid {
+    fn stat(&self) -> Result<libc::stat> {
+        stat(&self.path)
+    }
+
+    fn open_file(&self) -> Result<&File> {
+        self.file.as_ref().ok_or(EBADF)
+    }
+}
+
+pub struct Server {
+    root: File,
+    fids: HashMap<u32, Fid>,
+    msize: u32,
+}
+
+impl Server {
+    pub fn new(root: &Path) -> io::Result<Server> {
+        let root_c = CString::new(root.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe {
+            libc::open(
+                root_c.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Server {
+            root: unsafe { File::from_raw_fd(fd) },
+            fids: HashMap::new(),
+            msize: MAX_MSIZE,
+        })
+    }
+
+    /// Handles one request and returns the response.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let (size, type_, tag) = match parse_header(request) {
+            Ok(h) => h,
+            Err(e) => return error_response(0, e),
+        };
+        if size as usize > request.len() || (size as usize) < HEADER_SIZE {
+            return error_response(tag, EINVAL);
+        }
+        let mut r = Reader::new(&request[HEADER_SIZE..size as usize]);
+        let mut w = Writer::new(type_.wrapping_add(1), tag);
+        let res = match type_ {
+            P9_TVERSION => self.version(&mut r, &mut w),
+            P9_TATTACH => self.attach(&mut r, &mut w),
+            P9_TWALK => self.walk(&mut r, &mut w),
+            P9_TGETATTR => self.getattr(&mut r, &mut w),
+            P9_TSETATTR => self.setattr(&mut r),
+            P9_TLOPEN => self.lopen(&mut r, &mut w),
+            P9_TLCREATE => self.lcreate(&mut r, &mut w),
+            P9_TREAD => self.read(&mut r, &mut w),
+            P9_TWRITE => self.write(&mut r, &mut w),
+            P9_TREADDIR => self.readdir(&mut r, &mut w),
+            P9_TCLUNK => self.clunk(&mut r),
+            P9_TREMOVE => self.remove(&mut r),
+            P9_TMKDIR => self.mkdir(&mut r, &mut w),
+            P9_TSYMLINK => self.symlink(&mut r, &mut w),
+            P9_TMKNOD => self.mknod(&mut r, &mut w),
+            P9_TREADLINK => self.readlink(&mut r, &mut w),
+            P9_TLINK => self.link(&mut r),
+            P9_TRENAME => self.rename(&mut r),
+            P9_TRENAMEAT => self.renameat(&mut r),
+            P9_TUNLINKAT => self.unlinkat(&mut r),
+            P9_TFSYNC => self.fsync(&mut r),
+            P9_TSTATFS => self.statfs(&mut r, &mut w),
+            P9_TLOCK => self.lock(&mut r, &mut w),
+            P9_TGETLOCK => self.getlock(&mut r, &mut w),
+            // requests are handled synchronously, there is nothing to flush
+            P9_TFLUSH => Ok(()),
+            P9_TAUTH | P9_TXATTRWALK | P9_TXATTRCREATE => Err(EOPNOTSUPP),
+            _ => {
+                log::debug!("unsupported 9p request {}", type_);
+                Err(EOPNOTSUPP)
+            }
+        };
+        match res {
+            Ok(()) => w.finish(),
+            Err(e) => error_response(tag, e),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    /// Opens `components` below the shared directory without following symlinks.
+    fn resolve(&self, components: &[CString]) -> Result<File> {
+        let mut file = self.root.try_clone().map_err(errno)?;
+        for name in components {
+            file = open_path(&file, name)?;
+        }
+        Ok(file)
+    }
+
+    /// Parent directory and name of a fid, for operations that need the directory entry.
+    fn parent(&self, fid: &Fid) -> Result<(File, CString)> {
+        let (name, dir) = fid.components.split_last().ok_or(EBUSY)?;
+        Ok((self.resolve(dir)?, name.clone()))
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        // a new session, all fids of the previous one are gone
+        self.fids.clear();
+        self.msize = msize.min(MAX_MSIZE);
+        w.u32(self.msize);
+        if version == VERSION_9P2000_L.as_bytes() {
+            w.string(VERSION_9P2000_L.as_bytes());
+        } else {
+            w.string(b"unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let n_uname = r.u32()?;
+        if self.fids.contains_key(&fid) {
+            return Err(EBADF);
+        }
+        let path = self.root.try_clone().map_err(errno)?;
+        w.qid(&qid(&stat(&path)?));
+        self.fids.insert(
+            fid,
+            Fid {
+                path,
+                components: vec![],
+                file: None,
+                dir_entries: None,
+                uid: if n_uname == NONUNAME { 0 } else { n_uname },
+            },
+        );
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()? as usize;
+        if nwname > MAXWELEM {
+            return Err(EINVAL);
+        }
+        let mut names = Vec::with_capacity(nwname);
+        for _ in 0..nwname {
+            names.push(r.string()?);
+        }
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(EBADF);
+        }
+        let start = self.fid(fid)?;
+        let uid = start.uid;
+        let mut path = start.path.try_clone().map_err(errno)?;
+        let mut components = start.components.clone();
+        let mut qids = vec![];
+        for name in names {
+            let next = match name {
+                b"." => path.try_clone().map_err(errno),
+                b".." => {
+                    components.pop();
+                    self.resolve(&components)
+                }
+                _ => entry_name(name).and_then(|name| {
+                    let file = open_path(&path, &name)?;
+                    components.push(name);
+                    Ok(file)
+                }),
+            };
+            let st = match next.and_then(|f| Ok((stat(&f)?, f))) {
+                Ok((st, f)) => {
+                    path = f;
+                    st
+                }
+                // the first name must exist, afterwards the guest gets the qids that did
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            qids.push(qid(&st));
+        }
+        if qids.len() == nwname {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    components,
+                    file: None,
+                    dir_entries: None,
+                    uid,
+                },
+            );
+        }
+        w.u16(qids.len() as u16);
+        for q in &qids {
+            w.qid(q);
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let _request_mask = r.u64()?;
+        let st = self.fid(fid)?.stat()?;
+        w.u64(P9_GETATTR_BASIC)
+            .qid(&qid(&st))
+            .u32(st.st_mode)
+            .u32(st.st_uid)
+            .u32(st.st_gid)
+            .u64(st.st_nlink)
+            .u64(st.st_rdev)
+            .u64(st.st_size as u64)
+            .u64(st.st_blksize as u64)
+            .u64(st.st_blocks as u64)
+            .u64(st.st_atime as u64)
+            .u64(st.st_atime_nsec as u64)
+            .u64(st.st_mtime as u64)
+            .u64(st.st_mtime_nsec as u64)
+            .u64(st.st_ctime as u64)
+            .u64(st.st_ctime_nsec as u64)
+            // btime, gen and data_version
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = (r.u64()?, r.u64()?);
+        let mtime = (r.u64()?, r.u64()?);
+        let fid = self.fid(fid)?;
+        let symlink = file_type(fid.stat()?.st_mode) == libc::S_IFLNK;
+        let path = proc_path(&fid.path);
+
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if valid & P9_SETATTR_UID != 0 {
+                uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & P9_SETATTR_GID != 0 {
+                gid
+            } else {
+                u32::MAX
+            };
+            cvt(unsafe {
+                libc::fchownat(
+                    fid.path.as_raw_fd(),
+                    b"\0".as_ptr().cast(),
+                    uid,
+                    gid,
+                    libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        // all other attributes would follow the symlink
+        if symlink {
+            return Ok(());
+        }
+        if valid & P9_SETATTR_MODE != 0 {
+            cvt(unsafe { libc::chmod(path.as_ptr(), permissions(mode)) })?;
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            cvt(unsafe { libc::truncate(path.as_ptr(), size as libc::off_t) })?;
+        }
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let time = |set: bool, explicit: bool, (sec, nsec): (u64, u64)| match (set, explicit) {
+                (false, _) => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+                (true, false) => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_NOW,
+                },
+                (true, true) => libc::timespec {
+                    tv_sec: sec as libc::time_t,
+                    tv_nsec: nsec as libc::c_long,
+                },
+            };
+            let times = [
+                time(
+                    valid & P9_SETATTR_ATIME != 0,
+                    valid & P9_SETATTR_ATIME_SET != 0,
+                    atime,
+                ),
+                time(
+                    valid & P9_SETATTR_MTIME != 0,
+                    valid & P9_SETATTR_MTIME_SET != 0,
+                    mtime,
+                ),
+            ];
+            cvt(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })?;
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let flags = r.u32()? as c_int;
+        let fid = self.fid_mut(fid)?;
+        if fid.file.is_some() {
+            return Err(EBADF);
+        }
+        let st = fid.stat()?;
+        let flags = match file_type(st.st_mode) {
+            libc::S_IFDIR => libc::O_RDONLY | libc::O_DIRECTORY,
+            libc::S_IFREG => flags & (libc::O_ACCMODE | libc::O_APPEND | libc::O_TRUNC),
+            libc::S_IFLNK => return Err(ELOOP),
+            // opening fifos or devices could block the device thread
+            _ => return Err(EOPNOTSUPP),
+        };
+        let path = proc_path(&fid.path);
+        let fd = cvt(unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) })?;
+        fid.file = Some(unsafe { File::from_raw_fd(fd) });
+        w.qid(&qid(&st)).u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let flags = r.u32()? as c_int;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let fid = self.fid_mut(fid)?;
+        if fid.file.is_some() {
+            return Err(EBADF);
+        }
+        let flags = flags & (libc::O_ACCMODE | libc::O_APPEND | libc::O_TRUNC | libc::O_EXCL);
+        let fd = cvt(unsafe {
+            libc::openat(
+                fid.path.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_CREAT | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                permissions(mode) as libc::c_uint,
+            )
+        })?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        chown_entry(&fid.path, &name, fid.uid, gid)?;
+        let path = open_path(&fid.path, &name)?;
+        w.qid(&qid(&stat(&path)?)).u32(0);
+        // the fid now refers to the new file
+        fid.path = path;
+        fid.components.push(name);
+        fid.file = Some(file);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize.saturating_sub(RREAD_HEADER_SIZE));
+        let file = self.fid(fid)?.open_file()?;
+        let mut buf = vec![0u8; count as usize];
+        let n = file.read_at(&mut buf, offset).map_err(errno)?;
+        w.u32(n as u32).bytes(&buf[..n]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?;
+        let data = r.bytes(count as usize)?;
+        let file = self.fid(fid)?.open_file()?;
+        let n = file.write_at(data, offset).map_err(errno)?;
+        w.u32(n as u32);
+        Ok(())
+    }
+
+    fn read_dir_entries(&self, fid: &Fid) -> Result<Vec<DirEntry>> {
+        let dir = fid.open_file()?;
+        let parent = match fid.components.split_last() {
+            Some((_, dir)) => self.resolve(dir)?,
+            None => self.root.try_clone().map_err(errno)?,
+        };
+        let mut entries = vec![];
+        for (name, st) in &[(".", fid.stat()?), ("..", stat(&parent)?)] {
+            entries.push(DirEntry {
+                qid: qid(st),
+                type_: libc::DT_DIR as u8,
+                name: name.as_bytes().to_vec(),
+            });
+        }
+        let path = OsString::from_vec(proc_path(dir).into_bytes());
+        for entry in fs::read_dir(path).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            let type_ = entry.file_type().map(dirent_type).map_err(errno)?;
+            entries.push(DirEntry {
+                qid: Qid {
+                    type_: match type_ {
+                        t if t == libc::DT_DIR as u8 => QTDIR,
+                        t if t == libc::DT_LNK as u8 => QTSYMLINK,
+                        _ => QTFILE,
+                    },
+                    version: 0,
+                    path: entry.ino(),
+                },
+                type_,
+                name: entry.file_name().into_vec(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid_num = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize.saturating_sub(RREAD_HEADER_SIZE));
+        let fid = self.fid(fid_num)?;
+        // a readdir from the start sees new entries
+        let entries = if offset == 0 || fid.dir_entries.is_none() {
+            Some(self.read_dir_entries(fid)?)
+        } else {
+            None
+        };
+        let fid = self.fid_mut(fid_num)?;
+        if entries.is_some() {
+            fid.dir_entries = entries;
+        }
+        let data = pack_dir_entries(fid.dir_entries.as_deref().unwrap_or(&[]), offset, count);
+        w.u32(data.len() as u32).bytes(&data);
+        Ok(())
+    }
+
+    fn clunk(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        self.fids.remove(&fid).ok_or(EBADF)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        // the fid is clunked even if the file cannot be removed
+        let fid = self.fids.remove(&fid).ok_or(EBADF)?;
+        let (dir, name) = self.parent(&fid)?;
+        let flags = if file_type(fid.stat()?.st_mode) == libc::S_IFDIR {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let dfid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let dir = self.fid(dfid)?;
+        cvt(unsafe { libc::mkdirat(dir.path.as_raw_fd(), name.as_ptr(), permissions(mode)) })?;
+        chown_entry(&dir.path, &name, dir.uid, gid)?;
+        w.qid(&qid(&stat(&open_path(&dir.path, &name)?)?));
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let dfid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let target = CString::new(r.string()?).map_err(|_| EINVAL)?;
+        let gid = r.u32()?;
+        let dir = self.fid(dfid)?;
+        cvt(unsafe { libc::symlinkat(target.as_ptr(), dir.path.as_raw_fd(), name.as_ptr()) })?;
+        chown_entry(&dir.path, &name, dir.uid, gid)?;
+        w.qid(&qid(&stat(&open_path(&dir.path, &name)?)?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let dfid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let mode = r.u32()?;
+        let _major = r.u32()?;
+        let _minor = r.u32()?;
+        let gid = r.u32()?;
+        // device nodes would give the guest access to host devices
+        let type_ = file_type(mode as libc::mode_t);
+        if ![libc::S_IFIFO, libc::S_IFSOCK, libc::S_IFREG].contains(&type_) {
+            return Err(EPERM);
+        }
+        let dir = self.fid(dfid)?;
+        cvt(unsafe {
+            libc::mknodat(
+                dir.path.as_raw_fd(),
+                name.as_ptr(),
+                type_ | permissions(mode),
+                0,
+            )
+        })?;
+        chown_entry(&dir.path, &name, dir.uid, gid)?;
+        w.qid(&qid(&stat(&open_path(&dir.path, &name)?)?));
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let fid = self.fid(fid)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let n = unsafe {
+            libc::readlinkat(
+                fid.path.as_raw_fd(),
+                b"\0".as_ptr().cast(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if n < 0 {
+            return Err(last_errno());
+        }
+        w.string(&buf[..n as usize]);
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> Result<()> {
+        let dfid = r.u32()?;
+        let fid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let dir = self.fid(dfid)?;
+        let target = self.fid(fid)?;
+        if file_type(target.stat()?.st_mode) == libc::S_IFDIR {
+            return Err(EPERM);
+        }
+        cvt(unsafe {
+            libc::linkat(
+                target.path.as_raw_fd(),
+                b"\0".as_ptr().cast(),
+                dir.path.as_raw_fd(),
+                name.as_ptr(),
+                libc::AT_EMPTY_PATH,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> Result<()> {
+        let fid_num = r.u32()?;
+        let dfid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let fid = self.fid(fid_num)?;
+        let (old_dir, old_name) = self.parent(fid)?;
+        let dir = self.fid(dfid)?;
+        cvt(unsafe {
+            libc::renameat(
+                old_dir.as_raw_fd(),
+                old_name.as_ptr(),
+                dir.path.as_raw_fd(),
+                name.as_ptr(),
+            )
+        })?;
+        let mut components = dir.components.clone();
+        components.push(name);
+        self.fid_mut(fid_num)?.components = components;
+        Ok(())
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> Result<()> {
+        let old_dfid = r.u32()?;
+        let old_name = entry_name(r.string()?)?;
+        let new_dfid = r.u32()?;
+        let new_name = entry_name(r.string()?)?;
+        let old_dir = self.fid(old_dfid)?;
+        let new_dir = self.fid(new_dfid)?;
+        cvt(unsafe {
+            libc::renameat(
+                old_dir.path.as_raw_fd(),
+                old_name.as_ptr(),
+                new_dir.path.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> Result<()> {
+        let dfid = r.u32()?;
+        let name = entry_name(r.string()?)?;
+        let flags = r.u32()? as c_int & libc::AT_REMOVEDIR;
+        let dir = self.fid(dfid)?;
+        cvt(unsafe { libc::unlinkat(dir.path.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        let datasync = r.u32()?;
+        let file = self.fid(fid)?.open_file()?;
+        if datasync != 0 {
+            file.sync_data().map_err(errno)
+        } else {
+            file.sync_all().map_err(errno)
+        }
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let fid = self.fid(fid)?;
+        let mut st = MaybeUninit::<libc::statfs>::uninit();
+        cvt(unsafe { libc::fstatfs(fid.path.as_raw_fd(), st.as_mut_ptr()) })?;
+        let st = unsafe { st.assume_init() };
+        w.u32(st.f_type as u32)
+            .u32(st.f_bsize as u32)
+            .u64(st.f_blocks)
+            .u64(st.f_bfree)
+            .u64(st.f_bavail)
+            .u64(st.f_files)
+            .u64(st.f_ffree)
+            .u64(0)
+            .u32(st.f_namelen as u32);
+        Ok(())
+    }
+
+    /// Locks only need to be consistent within the guest, which the guest kernel already does.
+    fn lock(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        self.fid(fid)?;
+        w.u8(P9_LOCK_SUCCESS);
+        Ok(())
+    }
+
+    fn getlock(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let _type = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.string()?;
+        self.fid(fid)?;
+        w.u8(libc::F_UNLCK as u8)
+            .u64(start)
+            .u64(length)
+            .u32(proc_id)
+            .string(client_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_name() {
+        assert!(entry_name(b"file").is_ok());
+        assert_eq!(entry_name(b""), Err(EINVAL));
+        assert_eq!(entry_name(b".."), Err(EINVAL));
+        assert_eq!(entry_name(b"a/b"), Err(EINVAL));
+        assert_eq!(entry_name(b"a\0b"), Err(EINVAL));
+        assert_eq!(permissions(0o104755), 0o755);
+    }
+
+    #[test]
+    fn test_pack_dir_entries() {
+        let entries = ["."; 3]
+            .iter()
+            .enumerate()
+            .map(|(i, _)| DirEntry {
+                qid: Qid::default(),
+                type_: libc::DT_REG as u8,
+                name: format!("file{}", i).into_bytes(),
+            })
+            .collect::<Vec<_>>();
+        // every entry takes 29 bytes
+        let data = pack_dir_entries(&entries, 0, 60);
+        assert_eq!(data.len(), 58);
+        assert_eq!(&data[QID_SIZE..QID_SIZE + 8], &1u64.to_le_bytes());
+        let data = pack_dir_entries(&entries, 2, 60);
+        assert_eq!(data.len(), 29);
+        assert_eq!(&data[QID_SIZE..QID_SIZE + 8], &3u64.to_le_bytes());
+        assert!(pack_dir_entries(&entries, 3, 60).is_empty());
+    }
+}
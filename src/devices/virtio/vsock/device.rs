@@ -0,0 +1,288 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::borrow::{Borrow, BorrowMut};
+use std::fs;
+use std::ops::DerefMut;
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use virtio_device::{VirtioDevice, VirtioDeviceType};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
+use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::use_ioregionfd;
+use crate::devices::virtio::features::{VIRTIO_F_IN_ORDER, VIRTIO_F_VERSION_1};
+use crate::devices::virtio::vsock::muxer::VsockMuxer;
+use crate::devices::virtio::{
+    access, IrqAckHandler, MmioConfig, NotifyBatch, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
+use crate::devices::MaybeIoRegionFd;
+use crate::gc::{self, Artifact};
+use crate::kvm::hypervisor::{
+    ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd, Hypervisor,
+};
+
+use super::{build_config_space, Error, Result, VsockArgs, VsockOptions, VSOCK_DEVICE_ID};
+use simple_error::map_err_with;
+
+pub struct Vsock<M: GuestAddressSpace> {
+    virtio_cfg: VirtioConfig<M>,
+    pub mmio_cfg: MmioConfig,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    vmm: Arc<Hypervisor>,
+    irqfd: Arc<EventFd>,
+    pub ioregionfd: Option<IoRegionFd>,
+    pub uioefd: UserspaceIoEventFd,
+    opts: VsockOptions,
+    /// Bound when the device is created, so that vmsh fails early if the path is taken.
+    listener: UnixListener,
+    /// only used when ioregionfd != None
+    sub_id: Option<SubscriberId>,
+
+    // Before resetting we return the handler to the mmio thread for cleanup
+    #[allow(dead_code)]
+    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+}
+
+impl<M> Vsock<M>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+{
+    pub fn new<B>(mut args: VsockArgs<M, B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        // The muxer uses the buffers in order. Event idx is not supported, so the driver
+        // notifies us about every buffer.
+        let device_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IN_ORDER;
+
+        // A vsock device has a rx, tx and event queue.
+        let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 3];
+
+        let config_space = build_config_space(args.opts.guest_cid);
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        // Used to send notifications to the driver.
+        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
+        let irqfd = Arc::new(
+            args.common
+                .vmm
+                .irqfd(args.common.mmio_cfg.gsi)
+                .map_err(Error::Simple)?,
+        );
+
+        let mmio_cfg = args.common.mmio_cfg;
+
+        let listener = map_err_with!(
+            UnixListener::bind(&args.opts.uds_path),
+            "cannot listen on {}",
+            args.opts.uds_path.display()
+        )
+        .map_err(Error::Simple)?;
+        gc::record(&Artifact::Socket(args.opts.uds_path.clone()));
+        map_err_with!(
+            listener.set_nonblocking(true),
+            "cannot make vsock listener non-blocking"
+        )
+        .map_err(Error::Simple)?;
+
+        let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
+            virtio_cfg.interrupt_status.clone(),
+            Arc::clone(&irqfd),
+        )));
+
+        let mut ioregionfd = None;
+        if use_ioregionfd() {
+            ioregionfd = Some(
+                args.common
+                    .vmm
+                    .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
+                    .map_err(Error::Simple)?,
+            );
+        }
+
+        let vsock = Arc::new(Mutex::new(Vsock {
+            virtio_cfg,
+            mmio_cfg,
+            endpoint: args.common.event_mgr.remote_endpoint(),
+            irq_ack_handler,
+            vmm: args.common.vmm.clone(),
+            irqfd,
+            ioregionfd,
+            uioefd: UserspaceIoEventFd::default(),
+            opts: args.opts,
+            listener,
+            sub_id: None,
+            handler: None,
+        }));
+
+        // Register the device on the MMIO bus.
+        args.common
+            .mmio_mgr
+            .register_mmio(mmio_cfg.range, vsock.clone())
+            .map_err(Error::Bus)?;
+
+        Ok(vsock)
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+            ack_handler: self.irq_ack_handler.clone(),
+            batch: NotifyBatch::default(),
+        };
+
+        // The event queue is only needed to tell the driver about a transport reset, which
+        // never happens, so we don't listen for its notifications.
+        let rx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 0)
+            .map_err(Error::Simple)?;
+        let tx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, 1)
+            .map_err(Error::Simple)?;
+
+        let muxer = VsockMuxer::new(
+            &self.opts,
+            &self.listener,
+            driver_notify,
+            rx_fd,
+            tx_fd,
+            self.virtio_cfg.queues[0].clone(),
+            self.virtio_cfg.queues[1].clone(),
+        )
+        .map_err(Error::Simple)?;
+        let handler = Arc::new(Mutex::new(muxer));
+
+        // Register the queue handler with the `EventManager`. We record the `sub_id`
+        // (and/or keep a handler clone) to remove the subscriber when resetting the device
+        let sub_id = self
+            .endpoint
+            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                Ok(mgr.add_subscriber(handler))
+            })
+            .map_err(|e| {
+                log::warn!("{}", e);
+                Error::Endpoint(e)
+            })?;
+        self.sub_id = Some(sub_id);
+
+        log::debug!("activating device: ok");
+        self.virtio_cfg.device_activated = true;
+
+        Ok(())
+    }
+
+    fn _reset(&mut self) -> Result<()> {
+        // we remove the handler here, since we need to free up the ioeventfd resources
+        // in the mmio thread rather the eventmanager thread.
+        if let Some(sub_id) = self.sub_id.take() {
+            let handler = self
+                .endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.handler = Some(handler);
+        }
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace> Drop for Vsock<M> {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.opts.uds_path) {
+            log::warn!(
+                "cannot remove vsock socket {}: {}",
+                self.opts.uds_path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for Vsock<M> {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+// We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
+// for `VirtioDevice`.
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Vsock<M> {
+    fn device_type(&self) -> u32 {
+        VSOCK_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Vsock<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Vsock<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Vsock<M> {
+    type E = Error;
+
+    /// make sure to set self.vmm.wrapper to Some() before activating. Typically this is done by
+    /// activating during vmm.kvmrun_wrapped()
+    fn activate(&mut self) -> Result<()> {
+        let ret = self._activate();
+        if let Err(ref e) = ret {
+            log::warn!("failed to activate vsock device: {:?}", e);
+        }
+        ret
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.set_device_status(0);
+        self._reset()?;
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for Vsock<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if use_ioregionfd() {
+            self.uioefd.queue_notify(val);
+            log::trace!("queue_notify {}", val);
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for Vsock<M> {}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Vsock<M> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        access::read::<M, _>(self, offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        access::write::<M, _>(self, offset, data);
+    }
+}
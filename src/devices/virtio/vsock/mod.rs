@@ -0,0 +1,193 @@
+//! virtio-vsock device to open stream sockets into the guest.
+//!
+//! Since the device lives in vmsh and not in the host kernel, host tools cannot use AF_VSOCK
+//! directly. Instead vmsh listens on a unix socket, similar to firecracker: a client connects to
+//! it and sends `CONNECT <port>\n`. Once the guest accepted the connection, vmsh answers with
+//! `OK <host port>\n` and forwards the stream in both directions. Connections that the guest
+//! opens to CID 2 (the host) on port `<port>` are forwarded to the unix socket
+//! `<path>_<port>`, if it exists.
+//!
+//! The guest needs a kernel with CONFIG_VIRTIO_VSOCKETS and must not have a vsock device
+//! already, since linux only supports one guest-to-host transport.
+
+mod device;
+mod muxer;
+pub mod publish;
+
+use std::io;
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use simple_error::SimpleError;
+use vm_device::bus;
+use vmm_sys_util::errno;
+
+use crate::devices::virtio::CommonArgs;
+
+pub use device::Vsock;
+
+/// Vsock device ID as defined by the standard.
+pub const VSOCK_DEVICE_ID: u32 = 19;
+
+/// CID of the host
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// The only socket type of virtio-vsock before seqpacket support was added.
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Operations of a packet
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Flags of VIRTIO_VSOCK_OP_SHUTDOWN
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+/// Size of `struct virtio_vsock_hdr`
+const HEADER_SIZE: usize = 44;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    EventFd(io::Error),
+    #[allow(dead_code)] // FIXME
+    QueuesNotValid,
+    #[allow(dead_code)] // FIXME
+    RegisterIoevent(errno::Error),
+    #[allow(dead_code)] // FIXME
+    RegisterIrqfd(errno::Error),
+    Simple(SimpleError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// `struct virtio_vsock_hdr`, all fields are little endian in guest memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    /// receive buffer of the sender
+    buf_alloc: u32,
+    /// bytes the sender has consumed from its receive buffer
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn from_bytes(buf: &[u8]) -> Option<PacketHeader> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let u16_at = |o: usize| u16::from_le_bytes([buf[o], buf[o + 1]]);
+        let u32_at = |o: usize| u32::from_le_bytes([buf[o], buf[o + 1], buf[o + 2], buf[o + 3]]);
+        let u64_at = |o: usize| u64::from(u32_at(o)) | u64::from(u32_at(o + 4)) << 32;
+        Some(PacketHeader {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+}
+
+/// Parses the `CONNECT <port>` line a client sends after connecting to the unix socket.
+fn parse_connect(line: &str) -> Option<u32> {
+    let mut words = line.split_whitespace();
+    if words.next()? != "CONNECT" {
+        return None;
+    }
+    let port = words.next()?.parse().ok()?;
+    if words.next().is_some() {
+        return None;
+    }
+    Some(port)
+}
+
+fn build_config_space(guest_cid: u64) -> Vec<u8> {
+    // struct virtio_vsock_config { le64 guest_cid; }
+    guest_cid.to_le_bytes().to_vec()
+}
+
+/// Options of `vmsh attach --vsock`
+#[derive(Clone)]
+pub struct VsockOptions {
+    /// unix socket that forwards connections into the guest
+    pub uds_path: PathBuf,
+    pub guest_cid: u64,
+}
+
+// Arguments required when building a vsock device.
+pub struct VsockArgs<'a, M, B> {
+    pub common: CommonArgs<'a, M, B>,
+    pub opts: VsockOptions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_header() {
+        let hdr = PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: 0x1_0000_0003,
+            src_port: 1 << 30,
+            dst_port: 22,
+            len: 4096,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RW,
+            flags: VIRTIO_VSOCK_SHUTDOWN_SEND,
+            buf_alloc: 256 * 1024,
+            fwd_cnt: 42,
+        };
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[8..16], &[3, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&bytes[30..32], &[5, 0]);
+        assert_eq!(PacketHeader::from_bytes(&bytes), Some(hdr));
+        assert_eq!(PacketHeader::from_bytes(&bytes[..HEADER_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(parse_connect("CONNECT 1234\n"), Some(1234));
+        assert_eq!(parse_connect("CONNECT 52\r\n"), Some(52));
+        assert_eq!(parse_connect("CONNECT\n"), None);
+        assert_eq!(parse_connect("CONNECT -1\n"), None);
+        assert_eq!(parse_connect("CONNECT 1 2\n"), None);
+        assert_eq!(parse_connect("LISTEN 1\n"), None);
+    }
+}
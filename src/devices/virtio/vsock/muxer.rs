@@ -0,0 +1,654 @@
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::result;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use simple_error::try_with;
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{self, Bytes, GuestAddressSpace};
+
+use super::{
+    parse_connect, PacketHeader, VsockOptions, HEADER_SIZE, VIRTIO_VSOCK_OP_CREDIT_REQUEST,
+    VIRTIO_VSOCK_OP_CREDIT_UPDATE, VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RESPONSE,
+    VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_RCV,
+    VIRTIO_VSOCK_SHUTDOWN_SEND, VIRTIO_VSOCK_TYPE_STREAM, VSOCK_HOST_CID,
+};
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+use crate::result::Result;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+const RX_IOEVENT_DATA: u32 = 0;
+const TX_IOEVENT_DATA: u32 = 1;
+const LISTENER_DATA: u32 = 2;
+const HANDSHAKE_DATA: u32 = 3;
+const STREAM_DATA: u32 = 4;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Receive buffer we advertise to the guest for each connection
+const BUF_ALLOC: u32 = 256 * 1024;
+/// Linux fills the rx queue with buffers of 4 KiB for the payload.
+const MAX_PAYLOAD: usize = 4096;
+/// Largest packet we accept from the guest, linux sends at most 64 KiB of payload.
+const MAX_TX_PACKET: usize = HEADER_SIZE + 64 * 1024;
+/// Connections opened through the unix socket get host ports starting from here.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+/// We stop reading from the host streams while this many packets wait for rx buffers.
+const MAX_RX_PENDING: usize = 256;
+/// Longest `CONNECT <port>\n` line we accept
+const MAX_HANDSHAKE: usize = 32;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+struct ConnKey {
+    host_port: u32,
+    guest_port: u32,
+}
+
+/// A stream connection between a unix socket on the host and a vsock socket in the guest
+struct Connection {
+    stream: UnixStream,
+    /// the guest has not accepted the connection yet
+    connecting: bool,
+    /// events we are registered for on `stream`. Streams without interest are not registered at
+    /// all, since epoll reports hang ups regardless.
+    interest: EventSet,
+    /// the host closed `stream`
+    host_closed: bool,
+    /// `VIRTIO_VSOCK_SHUTDOWN_*` flags received from the guest
+    guest_shutdown: u32,
+    /// receive buffer of the guest
+    peer_buf_alloc: u32,
+    /// bytes the guest has consumed
+    peer_fwd_cnt: u32,
+    /// bytes sent to the guest
+    tx_cnt: u32,
+    /// bytes of the guest written to `stream`
+    fwd_cnt: u32,
+    /// `fwd_cnt` that the guest knows about
+    last_fwd_cnt: u32,
+    /// data of the guest that `stream` did not take yet
+    to_host: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, connecting: bool) -> Self {
+        Connection {
+            stream,
+            connecting,
+            interest: EventSet::empty(),
+            host_closed: false,
+            guest_shutdown: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            to_host: vec![],
+        }
+    }
+
+    /// Bytes we can send before the receive buffer of the guest is full
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn wants_input(&self) -> bool {
+        !self.connecting
+            && !self.host_closed
+            && self.guest_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0
+            && self.peer_credit() > 0
+    }
+
+    /// Writes as much of `to_host` to the stream as it takes without blocking.
+    fn flush(&mut self) -> std::io::Result<()> {
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.to_host.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.to_host.is_empty() && self.guest_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+            // the guest will not send any more data
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+/// Multiplexes the stream connections of the unix sockets on the host onto the rx and tx queue
+/// of the vsock device.
+pub(crate) struct VsockMuxer<M: GuestAddressSpace, S: SignalUsedQueue> {
+    /// ioevent fd to indicate new buffers added to the rx queue
+    rx_fd: IoEvent,
+    /// ioevent fd to indicate new packets added to the tx queue
+    tx_fd: IoEvent,
+    /// Notify driver about used buffers
+    driver_notify: S,
+    /// rx queue for sending packets to the guest
+    rxq: Queue<M>,
+    /// tx queue for receiving packets from the guest
+    txq: Queue<M>,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    /// clients of `listener` that have not sent `CONNECT <port>` yet
+    handshakes: HashMap<RawFd, (UnixStream, Vec<u8>)>,
+    connections: HashMap<ConnKey, Connection>,
+    /// connection of each stream fd
+    fds: HashMap<RawFd, ConnKey>,
+    /// packets waiting for buffers in the rx queue
+    rx_packets: VecDeque<(PacketHeader, Vec<u8>)>,
+    next_host_port: u32,
+}
+
+impl<M, S> VsockMuxer<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    pub fn new(
+        opts: &VsockOptions,
+        listener: &UnixListener,
+        driver_notify: S,
+        rx_fd: IoEvent,
+        tx_fd: IoEvent,
+        rxq: Queue<M>,
+        txq: Queue<M>,
+    ) -> Result<Self> {
+        let listener = try_with!(listener.try_clone(), "cannot clone vsock listener");
+        Ok(VsockMuxer {
+            rx_fd,
+            tx_fd,
+            driver_notify,
+            rxq,
+            txq,
+            guest_cid: opts.guest_cid,
+            uds_path: opts.uds_path.clone(),
+            listener,
+            handshakes: HashMap::new(),
+            connections: HashMap::new(),
+            fds: HashMap::new(),
+            rx_packets: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+        })
+    }
+
+    fn handle_error<Msg: AsRef<str>>(&self, s: Msg, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(&self.tx_fd))
+            .expect("Failed to remove tx ioevent");
+        ops.remove(Events::empty(&self.rx_fd))
+            .expect("Failed to remove rx ioevent");
+    }
+
+    /// Queues a packet for the guest.
+    fn send(&mut self, key: ConnKey, op: u16, flags: u32, data: Vec<u8>) {
+        let mut hdr = PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.host_port,
+            dst_port: key.guest_port,
+            len: data.len() as u32,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: 0,
+        };
+        if let Some(conn) = self.connections.get_mut(&key) {
+            hdr.fwd_cnt = conn.fwd_cnt;
+            conn.last_fwd_cnt = conn.fwd_cnt;
+            if op == VIRTIO_VSOCK_OP_RW {
+                conn.tx_cnt = conn.tx_cnt.wrapping_add(data.len() as u32);
+            }
+        }
+        self.rx_packets.push_back((hdr, data));
+    }
+
+    fn close(&mut self, key: ConnKey, ops: &mut EventOps) {
+        if let Some(conn) = self.connections.remove(&key) {
+            debug!("close vsock connection {:?}", key);
+            self.fds.remove(&conn.stream.as_raw_fd());
+            if !conn.interest.is_empty() {
+                let _ = ops.remove(Events::empty(&conn.stream));
+            }
+        }
+    }
+
+    fn reset(&mut self, key: ConnKey, ops: &mut EventOps) {
+        self.close(key, ops);
+        self.send(key, VIRTIO_VSOCK_OP_RST, 0, vec![]);
+    }
+
+    /// Registers the stream of a connection for the events it currently can handle.
+    fn update_interest(&mut self, key: ConnKey, ops: &mut EventOps) {
+        let rx_full = self.rx_packets.len() >= MAX_RX_PENDING;
+        let conn = match self.connections.get_mut(&key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut interest = EventSet::empty();
+        if conn.wants_input() && !rx_full {
+            interest |= EventSet::IN;
+        }
+        if !conn.to_host.is_empty() {
+            interest |= EventSet::OUT;
+        }
+        if interest == conn.interest {
+            return;
+        }
+        let events = Events::with_data(&conn.stream, STREAM_DATA, interest);
+        let res = if conn.interest.is_empty() {
+            ops.add(events)
+        } else if interest.is_empty() {
+            ops.remove(events)
+        } else {
+            ops.modify(events)
+        };
+        if let Err(e) = res {
+            error!("cannot update events of vsock connection: {}", e);
+            return;
+        }
+        conn.interest = interest;
+    }
+
+    fn update_all_interests(&mut self, ops: &mut EventOps) {
+        let keys = self.connections.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            self.update_interest(key, ops);
+        }
+    }
+
+    fn accept(&mut self, ops: &mut EventOps) {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                error!("cannot accept vsock client: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("cannot make vsock client non-blocking: {}", e);
+            return;
+        }
+        if let Err(e) = ops.add(Events::with_data(&stream, HANDSHAKE_DATA, EventSet::IN)) {
+            error!("cannot register vsock client: {}", e);
+            return;
+        }
+        self.handshakes.insert(stream.as_raw_fd(), (stream, vec![]));
+    }
+
+    /// Reads the `CONNECT <port>` line of a new client and asks the guest for a connection.
+    fn handshake(&mut self, fd: RawFd, ops: &mut EventOps) {
+        let (stream, line) = match self.handshakes.get_mut(&fd) {
+            Some(v) => v,
+            None => return,
+        };
+        // byte by byte, so that we don't consume data the client sends after the line
+        let mut byte = [0u8; 1];
+        let done = loop {
+            match stream.read(&mut byte) {
+                Ok(0) => break Err(()),
+                Ok(_) if byte[0] == b'\n' => break Ok(true),
+                Ok(_) if line.len() >= MAX_HANDSHAKE => break Err(()),
+                Ok(_) => line.push(byte[0]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break Err(()),
+            }
+        };
+        let port = match done {
+            Ok(false) => return,
+            Ok(true) => parse_connect(&String::from_utf8_lossy(line)),
+            Err(()) => None,
+        };
+        let (stream, line) = match self.handshakes.remove(&fd) {
+            Some(v) => v,
+            None => return,
+        };
+        let guest_port = match port {
+            Some(port) => port,
+            None => {
+                warn!(
+                    "invalid vsock handshake: {:?}",
+                    String::from_utf8_lossy(&line)
+                );
+                let _ = ops.remove(Events::empty(&stream));
+                return;
+            }
+        };
+        let key = ConnKey {
+            host_port: self.next_host_port,
+            guest_port,
+        };
+        self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+        // wait for the guest before we read from the stream
+        let _ = ops.remove(Events::empty(&stream));
+        debug!("connect to guest port {}", guest_port);
+        self.fds.insert(fd, key);
+        self.connections.insert(key, Connection::new(stream, true));
+        self.send(key, VIRTIO_VSOCK_OP_REQUEST, 0, vec![]);
+    }
+
+    /// Forwards data of the host to the guest or flushes pending data of the guest.
+    fn handle_stream(&mut self, fd: RawFd, event_set: EventSet, ops: &mut EventOps) {
+        let key = match self.fds.get(&fd) {
+            Some(key) => *key,
+            None => return,
+        };
+        let conn = match self.connections.get_mut(&key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if event_set.contains(EventSet::OUT) {
+            if let Err(e) = conn.flush() {
+                debug!("cannot write to vsock client: {}", e);
+                self.reset(key, ops);
+                return;
+            }
+        }
+        if event_set.intersects(EventSet::IN | EventSet::HANG_UP) && conn.wants_input() {
+            let mut buf = vec![0u8; min(conn.peer_credit() as usize, MAX_PAYLOAD)];
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    conn.host_closed = true;
+                    let flags = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                    self.send(key, VIRTIO_VSOCK_OP_SHUTDOWN, flags, vec![]);
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    self.send(key, VIRTIO_VSOCK_OP_RW, 0, buf);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("cannot read from vsock client: {}", e);
+                    self.reset(key, ops);
+                }
+            }
+        }
+    }
+
+    /// Handles a request of the guest to connect to a port of the host.
+    fn connect_to_host(&mut self, key: ConnKey, hdr: &PacketHeader) {
+        let mut path = self.uds_path.clone().into_os_string();
+        path.push(format!("_{}", key.host_port));
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("guest cannot connect to {:?}: {}", path, e);
+                self.send(key, VIRTIO_VSOCK_OP_RST, 0, vec![]);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("cannot make vsock connection non-blocking: {}", e);
+            self.send(key, VIRTIO_VSOCK_OP_RST, 0, vec![]);
+            return;
+        }
+        let mut conn = Connection::new(stream, false);
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        self.fds.insert(conn.stream.as_raw_fd(), key);
+        self.connections.insert(key, conn);
+        self.send(key, VIRTIO_VSOCK_OP_RESPONSE, 0, vec![]);
+    }
+
+    fn handle_packet(&mut self, hdr: PacketHeader, data: &[u8], ops: &mut EventOps) {
+        if hdr.src_cid != self.guest_cid || hdr.dst_cid != VSOCK_HOST_CID {
+            warn!(
+                "drop vsock packet from cid {} to cid {}",
+                hdr.src_cid, hdr.dst_cid
+            );
+            return;
+        }
+        let key = ConnKey {
+            host_port: hdr.dst_port,
+            guest_port: hdr.src_port,
+        };
+        if hdr.type_ != VIRTIO_VSOCK_TYPE_STREAM {
+            self.send(key, VIRTIO_VSOCK_OP_RST, 0, vec![]);
+            return;
+        }
+        let conn = match self.connections.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                match hdr.op {
+                    VIRTIO_VSOCK_OP_REQUEST => self.connect_to_host(key, &hdr),
+                    VIRTIO_VSOCK_OP_RST => {}
+                    _ => self.send(key, VIRTIO_VSOCK_OP_RST, 0, vec![]),
+                }
+                return;
+            }
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        match hdr.op {
+            VIRTIO_VSOCK_OP_RESPONSE if conn.connecting => {
+                conn.connecting = false;
+                let reply = format!("OK {}\n", key.host_port);
+                if let Err(e) = conn.stream.write_all(reply.as_bytes()) {
+                    debug!("cannot write to vsock client: {}", e);
+                    self.reset(key, ops);
+                }
+            }
+            VIRTIO_VSOCK_OP_RST => self.close(key, ops),
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.guest_shutdown |= hdr.flags;
+                let both = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                if conn.guest_shutdown & both == both || conn.host_closed {
+                    self.reset(key, ops);
+                } else if let Err(e) = conn.flush() {
+                    debug!("cannot write to vsock client: {}", e);
+                    self.reset(key, ops);
+                }
+            }
+            VIRTIO_VSOCK_OP_RW if !conn.connecting => {
+                if conn.to_host.len() + data.len() > BUF_ALLOC as usize {
+                    warn!("guest exceeded the credit of vsock connection {:?}", key);
+                    self.reset(key, ops);
+                    return;
+                }
+                conn.to_host.extend_from_slice(data);
+                if let Err(e) = conn.flush() {
+                    debug!("cannot write to vsock client: {}", e);
+                    self.reset(key, ops);
+                    return;
+                }
+                if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt) >= BUF_ALLOC / 2 {
+                    self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, vec![]);
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, vec![]);
+            }
+            op => {
+                warn!("unexpected vsock operation {} on {:?}", op, key);
+                self.reset(key, ops);
+            }
+        }
+    }
+
+    fn process_tx_chain(
+        &mut self,
+        mut chain: DescriptorChain<M>,
+        ops: &mut EventOps,
+    ) -> result::Result<(), Error> {
+        let mut buf = vec![];
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                continue;
+            }
+            let len = min(desc.len() as usize, MAX_TX_PACKET - buf.len());
+            let start = buf.len();
+            buf.resize(start + len, 0);
+            chain.memory().read_slice(&mut buf[start..], desc.addr())?;
+        }
+        self.txq.add_used(chain.head_index(), 0)?;
+
+        match PacketHeader::from_bytes(&buf) {
+            Some(hdr) => {
+                let end = min(HEADER_SIZE + hdr.len as usize, buf.len());
+                self.handle_packet(hdr, &buf[HEADER_SIZE..end], ops);
+            }
+            None => warn!("drop short vsock packet of {} bytes", buf.len()),
+        }
+        Ok(())
+    }
+
+    fn process_txq(&mut self, ops: &mut EventOps) -> result::Result<(), Error> {
+        let mut used = false;
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.txq.disable_notification()?;
+
+            while let Some(chain) = self.txq.iter()?.next() {
+                self.process_tx_chain(chain, ops)?;
+                used = true;
+            }
+
+            if !self.txq.enable_notification()? {
+                break;
+            }
+        }
+        if used && self.txq.needs_notification()? {
+            self.driver_notify.signal_used_queue(TX_QUEUE);
+        }
+        Ok(())
+    }
+
+    /// Moves pending packets into the buffers the guest has made available.
+    fn process_rxq(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+        while !self.rx_packets.is_empty() {
+            let mut chain = match self.rxq.iter()?.next() {
+                Some(chain) => chain,
+                None => break,
+            };
+            let (hdr, data) = match self.rx_packets.pop_front() {
+                Some(packet) => packet,
+                None => break,
+            };
+            let mut packet = hdr.to_bytes().to_vec();
+            packet.extend_from_slice(&data);
+            let mut written = 0;
+            while let Some(desc) = chain.next() {
+                if !desc.is_write_only() {
+                    continue;
+                }
+                let n = min(desc.len() as usize, packet.len() - written);
+                if n == 0 {
+                    break;
+                }
+                chain
+                    .memory()
+                    .write_slice(&packet[written..written + n], desc.addr())?;
+                written += n;
+            }
+            if written < packet.len() {
+                error!(
+                    "vsock rx buffer of the guest is too small for {} bytes",
+                    packet.len()
+                );
+            }
+            self.rxq.add_used(chain.head_index(), written as u32)?;
+            used = true;
+        }
+        if used && self.rxq.needs_notification()? {
+            self.driver_notify.signal_used_queue(RX_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for VsockMuxer<M, S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        match events.data() {
+            RX_IOEVENT_DATA => {
+                if events.event_set() != EventSet::IN || self.rx_fd.read().is_err() {
+                    self.handle_error("Rx ioevent read", ops);
+                    return;
+                }
+            }
+            TX_IOEVENT_DATA => {
+                if events.event_set() != EventSet::IN || self.tx_fd.read().is_err() {
+                    self.handle_error("Tx ioevent read", ops);
+                    return;
+                }
+                if let Err(e) = self.process_txq(ops) {
+                    self.handle_error(format!("Process tx error {:?}", e), ops);
+                    return;
+                }
+            }
+            LISTENER_DATA => self.accept(ops),
+            HANDSHAKE_DATA => self.handshake(events.fd(), ops),
+            STREAM_DATA => self.handle_stream(events.fd(), events.event_set(), ops),
+            _ => {
+                self.handle_error("Unexpected data", ops);
+                return;
+            }
+        }
+        if let Err(e) = self.process_rxq() {
+            self.handle_error(format!("Process rx error {:?}", e), ops);
+            return;
+        }
+        self.update_all_interests(ops);
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            &self.rx_fd,
+            RX_IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to register rx ioeventfd for vsock queue handler");
+        ops.add(Events::with_data(
+            &self.tx_fd,
+            TX_IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to register tx ioeventfd for vsock queue handler");
+        ops.add(Events::with_data(
+            &self.listener,
+            LISTENER_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to register vsock listener");
+    }
+}
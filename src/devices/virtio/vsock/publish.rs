@@ -0,0 +1,209 @@
+//! Host side of `vmsh attach --publish`: tcp connections to a host port are forwarded through
+//! the unix socket of the vsock device to a vsock port in the guest. Stage2 listens on that
+//! vsock port and connects to the same tcp port on localhost in the network namespace of the
+//! container, so the guest network is not touched.
+use log::{debug, info, warn};
+use simple_error::{bail, try_with};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::interrutable_thread::InterrutableThread;
+use crate::result::Result;
+
+/// How often the listeners are checked for connections when none arrived
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest `OK <port>\n` reply of the vsock device
+const MAX_REPLY: usize = 32;
+
+/// A port forwarded with `--publish`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Publish {
+    pub host: SocketAddr,
+    /// Tcp port in the guest, which is also the vsock port stage2 listens on
+    pub guest_port: u16,
+}
+
+impl Publish {
+    /// Parses `[HOST_ADDR:]HOST_PORT:GUEST_PORT`. Without an address only localhost can connect.
+    pub fn parse(arg: &str) -> Result<Publish> {
+        let (host, guest) = match arg.rsplit_once(':') {
+            Some(v) => v,
+            None => bail!("expected [HOST_ADDR:]HOST_PORT:GUEST_PORT, got {}", arg),
+        };
+        let guest_port = try_with!(guest.parse::<u16>(), "invalid guest port {}", guest);
+        let host = match host.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([127, 0, 0, 1], port)),
+            Err(_) => try_with!(host.parse::<SocketAddr>(), "invalid host address {}", host),
+        };
+        Ok(Publish { host, guest_port })
+    }
+}
+
+/// Listens on the host addresses of `publish`. Done before attaching, so that a taken port does
+/// not leave a half attached vm behind.
+pub fn bind(publish: &[Publish]) -> Result<Vec<(TcpListener, u16)>> {
+    publish
+        .iter()
+        .map(|p| {
+            let listener = try_with!(TcpListener::bind(p.host), "cannot listen on {}", p.host);
+            try_with!(
+                listener.set_nonblocking(true),
+                "cannot make {} non-blocking",
+                p.host
+            );
+            Ok((listener, p.guest_port))
+        })
+        .collect()
+}
+
+/// Reads the reply of the vsock device byte by byte, so that no data of the guest is consumed.
+fn read_reply(stream: &mut UnixStream) -> Result<String> {
+    let mut reply = vec![];
+    let mut byte = [0u8; 1];
+    while reply.len() < MAX_REPLY {
+        let n = try_with!(stream.read(&mut byte), "cannot read reply of vsock device");
+        if n == 0 || byte[0] == b'\n' {
+            return Ok(String::from_utf8_lossy(&reply).into_owned());
+        }
+        reply.push(byte[0]);
+    }
+    bail!("reply of vsock device is longer than {} bytes", MAX_REPLY)
+}
+
+fn connect_guest(uds_path: &Path, guest_port: u16) -> Result<UnixStream> {
+    let mut stream = try_with!(
+        UnixStream::connect(uds_path),
+        "cannot connect to {}",
+        uds_path.display()
+    );
+    try_with!(
+        stream.write_all(format!("CONNECT {}\n", guest_port).as_bytes()),
+        "cannot send request to vsock device"
+    );
+    let reply = read_reply(&mut stream)?;
+    if !reply.starts_with("OK ") {
+        bail!(
+            "guest port {} is not published, is the command of vmsh attach running?",
+            guest_port
+        );
+    }
+    Ok(stream)
+}
+
+/// Copies both directions until both sides have closed them.
+fn splice(tcp: TcpStream, guest: UnixStream) -> io::Result<()> {
+    let mut tcp_read = tcp.try_clone()?;
+    let mut guest_write = guest.try_clone()?;
+    let upstream = thread::spawn(move || {
+        let res = io::copy(&mut tcp_read, &mut guest_write);
+        let _ = guest_write.shutdown(Shutdown::Write);
+        res
+    });
+    let (mut tcp_write, mut guest_read) = (tcp, guest);
+    let res = io::copy(&mut guest_read, &mut tcp_write);
+    let _ = tcp_write.shutdown(Shutdown::Write);
+    let upstream = upstream
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::Other, "copy thread panicked")));
+    res.and(upstream).map(|_| ())
+}
+
+fn forward(tcp: TcpStream, uds_path: &Path, guest_port: u16) -> Result<()> {
+    try_with!(
+        tcp.set_nonblocking(false),
+        "cannot make published connection blocking"
+    );
+    let guest = connect_guest(uds_path, guest_port)?;
+    try_with!(
+        splice(tcp, guest),
+        "cannot forward connection to guest port {}",
+        guest_port
+    );
+    Ok(())
+}
+
+/// Accepts connections on `listeners` until it is shut down. Each connection is forwarded by a
+/// thread of its own.
+pub fn publish_thread(
+    listeners: Vec<(TcpListener, u16)>,
+    uds_path: PathBuf,
+    err_sender: &SyncSender<()>,
+) -> Result<InterrutableThread<(), ()>> {
+    for (listener, guest_port) in &listeners {
+        if let Ok(addr) = listener.local_addr() {
+            info!("publish guest port {} on {}", guest_port, addr);
+        }
+    }
+    let uds_path = Arc::new(uds_path);
+    let res = InterrutableThread::spawn(
+        "publish",
+        err_sender,
+        move |_ctx: &(), should_stop: Arc<AtomicBool>| {
+            while !should_stop.load(Ordering::Relaxed) {
+                let mut accepted = false;
+                for (listener, guest_port) in &listeners {
+                    let (stream, peer) = match listener.accept() {
+                        Ok(v) => v,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                        Err(e) => {
+                            warn!(
+                                "cannot accept connection for guest port {}: {}",
+                                guest_port, e
+                            );
+                            continue;
+                        }
+                    };
+                    accepted = true;
+                    debug!("forward {} to guest port {}", peer, guest_port);
+                    let uds_path = Arc::clone(&uds_path);
+                    let guest_port = *guest_port;
+                    thread::spawn(move || {
+                        if let Err(e) = forward(stream, &uds_path, guest_port) {
+                            warn!("{}", e);
+                        }
+                    });
+                }
+                if !accepted {
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+            }
+            Ok(())
+        },
+        (),
+    );
+    Ok(try_with!(res, "cannot spawn publish thread"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_publish() {
+        assert_eq!(
+            Publish::parse("8080:80").unwrap(),
+            Publish {
+                host: SocketAddr::from(([127, 0, 0, 1], 8080)),
+                guest_port: 80
+            }
+        );
+        assert_eq!(
+            Publish::parse("0.0.0.0:2222:22").unwrap().host,
+            SocketAddr::from(([0, 0, 0, 0], 2222))
+        );
+        assert_eq!(
+            Publish::parse("[::1]:9000:9000").unwrap().host,
+            "[::1]:9000".parse::<SocketAddr>().unwrap()
+        );
+        assert!(Publish::parse("8080").is_err());
+        assert!(Publish::parse("8080:70000").is_err());
+        assert!(Publish::parse("host:8080:80").is_err());
+    }
+}
@@ -49,6 +49,8 @@ pub const NT_SIGINFO: Elf_Word = 0x53494749;
 pub const NT_FILE: Elf_Word = 0x46494c45;
 #[cfg(target_arch = "x86_64")]
 pub const NT_PRXFPREG: Elf_Word = 0x46e62b7f;
+/// XSAVE area of a thread, with name LINUX
+pub const NT_X86_XSTATE: Elf_Word = 0x202;
 
 // e_version
 pub const EV_NONE: Elf_Word = 0;
@@ -98,6 +100,8 @@ mod headers {
     pub const ELFCLASS: u8 = super::ELFCLASS64;
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Nhdr {
     pub n_namesz: Elf_Word,
     pub n_descsz: Elf_Word,
@@ -159,7 +163,7 @@ pub const ELFDATA2: u8 = ELFDATA2MSB;
 pub const ELFDATA2: u8 = ELFDATA2LSB;
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct elf_siginfo {
     pub si_signo: c_int,
     pub si_code: c_int,
@@ -169,7 +173,7 @@ pub struct elf_siginfo {
 const ELF_PRARGSZ: usize = 80;
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct elf_prstatus {
     pub pr_info: elf_siginfo,
     pub pr_cursig: c_short,
@@ -0,0 +1,77 @@
+//! Encrypt artifacts while they are written, so that guest memory never reaches the disk in
+//! plaintext. Encryption is delegated to `age` or `gpg`, depending on the recipient.
+use log::info;
+use simple_error::{bail, require_with, try_with};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::result::Result;
+
+/// age recipients are either native age keys or ssh public keys. Everything else is passed to gpg
+/// as key id, fingerprint or email address.
+fn is_age_recipient(recipient: &str) -> bool {
+    recipient.starts_with("age1") || recipient.starts_with("ssh-")
+}
+
+pub struct Encryptor {
+    tool: &'static str,
+    child: Child,
+}
+
+impl Encryptor {
+    /// Spawn the encryption tool, which writes the ciphertext of everything written to `stdin()`
+    /// to `output`.
+    pub fn spawn(recipient: &str, output: &Path) -> Result<Encryptor> {
+        let (tool, mut cmd) = if is_age_recipient(recipient) {
+            let mut cmd = Command::new("age");
+            cmd.arg("--encrypt").arg("--recipient").arg(recipient);
+            ("age", cmd)
+        } else {
+            let mut cmd = Command::new("gpg");
+            cmd.args(&["--batch", "--yes", "--encrypt", "--recipient"])
+                .arg(recipient);
+            ("gpg", cmd)
+        };
+        cmd.arg("--output").arg(output).stdin(Stdio::piped());
+        let child = try_with!(cmd.spawn(), "cannot run {}", tool);
+        info!(
+            "encrypt {} for {} with {}",
+            output.display(),
+            recipient,
+            tool
+        );
+        Ok(Encryptor { tool, child })
+    }
+
+    pub fn stdin(&mut self) -> Result<&mut ChildStdin> {
+        Ok(require_with!(
+            self.child.stdin.as_mut(),
+            "stdin of {} was already closed",
+            self.tool
+        ))
+    }
+
+    /// Close the input and wait until the tool has written all ciphertext.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.child.stdin.take());
+        let status = try_with!(self.child.wait(), "cannot wait for {}", self.tool);
+        if !status.success() {
+            bail!("{} failed: {}", self.tool, status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_age_recipient() {
+        assert!(is_age_recipient(
+            "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+        ));
+        assert!(is_age_recipient("ssh-ed25519 AAAAC3Nza..."));
+        assert!(!is_age_recipient("joerg@thalheim.io"));
+    }
+}
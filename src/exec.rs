@@ -0,0 +1,56 @@
+//! Run a command in a guest and return once it exits, see `vmsh exec`.
+//!
+//! Like `kubectl exec`: `-i` passes stdin to the command and `-t` puts the local terminal into
+//! raw mode and keeps the size of the guest terminal in sync with it. The command talks to the
+//! console device of `vmsh attach`, only without a block device of its own.
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::attach::{self, AttachOptions};
+use crate::devices::virtio::block::CacheMode;
+use crate::devices::virtio::console::STOP_ON_SESSION_END;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+pub struct ExecOptions {
+    pub pid: Pid,
+    /// A login shell if empty
+    pub command: Vec<String>,
+    /// Pass stdin to the command
+    pub interactive: bool,
+    /// Raw terminal that is resized with the local one
+    pub tty: bool,
+    /// `KEY=VALUE` pairs
+    pub environment: Vec<String>,
+    pub cwd: Option<String>,
+    pub stage2_path: String,
+}
+
+pub fn exec(opts: &ExecOptions) -> Result<()> {
+    // stage2 ends the session once the command exits
+    STOP_ON_SESSION_END.store(true, Ordering::Release);
+
+    let mut command = vec![opts.stage2_path.clone()];
+    command.extend_from_slice(&opts.command);
+    attach::attach(&AttachOptions {
+        pid: opts.pid,
+        command,
+        backing: PathBuf::from("/dev/null"),
+        backing_format: None,
+        read_only: true,
+        cache: CacheMode::default(),
+        faults: None,
+        hotplug: None,
+        vsock: None,
+        share: None,
+        irq_affinity: vec![],
+        environment: opts.environment.clone(),
+        cwd: opts.cwd.clone(),
+        interactive: opts.interactive,
+        tty: Some(opts.tty),
+        metrics_addr: None,
+        publish: vec![],
+        symbols: SymbolSource::Ksymtab,
+    })
+}
@@ -0,0 +1,367 @@
+//! Scripted interaction with the console of a guest, like expect(1), see `vmsh expect`.
+//!
+//! vmsh attaches to the guest as with `vmsh attach` and runs a command on its virtio console,
+//! i.e. a shell or `login`. The script is a YAML list of steps that are executed in order:
+//!
+//! ```yaml
+//! # wait up to 30 seconds (the default) for the prompt
+//! - expect: "login:"
+//! - send: "root\n"
+//! - expect: "Password:"
+//!   timeout: 10
+//! - send: "hunter2\n"
+//! - sleep: 1
+//! ```
+//!
+//! Only this subset of YAML is understood: one step per list item, with double quoted (with
+//! escapes like `\n` and `\x03`), single quoted or plain values. Everything the guest writes is
+//! copied to stdout. Once the script is done, the input of the command is closed and vmsh detaches
+//! when it exits or `DETACH_TIMEOUT` passed.
+use log::{info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::result::Result;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the command may take to exit after its input was closed.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keep at most this much unmatched output, older output cannot be matched anymore.
+const MAX_BUFFERED: usize = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Send(String),
+    Expect { pattern: String, timeout: Duration },
+    Sleep(Duration),
+}
+
+pub struct ExpectOptions {
+    pub pid: Pid,
+    pub script: PathBuf,
+    /// Arguments for `vmsh attach` after the pid, e.g. the command to run.
+    pub attach_args: Vec<String>,
+}
+
+fn unescape_double_quoted(s: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('e') => out.push('\x1b'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                let code = try_with!(
+                    u8::from_str_radix(&hex, 16),
+                    "invalid escape \\x{} in \"{}\"",
+                    hex,
+                    s
+                );
+                out.push(code as char);
+            }
+            Some(c) => bail!("unknown escape \\{} in \"{}\"", c, s),
+            None => bail!("\"{}\" ends with a backslash", s),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_value(value: &str) -> Result<String> {
+    let value = value.trim();
+    if let Some(inner) = value.strip_prefix('"') {
+        let inner = require_with!(inner.strip_suffix('"'), "unterminated string {}", value);
+        return unescape_double_quoted(inner);
+    }
+    if let Some(inner) = value.strip_prefix('\'') {
+        let inner = require_with!(inner.strip_suffix('\''), "unterminated string {}", value);
+        return Ok(inner.replace("''", "'"));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_seconds(key: &str, value: &str) -> Result<Duration> {
+    let secs = try_with!(
+        value.parse::<f64>(),
+        "{} must be a number of seconds, not '{}'",
+        key,
+        value
+    );
+    if !secs.is_finite() || secs < 0.0 {
+        bail!("{} must not be negative", key);
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+fn parse_step(fields: &[(String, String)]) -> Result<Step> {
+    let mut timeout = None;
+    let mut action = None;
+    for (key, value) in fields {
+        match key.as_str() {
+            "timeout" => timeout = Some(parse_seconds(key, value)?),
+            "send" | "expect" | "sleep" if action.is_none() => action = Some((key, value)),
+            "send" | "expect" | "sleep" => {
+                bail!("a step can only have one of send, expect or sleep")
+            }
+            _ => bail!("unknown key '{}'", key),
+        }
+    }
+    let (key, value) = require_with!(action, "step has neither send, expect nor sleep");
+    match key.as_str() {
+        "expect" => Ok(Step::Expect {
+            pattern: value.clone(),
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        }),
+        _ if timeout.is_some() => bail!("timeout only applies to expect"),
+        "send" => Ok(Step::Send(value.clone())),
+        _ => Ok(Step::Sleep(parse_seconds(key, value)?)),
+    }
+}
+
+/// Parse a script, see the module documentation for its format.
+pub fn parse_script(content: &str) -> Result<Vec<Step>> {
+    let mut items: Vec<Vec<(String, String)>> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let field = if let Some(rest) = line.strip_prefix("- ") {
+            items.push(vec![]);
+            rest
+        } else if line.starts_with(char::is_whitespace) && !items.is_empty() {
+            trimmed
+        } else {
+            bail!("line {}: expected a list item starting with '- '", i + 1);
+        };
+        let (key, value) = require_with!(
+            field.split_once(':'),
+            "line {}: expected 'key: value'",
+            i + 1
+        );
+        let value = try_with!(parse_value(value), "line {}", i + 1);
+        if let Some(item) = items.last_mut() {
+            item.push((key.trim().to_string(), value));
+        }
+    }
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, fields)| Ok(try_with!(parse_step(fields), "step {}", i + 1)))
+        .collect()
+}
+
+struct Session {
+    child: Child,
+    input: Option<ChildStdin>,
+    output: Receiver<Vec<u8>>,
+    /// Output that was not matched by an expect step yet
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    fn spawn(opts: &ExpectOptions) -> Result<Session> {
+        let exe = try_with!(std::env::current_exe(), "cannot find vmsh executable");
+        let mut child = try_with!(
+            Command::new(exe)
+                .arg("attach")
+                .arg(opts.pid.to_string())
+                .args(&opts.attach_args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn(),
+            "cannot spawn vmsh attach"
+        );
+        let input = child.stdin.take();
+        let mut stdout = require_with!(child.stdout.take(), "no stdout of vmsh attach");
+        let (sender, output) = channel();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if sender.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Session {
+            child,
+            input,
+            output,
+            buffer: vec![],
+        })
+    }
+
+    fn send(&mut self, text: &str) -> Result<()> {
+        let input = require_with!(self.input.as_mut(), "input is already closed");
+        try_with!(
+            input.write_all(text.as_bytes()).and_then(|_| input.flush()),
+            "cannot write to the guest console"
+        );
+        Ok(())
+    }
+
+    /// Wait until `pattern` shows up in the output and drop the output up to its end.
+    fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(end) = find(&self.buffer, pattern.as_bytes()) {
+                self.buffer.drain(..end);
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let chunk = match self.output.recv_timeout(remaining) {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("'{}' did not appear within {:?}", pattern, timeout)
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    bail!("vmsh attach exited before '{}' appeared", pattern)
+                }
+            };
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&chunk).and_then(|_| stdout.flush());
+            self.buffer.extend_from_slice(&chunk);
+            if self.buffer.len() > MAX_BUFFERED {
+                let excess = self.buffer.len() - MAX_BUFFERED;
+                self.buffer.drain(..excess);
+            }
+        }
+    }
+
+    /// Close the input, wait for the command to exit and copy its remaining output.
+    fn finish(mut self) -> Result<()> {
+        drop(self.input.take());
+        let deadline = Instant::now() + DETACH_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(remaining) {
+                Ok(chunk) => {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&chunk).and_then(|_| stdout.flush());
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    info!(
+                        "command did not exit within {}s, detaching",
+                        DETACH_TIMEOUT.as_secs()
+                    );
+                    let pid = Pid::from_raw(self.child.id() as libc::pid_t);
+                    if let Err(e) = kill(pid, Signal::SIGINT) {
+                        warn!("cannot stop vmsh attach: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+        let status = try_with!(self.child.wait(), "cannot wait for vmsh attach");
+        if !status.success() {
+            // vmsh attach exits with the code of the command
+            bail!("vmsh attach or its command failed with {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// Position after the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len().max(1))
+        .position(|w| w == needle)
+        .map(|pos| pos + needle.len())
+}
+
+pub fn expect(opts: &ExpectOptions) -> Result<()> {
+    let content = try_with!(
+        fs::read_to_string(&opts.script),
+        "cannot read {}",
+        opts.script.display()
+    );
+    let steps = try_with!(
+        parse_script(&content),
+        "invalid script {}",
+        opts.script.display()
+    );
+
+    let mut session = Session::spawn(opts)?;
+    for (i, step) in steps.iter().enumerate() {
+        let res = match step {
+            Step::Send(text) => session.send(text),
+            Step::Expect { pattern, timeout } => session.expect(pattern, *timeout),
+            Step::Sleep(duration) => {
+                thread::sleep(*duration);
+                Ok(())
+            }
+        };
+        if let Err(e) = res {
+            let _ = session.finish();
+            bail!("step {} failed: {}", i + 1, e);
+        }
+    }
+    session.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script = r#"
+# log in
+- expect: "login:"
+- send: "root\n"
+- expect: 'it''s'
+  timeout: 2.5
+- send: "\x03"
+- sleep: 1
+"#;
+        assert_eq!(
+            parse_script(script).unwrap(),
+            vec![
+                Step::Expect {
+                    pattern: String::from("login:"),
+                    timeout: DEFAULT_TIMEOUT
+                },
+                Step::Send(String::from("root\n")),
+                Step::Expect {
+                    pattern: String::from("it's"),
+                    timeout: Duration::from_millis(2500)
+                },
+                Step::Send(String::from("\x03")),
+                Step::Sleep(Duration::from_secs(1)),
+            ]
+        );
+        assert!(parse_script("send: foo").is_err());
+        assert!(parse_script("- send: foo\n  expect: bar").is_err());
+        assert!(parse_script("- send: \"foo").is_err());
+        assert!(parse_script("- send: foo\n  timeout: 1").is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find(b"host login: ", b"login:"), Some(11));
+        assert_eq!(find(b"log", b"login:"), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Run a read-only command on many vms in one invocation, e.g. `vmsh inspect --all`.
+//!
+//! Each vm is handled by one worker thread from start to end, because the ptrace attachment of
+//! a hypervisor belongs to the thread that created it. At most `jobs` vms are stopped at the
+//! same time. The results are printed as one json array in the order of the pids. The bcc program
+//! that reads the memslots is compiled once and shared by all workers.
+use log::info;
+use nix::unistd::Pid;
+use simple_error::{bail, simple_error};
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::json::json_escape;
+use crate::kvm::hypervisor::find_hypervisors;
+use crate::result::Result;
+
+pub struct FleetOptions {
+    pub pids: Vec<Pid>,
+    /// Also run on every hypervisor found in /proc.
+    pub all: bool,
+    /// Number of vms handled in parallel.
+    pub jobs: usize,
+}
+
+/// Explicit pids first, then the discovered ones. Duplicates are removed.
+pub fn targets(opts: &FleetOptions) -> Result<Vec<Pid>> {
+    let mut pids = opts.pids.clone();
+    if opts.all {
+        let found = find_hypervisors()?;
+        info!("found {} hypervisors", found.len());
+        pids.extend(found);
+    }
+    let mut seen = vec![];
+    pids.retain(|pid| {
+        if seen.contains(pid) {
+            false
+        } else {
+            seen.push(*pid);
+            true
+        }
+    });
+    Ok(pids)
+}
+
+/// Calls `f` for every pid with at most `jobs` calls running at the same time. Results are in
+/// the order of `pids`.
+fn run_parallel<T: Send + 'static>(
+    pids: &[Pid],
+    jobs: usize,
+    f: fn(Pid) -> Result<T>,
+) -> Vec<(Pid, Result<T>)> {
+    let queue = Arc::new(Mutex::new(
+        pids.iter().copied().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let (sender, receiver) = mpsc::channel();
+    let workers = (0..jobs.max(1).min(pids.len()))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().expect("fleet queue lock poisoned").pop_front();
+                let (idx, pid) = match next {
+                    Some(v) => v,
+                    None => break,
+                };
+                if sender.send((idx, f(pid))).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    let mut results = pids.iter().map(|_| None).collect::<Vec<_>>();
+    for (idx, res) in receiver.iter() {
+        results[idx] = Some(res);
+    }
+    for worker in workers {
+        // a panicking worker loses its current vm, which is reported as failed below
+        let _ = worker.join();
+    }
+    pids.iter()
+        .copied()
+        .zip(results)
+        .map(|(pid, res)| {
+            let res = res.unwrap_or_else(|| Err(simple_error!("worker thread panicked")));
+            (pid, res)
+        })
+        .collect()
+}
+
+fn format_results(results: &[(Pid, Result<String>)]) -> String {
+    let entries = results
+        .iter()
+        .map(|(pid, res)| match res {
+            Ok(json) => format!("  {{\"pid\": {}, \"result\": {}}}", pid, json),
+            Err(e) => format!(
+                "  {{\"pid\": {}, \"error\": \"{}\"}}",
+                pid,
+                json_escape(&e.to_string())
+            ),
+        })
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]", entries.join(",\n"))
+}
+
+/// Prints the json results of `f` for all targets. Fails if any vm failed, after printing.
+pub fn run(opts: &FleetOptions, f: fn(Pid) -> Result<String>) -> Result<()> {
+    let pids = targets(opts)?;
+    if pids.is_empty() {
+        bail!("no hypervisors found");
+    }
+    let results = run_parallel(&pids, opts.jobs, f);
+    println!("{}", format_results(&results));
+    let failed = results.iter().filter(|(_, res)| res.is_err()).count();
+    if failed > 0 {
+        bail!("{} out of {} vms failed", failed, results.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    fn probe(pid: Pid) -> Result<i32> {
+        let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10));
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+        if pid.as_raw() % 3 == 0 {
+            bail!("pid {} failed", pid);
+        }
+        Ok(pid.as_raw() * 2)
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let pids = (1..=10).map(Pid::from_raw).collect::<Vec<_>>();
+        let results = run_parallel(&pids, 3, probe);
+        assert!(MAX_RUNNING.load(Ordering::SeqCst) <= 3);
+        assert_eq!(
+            results.iter().map(|(pid, _)| *pid).collect::<Vec<_>>(),
+            pids
+        );
+        assert_eq!(results[1].1.as_ref().unwrap(), &4);
+        assert!(results[2].1.is_err());
+    }
+
+    #[test]
+    fn test_format_results() {
+        let results = vec![
+            (Pid::from_raw(1), Ok(String::from("{}"))),
+            (Pid::from_raw(2), Err(simple_error!("no \"vm\""))),
+        ];
+        assert_eq!(
+            format_results(&results),
+            "[\n  {\"pid\": 1, \"result\": {}},\n  {\"pid\": 2, \"error\": \"no \\\"vm\\\"\"}\n]"
+        );
+    }
+}
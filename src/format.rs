@@ -0,0 +1,123 @@
+//! Versions of the files vmsh writes, so that files written by one vmsh release can still be
+//! read by the next one.
+//!
+//! Every file starts with the version of its format, text formats with a line like
+//! `vmsh-dedup 1`. The version is incremented whenever existing content changes its meaning.
+//! Readers accept all versions from `oldest` to `current` and convert older content while reading
+//! it. Files of a newer vmsh are rejected instead of being misread.
+use simple_error::bail;
+
+use crate::result::Result;
+
+pub struct Format {
+    pub name: &'static str,
+    /// Version that this vmsh writes.
+    pub current: u32,
+    /// Oldest version this vmsh can still read.
+    pub oldest: u32,
+}
+
+/// See `snapshot`.
+pub const SNAPSHOT: Format = Format {
+    name: "vmsh-snapshot",
+    current: 1,
+    oldest: 1,
+};
+
+/// See `gc`. Version 1 had no header line, version 3 added irq lines.
+pub const SESSION: Format = Format {
+    name: "vmsh-session",
+    current: 3,
+    oldest: 1,
+};
+
+/// See `dedup`.
+pub const DEDUP_INDEX: Format = Format {
+    name: "vmsh-dedup",
+    current: 1,
+    oldest: 1,
+};
+
+/// See `compat`.
+pub const COMPAT: Format = Format {
+    name: "vmsh-compat",
+    current: 1,
+    oldest: 1,
+};
+
+/// See `manifest`.
+pub const MANIFEST: Format = Format {
+    name: "vmsh-manifest",
+    current: 1,
+    oldest: 1,
+};
+
+/// See `rewind`, the header of its `memslots` file and the version of its checkpoints.
+pub const REWIND: Format = Format {
+    name: "vmsh-rewind",
+    current: 1,
+    oldest: 1,
+};
+
+impl Format {
+    /// Fails if this vmsh cannot read files of `version`.
+    pub fn check(&self, version: u32) -> Result<()> {
+        if version > self.current {
+            bail!(
+                "{} version {} was written by a newer vmsh, this vmsh reads up to version {}",
+                self.name,
+                version,
+                self.current
+            );
+        }
+        if version < self.oldest {
+            bail!(
+                "{} version {} is no longer supported, this vmsh reads version {} to {}. \
+                 Convert the file with an older vmsh first",
+                self.name,
+                version,
+                self.oldest,
+                self.current
+            );
+        }
+        Ok(())
+    }
+
+    /// First line of text formats.
+    pub fn text_header(&self) -> String {
+        format!("{} {}", self.name, self.current)
+    }
+
+    /// Version of a text header of this format, None if `line` is no such header. The version
+    /// still has to be checked with `check`.
+    pub fn header_version(&self, line: &str) -> Option<u32> {
+        let (name, version) = line.split_once(' ')?;
+        if name != self.name {
+            return None;
+        }
+        version.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(SESSION.check(1).is_ok());
+        assert!(SESSION.check(2).is_ok());
+        assert!(SESSION.check(3).is_ok());
+        assert!(SESSION.check(4).is_err());
+        assert!(SESSION.check(0).is_err());
+    }
+
+    #[test]
+    fn test_text_header() {
+        assert_eq!(DEDUP_INDEX.text_header(), "vmsh-dedup 1");
+        assert_eq!(DEDUP_INDEX.header_version("vmsh-dedup 1"), Some(1));
+        assert_eq!(DEDUP_INDEX.header_version("vmsh-dedup 7"), Some(7));
+        assert_eq!(DEDUP_INDEX.header_version("vmsh-manifest 1"), None);
+        assert_eq!(DEDUP_INDEX.header_version("vmsh-dedup"), None);
+    }
+}
@@ -0,0 +1,49 @@
+//! Check the filesystems of the guest with the checkers from a vmsh image, so that a broken
+//! guest userland is not needed.
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::attach::{self, AttachOptions};
+use crate::devices::virtio::block::{CacheMode, ImageFormat};
+use crate::devices::virtio::console::STOP_ON_SESSION_END;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+pub struct FsCheckOptions {
+    pub pid: Pid,
+    /// Guest device paths, i.e. `/dev/vda1`. All block devices of the guest if empty.
+    pub devices: Vec<String>,
+    /// Image with e2fsck, xfs_repair, btrfs or fsck.vfat
+    pub backing: PathBuf,
+    pub backing_format: Option<ImageFormat>,
+    pub stage2_path: String,
+}
+
+pub fn fscheck(opts: &FsCheckOptions) -> Result<()> {
+    // results are printed by stage2 to the console, we are done once it exits
+    STOP_ON_SESSION_END.store(true, Ordering::Release);
+
+    let mut command = vec![opts.stage2_path.clone(), String::from("--fscheck")];
+    command.extend(opts.devices.iter().cloned());
+    attach::attach(&AttachOptions {
+        pid: opts.pid,
+        command,
+        backing: opts.backing.clone(),
+        backing_format: opts.backing_format,
+        read_only: false,
+        cache: CacheMode::default(),
+        faults: None,
+        hotplug: None,
+        vsock: None,
+        share: None,
+        irq_affinity: vec![],
+        environment: vec![],
+        cwd: None,
+        interactive: true,
+        tty: None,
+        metrics_addr: None,
+        publish: vec![],
+        symbols: SymbolSource::Ksymtab,
+    })
+}
@@ -0,0 +1,503 @@
+//! Recover the ftrace ring buffer of a guest from its memory, see `vmsh ftrace-dump`. Meant for
+//! guests that hung while tracing, so that the trace leading up to the hang is not lost.
+//!
+//! The per-cpu ring buffers of the top level trace instance are decoded from the oldest to the
+//! newest event and merged by timestamp. `trace_printk()`, `trace_puts()` and the function tracer
+//! are printed like `/sys/kernel/tracing/trace` does, other events with their name and raw
+//! fields. Besides the offsets `vmsh ps` needs to show process names, the profile (see `vmi`)
+//! needs:
+//!
+//! ```text
+//! # address of global_trace minus address of init_task, from System.map. global_trace is not
+//! # exported, but the distance is the same for every boot of the same kernel build.
+//! init_task_to_global_trace 0x1b2c3e0
+//! trace_array.array_buffer.buffer 0x10
+//! trace_buffer.cpus 0x8
+//! trace_buffer.buffers 0x58
+//! ring_buffer_per_cpu.nr_pages 0x50
+//! ring_buffer_per_cpu.head_page 0x68
+//! ring_buffer_per_cpu.commit_page 0x78
+//! ring_buffer_per_cpu.reader_page 0x80
+//! buffer_page.read 0x18
+//! buffer_page.page 0x30
+//! # optional, for the names of trace events
+//! init_task_to_ftrace_events 0x1b2d180
+//! trace_event_call.list 0x0
+//! trace_event_call.event.type 0x28
+//! trace_event_call.name 0x68
+//! ```
+use log::warn;
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::bytes::{u32_at, u64_at};
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::ps;
+use crate::result::Result;
+use crate::sha256;
+use crate::symbolizer::{describe, SymbolTable};
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct FtraceDumpOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+    /// Only dump the buffer of this cpu
+    pub cpu: Option<usize>,
+}
+
+// ring buffer event types, see include/linux/ring_buffer.h
+const RINGBUF_TYPE_DATA_TYPE_LEN_MAX: u32 = 28;
+const RINGBUF_TYPE_PADDING: u32 = 29;
+const RINGBUF_TYPE_TIME_EXTEND: u32 = 30;
+const RINGBUF_TYPE_TIME_STAMP: u32 = 31;
+const TS_SHIFT: u32 = 27;
+const TS_MASK: u64 = (1 << 59) - 1;
+
+/// `struct buffer_data_page`: time_stamp, commit and the events
+const BUFFER_DATA_PAGE_COMMIT: usize = 8;
+const BUFFER_DATA_PAGE_DATA: usize = 16;
+/// Flags of the commit field
+const RB_MISSED_FLAGS: u64 = 3 << 30;
+/// `buffer_page.list` comes first, the kernel relies on it.
+const BUFFER_PAGE_LIST: usize = 0;
+/// The low bits of list pointers in the ring mark the head page.
+const RB_FLAG_MASK: usize = 3;
+
+// entry types of the trace instance itself, see kernel/trace/trace.h
+const TRACE_FN: u16 = 1;
+const TRACE_PRINT: u16 = 5;
+const TRACE_BPRINT: u16 = 6;
+/// `struct trace_entry`: type, flags, preempt_count and pid
+const TRACE_ENTRY_SIZE: usize = 8;
+/// Raw fields of other events shown
+const MAX_RAW_FIELDS: usize = 32;
+/// Stop following the list of event types after this many in case it forms a loop.
+const MAX_EVENT_TYPES: usize = 1 << 16;
+const NAME_MAX: usize = 255;
+const FORMAT_MAX: usize = 1024;
+
+/// Event as stored in the ring buffer.
+#[derive(Debug, PartialEq)]
+struct RawEvent {
+    /// Nanoseconds of the trace clock
+    ts: u64,
+    /// Starts with `struct trace_entry`
+    data: Vec<u8>,
+}
+
+/// Decodes the events of one page, `data` being the committed part after the page header. Events
+/// before the offset `skip` were consumed by a reader, but their time deltas still count.
+fn decode_page(data: &[u8], time_stamp: u64, skip: usize) -> Vec<RawEvent> {
+    let mut events = vec![];
+    let mut ts = time_stamp;
+    let mut pos = 0;
+    while let Some(header) = u32_at(data, pos) {
+        let type_len = header & 0x1f;
+        let time_delta = u64::from(header >> 5);
+        let array0 = u32_at(data, pos + 4);
+        let len = match type_len {
+            RINGBUF_TYPE_PADDING => match array0 {
+                // the rest of the page is unused
+                Some(0) | None if time_delta == 0 => break,
+                Some(len) => 4 + len as usize,
+                None => break,
+            },
+            RINGBUF_TYPE_TIME_EXTEND => {
+                ts += time_delta | u64::from(array0.unwrap_or(0)) << TS_SHIFT;
+                8
+            }
+            RINGBUF_TYPE_TIME_STAMP => {
+                let abs = time_delta | u64::from(array0.unwrap_or(0)) << TS_SHIFT;
+                ts = (ts & !TS_MASK) | abs;
+                8
+            }
+            0 => {
+                ts += time_delta;
+                let len = match array0 {
+                    Some(len) if len >= 4 => len as usize,
+                    _ => break,
+                };
+                if pos >= skip {
+                    match data.get(pos + 8..pos + 4 + len) {
+                        Some(event) => events.push(RawEvent {
+                            ts,
+                            data: event.to_vec(),
+                        }),
+                        None => break,
+                    }
+                }
+                4 + len
+            }
+            _ => {
+                debug_assert!(type_len <= RINGBUF_TYPE_DATA_TYPE_LEN_MAX);
+                ts += time_delta;
+                let len = type_len as usize * 4;
+                if pos >= skip {
+                    match data.get(pos + 4..pos + 4 + len) {
+                        Some(event) => events.push(RawEvent {
+                            ts,
+                            data: event.to_vec(),
+                        }),
+                        None => break,
+                    }
+                }
+                4 + len
+            }
+        };
+        pos += len;
+    }
+    events
+}
+
+/// Arguments of `trace_printk()` as `vbin_printf()` packed them: each aligned to 4 bytes,
+/// strings inline.
+struct BinaryArgs<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryArgs<'a> {
+    fn align(&mut self) {
+        self.pos = (self.pos + 3) & !3;
+    }
+
+    fn int(&mut self, size: usize) -> u64 {
+        self.align();
+        let value = match size {
+            8 => u64_at(self.data, self.pos),
+            _ => u32_at(self.data, self.pos).map(u64::from),
+        };
+        self.pos += size;
+        value.unwrap_or(0)
+    }
+
+    fn string(&mut self) -> String {
+        self.align();
+        let tail = self.data.get(self.pos..).unwrap_or(&[]);
+        let len = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+        self.pos += len + 1;
+        String::from_utf8_lossy(&tail[..len]).into_owned()
+    }
+}
+
+/// Formats the binary arguments of a `trace_printk()` with its format string. Supports the
+/// conversions the kernel does, without padding.
+fn format_bprint(fmt: &str, args: &[u8]) -> String {
+    let mut args = BinaryArgs { data: args, pos: 0 };
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        // flags, width and precision
+        while let Some(c) = chars.peek() {
+            if "-+ #0123456789.".contains(*c) {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let mut size = 4;
+        while let Some(c) = chars.peek() {
+            match c {
+                'l' | 'z' => size = 8,
+                'h' => {}
+                _ => break,
+            }
+            chars.next();
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('d') | Some('i') => {
+                let value = args.int(size);
+                let value = if size == 8 {
+                    value as i64
+                } else {
+                    i64::from(value as u32 as i32)
+                };
+                out.push_str(&value.to_string());
+            }
+            Some('u') => out.push_str(&args.int(size).to_string()),
+            Some('x') => out.push_str(&format!("{:x}", args.int(size))),
+            Some('X') => out.push_str(&format!("{:X}", args.int(size))),
+            Some('o') => out.push_str(&format!("{:o}", args.int(size))),
+            Some('c') => out.push(args.int(4) as u8 as char),
+            Some('s') => out.push_str(&args.string()),
+            Some('p') => {
+                // extensions like %pS are printed as plain pointers
+                while chars.peek().map_or(false, |c| c.is_ascii_alphanumeric()) {
+                    chars.next();
+                }
+                out.push_str(&format!("{:#x}", args.int(8)));
+            }
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// Events of one cpu from the oldest to the newest.
+fn read_cpu_buffer(k: &KernelMemory, p: &Profile, cpu_buffer: usize) -> Result<Vec<RawEvent>> {
+    let page_offset = p.get("buffer_page.page")?;
+    let nr_pages = k.read_u64(cpu_buffer + p.get("ring_buffer_per_cpu.nr_pages")?)? as usize;
+    let head = k.read_ptr(cpu_buffer + p.get("ring_buffer_per_cpu.head_page")?)?;
+    let commit = k.read_ptr(cpu_buffer + p.get("ring_buffer_per_cpu.commit_page")?)?;
+    let reader = k.read_ptr(cpu_buffer + p.get("ring_buffer_per_cpu.reader_page")?)?;
+
+    let read_page = |bpage: usize, skip: usize| -> Result<Vec<RawEvent>> {
+        let page = k.read_ptr(bpage + page_offset)?;
+        let time_stamp = k.read_u64(page)?;
+        let commit = (k.read_u64(page + BUFFER_DATA_PAGE_COMMIT)? & !RB_MISSED_FLAGS) as usize;
+        if commit == 0 {
+            return Ok(vec![]);
+        }
+        let mut data = vec![0u8; commit];
+        k.read_bytes(page + BUFFER_DATA_PAGE_DATA, &mut data)?;
+        Ok(decode_page(&data, time_stamp, skip))
+    };
+
+    // the reader page is outside of the ring and holds the oldest events not yet consumed
+    let read = k.read_u32(reader + p.get("buffer_page.read")?)? as usize;
+    let mut events = read_page(reader, read)?;
+    let mut bpage = head;
+    for _ in 0..=nr_pages {
+        if bpage != reader {
+            events.extend(read_page(bpage, 0)?);
+        }
+        if bpage == commit {
+            return Ok(events);
+        }
+        bpage = k.read_ptr(bpage + BUFFER_PAGE_LIST)? & !RB_FLAG_MASK;
+    }
+    bail!(
+        "did not reach the commit page after {} pages, are the ring buffer offsets right?",
+        nr_pages
+    )
+}
+
+/// Names of trace events by their type.
+fn event_names(k: &KernelMemory, p: &Profile) -> Result<HashMap<u16, String>> {
+    let head = k
+        .symbol("init_task")?
+        .wrapping_add(p.get("init_task_to_ftrace_events")?);
+    let list_offset = p.get("trace_event_call.list")?;
+    let type_offset = p.get("trace_event_call.event.type")?;
+    let name_offset = p.get("trace_event_call.name")?;
+    let mut names = HashMap::new();
+    let mut node = k.read_ptr(head)?;
+    while node != head {
+        if names.len() == MAX_EVENT_TYPES {
+            bail!("list of trace events does not end, is init_task_to_ftrace_events right?");
+        }
+        let call = node - list_offset;
+        let mut typ = [0u8; 2];
+        k.read_bytes(call + type_offset, &mut typ)?;
+        // name is either the name or, for tracepoints, a struct tracepoint starting with it
+        let name_ptr = k.read_ptr(call + name_offset)?;
+        let mut name = k.read_str(name_ptr, NAME_MAX).unwrap_or_default();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            name = k.read_str(k.read_ptr(name_ptr)?, NAME_MAX)?;
+        }
+        names.insert(u16::from_ne_bytes(typ), name);
+        node = k.read_ptr(node)?;
+    }
+    Ok(names)
+}
+
+struct Printer<'a> {
+    k: &'a KernelMemory<'a>,
+    symbols: SymbolTable,
+    comms: HashMap<i32, String>,
+    event_names: HashMap<u16, String>,
+}
+
+impl<'a> Printer<'a> {
+    fn message(&self, typ: u16, data: &[u8]) -> String {
+        let ptr = |offset| u64_at(data, offset).unwrap_or(0) as usize;
+        match typ {
+            TRACE_FN => format!(
+                "{} <-{}",
+                describe(&self.symbols, ptr(8)),
+                describe(&self.symbols, ptr(16))
+            ),
+            TRACE_PRINT => {
+                let buf = data.get(16..).unwrap_or(&[]);
+                let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+                String::from_utf8_lossy(&buf[..len]).trim_end().to_string()
+            }
+            TRACE_BPRINT => match self.k.read_str(ptr(16), FORMAT_MAX) {
+                Ok(fmt) => format_bprint(&fmt, data.get(24..).unwrap_or(&[]))
+                    .trim_end()
+                    .to_string(),
+                Err(e) => format!("<cannot read format: {}>", e),
+            },
+            _ => {
+                let name = match self.event_names.get(&typ) {
+                    Some(name) => name.clone(),
+                    None => format!("type {}", typ),
+                };
+                let fields = data.get(TRACE_ENTRY_SIZE..).unwrap_or(&[]);
+                let fields = &fields[..fields.len().min(MAX_RAW_FIELDS)];
+                format!("{}: {}", name, sha256::to_hex(fields))
+            }
+        }
+    }
+
+    fn print(&self, cpu: usize, event: &RawEvent) {
+        let typ = u16::from_ne_bytes([event.data[0], event.data[1]]);
+        let pid = u32_at(&event.data, 4).unwrap_or(0) as i32;
+        let comm = match (pid, self.comms.get(&pid)) {
+            (0, _) => "<idle>",
+            (_, Some(comm)) => comm.as_str(),
+            _ => "<...>",
+        };
+        println!(
+            "{:>16}-{:<7} [{:03}] {:>6}.{:06}: {}",
+            comm,
+            pid,
+            cpu,
+            event.ts / 1_000_000_000,
+            event.ts % 1_000_000_000 / 1000,
+            self.message(typ, &event.data)
+        );
+    }
+}
+
+fn ftrace_dump_guest(src: &dyn GuestAccess, opts: &FtraceDumpOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = Profile::load_for_kernel(&opts.profile, &k)?;
+    let global_trace = k
+        .symbol("init_task")?
+        .wrapping_add(p.get("init_task_to_global_trace")?);
+    let buffer = k.read_ptr(global_trace + p.get("trace_array.array_buffer.buffer")?)?;
+    if buffer == 0 {
+        bail!("tracing was never set up in the guest");
+    }
+    let cpus = k.read_i32(buffer + p.get("trace_buffer.cpus")?)? as usize;
+    let buffers = k.read_ptr(buffer + p.get("trace_buffer.buffers")?)?;
+
+    let mut events = vec![];
+    for cpu in 0..cpus {
+        if opts.cpu.map_or(false, |c| c != cpu) {
+            continue;
+        }
+        let cpu_buffer = k.read_ptr(buffers + cpu * 8)?;
+        // cpus that were never online have no buffer
+        if cpu_buffer == 0 {
+            continue;
+        }
+        let cpu_events = try_with!(
+            read_cpu_buffer(&k, &p, cpu_buffer),
+            "cannot read ring buffer of cpu {}",
+            cpu
+        );
+        events.extend(cpu_events.into_iter().map(|e| (cpu, e)));
+    }
+    events.sort_by_key(|(cpu, e)| (e.ts, *cpu));
+
+    let comms = match ps::processes(&k, &p) {
+        Ok(processes) => processes.into_iter().map(|p| (p.pid, p.comm)).collect(),
+        Err(e) => {
+            warn!("cannot list processes for their names: {}", e);
+            HashMap::new()
+        }
+    };
+    let event_names = if p.optional("init_task_to_ftrace_events").is_some() {
+        event_names(&k, &p).unwrap_or_else(|e| {
+            warn!("cannot read names of trace events: {}", e);
+            HashMap::new()
+        })
+    } else {
+        HashMap::new()
+    };
+    let printer = Printer {
+        k: &k,
+        symbols: SymbolTable::new(k.kernel.symbols.clone(), k.kernel.range.clone()),
+        comms,
+        event_names,
+    };
+    println!("# entries-in-buffer: {}", events.len());
+    for (cpu, event) in &events {
+        printer.print(*cpu, event);
+    }
+    Ok(())
+}
+
+pub fn ftrace_dump(opts: &FtraceDumpOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            ftrace_dump_guest(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            ftrace_dump_guest(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(page: &mut Vec<u8>, value: u32) {
+        page.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_decode_page() {
+        let mut page = vec![];
+        // small event of 8 bytes, 100ns after the page time stamp
+        push_u32(&mut page, 100 << 5 | 2);
+        page.extend_from_slice(&[5, 0, 0, 0, 42, 0, 0, 0]);
+        // time extend by 1 << 27
+        push_u32(&mut page, RINGBUF_TYPE_TIME_EXTEND);
+        push_u32(&mut page, 1);
+        // large event with its length in array[0]
+        push_u32(&mut page, 0);
+        push_u32(&mut page, 4 + 12);
+        page.extend_from_slice(&[6, 0, 0, 0, 7, 0, 0, 0, 1, 2, 3, 4]);
+        // padding until the end of the page
+        push_u32(&mut page, RINGBUF_TYPE_PADDING);
+        push_u32(&mut page, 0);
+
+        let events = decode_page(&page, 1000, 0);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].ts, 1100);
+        assert_eq!(events[0].data, vec![5, 0, 0, 0, 42, 0, 0, 0]);
+        assert_eq!(events[1].ts, 1100 + (1 << 27));
+        assert_eq!(events[1].data.len(), 12);
+
+        // the first event was consumed by a reader
+        let events = decode_page(&page, 1000, 12);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ts, 1100 + (1 << 27));
+    }
+
+    #[test]
+    fn test_format_bprint() {
+        let mut args = vec![];
+        args.extend_from_slice(&(-3i32).to_ne_bytes());
+        args.extend_from_slice(b"vda\0");
+        args.extend_from_slice(&0xdead_beef_u64.to_ne_bytes());
+        args.extend_from_slice(&255u32.to_ne_bytes());
+        assert_eq!(
+            format_bprint("ret=%d dev=%s at %pS mask=%02x 100%%\n", &args),
+            "ret=-3 dev=vda at 0xdeadbeef mask=ff 100%\n"
+        );
+    }
+}
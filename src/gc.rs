@@ -0,0 +1,508 @@
+//! Clean up what crashed vmsh sessions left behind on the host, see `vmsh gc`.
+//!
+//! `vmsh attach` keeps a session file in `session_dir()` that lists the artifacts it creates on
+//! the host: the hypervisor it attached to, the unix socket of the vsock device and the memslots
+//! it added to the vm. It also lists the interrupts of its devices, so that `vmsh irqstorm` can
+//! tell them apart from the devices of the hypervisor. The file is removed when vmsh detaches
+//! cleanly, so a session file of a process that no longer exists belongs to a session that
+//! crashed or was killed:
+//!
+//! ```text
+//! vmsh-session 3
+//! hypervisor 1234
+//! socket /tmp/vmsh-vsock
+//! memslot 0xfffe0000 0x20000
+//! irq block 5
+//! ```
+//!
+//! Sockets nobody listens on anymore are removed. Memslots are only reported unless
+//! `remove_memslots` is set, because the guest might still use them, i.e. its driver of a
+//! virtio device vmsh attached. Transfer files of `vmsh agent` that no client fetched within
+//! `max_age` are removed as well.
+use lazy_static::lazy_static;
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{geteuid, Pid};
+use simple_error::{bail, try_with};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::format::SESSION;
+use crate::kvm;
+use crate::poke::parse_addr;
+use crate::remote::transfer;
+use crate::result::Result;
+
+lazy_static! {
+    static ref SESSION_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Something a vmsh session creates on the host and might leave behind.
+#[derive(Debug, PartialEq)]
+pub enum Artifact {
+    Hypervisor(Pid),
+    Socket(PathBuf),
+    Memslot {
+        phys_addr: usize,
+        size: usize,
+    },
+    /// Interrupt of one of the devices, nothing to clean up.
+    Irq {
+        device: String,
+        gsi: u32,
+    },
+}
+
+impl Artifact {
+    pub(crate) fn to_line(&self) -> String {
+        match self {
+            Artifact::Hypervisor(pid) => format!("hypervisor {}", pid),
+            Artifact::Socket(path) => format!("socket {}", path.display()),
+            Artifact::Memslot { phys_addr, size } => {
+                format!("memslot {:#x} {:#x}", phys_addr, size)
+            }
+            Artifact::Irq { device, gsi } => format!("irq {} {}", device, gsi),
+        }
+    }
+
+    fn parse_line(line: &str) -> Result<Artifact> {
+        let (kind, value) = match line.find(' ') {
+            Some(idx) => (&line[..idx], &line[idx + 1..]),
+            None => bail!("invalid line '{}'", line),
+        };
+        Ok(match kind {
+            "hypervisor" => {
+                Artifact::Hypervisor(Pid::from_raw(try_with!(value.parse(), "invalid pid")))
+            }
+            "socket" => Artifact::Socket(PathBuf::from(value)),
+            "memslot" => {
+                let mut fields = value.split(' ').map(parse_addr);
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(phys_addr), Some(size), None) => Artifact::Memslot {
+                        phys_addr: phys_addr?,
+                        size: size?,
+                    },
+                    _ => bail!("invalid memslot '{}'", value),
+                }
+            }
+            "irq" => match value.split_once(' ') {
+                Some((device, gsi)) => Artifact::Irq {
+                    device: device.to_string(),
+                    gsi: try_with!(gsi.parse(), "invalid gsi '{}'", gsi),
+                },
+                None => bail!("invalid irq '{}'", value),
+            },
+            _ => bail!("unknown artifact '{}'", kind),
+        })
+    }
+}
+
+pub(crate) fn parse_session(content: &str) -> Result<Vec<Artifact>> {
+    let mut lines = content.lines().peekable();
+    // version 1 only differs by the missing header
+    if let Some(version) = lines.peek().and_then(|l| SESSION.header_version(l)) {
+        SESSION.check(version)?;
+        lines.next();
+    }
+    lines
+        .filter(|l| !l.is_empty())
+        .map(Artifact::parse_line)
+        .collect()
+}
+
+/// Directory `name` in the temporary directory, created if needed. The temporary directory is
+/// world writable, so the directory must not be trusted if someone else created it before us.
+pub fn private_temp_dir(name: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(name);
+    try_with!(
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir),
+        "cannot create {}",
+        dir.display()
+    );
+    let meta = try_with!(fs::symlink_metadata(&dir), "cannot stat {}", dir.display());
+    if !meta.is_dir() || meta.uid() != geteuid().as_raw() || meta.mode() & 0o077 != 0 {
+        bail!(
+            "{} is not a directory that is only accessible by us, remove it",
+            dir.display()
+        );
+    }
+    Ok(dir)
+}
+
+/// Directory of the session files, shared by all vmsh processes of the host.
+pub fn session_dir() -> Result<PathBuf> {
+    private_temp_dir("vmsh-sessions")
+}
+
+pub(crate) fn session_pid(path: &Path) -> Option<Pid> {
+    if path.extension()? != "session" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok().map(Pid::from_raw)
+}
+
+/// Session file of the vmsh process `pid`, see `Session`.
+pub fn session_path(pid: Pid) -> Result<PathBuf> {
+    Ok(session_dir()?.join(format!("{}.session", pid)))
+}
+
+fn process_exists(pid: Pid) -> bool {
+    // EPERM: the process exists but belongs to someone else
+    !matches!(kill(pid, None), Err(Errno::ESRCH))
+}
+
+/// Records the artifacts of this vmsh process until it is dropped, see `record`.
+pub struct Session {
+    path: PathBuf,
+}
+
+impl Session {
+    pub fn create(hypervisor: Pid) -> Result<Session> {
+        let path = session_path(Pid::this())?;
+        try_with!(
+            fs::write(
+                &path,
+                format!(
+                    "{}\n{}\n",
+                    SESSION.text_header(),
+                    Artifact::Hypervisor(hypervisor).to_line()
+                )
+            ),
+            "cannot write {}",
+            path.display()
+        );
+        *SESSION_FILE.lock().expect("cannot lock session file") = Some(path.clone());
+        Ok(Session { path })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        SESSION_FILE
+            .lock()
+            .expect("cannot lock session file")
+            .take();
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Adds `artifact` to the session file of this process, if there is one. Failures are only
+/// logged: not being able to clean up later is no reason to fail now.
+pub fn record(artifact: &Artifact) {
+    let session = SESSION_FILE.lock().expect("cannot lock session file");
+    let path = match session.as_ref() {
+        Some(path) => path,
+        None => return,
+    };
+    let res = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{}", artifact.to_line()));
+    if let Err(e) = res {
+        warn!("cannot record {:?} in {}: {}", artifact, path.display(), e);
+    }
+}
+
+/// `content` of a session file without the first line of `artifact`.
+fn without_artifact(content: &str, artifact: &Artifact) -> String {
+    let line = artifact.to_line();
+    let mut found = false;
+    content
+        .lines()
+        .filter(|l| {
+            if !found && *l == line {
+                found = true;
+                return false;
+            }
+            true
+        })
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+/// Removes `artifact` from the session file of this process again, i.e. once a device that was
+/// added at runtime is removed. Failures are only logged like in `record`.
+pub fn forget(artifact: &Artifact) {
+    let session = SESSION_FILE.lock().expect("cannot lock session file");
+    let path = match session.as_ref() {
+        Some(path) => path,
+        None => return,
+    };
+    let res =
+        fs::read_to_string(path).and_then(|c| fs::write(path, without_artifact(&c, artifact)));
+    if let Err(e) = res {
+        warn!("cannot forget {:?} in {}: {}", artifact, path.display(), e);
+    }
+}
+
+/// Control socket of the vmsh process `pid`, see `vmsh device`.
+pub fn control_path(pid: Pid) -> Result<PathBuf> {
+    Ok(session_dir()?.join(format!("{}.ctl", pid)))
+}
+
+pub struct GcOptions {
+    /// Only report what would be removed.
+    pub dry_run: bool,
+    /// Also remove memslots of crashed sessions from running vms.
+    pub remove_memslots: bool,
+    /// Transfer files of `vmsh agent` older than this are removed.
+    pub max_age: Duration,
+}
+
+/// Prints what was removed. Returns false if removing failed.
+fn report(opts: &GcOptions, what: &str, f: impl FnOnce() -> Result<()>) -> bool {
+    if opts.dry_run {
+        println!("would remove {}", what);
+        return true;
+    }
+    match f() {
+        Ok(()) => {
+            println!("removed {}", what);
+            true
+        }
+        Err(e) => {
+            println!("cannot remove {}: {}", what, e);
+            false
+        }
+    }
+}
+
+/// True if the socket exists and nobody listens on it.
+fn socket_is_stale(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        _ => return false,
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => false,
+        Err(e) => e.kind() == ErrorKind::ConnectionRefused,
+    }
+}
+
+/// Returns true if all memslots are gone after this, so the session file can be removed.
+fn collect_memslots(
+    opts: &GcOptions,
+    hypervisor: Option<Pid>,
+    memslots: &[(usize, usize)],
+) -> Result<bool> {
+    let pid = match hypervisor {
+        Some(pid) if !memslots.is_empty() && process_exists(pid) => pid,
+        // memslots die with the vm
+        _ => return Ok(true),
+    };
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(pid),
+        "cannot get vms for process {}",
+        pid
+    );
+    let _stopped = vm.stop_guard()?;
+    let slots = try_with!(vm.get_memslots(), "cannot get memslots");
+    let mut all_removed = true;
+    for (phys_addr, size) in memslots {
+        let slot = slots
+            .iter()
+            .find(|s| s.physical_start() == *phys_addr && s.size() == *size);
+        let slot = match slot {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let what = format!(
+            "memslot at {:#x} ({} kB) of vm {}",
+            phys_addr,
+            size >> 10,
+            pid
+        );
+        if opts.remove_memslots {
+            all_removed &= report(opts, &what, || vm.vm_remove_mem(slot));
+        } else {
+            println!("found {}, pass --remove-memslots to remove it", what);
+            all_removed = false;
+        }
+    }
+    Ok(all_removed)
+}
+
+fn collect_session(opts: &GcOptions, path: &Path) -> Result<()> {
+    let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    let artifacts = try_with!(parse_session(&content), "invalid {}", path.display());
+    let mut hypervisor = None;
+    let mut memslots = vec![];
+    for artifact in artifacts {
+        match artifact {
+            Artifact::Hypervisor(pid) => hypervisor = Some(pid),
+            Artifact::Socket(socket) => {
+                if socket_is_stale(&socket) {
+                    let what = format!("socket {}", socket.display());
+                    report(opts, &what, || {
+                        try_with!(fs::remove_file(&socket), "cannot remove socket");
+                        Ok(())
+                    });
+                }
+            }
+            Artifact::Memslot { phys_addr, size } => memslots.push((phys_addr, size)),
+            Artifact::Irq { .. } => {}
+        }
+    }
+    if collect_memslots(opts, hypervisor, &memslots)? {
+        report(opts, &format!("session file {}", path.display()), || {
+            try_with!(fs::remove_file(path), "cannot remove session file");
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Artifacts of all running vmsh sessions attached to `hypervisor`, by vmsh pid.
+pub fn running_sessions(hypervisor: Pid) -> Result<Vec<(Pid, Vec<Artifact>)>> {
+    let dir = session_dir()?;
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let mut sessions = vec![];
+    for entry in entries {
+        let path = try_with!(entry, "cannot read {}", dir.display()).path();
+        let pid = match session_pid(&path) {
+            Some(pid) if process_exists(pid) => pid,
+            _ => continue,
+        };
+        // the session might just have ended
+        let artifacts = match fs::read_to_string(&path).map(|c| parse_session(&c)) {
+            Ok(Ok(artifacts)) => artifacts,
+            _ => continue,
+        };
+        if artifacts.contains(&Artifact::Hypervisor(hypervisor)) {
+            sessions.push((pid, artifacts));
+        }
+    }
+    Ok(sessions)
+}
+
+/// Interrupts of the devices of all running vmsh sessions attached to `hypervisor`, as
+/// (vmsh pid, device, gsi).
+pub fn session_irqs(hypervisor: Pid) -> Result<Vec<(Pid, String, u32)>> {
+    let mut irqs = vec![];
+    for (pid, artifacts) in running_sessions(hypervisor)? {
+        for artifact in artifacts {
+            if let Artifact::Irq { device, gsi } = artifact {
+                irqs.push((pid, device, gsi));
+            }
+        }
+    }
+    Ok(irqs)
+}
+
+fn collect_transfers(opts: &GcOptions) -> Result<()> {
+    let dir = transfer::spool_dir()?;
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let now = SystemTime::now();
+    for entry in entries {
+        let entry = try_with!(entry, "cannot read {}", dir.display());
+        let path = entry.path();
+        let modified = try_with!(
+            entry.metadata().and_then(|m| m.modified()),
+            "cannot stat {}",
+            path.display()
+        );
+        if now.duration_since(modified).unwrap_or_default() < opts.max_age {
+            continue;
+        }
+        report(opts, &format!("transfer file {}", path.display()), || {
+            try_with!(fs::remove_file(&path), "cannot remove transfer file");
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+pub fn gc(opts: &GcOptions) -> Result<()> {
+    let dir = session_dir()?;
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let mut failed = 0;
+    for entry in entries {
+        let path = try_with!(entry, "cannot read {}", dir.display()).path();
+        let pid = match session_pid(&path) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if process_exists(pid) {
+            info!("session of vmsh process {} is still running", pid);
+            continue;
+        }
+        if let Err(e) = collect_session(opts, &path) {
+            warn!("{}", e);
+            failed += 1;
+        }
+    }
+    collect_transfers(opts)?;
+    if failed > 0 {
+        bail!("{} sessions could not be cleaned up", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session() {
+        let artifacts = vec![
+            Artifact::Hypervisor(Pid::from_raw(1234)),
+            Artifact::Socket(PathBuf::from("/tmp/vmsh vsock")),
+            Artifact::Memslot {
+                phys_addr: 0xfffe_0000,
+                size: 0x20000,
+            },
+            Artifact::Irq {
+                device: String::from("block"),
+                gsi: 5,
+            },
+        ];
+        let content = artifacts
+            .iter()
+            .map(|a| a.to_line() + "\n")
+            .collect::<String>();
+        // version 1
+        assert_eq!(parse_session(&content).unwrap(), artifacts);
+        let content = format!("{}\n{}", SESSION.text_header(), content);
+        assert_eq!(parse_session(&content).unwrap(), artifacts);
+        assert!(parse_session("vmsh-session 4\nhypervisor 1\n").is_err());
+        assert!(parse_session("irq block\n").is_err());
+        assert!(parse_session("memslot 0x1000\n").is_err());
+        assert!(parse_session("lock /tmp/foo\n").is_err());
+    }
+
+    #[test]
+    fn test_without_artifact() {
+        let irq = Artifact::Irq {
+            device: String::from("blk1"),
+            gsi: 5,
+        };
+        let content = "vmsh-session 3\nhypervisor 1\nirq blk1 5\nirq blk1 5\n";
+        assert_eq!(
+            without_artifact(content, &irq),
+            "vmsh-session 3\nhypervisor 1\nirq blk1 5\n"
+        );
+        let content = "vmsh-session 3\nhypervisor 1\n";
+        assert_eq!(without_artifact(content, &irq), content);
+    }
+
+    #[test]
+    fn test_session_pid() {
+        assert_eq!(
+            session_pid(Path::new("/tmp/vmsh-sessions/42.session")),
+            Some(Pid::from_raw(42))
+        );
+        assert_eq!(session_pid(Path::new("/tmp/vmsh-sessions/42.tmp")), None);
+        assert_eq!(session_pid(Path::new("/tmp/vmsh-sessions/x.session")), None);
+    }
+}
@@ -0,0 +1,662 @@
+//! GDB remote serial protocol server, so that `gdb vmlinux` can debug a running KVM guest without
+//! QEMU's `-s` flag.
+//!
+//! Each vcpu is presented as a thread (thread id = vcpu index + 1). Memory addresses are guest
+//! virtual addresses, translated with the page table of the selected vcpu. The guest is stopped
+//! while gdb is connected and resumed on `continue`, detach or disconnect. Breakpoints (`break`
+//! and `hbreak`) and single stepping use the guest debugging of KVM, see `breakpoint` and `step`.
+//! While the guest runs with breakpoints set, ^C of gdb is noticed at the next syscall of the
+//! hypervisor.
+//!
+//! `info threads` shows the kernel symbol each vcpu is executing, resolved with the symbol source
+//! given by `--symbols`.
+use kvm_bindings as kvmb;
+use log::{info, warn};
+use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::breakpoint::{BreakpointKind, Breakpoints};
+use crate::cpu::Regs;
+use crate::guest_access::GuestAccess;
+use crate::guest_mem::{get_page_table_addr, GuestMem};
+use crate::kernel::find_kernel;
+use crate::kvm::{self, hypervisor::Hypervisor};
+use crate::page_math::page_size;
+use crate::page_table::{self, PhysAddr};
+use crate::result::Result;
+use crate::sha256::to_hex;
+use crate::step;
+use crate::symbolizer::{self, NoSymbols, SymbolSource, Symbolizer};
+use crate::tracer::proc::Mapping;
+
+pub struct GdbServerOptions {
+    pub pid: Pid,
+    pub port: u16,
+    pub symbols: SymbolSource,
+}
+
+/// Largest packet we accept and announce to gdb.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Largest `m` reply that fits into a packet with `$`, `#` and the checksum. gdb accepts
+/// shorter replies and asks for the rest.
+const MAX_MEMORY_READ: usize = (PACKET_SIZE - 4) / 2;
+
+/// ^C sent by gdb to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Size of the general purpose registers and rip, followed by eflags and the segment selectors.
+const GPR_COUNT: usize = 17;
+const SEGMENT_COUNT: usize = 6;
+const REGISTERS_SIZE: usize = GPR_COUNT * 8 + 4 + SEGMENT_COUNT * 4;
+
+enum Input {
+    Packet(String),
+    Interrupt,
+    Closed,
+}
+
+enum Reply {
+    Send(String),
+    /// The reply was already sent while handling the packet.
+    Sent,
+    /// Resume the guest and end the session.
+    Detach,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse `addr,len` as used by memory packets.
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// Parse `type,addr,kind` of `Z` and `z` packets. Watchpoints are not supported.
+fn parse_breakpoint(args: &str) -> Option<(usize, BreakpointKind)> {
+    let mut fields = args.split(',');
+    let kind = match fields.next()? {
+        "0" => BreakpointKind::Software,
+        "1" => BreakpointKind::Hardware,
+        _ => return None,
+    };
+    Some((usize::from_str_radix(fields.next()?, 16).ok()?, kind))
+}
+
+/// Register layout of gdb's `i386:x86-64` architecture up to the segment selectors.
+fn encode_registers(regs: &Regs, sregs: &kvmb::kvm_sregs) -> Vec<u8> {
+    let gprs = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    let segments = [
+        sregs.cs.selector,
+        sregs.ss.selector,
+        sregs.ds.selector,
+        sregs.es.selector,
+        sregs.fs.selector,
+        sregs.gs.selector,
+    ];
+    let mut buf = Vec::with_capacity(REGISTERS_SIZE);
+    for r in &gprs {
+        buf.extend_from_slice(&r.to_le_bytes());
+    }
+    buf.extend_from_slice(&(regs.eflags as u32).to_le_bytes());
+    for s in &segments {
+        buf.extend_from_slice(&u32::from(*s).to_le_bytes());
+    }
+    buf
+}
+
+/// Update `regs` from a `G` packet. Segment selectors cannot be changed.
+fn decode_registers(buf: &[u8], regs: &mut Regs) -> Option<()> {
+    if buf.len() < GPR_COUNT * 8 + 4 {
+        return None;
+    }
+    let mut gprs = buf[..GPR_COUNT * 8].chunks_exact(8).map(|c| {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(c);
+        u64::from_le_bytes(b)
+    });
+    for r in [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ]
+    .iter_mut()
+    {
+        **r = gprs.next()?;
+    }
+    let mut eflags = [0u8; 4];
+    eflags.copy_from_slice(&buf[GPR_COUNT * 8..GPR_COUNT * 8 + 4]);
+    regs.eflags = u64::from(u32::from_le_bytes(eflags));
+    Some(())
+}
+
+struct Session<'a> {
+    vm: &'a Hypervisor,
+    maps: Vec<Mapping>,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// Index of the vcpu selected with `Hg`
+    vcpu: usize,
+    no_ack: bool,
+    symbolizer: Box<dyn Symbolizer>,
+    /// Set with `Z` and only written to the guest while it runs
+    breakpoints: Breakpoints,
+}
+
+impl<'a> Session<'a> {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut b = [0u8; 1];
+        match self.reader.read(&mut b) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(b[0])),
+            Err(e) => bail!("cannot read from gdb: {}", e),
+        }
+    }
+
+    fn read_input(&mut self) -> Result<Input> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(Input::Closed),
+                Some(INTERRUPT) => return Ok(Input::Interrupt),
+                Some(b'$') => break,
+                // acks and noise between packets
+                Some(_) => {}
+            }
+        }
+        let mut data = vec![];
+        try_with!(
+            self.reader.read_until(b'#', &mut data),
+            "cannot read from gdb"
+        );
+        if data.pop() != Some(b'#') {
+            return Ok(Input::Closed);
+        }
+        let mut sum = [0u8; 2];
+        try_with!(self.reader.read_exact(&mut sum), "cannot read from gdb");
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if !self.no_ack {
+            let ack: &[u8] = if expected == Some(checksum(&data)) {
+                b"+"
+            } else {
+                b"-"
+            };
+            try_with!(self.writer.write_all(ack), "cannot write to gdb");
+        }
+        Ok(Input::Packet(data))
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        try_with!(
+            self.writer.write_all(packet.as_bytes()),
+            "cannot write to gdb"
+        );
+        Ok(())
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        format!("T{:02x}thread:{:x};", signal, self.vcpu + 1)
+    }
+
+    fn selected_vcpu(&self) -> &kvm::hypervisor::VCPU {
+        &self.vm.vcpus[self.vcpu]
+    }
+
+    fn read_registers(&self) -> Result<String> {
+        let regs = self.vm.get_regs(self.selected_vcpu())?;
+        let sregs = self.vm.get_sregs(self.selected_vcpu())?;
+        Ok(to_hex(&encode_registers(&regs, &sregs)))
+    }
+
+    fn write_registers(&self, hex: &str) -> Result<()> {
+        let buf = require_with!(from_hex(hex), "invalid register data");
+        let mut regs = self.vm.get_regs(self.selected_vcpu())?;
+        require_with!(decode_registers(&buf, &mut regs), "register data too short");
+        self.vm.set_regs(self.selected_vcpu(), &regs)
+    }
+
+    /// Host address of the guest virtual address `addr` for the selected vcpu.
+    fn host_addr(&self, addr: usize) -> Result<usize> {
+        let sregs = self.vm.get_sregs(self.selected_vcpu())?;
+        let pml4_addr = get_page_table_addr(&sregs);
+        let pml4_map = require_with!(
+            self.phys_mapping(pml4_addr),
+            "page table at {:#x} is not in guest memory",
+            pml4_addr
+        );
+        let pml4 = PhysAddr {
+            value: pml4_addr,
+            host_offset: pml4_map.phys_to_host_offset(),
+        };
+        let phys = require_with!(
+            page_table::translate(self.vm, &pml4, page_table::paging_levels(sregs.cr4), addr)?,
+            "{:#x} is not mapped",
+            addr
+        );
+        let m = require_with!(
+            self.phys_mapping(phys),
+            "{:#x} is not in guest memory",
+            phys
+        );
+        Ok(m.start + (phys - m.phys_addr))
+    }
+
+    fn phys_mapping(&self, phys: usize) -> Option<&Mapping> {
+        self.maps
+            .iter()
+            .find(|m| m.phys_addr <= phys && phys < m.phys_end())
+    }
+
+    /// Split `addr..addr+len` at page boundaries, since each page is translated separately.
+    fn pages(addr: usize, len: usize) -> impl Iterator<Item = (usize, usize)> {
+        let end = addr.saturating_add(len);
+        let mut pos = addr;
+        std::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let page_end = (pos | (page_size() - 1)).saturating_add(1);
+            let n = std::cmp::min(page_end, end) - pos;
+            let piece = (pos, n);
+            pos += n;
+            Some(piece)
+        })
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        let mut offset = 0;
+        for (pos, n) in Self::pages(addr, len) {
+            let host_addr = self.host_addr(pos)?;
+            self.vm
+                .read_bytes(host_addr, &mut data[offset..offset + n])?;
+            offset += n;
+        }
+        Ok(data)
+    }
+
+    fn write_memory(&self, addr: usize, data: &[u8]) -> Result<()> {
+        let mut offset = 0;
+        for (pos, n) in Self::pages(addr, data.len()) {
+            let host_addr = self.host_addr(pos)?;
+            let local = [IoVec::from_slice(&data[offset..offset + n])];
+            let remote = [RemoteIoVec {
+                base: host_addr,
+                len: n,
+            }];
+            try_with!(
+                process_vm_writev(self.vm.pid, &local, &remote),
+                "cannot write hypervisor memory at {:#x}",
+                host_addr
+            );
+            offset += n;
+        }
+        Ok(())
+    }
+
+    fn select_thread(&mut self, thread: &str) -> bool {
+        // 0 means any thread, -1 all threads
+        if thread == "0" || thread == "-1" {
+            return true;
+        }
+        match usize::from_str_radix(thread, 16) {
+            Ok(tid) if tid >= 1 && tid <= self.vm.vcpus.len() => {
+                self.vcpu = tid - 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether gdb sent ^C or went away, without blocking.
+    fn interrupt_pending(&self) -> bool {
+        if self.reader.buffer().contains(&INTERRUPT) {
+            return true;
+        }
+        if self.writer.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut b = [0u8; 1];
+        let res = self.writer.peek(&mut b);
+        let _ = self.writer.set_nonblocking(false);
+        match res {
+            Ok(0) => true,
+            Ok(_) => b[0] == INTERRUPT,
+            Err(_) => false,
+        }
+    }
+
+    /// Like `cont`, but stops at the first breakpoint that is hit as well.
+    fn cont_until_hit(&mut self) -> Result<bool> {
+        let hit = self
+            .breakpoints
+            .wait_for_hit(self.vm, &|| self.interrupt_pending())?;
+        let reply = match hit {
+            Some(hit) => {
+                self.vcpu = hit.vcpu;
+                let reason = match hit.kind {
+                    BreakpointKind::Software => "swbreak",
+                    BreakpointKind::Hardware => "hwbreak",
+                };
+                format!("T{:02x}{}:;thread:{:x};", SIGTRAP, reason, hit.vcpu + 1)
+            }
+            None => {
+                if let Input::Closed = self.read_input()? {
+                    return Ok(false);
+                }
+                self.stop_reply(SIGINT)
+            }
+        };
+        self.send(&reply)?;
+        Ok(true)
+    }
+
+    /// Resume the guest until gdb interrupts it. Returns false if gdb went away.
+    fn cont(&mut self) -> Result<bool> {
+        if !self.breakpoints.is_empty() {
+            return self.cont_until_hit();
+        }
+        self.vm.resume()?;
+        loop {
+            match self.read_input()? {
+                Input::Interrupt => break,
+                Input::Closed => return Ok(false),
+                Input::Packet(p) => warn!("ignore gdb packet while running: {}", p),
+            }
+        }
+        self.vm.stop()?;
+        let reply = self.stop_reply(SIGINT);
+        self.send(&reply)?;
+        Ok(true)
+    }
+
+    fn handle(&mut self, packet: &str) -> Result<Reply> {
+        let (cmd, args) = packet.split_at(std::cmp::min(1, packet.len()));
+        let reply = match cmd {
+            "?" => self.stop_reply(SIGTRAP),
+            "g" => self.read_registers()?,
+            "G" => match self.write_registers(args) {
+                Ok(()) => String::from("OK"),
+                Err(e) => {
+                    warn!("cannot write registers: {}", e);
+                    String::from("E01")
+                }
+            },
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => match self.read_memory(addr, len.min(MAX_MEMORY_READ)) {
+                    Ok(data) => to_hex(&data),
+                    Err(_) => String::from("E14"),
+                },
+                None => String::from("E01"),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(addr_len, hex)| {
+                    let (addr, len) = parse_addr_len(addr_len)?;
+                    let data = from_hex(hex)?;
+                    if data.len() == len {
+                        Some((addr, data))
+                    } else {
+                        None
+                    }
+                });
+                match parsed {
+                    Some((addr, data)) => match self.write_memory(addr, &data) {
+                        Ok(()) => String::from("OK"),
+                        Err(_) => String::from("E14"),
+                    },
+                    None => String::from("E01"),
+                }
+            }
+            "H" => {
+                if self.select_thread(args.get(1..).unwrap_or("")) {
+                    String::from("OK")
+                } else {
+                    String::from("E01")
+                }
+            }
+            "T" => match usize::from_str_radix(args, 16) {
+                Ok(tid) if tid >= 1 && tid <= self.vm.vcpus.len() => String::from("OK"),
+                _ => String::from("E01"),
+            },
+            "c" => {
+                if self.cont()? {
+                    return Ok(Reply::Sent);
+                }
+                return Ok(Reply::Detach);
+            }
+            "s" => match step::step_vcpu(self.vm, self.selected_vcpu(), 1, |_| Ok(())) {
+                Ok(()) => self.stop_reply(SIGTRAP),
+                Err(e) => {
+                    warn!("cannot single-step: {}", e);
+                    String::from("E01")
+                }
+            },
+            "Z" | "z" => match parse_breakpoint(args) {
+                Some((addr, kind)) if cmd == "Z" => match self.breakpoints.insert(addr, kind) {
+                    Ok(()) => String::from("OK"),
+                    Err(e) => {
+                        warn!("{}", e);
+                        String::from("E01")
+                    }
+                },
+                Some((addr, kind)) => {
+                    self.breakpoints.remove(addr, kind);
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            "D" => {
+                self.send("OK")?;
+                return Ok(Reply::Detach);
+            }
+            // never kill the VM, only detach from it
+            "k" => return Ok(Reply::Detach),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                // the ack for this packet is still expected
+                self.send("OK")?;
+                self.no_ack = true;
+                return Ok(Reply::Sent);
+            }
+            _ => String::new(),
+        };
+        Ok(Reply::Send(reply))
+    }
+
+    /// Shown by `info threads`, e.g. `vcpu 0 at default_idle+0x16`.
+    fn thread_info(&self, idx: usize) -> String {
+        match self.vm.get_regs(&self.vm.vcpus[idx]) {
+            Ok(regs) => format!(
+                "vcpu {} at {}",
+                idx,
+                symbolizer::describe(self.symbolizer.as_ref(), regs.rip as usize)
+            ),
+            Err(_) => format!("vcpu {}", idx),
+        }
+    }
+
+    fn query(&self, args: &str) -> String {
+        let name = args.split(|c| c == ':' || c == ',').next().unwrap_or("");
+        match name {
+            "Supported" => format!(
+                "PacketSize={:x};QStartNoAckMode+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            ),
+            "Attached" => String::from("1"),
+            "C" => format!("QC{:x}", self.vcpu + 1),
+            "fThreadInfo" => {
+                let threads = (1..=self.vm.vcpus.len())
+                    .map(|tid| format!("{:x}", tid))
+                    .collect::<Vec<_>>();
+                format!("m{}", threads.join(","))
+            }
+            "sThreadInfo" => String::from("l"),
+            "ThreadExtraInfo" => match args.split(',').nth(1).map(|t| usize::from_str_radix(t, 16))
+            {
+                Some(Ok(tid)) if tid >= 1 && tid <= self.vm.vcpus.len() => {
+                    to_hex(self.thread_info(tid - 1).as_bytes())
+                }
+                _ => String::from("E01"),
+            },
+            _ => String::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let packet = match self.read_input()? {
+                Input::Packet(p) => p,
+                Input::Interrupt => {
+                    let reply = self.stop_reply(SIGINT);
+                    self.send(&reply)?;
+                    continue;
+                }
+                Input::Closed => return Ok(()),
+            };
+            match self.handle(&packet)? {
+                Reply::Send(reply) => self.send(&reply)?,
+                Reply::Sent => {}
+                Reply::Detach => return Ok(()),
+            }
+        }
+    }
+}
+
+pub(crate) fn open_symbols(vm: &Hypervisor, source: &SymbolSource) -> Result<Box<dyn Symbolizer>> {
+    if *source == SymbolSource::None {
+        return Ok(Box::new(NoSymbols));
+    }
+    let mem = GuestMem::new(vm)?;
+    let kernel = try_with!(
+        find_kernel(&mem, vm, source.fallbacks()),
+        "could not find kernel"
+    );
+    source.open(&kernel)
+}
+
+/// Serve a single gdb connection on `opts.port` and resume the guest afterwards.
+pub fn gdbserver(opts: &GdbServerOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let listener = try_with!(
+        TcpListener::bind(("127.0.0.1", opts.port)),
+        "cannot listen on port {}",
+        opts.port
+    );
+    info!("waiting for gdb on 127.0.0.1:{}", opts.port);
+    let (stream, addr) = try_with!(listener.accept(), "cannot accept gdb connection");
+    info!("gdb connected from {}", addr);
+    let writer = try_with!(stream.try_clone(), "cannot clone gdb connection");
+    let stopped = vm.stop_guard()?;
+    let maps = vm.get_maps()?;
+    let symbolizer = match open_symbols(&vm, &opts.symbols) {
+        Ok(symbolizer) => symbolizer,
+        Err(e) => {
+            warn!(
+                "cannot load kernel symbols, threads are shown without them: {}",
+                e
+            );
+            Box::new(NoSymbols)
+        }
+    };
+    let mut session = Session {
+        vm: &vm,
+        maps,
+        reader: BufReader::new(stream),
+        writer,
+        vcpu: 0,
+        no_ack: false,
+        symbolizer,
+        breakpoints: Breakpoints::default(),
+    };
+    let res = session.run();
+    drop(stopped);
+    info!("gdb disconnected");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(checksum("qSupported"), 0x37);
+    }
+
+    #[test]
+    fn test_parse_addr_len() {
+        assert_eq!(
+            parse_addr_len("ffffffff81000000,40"),
+            Some((0xffffffff81000000, 0x40))
+        );
+        assert_eq!(parse_addr_len("1000"), None);
+    }
+
+    #[test]
+    fn test_parse_breakpoint() {
+        assert_eq!(
+            parse_breakpoint("0,ffffffff81000000,1"),
+            Some((0xffffffff81000000, BreakpointKind::Software))
+        );
+        assert_eq!(
+            parse_breakpoint("1,1000,1"),
+            Some((0x1000, BreakpointKind::Hardware))
+        );
+        // write watchpoint
+        assert_eq!(parse_breakpoint("2,1000,8"), None);
+    }
+
+    #[test]
+    fn test_pages() {
+        let pages = Session::pages(0x1ff0, 0x20).collect::<Vec<_>>();
+        assert_eq!(pages, vec![(0x1ff0, 0x10), (0x2000, 0x10)]);
+    }
+}
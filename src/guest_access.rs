@@ -0,0 +1,89 @@
+//! Read-only access to the state of a VM as needed by virtual machine introspection.
+//!
+//! Both a running hypervisor and a previously captured coredump implement `GuestAccess`, so that
+//! page table walking, kernel detection and symbol lookup work on either of them.
+use kvm_bindings as kvmb;
+use libc::c_void;
+use nix::unistd::Pid;
+use simple_error::bail;
+use std::fmt;
+use std::mem::{size_of, MaybeUninit};
+use std::path::PathBuf;
+use std::slice;
+use vm_memory::remote_mem::process_read_bytes;
+
+use crate::cpu::Regs;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+pub trait GuestAccess {
+    /// Fill `buf` with memory found at `host_addr`. Host addresses are derived from guest
+    /// physical addresses via `PhysAddr::host_addr`.
+    fn read_bytes(&self, host_addr: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Guest physical memory and where it can be found in the host.
+    fn memory_maps(&self) -> Result<Vec<Mapping>>;
+
+    fn vcpu_count(&self) -> usize;
+
+    /// General purpose registers of the vcpu with index `idx`.
+    fn vcpu_regs(&self, idx: usize) -> Result<Regs>;
+
+    /// Special registers of the vcpu with index `idx`.
+    fn vcpu_sregs(&self, idx: usize) -> Result<kvmb::kvm_sregs>;
+}
+
+/// Read a T from `host_addr`. See `GuestAccess::read_bytes`.
+pub fn read<T: Sized + Copy>(src: &dyn GuestAccess, host_addr: usize) -> Result<T> {
+    let mut t = MaybeUninit::<T>::uninit();
+    let buf = unsafe { slice::from_raw_parts_mut(t.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    src.read_bytes(host_addr, buf)?;
+    Ok(unsafe { t.assume_init() })
+}
+
+impl GuestAccess for Hypervisor {
+    fn read_bytes(&self, host_addr: usize, buf: &mut [u8]) -> Result<()> {
+        if let Err(e) = process_read_bytes(self.pid, buf, host_addr as *const c_void) {
+            bail!("cannot read hypervisor memory at {:#x}: {}", host_addr, e);
+        }
+        Ok(())
+    }
+
+    fn memory_maps(&self) -> Result<Vec<Mapping>> {
+        self.get_maps()
+    }
+
+    fn vcpu_count(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    fn vcpu_regs(&self, idx: usize) -> Result<Regs> {
+        match self.vcpus.get(idx) {
+            Some(vcpu) => self.get_regs(vcpu),
+            None => bail!("vcpu {} does not exist", idx),
+        }
+    }
+
+    fn vcpu_sregs(&self, idx: usize) -> Result<kvmb::kvm_sregs> {
+        match self.vcpus.get(idx) {
+            Some(vcpu) => self.get_sregs(vcpu),
+            None => bail!("vcpu {} does not exist", idx),
+        }
+    }
+}
+
+/// The VM introspection commands operate on: either a running hypervisor or a coredump of it.
+pub enum GuestTarget {
+    Pid(Pid),
+    Core(PathBuf),
+}
+
+impl fmt::Display for GuestTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuestTarget::Pid(pid) => write!(f, "process {}", pid),
+            GuestTarget::Core(path) => write!(f, "coredump {}", path.display()),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::ops::Range;
 use std::sync::Arc;
 
+use crate::guest_access::GuestAccess;
 use crate::kvm::hypervisor::memory::PhysMem;
 use crate::page_math::huge_page_size;
 use crate::page_table::{
@@ -30,7 +31,8 @@ const PHYS_ADDR_MASK: u64 = 0xFFFFFFFFFF000;
 // enable PCID support
 const X86_CR4_PCIDE: u64 = 0x00020000;
 
-fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
+/// Physical address of the top-level page table of the vcpu.
+pub fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
     (if sregs.cr4 & X86_CR4_PCIDE != 0 {
         sregs.cr3 & PHYS_ADDR_MASK
     } else {
@@ -77,20 +79,16 @@ fn mapped_memory(e: &PageTableIteratorValue, host_offset: isize) -> MappedMemory
 }
 
 impl GuestMem {
-    pub fn new(hv: &Hypervisor) -> Result<GuestMem> {
+    pub fn new(hv: &dyn GuestAccess) -> Result<GuestMem> {
         // We only get maps once. This information could get all if the
         // hypervisor dynamically allocates physical memory. However this is
         // problematic anyway since it could override allocations made by us.
         // To make the design sound we try to allocate memory near the 4 Peta
         // byte limit in the hope that VMs are not getting close to this limit
         // any time soon.
-        let maps = try_with!(hv.get_maps(), "cannot vm memory allocations");
-        let first_core = &hv.vcpus[0];
-        let regs = try_with!(hv.get_regs(first_core), "failed to get vcpu registers");
-        let sregs = try_with!(
-            hv.get_sregs(first_core),
-            "failed to get vcpu special registers"
-        );
+        let maps = try_with!(hv.memory_maps(), "cannot vm memory allocations");
+        let regs = try_with!(hv.vcpu_regs(0), "failed to get vcpu registers");
+        let sregs = try_with!(hv.vcpu_sregs(0), "failed to get vcpu special registers");
 
         let pt_addr = get_page_table_addr(&sregs);
 
@@ -117,6 +115,10 @@ impl GuestMem {
         })
     }
 
+    pub fn maps(&self) -> &[Mapping] {
+        &self.maps
+    }
+
     pub fn last_mapping(&self) -> Option<&Mapping> {
         self.maps.iter().max_by_key(|m| m.phys_addr + m.size())
     }
@@ -142,7 +144,7 @@ impl GuestMem {
 
     pub fn find_kernel_sections(
         &self,
-        hv: &Hypervisor,
+        hv: &dyn GuestAccess,
         range: Range<usize>,
     ) -> Result<Vec<MappedMemory>> {
         let cpl = self.regs.cs & 3;
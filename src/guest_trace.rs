@@ -0,0 +1,104 @@
+//! Trace syscalls, kprobes and tracepoints inside the guest by running stage2 in its trace mode.
+//!
+//! Stage2 sets up ftrace in the guest kernel and prints the events to the console, so nothing has
+//! to be installed in the guest.
+use nix::unistd::Pid;
+use simple_error::bail;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::attach::{self, AttachOptions};
+use crate::devices::virtio::block::CacheMode;
+use crate::devices::virtio::console::STOP_ON_SESSION_END;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+pub struct GuestTraceOptions {
+    pub pid: Pid,
+    /// Only trace this thread of the guest
+    pub guest_pid: Option<u32>,
+    pub syscalls: bool,
+    /// Kernel functions to put kprobes on
+    pub kprobes: Vec<String>,
+    /// Tracepoints as `subsystem/event`, i.e. `sched/sched_process_exec`
+    pub events: Vec<String>,
+    /// Seconds to trace
+    pub duration: u64,
+    pub stage2_path: String,
+}
+
+fn stage2_command(opts: &GuestTraceOptions) -> Vec<String> {
+    let mut command = vec![
+        opts.stage2_path.clone(),
+        String::from("--trace"),
+        String::from("--duration"),
+        opts.duration.to_string(),
+    ];
+    if let Some(pid) = opts.guest_pid {
+        command.push(String::from("--pid"));
+        command.push(pid.to_string());
+    }
+    if opts.syscalls {
+        command.push(String::from("--syscalls"));
+    }
+    for function in &opts.kprobes {
+        command.push(String::from("--kprobe"));
+        command.push(function.clone());
+    }
+    for event in &opts.events {
+        command.push(String::from("--event"));
+        command.push(event.clone());
+    }
+    command
+}
+
+pub fn guest_trace(opts: &GuestTraceOptions) -> Result<()> {
+    if !opts.syscalls && opts.kprobes.is_empty() && opts.events.is_empty() {
+        bail!("nothing to trace, pass --syscalls, --kprobe or --event");
+    }
+    // events are printed by stage2 to the console, we are done once it exits
+    STOP_ON_SESSION_END.store(true, Ordering::Release);
+
+    attach::attach(&AttachOptions {
+        pid: opts.pid,
+        command: stage2_command(opts),
+        backing: PathBuf::from("/dev/null"),
+        backing_format: None,
+        read_only: false,
+        cache: CacheMode::default(),
+        faults: None,
+        hotplug: None,
+        vsock: None,
+        share: None,
+        irq_affinity: vec![],
+        environment: vec![],
+        cwd: None,
+        interactive: true,
+        tty: None,
+        metrics_addr: None,
+        publish: vec![],
+        symbols: SymbolSource::Ksymtab,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage2_command() {
+        let opts = GuestTraceOptions {
+            pid: Pid::from_raw(1),
+            guest_pid: Some(42),
+            syscalls: true,
+            kprobes: vec![String::from("do_sys_openat2")],
+            events: vec![],
+            duration: 5,
+            stage2_path: String::from("/dev/.vmsh"),
+        };
+        assert_eq!(
+            stage2_command(&opts).join(" "),
+            "/dev/.vmsh --trace --duration 5 --pid 42 --syscalls --kprobe do_sys_openat2"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Throttle heavy work of vmsh while the host is under pressure, see `--max-host-pressure` of
+//! `vmsh coredump` and `vmsh memreport`.
+//!
+//! Linux reports the share of time in which some tasks stalled on cpu, io or memory in
+//! `/proc/pressure` (PSI). Before each chunk of work, the highest `some avg10` of the three is
+//! compared against a threshold. Above it, vmsh sleeps until the pressure drops, but never for
+//! longer than `MAX_THROTTLE` per chunk, so the work still finishes on a permanently loaded host.
+//!
+//! The time the threads of the hypervisor waited on run queues is sampled as well and reported
+//! at the end, as a measure of how much the guest was slowed down while vmsh worked.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::kvm::topology;
+use crate::result::Result;
+use crate::tracer::proc::pid_path;
+
+const PRESSURE_FILES: &[(&str, &str)] = &[
+    ("cpu", "/proc/pressure/cpu"),
+    ("io", "/proc/pressure/io"),
+    ("memory", "/proc/pressure/memory"),
+];
+/// The kernel updates the averages every two seconds.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Continue after this long, even if the host is still under pressure.
+const MAX_THROTTLE: Duration = Duration::from_secs(30);
+
+/// `avg10` of the `some` line in a file of `/proc/pressure`, in percent.
+fn parse_some_avg10(content: &str) -> Option<f64> {
+    let line = content.lines().find(|l| l.starts_with("some "))?;
+    line.split_whitespace()
+        .find_map(|f| f.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Resource with the highest pressure and its `some avg10`.
+fn host_pressure() -> Result<(&'static str, f64)> {
+    let mut highest = ("cpu", 0.0);
+    for (name, path) in PRESSURE_FILES {
+        let content = try_with!(fs::read_to_string(path), "cannot read {}", path);
+        let avg10 = match parse_some_avg10(&content) {
+            Some(avg10) => avg10,
+            None => bail!("cannot parse {}", path),
+        };
+        if avg10 > highest.1 {
+            highest = (*name, avg10);
+        }
+    }
+    Ok(highest)
+}
+
+/// Run queue delay summed over all threads of `pid`. Threads that exit in the meantime do not
+/// count.
+fn run_queue_wait(pid: Pid) -> u64 {
+    let threads = match fs::read_dir(pid_path(pid).join("task")) {
+        Ok(threads) => threads,
+        Err(_) => return 0,
+    };
+    threads
+        .flatten()
+        .filter_map(|t| t.file_name().to_str()?.parse::<libc::pid_t>().ok())
+        .filter_map(|tid| topology::sched_stat(pid, Pid::from_raw(tid)).ok())
+        .map(|stat| stat.wait_ns)
+        .sum()
+}
+
+pub struct PressureMonitor {
+    pid: Pid,
+    /// Highest tolerated pressure in percent
+    threshold: f64,
+    last_check: Option<Instant>,
+    throttled: Duration,
+    peak: f64,
+    start_wait_ns: u64,
+}
+
+impl PressureMonitor {
+    /// Fails if the kernel does not provide PSI, i.e. it was built without CONFIG_PSI or booted
+    /// with `psi=0`.
+    pub fn new(pid: Pid, threshold: f64) -> Result<PressureMonitor> {
+        try_with!(host_pressure(), "host pressure is not available");
+        Ok(PressureMonitor {
+            pid,
+            threshold,
+            last_check: None,
+            throttled: Duration::from_secs(0),
+            peak: 0.0,
+            start_wait_ns: run_queue_wait(pid),
+        })
+    }
+
+    fn check(&mut self) -> Option<(&'static str, f64)> {
+        self.last_check = Some(Instant::now());
+        let (resource, avg10) = match host_pressure() {
+            Ok(pressure) => pressure,
+            Err(e) => {
+                warn!("{}", e);
+                return None;
+            }
+        };
+        self.peak = self.peak.max(avg10);
+        if avg10 > self.threshold {
+            Some((resource, avg10))
+        } else {
+            None
+        }
+    }
+
+    /// Block while the host is under pressure, for at most `MAX_THROTTLE`.
+    pub fn throttle(&mut self) {
+        if self
+            .last_check
+            .map_or(false, |t| t.elapsed() < CHECK_INTERVAL)
+        {
+            return;
+        }
+        let (resource, avg10) = match self.check() {
+            Some(pressure) => pressure,
+            None => return,
+        };
+        info!(
+            "host {} pressure is {:.1}%, above {:.1}%, pausing",
+            resource, avg10, self.threshold
+        );
+        let start = Instant::now();
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            if start.elapsed() >= MAX_THROTTLE {
+                warn!(
+                    "host is still under pressure after {}s, continuing",
+                    MAX_THROTTLE.as_secs()
+                );
+                break;
+            }
+            if self.check().is_none() {
+                break;
+            }
+        }
+        self.throttled += start.elapsed();
+    }
+
+    /// Log how long vmsh paused and how much the hypervisor waited for host cpus meanwhile.
+    pub fn report(&self) {
+        let wait_ns = run_queue_wait(self.pid).saturating_sub(self.start_wait_ns);
+        info!(
+            "paused for {:.1}s because of host pressure (peak {:.1}%), hypervisor threads waited {:.1}ms for a host cpu",
+            self.throttled.as_secs_f64(),
+            self.peak,
+            wait_ns as f64 / 1e6
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_some_avg10() {
+        let content = "some avg10=12.34 avg60=1.00 avg300=0.10 total=123456
+full avg10=5.00 avg60=0.50 avg300=0.05 total=6543
+";
+        assert_eq!(parse_some_avg10(content), Some(12.34));
+        assert_eq!(
+            parse_some_avg10("full avg10=1.00 avg60=0.00 avg300=0.00 total=0\n"),
+            None
+        );
+        assert_eq!(parse_some_avg10("some avg60=1.00\n"), None);
+    }
+}
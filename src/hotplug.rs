@@ -0,0 +1,238 @@
+//! Memory hotplug through stage1 for `vmsh attach --hotplug-memory`, `vmsh mem add` and
+//! `vmsh mem remove`.
+//!
+//! stage1 adds the memory with `add_memory_driver_managed()`, so it shows up as
+//! `System RAM (vmsh)` in `/proc/iomem` of the guest. Whether the guest onlines the memory
+//! depends on its auto-online policy (`/sys/devices/system/memory/auto_online_blocks`).
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, simple_error, try_with};
+use stage1_interface::{DeviceAction, DeviceState, HotplugAction};
+use std::sync::Arc;
+
+use crate::kvm::hypervisor::{self, memory::PhysMem, Hypervisor};
+use crate::kvm::PhysMemAllocator;
+use crate::result::Result;
+use crate::stage1::{HotplugRegion, Stage1};
+use crate::tracer::proc::parse_kernel_release;
+use crate::vmi::KernelMemory;
+
+/// Linux adds memory in blocks of at least 128 MiB.
+const MEMORY_BLOCK_SIZE: usize = 128 << 20;
+/// Large machines use memory blocks of up to 2 GiB.
+const HOTPLUG_ALIGN: usize = 2 << 30;
+/// Linux with 4-level paging does not support physical memory above this.
+const MAX_PHYSMEM: usize = 1 << 46;
+/// Name of the memory resource stage1 registers
+const RESOURCE_NAME: &str = "System RAM (vmsh)";
+
+/// Memory that is added to the guest as hotplugged memory while vmsh is attached
+pub struct HotplugOptions {
+    pub size: usize,
+    /// numa node of the memory, must be a possible node in the guest
+    pub node: i32,
+}
+
+/// Options of `vmsh mem add` and `vmsh mem remove`
+pub struct MemOptions {
+    pub pid: Pid,
+    pub size: usize,
+    /// numa node of the memory, must be a possible node in the guest
+    pub node: i32,
+}
+
+fn guest_kernel_version(kernel: &KernelMemory) -> Result<(String, (u32, u32))> {
+    let release = try_with!(kernel.release(), "cannot get guest kernel release");
+    let version = require_with!(
+        parse_kernel_release(&release),
+        "cannot parse guest kernel release {}",
+        release
+    );
+    Ok((release, version))
+}
+
+pub fn alloc_hotplug_memory(
+    vm: &Hypervisor,
+    allocator: &mut PhysMemAllocator,
+    opts: &HotplugOptions,
+    action: HotplugAction,
+) -> Result<(PhysMem<u8>, HotplugRegion)> {
+    if opts.size == 0 || opts.size % MEMORY_BLOCK_SIZE != 0 {
+        bail!(
+            "hotplugged memory must be a multiple of {} MiB",
+            MEMORY_BLOCK_SIZE >> 20
+        );
+    }
+    let (release, version) = {
+        let kernel = try_with!(KernelMemory::new(vm), "cannot inspect guest kernel");
+        guest_kernel_version(&kernel)?
+    };
+    let mem = try_with!(
+        allocator.phys_alloc_aligned(opts.size, HOTPLUG_ALIGN, false),
+        "cannot allocate memory for hotplug"
+    );
+    let start = mem.guest_phys_addr.value;
+    if start + opts.size > MAX_PHYSMEM {
+        bail!(
+            "hotplugged memory at {:#x} exceeds the physical address space of the guest",
+            start
+        );
+    }
+    info!(
+        "hotplug {} MiB at {:#x} into node {} of linux {}",
+        opts.size >> 20,
+        start,
+        opts.node,
+        release
+    );
+    let region = HotplugRegion {
+        start: start as u64,
+        size: opts.size as u64,
+        node: opts.node,
+        remove_with_node: version < (5, 15),
+        action,
+    };
+    Ok((mem, region))
+}
+
+/// Runs stage1 in the stopped vm until it has changed the memory. Returns the hotplug status
+/// reported by stage1. On error, the state of the memory is unknown.
+fn run_stage1(
+    vm: &Hypervisor,
+    allocator: PhysMemAllocator,
+    region: &HotplugRegion,
+) -> Result<DeviceState> {
+    // stage1 does not start stage2 for memory changes, but expects a stage2 path in argv
+    let command = vec![String::from("/dev/.vmsh")];
+    let mut stage1 = try_with!(
+        Stage1::new(
+            allocator,
+            &command,
+            &[],
+            vec![],
+            DeviceAction::Attach,
+            Some(region),
+            &[]
+        ),
+        "failed to initialize stage1"
+    );
+    let hotplug_status = require_with!(stage1.hotplug_status.take(), "no hotplug status set");
+    stage1.start(vm)?;
+    vm.resume()?;
+    let res = stage1.wait(vm).and_then(|state| match state {
+        DeviceState::Terminating => hotplug_status.check(vm),
+        state => Ok(state),
+    });
+    vm.stop()?;
+    if res.is_ok() {
+        drop(stage1);
+    } else {
+        // stage1 might still be running
+        warn!("leave stage1 in guest memory");
+        std::mem::forget(stage1);
+    }
+    res
+}
+
+pub fn mem_add(opts: &MemOptions) -> Result<()> {
+    let vm = Arc::new(try_with!(
+        hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    ));
+    let _stopped = vm.stop_guard()?;
+    let mut allocator = try_with!(
+        PhysMemAllocator::new(Arc::clone(&vm)),
+        "cannot create allocator"
+    );
+    let hotplug = HotplugOptions {
+        size: opts.size,
+        node: opts.node,
+    };
+    let (mem, region) = alloc_hotplug_memory(&vm, &mut allocator, &hotplug, HotplugAction::Add)?;
+    match run_stage1(&vm, allocator, &region) {
+        Ok(DeviceState::Ready) => {
+            info!(
+                "added {} MiB at {:#x} to the guest",
+                opts.size >> 20,
+                region.start
+            );
+            // the memslot stays until `vmsh mem remove`
+            std::mem::forget(mem);
+            Ok(())
+        }
+        Ok(_) => {
+            drop(mem);
+            Err(simple_error!(
+                "guest failed to add the memory, see the kernel log of the guest"
+            ))
+        }
+        Err(e) => {
+            // removing the memslot of memory the guest might use would crash it
+            std::mem::forget(mem);
+            Err(e)
+        }
+    }
+}
+
+pub fn mem_remove(opts: &MemOptions) -> Result<()> {
+    let vm = Arc::new(try_with!(
+        hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    ));
+    let _stopped = vm.stop_guard()?;
+    let (ranges, version) = {
+        let kernel = try_with!(
+            KernelMemory::new(vm.as_ref()),
+            "cannot inspect guest kernel"
+        );
+        let ranges = try_with!(
+            kernel.iomem_ranges(RESOURCE_NAME),
+            "cannot read memory resources of the guest"
+        );
+        (ranges, guest_kernel_version(&kernel)?.1)
+    };
+    // memory is allocated top-down, so the lowest range was added last
+    let range = ranges
+        .iter()
+        .filter(|r| r.end - r.start == opts.size)
+        .min_by_key(|r| r.start);
+    let range = require_with!(
+        range,
+        "guest has no memory of {} MiB added by vmsh, found: {:x?}",
+        opts.size >> 20,
+        ranges
+    );
+    let slots = try_with!(vm.get_memslots(), "cannot get memslots");
+    let slot = slots
+        .iter()
+        .find(|s| s.physical_start() == range.start && s.size() == opts.size);
+    let slot = require_with!(slot, "no memslot found for memory at {:#x}", range.start);
+
+    let allocator = try_with!(
+        PhysMemAllocator::new(Arc::clone(&vm)),
+        "cannot create allocator"
+    );
+    let region = HotplugRegion {
+        start: range.start as u64,
+        size: opts.size as u64,
+        node: opts.node,
+        remove_with_node: version < (5, 15),
+        action: HotplugAction::Remove,
+    };
+    match run_stage1(&vm, allocator, &region) {
+        Ok(DeviceState::Terminating) => {
+            info!(
+                "removed {} MiB at {:#x} from the guest",
+                opts.size >> 20,
+                range.start
+            );
+            vm.vm_remove_mem(slot)
+        }
+        Ok(_) => Err(simple_error!(
+            "guest cannot offline the memory, it might still be in use"
+        )),
+        Err(e) => Err(e),
+    }
+}
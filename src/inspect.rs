@@ -1,33 +1,214 @@
-//mod device;
-
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
 use crate::guest_mem::GuestMem;
+use crate::json::json_escape;
 use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::ioctls;
+use crate::kvm::kvm_ioregionfd::KVM_CAP_IOREGIONFD;
+use crate::kvm::memslots::MemSlot;
+use crate::kvm::topology::{self, SchedStat};
+use crate::memreport::backing;
 use crate::result::Result;
+use crate::sampling::percent_of_interval;
+use crate::tracer::proc::Mapping;
+use kvm_bindings as kvmb;
 use log::*;
 use nix::unistd::Pid;
-use simple_error::try_with;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::Duration;
 
 use crate::kvm;
 
 pub struct InspectOptions {
-    pub pid: Pid,
+    pub target: GuestTarget,
+    /// Show host threads and scheduling statistics of each vcpu.
+    pub sched: bool,
+    /// Print `InspectSummary` as json instead of text.
+    pub json: bool,
+    /// Show the msrs of each vcpu that describe the mode of the guest.
+    pub msrs: bool,
+    /// Show the cpuid leaves the hypervisor configured for each vcpu.
+    pub cpuid: bool,
+    /// Show the host file and offset behind each memslot.
+    pub backing: bool,
 }
 
-pub fn inspect(opts: &InspectOptions) -> Result<()> {
-    let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
-        "cannot get vms for process {}",
-        opts.pid
+/// Msrs shown by `--msrs`, names as in the intel sdm.
+const INSPECT_MSRS: &[(&str, u32)] = &[
+    ("IA32_TSC", 0x10),
+    ("IA32_APIC_BASE", 0x1b),
+    ("IA32_SYSENTER_CS", 0x174),
+    ("IA32_SYSENTER_ESP", 0x175),
+    ("IA32_SYSENTER_EIP", 0x176),
+    ("IA32_MISC_ENABLE", 0x1a0),
+    ("IA32_PAT", 0x277),
+    ("IA32_EFER", 0xc000_0080),
+    ("IA32_STAR", 0xc000_0081),
+    ("IA32_LSTAR", 0xc000_0082),
+    ("IA32_CSTAR", 0xc000_0083),
+    ("IA32_FMASK", 0xc000_0084),
+    ("IA32_FS_BASE", 0xc000_0100),
+    ("IA32_GS_BASE", 0xc000_0101),
+    ("IA32_KERNEL_GS_BASE", 0xc000_0102),
+    ("IA32_TSC_AUX", 0xc000_0103),
+];
+
+const CPUID_VENDOR_LEAF: u32 = 0;
+/// KVM signature of the hypervisor, see Documentation/virt/kvm/x86/cpuid.rst
+const CPUID_HYPERVISOR_LEAF: u32 = 0x4000_0000;
+
+/// Scheduling statistics are sampled over this period.
+const SCHED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Must be called while the VM is running, otherwise all vcpus look idle.
+fn inspect_sched(vm: &Hypervisor) -> Result<()> {
+    let threads = topology::vcpu_threads(vm.pid, &vm.vcpus)?;
+    if threads.len() < vm.vcpus.len() {
+        warn!(
+            "found threads for {} out of {} vcpus",
+            threads.len(),
+            vm.vcpus.len()
+        );
+    }
+    let sample = || {
+        threads
+            .iter()
+            .map(|t| topology::sched_stat(vm.pid, t.tid))
+            .collect::<Result<Vec<_>>>()
+    };
+    let before = sample()?;
+    thread::sleep(SCHED_SAMPLE_INTERVAL);
+    let after = sample()?;
+
+    println!(
+        "{:>4} {:>8} {:<16} {:>4} {:>7} {:>7} {:>12}",
+        "VCPU", "TID", "THREAD", "CPU", "RUN%", "STEAL%", "LATENCY(us)"
     );
-    vm.stop()?;
+    for (t, (b, a)) in threads.iter().zip(before.iter().zip(after.iter())) {
+        let delta = SchedStat {
+            run_ns: a.run_ns - b.run_ns,
+            wait_ns: a.wait_ns - b.wait_ns,
+            timeslices: a.timeslices - b.timeslices,
+        };
+        // average time from becoming runnable to running
+        let latency_us = if delta.timeslices == 0 {
+            0
+        } else {
+            delta.wait_ns / delta.timeslices / 1000
+        };
+        let cpu = topology::last_cpu(vm.pid, t.tid)
+            .map(|c| c.to_string())
+            .unwrap_or_else(|_| String::from("-"));
+        println!(
+            "{:>4} {:>8} {:<16} {:>4} {:>7.1} {:>7.1} {:>12}",
+            t.idx,
+            t.tid,
+            t.name,
+            cpu,
+            percent_of_interval(delta.run_ns, SCHED_SAMPLE_INTERVAL),
+            percent_of_interval(delta.wait_ns, SCHED_SAMPLE_INTERVAL),
+            latency_us
+        );
+    }
+    Ok(())
+}
 
-    for map in vm.get_maps()? {
-        info!(
-            "vm mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
-            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
-        )
+/// Requires the vm to be stopped.
+fn inspect_msrs(vm: &Hypervisor) -> Result<()> {
+    let indices = INSPECT_MSRS.iter().map(|(_, i)| *i).collect::<Vec<_>>();
+    println!(
+        "{:>4} {:<20} {:>10} {:>18}",
+        "VCPU", "MSR", "INDEX", "VALUE"
+    );
+    for vcpu in &vm.vcpus {
+        let msrs = vm.get_msrs(vcpu, &indices)?;
+        for (name, index) in INSPECT_MSRS {
+            let value = match msrs.iter().find(|m| m.index == *index) {
+                Some(m) => format!("{:#x}", m.data),
+                None => String::from("-"),
+            };
+            println!("{:>4} {:<20} {:>#10x} {:>18}", vcpu.idx, name, index, value);
+        }
     }
+    Ok(())
+}
+
+/// The valid entries of `cpuid`.
+fn cpuid_leaves(cpuid: &ioctls::kvm_cpuid2) -> &[kvmb::kvm_cpuid_entry2] {
+    let nent = (cpuid.nent as usize).min(cpuid.entries.len());
+    &cpuid.entries[..nent]
+}
+
+fn cpuid_regs(e: &kvmb::kvm_cpuid_entry2) -> [u32; 4] {
+    [e.eax, e.ebx, e.ecx, e.edx]
+}
+
+/// Ascii string in three registers of a cpuid leaf, like the vendor id in ebx, edx and ecx of
+/// leaf 0.
+fn cpuid_string(regs: [u32; 3]) -> String {
+    regs.iter()
+        .flat_map(|r| r.to_le_bytes())
+        .take_while(|b| *b != 0)
+        .map(|b| if b.is_ascii_graphic() { b as char } else { '.' })
+        .collect()
+}
 
+fn print_cpuid_leaf(vcpu: usize, e: &kvmb::kvm_cpuid_entry2) {
+    println!(
+        "{:>4} {:>#10x} {:>7} {:>#10x} {:>#10x} {:>#10x} {:>#10x}",
+        vcpu, e.function, e.index, e.eax, e.ebx, e.ecx, e.edx
+    );
+}
+
+/// Prints all leaves of the first vcpu and for the others only leaves that differ from it, e.g.
+/// the apic ids. Requires the vm to be stopped.
+fn inspect_cpuid(vm: &Hypervisor) -> Result<()> {
+    let first = match vm.vcpus.first() {
+        Some(vcpu) => vm.get_cpuid2(vcpu)?,
+        None => bail!("vm has no vcpus"),
+    };
+    let leaves = cpuid_leaves(&first);
+    let leaf = |function| leaves.iter().find(|e| e.function == function);
+    if let Some(e) = leaf(CPUID_VENDOR_LEAF) {
+        info!("cpu vendor: {}", cpuid_string([e.ebx, e.edx, e.ecx]));
+    }
+    match leaf(CPUID_HYPERVISOR_LEAF) {
+        Some(e) => info!("hypervisor: {}", cpuid_string([e.ebx, e.ecx, e.edx])),
+        None => info!("hypervisor leaves are hidden from the guest"),
+    }
+
+    println!(
+        "{:>4} {:>10} {:>7} {:>10} {:>10} {:>10} {:>10}",
+        "VCPU", "LEAF", "SUBLEAF", "EAX", "EBX", "ECX", "EDX"
+    );
+    for e in leaves {
+        print_cpuid_leaf(0, e);
+    }
+    for vcpu in vm.vcpus.iter().skip(1) {
+        let cpuid = vm.get_cpuid2(vcpu)?;
+        for e in cpuid_leaves(&cpuid) {
+            let same = leaves.iter().any(|f| {
+                f.function == e.function && f.index == e.index && cpuid_regs(f) == cpuid_regs(e)
+            });
+            if !same {
+                print_cpuid_leaf(vcpu.idx, e);
+            }
+        }
+        if cpuid.nent != first.nent {
+            warn!(
+                "vcpu {} has {} cpuid leaves, vcpu 0 has {}",
+                vcpu.idx, cpuid.nent, first.nent
+            );
+        }
+    }
+    Ok(())
+}
+
+fn inspect_vcpus(vm: &Hypervisor) -> Result<()> {
     info!("vcpu maps");
     for map in vm.get_vcpu_maps()? {
         info!(
@@ -37,19 +218,63 @@ pub fn inspect(opts: &InspectOptions) -> Result<()> {
 
         let map_ptr = map.start as *const kvm_bindings::kvm_run;
         let kvm_run: kvm_bindings::kvm_run =
-            kvm::hypervisor::memory::process_read(opts.pid, map_ptr as *const libc::c_void)?;
+            kvm::hypervisor::memory::process_read(vm.pid, map_ptr as *const libc::c_void)?;
         info!("kvm_run: exit_reason {}", kvm_run.exit_reason);
 
         let reason_ptr: *const u32 = unsafe { &((*map_ptr).exit_reason) };
         let reason: u32 =
-            kvm::hypervisor::memory::process_read(opts.pid, reason_ptr as *const libc::c_void)?;
+            kvm::hypervisor::memory::process_read(vm.pid, reason_ptr as *const libc::c_void)?;
         info!("reason ptr = {:?}", reason_ptr);
         info!("reason = {}", reason);
     }
+    Ok(())
+}
+
+/// Host file behind each memslot, to copy or share guest physical ranges out of band. The
+/// offset is the one of the memslot in the file, not the one of the whole mapping.
+fn inspect_backing(vm: &Hypervisor) -> Result<()> {
+    println!(
+        "{:<33} {:<18} {:<10} {:>6} {:>10} {:>14} PATH",
+        "PHYSICAL", "HOST", "KIND", "DEV", "INODE", "OFFSET"
+    );
+    let mut maps = vm.memory_maps()?;
+    maps.sort_by_key(|m| m.phys_addr);
+    for m in maps {
+        let kind = backing(&m, None);
+        let (dev, inode, offset) = if kind == "anon" {
+            (String::from("-"), String::from("-"), String::from("-"))
+        } else {
+            (
+                format!("{:02x}:{:02x}", m.major_dev, m.minor_dev),
+                m.inode.to_string(),
+                format!("{:#x}", m.offset),
+            )
+        };
+        println!(
+            "{:<33} {:<18} {:<10} {:>6} {:>10} {:>14} {}",
+            format!("{:#x}-{:#x}", m.phys_addr, m.phys_end()),
+            format!("{:#x}", m.start),
+            kind,
+            dev,
+            inode,
+            offset,
+            m.pathname
+        );
+    }
+    Ok(())
+}
+
+fn inspect_guest(src: &dyn GuestAccess) -> Result<()> {
+    for map in src.memory_maps()? {
+        info!(
+            "vm mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
+            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
+        )
+    }
 
-    let mem = GuestMem::new(&vm)?;
+    let mem = GuestMem::new(src)?;
 
-    match find_kernel(&mem, &vm) {
+    match find_kernel(&mem, src, &[]) {
         Ok(kernel) => {
             let sections = &kernel.memory_sections;
             info!(
@@ -70,3 +295,255 @@ pub fn inspect(opts: &InspectOptions) -> Result<()> {
 
     Ok(())
 }
+
+/// Bumped whenever a field of the json output is removed or changes its meaning. New fields
+/// can be added without a bump.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// KVM_CHECK_EXTENSION results in the json output, names as in linux/kvm.h without `KVM_CAP_`.
+pub(crate) const KVM_CAPABILITIES: &[(&str, u32)] = &[
+    ("irqchip", kvmb::KVM_CAP_IRQCHIP),
+    ("user_memory", kvmb::KVM_CAP_USER_MEMORY),
+    ("nr_vcpus", kvmb::KVM_CAP_NR_VCPUS),
+    ("max_vcpus", kvmb::KVM_CAP_MAX_VCPUS),
+    ("nr_memslots", kvmb::KVM_CAP_NR_MEMSLOTS),
+    ("user_nmi", kvmb::KVM_CAP_USER_NMI),
+    ("irqfd", kvmb::KVM_CAP_IRQFD),
+    ("ioeventfd", kvmb::KVM_CAP_IOEVENTFD),
+    ("ioeventfd_any_length", kvmb::KVM_CAP_IOEVENTFD_ANY_LENGTH),
+    ("signal_msi", kvmb::KVM_CAP_SIGNAL_MSI),
+    ("readonly_mem", kvmb::KVM_CAP_READONLY_MEM),
+    (
+        "manual_dirty_log_protect2",
+        ioctls::KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 as u32,
+    ),
+    ("ioregionfd", KVM_CAP_IOREGIONFD),
+];
+
+/// Machine readable result of `vmsh inspect`, see `summary_pid`.
+pub struct InspectSummary {
+    /// index and fd number in the hypervisor
+    vcpus: Vec<(usize, RawFd)>,
+    /// None if the memslots could not be read
+    memslots: Option<Vec<MemSlot>>,
+    mappings: Vec<Mapping>,
+    /// name and KVM_CHECK_EXTENSION result, None if the check failed
+    capabilities: Vec<(&'static str, Option<i32>)>,
+    /// virtual range and number of symbols, None if the kernel was not found
+    kernel: Option<(Range<usize>, usize)>,
+}
+
+fn summary(vm: &Hypervisor) -> Result<InspectSummary> {
+    let memslots = match vm.get_memslots() {
+        Ok(slots) => Some(slots),
+        Err(e) => {
+            warn!("cannot read memslots: {}", e);
+            None
+        }
+    };
+    let capabilities = KVM_CAPABILITIES
+        .iter()
+        .map(|(name, cap)| (*name, vm.check_extension(*cap as i32).ok()))
+        .collect();
+    let mappings = vm.memory_maps()?;
+    let mem = GuestMem::new(vm)?;
+    let kernel = match find_kernel(&mem, vm, &[]) {
+        Ok(kernel) => Some((kernel.range.clone(), kernel.symbols.len())),
+        Err(e) => {
+            warn!("could not find kernel: {}", e);
+            None
+        }
+    };
+    Ok(InspectSummary {
+        vcpus: vm.vcpus.iter().map(|v| (v.idx, v.fd_num)).collect(),
+        memslots,
+        mappings,
+        capabilities,
+        kernel,
+    })
+}
+
+/// Stops the vm while its memory is inspected.
+pub fn summary_pid(pid: Pid) -> Result<InspectSummary> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(pid),
+        "cannot get vms for process {}",
+        pid
+    );
+    let _stopped = vm.stop_guard()?;
+    summary(&vm)
+}
+
+fn json_optional<T: ToString>(v: Option<T>) -> String {
+    match v {
+        Some(v) => v.to_string(),
+        None => String::from("null"),
+    }
+}
+
+impl InspectSummary {
+    /// Addresses are hex strings, they don't fit into the numbers of most json parsers.
+    pub fn to_json(&self) -> String {
+        let vcpus = self
+            .vcpus
+            .iter()
+            .map(|(idx, fd)| format!("{{\"idx\": {}, \"fd\": {}}}", idx, fd))
+            .collect::<Vec<_>>();
+        let memslots = self.memslots.as_ref().map(|slots| {
+            let slots = slots
+                .iter()
+                .map(|s| {
+                    format!(
+                        "{{\"id\": {}, \"flags\": {}, \"phys_start\": \"{:#x}\", \"size\": \"{:#x}\", \"host_start\": \"{:#x}\"}}",
+                        s.id(),
+                        s.flags(),
+                        s.physical_start(),
+                        s.size(),
+                        s.start()
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("[{}]", slots.join(", "))
+        });
+        let mappings = self
+            .mappings
+            .iter()
+            .map(|m| {
+                format!(
+                    "{{\"start\": \"{:#x}\", \"end\": \"{:#x}\", \"phys_addr\": \"{:#x}\", \"path\": \"{}\", \"kind\": \"{}\", \"dev\": \"{:02x}:{:02x}\", \"inode\": {}, \"offset\": \"{:#x}\"}}",
+                    m.start,
+                    m.end,
+                    m.phys_addr,
+                    json_escape(&m.pathname),
+                    backing(m, None),
+                    m.major_dev,
+                    m.minor_dev,
+                    m.inode,
+                    m.offset
+                )
+            })
+            .collect::<Vec<_>>();
+        let capabilities = self
+            .capabilities
+            .iter()
+            .map(|(name, v)| format!("\"{}\": {}", name, json_optional(*v)))
+            .collect::<Vec<_>>();
+        let kernel = self.kernel.as_ref().map(|(range, symbols)| {
+            format!(
+                "{{\"start\": \"{:#x}\", \"end\": \"{:#x}\", \"symbols\": {}}}",
+                range.start, range.end, symbols
+            )
+        });
+        format!(
+            "{{\"version\": {}, \"vcpus\": [{}], \"memslots\": {}, \"mappings\": [{}], \"kvm_capabilities\": {{{}}}, \"kernel\": {}}}",
+            JSON_SCHEMA_VERSION,
+            vcpus.join(", "),
+            json_optional(memslots),
+            mappings.join(", "),
+            capabilities.join(", "),
+            json_optional(kernel)
+        )
+    }
+}
+
+pub fn inspect(opts: &InspectOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) if opts.json => {
+            if opts.sched {
+                bail!("--sched is not part of the json output");
+            }
+            if opts.msrs {
+                bail!("--msrs is not part of the json output");
+            }
+            if opts.cpuid {
+                bail!("--cpuid is not part of the json output");
+            }
+            if opts.backing {
+                bail!("the json output has the backing of each mapping without --backing");
+            }
+            println!("{}", summary_pid(*pid)?.to_json());
+            Ok(())
+        }
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            if opts.sched {
+                inspect_sched(&vm)?;
+            }
+            let _stopped = vm.stop_guard()?;
+            if opts.msrs {
+                inspect_msrs(&vm)?;
+            }
+            if opts.cpuid {
+                inspect_cpuid(&vm)?;
+            }
+            if opts.backing {
+                inspect_backing(&vm)?;
+            }
+            inspect_guest(&vm)?;
+            inspect_vcpus(&vm)
+        }
+        GuestTarget::Core(path) => {
+            if opts.sched {
+                bail!("--sched needs a running hypervisor, not a coredump");
+            }
+            if opts.msrs {
+                bail!("--msrs needs a running hypervisor, not a coredump");
+            }
+            if opts.cpuid {
+                bail!("--cpuid needs a running hypervisor, not a coredump");
+            }
+            if opts.backing {
+                bail!("--backing needs a running hypervisor, not a coredump");
+            }
+            if opts.json {
+                bail!("json output needs a running hypervisor, not a coredump");
+            }
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            info!(
+                "coredump {} with {} vcpus",
+                core.path().display(),
+                core.vcpu_count()
+            );
+            inspect_guest(&core)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::mman::{MapFlags, ProtFlags};
+
+    #[test]
+    fn test_cpuid_string() {
+        // leaf 0 of an intel cpu: ebx, edx, ecx
+        assert_eq!(
+            cpuid_string([0x756e_6547, 0x4965_6e69, 0x6c65_746e]),
+            "GenuineIntel"
+        );
+        // KVM pads its signature with zeros
+        assert_eq!(
+            cpuid_string([0x4b4d_564b, 0x564b_4d56, 0x0000_004d]),
+            "KVMKVMKVM"
+        );
+    }
+
+    #[test]
+    fn test_summary_json() {
+        let summary = InspectSummary {
+            vcpus: vec![(0, 12), (1, 13)],
+            memslots: None,
+            mappings: vec![],
+            capabilities: vec![("irqfd", Some(1)), ("ioregionfd", None)],
+            kernel: Some((0xffff_ffff_8100_0000..0xffff_ffff_8200_0000, 42)),
+        };
+        assert_eq!(
+            summary.to_json(),
+            "{\"version\": 1, \"vcpus\": [{\"idx\": 0, \"fd\": 12}, {\"idx\": 1, \"fd\": 13}], \"memslots\": null, \"mappings\": [], \"kvm_capabilities\": {\"irqfd\": 1, \"ioregionfd\": null}, \"kernel\": {\"start\": \"0xffffffff81000000\", \"end\": \"0xffffffff82000000\", \"symbols\": 42}}"
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Inject interrupts into a guest to test its interrupt handling or kick a stuck driver, see
+//! `vmsh irq`.
+//!
+//! The line is set through KVM_IRQ_LINE on the irqchip of KVM, which the hypervisor shares with
+//! its own devices. Lowering a level-triggered line that one of them holds high therefore clears
+//! it until the device sets it again.
+use log::info;
+use nix::unistd::Pid;
+use simple_error::try_with;
+
+use crate::kvm;
+use crate::result::Result;
+
+pub struct IrqOptions {
+    pub pid: Pid,
+    pub gsi: u32,
+    /// Leave the line at this level instead of raising and lowering it.
+    pub level: Option<bool>,
+}
+
+pub fn irq(opts: &IrqOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    match opts.level {
+        Some(level) => {
+            vm.set_irq_line(opts.gsi, level)?;
+            info!(
+                "set gsi {} {}",
+                opts.gsi,
+                if level { "high" } else { "low" }
+            );
+        }
+        None => {
+            vm.assert_irq(opts.gsi)?;
+            info!("raised and lowered gsi {}", opts.gsi);
+        }
+    }
+    Ok(())
+}
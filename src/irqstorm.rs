@@ -0,0 +1,450 @@
+//! Detect interrupt storms of a vm and find the device behind them, see `vmsh irqstorm`.
+//!
+//! Interrupts are counted per gsi in the kernel with kprobes on `kvm_set_irq`, which irqfds and
+//! KVM_IRQ_LINE end up in, and on the irqfd fast path for msis. The return value tells whether
+//! the guest made progress: an interrupt that is coalesced with one that is still pending means
+//! that the guest did not handle the previous one yet, an ignored one hit a masked pin.
+//!
+//! Interrupts of devices that a `vmsh attach` session added are recognized by the session files
+//! (see `gc`), all others belong to the hypervisor. With `mask`, the ioapic pin of a storming vmsh
+//! device is masked for a while. The devices re-send interrupts that are not acknowledged, so
+//! the guest catches up once the pin is unmasked. Devices of the hypervisor are never masked, as
+//! their interrupts might be needed by the guest to make progress at all.
+use bcc::{BPFBuilder, Kprobe, Kretprobe, BPF};
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::gc;
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+
+pub struct IrqStormOptions {
+    pub pid: Pid,
+    pub interval: Duration,
+    /// Interrupts per second of a gsi that count as storm.
+    pub threshold: u64,
+    /// Mask the ioapic pin of storming vmsh devices for this long.
+    pub mask: Option<Duration>,
+}
+
+/// Pins of the ioapic, higher gsis are msis.
+const IOAPIC_PINS: u32 = 24;
+
+const BPF_TEXT: &str = r#"
+#include <linux/kvm_host.h>
+
+struct irq_count {
+    u64 injected;
+    u64 coalesced;
+    u64 ignored;
+};
+
+// struct kvm of the vm
+BPF_ARRAY(target, u64, 1);
+// gsi of the injection in progress per thread
+BPF_HASH(pending, u64, u32);
+BPF_HASH(irqs, u32, struct irq_count);
+
+void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    if (pid != TARGET_PID) {
+        return;
+    }
+    u32 idx = 0;
+    u64 kvm = (u64)filp->private_data;
+    target.update(&idx, &kvm);
+}
+
+static int is_target(struct kvm *kvm) {
+    u32 idx = 0;
+    u64 *t = target.lookup(&idx);
+    return t && *t == (u64)kvm;
+}
+
+void kvm_set_irq(struct pt_regs *ctx, struct kvm *kvm, int irq_source_id, u32 irq, int level) {
+    if (!level || !is_target(kvm)) {
+        return;
+    }
+    u64 id = bpf_get_current_pid_tgid();
+    pending.update(&id, &irq);
+}
+
+void kvm_arch_set_irq_inatomic(struct pt_regs *ctx, struct kvm_kernel_irq_routing_entry *e,
+                               struct kvm *kvm, int irq_source_id, int level) {
+    if (!level || !is_target(kvm)) {
+        return;
+    }
+    u64 id = bpf_get_current_pid_tgid();
+    u32 gsi = e->gsi;
+    pending.update(&id, &gsi);
+}
+
+void set_irq_return(struct pt_regs *ctx) {
+    u64 id = bpf_get_current_pid_tgid();
+    u32 *pending_gsi = pending.lookup(&id);
+    if (!pending_gsi) {
+        return;
+    }
+    u32 gsi = *pending_gsi;
+    pending.delete(&id);
+    int ret = PT_REGS_RC(ctx);
+    // the irqfd retries with kvm_set_irq from a worker
+    if (ret == -EWOULDBLOCK) {
+        return;
+    }
+    struct irq_count zero = {};
+    struct irq_count *count = irqs.lookup_or_try_init(&gsi, &zero);
+    if (!count) {
+        return;
+    }
+    __sync_fetch_and_add(&count->injected, 1);
+    if (ret == 0) {
+        __sync_fetch_and_add(&count->coalesced, 1);
+    } else if (ret < 0) {
+        __sync_fetch_and_add(&count->ignored, 1);
+    }
+}
+"#;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct IrqCount {
+    injected: u64,
+    coalesced: u64,
+    ignored: u64,
+}
+
+impl IrqCount {
+    fn parse(value: &[u8]) -> Option<IrqCount> {
+        let field = |i: usize| {
+            value
+                .get(i * 8..i * 8 + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        };
+        Some(IrqCount {
+            injected: field(0)?,
+            coalesced: field(1)?,
+            ignored: field(2)?,
+        })
+    }
+
+    fn since(&self, before: &IrqCount) -> IrqCount {
+        IrqCount {
+            injected: self.injected.saturating_sub(before.injected),
+            coalesced: self.coalesced.saturating_sub(before.coalesced),
+            ignored: self.ignored.saturating_sub(before.ignored),
+        }
+    }
+}
+
+/// Who raises `gsi`: a device of a vmsh session or a guess from the conventional pc layout.
+fn describe_gsi(gsi: u32, vmsh_devices: &[(Pid, String, u32)]) -> String {
+    let vmsh = vmsh_devices
+        .iter()
+        .filter(|(_, _, g)| *g == gsi)
+        .map(|(pid, device, _)| format!("vmsh {} {}", pid, device))
+        .collect::<Vec<_>>();
+    if !vmsh.is_empty() {
+        return vmsh.join(", ");
+    }
+    let legacy = match gsi {
+        0 | 2 => "timer",
+        1 => "keyboard",
+        3 => "serial ttyS1",
+        4 => "serial ttyS0",
+        8 => "rtc",
+        9 => "acpi",
+        12 => "mouse",
+        14 | 15 => "ide",
+        g if g < 16 => "isa device",
+        g if g < IOAPIC_PINS => "pci intx",
+        _ => "msi",
+    };
+    format!("hypervisor ({})", legacy)
+}
+
+fn per_second(count: u64, elapsed: Duration) -> u64 {
+    (count as f64 / elapsed.as_secs_f64()) as u64
+}
+
+/// One line per gsi that had interrupts, the busiest first. Returns the gsis above `threshold`.
+fn format_rates(
+    counts: &HashMap<u32, IrqCount>,
+    elapsed: Duration,
+    threshold: u64,
+    vmsh_devices: &[(Pid, String, u32)],
+) -> (String, Vec<u32>) {
+    let mut busy = counts
+        .iter()
+        .filter(|(_, c)| c.injected > 0)
+        .collect::<Vec<_>>();
+    busy.sort_by(|a, b| b.1.injected.cmp(&a.1.injected).then(a.0.cmp(b.0)));
+    let mut out = String::new();
+    let mut storms = vec![];
+    for (gsi, c) in busy {
+        let rate = per_second(c.injected, elapsed);
+        let status = if rate < threshold {
+            ""
+        } else if c.coalesced * 2 > c.injected {
+            "STORM, guest is not keeping up"
+        } else {
+            "STORM"
+        };
+        if rate >= threshold {
+            storms.push(*gsi);
+        }
+        out.push_str(&format!(
+            "{:>5} {:>10} {:>12} {:>10}  {:<30} {}\n",
+            gsi,
+            rate,
+            per_second(c.coalesced, elapsed),
+            per_second(c.ignored, elapsed),
+            describe_gsi(*gsi, vmsh_devices),
+            status
+        ));
+    }
+    (out, storms)
+}
+
+fn bpf_prog(pid: Pid) -> Result<BPF> {
+    let builder = try_with!(BPFBuilder::new(BPF_TEXT), "cannot compile bpf program");
+    let cflags = &[format!("-DTARGET_PID={}", pid)];
+    let builder_with_cflags = try_with!(builder.cflags(cflags), "could not pass cflags");
+    let mut module = try_with!(
+        builder_with_cflags.build(),
+        "build failed. This might happen if vmsh was started without root (or cap_sys_admin)"
+    );
+    for function in &["kvm_vm_ioctl", "kvm_set_irq", "kvm_arch_set_irq_inatomic"] {
+        try_with!(
+            Kprobe::new()
+                .handler(function)
+                .function(function)
+                .attach(&mut module),
+            "failed to install kprobe on {}",
+            function
+        );
+    }
+    for function in &["kvm_set_irq", "kvm_arch_set_irq_inatomic"] {
+        try_with!(
+            Kretprobe::new()
+                .handler("set_irq_return")
+                .function(function)
+                .attach(&mut module),
+            "failed to install kretprobe on {}",
+            function
+        );
+    }
+    Ok(module)
+}
+
+fn read_counts(module: &mut BPF) -> Result<HashMap<u32, IrqCount>> {
+    let table = try_with!(module.table("irqs"), "failed to get irq table");
+    let mut counts = HashMap::new();
+    for entry in table.iter() {
+        let gsi = match entry.key.get(..4) {
+            Some(key) => u32::from_le_bytes(key.try_into().unwrap()),
+            None => continue,
+        };
+        if let Some(count) = IrqCount::parse(&entry.value) {
+            counts.insert(gsi, count);
+        }
+    }
+    Ok(counts)
+}
+
+/// Masks the ioapic pins of storming vmsh devices and unmasks them again when their time is up.
+struct Masker {
+    duration: Duration,
+    /// Pins we masked and when to unmask them
+    masked: HashMap<u32, Instant>,
+}
+
+impl Masker {
+    fn mask(&mut self, vm: &Hypervisor, gsi: u32) -> Result<()> {
+        if gsi >= IOAPIC_PINS || self.masked.contains_key(&gsi) {
+            return Ok(());
+        }
+        let _stopped = vm.stop_guard()?;
+        if vm.set_ioapic_masked(gsi as usize, true)? {
+            // the guest masked it itself, leave it to the guest
+            return Ok(());
+        }
+        warn!("masked gsi {} for {:?}", gsi, self.duration);
+        self.masked.insert(gsi, Instant::now() + self.duration);
+        Ok(())
+    }
+
+    fn unmask_expired(&mut self, vm: &Hypervisor, all: bool) -> Result<()> {
+        let now = Instant::now();
+        let expired = self
+            .masked
+            .iter()
+            .filter(|(_, until)| all || **until <= now)
+            .map(|(gsi, _)| *gsi)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        let _stopped = vm.stop_guard()?;
+        for gsi in expired {
+            vm.set_ioapic_masked(gsi as usize, false)?;
+            self.masked.remove(&gsi);
+            info!("unmasked gsi {}", gsi);
+        }
+        Ok(())
+    }
+}
+
+fn watch_irqs(
+    vm: &Hypervisor,
+    opts: &IrqStormOptions,
+    masker: &mut Option<Masker>,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let mut module = bpf_prog(opts.pid)?;
+    {
+        // let the kprobe on kvm_vm_ioctl find the vm
+        let _stopped = vm.stop_guard()?;
+        try_with!(vm.check_extension(0), "cannot query kvm extensions");
+    }
+    info!(
+        "counting interrupts every {:?}, storms are above {}/s",
+        opts.interval, opts.threshold
+    );
+    let mut before = read_counts(&mut module)?;
+    let mut last = Instant::now();
+    while !cancel.is_cancelled() {
+        thread::sleep(opts.interval);
+        let now = read_counts(&mut module)?;
+        let elapsed = last.elapsed();
+        last = Instant::now();
+        let delta = now
+            .iter()
+            .map(|(gsi, c)| {
+                (
+                    *gsi,
+                    c.since(before.get(gsi).unwrap_or(&IrqCount::default())),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        before = now;
+
+        let vmsh_devices = gc::session_irqs(opts.pid)?;
+        let (lines, storms) = format_rates(&delta, elapsed, opts.threshold, &vmsh_devices);
+        println!(
+            "{:>5} {:>10} {:>12} {:>10}  {:<30}",
+            "gsi", "irqs/s", "coalesced/s", "ignored/s", "device"
+        );
+        println!("{}", lines);
+
+        if let Some(masker) = masker {
+            masker.unmask_expired(vm, false)?;
+            for gsi in storms {
+                if vmsh_devices.iter().any(|(_, _, g)| *g == gsi) {
+                    masker.mask(vm, gsi)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn irqstorm(opts: &IrqStormOptions) -> Result<()> {
+    if opts.threshold == 0 {
+        bail!("the storm threshold must be at least 1 interrupt per second");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let cancel = Cancellation::setup()?;
+    let mut masker = opts.mask.map(|duration| Masker {
+        duration,
+        masked: HashMap::new(),
+    });
+    let res = watch_irqs(&vm, opts, &mut masker, &cancel);
+    // never leave pins masked behind
+    if let Some(masker) = &mut masker {
+        if let Err(e) = masker.unmask_expired(&vm, true) {
+            warn!("cannot unmask interrupts: {}", e);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_count() {
+        let mut value = vec![];
+        for v in &[10u64, 4, 1] {
+            value.extend_from_slice(&v.to_le_bytes());
+        }
+        let count = IrqCount::parse(&value).unwrap();
+        assert_eq!(
+            count,
+            IrqCount {
+                injected: 10,
+                coalesced: 4,
+                ignored: 1
+            }
+        );
+        assert_eq!(IrqCount::parse(&value[..16]), None);
+        let before = IrqCount {
+            injected: 4,
+            coalesced: 4,
+            ignored: 0,
+        };
+        assert_eq!(
+            count.since(&before),
+            IrqCount {
+                injected: 6,
+                coalesced: 0,
+                ignored: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_rates() {
+        let vmsh = vec![(Pid::from_raw(42), String::from("block"), 5)];
+        assert_eq!(describe_gsi(5, &vmsh), "vmsh 42 block");
+        assert_eq!(describe_gsi(4, &vmsh), "hypervisor (serial ttyS0)");
+        assert_eq!(describe_gsi(30, &vmsh), "hypervisor (msi)");
+
+        let mut counts = HashMap::new();
+        counts.insert(
+            5,
+            IrqCount {
+                injected: 30000,
+                coalesced: 20000,
+                ignored: 0,
+            },
+        );
+        counts.insert(
+            30,
+            IrqCount {
+                injected: 200,
+                coalesced: 0,
+                ignored: 0,
+            },
+        );
+        counts.insert(4, IrqCount::default());
+        let (out, storms) = format_rates(&counts, Duration::from_secs(2), 10000, &vmsh);
+        assert_eq!(storms, vec![5]);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("    5      15000        10000          0  vmsh 42 block"));
+        assert!(lines[0].ends_with("STORM, guest is not keeping up"));
+        assert!(lines[1].starts_with("   30        100            0          0  hypervisor (msi)"));
+    }
+}
@@ -0,0 +1,349 @@
+//! Minimal json values for the control api of `vmsh daemon`. The other json output of vmsh is
+//! formatted directly with `json_escape` and does not need to be parsed.
+use simple_error::{bail, require_with, try_with};
+use std::fmt;
+
+use crate::result::Result;
+
+/// Nesting depth at which parsing gives up, so that a request cannot overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Content of a json string literal for `s`, without the quotes.
+pub fn json_escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32).chars().collect(),
+            c => vec![c],
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys in the order of the input
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Member `key` of an object, `None` for other values.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Numbers without fraction that fit into an u64.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= u64::MAX as f64 => {
+                Some(*n as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Number(n as f64)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if !n.is_finite() => write!(f, "null"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "\"{}\"", json_escape(s)),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "\"{}\":{}", json_escape(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Object with the members in the given order.
+pub fn object(members: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        members
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches(&[' ', '\t', '\n', '\r'][..]).len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<()> {
+        if !self.input[self.pos..].starts_with(literal) {
+            bail!("expected '{}' at offset {}", literal, self.pos);
+        }
+        self.pos += literal.len();
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("json is nested deeper than {} levels", MAX_DEPTH);
+        }
+        self.skip_whitespace();
+        let value = match require_with!(self.peek(), "unexpected end of json") {
+            b'n' => self.expect("null").map(|_| Value::Null)?,
+            b't' => self.expect("true").map(|_| Value::Bool(true))?,
+            b'f' => self.expect("false").map(|_| Value::Bool(false))?,
+            b'"' => Value::String(self.string()?),
+            b'[' => self.array(depth)?,
+            b'{' => self.object(depth)?,
+            b'-' | b'0'..=b'9' => self.number()?,
+            c => bail!("unexpected '{}' at offset {}", c as char, self.pos),
+        };
+        self.skip_whitespace();
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let len = self.input[self.pos..]
+            .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            .unwrap_or(self.input.len() - self.pos);
+        let s = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(Value::Number(try_with!(
+            s.parse::<f64>(),
+            "invalid number '{}'",
+            s
+        )))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = require_with!(
+            self.input.get(self.pos..self.pos + 4),
+            "unexpected end of json"
+        );
+        self.pos += 4;
+        Ok(try_with!(
+            u32::from_str_radix(digits, 16),
+            "invalid escape \\u{}",
+            digits
+        ))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            let c = require_with!(self.input[self.pos..].chars().next(), "unterminated string");
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = require_with!(self.peek(), "unterminated string");
+                    self.pos += 1;
+                    s.push(match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // characters outside the basic plane are escaped as surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect("\\u")?;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    bail!("invalid surrogate pair at offset {}", self.pos);
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            require_with!(
+                                char::from_u32(code),
+                                "invalid escape at offset {}",
+                                self.pos
+                            )
+                        }
+                        c => bail!("invalid escape \\{}", c as char),
+                    });
+                }
+                c if (c as u32) < 0x20 => bail!("control character in string"),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value> {
+        self.expect("[")?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => bail!("expected ',' or ']' at offset {}", self.pos),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value> {
+        self.expect("{")?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            members.push((key, self.value(depth + 1)?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => bail!("expected ',' or '}}' at offset {}", self.pos),
+            }
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Value> {
+    let mut parser = Parser { input, pos: 0 };
+    let value = parser.value(0)?;
+    if parser.pos != input.len() {
+        bail!("trailing characters at offset {}", parser.pos);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let v =
+            parse(r#" {"a": [1, -2.5e1, true, null], "b": "x\"\u00e4\ud83d\ude00\n"} "#).unwrap();
+        assert_eq!(
+            v.get("a").unwrap(),
+            &Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-25.0),
+                Value::Bool(true),
+                Value::Null
+            ])
+        );
+        assert_eq!(v.get("b").unwrap().as_str(), Some("x\"ä😀\n"));
+        assert_eq!(parse(&v.to_string()).unwrap(), v);
+        assert_eq!(parse("{}").unwrap(), Value::Object(vec![]));
+        assert_eq!(parse("[ ]").unwrap(), Value::Array(vec![]));
+        for invalid in &["", "{", "[1,]", "{\"a\" 1}", "\"\\ud800\"", "1 2", "nul"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+    }
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+        assert_eq!(
+            parse(&format!("\"{}\"", json_escape("x\"\t")))
+                .unwrap()
+                .as_str(),
+            Some("x\"\t")
+        );
+    }
+
+    #[test]
+    fn test_as_u64() {
+        assert_eq!(Value::Number(42.0).as_u64(), Some(42));
+        assert_eq!(Value::Number(1.5).as_u64(), None);
+        assert_eq!(Value::Number(-1.0).as_u64(), None);
+        assert_eq!(Value::from("1").as_u64(), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Coredumps in the layout of /proc/vmcore of a kdump kernel, so that crash and makedumpfile can
+//! analyze guests captured by vmsh, see `vmsh coredump --format kdump`.
+//!
+//! Such a vmcore differs from the default coredump in two ways: the virtual address of each load
+//! segment is the address of its memory in the direct mapping of the guest kernel, and the notes
+//! only contain the NT_PRSTATUS of each vcpu and a VMCOREINFO note. VMCOREINFO describes the
+//! layout of the kernel (release, phys_base, KASLR offset, struct offsets) to the tools. Kernels
+//! built with CONFIG_CRASH_CORE, which kdump needs anyway, prepare it at boot in a page that
+//! starts with `OSRELEASE=`. vmsh searches guest memory for this page and appends the CRASHTIME,
+//! like the kernel does when it panics.
+use log::{debug, info};
+use simple_error::{bail, require_with};
+
+use crate::guest_access::GuestAccess;
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+use crate::tracer::proc::Mapping;
+use crate::vmi::KernelMemory;
+
+/// Base of the direct mapping without CONFIG_RANDOMIZE_MEMORY with 4- and 5-level paging.
+const DEFAULT_PAGE_OFFSET: usize = 0xffff_8880_0000_0000;
+const DEFAULT_PAGE_OFFSET_L5: usize = 0xff11_0000_0000_0000;
+/// Guest memory is searched in chunks of this size.
+const SCAN_CHUNK_SIZE: usize = 1024 * 1024;
+
+pub struct Vmcore {
+    /// Content of the VMCOREINFO note.
+    pub info: String,
+    /// Virtual address of guest physical address 0.
+    pub page_offset: usize,
+}
+
+/// VMCOREINFO if `page` starts with it. The kernel allocates it as zeroed page, the text ends at
+/// the first NUL.
+fn parse_vmcoreinfo_page(page: &[u8]) -> Option<String> {
+    if !page.starts_with(b"OSRELEASE=") {
+        return None;
+    }
+    let len = page.iter().position(|b| *b == 0)?;
+    let info = std::str::from_utf8(&page[..len]).ok()?;
+    if !info.ends_with('\n') || !info.lines().any(|l| l.starts_with("PAGESIZE=")) {
+        return None;
+    }
+    Some(info.to_string())
+}
+
+/// Value of a `KEY=value` line.
+pub fn vmcoreinfo_value<'a>(info: &'a str, key: &str) -> Option<&'a str> {
+    info.lines().find_map(|l| {
+        let (k, v) = l.split_once('=')?;
+        if k == key {
+            Some(v)
+        } else {
+            None
+        }
+    })
+}
+
+fn find_vmcoreinfo(
+    src: &dyn GuestAccess,
+    maps: &[Mapping],
+    cancel: &Cancellation,
+) -> Result<String> {
+    let mut buf = vec![0u8; SCAN_CHUNK_SIZE];
+    for m in maps {
+        let mut pos = m.start;
+        while pos < m.end {
+            cancel.check()?;
+            let n = std::cmp::min(buf.len(), m.end - pos);
+            if let Err(e) = src.read_bytes(pos, &mut buf[..n]) {
+                debug!("skip {:#x}-{:#x}: {}", pos, pos + n, e);
+                pos += n;
+                continue;
+            }
+            for (i, page) in buf[..n].chunks(page_size()).enumerate() {
+                if let Some(info) = parse_vmcoreinfo_page(page) {
+                    info!(
+                        "found vmcoreinfo at physical address {:#x}",
+                        m.phys_addr + (pos - m.start) + i * page_size()
+                    );
+                    return Ok(info);
+                }
+            }
+            pos += n;
+        }
+    }
+    bail!("guest memory contains no vmcoreinfo, is the guest kernel built with CONFIG_CRASH_CORE?")
+}
+
+/// Read from `page_offset_base`, which only exists with CONFIG_RANDOMIZE_MEMORY.
+fn page_offset(src: &dyn GuestAccess, info: &str) -> Result<usize> {
+    let kernel = KernelMemory::new(src)?;
+    if let Ok(addr) = kernel.symbol("page_offset_base") {
+        return Ok(kernel.read_u64(addr)? as usize);
+    }
+    if vmcoreinfo_value(info, "NUMBER(pgtable_l5_enabled)") == Some("1") {
+        Ok(DEFAULT_PAGE_OFFSET_L5)
+    } else {
+        Ok(DEFAULT_PAGE_OFFSET)
+    }
+}
+
+impl Vmcore {
+    /// Requires the guest to be stopped.
+    pub fn new(
+        src: &dyn GuestAccess,
+        maps: &[Mapping],
+        crash_time: u64,
+        cancel: &Cancellation,
+    ) -> Result<Vmcore> {
+        let mut info = find_vmcoreinfo(src, maps, cancel)?;
+        let page_size = require_with!(
+            vmcoreinfo_value(&info, "PAGESIZE"),
+            "vmcoreinfo has no PAGESIZE"
+        );
+        if page_size != page_size().to_string() {
+            bail!("guest page size {} is not supported", page_size);
+        }
+        let page_offset = page_offset(src, &info)?;
+        info.push_str(&format!("CRASHTIME={}\n", crash_time));
+        Ok(Vmcore { info, page_offset })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: &str = "OSRELEASE=5.15.0\nPAGESIZE=4096\nNUMBER(phys_base)=-2147483648\n\
+                        KERNELOFFSET=1e000000\n";
+
+    #[test]
+    fn test_parse_vmcoreinfo_page() {
+        let mut page = vec![0u8; 4096];
+        page[..INFO.len()].copy_from_slice(INFO.as_bytes());
+        assert_eq!(parse_vmcoreinfo_page(&page).as_deref(), Some(INFO));
+        page[0] = b'o';
+        assert_eq!(parse_vmcoreinfo_page(&page), None);
+        // a string that merely starts like vmcoreinfo
+        let mut page = vec![0u8; 4096];
+        page[..20].copy_from_slice(b"OSRELEASE=%s\nfoo\n\0\0\0");
+        assert_eq!(parse_vmcoreinfo_page(&page), None);
+    }
+
+    #[test]
+    fn test_vmcoreinfo_value() {
+        assert_eq!(vmcoreinfo_value(INFO, "OSRELEASE"), Some("5.15.0"));
+        assert_eq!(vmcoreinfo_value(INFO, "KERNELOFFSET"), Some("1e000000"));
+        assert_eq!(
+            vmcoreinfo_value(INFO, "NUMBER(phys_base)"),
+            Some("-2147483648")
+        );
+        assert_eq!(vmcoreinfo_value(INFO, "CRASHTIME"), None);
+    }
+}
@@ -5,11 +5,11 @@ use std::collections::HashMap;
 use std::ffi::CStr;
 use std::mem::{self, size_of};
 use std::ops::Range;
-use vm_memory::remote_mem::process_read_bytes;
 
+use crate::guest_access::GuestAccess;
 use crate::guest_mem::{GuestMem, MappedMemory};
-use crate::kvm::hypervisor::Hypervisor;
 use crate::result::Result;
+use crate::symbolizer::SymbolSource;
 
 /// Kernel range on x86_64
 pub const LINUX_KERNEL_KASLR_RANGE: Range<usize> = 0xFFFFFFFF80000000..0xFFFFFFFFC0000000;
@@ -184,9 +184,29 @@ impl Kernel {
     pub fn space_after(&self) -> usize {
         LINUX_KERNEL_KASLR_RANGE.end - self.range.end
     }
+
+    /// Approximate the address range of a symbol by the start of the next known symbol.
+    pub fn symbol_range(&self, name: &str) -> Option<Range<usize>> {
+        let start = *self.symbols.get(name)?;
+        let end = self.symbols.values().filter(|a| **a > start).min()?;
+        Some(start..*end)
+    }
+
+    /// Guest physical address of a virtual address in the kernel image.
+    pub fn virt_to_phys(&self, addr: usize) -> Option<usize> {
+        self.memory_sections
+            .iter()
+            .find(|s| s.virt_start <= addr && addr < s.virt_start + s.len)
+            .map(|s| s.phys_start.value + (addr - s.virt_start))
+    }
 }
 
-pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
+/// `fallbacks` are tried in order if the exported symbols cannot be found in guest memory.
+pub fn find_kernel(
+    guest_mem: &GuestMem,
+    hv: &dyn GuestAccess,
+    fallbacks: &[SymbolSource],
+) -> Result<Kernel> {
     let memory_sections = try_with!(
         guest_mem.find_kernel_sections(hv, LINUX_KERNEL_KASLR_RANGE),
         "could not find Linux kernel in VM memory"
@@ -203,8 +223,7 @@ pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
             return None;
         }
         let mut mem = vec![0; s.len];
-        let mem_base = s.phys_start.host_addr() as *const libc::c_void;
-        if let Err(e) = process_read_bytes(hv.pid, &mut mem, mem_base) {
+        if let Err(e) = hv.read_bytes(s.phys_start.host_addr(), &mut mem) {
             return Some(Err(SimpleError::new(format!(
                 "failed to read linux kernel from hypervisor memory: {}",
                 e
@@ -231,10 +250,38 @@ pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
         }
     });
 
-    let symbols = require_with!(symbols, "could not find section with kernel symbols")?;
+    let symbols = match symbols {
+        Some(Ok(symbols)) if !symbols.is_empty() => symbols,
+        Some(Err(e)) => SymbolSource::fallback_symbols(fallbacks, kernel_start, &e.to_string())?,
+        _ => SymbolSource::fallback_symbols(
+            fallbacks,
+            kernel_start,
+            "could not find section with kernel symbols",
+        )?,
+    };
     Ok(Kernel {
         range: kernel_start..kernel_end,
         memory_sections,
         symbols,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_range() {
+        let mut symbols = HashMap::new();
+        symbols.insert(String::from("panic"), 0x1000);
+        symbols.insert(String::from("printk"), 0x1400);
+        let kernel = Kernel {
+            range: 0x1000..0x2000,
+            memory_sections: vec![],
+            symbols,
+        };
+        assert_eq!(kernel.symbol_range("panic"), Some(0x1000..0x1400));
+        assert_eq!(kernel.symbol_range("printk"), None);
+        assert_eq!(kernel.symbol_range("nmi_panic"), None);
+    }
+}
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use crate::{
+    gc::{self, Artifact},
     guest_mem::{GuestMem, MappedMemory},
     page_table::{estimate_page_table_size, VirtMem},
 };
@@ -13,6 +14,10 @@ use crate::{page_math, result::Result};
 
 use super::hypervisor::{memory::PhysMem, Hypervisor};
 
+/// Allocates guest physical memory for stage1 and mmio ranges for the devices, all of it while
+/// attaching. Every allocation is a memslot of its own. The datapath of the devices does not
+/// allocate: virtio descriptors point into guest ram, which the devices access in place through
+/// the hypervisor, so requests need no bounce buffers in guest memory.
 pub struct PhysMemAllocator {
     pub hv: Arc<Hypervisor>,
     /// Physical guest memory
@@ -111,10 +116,20 @@ impl VirtAlloc {
     }
 }
 
+/// Memory of `vmsh mem add` stays in the vm after vmsh exits. It was allocated from the top of
+/// the physical address space, so we continue below it. Hypervisors put guest ram at the bottom,
+/// we assume it does not reach into the upper half.
+fn below_leftover_memory(top: usize, phys_starts: impl Iterator<Item = usize>) -> usize {
+    phys_starts.filter(|s| *s >= top / 2).fold(top, usize::min)
+}
+
 impl PhysMemAllocator {
     pub fn new(hv: Arc<Hypervisor>) -> Result<Self> {
-        let next_allocation = get_first_allocation(&hv)?;
-        let guest_mem = GuestMem::new(&hv)?;
+        let guest_mem = GuestMem::new(hv.as_ref())?;
+        let next_allocation = below_leftover_memory(
+            get_first_allocation(&hv)?,
+            guest_mem.maps().iter().map(|m| m.phys_addr),
+        );
         Ok(Self {
             hv,
             guest_mem,
@@ -147,11 +162,38 @@ impl PhysMemAllocator {
         let padded_size = page_math::page_align(size);
         let start = self.reserve_range(padded_size)?;
         let res = self.hv.vm_add_mem(start as u64, padded_size, readonly);
+        if res.is_err() {
+            self.next_allocation = old_start;
+        } else {
+            gc::record(&Artifact::Memslot {
+                phys_addr: start,
+                size: padded_size,
+            });
+        }
+        res
+    }
+
+    /// Like `phys_alloc` but the start of the allocation is aligned to `align`, which must be a
+    /// power of two. Skipped space below the previous allocation is not reused.
+    pub fn phys_alloc_aligned(
+        &mut self,
+        size: usize,
+        align: usize,
+        readonly: bool,
+    ) -> Result<PhysMem<u8>> {
+        let old_start = self.next_allocation;
+        let padded_size = page_math::page_align(size);
+        let start =
+            require_with!(old_start.checked_sub(padded_size), "out of memory") & !(align - 1);
+        // phys_alloc allocates right below next_allocation
+        self.next_allocation = start + padded_size;
+        let res = self.phys_alloc(size, readonly);
         if res.is_err() {
             self.next_allocation = old_start;
         }
         res
     }
+
     pub fn virt_alloc(&mut self, alloc: &[VirtAlloc]) -> Result<VirtMem> {
         let len = alloc.iter().map(|a| a.len).sum();
         let phys_mem = self.phys_alloc(len + estimate_page_table_size(len), false)?;
@@ -184,3 +226,20 @@ impl PhysMemAllocator {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_below_leftover_memory() {
+        let top = 1 << 40;
+        let ram = vec![0, 1 << 20, 4 << 30];
+        assert_eq!(below_leftover_memory(top, ram.iter().copied()), top);
+        let leftover = vec![0, 4 << 30, top - (4 << 30), top - (2 << 30)];
+        assert_eq!(
+            below_leftover_memory(top, leftover.iter().copied()),
+            top - (4 << 30)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Dirty page tracking of memslots with `KVM_GET_DIRTY_LOG`, see
+//! `Hypervisor::enable_dirty_log`.
+//!
+//! KVM also offers a per-vcpu dirty ring, but it has to be enabled before the vcpus are created,
+//! so only the bitmap interface is usable for a vm we attach to. If the hypervisor already uses
+//! the dirty ring, `KVM_GET_DIRTY_LOG` fails with `ENXIO`.
+use std::ops::Range;
+
+use crate::page_math::page_size;
+
+/// Pages of one memslot that the guest wrote to.
+pub struct DirtyBitmap {
+    /// Guest physical address of the first page of the memslot
+    pub phys_start: usize,
+    /// Hypervisor address of the first page of the memslot
+    pub host_start: usize,
+    npages: usize,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// `bits` has one bit per page in the layout of KVM, bits beyond `npages` are ignored.
+    pub fn new(phys_start: usize, host_start: usize, npages: usize, bits: Vec<u64>) -> Self {
+        DirtyBitmap {
+            phys_start,
+            host_start,
+            npages,
+            bits,
+        }
+    }
+
+    pub fn is_dirty(&self, page: usize) -> bool {
+        page < self.npages
+            && self
+                .bits
+                .get(page / 64)
+                .map_or(false, |w| w & (1 << (page % 64)) != 0)
+    }
+
+    /// Number of dirty pages
+    pub fn count(&self) -> usize {
+        (0..self.npages).filter(|p| self.is_dirty(*p)).count()
+    }
+
+    /// Indices of the dirty pages within the memslot
+    pub fn dirty_pages(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.npages).filter(move |p| self.is_dirty(*p))
+    }
+
+    /// Consecutive dirty pages as ranges of guest physical memory, so that they can be read with
+    /// few syscalls.
+    pub fn dirty_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for page in self.dirty_pages() {
+            let start = self.phys_start + page * page_size();
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end += page_size(),
+                _ => ranges.push(start..start + page_size()),
+            }
+        }
+        ranges
+    }
+
+    /// Hypervisor address of a guest physical address within the memslot
+    pub fn host_addr(&self, phys_addr: usize) -> usize {
+        phys_addr - self.phys_start + self.host_start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_ranges() {
+        let p = page_size();
+        // pages 0, 1, 3 and 64 are dirty, 66 is outside of the slot
+        let bitmap = DirtyBitmap::new(0x10000, 0x7f0000, 65, vec![0b1011, 0b101]);
+        assert_eq!(bitmap.count(), 4);
+        assert!(bitmap.is_dirty(64));
+        assert!(!bitmap.is_dirty(66));
+        assert_eq!(bitmap.dirty_pages().collect::<Vec<_>>(), vec![0, 1, 3, 64]);
+        assert_eq!(
+            bitmap.dirty_ranges(),
+            vec![
+                0x10000..0x10000 + 2 * p,
+                0x10000 + 3 * p..0x10000 + 4 * p,
+                0x10000 + 64 * p..0x10000 + 65 * p
+            ]
+        );
+        assert_eq!(bitmap.host_addr(0x10000 + p), 0x7f0000 + p);
+
+        let clean = DirtyBitmap::new(0, 0, 64, vec![0]);
+        assert_eq!(clean.count(), 0);
+        assert!(clean.dirty_ranges().is_empty());
+    }
+}
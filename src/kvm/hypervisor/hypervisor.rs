@@ -1,15 +1,19 @@
 use crate::page_table::PhysAddr;
 use crate::tracer::inject_syscall;
 use kvm_bindings as kvmb;
-use libc::c_int;
+use libc::{c_int, c_ulong};
 use log::*;
+use nix::errno::Errno;
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
 use nix::unistd::Pid;
-use simple_error::{bail, try_with};
+use simple_error::{bail, require_with, try_with};
 use std::ffi::OsStr;
+use std::fs::File;
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
+use std::ptr;
 use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
@@ -17,12 +21,14 @@ use super::ioeventfd::IoEventFd;
 use super::ioregionfd::IoRegionFd;
 use super::memory::*;
 use crate::cpu;
+use crate::kvm::dirty_log::DirtyBitmap;
 use crate::kvm::fd_transfer;
 use crate::kvm::ioctls;
-use crate::kvm::tracee::{kvm_msrs, Tracee};
+use crate::kvm::memslots::MemSlot;
+use crate::kvm::tracee::{kvm_msr_array, kvm_msrs, Tracee, MAX_MSRS};
 use crate::page_math::{self, compute_host_offset};
 use crate::result::Result;
-use crate::tracer::proc::{openpid, Mapping, PidHandle};
+use crate::tracer::proc::{copy_fd, openpid, Mapping, PidHandle};
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
 #[allow(clippy::upper_case_acronyms)]
@@ -44,6 +50,23 @@ pub struct Hypervisor {
     pub wrapper: Mutex<Option<KvmRunWrapper>>,
 }
 
+/// See `Hypervisor::stop_guard`.
+#[must_use = "the guest is resumed as soon as the guard is dropped"]
+pub struct StopGuard<'a> {
+    vm: &'a Hypervisor,
+}
+
+impl<'a> Drop for StopGuard<'a> {
+    fn drop(&mut self) {
+        // a panic while the tracee was locked must not keep the guest stopped
+        let mut tracee = match self.vm.tracee.write() {
+            Ok(tracee) => tracee,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = tracee.detach();
+    }
+}
+
 impl Hypervisor {
     fn attach(pid: Pid, vm_fd: RawFd) -> Tracee {
         Tracee::new(pid, vm_fd, None)
@@ -77,6 +100,13 @@ impl Hypervisor {
         Ok(())
     }
 
+    /// Stop the guest until the returned guard is dropped. Unlike pairing `stop` with `resume`,
+    /// the guest is also resumed and ptrace detached on early returns and panics.
+    pub fn stop_guard(&self) -> Result<StopGuard> {
+        self.stop()?;
+        Ok(StopGuard { vm: self })
+    }
+
     pub fn tracee_write_guard(&self) -> Result<RwLockWriteGuard<Tracee>> {
         let twg: RwLockWriteGuard<Tracee> = try_with!(
             self.tracee.write(),
@@ -163,6 +193,164 @@ impl Hypervisor {
         tracee.get_vcpu_maps()
     }
 
+    /// Read guest memory at the physical address `gpa`.
+    pub fn read_guest_phys(&self, gpa: usize, buf: &mut [u8]) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.read_guest_phys(gpa, buf)
+    }
+
+    /// Write `data` to guest memory at the physical address `gpa`. The guest should be stopped,
+    /// otherwise it might see a partial write.
+    pub fn write_guest_phys(&self, gpa: usize, data: &[u8]) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.write_guest_phys(gpa, data)
+    }
+
+    /// Read guest memory at the virtual address `gva` of the page tables in `sregs.cr3`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn read_guest_virt(
+        &self,
+        sregs: &kvmb::kvm_sregs,
+        gva: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.read_guest_virt(sregs, gva, buf)
+    }
+
+    /// Like `write_guest_phys`, but at the virtual address `gva` of the page tables in
+    /// `sregs.cr3`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn write_guest_virt(&self, sregs: &kvmb::kvm_sregs, gva: usize, data: &[u8]) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.write_guest_virt(sregs, gva, data)
+    }
+
+    /// Host address of the guest virtual address `gva` of the page tables in `sregs.cr3`. It
+    /// stays valid until the guest changes its page tables or the hypervisor its memslots.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn guest_virt_to_host(&self, sregs: &kvmb::kvm_sregs, gva: usize) -> Result<usize> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.guest_virt_to_host(sregs, gva)
+    }
+
+    /// Like `get_maps` but with the ids and flags of the memslots, which are needed for dirty
+    /// logging. Requires bcc.
+    pub fn get_memslots(&self) -> Result<Vec<MemSlot>> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.get_memslots()
+    }
+
+    fn set_memslot_flags(&self, slot: &MemSlot, flags: u32) -> Result<()> {
+        let arg = kvmb::kvm_userspace_memory_region {
+            slot: slot.id(),
+            flags,
+            guest_phys_addr: slot.physical_start() as u64,
+            memory_size: slot.size() as u64,
+            userspace_addr: slot.start() as u64,
+        };
+        self.ioctl_with_copy(None, ioctls::KVM_SET_USER_MEMORY_REGION(), &arg)?;
+        Ok(())
+    }
+
+    /// Let kvm track the pages the guest writes to in `slot`. We refuse to touch slots the
+    /// hypervisor logs itself, i.e. during live migration, as we would steal its dirty bits.
+    pub fn enable_dirty_log(&self, slot: &MemSlot) -> Result<()> {
+        if slot.flags() & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0 {
+            bail!("hypervisor already tracks dirty pages of memslot {}", slot);
+        }
+        try_with!(
+            self.set_memslot_flags(slot, slot.flags() | kvmb::KVM_MEM_LOG_DIRTY_PAGES),
+            "cannot enable dirty logging for memslot {}",
+            slot
+        );
+        Ok(())
+    }
+
+    /// `slot` must be the memslot as it was before `enable_dirty_log`.
+    pub fn disable_dirty_log(&self, slot: &MemSlot) -> Result<()> {
+        try_with!(
+            self.set_memslot_flags(slot, slot.flags() & !kvmb::KVM_MEM_LOG_DIRTY_PAGES),
+            "cannot disable dirty logging for memslot {}",
+            slot
+        );
+        Ok(())
+    }
+
+    /// Pages of `slot` written since dirty logging was enabled or since the last call. The
+    /// returned pages are write protected again, so their content must be read after this
+    /// returns. If the hypervisor enabled KVM_DIRTY_LOG_INITIALLY_SET, the first bitmap contains
+    /// all pages.
+    pub fn get_dirty_log(&self, slot: &MemSlot) -> Result<DirtyBitmap> {
+        let len = ((slot.npages() + 63) / 64) * size_of::<u64>();
+        let bitmap = self.alloc_mem_padded::<u64>(len)?;
+        let arg = ioctls::kvm_dirty_log {
+            slot: slot.id(),
+            padding1: 0,
+            dirty_bitmap: bitmap.ptr as u64,
+        };
+        try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_GET_DIRTY_LOG(), &arg),
+            "cannot get dirty log of memslot {}",
+            slot
+        );
+        // Hypervisors that enable KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 make KVM_GET_DIRTY_LOG keep
+        // the bits, otherwise clearing them again is a no-op.
+        if self.check_extension(ioctls::KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2)? > 0 {
+            let arg = ioctls::kvm_clear_dirty_log {
+                slot: slot.id(),
+                num_pages: slot.npages() as u32,
+                first_page: 0,
+                dirty_bitmap: bitmap.ptr as u64,
+            };
+            try_with!(
+                self.ioctl_with_copy(None, ioctls::KVM_CLEAR_DIRTY_LOG(), &arg),
+                "cannot clear dirty log of memslot {}",
+                slot
+            );
+        }
+        let mut bits = vec![0u64; len / size_of::<u64>()];
+        let local = unsafe { std::slice::from_raw_parts_mut(bits.as_mut_ptr() as *mut u8, len) };
+        let read = try_with!(
+            process_vm_readv(
+                self.pid,
+                &[IoVec::from_mut_slice(local)],
+                &[RemoteIoVec {
+                    base: bitmap.ptr,
+                    len,
+                }],
+            ),
+            "cannot read dirty bitmap"
+        );
+        if read != len {
+            bail!("short read of dirty bitmap: {} of {} bytes", read, len);
+        }
+        Ok(DirtyBitmap::new(
+            slot.physical_start(),
+            slot.start(),
+            slot.npages(),
+            bits,
+        ))
+    }
+
     /// `readonly`: If true, a guest writing to it leads to KVM_EXIT_MMIO.
     ///
     /// Safety: This function is safe even for the guest because VmMem enforces, that only the
@@ -207,6 +395,33 @@ impl Hypervisor {
         })
     }
 
+    /// Remove a memslot that outlived the `PhysMem` it was created with, i.e. by `vmsh mem add`,
+    /// and unmap its memory from the hypervisor.
+    pub fn vm_remove_mem(&self, slot: &MemSlot) -> Result<()> {
+        let arg = kvmb::kvm_userspace_memory_region {
+            slot: slot.id(),
+            flags: slot.flags(),
+            guest_phys_addr: slot.physical_start() as u64,
+            memory_size: 0, // indicates request for deletion
+            userspace_addr: slot.start() as u64,
+        };
+        try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_SET_USER_MEMORY_REGION(), &arg),
+            "cannot remove memslot {}",
+            slot
+        );
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        try_with!(
+            tracee.munmap(slot.start() as *mut libc::c_void, slot.size()),
+            "cannot unmap memory of memslot {}",
+            slot
+        );
+        Ok(())
+    }
+
     pub fn alloc_mem<T: Copy>(&self) -> Result<HvMem<T>> {
         self.alloc_mem_padded::<T>(size_of::<T>())
     }
@@ -302,6 +517,12 @@ impl Hypervisor {
 
     /// param `gsi`: pin on the irqchip to be toggled by fd events
     pub fn irqfd(&self, gsi: u32) -> Result<EventFd> {
+        Ok(self.irqfd_with_remote(gsi)?.0)
+    }
+
+    /// Like `irqfd`, but also returns the file descriptor of the eventfd in the hypervisor, so
+    /// that the irqfd can be removed again with `remove_irqfd`.
+    pub fn irqfd_with_remote(&self, gsi: u32) -> Result<(EventFd, RawFd)> {
         let eventfd = try_with!(EventFd::new(EFD_NONBLOCK), "cannot create event fd");
         info!("irqfd {:?}, interupt gsi/nr {:?}", eventfd.as_raw_fd(), gsi);
         let hv_eventfd = self.transfer(vec![eventfd.as_raw_fd()].as_slice())?[0];
@@ -329,7 +550,59 @@ impl Hypervisor {
             bail!("cannot register KVM_IRQFD via ioctl: {:?}", ret);
         }
 
-        Ok(eventfd)
+        Ok((eventfd, hv_eventfd))
+    }
+
+    /// Stops kvm from injecting `gsi` on events of `remote_fd` and closes it in the hypervisor.
+    pub fn remove_irqfd(&self, remote_fd: RawFd, gsi: u32) -> Result<()> {
+        let irqfd = kvmb::kvm_irqfd {
+            fd: remote_fd as u32,
+            gsi,
+            flags: kvmb::KVM_IRQFD_FLAG_DEASSIGN,
+            resamplefd: 0,
+            ..Default::default()
+        };
+        let mem = self.alloc_mem()?;
+        mem.write(&irqfd)?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let ret = try_with!(
+            tracee.vm_ioctl_with_ref(ioctls::KVM_IRQFD(), &mem),
+            "kvm irqfd ioctl injection failed"
+        );
+        if ret != 0 {
+            bail!("cannot remove KVM_IRQFD via ioctl: {:?}", ret);
+        }
+        try_with!(
+            tracee.close(remote_fd),
+            "cannot close irqfd {} in hypervisor",
+            remote_fd
+        );
+        Ok(())
+    }
+
+    /// Set `gsi` of the in-kernel irqchip to `level`, see KVM_IRQ_LINE. Unlike an irqfd this
+    /// needs no file descriptor in the hypervisor, but fails if it emulates the ioapic itself
+    /// (i.e. qemu's `kernel-irqchip=split`).
+    pub fn set_irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        let irq_level = kvmb::kvm_irq_level {
+            __bindgen_anon_1: kvmb::kvm_irq_level__bindgen_ty_1 { irq: gsi },
+            level: level as u32,
+        };
+        try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_IRQ_LINE(), &irq_level),
+            "cannot set level of gsi {}, the hypervisor may not use an in-kernel ioapic",
+            gsi
+        );
+        Ok(())
+    }
+
+    /// Raise and lower `gsi`, which triggers edge- as well as level-triggered interrupts once.
+    pub fn assert_irq(&self, gsi: u32) -> Result<()> {
+        self.set_irq_line(gsi, true)?;
+        self.set_irq_line(gsi, false)
     }
 
     pub fn userfaultfd(&self) -> Result<c_int> {
@@ -349,6 +622,33 @@ impl Hypervisor {
         Ok(-1)
     }
 
+    /// Give the kernel advice about hypervisor memory at `addr`, see madvise(2).
+    pub fn madvise(&self, addr: usize, length: usize, advice: c_int) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.madvise(addr as *mut libc::c_void, length, advice)
+    }
+
+    /// Binary statistics of the vm or, if given, of `vcpu`, see KVM_GET_STATS_FD. The file
+    /// descriptor is opened in the hypervisor and moved to vmsh, so reading it afterwards does
+    /// not need the hypervisor to be stopped.
+    pub fn stats_fd(&self, vcpu: Option<&VCPU>) -> Result<File> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let hv_fd = tracee.get_stats_fd(vcpu)?;
+        let file = copy_fd(self.pid, hv_fd);
+        match tracee.close(hv_fd) {
+            Ok(0) => {}
+            Ok(ret) => warn!("cannot close stats fd in hypervisor: {}", ret),
+            Err(e) => warn!("cannot close stats fd in hypervisor: {}", e),
+        }
+        file
+    }
+
     pub fn check_extension(&self, cap: c_int) -> Result<c_int> {
         let tracee = try_with!(
             self.tracee.read(),
@@ -357,6 +657,31 @@ impl Hypervisor {
         tracee.check_extension(cap)
     }
 
+    /// Run an ioctl with a pointer to a copy of `arg` on the vm or, if given, on `vcpu`. Returns
+    /// the result of the ioctl and the argument after the kernel is done with it.
+    pub fn ioctl_with_copy<T: Copy>(
+        &self,
+        vcpu: Option<&VCPU>,
+        request: c_ulong,
+        arg: &T,
+    ) -> Result<(c_int, T)> {
+        let mem = self.alloc_mem()?;
+        mem.write(arg)?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        let ret = match vcpu {
+            Some(vcpu) => tracee.vcpu_ioctl_with_ref(vcpu, request, &mem)?,
+            None => tracee.vm_ioctl_with_ref(request, &mem)?,
+        };
+        if ret < 0 {
+            bail!("ioctl {:#x} failed: {}", request, Errno::from_i32(-ret));
+        }
+        drop(tracee);
+        Ok((ret, mem.read()?))
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_cpuid2(&self, vcpu: &VCPU) -> Result<ioctls::kvm_cpuid2> {
         let mem = self.alloc_mem()?;
@@ -394,6 +719,18 @@ impl Hypervisor {
         tracee.get_sregs(vcpu, &mem)
     }
 
+    /// The vcpu must not be running, i.e. the vm must be stopped.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_sregs(&self, vcpu: &VCPU, sregs: &kvmb::kvm_sregs) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        mem.write(sregs)?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.set_sregs(vcpu, &mem)
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_regs(&self, vcpu: &VCPU) -> Result<cpu::Regs> {
         let mem = self.alloc_mem()?;
@@ -445,6 +782,143 @@ impl Hypervisor {
         tracee.get_fpu_regs(vcpu, &mem)
     }
 
+    /// Inverse of `get_fpu_regs`, `mxcsr_mask` is read-only and ignored.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_fpu_regs(&self, vcpu: &VCPU, regs: &cpu::FpuRegs) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        let fpu = kvmb::kvm_fpu {
+            fpr: unsafe { ptr::read(&regs.st_space as *const [u32; 32] as *const [[u8; 16]; 8]) },
+            fcw: regs.cwd,
+            fsw: regs.swd,
+            ftwx: regs.twd as u8,
+            last_opcode: regs.fop,
+            last_ip: regs.rip,
+            last_dp: regs.rdp,
+            xmm: unsafe { ptr::read(&regs.xmm_space as *const [u32; 64] as *const [[u8; 16]; 16]) },
+            mxcsr: regs.mxcsr,
+            ..Default::default()
+        };
+        mem.write(&fpu)?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.set_fpu_regs(vcpu, &mem)
+    }
+
+    /// XSAVE area of the vcpu with the x87, sse and avx state. Needs KVM_CAP_XSAVE.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xsave(&self, vcpu: &VCPU) -> Result<kvmb::kvm_xsave> {
+        let arg = kvmb::kvm_xsave::default();
+        let (_, xsave) = self.ioctl_with_copy(Some(vcpu), ioctls::KVM_GET_XSAVE(), &arg)?;
+        Ok(xsave)
+    }
+
+    /// XCR0 of the vcpu, i.e. the state components saved by XSAVE. Needs KVM_CAP_XCRS.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xcr0(&self, vcpu: &VCPU) -> Result<u64> {
+        let arg = kvmb::kvm_xcrs::default();
+        let (_, xcrs) = self.ioctl_with_copy(Some(vcpu), ioctls::KVM_GET_XCRS(), &arg)?;
+        let count = std::cmp::min(xcrs.nr_xcrs as usize, xcrs.xcrs.len());
+        let xcr0 = xcrs.xcrs[..count].iter().find(|x| x.xcr == 0);
+        Ok(require_with!(xcr0, "kvm returned no xcr0").value)
+    }
+
+    /// Redirection table of the ioapic of the in-kernel irqchip, one entry per pin.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_ioapic_redirtbl(&self) -> Result<Vec<u64>> {
+        let arg = kvmb::kvm_irqchip {
+            chip_id: kvmb::KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        let (_, chip) = try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_GET_IRQCHIP(), &arg),
+            "cannot read ioapic state, does the vm use the in-kernel irqchip?"
+        );
+        // the kernel fills in the ioapic variant for KVM_IRQCHIP_IOAPIC
+        let ioapic = unsafe { chip.chip.ioapic };
+        Ok(ioapic
+            .redirtbl
+            .iter()
+            .map(|entry| unsafe { entry.bits })
+            .collect())
+    }
+
+    /// Set the mask bit of `pin` in the ioapic of the in-kernel irqchip and return its previous
+    /// value. The guest should be stopped, so that it does not reprogram the pin meanwhile.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_ioapic_masked(&self, pin: usize, masked: bool) -> Result<bool> {
+        const IOAPIC_MASKED: u64 = 1 << 16;
+        let arg = kvmb::kvm_irqchip {
+            chip_id: kvmb::KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        let (_, mut chip) = try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_GET_IRQCHIP(), &arg),
+            "cannot read ioapic state, does the vm use the in-kernel irqchip?"
+        );
+        let mut ioapic = unsafe { chip.chip.ioapic };
+        let entry = match ioapic.redirtbl.get_mut(pin) {
+            Some(entry) => entry,
+            None => bail!("ioapic has no pin {}", pin),
+        };
+        let bits = unsafe { entry.bits };
+        let was_masked = bits & IOAPIC_MASKED != 0;
+        if was_masked == masked {
+            return Ok(was_masked);
+        }
+        entry.bits = if masked {
+            bits | IOAPIC_MASKED
+        } else {
+            bits & !IOAPIC_MASKED
+        };
+        chip.chip.ioapic = ioapic;
+        try_with!(
+            self.ioctl_with_copy(None, ioctls::KVM_SET_IRQCHIP(), &chip),
+            "cannot write ioapic state"
+        );
+        Ok(was_masked)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn nmi(&self, vcpu: &VCPU) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.nmi(vcpu)
+    }
+
+    /// Set the KVM_GUESTDBG_* flags and debug registers of `vcpu`. A `control` of 0 disables
+    /// guest debugging again.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_guest_debug(&self, vcpu: &VCPU, debug: &kvmb::kvm_guest_debug) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        mem.write(debug)?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.set_guest_debug(vcpu, &mem)
+    }
+
+    /// Run `vcpu` from vmsh until its next exit and return its kvm_run structure. The vm must be
+    /// stopped, the exit is not seen by the hypervisor.
+    pub fn run_vcpu(&self, vcpu: &VCPU) -> Result<kvmb::kvm_run> {
+        let map = require_with!(
+            self.vcpu_maps.get(vcpu.idx),
+            "no kvm_run mapping for vcpu {}",
+            vcpu.idx
+        );
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.run(vcpu)?;
+        drop(tracee);
+        process_read(self.pid, map.start as *const libc::c_void)
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_msr(&self, vcpu: &VCPU, msr: &kvmb::kvm_msr_entry) -> Result<kvmb::kvm_msr_entry> {
         let mem = self.alloc_mem()?;
@@ -462,6 +936,59 @@ impl Hypervisor {
         );
         tracee.get_msr(vcpu, &mem)
     }
+
+    /// Read the msrs with the given indices. Msrs that kvm does not know are left out of the
+    /// result.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_msrs(&self, vcpu: &VCPU, indices: &[u32]) -> Result<Vec<kvmb::kvm_msr_entry>> {
+        let mem = self.alloc_mem::<kvm_msr_array>()?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let mut found = vec![];
+        let mut pos = 0;
+        while pos < indices.len() {
+            let chunk = &indices[pos..std::cmp::min(pos + MAX_MSRS, indices.len())];
+            let mut msrs = kvm_msr_array {
+                nmsrs: chunk.len() as u32,
+                ..Default::default()
+            };
+            for (entry, index) in msrs.entries.iter_mut().zip(chunk) {
+                entry.index = *index;
+            }
+            mem.write(&msrs)?;
+            let n = tracee.get_msrs(vcpu, &mem)?;
+            let msrs = mem.read()?;
+            found.extend_from_slice(&msrs.entries[..n]);
+            // skip the msr that kvm did not know
+            pos += std::cmp::min(n + 1, chunk.len());
+        }
+        Ok(found)
+    }
+
+    /// Write the given msrs, fails at the first msr that kvm refuses.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_msrs(&self, vcpu: &VCPU, entries: &[kvmb::kvm_msr_entry]) -> Result<()> {
+        let mem = self.alloc_mem::<kvm_msr_array>()?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        for chunk in entries.chunks(MAX_MSRS) {
+            let mut msrs = kvm_msr_array {
+                nmsrs: chunk.len() as u32,
+                ..Default::default()
+            };
+            msrs.entries[..chunk.len()].copy_from_slice(chunk);
+            mem.write(&msrs)?;
+            let n = tracee.set_msrs(vcpu, &mem)?;
+            if n < chunk.len() {
+                bail!("kvm refused to set msr {:#x}", chunk[n].index);
+            }
+        }
+        Ok(())
+    }
 }
 
 pub const VMFD_INODE_NAME: &str = "anon_inode:kvm-vm";
@@ -540,3 +1067,36 @@ pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
         wrapper: Mutex::new(None),
     })
 }
+
+/// Processes that hold a kvm vm fd. Processes that exit or that we are not allowed to inspect
+/// during the scan are skipped.
+pub fn find_hypervisors() -> Result<Vec<Pid>> {
+    let entries = try_with!(std::fs::read_dir("/proc"), "failed to read /proc");
+    let own_pid = nix::unistd::getpid();
+    let mut pids = vec![];
+    for entry in entries.flatten() {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<i32>().ok())
+        {
+            Some(pid) => Pid::from_raw(pid),
+            None => continue,
+        };
+        if pid == own_pid {
+            continue;
+        }
+        let fds = match openpid(pid).and_then(|handle| handle.fds()) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        if fds
+            .iter()
+            .any(|fd| fd.path.file_name() == Some(OsStr::new(VMFD_INODE_NAME)))
+        {
+            pids.push(pid);
+        }
+    }
+    pids.sort_unstable_by_key(|pid| pid.as_raw());
+    Ok(pids)
+}
@@ -198,7 +198,12 @@ const _IOC_READ: c_uint = 2;
 const KVMIO: c_uint = 0xAE;
 
 // Ioctls for /dev/kvm.
+ioctl_io_nr!(KVM_CREATE_VM, KVMIO, 0x01);
 ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_io_nr!(KVM_GET_VCPU_MMAP_SIZE, KVMIO, 0x04);
+
+pub const KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2: i32 = 168;
+pub const KVM_CAP_BINARY_STATS_FD: i32 = 203;
 
 // Available with KVM_CAP_IOEVENTFD
 ioctl_iow_nr!(KVM_IOEVENTFD, KVMIO, 0x79, kvmb::kvm_ioeventfd);
@@ -219,7 +224,40 @@ ioctl_iow_nr!(KVM_SET_IOREGION, KVMIO, 0x49, kvm_ioregion);
 
 ioctl_io_nr!(KVM_RUN, KVMIO, 0x80);
 
+// Available with KVM_CAP_USER_NMI
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_NMI, KVMIO, 0x9a);
+
+// Available with KVM_CAP_BINARY_STATS_FD, on vm and vcpu fds
+ioctl_io_nr!(KVM_GET_STATS_FD, KVMIO, 0xce);
+
+// Available with KVM_CAP_SET_GUEST_DEBUG
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvmb::kvm_guest_debug);
+
 // Ioctls for VM fds.
+ioctl_io_nr!(KVM_CREATE_VCPU, KVMIO, 0x41);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_SET_TSS_ADDR, KVMIO, 0x47);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_CREATE_IRQCHIP, KVMIO, 0x60);
+// Available with KVM_CAP_IRQCHIP
+ioctl_iow_nr!(KVM_IRQ_LINE, KVMIO, 0x61, kvmb::kvm_irq_level);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvmb::kvm_irqchip);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_SET_IRQCHIP, KVMIO, 0x63, kvmb::kvm_irqchip);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvmb::kvm_clock_data);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvmb::kvm_clock_data);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_PIT2, KVMIO, 0x9f, kvmb::kvm_pit_state2);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_PIT2, KVMIO, 0xa0, kvmb::kvm_pit_state2);
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
+// Available with KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2
+ioctl_iowr_nr!(KVM_CLEAR_DIRTY_LOG, KVMIO, 0xc0, kvm_clear_dirty_log);
 /* Available with KVM_CAP_USER_MEMORY */
 //ioctl_iow_nr!(
 //    KVM_SET_USER_MEMORY_REGION,
@@ -240,6 +278,13 @@ ioctl_iow_nr!(KVM_SET_REGS, KVMIO, 0x82, kvmb::kvm_regs);
     target_arch = "powerpc64"
 ))]
 ioctl_ior_nr!(KVM_GET_SREGS, KVMIO, 0x83, kvmb::kvm_sregs);
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64"
+))]
+ioctl_iow_nr!(KVM_SET_SREGS, KVMIO, 0x84, kvmb::kvm_sregs);
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_ior_nr!(KVM_GET_FPU, KVMIO, 0x8c, kvmb::kvm_fpu);
@@ -248,6 +293,28 @@ ioctl_iow_nr!(KVM_SET_FPU, KVMIO, 0x8d, kvmb::kvm_fpu);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_MSRS, KVMIO, 0x88, kvmb::kvm_msrs);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_MSRS, KVMIO, 0x89, kvmb::kvm_msrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_LAPIC, KVMIO, 0x8e, kvmb::kvm_lapic_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_LAPIC, KVMIO, 0x8f, kvmb::kvm_lapic_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvmb::kvm_mp_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_MP_STATE, KVMIO, 0x99, kvmb::kvm_mp_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvmb::kvm_vcpu_events);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_VCPU_EVENTS, KVMIO, 0xa0, kvmb::kvm_vcpu_events);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XSAVE, KVMIO, 0xa4, kvmb::kvm_xsave);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_XSAVE, KVMIO, 0xa5, kvmb::kvm_xsave);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvmb::kvm_xcrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_XCRS, KVMIO, 0xa7, kvmb::kvm_xcrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 
 /// according to arch/x86/include/asm/kvm_host.h
 pub const KVM_MAX_CPUID_ENTRIES: usize = 256;
@@ -261,3 +328,37 @@ pub struct kvm_cpuid2 {
     pub entries: [kvmb::kvm_cpuid_entry2; KVM_MAX_CPUID_ENTRIES],
 }
 ioctl_iowr_nr!(KVM_GET_CPUID2, KVMIO, 0x91, kvmb::kvm_cpuid2);
+
+/// kvmb::kvm_dirty_log has the bitmap pointer in a union, we only need the pointer
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_dirty_log {
+    pub slot: u32,
+    pub padding1: u32,
+    pub dirty_bitmap: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_clear_dirty_log {
+    pub slot: u32,
+    pub num_pages: u32,
+    pub first_page: u64,
+    pub dirty_bitmap: u64,
+}
+
+/// kvmb::kvm_clock_data has only padding where linux 5.16 added realtime and host_tsc. It has
+/// the same size, so KVM_GET_CLOCK works with both.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct kvm_clock_data {
+    pub clock: u64,
+    pub flags: u32,
+    pub pad0: u32,
+    pub realtime: u64,
+    pub host_tsc: u64,
+    pub pad: [u32; 4],
+}
+pub const KVM_CLOCK_TSC_STABLE: u32 = 1 << 1;
+pub const KVM_CLOCK_REALTIME: u32 = 1 << 2;
+pub const KVM_CLOCK_HOST_TSC: u32 = 1 << 3;
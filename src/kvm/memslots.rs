@@ -1,28 +1,80 @@
-use bcc::perf_event::PerfMapBuilder;
+use bcc::perf_event::{PerfMap, PerfMapBuilder};
 use bcc::{BPFBuilder, Kprobe, BPF};
 use core::slice::from_raw_parts as make_slice;
+use lazy_static::lazy_static;
 use libc::{c_ulong, size_t};
-use log::warn;
+use log::{info, warn};
+use nix::sys::mman::ProtFlags;
 use nix::unistd::Pid;
 use simple_error::bail;
-use simple_error::require_with;
-use simple_error::try_with;
-use std::sync::mpsc::channel;
+use simple_error::{require_with, simple_error, try_with};
+use std::fs;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
 use std::time::Duration;
 use std::{fmt, ptr};
 
-use crate::kvm::hypervisor;
 use crate::result::Result;
 use crate::tracer::proc::openpid;
 use crate::tracer::proc::{self, Mapping};
+use crate::vmi::profiles::Btf;
 use crate::{kvm::tracee::Tracee, page_math::page_size};
 
+/// How the memslots of the hypervisor are obtained.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemslotSource {
+    /// Read the memslots from the kernel with a kprobe. Requires kernel headers and a clang/bcc
+    /// toolchain on the host.
+    Bcc = 0,
+    /// Infer the memslots from the mappings of the hypervisor and the guest memory layout that
+    /// the hypervisor is known to use. Works without kernel support but only for the common
+    /// layouts of qemu, firecracker, cloud-hypervisor and crosvm. A wrong guess only gives wrong
+    /// reads, so guest memory is never written with these memslots, see `check_writable`.
+    Maps = 1,
+}
+
+impl MemslotSource {
+    pub fn from_name(name: &str) -> Option<MemslotSource> {
+        match name {
+            "bcc" => Some(MemslotSource::Bcc),
+            "maps" => Some(MemslotSource::Maps),
+            _ => None,
+        }
+    }
+}
+
+/// Should be initialized by the argument parser.
+static MEMSLOT_SOURCE: AtomicU8 = AtomicU8::new(MemslotSource::Bcc as u8);
+
+pub fn set_memslot_source(source: MemslotSource) {
+    MEMSLOT_SOURCE.store(source as u8, Ordering::Release);
+}
+
+pub fn memslot_source() -> MemslotSource {
+    match MEMSLOT_SOURCE.load(Ordering::Acquire) {
+        1 => MemslotSource::Maps,
+        _ => MemslotSource::Bcc,
+    }
+}
+
+/// Fails if the memslots are only guessed. Writing through a wrong guess would corrupt host
+/// memory of the hypervisor instead of the guest.
+pub fn check_writable() -> Result<()> {
+    if memslot_source() == MemslotSource::Maps {
+        bail!("guest memory is not written with memslots guessed by --memslots maps, use --memslots bcc");
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct MemSlot {
     base_gfn: u64,
     npages: c_ulong,
     userspace_addr: c_ulong,
+    id: u32,
+    flags: u32,
 }
 
 impl MemSlot {
@@ -41,13 +93,28 @@ impl MemSlot {
     pub fn physical_start(&self) -> usize {
         (self.base_gfn as usize) * page_size()
     }
+
+    /// Slot id for `KVM_SET_USER_MEMORY_REGION`
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// `KVM_MEM_*` flags of the slot
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn npages(&self) -> usize {
+        self.npages as usize
+    }
 }
 
 impl fmt::Display for MemSlot {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Mapping {{ start={:#x}, end={:#x}, size={:#x}, physical_start={:#x} }}",
+            "Mapping {{ id={}, start={:#x}, end={:#x}, size={:#x}, physical_start={:#x} }}",
+            self.id,
             self.start(),
             self.end(),
             self.size(),
@@ -56,13 +123,16 @@ impl fmt::Display for MemSlot {
     }
 }
 
-const BPF_TEXT: &str = r#"
+/// Memslot layout before linux 5.17: `kvm_memslots` has a sorted array of `used_slots` slots.
+const BPF_TEXT_ARRAY: &str = r#"
 #include <linux/kvm_host.h>
 
 struct memslot {
     gfn_t base_gfn;
     unsigned long npages;
     unsigned long userspace_addr;
+    u32 id;
+    u32 flags;
 };
 
 // KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
@@ -77,11 +147,16 @@ BPF_PERCPU_ARRAY(slots, out_t, 1);
 
 BPF_PERF_OUTPUT(memslots);
 
+// pid of the hypervisor whose memslots are read, set by vmsh before each query
+BPF_ARRAY(target_pid, u32, 1);
+
 void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
     struct kvm *kvm = (struct kvm *)filp->private_data;
 
+    u32 zero = 0;
+    u32 *target = target_pid.lookup(&zero);
     u32 pid = bpf_get_current_pid_tgid() >> 32;
-    if (pid != TARGET_PID) {
+    if (!target || pid != *target) {
         return;
     }
 
@@ -101,14 +176,326 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
       out_slot->base_gfn = in_slot->base_gfn;
       out_slot->npages = in_slot->npages;
       out_slot->userspace_addr = in_slot->userspace_addr;
+      out_slot->id = in_slot->id;
+      out_slot->flags = in_slot->flags;
+    }
+    memslots.perf_submit(ctx, out, sizeof(*out));
+}"#;
+
+/// Memslot layout since linux 5.17: slots are kept in a gfn rbtree, a hva interval tree and an
+/// id hashtable. Each slot is part of two memslot sets (active and inactive), `node_idx` tells
+/// which of the embedded nodes belong to the set. The hashtable is the easiest to walk with a
+/// bounded loop.
+const BPF_TEXT_TREE: &str = r#"
+#include <linux/kvm_host.h>
+
+struct memslot {
+    gfn_t base_gfn;
+    unsigned long npages;
+    unsigned long userspace_addr;
+    u32 id;
+    u32 flags;
+};
+
+// KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
+#define MAX_SLOTS 1024
+// slots are hashed by id, so buckets only contain a few entries
+#define MAX_CHAIN 8
+#define ID_HASH_BUCKETS (1 << 7)
+
+typedef struct {
+  size_t used_slots;
+  struct memslot memslots[MAX_SLOTS];
+} out_t;
+
+BPF_PERCPU_ARRAY(slots, out_t, 1);
+
+BPF_PERF_OUTPUT(memslots);
+
+// pid of the hypervisor whose memslots are read, set by vmsh before each query
+BPF_ARRAY(target_pid, u32, 1);
+
+void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
+    struct kvm *kvm = (struct kvm *)filp->private_data;
+
+    u32 zero = 0;
+    u32 *target = target_pid.lookup(&zero);
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    if (!target || pid != *target) {
+        return;
+    }
+
+    u32 idx = 0;
+    out_t *out = slots.lookup(&idx);
+    if (!out) {
+      return;
+    }
+
+    // same as in the array layout, we ignore the system management mode address space
+    struct kvm_memslots *set = kvm->memslots[0];
+    int node_idx = set->node_idx;
+    size_t used = 0;
+    for (int bucket = 0; bucket < ID_HASH_BUCKETS; bucket++) {
+      struct hlist_node *node = set->id_hash[bucket].first;
+      for (int j = 0; j < MAX_CHAIN && node && used < MAX_SLOTS; j++) {
+        struct kvm_memory_slot *in_slot = (struct kvm_memory_slot *)((char *)node -
+            offsetof(struct kvm_memory_slot, id_node) - node_idx * sizeof(struct hlist_node));
+        struct memslot *out_slot = &out->memslots[used & (MAX_SLOTS - 1)];
+
+        out_slot->base_gfn = in_slot->base_gfn;
+        out_slot->npages = in_slot->npages;
+        out_slot->userspace_addr = in_slot->userspace_addr;
+        out_slot->id = in_slot->id;
+        out_slot->flags = in_slot->flags;
+        used++;
+        node = node->next;
+      }
     }
+    out->used_slots = used;
     memslots.perf_submit(ctx, out, sizeof(*out));
 }"#;
 
-fn bpf_prog(pid: Pid) -> Result<BPF> {
-    let builder = try_with!(BPFBuilder::new(BPF_TEXT), "cannot compile bpf program");
-    let cflags = &[format!("-DTARGET_PID={}", pid)];
-    let builder_with_cflags = try_with!(builder.cflags(cflags), "could not pass cflags");
+/// Both memslot layouts with the offsets and sizes of all fields passed as defines, so that the
+/// program does not depend on the struct layouts in the kernel headers. See `btf_cflags`.
+const BPF_TEXT_BTF: &str = r#"
+#include <uapi/linux/ptrace.h>
+
+struct memslot {
+    u64 base_gfn;
+    unsigned long npages;
+    unsigned long userspace_addr;
+    u32 id;
+    u32 flags;
+};
+
+// KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
+#define MAX_SLOTS 1024
+// slots are hashed by id, so buckets only contain a few entries
+#define MAX_CHAIN 8
+#define ID_HASH_BUCKETS (1 << 7)
+
+typedef struct {
+  size_t used_slots;
+  struct memslot memslots[MAX_SLOTS];
+} out_t;
+
+BPF_PERCPU_ARRAY(slots, out_t, 1);
+
+BPF_PERF_OUTPUT(memslots);
+
+// pid of the hypervisor whose memslots are read, set by vmsh before each query
+BPF_ARRAY(target_pid, u32, 1);
+
+// reads an integer or pointer of `size` bytes, the host is little endian
+static inline u64 read_field(char *base, u32 offset, u32 size) {
+    u64 value = 0;
+    bpf_probe_read_kernel(&value, size, base + offset);
+    return value;
+}
+
+#define FIELD(base, name) read_field((char *)(base), name, name##_SIZE)
+#define POINTER(base, offset) ((char *)read_field((char *)(base), offset, sizeof(void *)))
+
+static inline void copy_slot(struct memslot *out_slot, char *in_slot) {
+    out_slot->base_gfn = FIELD(in_slot, SLOT_BASE_GFN);
+    out_slot->npages = FIELD(in_slot, SLOT_NPAGES);
+    out_slot->userspace_addr = FIELD(in_slot, SLOT_USERSPACE_ADDR);
+    out_slot->id = FIELD(in_slot, SLOT_ID);
+    out_slot->flags = FIELD(in_slot, SLOT_FLAGS);
+}
+
+void kvm_vm_ioctl(struct pt_regs *ctx, void *filp) {
+    u32 zero = 0;
+    u32 *target = target_pid.lookup(&zero);
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    if (!target || pid != *target) {
+        return;
+    }
+
+    u32 idx = 0;
+    out_t *out = slots.lookup(&idx);
+    if (!out) {
+      return;
+    }
+
+    char *kvm = (char *)FIELD(filp, FILE_PRIVATE_DATA);
+    // kvm->memslots[0], we ignore the system management mode address space
+    char *set = POINTER(kvm, KVM_MEMSLOTS);
+#ifdef TREE_LAYOUT
+    int node_idx = FIELD(set, MEMSLOTS_NODE_IDX);
+    size_t used = 0;
+    for (int bucket = 0; bucket < ID_HASH_BUCKETS; bucket++) {
+      // hlist_head.first and hlist_node.next are the first members
+      char *node = POINTER(set, MEMSLOTS_ID_HASH + bucket * sizeof(void *));
+      for (int j = 0; j < MAX_CHAIN && node && used < MAX_SLOTS; j++) {
+        char *in_slot = node - SLOT_ID_NODE - node_idx * HLIST_NODE_SIZE;
+        copy_slot(&out->memslots[used & (MAX_SLOTS - 1)], in_slot);
+        used++;
+        node = POINTER(node, 0);
+      }
+    }
+    out->used_slots = used;
+#else
+    out->used_slots = FIELD(set, MEMSLOTS_USED_SLOTS);
+    for (size_t i = 0; i < MAX_SLOTS && i < out->used_slots; i++) {
+      copy_slot(&out->memslots[i], set + MEMSLOTS_MEMSLOTS + i * SLOT_SIZE);
+    }
+#endif
+    memslots.perf_submit(ctx, out, sizeof(*out));
+}"#;
+
+/// Integer and pointer fields that `BPF_TEXT_BTF` reads, as (define, `struct.field`). Each also
+/// gets a `<define>_SIZE`.
+const BTF_FIELDS: &[(&str, &str)] = &[
+    ("FILE_PRIVATE_DATA", "file.private_data"),
+    ("SLOT_BASE_GFN", "kvm_memory_slot.base_gfn"),
+    ("SLOT_NPAGES", "kvm_memory_slot.npages"),
+    ("SLOT_USERSPACE_ADDR", "kvm_memory_slot.userspace_addr"),
+    ("SLOT_ID", "kvm_memory_slot.id"),
+    ("SLOT_FLAGS", "kvm_memory_slot.flags"),
+];
+const BTF_ARRAY_FIELDS: &[(&str, &str)] = &[("MEMSLOTS_USED_SLOTS", "kvm_memslots.used_slots")];
+const BTF_TREE_FIELDS: &[(&str, &str)] = &[("MEMSLOTS_NODE_IDX", "kvm_memslots.node_idx")];
+/// Arrays and embedded structs, of which only the offset is needed
+const BTF_OFFSETS: &[(&str, &str)] = &[("KVM_MEMSLOTS", "kvm.memslots")];
+const BTF_ARRAY_OFFSETS: &[(&str, &str)] = &[("MEMSLOTS_MEMSLOTS", "kvm_memslots.memslots")];
+const BTF_TREE_OFFSETS: &[(&str, &str)] = &[
+    ("MEMSLOTS_ID_HASH", "kvm_memslots.id_hash"),
+    ("SLOT_ID_NODE", "kvm_memory_slot.id_node"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KernelLayout {
+    Array,
+    Tree,
+}
+
+/// Looks for a struct member name in the string section of raw btf data.
+fn btf_has_name(btf: &[u8], name: &str) -> bool {
+    let needle = [&[0], name.as_bytes(), &[0]].concat();
+    btf.windows(needle.len()).any(|w| w == needle.as_slice())
+}
+
+/// The layout was changed in 5.17. Distributions might backport it, so btf is preferred.
+fn layout_from_version(version: (u32, u32)) -> KernelLayout {
+    if version >= (5, 17) {
+        KernelLayout::Tree
+    } else {
+        KernelLayout::Array
+    }
+}
+
+fn layout_from_btf() -> Option<KernelLayout> {
+    // kvm is usually a module, but might also be built into the kernel
+    for path in &["/sys/kernel/btf/kvm", "/sys/kernel/btf/vmlinux"] {
+        let btf = match fs::read(path) {
+            Ok(btf) => btf,
+            Err(_) => continue,
+        };
+        if btf_has_name(&btf, "gfn_tree") {
+            return Some(KernelLayout::Tree);
+        }
+        if btf_has_name(&btf, "kvm_memslots") && btf_has_name(&btf, "used_slots") {
+            return Some(KernelLayout::Array);
+        }
+    }
+    None
+}
+
+fn kernel_layout() -> KernelLayout {
+    if let Some(layout) = layout_from_btf() {
+        info!("memslot layout from btf: {:?}", layout);
+        return layout;
+    }
+    match proc::kernel_version() {
+        Some(version) => {
+            let layout = layout_from_version(version);
+            info!(
+                "memslot layout from kernel {}.{}: {:?}",
+                version.0, version.1, layout
+            );
+            layout
+        }
+        None => {
+            warn!("cannot parse kernel release, assume tree based memslots");
+            KernelLayout::Tree
+        }
+    }
+}
+
+/// The btf of the host kernel and of kvm, which is usually a module with split btf.
+fn host_btf() -> Result<Btf> {
+    let path = "/sys/kernel/btf/vmlinux";
+    let content = try_with!(fs::read(path), "cannot read {}", path);
+    let btf = try_with!(Btf::parse(content), "invalid btf in {}", path);
+    let path = "/sys/kernel/btf/kvm";
+    match fs::read(path) {
+        Ok(content) => Ok(try_with!(
+            Btf::parse_split(content, btf),
+            "invalid btf in {}",
+            path
+        )),
+        Err(_) => Ok(btf),
+    }
+}
+
+/// Defines with the offsets and sizes `BPF_TEXT_BTF` needs for `layout`.
+fn btf_cflags(btf: &Btf, layout: KernelLayout) -> Result<Vec<String>> {
+    let (fields, offsets) = match layout {
+        KernelLayout::Array => (BTF_ARRAY_FIELDS, BTF_ARRAY_OFFSETS),
+        KernelLayout::Tree => (BTF_TREE_FIELDS, BTF_TREE_OFFSETS),
+    };
+    let mut cflags = vec![];
+    for (define, path) in BTF_FIELDS.iter().chain(fields) {
+        let offset = require_with!(btf.offset_of(path), "btf has no {}", path);
+        let size = require_with!(btf.member_size(path), "btf has no size of {}", path);
+        cflags.push(format!("-D{}={}", define, offset));
+        cflags.push(format!("-D{}_SIZE={}", define, size));
+    }
+    for (define, path) in BTF_OFFSETS.iter().chain(offsets) {
+        let offset = require_with!(btf.offset_of(path), "btf has no {}", path);
+        cflags.push(format!("-D{}={}", define, offset));
+    }
+    match layout {
+        KernelLayout::Array => {
+            let size = require_with!(
+                btf.struct_size("kvm_memory_slot"),
+                "btf has no struct kvm_memory_slot"
+            );
+            cflags.push(format!("-DSLOT_SIZE={}", size));
+        }
+        KernelLayout::Tree => {
+            let size = require_with!(
+                btf.struct_size("hlist_node"),
+                "btf has no struct hlist_node"
+            );
+            cflags.push(format!("-DHLIST_NODE_SIZE={}", size));
+            cflags.push(String::from("-DTREE_LAYOUT"));
+        }
+    }
+    Ok(cflags)
+}
+
+fn bpf_prog() -> Result<BPF> {
+    let layout = kernel_layout();
+    let mut cflags = vec![];
+    // offsets from btf survive struct changes the kernel headers on the host do not match
+    let text = match host_btf().and_then(|btf| btf_cflags(&btf, layout)) {
+        Ok(offsets) => {
+            info!("memslot offsets from btf");
+            cflags.extend(offsets);
+            BPF_TEXT_BTF
+        }
+        Err(e) => {
+            info!("{}, using the memslot layout of the kernel headers", e);
+            match layout {
+                KernelLayout::Array => BPF_TEXT_ARRAY,
+                KernelLayout::Tree => BPF_TEXT_TREE,
+            }
+        }
+    };
+    let builder = try_with!(BPFBuilder::new(text), "cannot compile bpf program");
+    let builder_with_cflags = try_with!(builder.cflags(cflags.as_slice()), "could not pass cflags");
     Ok(try_with!(
         builder_with_cflags.build(),
         "build failed. This might happen if vmsh was started without root (or cap_sys_admin)"
@@ -121,46 +508,126 @@ pub fn fetch_mappings(pid: Pid) -> Result<Vec<Mapping>> {
     Ok(mappings)
 }
 
-pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
-    let mut module = bpf_prog(tracee.pid())?;
-    try_with!(
-        Kprobe::new()
-            .handler("kvm_vm_ioctl")
-            .function("kvm_vm_ioctl")
-            .attach(&mut module),
-        "failed to install kprobe"
-    );
-    let table = try_with!(module.table("memslots"), "failed to get perf event table");
-
-    let (sender, receiver) = channel();
-    let builder = PerfMapBuilder::new(table, move || {
-        let sender = sender.clone();
-        Box::new(move |x| {
-            let head = x.as_ptr() as *const size_t;
-            let size = unsafe { ptr::read(head) };
-            let memslots_slice = unsafe { make_slice(head.add(1) as *const MemSlot, size) };
-            sender.send(memslots_slice.to_vec()).unwrap();
+/// The memslot program with its kprobe and perf buffer. Compiling it takes seconds, so it is
+/// loaded once and shared by all vms, e.g. by `vmsh inspect --all`.
+struct MemslotProgram {
+    // dropped before the module it reads from
+    perf_map: PerfMap,
+    receiver: Receiver<Vec<MemSlot>>,
+    module: BPF,
+}
+
+// bcc keeps no thread local state and the program is only used while `MEMSLOT_PROGRAM` is locked
+unsafe impl Send for MemslotProgram {}
+
+lazy_static! {
+    static ref MEMSLOT_PROGRAM: Mutex<Option<MemslotProgram>> = Mutex::new(None);
+}
+
+impl MemslotProgram {
+    fn load() -> Result<MemslotProgram> {
+        let mut module = bpf_prog()?;
+        try_with!(
+            Kprobe::new()
+                .handler("kvm_vm_ioctl")
+                .function("kvm_vm_ioctl")
+                .attach(&mut module),
+            "failed to install kprobe"
+        );
+        let table = try_with!(module.table("memslots"), "failed to get perf event table");
+
+        let (sender, receiver) = channel();
+        let builder = PerfMapBuilder::new(table, move || {
+            let sender = sender.clone();
+            Box::new(move |x| {
+                let head = x.as_ptr() as *const size_t;
+                let size = unsafe { ptr::read(head) };
+                let memslots_slice = unsafe { make_slice(head.add(1) as *const MemSlot, size) };
+                sender.send(memslots_slice.to_vec()).unwrap();
+            })
+        });
+        let perf_map = try_with!(builder.build(), "could not install perf event handler");
+        Ok(MemslotProgram {
+            perf_map,
+            receiver,
+            module,
         })
-    });
-    let mut perf_map = try_with!(builder.build(), "could not install perf event handler");
-    try_with!(tracee.check_extension(0), "cannot query kvm extensions");
-
-    perf_map.poll(0);
-    let memslots = try_with!(
-        receiver.recv_timeout(Duration::from_secs(0)),
-        "could not receive memslots from kernel"
-    );
+    }
+
+    fn set_target(&mut self, pid: u32) -> Result<()> {
+        let mut table = try_with!(
+            self.module.table("target_pid"),
+            "failed to get target pid table"
+        );
+        try_with!(
+            table.set(&mut 0u32.to_ne_bytes(), &mut pid.to_ne_bytes()),
+            "cannot set target pid"
+        );
+        Ok(())
+    }
+
+    /// Memslots of the vm of `tracee`, reported by the kprobe when it calls a vm ioctl.
+    fn query(&mut self, tracee: &Tracee) -> Result<Vec<MemSlot>> {
+        self.set_target(tracee.pid().as_raw() as u32)?;
+        // events of an earlier failed query
+        self.receiver.try_iter().for_each(drop);
+        let res = tracee
+            .check_extension(0)
+            .map_err(|e| simple_error!("cannot query kvm extensions: {}", e))
+            .and_then(|_| {
+                self.perf_map.poll(0);
+                self.receiver
+                    .recv_timeout(Duration::from_secs(0))
+                    .map_err(|e| simple_error!("could not receive memslots from kernel: {}", e))
+            });
+        self.set_target(0)?;
+        res
+    }
+}
+
+/// Read the memslots with their ids and flags from the kernel. Unlike `get_maps` this always
+/// needs bcc, the memslot ids cannot be guessed from the mappings of the hypervisor.
+pub fn get_memslots(tracee: &Tracee) -> Result<Vec<MemSlot>> {
+    let memslots = {
+        let mut program = MEMSLOT_PROGRAM
+            .lock()
+            .expect("memslot program lock poisoned");
+        if program.is_none() {
+            *program = Some(MemslotProgram::load()?);
+        }
+        program.as_mut().unwrap().query(tracee)?
+    };
     if memslots.len() == 1024 {
         warn!(
             "Reached capacity of kvm memslots we can extract from the kernel.
 We might miss physical memory allocations."
         );
     }
+    // If the bpf program does not match the kernel we read garbage instead of failing.
+    if memslots.is_empty() {
+        bail!("kernel reported no memslots, the memslot layout might not be supported");
+    }
+    if let Some(slot) = memslots
+        .iter()
+        .find(|s| s.npages == 0 || s.start() % page_size() != 0)
+    {
+        bail!(
+            "invalid memslot {} received, the memslot layout might not be supported",
+            slot
+        );
+    }
+    Ok(memslots)
+}
+
+fn get_maps_bcc(tracee: &Tracee) -> Result<Vec<Mapping>> {
+    let memslots = get_memslots(tracee)?;
     let mappings = fetch_mappings(tracee.pid())?;
     memslots
         .iter()
         .map(|slot| match proc::find_mapping(&mappings, slot.start()) {
             Some(mut m) => {
+                // file offset of the memslot, which may start in the middle of the mapping
+                m.offset += (slot.start() - m.start) as u64;
                 m.start = slot.start();
                 m.end = slot.end();
                 m.phys_addr = slot.physical_start();
@@ -175,35 +642,214 @@ We might miss physical memory allocations."
         .collect()
 }
 
-/// ordered list of the hypervisor memory mapped to [vcpu0fd, vcpu1fd, ...]
-pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
+/// Guest memory layouts of the hypervisors we know.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Layout {
+    QemuPc,
+    QemuQ35,
+    QemuMicrovm,
+    Firecracker,
+    CloudHypervisor,
+    Crosvm,
+    Unknown,
+}
+
+const FOUR_GB: usize = 1 << 32;
+
+fn qemu_machine(cmdline: &[String]) -> Option<&str> {
+    cmdline.iter().enumerate().find_map(|(i, arg)| {
+        let arg = arg.strip_prefix('-')?.trim_start_matches('-');
+        let value = match arg.split_once('=') {
+            Some(("machine", value)) => value,
+            _ if arg == "machine" || arg == "M" => cmdline.get(i + 1)?.as_str(),
+            _ => return None,
+        };
+        // -machine q35,accel=kvm or -machine type=q35,accel=kvm
+        value.split(',').find_map(|opt| match opt.split_once('=') {
+            Some(("type", machine)) => Some(machine),
+            Some(_) => None,
+            None => Some(opt),
+        })
+    })
+}
+
+fn detect_layout(exe: &str, cmdline: &[String]) -> Layout {
+    if exe.starts_with("qemu-system") || exe.starts_with("qemu-kvm") {
+        match qemu_machine(cmdline) {
+            Some(m) if m.contains("q35") => Layout::QemuQ35,
+            Some(m) if m == "microvm" => Layout::QemuMicrovm,
+            _ => Layout::QemuPc,
+        }
+    } else if exe.starts_with("firecracker") {
+        Layout::Firecracker
+    } else if exe.starts_with("cloud-hypervisor") {
+        Layout::CloudHypervisor
+    } else if exe.starts_with("crosvm") {
+        Layout::Crosvm
+    } else {
+        Layout::Unknown
+    }
+}
+
+/// End of the guest ram below the 32-bit pci hole. Memory beyond that is placed at 4GB.
+fn lowmem_end(layout: Layout, ram_size: usize) -> usize {
+    match layout {
+        Layout::QemuPc if ram_size >= 0xe000_0000 => 0xc000_0000,
+        Layout::QemuPc => 0xe000_0000,
+        Layout::QemuQ35 if ram_size >= 0xb000_0000 => 0x8000_0000,
+        Layout::QemuQ35 => 0xb000_0000,
+        Layout::Firecracker | Layout::Crosvm => 0xd000_0000,
+        Layout::QemuMicrovm | Layout::CloudHypervisor | Layout::Unknown => 0xc000_0000,
+    }
+}
+
+/// Split a guest ram mapping of `ram_size` bytes into
+/// (guest physical address, offset in the mapping, size) tuples.
+fn guest_ram_layout(layout: Layout, ram_size: usize) -> Vec<(usize, usize, usize)> {
+    let low = std::cmp::min(ram_size, lowmem_end(layout, ram_size));
+    let mut slots = vec![(0, 0, low)];
+    if ram_size > low {
+        slots.push((FOUR_GB, low, ram_size - low));
+    }
+    slots
+}
+
+/// Guest ram is a large private or shared read-write mapping without code in it.
+fn is_ram_candidate(m: &Mapping) -> bool {
+    let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+    let special = m.pathname.starts_with('[') && !m.pathname.starts_with("[anon:");
+    m.prot_flags.contains(rw)
+        && !m.prot_flags.contains(ProtFlags::PROT_EXEC)
+        && !special
+        && !m.pathname.starts_with("anon_inode:")
+}
+
+/// Infer the memslots without help from the kernel: The largest read-write mapping of the
+/// hypervisor is assumed to be the guest ram, which is then split into memslots like the
+/// hypervisor does it. Rom and device memory is not included.
+fn get_maps_from_proc(pid: Pid) -> Result<Vec<Mapping>> {
     let mappings = fetch_mappings(pid)?;
-    let vcpu_maps = mappings.into_iter().filter(|m| {
-        m.pathname
-            .starts_with(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH)
-    });
-
-    // we need a for loop, because we can not return errors from within a .sort() lambda.
-    let mut taged_maps = vec![]; // (vcpunr, vcpu_map)
-    for vcpu_map in vcpu_maps {
-        let ao: Option<&str> = vcpu_map
-            .pathname
-            .strip_prefix(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH);
-        let astr: &str = require_with!(
-            ao,
-            "vcpufd {} does not start with expected prefix",
-            vcpu_map.pathname,
+    let ram = mappings
+        .iter()
+        .filter(|m| is_ram_candidate(m))
+        .max_by_key(|m| m.size());
+    let ram = match ram {
+        Some(ram) => ram,
+        None => bail!("no guest memory found in /proc/{}/maps", pid),
+    };
+
+    let exe = fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let cmdline = cmdline
+        .split(|b| *b == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect::<Vec<_>>();
+    let layout = detect_layout(&exe, &cmdline);
+    if layout == Layout::Unknown {
+        warn!(
+            "unknown hypervisor '{}', assuming guest memory above 3GB is mapped at 4GB",
+            exe
         );
-        let ai = try_with!(
-            astr.parse::<u64>(),
-            "vcpufd {} has unexpected postfix {}",
-            vcpu_map.pathname,
-            astr,
+    }
+    info!(
+        "guessing memslots of {} ({:?} layout) from {:#x}-{:#x} ({}MB)",
+        pid,
+        layout,
+        ram.start,
+        ram.end,
+        ram.size() >> 20
+    );
+
+    Ok(guest_ram_layout(layout, ram.size())
+        .into_iter()
+        .map(|(phys_addr, offset, size)| {
+            let mut m = ram.clone();
+            m.start = ram.start + offset;
+            m.offset = ram.offset + offset as u64;
+            m.end = m.start + size;
+            m.phys_addr = phys_addr;
+            m
+        })
+        .collect())
+}
+
+pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
+    match memslot_source() {
+        MemslotSource::Bcc => get_maps_bcc(tracee),
+        MemslotSource::Maps => get_maps_from_proc(tracee.pid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_from_version() {
+        assert_eq!(layout_from_version((5, 10)), KernelLayout::Array);
+        assert_eq!(layout_from_version((4, 19)), KernelLayout::Array);
+        assert_eq!(layout_from_version((5, 17)), KernelLayout::Tree);
+        assert_eq!(layout_from_version((6, 1)), KernelLayout::Tree);
+    }
+
+    #[test]
+    fn test_btf_has_name() {
+        let strings = b"\0kvm_memslots\0gfn_tree\0node_idx\0";
+        assert!(btf_has_name(strings, "gfn_tree"));
+        assert!(!btf_has_name(strings, "gfn"));
+        assert!(!btf_has_name(strings, "used_slots"));
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_detect_layout() {
+        let q35 = args(&[
+            "qemu-system-x86_64",
+            "-m",
+            "4G",
+            "-machine",
+            "q35,accel=kvm",
+        ]);
+        assert_eq!(detect_layout("qemu-system-x86_64", &q35), Layout::QemuQ35);
+        let q35 = args(&["qemu-kvm", "-machine=type=pc-q35-6.0"]);
+        assert_eq!(detect_layout("qemu-kvm", &q35), Layout::QemuQ35);
+        let pc = args(&["qemu-system-x86_64", "-enable-kvm"]);
+        assert_eq!(detect_layout("qemu-system-x86_64", &pc), Layout::QemuPc);
+        let microvm = args(&["qemu-system-x86_64", "-M", "microvm"]);
+        assert_eq!(
+            detect_layout("qemu-system-x86_64", &microvm),
+            Layout::QemuMicrovm
         );
-        taged_maps.push((ai, vcpu_map));
+        assert_eq!(detect_layout("firecracker", &[]), Layout::Firecracker);
+        assert_eq!(detect_layout("kvmtool", &[]), Layout::Unknown);
     }
 
-    taged_maps.sort_unstable_by_key(|(i, _map)| *i);
-    let sorted_maps = taged_maps.into_iter().map(|(_i, map)| map).collect();
-    Ok(sorted_maps)
+    #[test]
+    fn test_guest_ram_layout() {
+        let gb = 1 << 30;
+        assert_eq!(guest_ram_layout(Layout::QemuPc, gb), vec![(0, 0, gb)]);
+        assert_eq!(
+            guest_ram_layout(Layout::QemuPc, 4 * gb),
+            vec![(0, 0, 3 * gb), (FOUR_GB, 3 * gb, gb)]
+        );
+        // qemu only moves memory above 4GB if it does not fit below the pci hole
+        assert_eq!(
+            guest_ram_layout(Layout::QemuQ35, 0xa000_0000),
+            vec![(0, 0, 0xa000_0000)]
+        );
+        assert_eq!(
+            guest_ram_layout(Layout::QemuQ35, 3 * gb),
+            vec![(0, 0, 2 * gb), (FOUR_GB, 2 * gb, gb)]
+        );
+        assert_eq!(
+            guest_ram_layout(Layout::Firecracker, 4 * gb),
+            vec![(0, 0, 0xd000_0000), (FOUR_GB, 0xd000_0000, 0x3000_0000)]
+        );
+    }
 }
@@ -0,0 +1,250 @@
+//! Which hypervisor resources belong to which vcpu: the memory mapping of each vcpu fd and the
+//! host thread that runs it.
+use libc::pid_t;
+use nix::unistd::Pid;
+use simple_error::{require_with, try_with};
+use std::fs;
+
+use crate::kvm::hypervisor::{self, VCPU};
+use crate::kvm::ioctls::KVM_RUN;
+use crate::kvm::memslots::fetch_mappings;
+use crate::result::Result;
+use crate::tracer::proc::{pid_path, Mapping};
+
+/// ordered list of the hypervisor memory mapped to [vcpu0fd, vcpu1fd, ...]
+pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
+    let mappings = fetch_mappings(pid)?;
+    let vcpu_maps = mappings.into_iter().filter(|m| {
+        m.pathname
+            .starts_with(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH)
+    });
+
+    // we need a for loop, because we can not return errors from within a .sort() lambda.
+    let mut taged_maps = vec![]; // (vcpunr, vcpu_map)
+    for vcpu_map in vcpu_maps {
+        let ao: Option<&str> = vcpu_map
+            .pathname
+            .strip_prefix(hypervisor::VCPUFD_INODE_NAME_STARTS_WITH);
+        let astr: &str = require_with!(
+            ao,
+            "vcpufd {} does not start with expected prefix",
+            vcpu_map.pathname,
+        );
+        let ai = try_with!(
+            astr.parse::<u64>(),
+            "vcpufd {} has unexpected postfix {}",
+            vcpu_map.pathname,
+            astr,
+        );
+        taged_maps.push((ai, vcpu_map));
+    }
+
+    taged_maps.sort_unstable_by_key(|(i, _map)| *i);
+    let sorted_maps = taged_maps.into_iter().map(|(_i, map)| map).collect();
+    Ok(sorted_maps)
+}
+
+/// Host thread running a vcpu.
+pub struct VcpuThread {
+    pub idx: usize,
+    pub tid: Pid,
+    /// Thread name as set by the hypervisor, i.e. `CPU 0/KVM` in qemu.
+    pub name: String,
+}
+
+/// Fd of the KVM_RUN ioctl a thread is blocked in, from the content of `/proc/<tid>/syscall`.
+fn kvm_run_fd(syscall: &str) -> Option<i32> {
+    let mut fields = syscall.split_whitespace();
+    if fields.next()?.parse::<i64>().ok()? != libc::SYS_ioctl {
+        return None;
+    }
+    let fd = i64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+    let request = u64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+    if request != KVM_RUN() as u64 {
+        return None;
+    }
+    Some(fd as i32)
+}
+
+/// Vcpu index from thread names used by common hypervisors: `CPU 0/KVM` (qemu), `vcpu0`
+/// (cloud-hypervisor), `fc_vcpu 0` (firecracker), `crosvm_vcpu0` (crosvm).
+fn vcpu_index_from_name(name: &str) -> Option<usize> {
+    let lower = name.to_lowercase();
+    let pos = lower.find("cpu")?;
+    let rest = lower[pos + 3..].trim_start_matches(|c| c == ' ' || c == '_');
+    let digits = rest
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>();
+    digits.parse().ok()
+}
+
+/// Find the thread of each vcpu. Threads that are currently in KVM_RUN are matched by their
+/// vcpu fd, all others by their name.
+pub fn vcpu_threads(pid: Pid, vcpus: &[VCPU]) -> Result<Vec<VcpuThread>> {
+    let dir = pid_path(pid).join("task");
+    let entries = try_with!(fs::read_dir(&dir), "cannot read {}", dir.display());
+    let mut threads = vec![];
+    for entry in entries {
+        let entry = try_with!(entry, "cannot read {}", dir.display());
+        let tid = match entry.file_name().to_str().map(|s| s.parse::<pid_t>()) {
+            Some(Ok(tid)) => Pid::from_raw(tid),
+            _ => continue,
+        };
+        // threads might exit while we look at them
+        let name = match fs::read_to_string(entry.path().join("comm")) {
+            Ok(name) => name.trim_end().to_string(),
+            Err(_) => continue,
+        };
+        let syscall = fs::read_to_string(entry.path().join("syscall")).unwrap_or_default();
+        let by_fd = kvm_run_fd(&syscall)
+            .and_then(|fd| vcpus.iter().find(|v| v.fd_num == fd))
+            .map(|v| v.idx);
+        let idx = by_fd
+            .or_else(|| vcpu_index_from_name(&name).filter(|i| vcpus.iter().any(|v| v.idx == *i)));
+        if let Some(idx) = idx {
+            threads.push(VcpuThread { idx, tid, name });
+        }
+    }
+    threads.sort_by_key(|t| t.idx);
+    threads.dedup_by_key(|t| t.idx);
+    Ok(threads)
+}
+
+/// Scheduler statistics of a thread, see Documentation/scheduler/sched-stats.rst
+#[derive(Clone, Copy, Default)]
+pub struct SchedStat {
+    /// Time spent on a cpu
+    pub run_ns: u64,
+    /// Time spent runnable on a run queue but not running. For vcpus this is steal time.
+    pub wait_ns: u64,
+    pub timeslices: u64,
+}
+
+fn parse_schedstat(content: &str) -> Option<SchedStat> {
+    let mut fields = content.split_whitespace().map(|f| f.parse::<u64>());
+    Some(SchedStat {
+        run_ns: fields.next()?.ok()?,
+        wait_ns: fields.next()?.ok()?,
+        timeslices: fields.next()?.ok()?,
+    })
+}
+
+pub fn sched_stat(pid: Pid, tid: Pid) -> Result<SchedStat> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.to_string())
+        .join("schedstat");
+    let content = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(require_with!(
+        parse_schedstat(&content),
+        "cannot parse {}",
+        path.display()
+    ))
+}
+
+/// Host cpu from the content of `/proc/<tid>/stat`.
+pub fn parse_last_cpu(stat: &str) -> Option<usize> {
+    // the thread name in field 2 might contain spaces
+    stat.rsplit_once(')')
+        .and_then(|(_, rest)| rest.split_whitespace().nth(36))
+        .and_then(|f| f.parse().ok())
+}
+
+/// Host cpu the thread ran on last.
+pub fn last_cpu(pid: Pid, tid: Pid) -> Result<usize> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.to_string())
+        .join("stat");
+    let content = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(require_with!(
+        parse_last_cpu(&content),
+        "cannot parse {}",
+        path.display()
+    ))
+}
+
+/// Context switch counters of a thread.
+#[derive(Clone, Copy, Default)]
+pub struct ContextSwitches {
+    /// The thread blocked, i.e. the vcpu halted.
+    pub voluntary: u64,
+    /// The thread was preempted by the host scheduler.
+    pub nonvoluntary: u64,
+}
+
+fn parse_context_switches(status: &str) -> Option<ContextSwitches> {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+    Some(ContextSwitches {
+        voluntary: field("voluntary_ctxt_switches:")?,
+        nonvoluntary: field("nonvoluntary_ctxt_switches:")?,
+    })
+}
+
+pub fn context_switches(pid: Pid, tid: Pid) -> Result<ContextSwitches> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.to_string())
+        .join("status");
+    let content = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    Ok(require_with!(
+        parse_context_switches(&content),
+        "cannot parse {}",
+        path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcpu_index_from_name() {
+        assert_eq!(vcpu_index_from_name("CPU 1/KVM"), Some(1));
+        assert_eq!(vcpu_index_from_name("vcpu12"), Some(12));
+        assert_eq!(vcpu_index_from_name("fc_vcpu 0"), Some(0));
+        assert_eq!(vcpu_index_from_name("crosvm_vcpu3"), Some(3));
+        assert_eq!(vcpu_index_from_name("IO mon_iothread"), None);
+    }
+
+    #[test]
+    fn test_kvm_run_fd() {
+        let syscall = format!(
+            "{} 0x1c 0x{:x} 0x0 0x0 0x0 0x0 0x7ffc 0x7f12",
+            libc::SYS_ioctl,
+            KVM_RUN()
+        );
+        assert_eq!(kvm_run_fd(&syscall), Some(0x1c));
+        assert_eq!(kvm_run_fd("running"), None);
+        assert_eq!(kvm_run_fd("7 0x5 0x1 0x0 0x0 0x0 0x0 0x0 0x0"), None);
+    }
+
+    #[test]
+    fn test_parse_schedstat() {
+        let stat = parse_schedstat("1234 56 7\n").unwrap();
+        assert_eq!((stat.run_ns, stat.wait_ns, stat.timeslices), (1234, 56, 7));
+        assert!(parse_schedstat("1234").is_none());
+    }
+
+    #[test]
+    fn test_parse_context_switches() {
+        let status =
+            "Name:\tCPU 0/KVM\nvoluntary_ctxt_switches:\t150\nnonvoluntary_ctxt_switches:\t7\n";
+        let switches = parse_context_switches(status).unwrap();
+        assert_eq!((switches.voluntary, switches.nonvoluntary), (150, 7));
+        assert!(parse_context_switches("Name:\tfoo\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_last_cpu() {
+        let stat = "42 (CPU 0/KVM) S 1 42 42 0 -1 4194624 0 0 0 0 10 5 0 0 20 0 4 0 100 0 0 \
+                    18446744073709551615 0 0 0 0 0 0 0 4096 0 0 0 0 17 3 0 0 0 0 0";
+        assert_eq!(parse_last_cpu(stat), Some(3));
+    }
+}
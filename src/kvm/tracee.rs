@@ -1,16 +1,20 @@
 use kvm_bindings as kvmb;
 use libc::{c_int, c_ulong, c_void};
+use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
 use nix::unistd::Pid;
-use simple_error::{bail, try_with};
+use simple_error::{bail, require_with, try_with};
 use std::mem::MaybeUninit;
 use std::os::unix::prelude::RawFd;
 use std::ptr;
+use vm_memory::remote_mem::process_read_bytes;
 
 use super::ioctls;
 use crate::cpu;
 use crate::kvm::hypervisor::{memory::HvMem, VCPU};
 use crate::kvm::ioctls::KVM_CHECK_EXTENSION;
-use crate::kvm::memslots::{get_maps, get_vcpu_maps};
+use crate::kvm::memslots::{check_writable, get_maps, get_memslots, MemSlot};
+use crate::kvm::topology::get_vcpu_maps;
+use crate::page_table;
 use crate::result::Result;
 use crate::tracer::inject_syscall;
 use crate::tracer::inject_syscall::Process as Injectee;
@@ -26,6 +30,28 @@ pub struct kvm_msrs {
     pub entries: [kvmb::kvm_msr_entry; 1],
 }
 
+/// Number of msrs that `Tracee::get_msrs` and `Tracee::set_msrs` transfer with one ioctl
+pub const MAX_MSRS: usize = 32;
+
+/// Like `kvm_msrs`, but with room for `MAX_MSRS` entries
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_msr_array {
+    pub nmsrs: u32,
+    pub pad: u32,
+    pub entries: [kvmb::kvm_msr_entry; MAX_MSRS],
+}
+
+impl Default for kvm_msr_array {
+    fn default() -> Self {
+        kvm_msr_array {
+            nmsrs: 0,
+            pad: 0,
+            entries: [kvmb::kvm_msr_entry::default(); MAX_MSRS],
+        }
+    }
+}
+
 /// This is a handle with abstractions for the syscall injector. Its primary goal is to be an interface for the
 /// destructors of `HvMem` and `VmMem` to be able to (de-)allocate memory.
 #[derive(Debug)]
@@ -49,6 +75,17 @@ pub type socklen_t = usize;
 #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
 pub type socklen_t = libc::socklen_t;
 
+/// Host address of guest physical address `phys` and the bytes left in its memslot.
+fn host_addr(maps: &[Mapping], phys: usize) -> Result<(usize, usize)> {
+    let m = require_with!(
+        maps.iter()
+            .find(|m| m.phys_addr <= phys && phys < m.phys_end()),
+        "guest physical address {:#x} is not backed by a memslot",
+        phys
+    );
+    Ok((m.start + (phys - m.phys_addr), m.phys_end() - phys))
+}
+
 impl Tracee {
     pub fn new(pid: Pid, vm_fd: RawFd, proc: Option<Injectee>) -> Tracee {
         Tracee { pid, vm_fd, proc }
@@ -146,6 +183,16 @@ impl Tracee {
         proc.ioctl(vcpu.fd_num, request, arg)
     }
 
+    /// Like `vm_ioctl_with_ref`, but for a vcpu.
+    pub fn vcpu_ioctl_with_ref<T: Sized + Copy>(
+        &self,
+        vcpu: &VCPU,
+        request: c_ulong,
+        arg: &HvMem<T>,
+    ) -> Result<c_int> {
+        self.vcpu_ioctl(vcpu, request, arg.ptr as c_ulong)
+    }
+
     /// Make the kernel allocate anonymous memory (anywhere he likes, not bound to a file
     /// descriptor). This is not fully POSIX compliant, but works on linux.
     ///
@@ -219,6 +266,8 @@ impl Tracee {
         libc::CMSG_DATA(cmsg)
     }
 
+    /// Read the cpuid leaves that the hypervisor set for the VCPU, i.e. the ones the guest
+    /// sees. `nent` of `cpuid` must hold the number of entries it has room for.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_cpuid2(
         &self,
@@ -227,10 +276,13 @@ impl Tracee {
     ) -> Result<ioctls::kvm_cpuid2> {
         use crate::kvm::ioctls::KVM_GET_CPUID2;
 
-        try_with!(
+        let ret = try_with!(
             self.vcpu_ioctl(vcpu, KVM_GET_CPUID2(), cpuid.ptr as c_ulong),
             "vcpu_ioctl failed"
         );
+        if ret != 0 {
+            bail!("KVM_GET_CPUID2 failed with {}", ret);
+        }
         let cpuid = try_with!(cpuid.read(), "cannot read cpuid");
         Ok(cpuid)
     }
@@ -250,6 +302,17 @@ impl Tracee {
         Ok(sregs)
     }
 
+    /// Set segment and control registers of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_sregs(&self, vcpu: &VCPU, sregs: &HvMem<kvmb::kvm_sregs>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_SREGS;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_SREGS(), sregs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
     /// Set general-purpose pointer registers of VCPU
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn set_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_regs>) -> Result<()> {
@@ -330,6 +393,17 @@ impl Tracee {
         })
     }
 
+    /// Set floating pointer registers of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_fpu_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_fpu>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_FPU;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_FPU(), regs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        Ok(())
+    }
+
     /// Get model-specific pointer registers of VCPU
     /// See https://github.com/rust-vmm/kvm-ioctls/blob/8eee8cd7ffea51c9463220f25e505b57b60cb2c7/src/ioctls/vcpu.rs#L522 for usage
     ///
@@ -346,6 +420,75 @@ impl Tracee {
         Ok(msrs.entries[0])
     }
 
+    /// Read the first `nmsrs` msrs of `msrs` into their entries. KVM stops at the first msr it
+    /// does not know, returns the number of msrs read.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_msrs(&self, vcpu: &VCPU, msrs: &HvMem<kvm_msr_array>) -> Result<usize> {
+        use crate::kvm::ioctls::KVM_GET_MSRS;
+        let ret = try_with!(
+            self.vcpu_ioctl_with_ref(vcpu, KVM_GET_MSRS(), msrs),
+            "vcpu_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("KVM_GET_MSRS failed with {}", ret);
+        }
+        Ok(ret as usize)
+    }
+
+    /// Write the first `nmsrs` msrs of `msrs`. Like `get_msrs`, returns the number of msrs
+    /// written.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_msrs(&self, vcpu: &VCPU, msrs: &HvMem<kvm_msr_array>) -> Result<usize> {
+        use crate::kvm::ioctls::KVM_SET_MSRS;
+        let ret = try_with!(
+            self.vcpu_ioctl_with_ref(vcpu, KVM_SET_MSRS(), msrs),
+            "vcpu_ioctl failed"
+        );
+        if ret < 0 {
+            bail!("KVM_SET_MSRS failed with {}", ret);
+        }
+        Ok(ret as usize)
+    }
+
+    /// Inject a non-maskable interrupt into the VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn nmi(&self, vcpu: &VCPU) -> Result<()> {
+        use crate::kvm::ioctls::KVM_NMI;
+        let ret = try_with!(self.vcpu_ioctl(vcpu, KVM_NMI(), 0), "vcpu_ioctl failed");
+        if ret != 0 {
+            bail!("KVM_NMI failed with {}", ret);
+        }
+        Ok(())
+    }
+
+    /// Enable or disable debugging features of VCPU, e.g. single stepping, see
+    /// KVM_SET_GUEST_DEBUG.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_guest_debug(&self, vcpu: &VCPU, dbg: &HvMem<kvmb::kvm_guest_debug>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_GUEST_DEBUG;
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_GUEST_DEBUG(), dbg.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        if ret != 0 {
+            bail!("KVM_SET_GUEST_DEBUG failed with {}", ret);
+        }
+        Ok(())
+    }
+
+    /// Run VCPU until its next exit, which is then found in the kvm_run mapping of the vcpu. The
+    /// hypervisor thread of the vcpu must not be in KVM_RUN at the same time.
+    pub fn run(&self, vcpu: &VCPU) -> Result<()> {
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, ioctls::KVM_RUN(), 0),
+            "vcpu_ioctl failed"
+        );
+        if ret != 0 {
+            bail!("KVM_RUN failed with {}", ret);
+        }
+        Ok(())
+    }
+
     /// Unmap memory in the process
     ///
     /// length in bytes.
@@ -354,6 +497,30 @@ impl Tracee {
         proc.munmap(addr, length)
     }
 
+    /// Give the kernel advice about the use of memory in the process, see madvise(2).
+    pub fn madvise(&self, addr: *mut c_void, length: libc::size_t, advice: c_int) -> Result<()> {
+        let proc = self.try_get_proc()?;
+        let ret = proc.madvise(addr, length, advice)?;
+        if ret != 0 {
+            bail!("madvise failed with {}", ret);
+        }
+        Ok(())
+    }
+
+    /// Open a file descriptor in the hypervisor to read binary statistics of the vm or, if
+    /// given, of `vcpu`.
+    pub fn get_stats_fd(&self, vcpu: Option<&VCPU>) -> Result<RawFd> {
+        let ret = match vcpu {
+            Some(vcpu) => self.vcpu_ioctl(vcpu, ioctls::KVM_GET_STATS_FD(), 0),
+            None => self.vm_ioctl(ioctls::KVM_GET_STATS_FD(), 0),
+        };
+        let fd = try_with!(ret, "ioctl failed");
+        if fd < 0 {
+            bail!("KVM_GET_STATS_FD failed with {}", fd);
+        }
+        Ok(fd)
+    }
+
     pub fn close(&self, fd: RawFd) -> Result<i32> {
         let proc = self.try_get_proc()?;
         proc.close(fd)
@@ -371,7 +538,130 @@ impl Tracee {
         get_maps(self)
     }
 
+    pub fn get_memslots(&self) -> Result<Vec<MemSlot>> {
+        get_memslots(self)
+    }
+
     pub fn get_vcpu_maps(&self) -> Result<Vec<Mapping>> {
         get_vcpu_maps(self.pid)
     }
+
+    fn read_hv_bytes(&self, host_addr: usize, buf: &mut [u8]) -> Result<()> {
+        if let Err(e) = process_read_bytes(self.pid, buf, host_addr as *const c_void) {
+            bail!("cannot read hypervisor memory at {:#x}: {}", host_addr, e);
+        }
+        Ok(())
+    }
+
+    fn write_hv_pieces(&self, pieces: &[(usize, usize)], data: &[u8]) -> Result<()> {
+        check_writable()?;
+        let mut offset = 0;
+        for (addr, n) in pieces {
+            let local = [IoVec::from_slice(&data[offset..offset + n])];
+            let remote = [RemoteIoVec {
+                base: *addr,
+                len: *n,
+            }];
+            try_with!(
+                process_vm_writev(self.pid, &local, &remote),
+                "cannot write hypervisor memory at {:#x}",
+                addr
+            );
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Host addresses and lengths of the guest physical memory at `gpa`, split at memslot
+    /// boundaries.
+    fn guest_phys_pieces(&self, gpa: usize, len: usize) -> Result<Vec<(usize, usize)>> {
+        let maps = self.get_maps()?;
+        let mut pieces = vec![];
+        let mut pos = 0;
+        while pos < len {
+            let (addr, slot_left) = host_addr(&maps, gpa + pos)?;
+            let n = std::cmp::min(len - pos, slot_left);
+            pieces.push((addr, n));
+            pos += n;
+        }
+        Ok(pieces)
+    }
+
+    /// Read guest memory at the physical address `gpa`.
+    pub fn read_guest_phys(&self, gpa: usize, buf: &mut [u8]) -> Result<()> {
+        let mut offset = 0;
+        for (addr, n) in self.guest_phys_pieces(gpa, buf.len())? {
+            self.read_hv_bytes(addr, &mut buf[offset..offset + n])?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to guest memory at the physical address `gpa`.
+    pub fn write_guest_phys(&self, gpa: usize, data: &[u8]) -> Result<()> {
+        self.write_hv_pieces(&self.guest_phys_pieces(gpa, data.len())?, data)
+    }
+
+    /// Host addresses and lengths of the memory at guest virtual address `gva`, translated with
+    /// the page tables of `sregs` (e.g. of a vcpu). The pieces are split at page and memslot
+    /// boundaries.
+    fn guest_virt_pieces(
+        &self,
+        sregs: &kvmb::kvm_sregs,
+        gva: usize,
+        len: usize,
+    ) -> Result<Vec<(usize, usize)>> {
+        let maps = self.get_maps()?;
+        let mut read_entry = |phys: usize| -> Result<u64> {
+            let (addr, _) = host_addr(&maps, phys)?;
+            let mut entry = [0u8; 8];
+            self.read_hv_bytes(addr, &mut entry)?;
+            Ok(u64::from_le_bytes(entry))
+        };
+        let levels = page_table::paging_levels(sregs.cr4);
+        let mut pieces = vec![];
+        let mut pos = 0;
+        while pos < len {
+            let virt = gva + pos;
+            let t = require_with!(
+                page_table::walk(&mut read_entry, sregs.cr3, levels, virt)?,
+                "guest virtual address {:#x} is not mapped",
+                virt
+            );
+            let (addr, slot_left) = host_addr(&maps, t.phys_addr)?;
+            let page_left = t.page_size - (virt & (t.page_size - 1));
+            let n = std::cmp::min(len - pos, std::cmp::min(page_left, slot_left));
+            pieces.push((addr, n));
+            pos += n;
+        }
+        Ok(pieces)
+    }
+
+    /// Read guest memory at the virtual address `gva` of the address space of `sregs`, e.g. of a
+    /// vcpu. 4-level and 5-level paging are supported.
+    pub fn read_guest_virt(
+        &self,
+        sregs: &kvmb::kvm_sregs,
+        gva: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let mut offset = 0;
+        for (addr, n) in self.guest_virt_pieces(sregs, gva, buf.len())? {
+            self.read_hv_bytes(addr, &mut buf[offset..offset + n])?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Host address of the guest virtual address `gva`.
+    pub fn guest_virt_to_host(&self, sregs: &kvmb::kvm_sregs, gva: usize) -> Result<usize> {
+        let pieces = self.guest_virt_pieces(sregs, gva, 1)?;
+        Ok(require_with!(pieces.first(), "{:#x} is not mapped", gva).0)
+    }
+
+    /// Like `read_guest_virt`, but writes `data`. Page protection of the guest is ignored, like
+    /// a debugger does.
+    pub fn write_guest_virt(&self, sregs: &kvmb::kvm_sregs, gva: usize, data: &[u8]) -> Result<()> {
+        self.write_hv_pieces(&self.guest_virt_pieces(sregs, gva, data.len())?, data)
+    }
 }
@@ -0,0 +1,301 @@
+//! Report the kvmclock of a guest and how far it is off from host time, see `vmsh clock`.
+//!
+//! KVM_GET_CLOCK gives the kvmclock of the vm, i.e. nanoseconds since the guest booted. Each vcpu
+//! has a pvclock page registered in MSR_KVM_SYSTEM_TIME_NEW that the guest uses to compute the
+//! same clock from its TSC, so we check that both agree. The wall clock page registered in
+//! MSR_KVM_WALL_CLOCK_NEW holds the host time at which the guest booted, as kvm saw it when the
+//! guest registered the page. Boot time plus kvmclock is the wall clock the guest started from:
+//! the guest adjusts its own clock afterwards (i.e. with NTP), which is not visible here.
+//!
+//! The TSC offset is the difference of the guest TSC msr and the host TSC read around it, so it
+//! is only accurate to the few microseconds the ioctl takes and includes the scaling if the
+//! guest runs with a different TSC frequency.
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu_report::read_msr;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::kvm::ioctls;
+use crate::result::Result;
+
+pub struct ClockOptions {
+    pub pid: Pid,
+}
+
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_KVM_WALL_CLOCK: u32 = 0x11;
+const MSR_KVM_SYSTEM_TIME: u32 = 0x12;
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+/// PVCLOCK_TSC_STABLE_BIT in the flags of pvclock_vcpu_time_info
+const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+/// Size of struct pvclock_vcpu_time_info
+const TIME_INFO_SIZE: usize = 32;
+/// Size of struct pvclock_wall_clock
+const WALL_CLOCK_SIZE: usize = 12;
+
+/// struct pvclock_vcpu_time_info
+#[derive(Debug, PartialEq)]
+struct TimeInfo {
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+}
+
+impl TimeInfo {
+    fn parse(b: &[u8; TIME_INFO_SIZE]) -> TimeInfo {
+        TimeInfo {
+            tsc_timestamp: u64::from_le_bytes(b[8..16].try_into().unwrap()),
+            system_time: u64::from_le_bytes(b[16..24].try_into().unwrap()),
+            tsc_to_system_mul: u32::from_le_bytes(b[24..28].try_into().unwrap()),
+            tsc_shift: b[28] as i8,
+            flags: b[29],
+        }
+    }
+
+    /// kvmclock in nanoseconds at the guest TSC value `tsc`, as pvclock_clocksource_read computes
+    /// it.
+    fn clock_at(&self, tsc: u64) -> u64 {
+        let mut delta = tsc.wrapping_sub(self.tsc_timestamp);
+        if self.tsc_shift < 0 {
+            delta >>= -self.tsc_shift as u32;
+        } else {
+            delta <<= self.tsc_shift as u32;
+        }
+        let scaled = ((delta as u128 * self.tsc_to_system_mul as u128) >> 32) as u64;
+        self.system_time.wrapping_add(scaled)
+    }
+}
+
+/// Wall clock time in nanoseconds since the epoch from struct pvclock_wall_clock.
+fn parse_wall_clock(b: &[u8; WALL_CLOCK_SIZE]) -> u64 {
+    let sec = u32::from_le_bytes(b[4..8].try_into().unwrap()) as u64;
+    let nsec = u32::from_le_bytes(b[8..12].try_into().unwrap()) as u64;
+    sec * 1_000_000_000 + nsec
+}
+
+/// Nanoseconds as seconds with sign, i.e. `+0.000123456s`.
+fn format_offset(ns: i128) -> String {
+    let (sign, abs) = if ns < 0 { ('-', -ns) } else { ('+', ns) };
+    format!(
+        "{}{}.{:09}s",
+        sign,
+        abs / 1_000_000_000,
+        abs % 1_000_000_000
+    )
+}
+
+fn format_ns(ns: u64) -> String {
+    format!("{}.{:09}s", ns / 1_000_000_000, ns % 1_000_000_000)
+}
+
+/// Read a structure that kvm updates with a version that is odd while it writes.
+fn read_versioned(vm: &Hypervisor, gpa: usize, buf: &mut [u8]) -> Result<()> {
+    for _ in 0..10 {
+        vm.read_guest_phys(gpa, buf)?;
+        if buf[0] & 1 == 0 {
+            return Ok(());
+        }
+    }
+    bail!(
+        "pvclock structure at {:#x} is constantly being updated",
+        gpa
+    )
+}
+
+/// Guest physical address registered in the new or the legacy msr, None if the guest did not
+/// register one. The system time msrs have an enable bit in bit 0.
+fn registered_page(
+    vm: &Hypervisor,
+    vcpu: &VCPU,
+    new: u32,
+    legacy: u32,
+    enable_bit: bool,
+) -> Result<Option<usize>> {
+    for index in &[new, legacy] {
+        let value = read_msr(vm, vcpu, *index)?.unwrap_or(0);
+        let enabled = if enable_bit {
+            value & 1 != 0
+        } else {
+            value != 0
+        };
+        if enabled {
+            return Ok(Some((value & !1) as usize));
+        }
+    }
+    Ok(None)
+}
+
+fn host_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn get_clock(vm: &Hypervisor) -> Result<ioctls::kvm_clock_data> {
+    let (_, clock) = try_with!(
+        vm.ioctl_with_copy(
+            None,
+            ioctls::KVM_GET_CLOCK(),
+            &ioctls::kvm_clock_data::default()
+        ),
+        "cannot get kvmclock"
+    );
+    Ok(clock)
+}
+
+fn print_clock(vm: &Hypervisor) -> Result<()> {
+    let clock = get_clock(vm)?;
+    let host_realtime = if clock.flags & ioctls::KVM_CLOCK_REALTIME != 0 {
+        clock.realtime as u128
+    } else {
+        try_with!(
+            SystemTime::now().duration_since(UNIX_EPOCH),
+            "invalid host time"
+        )
+        .as_nanos()
+    };
+    println!(
+        "kvmclock:        {} since boot{}",
+        format_ns(clock.clock),
+        if clock.flags & ioctls::KVM_CLOCK_TSC_STABLE != 0 {
+            ", master clock (tsc stable)"
+        } else {
+            ""
+        }
+    );
+    println!("host realtime:   {}", format_ns(host_realtime as u64));
+
+    let wall_clock = registered_page(
+        vm,
+        &vm.vcpus[0],
+        MSR_KVM_WALL_CLOCK_NEW,
+        MSR_KVM_WALL_CLOCK,
+        false,
+    )?;
+    match wall_clock {
+        Some(gpa) => {
+            let mut buf = [0u8; WALL_CLOCK_SIZE];
+            read_versioned(vm, gpa, &mut buf)?;
+            let boot = parse_wall_clock(&buf);
+            let guest = boot as u128 + clock.clock as u128;
+            println!("guest boot time: {}", format_ns(boot));
+            println!("guest wallclock: {}", format_ns(guest as u64));
+            println!(
+                "drift:           {} (guest - host)",
+                format_offset(guest as i128 - host_realtime as i128)
+            );
+        }
+        None => println!("guest wallclock: not registered by the guest"),
+    }
+
+    println!(
+        "\n{:>4} {:>20} {:>20} {:>20} {:>10} {:>5} {:>6} {:>16}",
+        "vcpu", "tsc offset", "tsc_timestamp", "system_time", "mul", "shift", "stable", "skew"
+    );
+    for vcpu in &vm.vcpus {
+        let before = host_tsc();
+        let guest_tsc = match read_msr(vm, vcpu, MSR_IA32_TSC)? {
+            Some(tsc) => tsc,
+            None => bail!("cannot read the tsc of vcpu {}", vcpu.idx),
+        };
+        let after = host_tsc();
+        let tsc_offset = guest_tsc as i128 - (before + (after - before) / 2) as i128;
+        let offset = format!("{:+}", tsc_offset);
+        // With KVM_CLOCK_HOST_TSC we know the host TSC at which kvm sampled its clock and
+        // therefore the guest TSC at that time. Otherwise both are read a few microseconds apart.
+        let now = get_clock(vm)?;
+        let tsc_at_clock = if now.flags & ioctls::KVM_CLOCK_HOST_TSC != 0 {
+            (now.host_tsc as i128 + tsc_offset) as u64
+        } else {
+            guest_tsc
+        };
+
+        let system_time =
+            registered_page(vm, vcpu, MSR_KVM_SYSTEM_TIME_NEW, MSR_KVM_SYSTEM_TIME, true)?;
+        let gpa = match system_time {
+            Some(gpa) => gpa,
+            None => {
+                println!("{:>4} {:>20} pvclock not enabled", vcpu.idx, offset);
+                continue;
+            }
+        };
+        let mut buf = [0u8; TIME_INFO_SIZE];
+        read_versioned(vm, gpa, &mut buf)?;
+        let info = TimeInfo::parse(&buf);
+        // difference of the clock the guest computes on this vcpu to the clock of kvm
+        let skew = info.clock_at(tsc_at_clock) as i128 - now.clock as i128;
+        println!(
+            "{:>4} {:>20} {:>20} {:>20} {:>10} {:>5} {:>6} {:>16}",
+            vcpu.idx,
+            offset,
+            info.tsc_timestamp,
+            format_ns(info.system_time),
+            info.tsc_to_system_mul,
+            info.tsc_shift,
+            if info.flags & PVCLOCK_TSC_STABLE != 0 {
+                "yes"
+            } else {
+                "no"
+            },
+            format_offset(skew)
+        );
+    }
+    Ok(())
+}
+
+pub fn clock(opts: &ClockOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    print_clock(&vm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_info() {
+        let mut b = [0u8; TIME_INFO_SIZE];
+        b[0..4].copy_from_slice(&4u32.to_le_bytes());
+        b[8..16].copy_from_slice(&1_000_000u64.to_le_bytes());
+        b[16..24].copy_from_slice(&5_000_000_000u64.to_le_bytes());
+        // 2 GHz: 0.5 ns per cycle, mul = 0.5 * 2^32 with a shift of 0
+        b[24..28].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        b[28] = 0;
+        b[29] = PVCLOCK_TSC_STABLE;
+        let info = TimeInfo::parse(&b);
+        assert_eq!(info.flags, PVCLOCK_TSC_STABLE);
+        assert_eq!(info.clock_at(1_000_000), 5_000_000_000);
+        assert_eq!(info.clock_at(3_000_000), 5_001_000_000);
+
+        // same frequency expressed with a shift of -1 and a factor of 1
+        b[24..28].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+        b[28] = -1i8 as u8;
+        let info = TimeInfo::parse(&b);
+        assert_eq!(info.clock_at(3_000_001), 5_000_999_999);
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let mut b = [0u8; WALL_CLOCK_SIZE];
+        b[4..8].copy_from_slice(&1_700_000_000u32.to_le_bytes());
+        b[8..12].copy_from_slice(&5u32.to_le_bytes());
+        assert_eq!(parse_wall_clock(&b), 1_700_000_000_000_000_005);
+    }
+
+    #[test]
+    fn test_format_offset() {
+        assert_eq!(format_offset(123_456), "+0.000123456s");
+        assert_eq!(format_offset(-2_500_000_000), "-2.500000000s");
+        assert_eq!(format_ns(1_000_000_001), "1.000000001s");
+    }
+}
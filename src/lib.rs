@@ -11,20 +11,82 @@
 //)]
 
 pub mod attach;
+pub mod breakpoint;
+pub mod bundle;
+pub mod bytes;
+pub mod cert_scan;
+pub mod compat;
+pub mod containers;
+pub mod core_file;
 pub mod coredump;
 pub mod cpu;
+pub mod cpu_report;
+pub mod crashdump;
+pub mod daemon;
 pub mod debug;
+pub mod dedup;
 pub mod devices;
 pub mod elf;
+pub mod encrypt;
+pub mod exec;
+pub mod expect;
+pub mod fleet;
+pub mod format;
+pub mod fscheck;
+pub mod ftrace_dump;
+pub mod gc;
+pub mod gdbstub;
+pub mod guest_access;
 pub mod guest_mem;
+pub mod guest_trace;
+pub mod host_pressure;
+pub mod hotplug;
 pub mod inspect;
 pub mod interrutable_thread;
+pub mod irq;
+pub mod irqstorm;
+pub mod json;
+pub mod kdump;
 pub mod kernel;
 pub mod kvm;
+pub mod kvmclock;
 pub mod loader;
+pub mod lockstat;
+pub mod manifest;
+pub mod memreport;
+pub mod memwatch;
+pub mod metrics;
+pub mod net_check;
+pub mod pacing;
 pub mod page_math;
 pub mod page_table;
+pub mod pagemap;
+pub mod pmu;
+pub mod poke;
+pub mod process_dump;
+pub mod profile;
+pub mod ps;
+pub mod remote;
 pub mod result;
+pub mod rewind;
+pub mod route;
+pub mod sampling;
+pub mod sched_diag;
+pub mod screenshot;
+pub mod scrub;
+pub mod security_audit;
+pub mod selftest;
+pub mod sha256;
 pub mod signal_handler;
+pub mod snapshot;
 pub mod stage1;
+pub mod stats;
+pub mod step;
+pub mod swap;
+pub mod symbolizer;
 pub mod tracer;
+pub mod vcat;
+pub mod vcpu_pin;
+pub mod virtio_ls;
+pub mod vmi;
+pub mod watchdog;
@@ -1,17 +1,22 @@
 use std::collections::HashMap;
-use std::mem::{size_of, size_of_val};
-use std::ptr;
+use std::convert::TryFrom;
+use std::mem::size_of;
 
 use elfloader::{
-    ElfBinary, ElfLoader, ElfLoaderErr, Entry, Flags, LoadableHeaders, Rela, TypeRela64, VAddr, P64,
+    ElfBinary, ElfLoader, ElfLoaderErr, Entry, Flags, LoadableHeaders, Rela, VAddr, P64,
 };
 use log::{debug, error, info, warn};
 use nix::sys::mman::ProtFlags;
 use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
 use simple_error::{bail, require_with, try_with};
-use stage1_interface::{DeviceState, Stage1Args};
+use stage1_interface::{
+    DeviceAction, DeviceState, HotplugAction, HotplugMemory, Stage1Args, STAGE1_ABI_VERSION,
+    STAGE1_ARGS_MAGIC,
+};
+use xmas_elf::header::Machine;
 use xmas_elf::sections::{SectionData, SHN_UNDEF};
 use xmas_elf::symbol_table::{Binding, DynEntry64};
+use xmas_elf::ElfFile;
 
 use crate::guest_mem::MappedMemory;
 use crate::kernel::{Kernel, LINUX_KERNEL_KASLR_RANGE};
@@ -20,7 +25,7 @@ use crate::kvm::PhysMemAllocator;
 use crate::page_math::{page_align, page_start};
 use crate::page_table::VirtMem;
 use crate::result::Result;
-use crate::stage1::{DeviceStatus, DriverStatus};
+use crate::stage1::{DeviceHandles, DeviceStatus, DriverStatus, HotplugRegion, Stage1Device};
 use crate::try_core_res;
 
 pub struct Loader<'a> {
@@ -44,13 +49,135 @@ pub struct Loader<'a> {
     dyn_syms: &'a [DynEntry64],
     /// virtual address to `VMSH_STAGE1_ARGS` struct, used to write stage1 arguments
     vmsh_stage1_args: usize,
-    /// How much space we need to reserve for strings for stage1_args.
+    /// Address to write and ifunc resolver of each IRELATIVE relocation, for stage1 to apply
+    irelative: Vec<(usize, usize)>,
+    /// How much space we need to reserve for the arrays and strings of stage1_args.
     /// Needs to be page aligned
-    string_arg_size: usize,
+    args_size: usize,
     /// virtual address of the `vmsh_stage1_init` function
     pub init_func: usize,
 }
 
+/// `magic` and `abi_version` are those that stage1 initializes `VMSH_STAGE1_ARGS` with.
+fn check_stage1_abi(magic: u32, abi_version: u32) -> Result<()> {
+    if magic != STAGE1_ARGS_MAGIC {
+        bail!("stage1 was built without the abi version of its arguments, rebuild vmsh and stage1 together");
+    }
+    if abi_version != STAGE1_ABI_VERSION {
+        bail!(
+            "stage1 expects arguments of abi version {}, but vmsh writes version {}. Rebuild vmsh and stage1 together",
+            abi_version,
+            STAGE1_ABI_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Guest virtual addresses of the arrays in the args mapping
+struct ArgsLayout {
+    argv: usize,
+    envp: usize,
+    device_addrs: usize,
+    device_irqs: usize,
+    device_cpus: usize,
+    device_ids: usize,
+    device_handles: usize,
+    irelative: usize,
+}
+
+const GUEST_PTR_SIZE: usize = size_of::<u64>();
+
+/// Size of the args mapping: the pointer arrays of argv and envp including their terminating
+/// null pointers, the device tables, the pairs of `irelative` relocations and the strings of
+/// argv and envp.
+fn args_size(
+    command: &[String],
+    environment: &[String],
+    devices: &[Stage1Device],
+    irelative: usize,
+) -> usize {
+    let tables = (command.len() + 1 + environment.len() + 1 + 5 * devices.len() + 2 * irelative)
+        * GUEST_PTR_SIZE;
+    let strings = command.iter().chain(environment).map(|c| c.len() + 1);
+    tables + strings.sum::<usize>()
+}
+
+/// Content of the args mapping at the guest virtual address `base`. The arrays come first, so
+/// they are aligned, followed by the null terminated strings argv and envp point to.
+fn args_content(
+    base: usize,
+    command: &[String],
+    environment: &[String],
+    devices: &[Stage1Device],
+    irelative: &[(usize, usize)],
+) -> (Vec<u8>, ArgsLayout) {
+    let argv = 0;
+    let envp = argv + (command.len() + 1) * GUEST_PTR_SIZE;
+    let device_addrs = envp + (environment.len() + 1) * GUEST_PTR_SIZE;
+    let device_irqs = device_addrs + devices.len() * GUEST_PTR_SIZE;
+    let device_cpus = device_irqs + devices.len() * GUEST_PTR_SIZE;
+    let device_ids = device_cpus + devices.len() * GUEST_PTR_SIZE;
+    let device_handles = device_ids + devices.len() * GUEST_PTR_SIZE;
+    let irelative_table = device_handles + devices.len() * GUEST_PTR_SIZE;
+    let strings = irelative_table + 2 * irelative.len() * GUEST_PTR_SIZE;
+
+    let mut content = vec![0u8; strings];
+    for (array, values) in &[(argv, command), (envp, environment)] {
+        for (i, value) in values.iter().enumerate() {
+            let ptr = (base + content.len()) as u64;
+            let entry = array + i * GUEST_PTR_SIZE;
+            content[entry..entry + GUEST_PTR_SIZE].copy_from_slice(&ptr.to_ne_bytes());
+            content.extend_from_slice(value.as_bytes());
+            content.push(b'\0');
+        }
+    }
+    for (i, device) in devices.iter().enumerate() {
+        let cpu = device.cpu.map_or(-1, i64::from);
+        let values = [
+            (device_addrs, device.mmio_addr.to_ne_bytes()),
+            (device_irqs, u64::from(device.irq).to_ne_bytes()),
+            (device_cpus, cpu.to_ne_bytes()),
+            (device_ids, u64::from(device.id).to_ne_bytes()),
+            (device_handles, device.handle.to_ne_bytes()),
+        ];
+        for (table, value) in values.iter() {
+            let entry = table + i * GUEST_PTR_SIZE;
+            content[entry..entry + GUEST_PTR_SIZE].copy_from_slice(value);
+        }
+    }
+    for (i, (place, resolver)) in irelative.iter().enumerate() {
+        let entry = irelative_table + 2 * i * GUEST_PTR_SIZE;
+        content[entry..entry + GUEST_PTR_SIZE].copy_from_slice(&(*place as u64).to_ne_bytes());
+        content[entry + GUEST_PTR_SIZE..entry + 2 * GUEST_PTR_SIZE]
+            .copy_from_slice(&(*resolver as u64).to_ne_bytes());
+    }
+    let layout = ArgsLayout {
+        argv: base + argv,
+        envp: base + envp,
+        device_addrs: base + device_addrs,
+        device_irqs: base + device_irqs,
+        device_cpus: base + device_cpus,
+        device_ids: base + device_ids,
+        device_handles: base + device_handles,
+        irelative: base + irelative_table,
+    };
+    (content, layout)
+}
+
+/// Number of IRELATIVE relocations in `file`, their resolvers are listed in the args mapping.
+fn count_irelative(file: &ElfFile) -> usize {
+    let machine = file.header.pt2.machine().as_machine();
+    file.section_iter()
+        .map(|section| match section.get_data(file) {
+            Ok(SectionData::Rela64(entries)) => entries
+                .iter()
+                .filter(|e| relocation_kind(machine, e.get_type()) == Some(Relocation::IRelative))
+                .count(),
+            _ => 0,
+        })
+        .sum()
+}
+
 fn find_loadable(loadables: &mut [Loadable], addr: usize) -> Option<&mut Loadable> {
     loadables
         .iter_mut()
@@ -104,7 +231,8 @@ impl<'a> Loader<'a> {
                 "no cleanup_vmsh_stage1 symbol found"
             ),
             lib_syms: syms,
-            string_arg_size: 0,
+            args_size: 0,
+            irelative: vec![],
         })
     }
 
@@ -138,12 +266,72 @@ impl<'a> Loader<'a> {
         self.kernel.range.end
     }
 
+    /// The hotplug functions are only available with CONFIG_MEMORY_HOTPLUG, so stage1 does not
+    /// link against them but gets their addresses from us.
+    fn hotplug_args(&self, region: &HotplugRegion) -> Result<HotplugMemory> {
+        let symbol = |name: &str| {
+            Ok(*require_with!(
+                self.kernel.symbols.get(name),
+                "guest kernel does not export {}, memory hotplug requires linux 5.8 or newer with CONFIG_MEMORY_HOTPLUG",
+                name
+            ) as u64)
+        };
+        // stage1 only removes memory that is marked as plugged
+        let status = if region.action == HotplugAction::Remove {
+            DeviceState::Ready
+        } else {
+            DeviceState::Undefined
+        };
+        Ok(HotplugMemory {
+            start: region.start,
+            size: region.size,
+            node: region.node,
+            add_memory: symbol("add_memory_driver_managed")?,
+            remove_memory: symbol("offline_and_remove_memory")?,
+            remove_with_node: region.remove_with_node,
+            action: region.action,
+            status,
+        })
+    }
+
+    /// `irq_set_affinity()` is exported since linux 5.13. Before, `irq_set_affinity_hint()`
+    /// with the same signature also set the affinity.
+    fn irq_set_affinity(&self) -> Result<u64> {
+        let addr = self
+            .kernel
+            .symbols
+            .get("irq_set_affinity")
+            .or_else(|| self.kernel.symbols.get("irq_set_affinity_hint"));
+        Ok(*require_with!(
+            addr,
+            "guest kernel exports neither irq_set_affinity nor irq_set_affinity_hint, cannot pin device interrupts"
+        ) as u64)
+    }
+
     fn write_stage1_args(
         &mut self,
         command: &[String],
-        mmio_ranges: Vec<u64>,
-    ) -> Result<(DeviceStatus, DriverStatus)> {
-        let string_mapping = self
+        environment: &[String],
+        devices: Vec<Stage1Device>,
+        device_action: DeviceAction,
+        hotplug: Option<&HotplugRegion>,
+    ) -> Result<(
+        DeviceStatus,
+        DriverStatus,
+        Option<DriverStatus>,
+        DeviceHandles,
+    )> {
+        let hotplug = match hotplug {
+            Some(region) => Some(self.hotplug_args(region)?),
+            None => None,
+        };
+        let irq_set_affinity = if devices.iter().any(|d| d.cpu.is_some()) {
+            self.irq_set_affinity()?
+        } else {
+            0
+        };
+
+        let args_mapping = self
             .virt_mem
             .as_ref()
             .unwrap()
@@ -152,26 +340,30 @@ impl<'a> Loader<'a> {
             .unwrap()
             .clone();
 
-        let mut strings: Vec<u8> = Vec::with_capacity(self.string_arg_size);
-
-        let mut argv = command
-            .iter()
-            .map(|arg| {
-                let ptr = strings.len() + string_mapping.virt_start;
-                strings.extend_from_slice(arg.as_bytes());
-                // make string null-terminated
-                strings.push(b'\0');
-                ptr as *mut libc::c_char
-            })
-            .collect::<Vec<_>>();
-
+        let (content, layout) = args_content(
+            args_mapping.virt_start,
+            command,
+            environment,
+            &devices,
+            &self.irelative,
+        );
+        if content.len() > args_mapping.len {
+            bail!(
+                "stage1 args need {:#x} bytes, but only {:#x} were reserved",
+                content.len(),
+                args_mapping.len
+            );
+        }
+        let device_handles = DeviceHandles {
+            host_addr: layout.device_handles - args_mapping.virt_start
+                + args_mapping.phys_start.host_addr(),
+            count: devices.len(),
+        };
         self.loadables.push(Loadable {
-            content: strings,
-            mapping: string_mapping,
+            content,
+            mapping: args_mapping,
             virt_offset: 0,
         });
-        // make argv null-terminated
-        argv.push(ptr::null_mut());
 
         let addr = self.vmsh_stage1_args;
         let loadable = require_with!(
@@ -191,10 +383,28 @@ impl<'a> Loader<'a> {
         }
         let stage1_args = loadable.content[range].as_mut_ptr() as *mut Stage1Args;
         let stage1_args = unsafe { &mut (*stage1_args) };
-
-        stage1_args.argv[0..argv.len()].clone_from_slice(argv.as_slice());
-        stage1_args.device_addrs[0..mmio_ranges.len()].clone_from_slice(&mmio_ranges);
+        // check before writing anything, other versions might have a different layout
+        check_stage1_abi(stage1_args.magic, stage1_args.abi_version)?;
+        stage1_args.magic = STAGE1_ARGS_MAGIC;
+        stage1_args.abi_version = STAGE1_ABI_VERSION;
+
+        stage1_args.argv = layout.argv as *mut _;
+        stage1_args.envp = layout.envp as *mut _;
+        stage1_args.device_addrs = layout.device_addrs as *const _;
+        stage1_args.device_irqs = layout.device_irqs as *const _;
+        stage1_args.device_cpus = layout.device_cpus as *const _;
+        stage1_args.device_ids = layout.device_ids as *const _;
+        stage1_args.device_handles = layout.device_handles as *mut _;
+        stage1_args.device_count = devices.len() as u64;
+        stage1_args.device_action = device_action;
+        stage1_args.irq_set_affinity = irq_set_affinity;
         stage1_args.device_status = DeviceState::Initializing;
+        stage1_args.irelative = layout.irelative as *const _;
+        stage1_args.irelative_count = self.irelative.len() as u64;
+        let hotplug_enabled = hotplug.is_some();
+        if let Some(hotplug) = hotplug {
+            stage1_args.hotplug = hotplug;
+        }
 
         let stage1_args_addr = stage1_args as *const Stage1Args as usize;
 
@@ -202,8 +412,17 @@ impl<'a> Loader<'a> {
             &stage1_args.device_status as *const DeviceState as usize - stage1_args_addr;
         let drv_offset =
             &stage1_args.driver_status as *const DeviceState as usize - stage1_args_addr;
+        let hotplug_offset =
+            &stage1_args.hotplug.status as *const DeviceState as usize - stage1_args_addr;
         let host_offset =
             addr - loadable.mapping.virt_start + loadable.mapping.phys_start.host_addr();
+        let hotplug_status = if hotplug_enabled {
+            Some(DriverStatus {
+                host_addr: host_offset + hotplug_offset,
+            })
+        } else {
+            None
+        };
         Ok((
             DeviceStatus {
                 host_addr: host_offset + dev_offset,
@@ -211,26 +430,44 @@ impl<'a> Loader<'a> {
             DriverStatus {
                 host_addr: host_offset + drv_offset,
             },
+            hotplug_status,
+            device_handles,
         ))
     }
 
     pub fn load_binary(
         &mut self,
         command: &[String],
-        mmio_ranges: Vec<u64>,
-    ) -> Result<(VirtMem, DeviceStatus, DriverStatus)> {
+        environment: &[String],
+        devices: Vec<Stage1Device>,
+        device_action: DeviceAction,
+        hotplug: Option<&HotplugRegion>,
+    ) -> Result<(
+        VirtMem,
+        DeviceStatus,
+        DriverStatus,
+        Option<DriverStatus>,
+        DeviceHandles,
+    )> {
         let binary = try_core_res!(ElfBinary::new(self.binary), "cannot parse elf binary");
 
-        self.string_arg_size = page_align(command.iter().map(|c| c.len() + 1).sum());
+        let irelative = count_irelative(&binary.file);
+        self.args_size = page_align(args_size(command, environment, &devices, irelative));
         try_core_res!(binary.load(self), "cannot load elf binary");
 
-        let (device_status, driver_status) = try_with!(
-            self.write_stage1_args(command, mmio_ranges),
+        let (device_status, driver_status, hotplug_status, device_handles) = try_with!(
+            self.write_stage1_args(command, environment, devices, device_action, hotplug),
             "failed to write stage1 arguments"
         );
 
         try_with!(self.upload_binary(), "failed to upload binary to vm");
-        Ok((self.virt_mem.take().unwrap(), device_status, driver_status))
+        Ok((
+            self.virt_mem.take().unwrap(),
+            device_status,
+            driver_status,
+            hotplug_status,
+            device_handles,
+        ))
     }
 }
 
@@ -294,11 +531,11 @@ impl<'a> ElfLoader for Loader<'a> {
         allocs.sort_by_key(|k| k.virt_start);
         let last_addr = allocs.last().unwrap().virt_end();
 
-        // put strings for stage1 args before elf binary
+        // put arrays and strings for stage1 args after elf binary
         let last = VirtAlloc {
             virt_start: last_addr,
             virt_offset: 0,
-            len: self.string_arg_size,
+            len: self.args_size,
             prot: ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
         };
         if !LINUX_KERNEL_KASLR_RANGE.contains(&(last.virt_start + last.len)) {
@@ -378,7 +615,8 @@ impl<'a> ElfLoader for Loader<'a> {
     }
 
     fn relocate(&mut self, entry: &Rela<P64>) -> ElfResult {
-        let typ = TypeRela64::from(entry.get_type());
+        let machine = self.elf.file.header.pt2.machine().as_machine();
+        let typ = entry.get_type();
         let addr = self.vbase() + entry.get_offset() as usize;
         let syms = &self.kernel.symbols;
         let lib_syms = &self.lib_syms;
@@ -393,42 +631,252 @@ impl<'a> ElfLoader for Loader<'a> {
         });
         let start = addr - (loadable.mapping.virt_start + loadable.virt_offset);
 
-        match typ {
-            TypeRela64::R_RELATIVE => {
-                // This is a relative relocation, add the offset (where we put our
-                // binary in the vspace) to the addend and we're done.
-                let dest_addr = vbase + entry.get_addend() as usize;
-                debug!("R_RELATIVE *{:#x} = {:#x}", addr, dest_addr);
-                let range = start..(start + size_of_val(&dest_addr));
-                loadable.content[range].clone_from_slice(&dest_addr.to_ne_bytes());
-                Ok(())
+        let kind = match relocation_kind(machine, typ) {
+            Some(kind) => kind,
+            None => {
+                warn!("loader: unhandled relocation {} for {:?}", typ, machine);
+                return Err(ElfLoaderErr::UnsupportedRelocationEntry);
+            }
+        };
+        let symbol = if kind.needs_symbol() {
+            let sym = &self.dyn_syms[entry.get_symbol_table_index() as usize];
+            if sym.get_binding()? == Binding::Weak {
+                // we have some weak symbols that are included by default
+                // but not used for anything in the kernel.
+                // Seem to be safe to ignore
+                return Ok(());
             }
-            TypeRela64::R_GLOB_DAT => {
-                let sym = &self.dyn_syms[entry.get_symbol_table_index() as usize];
-                if sym.get_binding()? == Binding::Weak {
-                    // we have some weak symbols that are included by default
-                    // but not used for anything in the kernel.
-                    // Seem to be safe to ignore
-                    return Ok(());
-                }
-
-                let sym_name = sym.get_name(&self.elf.file)?;
-                debug!("R_GLOB_DAT *{:#x} = @ {}", addr, sym_name);
-                let res = syms.get(sym_name).or_else(|| lib_syms.get(sym_name));
-                let symbol = require_elf!(res, {
-                    error!("binary requires unknown symbol: {}", sym_name);
-                    "cannot find symbol"
-                });
-                let dest_addr = (symbol + entry.get_addend() as usize).to_ne_bytes();
-                let range = start..(start + size_of_val(symbol));
-                loadable.content[range].clone_from_slice(&dest_addr);
-
-                Ok(())
+
+            let sym_name = sym.get_name(&self.elf.file)?;
+            debug!("{:?} *{:#x} = @ {}", kind, addr, sym_name);
+            let res = syms.get(sym_name).or_else(|| lib_syms.get(sym_name));
+            Some(*require_elf!(res, {
+                error!("binary requires unknown symbol: {}", sym_name);
+                "cannot find symbol"
+            }))
+        } else {
+            None
+        };
+        let value = require_elf!(
+            relocation_value(&kind, vbase, symbol, entry.get_addend() as i64, addr),
+            {
+                error!(
+                    "cannot apply {:?} at {:#x}: the target is out of range",
+                    kind, addr
+                );
+                "cannot apply relocation"
             }
-            other => {
-                warn!("loader: unhandled relocation: {:?}", other);
-                Err(ElfLoaderErr::UnsupportedRelocationEntry)
+        );
+        debug!("{:?} *{:#x} = {:x?}", kind, addr, value);
+        if kind == Relocation::IRelative {
+            self.irelative
+                .push((addr, vbase.wrapping_add(entry.get_addend() as usize)));
+        }
+        let range = start..(start + value.len());
+        loadable.content[range].clone_from_slice(&value);
+        Ok(())
+    }
+}
+
+/// Relocations stage1 is linked with, independent of the architecture.
+#[derive(Clone, Debug, PartialEq)]
+enum Relocation {
+    /// B + A
+    Relative,
+    /// S + A
+    GlobDat,
+    /// S + A
+    JumpSlot,
+    /// S + A
+    Abs64,
+    /// S + A - P, 32 bit
+    Pc32,
+    /// The value returned by the ifunc resolver at B + A. The resolver has to run in the guest,
+    /// so the loader writes B + A and stage1 replaces it before anything else runs.
+    IRelative,
+}
+
+impl Relocation {
+    fn needs_symbol(&self) -> bool {
+        !matches!(self, Relocation::Relative | Relocation::IRelative)
+    }
+}
+
+/// Maps the `r_type` of a relocation in an elf binary for `machine` to what the loader has to do.
+/// The AArch64 types are only the relocation side, vmsh itself is x86_64 only so far.
+fn relocation_kind(machine: Machine, typ: u32) -> Option<Relocation> {
+    match (machine, typ) {
+        (Machine::X86_64, 1) => Some(Relocation::Abs64),
+        // R_X86_64_PC32 and R_X86_64_PLT32, we have no PLT so the latter is the same
+        (Machine::X86_64, 2) | (Machine::X86_64, 4) => Some(Relocation::Pc32),
+        (Machine::X86_64, 6) => Some(Relocation::GlobDat),
+        (Machine::X86_64, 7) => Some(Relocation::JumpSlot),
+        (Machine::X86_64, 8) => Some(Relocation::Relative),
+        (Machine::X86_64, 37) => Some(Relocation::IRelative),
+        (Machine::AArch64, 257) => Some(Relocation::Abs64),
+        (Machine::AArch64, 1025) => Some(Relocation::GlobDat),
+        (Machine::AArch64, 1026) => Some(Relocation::JumpSlot),
+        (Machine::AArch64, 1027) => Some(Relocation::Relative),
+        _ => None,
+    }
+}
+
+/// Bytes to write at `place` for relocation `kind` of a binary loaded at `base`, None if the value
+/// does not fit or cannot be computed on the host.
+fn relocation_value(
+    kind: &Relocation,
+    base: usize,
+    symbol: Option<usize>,
+    addend: i64,
+    place: usize,
+) -> Option<Vec<u8>> {
+    let add = |value: usize| (value as i64).wrapping_add(addend) as usize;
+    match kind {
+        Relocation::Relative | Relocation::IRelative => Some(add(base).to_ne_bytes().to_vec()),
+        Relocation::GlobDat | Relocation::JumpSlot | Relocation::Abs64 => {
+            Some(add(symbol?).to_ne_bytes().to_vec())
+        }
+        Relocation::Pc32 => {
+            let value = (add(symbol?) as i64).wrapping_sub(place as i64);
+            let value = i32::try_from(value).ok()?;
+            Some(value.to_ne_bytes().to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocation_kind() {
+        assert_eq!(
+            relocation_kind(Machine::X86_64, 8),
+            Some(Relocation::Relative)
+        );
+        assert_eq!(
+            relocation_kind(Machine::AArch64, 1027),
+            Some(Relocation::Relative)
+        );
+        assert_eq!(
+            relocation_kind(Machine::AArch64, 1026),
+            Some(Relocation::JumpSlot)
+        );
+        // R_X86_64_RELATIVE means nothing on arm64
+        assert_eq!(relocation_kind(Machine::AArch64, 8), None);
+    }
+
+    #[test]
+    fn test_stage1_abi_mismatch() {
+        // all-zero is valid for the enums, pointers and numbers in it
+        let mut args: Stage1Args = unsafe { std::mem::zeroed() };
+        args.magic = STAGE1_ARGS_MAGIC;
+        args.abi_version = STAGE1_ABI_VERSION - 1;
+        assert!(!args.check_abi());
+        assert_eq!(args.driver_status, DeviceState::Error);
+        // vmsh finds the error at the same offset in stage1 builds of other versions
+        let base = &args as *const Stage1Args as usize;
+        assert_eq!(
+            &args.driver_status as *const DeviceState as usize - base,
+            2 * size_of::<u32>()
+        );
+
+        args.abi_version = STAGE1_ABI_VERSION;
+        args.driver_status = DeviceState::Undefined;
+        assert!(args.check_abi());
+        assert_eq!(args.driver_status, DeviceState::Undefined);
+    }
+
+    #[test]
+    fn test_args_content() {
+        let base = 0x1000;
+        let command = vec![String::from("/dev/.vmsh"), String::from("ls")];
+        let environment = vec![String::from("LANG=C")];
+        let irelative = [(0x2000, 0x3000)];
+        let (content, layout) = args_content(base, &command, &environment, &[], &irelative);
+        assert_eq!(content.len(), args_size(&command, &environment, &[], 1));
+        let ptr = |addr: usize| {
+            let offset = addr - base;
+            let mut buf = [0u8; GUEST_PTR_SIZE];
+            buf.copy_from_slice(&content[offset..offset + GUEST_PTR_SIZE]);
+            u64::from_ne_bytes(buf) as usize
+        };
+        let string = |addr: usize| {
+            let tail = &content[addr - base..];
+            let len = tail.iter().position(|b| *b == 0).unwrap();
+            String::from_utf8_lossy(&tail[..len]).into_owned()
+        };
+        assert_eq!(string(ptr(layout.argv + GUEST_PTR_SIZE)), "ls");
+        assert_eq!(ptr(layout.argv + 2 * GUEST_PTR_SIZE), 0);
+        assert_eq!(string(ptr(layout.envp)), "LANG=C");
+        assert_eq!(ptr(layout.envp + GUEST_PTR_SIZE), 0);
+        assert_eq!(ptr(layout.irelative), 0x2000);
+        assert_eq!(ptr(layout.irelative + GUEST_PTR_SIZE), 0x3000);
+    }
+
+    #[test]
+    fn test_relocation_value() {
+        let kernel = 0xffff_ffff_8100_0000usize;
+        let base = 0xffff_ffff_c000_0000usize;
+        let abs = relocation_value(&Relocation::Abs64, base, Some(kernel), 0x10, base);
+        assert_eq!(abs, Some((kernel + 0x10).to_ne_bytes().to_vec()));
+        let rel = relocation_value(&Relocation::Relative, base, None, 0x20, base);
+        assert_eq!(rel, Some((base + 0x20).to_ne_bytes().to_vec()));
+        // call printk from stage1, the kernel is within 2GB
+        let pc32 = relocation_value(&Relocation::Pc32, base, Some(kernel), -4, base + 0x100);
+        let expected = (kernel as i64 - 4 - (base + 0x100) as i64) as i32;
+        assert_eq!(pc32, Some(expected.to_ne_bytes().to_vec()));
+        assert_eq!(
+            relocation_value(&Relocation::Pc32, base, Some(0x7fff_0000_0000), 0, base),
+            None
+        );
+        // the resolver, stage1 replaces it with what the resolver returns
+        assert_eq!(
+            relocation_value(&Relocation::IRelative, base, None, 0x30, base),
+            Some((base + 0x30).to_ne_bytes().to_vec())
+        );
+    }
+
+    /// Relocation types in a fixture, see tests/fixtures/loader/relocations.c
+    fn fixture_relocations(binary: &[u8]) -> Vec<Option<Relocation>> {
+        let file = ElfFile::new(binary).unwrap();
+        let machine = file.header.pt2.machine().as_machine();
+        let mut kinds = vec![];
+        for section in file.section_iter() {
+            if let Ok(SectionData::Rela64(entries)) = section.get_data(&file) {
+                kinds.extend(
+                    entries
+                        .iter()
+                        .map(|e| relocation_kind(machine, e.get_type())),
+                );
             }
         }
+        kinds
+    }
+
+    #[test]
+    fn test_fixture_object() {
+        let kinds = fixture_relocations(include_bytes!("../tests/fixtures/loader/relocations.o"));
+        assert!(kinds.iter().all(Option::is_some));
+        for kind in &[Relocation::Abs64, Relocation::Pc32] {
+            assert!(kinds.contains(&Some(kind.clone())), "no {:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_fixture_shared_object() {
+        let binary = include_bytes!("../tests/fixtures/loader/relocations.so");
+        let kinds = fixture_relocations(binary);
+        assert!(kinds.iter().all(Option::is_some));
+        for kind in &[
+            Relocation::Abs64,
+            Relocation::Relative,
+            Relocation::GlobDat,
+            Relocation::JumpSlot,
+            Relocation::IRelative,
+        ] {
+            assert!(kinds.contains(&Some(kind.clone())), "no {:?}", kind);
+        }
+        assert_eq!(count_irelative(&ElfFile::new(binary).unwrap()), 1);
     }
 }
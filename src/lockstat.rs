@@ -0,0 +1,354 @@
+//! Find contended locks in the guest kernel without running anything in the guest, see
+//! `vmsh lockstat`.
+//!
+//! vmsh samples the registers of all vcpus at a fixed interval and counts samples whose
+//! instruction pointer is in one of the slowpaths of kernel locks, i.e. where a vcpu spins or
+//! sleeps on a lock that someone else holds. Samples are grouped by the function that took the
+//! lock, which is the first kernel address on the stack outside of lock functions. The stack may
+//! contain stale return addresses and pointers to kernel data, so the caller is a good guess
+//! rather than a reliable backtrace. Spinlock slowpaths get the lock in rdi and usually keep it
+//! there, so the lock address is shown for them.
+//!
+//! Most slowpaths are static functions, so the analysis needs the full symbols of the guest
+//! kernel (`--symbols vmlinux:PATH` or `system-map:PATH`): the exported symbols only cover the
+//! spinlock slowpaths. Like any sampling, this only shows where vcpus spend time. A lock with
+//! waiters that sleep shows up in the scheduler instead.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::time::Duration;
+
+use crate::gdbstub::open_symbols;
+use crate::kvm;
+use crate::result::Result;
+use crate::sampling::{percent, sample_vcpus, VcpuSample};
+use crate::signal_handler::Cancellation;
+use crate::symbolizer::{self, SymbolSource, Symbolizer};
+
+pub struct LockStatOptions {
+    pub pid: Pid,
+    pub duration: Duration,
+    pub interval: Duration,
+    pub symbols: SymbolSource,
+    /// Number of locks to show.
+    pub top: usize,
+}
+
+/// Number of stack words searched for the caller of the lock.
+const STACK_WORDS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LockKind {
+    Spinlock,
+    Rwlock,
+    Mutex,
+    Rwsem,
+    RtMutex,
+    /// Optimistic spinning of mutexes and rwsems on their owner
+    OptimisticSpin,
+}
+
+impl fmt::Display for LockKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LockKind::Spinlock => "spinlock",
+            LockKind::Rwlock => "rwlock",
+            LockKind::Mutex => "mutex",
+            LockKind::Rwsem => "rwsem",
+            LockKind::RtMutex => "rt_mutex",
+            LockKind::OptimisticSpin => "osq",
+        };
+        f.pad(name)
+    }
+}
+
+/// Functions in which a vcpu waits for a lock, across the kernel versions since 4.x.
+const SLOWPATHS: &[(&str, LockKind)] = &[
+    ("native_queued_spin_lock_slowpath", LockKind::Spinlock),
+    ("__pv_queued_spin_lock_slowpath", LockKind::Spinlock),
+    ("queued_spin_lock_slowpath", LockKind::Spinlock),
+    ("pv_wait_head_or_lock", LockKind::Spinlock),
+    ("pv_wait_node", LockKind::Spinlock),
+    ("kvm_wait", LockKind::Spinlock),
+    ("queued_read_lock_slowpath", LockKind::Rwlock),
+    ("queued_write_lock_slowpath", LockKind::Rwlock),
+    ("__mutex_lock", LockKind::Mutex),
+    ("__mutex_lock_slowpath", LockKind::Mutex),
+    ("__mutex_lock_killable_slowpath", LockKind::Mutex),
+    ("__mutex_lock_interruptible_slowpath", LockKind::Mutex),
+    ("__ww_mutex_lock", LockKind::Mutex),
+    ("rwsem_down_read_slowpath", LockKind::Rwsem),
+    ("rwsem_down_write_slowpath", LockKind::Rwsem),
+    ("rwsem_down_read_failed", LockKind::Rwsem),
+    ("rwsem_down_write_failed", LockKind::Rwsem),
+    ("rt_mutex_slowlock", LockKind::RtMutex),
+    ("__rt_mutex_slowlock", LockKind::RtMutex),
+    ("rt_mutex_slowlock_block", LockKind::RtMutex),
+    ("osq_lock", LockKind::OptimisticSpin),
+    ("mutex_spin_on_owner", LockKind::OptimisticSpin),
+    ("rwsem_optimistic_spin", LockKind::OptimisticSpin),
+    ("rwsem_spin_on_owner", LockKind::OptimisticSpin),
+];
+
+/// Lock slowpath of a symbol name, ignoring compiler suffixes like `.constprop.0`.
+fn classify(name: &str) -> Option<LockKind> {
+    let base = name.split('.').next().unwrap_or(name);
+    SLOWPATHS
+        .iter()
+        .find(|(n, _)| *n == base)
+        .map(|(_, kind)| *kind)
+}
+
+fn classify_addr(symbolizer: &dyn Symbolizer, addr: usize) -> Option<LockKind> {
+    symbolizer
+        .symbolize(addr)
+        .and_then(|(name, _)| classify(name))
+}
+
+/// First kernel address in `stack` that is not part of a lock function.
+fn find_caller(symbolizer: &dyn Symbolizer, stack: &[u8]) -> Option<usize> {
+    stack
+        .chunks_exact(8)
+        .map(|w| u64::from_le_bytes(w.try_into().unwrap()) as usize)
+        .find(|addr| {
+            symbolizer.symbolize(*addr).map_or(false, |(name, offset)| {
+                offset > 0 && classify(name).is_none()
+            })
+        })
+}
+
+#[derive(Default)]
+struct Contention {
+    samples: u64,
+    /// Samples per lock address, only known for spinlocks and rwlocks.
+    locks: HashMap<usize, u64>,
+}
+
+#[derive(Default)]
+struct Profile {
+    /// Samples per vcpu and how many of them were in lock slowpaths.
+    vcpus: Vec<(u64, u64)>,
+    contention: HashMap<(LockKind, Option<usize>), Contention>,
+}
+
+impl Profile {
+    fn add(&mut self, vcpu: usize, sample: Option<(LockKind, Option<usize>, usize)>) {
+        if self.vcpus.len() <= vcpu {
+            self.vcpus.resize(vcpu + 1, (0, 0));
+        }
+        self.vcpus[vcpu].0 += 1;
+        if let Some((kind, caller, lock)) = sample {
+            self.vcpus[vcpu].1 += 1;
+            let c = self.contention.entry((kind, caller)).or_default();
+            c.samples += 1;
+            if matches!(kind, LockKind::Spinlock | LockKind::Rwlock) {
+                *c.locks.entry(lock).or_default() += 1;
+            }
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.vcpus.iter().map(|(samples, _)| samples).sum()
+    }
+}
+
+fn format_profile(profile: &Profile, symbolizer: &dyn Symbolizer, top: usize) -> String {
+    let total = profile.total();
+    let mut out = format!("{:>4} {:>8} {:>8}\n", "vcpu", "samples", "in locks");
+    for (idx, (samples, locked)) in profile.vcpus.iter().enumerate() {
+        out.push_str(&format!(
+            "{:>4} {:>8} {:>7.1}%\n",
+            idx,
+            samples,
+            percent(*locked, *samples)
+        ));
+    }
+
+    let mut hottest = profile.contention.iter().collect::<Vec<_>>();
+    hottest.sort_by(|a, b| b.1.samples.cmp(&a.1.samples).then(a.0.cmp(b.0)));
+    out.push_str(&format!(
+        "\n{:>8} {:>6}  {:<8} {:<40} lock\n",
+        "samples", "share", "kind", "caller"
+    ));
+    for ((kind, caller), c) in hottest.iter().take(top) {
+        let caller =
+            caller.map_or_else(|| "?".to_string(), |a| symbolizer::describe(symbolizer, a));
+        let lock = c
+            .locks
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map_or_else(
+                || "-".to_string(),
+                |(a, _)| symbolizer::describe(symbolizer, *a),
+            );
+        out.push_str(&format!(
+            "{:>8} {:>5.1}%  {:<8} {:<40} {}\n",
+            c.samples,
+            percent(c.samples, total),
+            kind,
+            caller,
+            lock
+        ));
+    }
+    out
+}
+
+/// Lock slowpath, caller and rdi of a vcpu, None if it does not wait for a lock.
+fn lock_sample(
+    sample: &VcpuSample,
+    symbolizer: &dyn Symbolizer,
+) -> Result<Option<(LockKind, Option<usize>, usize)>> {
+    let regs = &sample.regs;
+    let kind = match classify_addr(symbolizer, regs.rip as usize) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+    let mut stack = [0u8; STACK_WORDS * 8];
+    let caller = match sample.memory()?.read(regs.rsp as usize, &mut stack) {
+        Ok(()) => find_caller(symbolizer, &stack),
+        Err(_) => None,
+    };
+    Ok(Some((kind, caller, regs.rdi as usize)))
+}
+
+pub fn lockstat(opts: &LockStatOptions) -> Result<()> {
+    if opts.symbols == SymbolSource::None {
+        bail!("lock functions can only be found with kernel symbols");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let symbolizer = {
+        let _stopped = vm.stop_guard()?;
+        try_with!(
+            open_symbols(&vm, &opts.symbols),
+            "cannot load kernel symbols"
+        )
+    };
+    let known = SLOWPATHS
+        .iter()
+        .filter(|(name, _)| symbolizer.address(name).is_some())
+        .count();
+    if known == 0 {
+        bail!("no lock slowpath in the kernel symbols, try --symbols vmlinux:PATH");
+    }
+    if known < 4 {
+        warn!(
+            "only {} lock slowpaths in the kernel symbols, use --symbols vmlinux:PATH to find \
+             mutexes and rwsems",
+            known
+        );
+    }
+
+    info!(
+        "sampling {} vcpus every {:?} for {:?}",
+        vm.vcpus.len(),
+        opts.interval,
+        opts.duration
+    );
+    let cancel = Cancellation::setup()?;
+    let mut profile = Profile::default();
+    let elapsed = sample_vcpus(&vm, opts.interval, opts.duration, &cancel, |sample| {
+        profile.add(sample.vcpu.idx, lock_sample(&sample, symbolizer.as_ref())?);
+        Ok(())
+    })?;
+
+    println!(
+        "{} samples over {:.1}s\n",
+        profile.total(),
+        elapsed.as_secs_f64()
+    );
+    print!(
+        "{}",
+        format_profile(&profile, symbolizer.as_ref(), opts.top)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolizer::SymbolTable;
+
+    fn symbols() -> SymbolTable {
+        let symbols = [
+            ("native_queued_spin_lock_slowpath", 0xffff_ffff_8100_0000),
+            ("__mutex_lock.constprop.0", 0xffff_ffff_8100_1000),
+            ("try_to_wake_up", 0xffff_ffff_8100_2000),
+            ("jiffies", 0xffff_ffff_8200_0000),
+        ];
+        SymbolTable::new(
+            symbols
+                .iter()
+                .map(|(name, addr)| (name.to_string(), *addr))
+                .collect(),
+            0xffff_ffff_8100_0000..0xffff_ffff_8300_0000,
+        )
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify("native_queued_spin_lock_slowpath"),
+            Some(LockKind::Spinlock)
+        );
+        assert_eq!(classify("__mutex_lock.constprop.0"), Some(LockKind::Mutex));
+        assert_eq!(classify("osq_lock"), Some(LockKind::OptimisticSpin));
+        assert_eq!(classify("mutex_lock"), None);
+
+        let table = symbols();
+        assert_eq!(
+            classify_addr(&table, 0xffff_ffff_8100_1010),
+            Some(LockKind::Mutex)
+        );
+        assert_eq!(classify_addr(&table, 0xffff_ffff_8100_2010), None);
+        assert_eq!(classify_addr(&table, 0x1000), None);
+    }
+
+    #[test]
+    fn test_find_caller() {
+        let table = symbols();
+        let stack = [
+            0x1234usize,           // not a kernel address
+            0xffff_ffff_8100_0040, // inside the lock slowpath
+            0xffff_ffff_8100_2000, // start of a symbol, no return address
+            0xffff_ffff_8100_2123, // caller
+            0xffff_ffff_8200_0008,
+        ]
+        .iter()
+        .flat_map(|w| (*w as u64).to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+        assert_eq!(find_caller(&table, &stack), Some(0xffff_ffff_8100_2123));
+        assert_eq!(find_caller(&table, &stack[..16]), None);
+    }
+
+    #[test]
+    fn test_profile() {
+        let table = symbols();
+        let caller = Some(0xffff_ffff_8100_2123);
+        let mut profile = Profile::default();
+        profile.add(0, None);
+        profile.add(1, Some((LockKind::Spinlock, caller, 0xffff_ffff_8200_0010)));
+        profile.add(1, Some((LockKind::Spinlock, caller, 0xffff_ffff_8200_0010)));
+        profile.add(1, Some((LockKind::Mutex, None, 0)));
+        assert_eq!(profile.total(), 4);
+        assert_eq!(profile.vcpus, vec![(1, 0), (3, 3)]);
+        assert_eq!(
+            format_profile(&profile, &table, 10),
+            "\
+vcpu  samples in locks
+   0        1     0.0%
+   1        3   100.0%
+
+ samples  share  kind     caller                                   lock
+       2  50.0%  spinlock try_to_wake_up+0x123                     jiffies+0x10
+       1  25.0%  mutex    ?                                        -
+"
+        );
+    }
+}
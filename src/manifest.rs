@@ -0,0 +1,242 @@
+//! Manifests record checksums and provenance of captured artifacts, so that later analysis can
+//! prove it operates on exactly what vmsh captured.
+//!
+//! A manifest is a line based text file next to the artifact (`<artifact>.manifest`):
+//!
+//! ```text
+//! vmsh-manifest 1
+//! tool vmsh 0.1.0
+//! host myhost
+//! pid 1234
+//! started 1626780000
+//! finished 1626780012
+//! file 1073745920 <sha256>
+//! segment 0 note 0x0 0x1000 0x4f0 <sha256>
+//! segment 1 load 0x0 0x2000 0x40000000 <sha256>
+//! extra core.1234.dedup <sha256>
+//! ```
+//!
+//! Segment lines contain the physical address, file offset, size and checksum of each ELF
+//! segment, or of each memslot for snapshots (`segment 0 memory ...`). Extra lines cover files
+//! that belong to the artifact, i.e. a dedup index. Other artifacts, such as encrypted coredumps,
+//! only have a file line.
+use libc::{PT_LOAD, PT_NOTE};
+use log::info;
+use nix::unistd::Pid;
+use simple_error::{require_with, try_with};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::{size_of, MaybeUninit};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use crate::elf::{Ehdr, Phdr, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3};
+use crate::format::MANIFEST;
+use crate::result::Result;
+use crate::sha256::{self, Sha256};
+use crate::snapshot;
+
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Where and when an artifact was captured.
+pub struct Provenance {
+    pub pid: Pid,
+    /// Unix timestamps
+    pub started: u64,
+    pub finished: u64,
+}
+
+pub fn manifest_path(artifact: &Path) -> PathBuf {
+    let mut path = artifact.as_os_str().to_owned();
+    path.push(".manifest");
+    PathBuf::from(path)
+}
+
+struct Segment {
+    kind: &'static str,
+    phys_addr: u64,
+    offset: u64,
+    size: u64,
+}
+
+fn read_struct<T: Copy>(file: &mut File, offset: u64) -> Result<T> {
+    let mut t = MaybeUninit::<T>::uninit();
+    let buf = unsafe { slice::from_raw_parts_mut(t.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    try_with!(file.seek(SeekFrom::Start(offset)), "cannot seek");
+    try_with!(file.read_exact(buf), "cannot read at offset {}", offset);
+    Ok(unsafe { t.assume_init() })
+}
+
+fn snapshot_segments(file: &mut File) -> Result<Vec<Segment>> {
+    let regions = try_with!(snapshot::memory_regions(file), "cannot read snapshot");
+    Ok(regions
+        .into_iter()
+        .map(|r| Segment {
+            kind: "memory",
+            phys_addr: r.phys_addr,
+            offset: r.offset,
+            size: r.size,
+        })
+        .collect())
+}
+
+fn segments(file: &mut File) -> Result<Vec<Segment>> {
+    let magic: [u8; 8] = try_with!(read_struct(file, 0), "cannot read file header");
+    if &magic == snapshot::MAGIC {
+        return snapshot_segments(file);
+    }
+    let ehdr: Ehdr = try_with!(read_struct(file, 0), "cannot read elf header");
+    let mut segments = vec![];
+    // i.e. encrypted artifacts are only covered as a whole
+    if ehdr.e_ident[..4] != [ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3] {
+        return Ok(segments);
+    }
+    for i in 0..u64::from(ehdr.e_phnum) {
+        let phdr: Phdr = try_with!(
+            read_struct(file, u64::from(ehdr.e_phoff) + i * size_of::<Phdr>() as u64),
+            "cannot read program header {}",
+            i
+        );
+        let kind = match phdr.p_type {
+            PT_LOAD => "load",
+            PT_NOTE => "note",
+            _ => continue,
+        };
+        segments.push(Segment {
+            kind,
+            phys_addr: u64::from(phdr.p_paddr),
+            offset: u64::from(phdr.p_offset),
+            size: u64::from(phdr.p_filesz),
+        });
+    }
+    Ok(segments)
+}
+
+/// Checksums of the whole file and of each of its segments, computed in a single pass.
+fn checksums(path: &Path, segments: &[Segment]) -> Result<(u64, String, Vec<String>)> {
+    let mut file = try_with!(File::open(path), "cannot open {}", path.display());
+    let mut file_hasher = Sha256::new();
+    let mut segment_hashers = segments.iter().map(|_| Sha256::new()).collect::<Vec<_>>();
+    let mut buf = vec![0u8; READ_SIZE];
+    let mut pos = 0u64;
+    loop {
+        let n = try_with!(file.read(&mut buf), "cannot read {}", path.display());
+        if n == 0 {
+            break;
+        }
+        let chunk = &buf[..n];
+        file_hasher.update(chunk);
+        let chunk_end = pos + n as u64;
+        for (s, hasher) in segments.iter().zip(segment_hashers.iter_mut()) {
+            let start = std::cmp::max(s.offset, pos);
+            let end = std::cmp::min(s.offset + s.size, chunk_end);
+            if start < end {
+                hasher.update(&chunk[(start - pos) as usize..(end - pos) as usize]);
+            }
+        }
+        pos = chunk_end;
+    }
+    let segment_digests = segment_hashers
+        .into_iter()
+        .map(|h| sha256::to_hex(&h.finish()))
+        .collect();
+    Ok((pos, sha256::to_hex(&file_hasher.finish()), segment_digests))
+}
+
+fn file_digest(path: &Path) -> Result<String> {
+    let (_, digest, _) = checksums(path, &[])?;
+    Ok(digest)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn manifest_lines(artifact: &Path, extra: &[PathBuf]) -> Result<Vec<String>> {
+    let mut file = try_with!(File::open(artifact), "cannot open {}", artifact.display());
+    let segments = segments(&mut file)?;
+    let (size, digest, segment_digests) = checksums(artifact, &segments)?;
+    let mut lines = vec![format!("file {} {}", size, digest)];
+    for (i, (s, d)) in segments.iter().zip(segment_digests).enumerate() {
+        lines.push(format!(
+            "segment {} {} {:#x} {:#x} {:#x} {}",
+            i, s.kind, s.phys_addr, s.offset, s.size, d
+        ));
+    }
+    for path in extra {
+        lines.push(format!("extra {} {}", file_name(path), file_digest(path)?));
+    }
+    Ok(lines)
+}
+
+/// Write `<artifact>.manifest` for a coredump or snapshot and the files in `extra`.
+pub fn write_manifest(artifact: &Path, extra: &[PathBuf], provenance: &Provenance) -> Result<()> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let mut content = format!(
+        "{}\ntool vmsh {}\nhost {}\npid {}\nstarted {}\nfinished {}\n",
+        MANIFEST.text_header(),
+        env!("CARGO_PKG_VERSION"),
+        hostname.trim(),
+        provenance.pid,
+        provenance.started,
+        provenance.finished
+    );
+    for line in manifest_lines(artifact, extra)? {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    let path = manifest_path(artifact);
+    try_with!(fs::write(&path, content), "cannot write {}", path.display());
+    info!("wrote manifest {}", path.display());
+    Ok(())
+}
+
+/// Check `artifact` against its manifest. Returns a description of every mismatch.
+pub fn verify(artifact: &Path, manifest: &Path) -> Result<Vec<String>> {
+    let content = try_with!(
+        fs::read_to_string(manifest),
+        "cannot read {}",
+        manifest.display()
+    );
+    let mut lines = content.lines();
+    let version = require_with!(
+        lines.next().and_then(|l| MANIFEST.header_version(l)),
+        "{} is not a vmsh manifest",
+        manifest.display()
+    );
+    try_with!(
+        MANIFEST.check(version),
+        "cannot read {}",
+        manifest.display()
+    );
+    let mut expected = vec![];
+    let mut extra = vec![];
+    for line in lines {
+        match line.split_whitespace().next() {
+            Some("file") | Some("segment") => expected.push(line.to_string()),
+            Some("extra") => {
+                let name = line.split_whitespace().nth(1).unwrap_or("");
+                let dir = artifact.parent().unwrap_or_else(|| Path::new(""));
+                extra.push(dir.join(name));
+                expected.push(line.to_string());
+            }
+            _ => {}
+        }
+    }
+    let actual = manifest_lines(artifact, &extra)?;
+
+    let mut problems = vec![];
+    for line in &expected {
+        if !actual.contains(line) {
+            problems.push(format!("expected: {}", line));
+        }
+    }
+    for line in &actual {
+        if !expected.contains(line) {
+            problems.push(format!("found:    {}", line));
+        }
+    }
+    Ok(problems)
+}
@@ -0,0 +1,365 @@
+//! Report how guest physical memory is backed on the host.
+//!
+//! Memslots tell us where guest physical memory lives in the hypervisor. For each of them we
+//! combine `/proc/<pid>/smaps` (per mapping accounting) with `/proc/<pid>/pagemap` and
+//! `/proc/kpageflags` (per page state) to attribute every guest page to anonymous, file,
+//! hugetlb, swapped, shared, KSM or THP backed host memory.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::host_pressure::PressureMonitor;
+use crate::kvm;
+use crate::page_math::page_size;
+use crate::pagemap::{
+    KPageFlags, PageMap, KPF_HUGE, KPF_KSM, KPF_THP, PM_FILE_OR_SHARED_ANON, PM_MMAP_EXCLUSIVE,
+    PM_PFN_MASK, PM_PRESENT, PM_SWAPPED,
+};
+use crate::result::Result;
+use crate::tracer::proc::{pid_path, Mapping};
+
+pub struct MemreportOptions {
+    pub pid: Pid,
+    /// Pause the scan while the pressure of the host is above this percentage, see
+    /// `host_pressure`.
+    pub max_host_pressure: Option<f64>,
+}
+
+/// Guest memory scanned between two checks of the host pressure.
+const SCAN_CHUNK_SIZE: usize = 1 << 30;
+
+/// A mapping in `/proc/<pid>/smaps`.
+#[derive(Debug, PartialEq)]
+struct SmapsEntry {
+    start: usize,
+    end: usize,
+    pathname: String,
+    /// Counters in kB, i.e. `Rss`, `Swap` or `AnonHugePages`
+    counters: HashMap<String, u64>,
+    /// Two letter flags from the `VmFlags` line, i.e. `ht` for hugetlb or `mg` for mergeable
+    vm_flags: Vec<String>,
+}
+
+impl SmapsEntry {
+    fn counter(&self, name: &str) -> u64 {
+        self.counters.get(name).copied().unwrap_or(0)
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.vm_flags.iter().any(|f| f == flag)
+    }
+}
+
+fn parse_smaps(content: &str) -> Result<Vec<SmapsEntry>> {
+    let mut entries: Vec<SmapsEntry> = vec![];
+    for line in content.lines() {
+        let (key, value) = match line.split_once(':') {
+            // mapping headers contain a ':' in the device field, but never as part of the first word
+            Some((key, value)) if !key.contains(' ') && !key.contains('-') => (key, value.trim()),
+            _ => {
+                let mut fields = line.split_whitespace();
+                let range = fields.next().unwrap_or("");
+                let (start, end) = match range.split_once('-') {
+                    Some((s, e)) => (
+                        try_with!(usize::from_str_radix(s, 16), "invalid address {}", s),
+                        try_with!(usize::from_str_radix(e, 16), "invalid address {}", e),
+                    ),
+                    None => bail!("unexpected line in smaps: {}", line),
+                };
+                let pathname = fields.skip(4).collect::<Vec<_>>().join(" ");
+                entries.push(SmapsEntry {
+                    start,
+                    end,
+                    pathname,
+                    counters: HashMap::new(),
+                    vm_flags: vec![],
+                });
+                continue;
+            }
+        };
+        let entry = match entries.last_mut() {
+            Some(e) => e,
+            None => bail!("smaps does not start with a mapping: {}", line),
+        };
+        if key == "VmFlags" {
+            entry.vm_flags = value.split_whitespace().map(String::from).collect();
+        } else if let Some(kb) = value.strip_suffix(" kB") {
+            let kb = try_with!(kb.trim().parse::<u64>(), "invalid value for {}", key);
+            entry.counters.insert(key.to_string(), kb);
+        }
+    }
+    Ok(entries)
+}
+
+fn read_smaps(pid: Pid) -> Result<Vec<SmapsEntry>> {
+    let path = pid_path(pid).join("smaps");
+    let content = try_with!(fs::read_to_string(&path), "cannot read {}", path.display());
+    parse_smaps(&content)
+}
+
+/// Page counts of a guest physical range.
+#[derive(Default, Debug)]
+struct PageStats {
+    present: u64,
+    swapped: u64,
+    /// file backed or shared anonymous memory (shmem/memfd)
+    file_or_shmem: u64,
+    /// mapped by more than one process
+    shared: u64,
+    ksm: u64,
+    thp: u64,
+    hugetlb: u64,
+}
+
+struct PageFlags {
+    kpageflags: Option<KPageFlags>,
+}
+
+impl PageFlags {
+    fn open() -> PageFlags {
+        let kpageflags = KPageFlags::open()
+            .map_err(|e| warn!("KSM and THP statistics are not available: {}", e))
+            .ok();
+        PageFlags { kpageflags }
+    }
+
+    fn get(&self, pfn: u64) -> Option<u64> {
+        self.kpageflags.as_ref()?.get(pfn).ok()
+    }
+}
+
+fn page_stats(
+    pagemap: &PageMap,
+    flags: &PageFlags,
+    start: usize,
+    end: usize,
+    mut pressure: Option<&mut PressureMonitor>,
+) -> Result<PageStats> {
+    let mut stats = PageStats::default();
+    for chunk in (start..end).step_by(SCAN_CHUNK_SIZE) {
+        if let Some(pressure) = pressure.as_mut() {
+            pressure.throttle();
+        }
+        let chunk_end = std::cmp::min(chunk + SCAN_CHUNK_SIZE, end);
+        scan_chunk(pagemap, flags, chunk, chunk_end, &mut stats)?;
+    }
+    Ok(stats)
+}
+
+fn scan_chunk(
+    pagemap: &PageMap,
+    flags: &PageFlags,
+    start: usize,
+    end: usize,
+    stats: &mut PageStats,
+) -> Result<()> {
+    pagemap.scan(start, end, |_, entry| {
+        if entry & PM_SWAPPED != 0 {
+            stats.swapped += 1;
+        }
+        if entry & PM_PRESENT == 0 {
+            return;
+        }
+        stats.present += 1;
+        if entry & PM_FILE_OR_SHARED_ANON != 0 {
+            stats.file_or_shmem += 1;
+        }
+        if entry & PM_MMAP_EXCLUSIVE == 0 {
+            stats.shared += 1;
+        }
+        let pfn = entry & PM_PFN_MASK;
+        if pfn == 0 {
+            return;
+        }
+        if let Some(kflags) = flags.get(pfn) {
+            if kflags & KPF_KSM != 0 {
+                stats.ksm += 1;
+            }
+            if kflags & KPF_THP != 0 {
+                stats.thp += 1;
+            }
+            if kflags & KPF_HUGE != 0 {
+                stats.hugetlb += 1;
+            }
+        }
+    })
+}
+
+/// What kind of host memory backs a mapping of the hypervisor. Without `smaps`, hugetlbfs is only
+/// recognized by its path.
+pub(crate) fn backing(mapping: &Mapping, smaps: Option<&SmapsEntry>) -> &'static str {
+    let path = &mapping.pathname;
+    if smaps.map_or(false, |s| s.has_flag("ht"))
+        || path.starts_with("/dev/hugepages")
+        || path.contains("hugetlbfs")
+    {
+        "hugetlb"
+    } else if path.is_empty() || path.starts_with('[') {
+        "anon"
+    } else if path.starts_with("/memfd:")
+        || path.starts_with("/dev/shm/")
+        || path.starts_with("/SYSV")
+    {
+        "shmem"
+    } else {
+        "file"
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+fn print_host_settings() {
+    let settings = [
+        ("THP", "/sys/kernel/mm/transparent_hugepage/enabled"),
+        ("KSM run", "/sys/kernel/mm/ksm/run"),
+        ("KSM pages sharing", "/sys/kernel/mm/ksm/pages_sharing"),
+    ];
+    for (name, path) in &settings {
+        match fs::read_to_string(path) {
+            Ok(v) => println!("{}: {}", name, v.trim()),
+            Err(e) => info!("cannot read {}: {}", path, e),
+        }
+    }
+}
+
+pub fn memreport(opts: &MemreportOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let mut memslots = vm.get_maps()?;
+    memslots.sort_by_key(|m| m.phys_addr);
+    let smaps = read_smaps(opts.pid)?;
+    let pagemap = PageMap::open(opts.pid)?;
+    let flags = PageFlags::open();
+    let page = page_size() as u64;
+    let mut pressure = match opts.max_host_pressure {
+        Some(threshold) => Some(PressureMonitor::new(opts.pid, threshold)?),
+        None => None,
+    };
+
+    print_host_settings();
+    println!(
+        "{:<33} {:>8} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "guest physical",
+        "size",
+        "backing",
+        "resident",
+        "swapped",
+        "file",
+        "shared",
+        "ksm",
+        "thp",
+        "hugetlb"
+    );
+    for slot in &memslots {
+        let entry = smaps
+            .iter()
+            .find(|s| s.start <= slot.start && slot.start < s.end);
+        let stats = page_stats(&pagemap, &flags, slot.start, slot.end, pressure.as_mut())?;
+        println!(
+            "{:#016x}-{:#016x} {:>8} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            slot.phys_addr,
+            slot.phys_end(),
+            format_size(slot.size() as u64),
+            backing(slot, entry),
+            format_size(stats.present * page),
+            format_size(stats.swapped * page),
+            format_size(stats.file_or_shmem * page),
+            format_size(stats.shared * page),
+            format_size(stats.ksm * page),
+            format_size(stats.thp * page),
+            format_size(stats.hugetlb * page),
+        );
+        if let Some(e) = entry {
+            println!(
+                "  host {:#x}-{:#x} {} (Rss: {}kB, Pss: {}kB, Swap: {}kB, AnonHugePages: {}kB, mergeable: {})",
+                e.start,
+                e.end,
+                if e.pathname.is_empty() { "[anon]" } else { e.pathname.as_str() },
+                e.counter("Rss"),
+                e.counter("Pss"),
+                e.counter("Swap"),
+                e.counter("AnonHugePages"),
+                e.has_flag("mg"),
+            );
+        }
+    }
+    if let Some(pressure) = pressure {
+        pressure.report();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::mman::{MapFlags, ProtFlags};
+
+    #[test]
+    fn test_parse_smaps() {
+        let content = "7f0000000000-7f0040000000 rw-p 00000000 00:00 0
+Size:            1048576 kB
+Rss:              524288 kB
+AnonHugePages:    262144 kB
+VmFlags: rd wr mr mw me ac mg
+7f0040000000-7f0040001000 r--p 00000000 fd:01 1234                       /nix/store/qemu (deleted)
+Rss:                   4 kB
+";
+        let entries = parse_smaps(content).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].start, 0x7f00_0000_0000);
+        assert_eq!(entries[0].pathname, "");
+        assert_eq!(entries[0].counter("Rss"), 524_288);
+        assert_eq!(entries[0].counter("AnonHugePages"), 262_144);
+        assert!(entries[0].has_flag("mg"));
+        assert_eq!(entries[1].pathname, "/nix/store/qemu (deleted)");
+        assert_eq!(entries[1].counter("Rss"), 4);
+    }
+
+    #[test]
+    fn test_backing() {
+        let mapping = |pathname: &str| Mapping {
+            start: 0x7f00_0000_0000,
+            end: 0x7f00_4000_0000,
+            prot_flags: ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            map_flags: MapFlags::MAP_SHARED,
+            offset: 0,
+            major_dev: 0,
+            minor_dev: 1,
+            inode: 0,
+            pathname: String::from(pathname),
+            phys_addr: 0,
+        };
+        assert_eq!(backing(&mapping(""), None), "anon");
+        assert_eq!(backing(&mapping("/memfd:pc.ram (deleted)"), None), "shmem");
+        assert_eq!(
+            backing(&mapping("/dev/hugepages/qemu_back_mem.pc.ram"), None),
+            "hugetlb"
+        );
+        assert_eq!(backing(&mapping("/dev/shm/vm0"), None), "shmem");
+        assert_eq!(backing(&mapping("/var/lib/vm/mem.img"), None), "file");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(2 * 1024 * 1024), "2.0M");
+    }
+}
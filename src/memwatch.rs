@@ -0,0 +1,125 @@
+//! Poll a range of guest memory and report changes, see `vmsh watch <pid> <addr> <len>`.
+//!
+//! The guest is only stopped while the range is read, so a value that changes and changes back
+//! within one interval is missed. Reading a few bytes is cheap, intervals of a few milliseconds
+//! are fine for watching kernel variables.
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::ops::Range;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::kvm;
+use crate::result::Result;
+use crate::sha256::to_hex;
+use crate::signal_handler::Cancellation;
+
+pub struct MemWatchOptions {
+    pub pid: Pid,
+    /// Guest physical address
+    pub addr: usize,
+    pub len: usize,
+    pub interval: Duration,
+    /// Shell command to run on every change. It gets the old and new content as hex in
+    /// `VMSH_WATCH_OLD` and `VMSH_WATCH_NEW`.
+    pub command: Option<String>,
+}
+
+/// Runs of bytes that differ between `old` and `new`, which have the same length.
+fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for i in (0..old.len()).filter(|i| old[*i] != new[*i]) {
+        match ranges.last_mut() {
+            Some(r) if r.end == i => r.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+fn run_command(command: &str, old: &[u8], new: &[u8]) -> Result<()> {
+    let status = try_with!(
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("VMSH_WATCH_OLD", to_hex(old))
+            .env("VMSH_WATCH_NEW", to_hex(new))
+            .status(),
+        "cannot run {}",
+        command
+    );
+    if !status.success() {
+        bail!("{} failed: {}", command, status);
+    }
+    Ok(())
+}
+
+pub fn watch_memory(opts: &MemWatchOptions) -> Result<()> {
+    require_with!(
+        opts.addr.checked_add(opts.len),
+        "address range {:#x}+{:#x} overflows",
+        opts.addr,
+        opts.len
+    );
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let read = |buf: &mut [u8]| -> Result<()> {
+        let _stopped = vm.stop_guard()?;
+        vm.read_guest_phys(opts.addr, buf)
+    };
+    let mut old = vec![0u8; opts.len];
+    read(&mut old)?;
+    let mut new = old.clone();
+
+    info!(
+        "watching {:#x}-{:#x} every {:?}",
+        opts.addr,
+        opts.addr + opts.len,
+        opts.interval
+    );
+    let cancel = Cancellation::setup()?;
+    let start = Instant::now();
+    while !cancel.is_cancelled() {
+        thread::sleep(opts.interval);
+        read(&mut new)?;
+        let changes = changed_ranges(&old, &new);
+        if changes.is_empty() {
+            continue;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        for r in &changes {
+            println!(
+                "[{:10.3}s] {:#x}: {} -> {}",
+                elapsed,
+                opts.addr + r.start,
+                to_hex(&old[r.clone()]),
+                to_hex(&new[r.clone()])
+            );
+        }
+        if let Some(command) = &opts.command {
+            if let Err(e) = run_command(command, &old, &new) {
+                warn!("{}", e);
+            }
+        }
+        std::mem::swap(&mut old, &mut new);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_ranges() {
+        assert_eq!(changed_ranges(b"abcd", b"abcd"), vec![]);
+        assert_eq!(changed_ranges(b"abcd", b"xbyz"), vec![0..1, 2..4]);
+        assert_eq!(changed_ranges(b"abcd", b"wxyz"), vec![0..4]);
+        assert_eq!(changed_ranges(b"", b""), vec![]);
+    }
+}
@@ -0,0 +1,332 @@
+//! Counters of a `vmsh attach` session, served in the prometheus text format on
+//! `--metrics-addr`, i.e. `curl http://127.0.0.1:9100/metrics`.
+//!
+//! The counters are process-wide like the session itself and are only ever incremented, so the
+//! device threads update them without locking. Attach phase latencies are recorded once while
+//! attaching and stay at zero for phases that did not finish yet.
+use lazy_static::lazy_static;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::interrutable_thread::InterrutableThread;
+use crate::result::Result;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static BLOCK_READS: Counter = Counter::new();
+pub static BLOCK_WRITES: Counter = Counter::new();
+pub static BLOCK_FLUSHES: Counter = Counter::new();
+/// Requests that are neither reads, writes nor flushes, i.e. GET_ID
+pub static BLOCK_OTHER: Counter = Counter::new();
+pub static BLOCK_READ_BYTES: Counter = Counter::new();
+pub static BLOCK_WRITE_BYTES: Counter = Counter::new();
+/// Requests answered with an error status or that could not be parsed
+pub static BLOCK_ERRORS: Counter = Counter::new();
+/// Interrupts sent to the guest through the irqfd
+pub static IRQ_INJECTIONS: Counter = Counter::new();
+/// Interrupts sent again because the guest did not acknowledge them in time
+pub static IRQ_RESENDS: Counter = Counter::new();
+/// Mmio exits to the devices of vmsh trapped with ptrace
+pub static MMIO_EXITS: Counter = Counter::new();
+/// Mmio exits trapped with ptrace that belong to the hypervisor and are passed on
+pub static MMIO_EXITS_PASSED: Counter = Counter::new();
+/// Mmio accesses to the devices of vmsh received through ioregionfd
+pub static IOREGION_ACCESSES: Counter = Counter::new();
+
+/// The phases of `vmsh attach` in the order they run.
+pub const ATTACH_PHASES: &[&str] = &["stop", "devices", "stage1", "device_ready"];
+
+lazy_static! {
+    /// Duration of the finished attach phases and when the last one ended.
+    static ref PHASES: Mutex<(Vec<(&'static str, Duration)>, Option<Instant>)> =
+        Mutex::new((vec![], None));
+}
+
+/// Starts timing the first attach phase.
+pub fn start_phases() {
+    if let Ok(mut phases) = PHASES.lock() {
+        *phases = (vec![], Some(Instant::now()));
+    }
+}
+
+/// Records the duration of `phase` since the previous phase ended.
+pub fn phase_done(phase: &'static str) {
+    if let Ok(mut phases) = PHASES.lock() {
+        let now = Instant::now();
+        if let Some(started) = phases.1.replace(now) {
+            phases.0.push((phase, now - started));
+        }
+    }
+}
+
+/// How often the metrics thread checks for connections and whether it should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Appends one metric with its help and type lines. Each sample is `(labels, value)`, where the
+/// labels are already formatted, i.e. `op="read"`, or empty.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn render(pid: Pid) -> String {
+    let mut out = String::new();
+    let counter = |c: &Counter| c.get() as f64;
+    let pid_label = format!("pid=\"{}\"", pid);
+    write_metric(
+        &mut out,
+        "vmsh_attach_info",
+        "gauge",
+        "Hypervisor process this vmsh is attached to.",
+        &[(&pid_label, 1.0)],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_block_requests_total",
+        "counter",
+        "Requests processed by the block device.",
+        &[
+            ("op=\"read\"", counter(&BLOCK_READS)),
+            ("op=\"write\"", counter(&BLOCK_WRITES)),
+            ("op=\"flush\"", counter(&BLOCK_FLUSHES)),
+            ("op=\"other\"", counter(&BLOCK_OTHER)),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_block_bytes_total",
+        "counter",
+        "Bytes read and written by the block device.",
+        &[
+            ("op=\"read\"", counter(&BLOCK_READ_BYTES)),
+            ("op=\"write\"", counter(&BLOCK_WRITE_BYTES)),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_block_errors_total",
+        "counter",
+        "Block requests that failed or could not be parsed.",
+        &[("", counter(&BLOCK_ERRORS))],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_irq_injections_total",
+        "counter",
+        "Interrupts injected into the guest.",
+        &[("", counter(&IRQ_INJECTIONS))],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_irq_resends_total",
+        "counter",
+        "Interrupts injected again because the guest did not acknowledge them in time.",
+        &[("", counter(&IRQ_RESENDS))],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_mmio_exits_total",
+        "counter",
+        "Mmio exits trapped by vmsh, by whether a vmsh device or the hypervisor handled them.",
+        &[
+            ("device=\"vmsh\"", counter(&MMIO_EXITS)),
+            ("device=\"hypervisor\"", counter(&MMIO_EXITS_PASSED)),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "vmsh_ioregion_accesses_total",
+        "counter",
+        "Mmio accesses to vmsh devices received through ioregionfd.",
+        &[("", counter(&IOREGION_ACCESSES))],
+    );
+    let finished = PHASES
+        .lock()
+        .map(|phases| phases.0.clone())
+        .unwrap_or_default();
+    let labels = ATTACH_PHASES
+        .iter()
+        .map(|phase| format!("phase=\"{}\"", phase))
+        .collect::<Vec<_>>();
+    let samples = ATTACH_PHASES
+        .iter()
+        .zip(&labels)
+        .map(|(phase, labels)| {
+            let seconds = finished
+                .iter()
+                .find(|(p, _)| p == phase)
+                .map_or(0.0, |(_, d)| d.as_secs_f64());
+            (labels.as_str(), seconds)
+        })
+        .collect::<Vec<_>>();
+    write_metric(
+        &mut out,
+        "vmsh_attach_phase_seconds",
+        "gauge",
+        "How long each phase of attaching took.",
+        &samples,
+    );
+    out
+}
+
+/// Path of a `GET` request line, `None` for other methods.
+fn request_path(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) => Some(path.split('?').next().unwrap_or(path)),
+        _ => None,
+    }
+}
+
+fn handle(stream: TcpStream, pid: Pid) -> Result<()> {
+    try_with!(
+        stream.set_nonblocking(false),
+        "cannot make metrics connection blocking"
+    );
+    try_with!(
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT)),
+        "cannot set timeout of metrics connection"
+    );
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    try_with!(reader.read_line(&mut request), "cannot read request");
+    // the headers are not needed, but clients expect them to be read
+    let mut header = String::new();
+    while try_with!(reader.read_line(&mut header), "cannot read request") > 2 {
+        header.clear();
+    }
+    let (status, content_type, body) = match request_path(&request) {
+        Some("/metrics") | Some("/") => ("200 OK", "text/plain; version=0.0.4", render(pid)),
+        Some(_) => ("404 Not Found", "text/plain", String::from("not found\n")),
+        None => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("only GET is supported\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    try_with!(
+        (&stream).write_all(response.as_bytes()),
+        "cannot send metrics"
+    );
+    Ok(())
+}
+
+/// Listens on `addr` for `metrics_thread`. Done before attaching, so that a taken address does
+/// not leave a half attached vm behind.
+pub fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = try_with!(TcpListener::bind(addr), "cannot listen on {}", addr);
+    try_with!(
+        listener.set_nonblocking(true),
+        "cannot make {} non-blocking",
+        addr
+    );
+    Ok(listener)
+}
+
+/// Serves the metrics of the session attached to `pid` on `listener` until it is shut down.
+pub fn metrics_thread(
+    listener: TcpListener,
+    pid: Pid,
+    err_sender: &SyncSender<()>,
+) -> Result<InterrutableThread<(), ()>> {
+    if let Ok(addr) = listener.local_addr() {
+        info!("serving metrics on http://{}/metrics", addr);
+    }
+    let res = InterrutableThread::spawn(
+        "metrics",
+        err_sender,
+        move |_ctx: &(), should_stop: Arc<AtomicBool>| {
+            while !should_stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = handle(stream, pid) {
+                            warn!("{}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(e) => warn!("cannot accept metrics connection: {}", e),
+                }
+            }
+            Ok(())
+        },
+        (),
+    );
+    Ok(try_with!(res, "cannot spawn metrics thread"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_metric() {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "vmsh_block_bytes_total",
+            "counter",
+            "Bytes.",
+            &[("op=\"read\"", 4096.0), ("", 0.5)],
+        );
+        assert_eq!(
+            out,
+            "# HELP vmsh_block_bytes_total Bytes.\n\
+             # TYPE vmsh_block_bytes_total counter\n\
+             vmsh_block_bytes_total{op=\"read\"} 4096\n\
+             vmsh_block_bytes_total 0.5\n"
+        );
+    }
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET /metrics HTTP/1.1\r\n"), Some("/metrics"));
+        assert_eq!(
+            request_path("GET /metrics?x=1 HTTP/1.0\r\n"),
+            Some("/metrics")
+        );
+        assert_eq!(request_path("POST /metrics HTTP/1.1\r\n"), None);
+        assert_eq!(request_path(""), None);
+    }
+}
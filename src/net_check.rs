@@ -0,0 +1,45 @@
+//! Check the network from inside the guest by running stage2 in its network check mode.
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::attach::{self, AttachOptions};
+use crate::devices::virtio::block::CacheMode;
+use crate::devices::virtio::console::STOP_ON_SESSION_END;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+pub struct NetCheckOptions {
+    pub pid: Pid,
+    /// `host:port` pairs to resolve and connect to.
+    pub targets: Vec<String>,
+    pub stage2_path: String,
+}
+
+pub fn net_check(opts: &NetCheckOptions) -> Result<()> {
+    // results are printed by stage2 to the console, we are done once it exits
+    STOP_ON_SESSION_END.store(true, Ordering::Release);
+
+    let mut command = vec![opts.stage2_path.clone(), String::from("--net-check")];
+    command.extend(opts.targets.iter().cloned());
+    attach::attach(&AttachOptions {
+        pid: opts.pid,
+        command,
+        backing: PathBuf::from("/dev/null"),
+        backing_format: None,
+        read_only: false,
+        cache: CacheMode::default(),
+        faults: None,
+        hotplug: None,
+        vsock: None,
+        share: None,
+        irq_affinity: vec![],
+        environment: vec![],
+        cwd: None,
+        interactive: true,
+        tty: None,
+        metrics_addr: None,
+        publish: vec![],
+        symbols: SymbolSource::Ksymtab,
+    })
+}
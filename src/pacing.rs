@@ -0,0 +1,233 @@
+//! Pace background reads of guest memory by the activity of the guest, see
+//! `vmsh coredump --adaptive`.
+//!
+//! Before a chunk of memory is read, the cpu time of the vcpu threads and the rate of vm exits are
+//! compared against thresholds. While the guest is busy, reads are postponed, but never for
+//! longer than `MAX_DEFER` per chunk, so the acquisition of a permanently busy guest still
+//! finishes. Reads can be throttled by the pressure of the host as well, see `host_pressure`.
+use log::info;
+use nix::unistd::Pid;
+use simple_error::bail;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::host_pressure::PressureMonitor;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::topology::{self, VcpuThread};
+use crate::result::Result;
+
+/// Vcpus that run more than this share of the time are busy.
+const BUSY_THRESHOLD: f64 = 0.3;
+/// More vm exits per second and vcpu indicate an io or interrupt heavy workload.
+const EXIT_RATE_THRESHOLD: f64 = 5000.0;
+/// Scheduler statistics are not precise enough for shorter intervals.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+/// Give up waiting for an idle guest after this long and read anyway.
+const MAX_DEFER: Duration = Duration::from_secs(1);
+
+/// Counters summed over all vcpus.
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    run_ns: u64,
+    exits: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct Activity {
+    /// Average share of time the vcpus ran
+    busy: f64,
+    /// Exits per second and vcpu
+    exit_rate: f64,
+}
+
+impl Activity {
+    fn is_idle(&self) -> bool {
+        self.busy < BUSY_THRESHOLD && self.exit_rate < EXIT_RATE_THRESHOLD
+    }
+}
+
+fn activity(before: &Sample, after: &Sample, elapsed: Duration, vcpus: usize) -> Activity {
+    let total_ns = elapsed.as_nanos() as f64 * vcpus.max(1) as f64;
+    if total_ns == 0.0 {
+        return Activity {
+            busy: 0.0,
+            exit_rate: 0.0,
+        };
+    }
+    Activity {
+        busy: after.run_ns.saturating_sub(before.run_ns) as f64 / total_ns,
+        exit_rate: after.exits.saturating_sub(before.exits) as f64 * 1e9 / total_ns,
+    }
+}
+
+struct GuestPacer {
+    pid: Pid,
+    threads: Vec<VcpuThread>,
+    /// Exit counter of the vm in kvm's debugfs. Without debugfs, halts of the vcpus (voluntary
+    /// context switches) are counted instead.
+    exits_path: Option<PathBuf>,
+    last: Sample,
+    last_time: Instant,
+    idle: bool,
+}
+
+impl GuestPacer {
+    fn new(vm: &Hypervisor) -> Result<GuestPacer> {
+        let threads = topology::vcpu_threads(vm.pid, &vm.vcpus)?;
+        if threads.is_empty() {
+            bail!("cannot find the vcpu threads of {}", vm.pid);
+        }
+        let exits_path = PathBuf::from("/sys/kernel/debug/kvm")
+            .join(format!("{}-{}", vm.pid, vm.vm_fd))
+            .join("exits");
+        let exits_path = if fs::read_to_string(&exits_path).is_ok() {
+            Some(exits_path)
+        } else {
+            info!("kvm debugfs is not available, use vcpu halts instead of vm exits");
+            None
+        };
+        let mut pacer = GuestPacer {
+            pid: vm.pid,
+            threads,
+            exits_path,
+            last: Sample::default(),
+            last_time: Instant::now(),
+            idle: false,
+        };
+        pacer.last = pacer.sample();
+        Ok(pacer)
+    }
+
+    /// Threads that exited in the meantime do not count.
+    fn sample(&self) -> Sample {
+        let mut sample = Sample::default();
+        for t in &self.threads {
+            if let Ok(stat) = topology::sched_stat(self.pid, t.tid) {
+                sample.run_ns += stat.run_ns;
+            }
+            if self.exits_path.is_none() {
+                if let Ok(switches) = topology::context_switches(self.pid, t.tid) {
+                    sample.exits += switches.voluntary;
+                }
+            }
+        }
+        if let Some(path) = &self.exits_path {
+            sample.exits = fs::read_to_string(path)
+                .ok()
+                .and_then(|c| c.trim().parse().ok())
+                .unwrap_or(0);
+        }
+        sample
+    }
+
+    /// Block until the guest is idle or the read was postponed for `MAX_DEFER`.
+    fn wait_idle(&mut self) {
+        let start = Instant::now();
+        loop {
+            let elapsed = self.last_time.elapsed();
+            if elapsed >= SAMPLE_INTERVAL {
+                let sample = self.sample();
+                self.idle = activity(&self.last, &sample, elapsed, self.threads.len()).is_idle();
+                self.last = sample;
+                self.last_time = Instant::now();
+            }
+            if self.idle || start.elapsed() >= MAX_DEFER {
+                break;
+            }
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+    }
+}
+
+pub struct Pacer {
+    guest: Option<GuestPacer>,
+    pressure: Option<PressureMonitor>,
+    deferred: Duration,
+}
+
+impl Pacer {
+    /// The hypervisor must be running, otherwise the guest looks idle all the time.
+    pub fn new(vm: &Hypervisor) -> Result<Pacer> {
+        Ok(Pacer {
+            guest: Some(GuestPacer::new(vm)?),
+            pressure: None,
+            deferred: Duration::from_secs(0),
+        })
+    }
+
+    /// Pace only by the pressure of the host, i.e. while the guest is stopped.
+    pub fn host_only(monitor: PressureMonitor) -> Pacer {
+        Pacer {
+            guest: None,
+            pressure: Some(monitor),
+            deferred: Duration::from_secs(0),
+        }
+    }
+
+    pub fn with_host_pressure(mut self, monitor: PressureMonitor) -> Pacer {
+        self.pressure = Some(monitor);
+        self
+    }
+
+    /// Block until the guest is idle and the host is not under pressure, each for a limited
+    /// time.
+    pub fn wait_idle(&mut self) {
+        if let Some(guest) = &mut self.guest {
+            let start = Instant::now();
+            guest.wait_idle();
+            self.deferred += start.elapsed();
+        }
+        if let Some(pressure) = &mut self.pressure {
+            pressure.throttle();
+        }
+    }
+
+    /// Log how long reads were postponed.
+    pub fn report(&self) {
+        if self.guest.is_some() {
+            info!(
+                "postponed reads for {:.1}s while the guest was busy",
+                self.deferred.as_secs_f64()
+            );
+        }
+        if let Some(pressure) = &self.pressure {
+            pressure.report();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity() {
+        let before = Sample {
+            run_ns: 1_000_000,
+            exits: 100,
+        };
+        let after = Sample {
+            run_ns: 11_000_000,
+            exits: 200,
+        };
+        // 2 vcpus for 10ms, one of them ran all the time
+        let a = activity(&before, &after, Duration::from_millis(10), 2);
+        assert_eq!(
+            a,
+            Activity {
+                busy: 0.5,
+                exit_rate: 5000.0
+            }
+        );
+        assert!(!a.is_idle());
+
+        let a = activity(&before, &before, Duration::from_millis(10), 2);
+        assert!(a.is_idle());
+        // counters of exited threads might go backwards
+        let a = activity(&after, &before, Duration::from_millis(10), 2);
+        assert!(a.is_idle());
+        assert!(activity(&before, &after, Duration::from_secs(0), 2).is_idle());
+    }
+}
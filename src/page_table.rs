@@ -7,8 +7,9 @@ use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
 
+use crate::guest_access::{self, GuestAccess};
 use crate::guest_mem::MappedMemory;
-use crate::kvm::hypervisor::{memory::process_read, memory::PhysMem, Hypervisor};
+use crate::kvm::hypervisor::{memory::PhysMem, Hypervisor};
 use crate::page_math::{is_page_aligned, page_align, page_size};
 use crate::result::Result;
 use bitflags::bitflags;
@@ -119,7 +120,13 @@ impl PageTableEntry {
     /// Returns the physical address mapped by this entry, might be zero.
     #[inline]
     pub fn addr(&self) -> u64 {
-        self.entry & 0x000f_ffff_ffff_f000
+        self.entry & ENTRY_ADDR_MASK
+    }
+}
+
+impl From<u64> for PageTableEntry {
+    fn from(entry: u64) -> Self {
+        PageTableEntry { entry }
     }
 }
 
@@ -133,7 +140,7 @@ pub struct PageTable {
 }
 
 pub struct PageTableIterator<'a> {
-    hv: &'a Hypervisor,
+    hv: &'a dyn GuestAccess,
     page_table: PageTable,
     range: Range<usize>,
     count: usize,
@@ -151,9 +158,13 @@ impl PageTable {
         }
     }
 
-    pub fn read(hv: &Hypervisor, phys_addr: &PhysAddr, virt_addr: u64, level: u8) -> Result<Self> {
-        let host_addr = phys_addr.host_addr();
-        let entries = process_read(hv.pid, host_addr as *const libc::c_void)?;
+    pub fn read(
+        hv: &dyn GuestAccess,
+        phys_addr: &PhysAddr,
+        virt_addr: u64,
+        level: u8,
+    ) -> Result<Self> {
+        let entries = guest_access::read(hv, phys_addr.host_addr())?;
 
         Ok(PageTable {
             phys_addr: phys_addr.clone(),
@@ -163,6 +174,10 @@ impl PageTable {
         })
     }
 
+    pub fn entries(&self) -> &[PageTableEntry] {
+        &self.entries
+    }
+
     pub fn phys_addr(&self, e: PageTableEntry) -> PhysAddr {
         PhysAddr {
             value: e.addr() as usize,
@@ -170,7 +185,7 @@ impl PageTable {
         }
     }
 
-    pub fn iter(self, hv: &Hypervisor, range: Range<usize>) -> PageTableIterator {
+    pub fn iter(self, hv: &dyn GuestAccess, range: Range<usize>) -> PageTableIterator {
         PageTableIterator {
             hv,
             range,
@@ -190,6 +205,90 @@ fn get_index(virt: u64, level: u8) -> u64 {
     virt >> get_shift(level) & 0x1FF
 }
 
+/// CR4.LA57: the guest uses 5-level paging.
+const X86_CR4_LA57: u64 = 1 << 12;
+
+/// Address bits of a page table entry or cr3.
+const ENTRY_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Number of page table levels, 4 or 5, used by a vcpu with this cr4.
+pub fn paging_levels(cr4: u64) -> u8 {
+    if cr4 & X86_CR4_LA57 != 0 {
+        5
+    } else {
+        4
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Translation {
+    /// Guest physical address
+    pub phys_addr: usize,
+    /// Size of the page that maps the address: 4 KiB, 2 MiB or 1 GiB
+    pub page_size: usize,
+}
+
+/// Translate the guest virtual address `gva` with the page tables at `cr3` the same way the MMU
+/// of the vcpu does. `levels` is 4 or 5, see `paging_levels`. `read_entry` reads the 8 bytes at a
+/// guest physical address. Returns None if `gva` is not mapped.
+pub fn walk(
+    read_entry: &mut dyn FnMut(usize) -> Result<u64>,
+    cr3: u64,
+    levels: u8,
+    gva: usize,
+) -> Result<Option<Translation>> {
+    // canonical addresses sign extend the highest implemented bit
+    let high = (gva as i64) >> (11 + 9 * levels as u32);
+    if high != 0 && high != -1 {
+        return Ok(None);
+    }
+    let mut table = (cr3 & ENTRY_ADDR_MASK) as usize;
+    // unlike `PageTable::level`, level 0 is the last level, which maps 4 KiB pages
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level as u32;
+        let idx = (gva >> shift) & (ENTRY_COUNT - 1);
+        let entry = PageTableEntry {
+            entry: read_entry(table + idx * size_of::<u64>())?,
+        };
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Ok(None);
+        }
+        // 2 MiB and 1 GiB pages
+        let huge = (level == 1 || level == 2) && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level == 0 || huge {
+            let page_size = 1 << shift;
+            // bit 12 is the PAT bit in huge page entries
+            let page = entry.addr() as usize & !(page_size - 1);
+            return Ok(Some(Translation {
+                phys_addr: page + (gva & (page_size - 1)),
+                page_size,
+            }));
+        }
+        table = entry.addr() as usize;
+    }
+    Ok(None)
+}
+
+/// Guest physical address of `virt` in the address space of `pml4`, or None if it is not mapped.
+/// `levels` is 4 or 5, see `paging_levels`. The page tables are expected in the same memslot as
+/// `pml4`.
+pub fn translate(
+    hv: &dyn GuestAccess,
+    pml4: &PhysAddr,
+    levels: u8,
+    virt: usize,
+) -> Result<Option<usize>> {
+    let mut read_entry = |phys: usize| {
+        let entry = PhysAddr {
+            value: phys,
+            host_offset: pml4.host_offset,
+        };
+        guest_access::read::<u64>(hv, entry.host_addr())
+    };
+    let translation = walk(&mut read_entry, pml4.value as u64, levels, virt)?;
+    Ok(translation.map(|t| t.phys_addr))
+}
+
 pub fn table_align(pages: usize) -> usize {
     (pages + (ENTRY_COUNT - 1)) & !(ENTRY_COUNT - 1)
 }
@@ -579,7 +678,13 @@ impl<'a> Iterator for PageTableIterator<'a> {
 mod tests {
     use crate::page_math::page_size;
 
-    use super::{estimate_page_table_size, ENTRY_COUNT, LEVEL_COUNT};
+    use super::{
+        estimate_page_table_size, paging_levels, walk, PageTableFlags, Translation, ENTRY_COUNT,
+        LEVEL_COUNT,
+    };
+    use crate::result::Result;
+    use std::collections::HashMap;
+
     #[test]
     fn test_page_table_size() {
         assert_eq!(estimate_page_table_size(1), page_size() * LEVEL_COUNT);
@@ -589,4 +694,71 @@ mod tests {
             page_size() + page_size() * LEVEL_COUNT
         );
     }
+
+    /// Guest physical memory that only contains page table entries.
+    fn entries(list: &[(usize, u64)]) -> impl FnMut(usize) -> Result<u64> {
+        let mem = list.iter().copied().collect::<HashMap<_, _>>();
+        move |addr| Ok(mem.get(&addr).copied().unwrap_or(0))
+    }
+
+    #[test]
+    fn test_walk() {
+        let present = PageTableFlags::PRESENT.bits();
+        let huge = present | PageTableFlags::HUGE_PAGE.bits();
+        // 0xffff_8880_0000_1234: pml4 index 273, pdpt 0, pd 0, pt 1
+        let gva = 0xffff_8880_0000_1234;
+        let mut read = entries(&[
+            (0x1000 + 273 * 8, 0x2000 | present),
+            (0x2000, 0x3000 | present),
+            (0x3000, 0x4000 | present),
+            (0x4000 + 8, 0x7_6543_2000 | present),
+            // 2 MiB page with PAT bit at 0xffff_8880_0020_0000
+            (0x3000 + 8, 0x20_0000 | 1 << 12 | huge),
+        ]);
+        assert_eq!(
+            walk(&mut read, 0x1000 | 0x18, 4, gva).unwrap(),
+            Some(Translation {
+                phys_addr: 0x7_6543_2234,
+                page_size: 0x1000
+            })
+        );
+        assert_eq!(
+            walk(&mut read, 0x1000, 4, 0xffff_8880_0021_0000).unwrap(),
+            Some(Translation {
+                phys_addr: 0x21_0000,
+                page_size: 0x20_0000
+            })
+        );
+        assert_eq!(
+            walk(&mut read, 0x1000, 4, 0xffff_8880_4000_0000).unwrap(),
+            None
+        );
+        // not canonical with 4 levels
+        assert_eq!(
+            walk(&mut read, 0x1000, 4, 0x0000_8880_0000_1234).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_walk_5_levels() {
+        let present = PageTableFlags::PRESENT.bits();
+        let huge = present | PageTableFlags::HUGE_PAGE.bits();
+        // pml5 index 511, pml4 index 273, 1 GiB page
+        let gva = 0xffff_8880_4000_1234;
+        let mut read = entries(&[
+            (0x1000 + 511 * 8, 0x2000 | present),
+            (0x2000 + 273 * 8, 0x3000 | present),
+            (0x3000 + 8, 0x8000_0000 | huge),
+        ]);
+        assert_eq!(paging_levels(1 << 12), 5);
+        assert_eq!(paging_levels(0x3406e0), 4);
+        assert_eq!(
+            walk(&mut read, 0x1000, 5, gva).unwrap(),
+            Some(Translation {
+                phys_addr: 0x8000_1234,
+                page_size: 0x4000_0000
+            })
+        );
+    }
 }
@@ -0,0 +1,100 @@
+//! Access to `/proc/<pid>/pagemap`, which tells whether pages of a process are resident,
+//! swapped out or backed by a file. See Documentation/admin-guide/mm/pagemap.rst
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::proc::pid_path;
+
+pub const PM_PRESENT: u64 = 1 << 63;
+pub const PM_SWAPPED: u64 = 1 << 62;
+pub const PM_FILE_OR_SHARED_ANON: u64 = 1 << 61;
+pub const PM_MMAP_EXCLUSIVE: u64 = 1 << 56;
+/// Reads as 0 without CAP_SYS_ADMIN
+pub const PM_PFN_MASK: u64 = (1 << 55) - 1;
+
+pub const KPF_HUGE: u64 = 1 << 17;
+pub const KPF_KSM: u64 = 1 << 21;
+pub const KPF_THP: u64 = 1 << 22;
+
+/// Number of entries read at once.
+const BATCH: usize = 64 * 1024;
+
+pub struct PageMap {
+    file: File,
+}
+
+impl PageMap {
+    pub fn open(pid: Pid) -> Result<PageMap> {
+        let path = pid_path(pid).join("pagemap");
+        let file = try_with!(File::open(&path), "cannot open {}", path.display());
+        Ok(PageMap { file })
+    }
+
+    /// Call `f` with the address and pagemap entry of every page in `start..end`.
+    pub fn scan(&self, start: usize, end: usize, mut f: impl FnMut(usize, u64)) -> Result<()> {
+        let mut buf = vec![0u8; BATCH * 8];
+        let mut page = start / page_size();
+        let last_page = end / page_size();
+        while page < last_page {
+            let count = std::cmp::min(BATCH, last_page - page);
+            let chunk = &mut buf[..count * 8];
+            try_with!(
+                self.file.read_exact_at(chunk, (page * 8) as u64),
+                "cannot read pagemap at {:#x}",
+                page * page_size()
+            );
+            for (i, entry) in chunk.chunks_exact(8).enumerate() {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(entry);
+                f((page + i) * page_size(), u64::from_le_bytes(bytes));
+            }
+            page += count;
+        }
+        Ok(())
+    }
+
+    /// Address ranges in `start..end` that are swapped out.
+    pub fn swapped_ranges(&self, start: usize, end: usize) -> Result<Vec<Range<usize>>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        self.scan(start, end, |addr, entry| {
+            if entry & PM_SWAPPED == 0 {
+                return;
+            }
+            match ranges.last_mut() {
+                Some(r) if r.end == addr => r.end += page_size(),
+                _ => ranges.push(addr..addr + page_size()),
+            }
+        })?;
+        Ok(ranges)
+    }
+}
+
+/// `/proc/kpageflags`: flags of physical pages by pfn. Requires CAP_SYS_ADMIN.
+pub struct KPageFlags {
+    file: File,
+}
+
+impl KPageFlags {
+    pub fn open() -> Result<KPageFlags> {
+        let file = try_with!(
+            File::open("/proc/kpageflags"),
+            "cannot open /proc/kpageflags"
+        );
+        Ok(KPageFlags { file })
+    }
+
+    pub fn get(&self, pfn: u64) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        try_with!(
+            self.file.read_exact_at(&mut buf, pfn * 8),
+            "cannot read flags of pfn {:#x}",
+            pfn
+        );
+        Ok(u64::from_le_bytes(buf))
+    }
+}
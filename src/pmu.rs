@@ -0,0 +1,449 @@
+//! Count hardware events of a guest with its virtual PMU, see `vmsh pmu`. Shows the IPC and
+//! cache miss rate of guest workloads without anything running inside of the guest.
+//!
+//! KVM emulates the PMU of the guest with perf events on the host, which it reprograms whenever
+//! the event select msrs of a vcpu are written, also by KVM_SET_MSRS. vmsh takes general purpose
+//! counters the guest does not use, programs them on every vcpu and reads them periodically. The
+//! msrs are restored when vmsh exits, so a guest that starts using the PMU in the meantime may
+//! see one of its counters disabled at that point.
+//!
+//! Intel cpus are supported from architectural perfmon version 1 on (cpuid leaf 0xa), AMD cpus
+//! with their four legacy counters. The hypervisor has to expose a PMU to the guest, i.e. qemu
+//! with `-cpu host` or `-cpu ...,pmu=on`.
+use kvm_bindings as kvmb;
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::thread;
+use std::time::Duration;
+
+use crate::cpu_report::cpu_model;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+
+pub struct PmuOptions {
+    pub pid: Pid,
+    /// Names as in `perf list`, see `EVENTS`
+    pub events: Vec<String>,
+    pub interval: Duration,
+    /// Stop after this many intervals, otherwise run until interrupted.
+    pub count: Option<usize>,
+    /// Show each vcpu instead of the sum of all.
+    pub per_vcpu: bool,
+}
+
+pub const DEFAULT_EVENTS: &[&str] = &["cycles", "instructions", "cache-references", "cache-misses"];
+
+const MSR_IA32_PMC0: u32 = 0xc1;
+const MSR_IA32_PERFEVTSEL0: u32 = 0x186;
+const MSR_IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const MSR_K7_EVNTSEL0: u32 = 0xc001_0000;
+const MSR_K7_PERFCTR0: u32 = 0xc001_0004;
+/// The legacy counters every AMD cpu with a PMU has
+const AMD_COUNTERS: usize = 4;
+const AMD_COUNTER_WIDTH: u32 = 48;
+
+const EVENTSEL_USR: u64 = 1 << 16;
+const EVENTSEL_OS: u64 = 1 << 17;
+const EVENTSEL_ENABLE: u64 = 1 << 22;
+
+/// Event select and unit mask, the same as perf uses for its generic events.
+struct Event {
+    name: &'static str,
+    intel: (u8, u8),
+    amd: (u8, u8),
+}
+
+const EVENTS: &[Event] = &[
+    Event {
+        name: "cycles",
+        intel: (0x3c, 0x00),
+        amd: (0x76, 0x00),
+    },
+    Event {
+        name: "instructions",
+        intel: (0xc0, 0x00),
+        amd: (0xc0, 0x00),
+    },
+    Event {
+        name: "cache-references",
+        intel: (0x2e, 0x4f),
+        amd: (0x7d, 0x07),
+    },
+    Event {
+        name: "cache-misses",
+        intel: (0x2e, 0x41),
+        amd: (0x7e, 0x07),
+    },
+    Event {
+        name: "branches",
+        intel: (0xc4, 0x00),
+        amd: (0xc2, 0x00),
+    },
+    Event {
+        name: "branch-misses",
+        intel: (0xc5, 0x00),
+        amd: (0xc3, 0x00),
+    },
+];
+
+/// General purpose counters of the guest PMU
+#[derive(Debug, PartialEq)]
+struct Pmu {
+    eventsel_base: u32,
+    counter_base: u32,
+    counters: usize,
+    width: u32,
+    /// Intel perfmon version 2 and later also need the bit in IA32_PERF_GLOBAL_CTRL.
+    global_ctrl: bool,
+    intel: bool,
+}
+
+impl Pmu {
+    fn eventsel(&self, counter: usize) -> u32 {
+        self.eventsel_base + counter as u32
+    }
+
+    fn counter(&self, counter: usize) -> u32 {
+        self.counter_base + counter as u32
+    }
+}
+
+fn guest_pmu(entries: &[kvmb::kvm_cpuid_entry2]) -> Result<Pmu> {
+    let vendor = match cpu_model(entries) {
+        Some((vendor, _, _, _)) => vendor,
+        None => bail!("cpuid of the guest has no vendor"),
+    };
+    match vendor.as_str() {
+        "GenuineIntel" => {
+            let leaf = entries.iter().find(|e| e.function == 0xa);
+            let eax = leaf.map_or(0, |e| e.eax);
+            let version = eax & 0xff;
+            let counters = ((eax >> 8) & 0xff) as usize;
+            if version == 0 || counters == 0 {
+                bail!("the hypervisor exposes no PMU to the guest");
+            }
+            Ok(Pmu {
+                eventsel_base: MSR_IA32_PERFEVTSEL0,
+                counter_base: MSR_IA32_PMC0,
+                counters,
+                width: (eax >> 16) & 0xff,
+                global_ctrl: version >= 2,
+                intel: true,
+            })
+        }
+        "AuthenticAMD" | "HygonGenuine" => Ok(Pmu {
+            eventsel_base: MSR_K7_EVNTSEL0,
+            counter_base: MSR_K7_PERFCTR0,
+            counters: AMD_COUNTERS,
+            width: AMD_COUNTER_WIDTH,
+            global_ctrl: false,
+            intel: false,
+        }),
+        _ => bail!("no PMU support for cpu vendor {}", vendor),
+    }
+}
+
+fn eventsel(pmu: &Pmu, event: &Event) -> u64 {
+    let (select, umask) = if pmu.intel { event.intel } else { event.amd };
+    u64::from(select) | u64::from(umask) << 8 | EVENTSEL_USR | EVENTSEL_OS | EVENTSEL_ENABLE
+}
+
+/// Increase of a counter of `width` bits, which may have wrapped around once.
+fn counter_delta(before: u64, now: u64, width: u32) -> u64 {
+    let mask = if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+    now.wrapping_sub(before) & mask
+}
+
+fn msr(index: u32, data: u64) -> kvmb::kvm_msr_entry {
+    kvmb::kvm_msr_entry {
+        index,
+        data,
+        ..Default::default()
+    }
+}
+
+fn read_msrs(vm: &Hypervisor, vcpu: &VCPU, indices: &[u32]) -> Result<Vec<u64>> {
+    let entries = vm.get_msrs(vcpu, indices)?;
+    indices
+        .iter()
+        .map(|index| match entries.iter().find(|e| e.index == *index) {
+            Some(entry) => Ok(entry.data),
+            None => bail!("kvm does not know msr {:#x} of vcpu {}", index, vcpu.idx),
+        })
+        .collect()
+}
+
+/// The counters vmsh took and what to write back.
+struct Programmed<'a> {
+    vm: &'a Hypervisor,
+    pmu: Pmu,
+    /// Index of the counter for each event
+    counters: Vec<usize>,
+    /// Previous msrs per vcpu
+    saved: Vec<Vec<kvmb::kvm_msr_entry>>,
+}
+
+impl<'a> Programmed<'a> {
+    /// Takes the first counters that are disabled on every vcpu.
+    fn new(vm: &'a Hypervisor, pmu: Pmu, events: &[&Event]) -> Result<Programmed<'a>> {
+        let selects = (0..pmu.counters)
+            .map(|c| pmu.eventsel(c))
+            .collect::<Vec<_>>();
+        let mut in_use = vec![false; pmu.counters];
+        for vcpu in &vm.vcpus {
+            for (c, value) in read_msrs(vm, vcpu, &selects)?.iter().enumerate() {
+                in_use[c] |= value & EVENTSEL_ENABLE != 0;
+            }
+        }
+        let counters = (0..pmu.counters)
+            .filter(|c| !in_use[*c])
+            .take(events.len())
+            .collect::<Vec<_>>();
+        if counters.len() < events.len() {
+            bail!(
+                "the guest uses {} of its {} counters, {} are left for {} events",
+                in_use.iter().filter(|u| **u).count(),
+                pmu.counters,
+                counters.len(),
+                events.len()
+            );
+        }
+
+        let mut indices = vec![];
+        for c in &counters {
+            indices.push(pmu.eventsel(*c));
+            indices.push(pmu.counter(*c));
+        }
+        if pmu.global_ctrl {
+            indices.push(MSR_IA32_PERF_GLOBAL_CTRL);
+        }
+        let mut programmed = Programmed {
+            vm,
+            pmu,
+            counters,
+            saved: vec![],
+        };
+        for vcpu in &vm.vcpus {
+            let values = read_msrs(vm, vcpu, &indices)?;
+            let saved = indices
+                .iter()
+                .zip(&values)
+                .map(|(i, v)| msr(*i, *v))
+                .collect();
+            programmed.saved.push(saved);
+
+            let mut entries = vec![];
+            for (c, event) in programmed.counters.iter().zip(events) {
+                entries.push(msr(programmed.pmu.counter(*c), 0));
+                entries.push(msr(
+                    programmed.pmu.eventsel(*c),
+                    eventsel(&programmed.pmu, event),
+                ));
+            }
+            if programmed.pmu.global_ctrl {
+                let global = values[values.len() - 1];
+                let ours = programmed.counters.iter().fold(0, |m, c| m | 1 << c);
+                entries.push(msr(MSR_IA32_PERF_GLOBAL_CTRL, global | ours));
+            }
+            try_with!(
+                vm.set_msrs(vcpu, &entries),
+                "cannot program the PMU of vcpu {}",
+                vcpu.idx
+            );
+        }
+        Ok(programmed)
+    }
+
+    /// Counter values per vcpu, in the order of the events.
+    fn read(&self) -> Result<Vec<Vec<u64>>> {
+        let indices = self
+            .counters
+            .iter()
+            .map(|c| self.pmu.counter(*c))
+            .collect::<Vec<_>>();
+        self.vm
+            .vcpus
+            .iter()
+            .map(|vcpu| read_msrs(self.vm, vcpu, &indices))
+            .collect()
+    }
+}
+
+impl<'a> Drop for Programmed<'a> {
+    fn drop(&mut self) {
+        let _stopped = match self.vm.stop_guard() {
+            Ok(stopped) => stopped,
+            Err(e) => {
+                warn!("cannot stop the guest to restore its PMU: {}", e);
+                return;
+            }
+        };
+        for (vcpu, saved) in self.vm.vcpus.iter().zip(&self.saved) {
+            if let Err(e) = self.vm.set_msrs(vcpu, saved) {
+                warn!("cannot restore the PMU of vcpu {}: {}", vcpu.idx, e);
+            }
+        }
+    }
+}
+
+fn ratio(a: u64, b: u64) -> String {
+    if b == 0 {
+        String::from("-")
+    } else {
+        format!("{:.2}", a as f64 / b as f64)
+    }
+}
+
+fn print_counts(label: &str, names: &[&str], counts: &[u64]) {
+    let count = |name: &str| names.iter().position(|n| *n == name).map(|i| counts[i]);
+    let mut line = format!("{:<6}", label);
+    for (name, count) in names.iter().zip(counts) {
+        line.push_str(&format!(" {}={}", name, count));
+    }
+    if let (Some(cycles), Some(instructions)) = (count("cycles"), count("instructions")) {
+        line.push_str(&format!(" ipc={}", ratio(instructions, cycles)));
+    }
+    if let (Some(refs), Some(misses)) = (count("cache-references"), count("cache-misses")) {
+        if refs != 0 {
+            line.push_str(&format!(
+                " cache-miss-rate={:.1}%",
+                misses as f64 * 100.0 / refs as f64
+            ));
+        }
+    }
+    if let (Some(branches), Some(misses)) = (count("branches"), count("branch-misses")) {
+        if branches != 0 {
+            line.push_str(&format!(
+                " branch-miss-rate={:.1}%",
+                misses as f64 * 100.0 / branches as f64
+            ));
+        }
+    }
+    println!("{}", line);
+}
+
+pub fn pmu(opts: &PmuOptions) -> Result<()> {
+    let events = opts
+        .events
+        .iter()
+        .map(|name| match EVENTS.iter().find(|e| e.name == name) {
+            Some(event) => Ok(event),
+            None => bail!(
+                "unknown event {}, known are: {}",
+                name,
+                EVENTS.iter().map(|e| e.name).collect::<Vec<_>>().join(", ")
+            ),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if events.is_empty() {
+        bail!("no events to count");
+    }
+    let names = events.iter().map(|e| e.name).collect::<Vec<_>>();
+
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let cancel = Cancellation::setup()?;
+    let (programmed, mut before) = {
+        let _stopped = vm.stop_guard()?;
+        let cpuid = try_with!(vm.get_cpuid2(&vm.vcpus[0]), "cannot get cpuid of the guest");
+        let entries = &cpuid.entries[..(cpuid.nent as usize).min(cpuid.entries.len())];
+        let pmu = guest_pmu(entries)?;
+        let programmed = Programmed::new(&vm, pmu, &events)?;
+        let before = programmed.read()?;
+        (programmed, before)
+    };
+
+    let mut intervals = 0;
+    while opts.count.map_or(true, |count| intervals < count) {
+        thread::sleep(opts.interval);
+        if cancel.is_cancelled() {
+            break;
+        }
+        let now = {
+            let _stopped = vm.stop_guard()?;
+            programmed.read()?
+        };
+        let deltas = now
+            .iter()
+            .zip(&before)
+            .map(|(now, before)| {
+                now.iter()
+                    .zip(before)
+                    .map(|(n, b)| counter_delta(*b, *n, programmed.pmu.width))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        if opts.per_vcpu {
+            println!("--- last {:.1}s", opts.interval.as_secs_f64());
+            for (vcpu, counts) in programmed.vm.vcpus.iter().zip(&deltas) {
+                print_counts(&format!("vcpu{}", vcpu.idx), &names, counts);
+            }
+        } else {
+            let total = (0..names.len())
+                .map(|i| deltas.iter().map(|d| d[i]).sum())
+                .collect::<Vec<u64>>();
+            print_counts(
+                &format!("{:.1}s", opts.interval.as_secs_f64()),
+                &names,
+                &total,
+            );
+        }
+        before = now;
+        intervals += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_pmu() {
+        let mut leaf0 = kvmb::kvm_cpuid_entry2 {
+            function: 0,
+            ..Default::default()
+        };
+        let vendor = b"GenuineIntel";
+        leaf0.ebx = u32::from_le_bytes([vendor[0], vendor[1], vendor[2], vendor[3]]);
+        leaf0.edx = u32::from_le_bytes([vendor[4], vendor[5], vendor[6], vendor[7]]);
+        leaf0.ecx = u32::from_le_bytes([vendor[8], vendor[9], vendor[10], vendor[11]]);
+        let leaf1 = kvmb::kvm_cpuid_entry2 {
+            function: 1,
+            ..Default::default()
+        };
+        let mut leafa = kvmb::kvm_cpuid_entry2 {
+            function: 0xa,
+            eax: 48 << 16 | 8 << 8 | 2,
+            ..Default::default()
+        };
+        let pmu = guest_pmu(&[leaf0, leaf1, leafa]).unwrap();
+        assert_eq!(pmu.counters, 8);
+        assert_eq!(pmu.width, 48);
+        assert!(pmu.global_ctrl);
+        assert_eq!(pmu.eventsel(2), MSR_IA32_PERFEVTSEL0 + 2);
+        assert_eq!(
+            eventsel(&pmu, &EVENTS[3]),
+            0x412e | EVENTSEL_USR | EVENTSEL_OS | EVENTSEL_ENABLE
+        );
+
+        leafa.eax = 0;
+        assert!(guest_pmu(&[leaf0, leaf1, leafa]).is_err());
+    }
+
+    #[test]
+    fn test_counter_delta() {
+        assert_eq!(counter_delta(100, 250, 48), 150);
+        assert_eq!(counter_delta((1 << 48) - 10, 5, 48), 15);
+    }
+}
@@ -0,0 +1,249 @@
+//! Read and patch guest memory, see `vmsh read` and `vmsh write`.
+//!
+//! The bytes are written while the guest is stopped, so it never sees a partial write. The
+//! previous content is logged, so that a patch can be reverted with a second `vmsh write`.
+use log::{debug, info};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::io::Write;
+use std::ops::Range;
+
+use crate::kvm;
+use crate::result::Result;
+use crate::sha256;
+
+pub struct PeekOptions {
+    pub pid: Pid,
+    /// Guest physical address
+    pub addr: usize,
+    pub len: usize,
+    /// Write the bytes to stdout instead of a hexdump.
+    pub raw: bool,
+}
+
+pub struct PokeOptions {
+    pub pid: Pid,
+    pub addr: usize,
+    pub data: Vec<u8>,
+    /// `addr` is a virtual address of the page tables in `cr3`.
+    pub virt: bool,
+    /// Page table base for `virt`. Defaults to the cr3 of the first vcpu, i.e. of the process
+    /// it currently runs, which maps the kernel as well.
+    pub cr3: Option<u64>,
+}
+
+/// Parse a hexadecimal address with or without `0x`.
+pub fn parse_addr(s: &str) -> Result<usize> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    Ok(try_with!(
+        usize::from_str_radix(digits, 16),
+        "invalid address '{}'",
+        s
+    ))
+}
+
+/// Parse bytes written as hex digits, e.g. `90 90` or `0x9090`.
+pub fn parse_hex_bytes(s: &str) -> Result<Vec<u8>> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        bail!("expected an even number of hex digits, got '{}'", s);
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = require_with!(digits.get(i..i + 2), "invalid hex bytes '{}'", s);
+            Ok(try_with!(
+                u8::from_str_radix(byte, 16),
+                "invalid hex bytes '{}'",
+                s
+            ))
+        })
+        .collect()
+}
+
+/// Parts of `range` that are not in any of `mapped`.
+fn holes(mapped: &[Range<usize>], range: Range<usize>) -> Vec<Range<usize>> {
+    let mut mapped = mapped
+        .iter()
+        .filter(|m| m.start < range.end && range.start < m.end)
+        .collect::<Vec<_>>();
+    mapped.sort_by_key(|m| m.start);
+    let mut holes = vec![];
+    let mut pos = range.start;
+    for m in mapped {
+        if m.start > pos {
+            holes.push(pos..m.start);
+        }
+        pos = std::cmp::max(pos, m.end);
+    }
+    if pos < range.end {
+        holes.push(pos..range.end);
+    }
+    holes
+}
+
+/// One line of `hexdump -C`, `bytes` has at most 16 elements.
+pub(crate) fn hexdump_line(addr: usize, bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for i in 0..16 {
+        if i == 8 {
+            hex.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => hex.push_str(&format!("{:02x} ", b)),
+            None => hex.push_str("   "),
+        }
+    }
+    let ascii = bytes
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect::<String>();
+    format!("{:016x}  {} |{}|", addr, hex, ascii)
+}
+
+pub fn peek(opts: &PeekOptions) -> Result<()> {
+    let end = require_with!(
+        opts.addr.checked_add(opts.len),
+        "address range {:#x}+{:#x} overflows",
+        opts.addr,
+        opts.len
+    );
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    let maps = vm.get_maps()?;
+    let slots = maps
+        .iter()
+        .map(|m| m.phys_addr..m.phys_end())
+        .collect::<Vec<_>>();
+    let holes = holes(&slots, opts.addr..end);
+    if !holes.is_empty() {
+        bail!(
+            "{:#x}-{:#x} is not fully backed by memslots, unmapped: {}",
+            opts.addr,
+            end,
+            holes
+                .iter()
+                .map(|h| format!("{:#x}-{:#x}", h.start, h.end))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    for m in maps
+        .iter()
+        .filter(|m| m.phys_addr < end && opts.addr < m.phys_end())
+    {
+        debug!(
+            "memslot {:#x}-{:#x} at host address {:#x}",
+            m.phys_addr,
+            m.phys_end(),
+            m.start
+        );
+    }
+
+    let mut buf = vec![0u8; opts.len];
+    vm.read_guest_phys(opts.addr, &mut buf)?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if opts.raw {
+        try_with!(out.write_all(&buf), "cannot write to stdout");
+    } else {
+        for (i, line) in buf.chunks(16).enumerate() {
+            try_with!(
+                writeln!(out, "{}", hexdump_line(opts.addr + i * 16, line)),
+                "cannot write to stdout"
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn poke(opts: &PokeOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    let mut old = vec![0u8; opts.data.len()];
+    if opts.virt {
+        let vcpu = require_with!(vm.vcpus.first(), "vm has no vcpus");
+        let mut sregs = vm.get_sregs(vcpu)?;
+        if let Some(cr3) = opts.cr3 {
+            sregs.cr3 = cr3;
+        }
+        vm.read_guest_virt(&sregs, opts.addr, &mut old)?;
+        vm.write_guest_virt(&sregs, opts.addr, &opts.data)?;
+    } else {
+        vm.read_guest_phys(opts.addr, &mut old)?;
+        vm.write_guest_phys(opts.addr, &opts.data)?;
+    }
+    info!(
+        "wrote {} bytes at {} address {:#x}, previous content: {}",
+        opts.data.len(),
+        if opts.virt { "virtual" } else { "physical" },
+        opts.addr,
+        sha256::to_hex(&old)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(parse_addr("0x1000").unwrap(), 0x1000);
+        assert_eq!(
+            parse_addr("ffffffff81000000").unwrap(),
+            0xffff_ffff_8100_0000
+        );
+        assert!(parse_addr("0xg").is_err());
+    }
+
+    #[test]
+    fn test_holes() {
+        let mapped = [0x10000..0x20000, 0..0x1000, 0x1000..0x4000];
+        assert_eq!(holes(&mapped, 0..0x4000), vec![]);
+        assert_eq!(holes(&mapped, 0x3000..0x11000), vec![0x4000..0x10000]);
+        assert_eq!(holes(&mapped, 0x1f000..0x30000), vec![0x20000..0x30000]);
+        assert_eq!(holes(&[], 0x100..0x200), vec![0x100..0x200]);
+    }
+
+    #[test]
+    fn test_hexdump_line() {
+        assert_eq!(
+            hexdump_line(0x1000, b"vmsh\x00\x01\x02\x03\xfa\xf4\xeb\xfd ~AZ"),
+            "0000000000001000  76 6d 73 68 00 01 02 03  fa f4 eb fd 20 7e 41 5a  |vmsh........ ~AZ|"
+        );
+        assert_eq!(
+            hexdump_line(0x10, b"ab"),
+            "0000000000000010  61 62                                             |ab|"
+        );
+    }
+
+    #[test]
+    fn test_parse_hex_bytes() {
+        assert_eq!(parse_hex_bytes("90 90").unwrap(), vec![0x90, 0x90]);
+        assert_eq!(
+            parse_hex_bytes("0xdeadBEEF").unwrap(),
+            vec![0xde, 0xad, 0xbe, 0xef]
+        );
+        assert!(parse_hex_bytes("909").is_err());
+        assert!(parse_hex_bytes("zz").is_err());
+        assert!(parse_hex_bytes("").is_err());
+        assert!(parse_hex_bytes("é0").is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Dump the address space of a single guest process into an ELF core that gdb can load.
+//!
+//! The profile (see `vmi`) needs these offsets:
+//!
+//! ```text
+//! task_struct.tasks 0x4c8
+//! task_struct.pid 0x5c0
+//! task_struct.comm 0x778
+//! task_struct.mm 0x518
+//! task_struct.stack 0x20
+//! mm_struct.pgd 0x48
+//! # linux >= 6.1 keeps vmas in a maple tree
+//! mm_struct.mm_mt.ma_root 0x48
+//! # older kernels in a list instead
+//! # mm_struct.mmap 0x0
+//! # vm_area_struct.vm_next 0x10
+//! vm_area_struct.vm_start 0x0
+//! vm_area_struct.vm_end 0x8
+//! vm_area_struct.vm_mm 0x10
+//! vm_area_struct.vm_flags 0x20
+//! # optional
+//! mm_struct.saved_auxv 0x150
+//! task_struct.thread.fsbase 0x1528
+//! thread_size 0x4000
+//! ```
+//!
+//! Only the main thread of the process is dumped. Pages that are not present in the guest, i.e.
+//! swapped out or never touched, read as zeros. Guest pids are those of the initial pid
+//! namespace. Pass the executable of the process to gdb alongside the core, file names of the
+//! mappings are not recorded.
+use libc::{timeval, PT_LOAD, PT_NOTE};
+use log::{info, warn};
+use simple_error::{bail, require_with, try_with};
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::core_file::CoreFile;
+use crate::coredump::{any_as_bytes, elf_header};
+use crate::elf::{
+    elf_prpsinfo, elf_prstatus, elf_siginfo, Ehdr, Elf_Addr, Elf_Off, Elf_Word, Nhdr, Phdr,
+    ELF_NGREG, NT_AUXV, NT_PRPSINFO, NT_PRSTATUS, PF_R, PF_W, PF_X,
+};
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::guest_mem::get_page_table_addr;
+use crate::kvm;
+use crate::page_math::{huge_page_size, page_align, page_size};
+use crate::page_table::{PageTable, PhysAddr};
+use crate::ps::{self, TASK_COMM_LEN};
+use crate::result::Result;
+use crate::vmi::{GuestMemory, KernelMemory, Profile, PTI_USER_PGTABLE};
+
+pub struct ProcessDumpOptions {
+    pub target: GuestTarget,
+    /// Pid of the process in the guest.
+    pub process: i32,
+    pub path: PathBuf,
+    pub profile: PathBuf,
+}
+
+/// Linux on x86_64 keeps the user registers at the top of the kernel stack.
+const THREAD_SIZE: usize = 16 * 1024;
+/// Registers saved in `struct pt_regs`, they come first in `user_regs_struct`.
+const PT_REGS_COUNT: usize = 21;
+/// End of the user address space with 4-level paging.
+const USER_END: usize = 0x0000_8000_0000_0000;
+/// Stop walking lists that do not lead back to their head.
+const MAX_VMAS: usize = 1 << 16;
+
+// vm_area_struct.vm_flags
+const VM_READ: u64 = 0x1;
+const VM_WRITE: u64 = 0x2;
+const VM_EXEC: u64 = 0x4;
+const VM_PFNMAP: u64 = 0x400;
+const VM_IO: u64 = 0x4000;
+
+// maple tree node encoding, see lib/maple_tree.c
+const MAPLE_NODE_MASK: u64 = 0xff;
+const MAPLE_NODE_TYPE_SHIFT: u64 = 3;
+const MAPLE_NODE_TYPE_MASK: u64 = 0xf;
+const MAPLE_LEAF_64: u64 = 1;
+const MAPLE_RANGE_64: u64 = 2;
+const MAPLE_ARANGE_64: u64 = 3;
+/// Depth is bounded by the number of vmas, this is plenty.
+const MAPLE_MAX_HEIGHT: usize = 31;
+/// auxv entries saved in mm_struct, AT_VECTOR_SIZE on x86_64
+const AUXV_ENTRIES: usize = 2 * (2 + 20 + 1 + 3);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Vma {
+    start: usize,
+    end: usize,
+    flags: u64,
+}
+
+struct Task {
+    addr: usize,
+    comm: [u8; TASK_COMM_LEN],
+    mm: usize,
+}
+
+/// The task list only contains thread group leaders, so this finds processes but not their
+/// other threads.
+fn find_task(k: &KernelMemory, p: &Profile, pid: i32) -> Result<Task> {
+    let pid_offset = p.get("task_struct.pid")?;
+    for task in ps::task_list(k, p)? {
+        if k.read_i32(task + pid_offset)? == pid {
+            let mut comm = [0u8; TASK_COMM_LEN];
+            k.read_bytes(task + p.get("task_struct.comm")?, &mut comm)?;
+            let mm = k.read_ptr(task + p.get("task_struct.mm")?)?;
+            return Ok(Task {
+                addr: task,
+                comm,
+                mm,
+            });
+        }
+    }
+    bail!("no process with pid {} in the guest", pid)
+}
+
+fn vma_flags(flags: u64) -> Elf_Word {
+    let mut f = 0;
+    if flags & VM_READ != 0 {
+        f |= PF_R;
+    }
+    if flags & VM_WRITE != 0 {
+        f |= PF_W;
+    }
+    if flags & VM_EXEC != 0 {
+        f |= PF_X;
+    }
+    f
+}
+
+/// Read a vma and make sure it belongs to `mm`, to not trip over stale pointers.
+fn read_vma(k: &KernelMemory, p: &Profile, mm: usize, addr: usize) -> Result<Option<Vma>> {
+    let vma_mm = k.read_ptr(addr + p.get("vm_area_struct.vm_mm")?)?;
+    let start = k.read_ptr(addr + p.get("vm_area_struct.vm_start")?)?;
+    let end = k.read_ptr(addr + p.get("vm_area_struct.vm_end")?)?;
+    let flags = k.read_u64(addr + p.get("vm_area_struct.vm_flags")?)?;
+    if vma_mm != mm || start >= end || end > USER_END || start % page_size() != 0 {
+        return Ok(None);
+    }
+    Ok(Some(Vma { start, end, flags }))
+}
+
+/// Linux before 6.1 links the vmas of a process in a sorted list.
+fn list_vmas(k: &KernelMemory, p: &Profile, mm: usize, mmap: usize) -> Result<Vec<Vma>> {
+    let next = p.get("vm_area_struct.vm_next")?;
+    let mut vmas = vec![];
+    let mut addr = k.read_ptr(mm + mmap)?;
+    while addr != 0 {
+        if vmas.len() == MAX_VMAS {
+            bail!("vma list does not end, is the offset of vm_area_struct.vm_next right?");
+        }
+        match read_vma(k, p, mm, addr)? {
+            Some(vma) => vmas.push(vma),
+            None => bail!(
+                "invalid vma at {:#x}, check the vm_area_struct offsets",
+                addr
+            ),
+        }
+        addr = k.read_ptr(addr + next)?;
+    }
+    Ok(vmas)
+}
+
+/// The root of a maple tree is either a node marked as internal entry or, if the tree has
+/// only one entry, that entry.
+fn maple_root(root: u64) -> Option<u64> {
+    // xa_is_node()
+    if root & 3 == 2 && root > 4096 {
+        Some(root & !2)
+    } else {
+        None
+    }
+}
+
+/// Node pointer and type of an encoded maple tree node, see mt_mk_node().
+fn maple_node(enode: u64) -> (usize, u64) {
+    (
+        (enode & !MAPLE_NODE_MASK) as usize,
+        (enode >> MAPLE_NODE_TYPE_SHIFT) & MAPLE_NODE_TYPE_MASK,
+    )
+}
+
+/// Offset and number of the slots in a maple tree node of type `node_type`.
+fn maple_slots(node_type: u64) -> Option<(usize, usize)> {
+    match node_type {
+        // parent pointer, 15 pivots, 16 slots
+        MAPLE_LEAF_64 | MAPLE_RANGE_64 => Some((8 + 15 * 8, 16)),
+        // parent pointer, 9 pivots, 10 slots
+        MAPLE_ARANGE_64 => Some((8 + 9 * 8, 10)),
+        _ => None,
+    }
+}
+
+fn collect_maple_entries(
+    k: &KernelMemory,
+    enode: u64,
+    height: usize,
+    entries: &mut Vec<usize>,
+) -> Result<()> {
+    let (node, node_type) = maple_node(enode);
+    if height > MAPLE_MAX_HEIGHT {
+        bail!("maple tree of the process is too deep, is it corrupted?");
+    }
+    let (offset, count) = require_with!(
+        maple_slots(node_type),
+        "unsupported maple tree node type {}",
+        node_type
+    );
+    let mut buf = vec![0u8; count * 8];
+    k.read_bytes(node + offset, &mut buf)?;
+    for slot in buf.chunks(8) {
+        let slot = u64::from_ne_bytes([
+            slot[0], slot[1], slot[2], slot[3], slot[4], slot[5], slot[6], slot[7],
+        ]);
+        if node_type == MAPLE_LEAF_64 {
+            if slot != 0 && slot & 3 == 0 {
+                entries.push(slot as usize);
+            }
+        } else if slot != 0 {
+            collect_maple_entries(k, slot, height + 1, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Linux 6.1 and later keep the vmas of a process in a maple tree.
+fn maple_tree_vmas(k: &KernelMemory, p: &Profile, mm: usize, root: usize) -> Result<Vec<Vma>> {
+    let mut entries = vec![];
+    let root = k.read_u64(mm + root)?;
+    match maple_root(root) {
+        Some(enode) => collect_maple_entries(k, enode, 0, &mut entries)?,
+        None if root != 0 && root & 3 == 0 => entries.push(root as usize),
+        None => {}
+    }
+    entries.sort_unstable();
+    entries.dedup();
+    let mut vmas = vec![];
+    for addr in entries {
+        // slots past the end of a node can hold stale pointers
+        if let Ok(Some(vma)) = read_vma(k, p, mm, addr) {
+            vmas.push(vma);
+        }
+    }
+    vmas.sort_by_key(|v| v.start);
+    vmas.dedup();
+    Ok(vmas)
+}
+
+fn find_vmas(k: &KernelMemory, p: &Profile, mm: usize) -> Result<Vec<Vma>> {
+    if let Some(root) = p.optional("mm_struct.mm_mt.ma_root") {
+        maple_tree_vmas(k, p, mm, root)
+    } else if let Some(mmap) = p.optional("mm_struct.mmap") {
+        list_vmas(k, p, mm, mmap)
+    } else {
+        bail!("profile needs either mm_struct.mm_mt.ma_root or mm_struct.mmap")
+    }
+}
+
+/// Registers of `task` in the order of `user_regs_struct`.
+fn task_registers(
+    k: &KernelMemory,
+    p: &Profile,
+    task: &Task,
+    pgd: usize,
+) -> Result<[u64; ELF_NGREG]> {
+    // a process that runs in userspace right now has its registers only in the vcpu
+    for idx in 0..k.mem.src.vcpu_count() {
+        let sregs = k.mem.src.vcpu_sregs(idx)?;
+        let regs = k.mem.src.vcpu_regs(idx)?;
+        if get_page_table_addr(&sregs) & !PTI_USER_PGTABLE == pgd && regs.is_userspace() {
+            info!("process is running on vcpu {}", idx);
+            return Ok(unsafe { ptr::read(&regs as *const _ as *const [u64; ELF_NGREG]) });
+        }
+    }
+
+    // otherwise they were saved on kernel entry
+    let stack = k.read_ptr(task.addr + p.get("task_struct.stack")?)?;
+    let thread_size = p.optional("thread_size").unwrap_or(THREAD_SIZE);
+    let pt_regs = stack + thread_size - PT_REGS_COUNT * 8;
+    let mut buf = [0u8; PT_REGS_COUNT * 8];
+    k.read_bytes(pt_regs, &mut buf)?;
+    let mut regs = [0u64; ELF_NGREG];
+    for (reg, bytes) in regs.iter_mut().zip(buf.chunks(8)) {
+        *reg = u64::from_ne_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]);
+    }
+    if let Some(fsbase) = p.optional("task_struct.thread.fsbase") {
+        // fs_base follows the pt_regs registers
+        regs[PT_REGS_COUNT] = k.read_u64(task.addr + fsbase)?;
+    }
+    Ok(regs)
+}
+
+/// auxv entries up to and including AT_NULL, gdb needs them to relocate PIE executables.
+fn read_auxv(k: &KernelMemory, p: &Profile, mm: usize) -> Result<Vec<u64>> {
+    let offset = match p.optional("mm_struct.saved_auxv") {
+        Some(offset) => offset,
+        None => return Ok(vec![]),
+    };
+    let mut buf = [0u8; AUXV_ENTRIES * 8];
+    k.read_bytes(mm + offset, &mut buf)?;
+    let mut auxv = vec![];
+    for pair in buf.chunks(16) {
+        let word = |b: &[u8]| u64::from_ne_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+        let (key, value) = (word(&pair[..8]), word(&pair[8..]));
+        auxv.push(key);
+        auxv.push(value);
+        if key == 0 {
+            break;
+        }
+    }
+    Ok(auxv)
+}
+
+fn write_note(out: &mut Vec<u8>, ntype: Elf_Word, desc: &[u8]) {
+    let hdr = Nhdr {
+        n_namesz: 5,
+        n_descsz: desc.len() as Elf_Word,
+        n_type: ntype,
+    };
+    out.extend_from_slice(unsafe { any_as_bytes(&hdr) });
+    out.extend_from_slice(b"CORE\0\0\0\0");
+    out.extend_from_slice(desc);
+    out.resize((out.len() + 3) & !3, 0);
+}
+
+fn notes(pid: i32, comm: &[u8; TASK_COMM_LEN], regs: [u64; ELF_NGREG], auxv: &[u64]) -> Vec<u8> {
+    let zero = timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let status = elf_prstatus {
+        pr_info: elf_siginfo {
+            si_signo: 0,
+            si_code: 0,
+            si_errno: 0,
+        },
+        pr_cursig: 0,
+        pr_sigpend: 0,
+        pr_sighold: 0,
+        pr_pid: pid,
+        pr_ppid: 0,
+        pr_pgrp: 0,
+        pr_sid: 0,
+        pr_utime: zero,
+        pr_stime: zero,
+        pr_cutime: zero,
+        pr_cstime: zero,
+        pr_reg: regs,
+        pr_fpvalid: 0,
+    };
+    let mut psargs = [0; 80];
+    for (dst, src) in psargs.iter_mut().zip(comm.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    let info = elf_prpsinfo {
+        pr_state: 0,
+        pr_sname: 0,
+        pr_zomb: 0,
+        pr_nice: 0,
+        pr_flag: 0,
+        pr_uid: 0,
+        pr_gid: 0,
+        pr_pid: pid,
+        pr_ppid: 0,
+        pr_pgrp: 0,
+        pr_sid: 0,
+        pr_fname: *comm,
+        pr_psargs: psargs,
+    };
+    let mut out = vec![];
+    write_note(&mut out, NT_PRSTATUS, unsafe { any_as_bytes(&status) });
+    write_note(&mut out, NT_PRPSINFO, unsafe { any_as_bytes(&info) });
+    if !auxv.is_empty() {
+        let auxv = auxv
+            .iter()
+            .flat_map(|w| w.to_ne_bytes())
+            .collect::<Vec<_>>();
+        write_note(&mut out, NT_AUXV, &auxv);
+    }
+    out
+}
+
+/// Whether the content of a vma can and should be read.
+fn dumpable(vma: &Vma) -> bool {
+    vma.flags & VM_READ != 0 && vma.flags & (VM_IO | VM_PFNMAP) == 0
+}
+
+/// Copy the present pages of `vma` to `offset` in `core`. Returns the number of pages that
+/// were not present.
+fn dump_vma(
+    mem: &GuestMemory,
+    upml4: &PhysAddr,
+    vma: &Vma,
+    core: &File,
+    offset: usize,
+) -> Result<usize> {
+    let pml4 = PageTable::read(mem.src, upml4, 0, 0)?;
+    let mut present = 0;
+    let mut buf = vec![];
+    for e in pml4.iter(mem.src, vma.start..vma.end - 1) {
+        let e = e?;
+        let virt = e.virt_addr as usize;
+        let size = huge_page_size(e.level);
+        let start = std::cmp::max(virt, vma.start);
+        let end = std::cmp::min(virt + size, vma.end);
+        if start >= end {
+            continue;
+        }
+        let phys = (e.entry.addr() as usize & !(size - 1)) + (start - virt);
+        buf.resize(end - start, 0);
+        if let Err(e) = mem.read_phys(phys, &mut buf) {
+            warn!("cannot read {:#x}-{:#x}: {}", start, end, e);
+            continue;
+        }
+        try_with!(
+            core.write_all_at(&buf, (offset + start - vma.start) as u64),
+            "cannot write core file"
+        );
+        present += end - start;
+    }
+    Ok((vma.end - vma.start - present) / page_size())
+}
+
+fn write_process_core(
+    mem: &GuestMemory,
+    upml4: &PhysAddr,
+    vmas: &[Vma],
+    notes: &[u8],
+    path: &Path,
+) -> Result<()> {
+    let core = try_with!(File::create(path), "cannot create {}", path.display());
+    // +1 == PT_NOTE section
+    let ehdr = elf_header((vmas.len() + 1) as u16);
+    let notes_offset = size_of::<Ehdr>() + size_of::<Phdr>() * (vmas.len() + 1);
+    let mut offset = page_align(notes_offset + notes.len());
+
+    let mut headers = vec![Phdr {
+        p_type: PT_NOTE,
+        p_flags: 0,
+        p_offset: notes_offset as Elf_Off,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes.len() as u64,
+        p_memsz: 0,
+        p_align: 0,
+    }];
+    let mut missing = 0;
+    for vma in vmas {
+        let size = vma.end - vma.start;
+        let filesz = if dumpable(vma) {
+            missing += dump_vma(mem, upml4, vma, &core, offset)?;
+            size
+        } else {
+            0
+        };
+        headers.push(Phdr {
+            p_type: PT_LOAD,
+            p_flags: vma_flags(vma.flags),
+            p_offset: offset as Elf_Off,
+            p_vaddr: vma.start as Elf_Addr,
+            p_paddr: 0,
+            p_filesz: filesz as u64,
+            p_memsz: size as u64,
+            p_align: page_size() as u64,
+        });
+        offset += filesz;
+    }
+    // pages that were not present stay holes in the file
+    try_with!(core.set_len(offset as u64), "cannot truncate core file");
+
+    let mut metadata = vec![];
+    metadata.extend_from_slice(unsafe { any_as_bytes(&ehdr) });
+    for h in &headers {
+        metadata.extend_from_slice(unsafe { any_as_bytes(h) });
+    }
+    metadata.extend_from_slice(notes);
+    try_with!(core.write_all_at(&metadata, 0), "cannot write core file");
+    if missing > 0 {
+        info!(
+            "{} pages were not present in the guest and read as zeros",
+            missing
+        );
+    }
+    Ok(())
+}
+
+fn dump(src: &dyn GuestAccess, opts: &ProcessDumpOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = &Profile::load_for_kernel(&opts.profile, &k)?;
+
+    let task = find_task(&k, p, opts.process)?;
+    if task.mm == 0 {
+        bail!("{} is a kernel thread", opts.process);
+    }
+    let pgd = k.read_ptr(task.mm + p.get("mm_struct.pgd")?)?;
+    let pgd = try_with!(
+        k.virt_to_phys(pgd),
+        "page table of the process is not mapped"
+    );
+    let upml4 = k.mem.page_table(pgd)?;
+    let vmas = find_vmas(&k, p, task.mm)?;
+    info!(
+        "found process {} ({}) with {} vmas",
+        opts.process,
+        String::from_utf8_lossy(&task.comm).trim_end_matches('\0'),
+        vmas.len()
+    );
+    let regs = task_registers(&k, p, &task, pgd)?;
+    let auxv = read_auxv(&k, p, task.mm)?;
+    let notes = notes(opts.process, &task.comm, regs, &auxv);
+    write_process_core(&k.mem, &upml4, &vmas, &notes, &opts.path)
+}
+
+pub fn process_dump(opts: &ProcessDumpOptions) -> Result<()> {
+    println!("Write {}", opts.path.display());
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            dump(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            dump(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maple_node() {
+        let node = 0xffff_8880_0123_4500u64;
+        // mt_mk_node(): type in bits 3-6 and MAPLE_ENODE_NULL
+        let enode = node | (MAPLE_RANGE_64 << 3) | 0x4;
+        assert_eq!(maple_node(enode), (node as usize, MAPLE_RANGE_64));
+        assert_eq!(
+            maple_node(node | (MAPLE_LEAF_64 << 3) | 0x4),
+            (node as usize, MAPLE_LEAF_64)
+        );
+        // mte_mk_root()
+        assert_eq!(maple_root(enode | 2), Some(enode));
+        // a tree with a single vma
+        assert_eq!(maple_root(0xffff_8880_0123_4568), None);
+        assert_eq!(maple_root(0), None);
+        assert_eq!(maple_slots(MAPLE_ARANGE_64), Some((80, 10)));
+    }
+
+    #[test]
+    fn test_notes() {
+        let comm = *b"bash\0\0\0\0\0\0\0\0\0\0\0\0";
+        let notes = notes(42, &comm, [0; ELF_NGREG], &[6, 4096, 0, 0]);
+        let note_size = |desc: usize| size_of::<Nhdr>() + 8 + ((desc + 3) & !3);
+        assert_eq!(
+            notes.len(),
+            note_size(size_of::<elf_prstatus>())
+                + note_size(size_of::<elf_prpsinfo>())
+                + note_size(4 * 8)
+        );
+        assert_eq!(&notes[size_of::<Nhdr>()..size_of::<Nhdr>() + 5], b"CORE\0");
+    }
+}
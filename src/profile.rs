@@ -0,0 +1,358 @@
+//! Find out what the guest spends its cpu time on without running anything in it, see
+//! `vmsh profile`.
+//!
+//! vmsh stops the guest at a fixed frequency and reads the registers of every vcpu. In kernel
+//! mode the stack is unwound along the frame pointers, which needs a guest kernel with
+//! CONFIG_UNWINDER_FRAME_POINTER. Kernels with the ORC unwinder only show the function the vcpu
+//! was in. Samples in user mode are counted as `[user]`.
+//!
+//! The result is written as folded stacks, one line per stack with its sample count, which is
+//! the input format of flamegraph.pl and most flamegraph viewers, or rendered as a flamegraph
+//! svg directly. Static functions only have names with the full kernel symbols
+//! (`--symbols vmlinux:PATH` or `system-map:PATH`).
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::gdbstub::open_symbols;
+use crate::kvm;
+use crate::result::Result;
+use crate::sampling::{sample_vcpus, VcpuSample};
+use crate::signal_handler::Cancellation;
+use crate::symbolizer::{SymbolSource, Symbolizer};
+
+pub struct ProfileOptions {
+    pub pid: Pid,
+    /// Samples per second of each vcpu
+    pub frequency: u32,
+    pub duration: Duration,
+    pub symbols: SymbolSource,
+    /// Start each stack with the vcpu it was sampled on.
+    pub per_vcpu: bool,
+    /// Write folded stacks here instead of stdout.
+    pub output: Option<PathBuf>,
+    /// Also render a flamegraph svg here.
+    pub svg: Option<PathBuf>,
+}
+
+/// Deepest stack that is unwound.
+const MAX_FRAMES: usize = 64;
+/// Kernel stacks are 16 KiB, 32 KiB with KASAN. Frames further away belong to another stack.
+const MAX_STACK_SIZE: usize = 32 * 1024;
+
+const USER_FRAME: &str = "[user]";
+
+const SVG_WIDTH: f64 = 1200.0;
+const SVG_FRAME_HEIGHT: f64 = 16.0;
+const SVG_HEADER: f64 = 32.0;
+/// Frames narrower than this are left out of the svg.
+const SVG_MIN_WIDTH: f64 = 0.1;
+/// Average width of a character at the font size of the svg
+const SVG_CHAR_WIDTH: f64 = 7.0;
+
+/// Return addresses along the frame pointer chain starting at `rbp`, innermost first. `read`
+/// returns the saved frame pointer and the return address of a frame.
+fn walk_frames(
+    read: impl Fn(usize) -> Option<(usize, usize)>,
+    symbolizer: &dyn Symbolizer,
+    rsp: usize,
+    rbp: usize,
+) -> Vec<usize> {
+    let mut frames = vec![];
+    let mut fp = rbp;
+    while frames.len() < MAX_FRAMES {
+        if fp % 8 != 0 || fp < rsp || fp - rsp > MAX_STACK_SIZE {
+            break;
+        }
+        let (next, ret) = match read(fp) {
+            Some(frame) => frame,
+            None => break,
+        };
+        // a return address points behind a call, never to the start of a function
+        if !matches!(symbolizer.symbolize(ret), Some((_, offset)) if offset > 0) {
+            break;
+        }
+        frames.push(ret);
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    frames
+}
+
+fn frame_name(symbolizer: &dyn Symbolizer, addr: usize) -> String {
+    match symbolizer.symbolize(addr) {
+        Some((name, _)) => name.to_string(),
+        None => format!("{:#x}", addr),
+    }
+}
+
+/// Stack of a vcpu from the outermost frame to the one it executes.
+fn stack_sample(sample: &VcpuSample, symbolizer: &dyn Symbolizer) -> Result<Vec<String>> {
+    if sample.is_user() {
+        return Ok(vec![USER_FRAME.to_string()]);
+    }
+    let regs = &sample.regs;
+    let memory = sample.memory()?;
+    let read = |fp: usize| {
+        let mut frame = [0u8; 16];
+        memory.read(fp, &mut frame).ok()?;
+        let word = |i: usize| u64::from_le_bytes(frame[i..i + 8].try_into().unwrap()) as usize;
+        Some((word(0), word(8)))
+    };
+    let callers = walk_frames(read, symbolizer, regs.rsp as usize, regs.rbp as usize);
+    let mut stack = callers
+        .iter()
+        .rev()
+        // the call can be the last instruction of a function
+        .map(|ret| frame_name(symbolizer, ret - 1))
+        .collect::<Vec<_>>();
+    stack.push(frame_name(symbolizer, regs.rip as usize));
+    Ok(stack)
+}
+
+/// Folded stacks: the frames joined by `;` and the number of samples, sorted by stack.
+fn format_folded(stacks: &BTreeMap<Vec<String>, u64>) -> String {
+    let mut out = String::new();
+    for (stack, count) in stacks {
+        let _ = writeln!(out, "{} {}", stack.join(";"), count);
+    }
+    out
+}
+
+#[derive(Default)]
+struct Node {
+    samples: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        self.children
+            .values()
+            .map(|c| c.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Warm colour that stays the same for a function across runs.
+fn frame_color(name: &str) -> String {
+    let hash = name.bytes().fold(2166136261u32, |h, b| {
+        (h ^ u32::from(b)).wrapping_mul(16777619)
+    });
+    format!(
+        "rgb({},{},{})",
+        205 + hash % 50,
+        (hash >> 8) % 230,
+        (hash >> 16) % 55
+    )
+}
+
+fn render_node(out: &mut String, name: &str, node: &Node, x: f64, depth: usize, ctx: (f64, f64)) {
+    let (scale, height) = ctx;
+    let width = node.samples as f64 * scale;
+    if width < SVG_MIN_WIDTH {
+        return;
+    }
+    let y = height - (depth + 1) as f64 * SVG_FRAME_HEIGHT;
+    let chars = ((width - 6.0) / SVG_CHAR_WIDTH).max(0.0) as usize;
+    let label = if name.chars().count() <= chars {
+        name.to_string()
+    } else if chars > 2 {
+        format!("{}..", name.chars().take(chars - 2).collect::<String>())
+    } else {
+        String::new()
+    };
+    let _ = writeln!(
+        out,
+        "<g><title>{} ({} samples, {:.2}%)</title><rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\" rx=\"2\"/><text x=\"{:.1}\" y=\"{:.1}\">{}</text></g>",
+        xml_escape(name),
+        node.samples,
+        width * 100.0 / SVG_WIDTH,
+        x,
+        y,
+        width,
+        SVG_FRAME_HEIGHT - 1.0,
+        frame_color(name),
+        x + 3.0,
+        y + SVG_FRAME_HEIGHT - 4.0,
+        xml_escape(&label)
+    );
+    let mut child_x = x;
+    for (child_name, child) in &node.children {
+        render_node(out, child_name, child, child_x, depth + 1, ctx);
+        child_x += child.samples as f64 * scale;
+    }
+}
+
+/// Flamegraph with the outermost frames at the bottom and the widths proportional to the
+/// samples.
+fn render_svg(stacks: &BTreeMap<Vec<String>, u64>, title: &str) -> String {
+    let mut root = Node::default();
+    for (stack, count) in stacks {
+        root.samples += count;
+        let mut node = &mut root;
+        for frame in stack {
+            node = node.children.entry(frame.clone()).or_default();
+            node.samples += count;
+        }
+    }
+    let height = SVG_HEADER + (root.depth() + 1) as f64 * SVG_FRAME_HEIGHT;
+    let scale = if root.samples == 0 {
+        0.0
+    } else {
+        SVG_WIDTH / root.samples as f64
+    };
+    let mut out = format!(
+        "<?xml version=\"1.0\" standalone=\"no\"?>\n<svg version=\"1.1\" width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\" font-family=\"monospace\" font-size=\"12\">\n<rect width=\"100%\" height=\"100%\" fill=\"#f8f8f8\"/>\n<text x=\"{}\" y=\"20\" text-anchor=\"middle\" font-size=\"16\">{}</text>\n",
+        SVG_WIDTH,
+        height,
+        SVG_WIDTH / 2.0,
+        xml_escape(title)
+    );
+    render_node(&mut out, "all", &root, 0.0, 0, (scale, height));
+    out.push_str("</svg>\n");
+    out
+}
+
+pub fn profile(opts: &ProfileOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let symbolizer = {
+        let _stopped = vm.stop_guard()?;
+        try_with!(
+            open_symbols(&vm, &opts.symbols),
+            "cannot load kernel symbols"
+        )
+    };
+    if opts.symbols == SymbolSource::Ksymtab {
+        warn!("only exported kernel symbols are known, static functions are shown as the exported function before them. Use --symbols vmlinux:PATH for all functions");
+    }
+
+    let interval = Duration::from_secs(1) / opts.frequency.max(1);
+    info!(
+        "sampling {} vcpus at {}Hz for {:?}",
+        vm.vcpus.len(),
+        opts.frequency,
+        opts.duration
+    );
+    let cancel = Cancellation::setup()?;
+    let mut stacks: BTreeMap<Vec<String>, u64> = BTreeMap::new();
+    let mut samples = 0u64;
+    let elapsed = sample_vcpus(&vm, interval, opts.duration, &cancel, |sample| {
+        let mut stack = stack_sample(&sample, symbolizer.as_ref())?;
+        if opts.per_vcpu {
+            stack.insert(0, format!("vcpu{}", sample.vcpu.idx));
+        }
+        *stacks.entry(stack).or_default() += 1;
+        samples += 1;
+        Ok(())
+    })?;
+    let user = stacks
+        .iter()
+        .filter(|(stack, _)| stack.last().map(String::as_str) == Some(USER_FRAME))
+        .map(|(_, count)| count)
+        .sum::<u64>();
+    info!(
+        "{} samples over {:.1}s, {} in user mode",
+        samples,
+        elapsed.as_secs_f64(),
+        user
+    );
+
+    let folded = format_folded(&stacks);
+    match &opts.output {
+        Some(path) => try_with!(fs::write(path, &folded), "cannot write {}", path.display()),
+        None => print!("{}", folded),
+    }
+    if let Some(path) = &opts.svg {
+        let title = format!("vmsh profile of {}, {} samples", opts.pid, samples);
+        try_with!(
+            fs::write(path, render_svg(&stacks, &title)),
+            "cannot write {}",
+            path.display()
+        );
+        info!("flamegraph written to {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolizer::SymbolTable;
+    use std::collections::HashMap;
+
+    fn symbols() -> SymbolTable {
+        let symbols = [
+            ("native_safe_halt", 0xffff_ffff_8100_0000),
+            ("default_idle", 0xffff_ffff_8100_1000),
+            ("do_idle", 0xffff_ffff_8100_2000),
+        ];
+        SymbolTable::new(
+            symbols
+                .iter()
+                .map(|(name, addr)| (name.to_string(), *addr))
+                .collect(),
+            0xffff_ffff_8100_0000..0xffff_ffff_8200_0000,
+        )
+    }
+
+    #[test]
+    fn test_walk_frames() {
+        let table = symbols();
+        let rsp = 0xffff_c900_0000_3e00;
+        let frames: HashMap<usize, (usize, usize)> = [
+            (rsp + 0x10, (rsp + 0x40, 0xffff_ffff_8100_1010)),
+            (rsp + 0x40, (rsp + 0x80, 0xffff_ffff_8100_2020)),
+            // entry code without a symbol ends the chain
+            (rsp + 0x80, (rsp + 0xc0, 0x1234)),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let read = |fp: usize| frames.get(&fp).copied();
+        assert_eq!(
+            walk_frames(read, &table, rsp, rsp + 0x10),
+            vec![0xffff_ffff_8100_1010, 0xffff_ffff_8100_2020]
+        );
+        // a frame pointer below the stack pointer is no frame pointer
+        assert!(walk_frames(read, &table, rsp, rsp - 0x10).is_empty());
+        assert!(walk_frames(read, &table, rsp, rsp + 0x11).is_empty());
+    }
+
+    #[test]
+    fn test_format_folded() {
+        let mut stacks = BTreeMap::new();
+        let idle = vec!["do_idle", "default_idle", "native_safe_halt"];
+        stacks.insert(idle.iter().map(|f| f.to_string()).collect(), 3);
+        stacks.insert(vec![USER_FRAME.to_string()], 1);
+        assert_eq!(
+            format_folded(&stacks),
+            "[user] 1\ndo_idle;default_idle;native_safe_halt 3\n"
+        );
+        let svg = render_svg(&stacks, "<test>");
+        assert!(svg.contains("&lt;test&gt;"));
+        assert!(svg.contains("<title>all (4 samples, 100.00%)</title>"));
+        assert!(svg.contains("<title>native_safe_halt (3 samples, 75.00%)</title>"));
+        assert!(svg.ends_with("</svg>\n"));
+    }
+}
@@ -0,0 +1,194 @@
+//! List the processes of a guest by walking the task list of its kernel, see `vmsh ps`.
+//!
+//! Nothing is injected into the guest, the list is read from memory while the vm is stopped.
+//! The profile (see `vmi`) needs these offsets, all of them can be derived from BTF:
+//!
+//! ```text
+//! task_struct.tasks 0x4c8
+//! task_struct.pid 0x5c0
+//! task_struct.comm 0x778
+//! # linux < 5.14 calls it task_struct.state
+//! task_struct.__state 0x18
+//! # optional
+//! task_struct.tgid 0x5c4
+//! task_struct.real_parent 0x5d0
+//! task_struct.exit_state 0x5a4
+//! task_struct.mm 0x518
+//! ```
+//!
+//! The task list only contains thread group leaders, so threads are not listed. Pids are those
+//! of the initial pid namespace.
+use simple_error::{bail, require_with, try_with};
+use std::path::PathBuf;
+
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::result::Result;
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct PsOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+}
+
+pub(crate) const TASK_COMM_LEN: usize = 16;
+/// Stop walking lists that do not lead back to their head.
+const MAX_TASKS: usize = 1 << 16;
+
+/// Bits of `__state | exit_state` that ps reports, see task_state_index() in linux.
+const TASK_REPORT: u32 = 0x7f;
+/// TASK_UNINTERRUPTIBLE | TASK_NOLOAD
+const TASK_IDLE: u32 = 0x402;
+/// Indexed by the highest bit of the reported state, as in /proc/<pid>/stat.
+const TASK_STATE_CHARS: &[u8] = b"RSDTtXZP";
+
+pub struct GuestProcess {
+    /// Address of the task_struct in the guest kernel
+    pub task: usize,
+    pub pid: i32,
+    /// None if the profile has no offsets for the parent
+    pub ppid: Option<i32>,
+    pub state: char,
+    pub comm: String,
+    /// Kernel threads have no address space.
+    pub kernel_thread: bool,
+}
+
+fn state_char(state: u32, exit_state: u32) -> char {
+    if state == TASK_IDLE {
+        return 'I';
+    }
+    let reported = (state | exit_state) & TASK_REPORT;
+    let idx = (32 - reported.leading_zeros()) as usize;
+    TASK_STATE_CHARS.get(idx).map_or('?', |c| *c as char)
+}
+
+fn comm_str(comm: &[u8]) -> String {
+    let len = comm.iter().position(|b| *b == 0).unwrap_or(comm.len());
+    String::from_utf8_lossy(&comm[..len]).into_owned()
+}
+
+fn read_process(k: &KernelMemory, p: &Profile, task: usize) -> Result<GuestProcess> {
+    let state_offset = require_with!(
+        p.optional("task_struct.__state")
+            .or_else(|| p.optional("task_struct.state")),
+        "profile has no offset for task_struct.__state"
+    );
+    let exit_state = match p.optional("task_struct.exit_state") {
+        Some(offset) => k.read_u32(task + offset)?,
+        None => 0,
+    };
+    let ppid = match (
+        p.optional("task_struct.real_parent"),
+        p.optional("task_struct.tgid"),
+    ) {
+        (Some(parent), Some(tgid)) => Some(k.read_i32(k.read_ptr(task + parent)? + tgid)?),
+        _ => None,
+    };
+    let kernel_thread = match p.optional("task_struct.mm") {
+        Some(mm) => k.read_ptr(task + mm)? == 0,
+        None => false,
+    };
+    let mut comm = [0u8; TASK_COMM_LEN];
+    k.read_bytes(task + p.get("task_struct.comm")?, &mut comm)?;
+    Ok(GuestProcess {
+        task,
+        pid: k.read_i32(task + p.get("task_struct.pid")?)?,
+        ppid,
+        state: state_char(k.read_u32(task + state_offset)?, exit_state),
+        comm: comm_str(&comm),
+        kernel_thread,
+    })
+}
+
+/// Addresses of the task_structs in the task list, without the idle task `init_task`.
+pub fn task_list(k: &KernelMemory, p: &Profile) -> Result<Vec<usize>> {
+    let tasks = p.get("task_struct.tasks")?;
+    let init_task = k.symbol("init_task")?;
+    let mut task = k.read_ptr(init_task + tasks)?.wrapping_sub(tasks);
+    let mut list = vec![];
+    while task != init_task {
+        if list.len() == MAX_TASKS {
+            bail!("task list of the guest does not end, is the offset of task_struct.tasks right?");
+        }
+        list.push(task);
+        task = k.read_ptr(task + tasks)?.wrapping_sub(tasks);
+    }
+    Ok(list)
+}
+
+/// Processes in the order of the task list, without the idle task.
+pub fn processes(k: &KernelMemory, p: &Profile) -> Result<Vec<GuestProcess>> {
+    task_list(k, p)?
+        .into_iter()
+        .map(|task| read_process(k, p, task))
+        .collect()
+}
+
+fn print_processes(processes: &[GuestProcess]) {
+    println!("{:>7} {:>7} {} COMMAND", "PID", "PPID", "S");
+    for process in processes {
+        let ppid = match process.ppid {
+            Some(ppid) => ppid.to_string(),
+            None => String::from("-"),
+        };
+        // like ps, kernel threads are shown in brackets
+        let comm = if process.kernel_thread {
+            format!("[{}]", process.comm)
+        } else {
+            process.comm.clone()
+        };
+        println!("{:>7} {:>7} {} {}", process.pid, ppid, process.state, comm);
+    }
+}
+
+fn ps_guest(src: &dyn GuestAccess, opts: &PsOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = Profile::load_for_kernel(&opts.profile, &k)?;
+    print_processes(&processes(&k, &p)?);
+    Ok(())
+}
+
+pub fn ps(opts: &PsOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            ps_guest(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            ps_guest(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_char() {
+        assert_eq!(state_char(0, 0), 'R');
+        assert_eq!(state_char(1, 0), 'S');
+        assert_eq!(state_char(2, 0), 'D');
+        assert_eq!(state_char(TASK_IDLE, 0), 'I');
+        // TASK_WAKEKILL | __TASK_STOPPED
+        assert_eq!(state_char(0x104, 0), 'T');
+        assert_eq!(state_char(0x8, 0), 't');
+        // TASK_DEAD with EXIT_ZOMBIE
+        assert_eq!(state_char(0x80, 0x20), 'Z');
+        assert_eq!(state_char(0x40, 0), 'P');
+    }
+
+    #[test]
+    fn test_comm_str() {
+        assert_eq!(comm_str(b"systemd\0\0\0\0\0\0\0\0\0"), "systemd");
+        assert_eq!(comm_str(b"0123456789abcdef"), "0123456789abcdef");
+    }
+}
@@ -0,0 +1,307 @@
+//! Host side of remote mode: executes whitelisted vmsh commands on behalf of a client.
+//!
+//! Over tcp, nothing is encrypted: the token, the command output and coredumps, i.e. guest
+//! memory, travel in plaintext. Only listen on trusted networks or on localhost behind a tunnel,
+//! and prefer ssh otherwise.
+use log::{info, warn};
+use nix::unistd::{dup, pipe};
+use simple_error::{bail, try_with};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::protocol::{self, Kind};
+use super::transfer::{self, RateLimiter};
+use crate::result::Result;
+
+/// Commands a client may run through the agent.
+pub const ALLOWED_COMMANDS: &[&str] = &["inspect", "coredump"];
+
+/// Flags of `vmsh inspect` a client may pass besides pids. `--core` and the fleet options are
+/// left out, they would read files or processes the client did not name by pid.
+const INSPECT_FLAGS: &[&str] = &["--sched", "--msrs", "--cpuid", "--backing"];
+const INSPECT_OUTPUT_FORMATS: &[&str] = &["text", "json"];
+
+/// Size of `Output` frames sent by the agent.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// A tcp session is dropped if the client does not read or write for this long.
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// Further tcp clients are turned away while this many sessions run.
+const MAX_SESSIONS: usize = 16;
+
+pub enum Listen {
+    /// Serve a single session on stdin/stdout, i.e. when spawned via ssh.
+    Stdio,
+    /// Serve sessions of tcp clients, each in its own thread. Clients have to send `token` first.
+    /// The connection is not encrypted, see module documentation.
+    Tcp { addr: String, token: Vec<u8> },
+}
+
+pub struct AgentOptions {
+    pub listen: Listen,
+}
+
+/// Run `args` with the current vmsh executable and forward its output as `Output` frames.
+fn run_command(w: &mut dyn Write, args: &[String]) -> Result<i32> {
+    let exe = try_with!(std::env::current_exe(), "cannot find vmsh executable");
+    let (read_fd, write_fd) = try_with!(pipe(), "cannot create pipe");
+    let write_fd2 = try_with!(dup(write_fd), "cannot duplicate pipe");
+    let mut output = unsafe { File::from_raw_fd(read_fd) };
+
+    let mut cmd = Command::new(exe);
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(unsafe { Stdio::from_raw_fd(write_fd) })
+        .stderr(unsafe { Stdio::from_raw_fd(write_fd2) });
+    let mut child = try_with!(cmd.spawn(), "cannot spawn vmsh {}", args.join(" "));
+    // close our copies of the write end, so that we see EOF once the child exits
+    drop(cmd);
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = try_with!(output.read(&mut buf), "cannot read command output");
+        if n == 0 {
+            break;
+        }
+        protocol::write_frame(w, Kind::Output, &buf[..n])?;
+    }
+    let status = try_with!(child.wait(), "cannot wait for vmsh {}", args.join(" "));
+    Ok(status.code().unwrap_or(1))
+}
+
+/// A file the client still has to fetch and acknowledge.
+struct PendingTransfer {
+    id: String,
+    path: PathBuf,
+    offset: u64,
+}
+
+/// Coredumps are written into the spool directory and transferred from there.
+fn coredump(w: &mut dyn Write, mut args: Vec<String>) -> Result<(i32, Option<PendingTransfer>)> {
+    // The client names the output file on its side.
+    if args.len() != 2 {
+        bail!("coredump request must only contain a pid");
+    }
+    let id = transfer::new_id(&format!("{}.core", args[1]))?;
+    let path = transfer::spool_path(&id)?;
+    args.push(path.display().to_string());
+    let code = run_command(w, &args)?;
+    if code != 0 {
+        let _ = fs::remove_file(&path);
+        return Ok((code, None));
+    }
+    let pending = PendingTransfer {
+        id,
+        path,
+        offset: 0,
+    };
+    Ok((code, Some(pending)))
+}
+
+/// `transfer <id> <offset>`: continue sending a file of an earlier session.
+fn resume(args: &[String]) -> Result<PendingTransfer> {
+    if args.len() != 3 {
+        bail!("transfer request must contain an id and an offset");
+    }
+    let id = args[1].clone();
+    let offset = try_with!(args[2].parse::<u64>(), "invalid offset {}", args[2]);
+    let path = transfer::spool_path(&id)?;
+    if !path.exists() {
+        bail!("no pending transfer with id {}", id);
+    }
+    info!("resume transfer {} at offset {}", id, offset);
+    Ok(PendingTransfer { id, path, offset })
+}
+
+/// Only pids and the flags in `INSPECT_FLAGS` are passed on to `vmsh inspect`.
+fn check_inspect_args(args: &[String]) -> Result<()> {
+    let mut args = args.iter();
+    let mut pids = 0;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => match args.next() {
+                Some(format) if INSPECT_OUTPUT_FORMATS.contains(&format.as_str()) => {}
+                _ => bail!(
+                    "--output must be one of {}",
+                    INSPECT_OUTPUT_FORMATS.join(", ")
+                ),
+            },
+            flag if INSPECT_FLAGS.contains(&flag) => {}
+            pid if !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()) => pids += 1,
+            _ => bail!(
+                "argument '{}' of inspect is not allowed in remote mode",
+                arg
+            ),
+        }
+    }
+    if pids == 0 {
+        bail!("inspect request must contain a pid");
+    }
+    Ok(())
+}
+
+fn handle_request(w: &mut dyn Write, args: Vec<String>) -> Result<(i32, Option<PendingTransfer>)> {
+    let command = match args.first() {
+        Some(c) if c == "transfer" => return Ok((0, Some(resume(&args)?))),
+        Some(c) if ALLOWED_COMMANDS.contains(&c.as_str()) => c.clone(),
+        Some(c) => bail!("command '{}' is not allowed in remote mode", c),
+        None => bail!("empty request"),
+    };
+    info!("remote request: {}", args.join(" "));
+
+    if command == "coredump" {
+        coredump(w, args)
+    } else {
+        check_inspect_args(&args[1..])?;
+        Ok((run_command(w, &args)?, None))
+    }
+}
+
+fn read_request(r: &mut dyn Read) -> Result<(Vec<String>, RateLimiter)> {
+    let mut limiter = RateLimiter::new(0);
+    loop {
+        let (kind, payload) = protocol::read_frame(r)?;
+        match kind {
+            Kind::Limit if payload.len() == 8 => {
+                limiter = RateLimiter::new(u64::from_le_bytes(payload[..].try_into().unwrap()));
+            }
+            Kind::Request => return Ok((protocol::decode_args(&payload)?, limiter)),
+            _ => bail!("expected request frame, got {:?}", kind),
+        }
+    }
+}
+
+/// Check the `Auth` frame of a tcp client, the agent runs commands as root.
+fn authenticate(r: &mut dyn Read, token: &[u8]) -> Result<()> {
+    let (kind, payload) = protocol::read_frame(r)?;
+    if kind != Kind::Auth || !protocol::token_matches(token, &payload) {
+        bail!("client failed to authenticate");
+    }
+    Ok(())
+}
+
+/// Serve a single client session. Sessions of tcp clients are authenticated with `token`, ssh
+/// already did that for stdio sessions.
+pub fn serve(r: &mut dyn Read, w: &mut dyn Write, token: Option<&[u8]>) -> Result<()> {
+    protocol::handshake(r, w)?;
+    if let Some(token) = token {
+        if let Err(e) = authenticate(r, token) {
+            protocol::write_frame(w, Kind::Error, b"authentication failed")?;
+            return Err(e);
+        }
+    }
+    let (args, mut limiter) = read_request(r)?;
+    let res = handle_request(w, args).and_then(|(code, pending)| {
+        if let Some(p) = &pending {
+            transfer::send(w, &p.id, &p.path, p.offset, &mut limiter)?;
+        }
+        Ok((code, pending))
+    });
+    let pending = match res {
+        Ok((code, pending)) => {
+            protocol::write_frame(w, Kind::Exit, &code.to_le_bytes())?;
+            pending
+        }
+        Err(e) => {
+            protocol::write_frame(w, Kind::Error, e.to_string().as_bytes())?;
+            return Err(e);
+        }
+    };
+
+    if let Some(p) = pending {
+        // If the client goes away before acknowledging, we keep the file so it can resume.
+        let (kind, _) = try_with!(
+            protocol::read_frame(r),
+            "client did not acknowledge transfer {}, keeping it for resume",
+            p.id
+        );
+        if kind != Kind::Ack {
+            bail!("expected ack for transfer {}, got {:?}", p.id, kind);
+        }
+        try_with!(
+            fs::remove_file(&p.path),
+            "cannot remove {}",
+            p.path.display()
+        );
+        info!("transfer {} completed", p.id);
+    }
+    Ok(())
+}
+
+pub fn run(opts: &AgentOptions) -> Result<()> {
+    match &opts.listen {
+        Listen::Stdio => {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            serve(&mut stdin.lock(), &mut stdout.lock(), None)
+        }
+        Listen::Tcp { addr, token } => {
+            let listener = try_with!(TcpListener::bind(addr), "cannot listen on {}", addr);
+            info!("listening on {}", addr);
+            let sessions = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                let stream = try_with!(stream, "cannot accept connection");
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|_| String::from("unknown"));
+                if sessions.load(Ordering::SeqCst) >= MAX_SESSIONS {
+                    warn!("too many sessions, dropping connection from {}", peer);
+                    continue;
+                }
+                info!("connection from {}", peer);
+                sessions.fetch_add(1, Ordering::SeqCst);
+                let sessions = Arc::clone(&sessions);
+                let token = token.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_tcp(stream, &token) {
+                        warn!("session with {} failed: {}", peer, e);
+                    }
+                    sessions.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Without timeouts, a client that never sends anything would keep its session forever.
+fn serve_tcp(stream: TcpStream, token: &[u8]) -> Result<()> {
+    try_with!(
+        stream.set_read_timeout(Some(IO_TIMEOUT)),
+        "cannot set read timeout"
+    );
+    try_with!(
+        stream.set_write_timeout(Some(IO_TIMEOUT)),
+        "cannot set write timeout"
+    );
+    let mut reader = try_with!(stream.try_clone(), "cannot clone tcp stream");
+    let mut writer = stream;
+    serve(&mut reader, &mut writer, Some(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_inspect_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(check_inspect_args(&args(&["1234"])).is_ok());
+        assert!(check_inspect_args(&args(&["1234", "42", "--sched", "-o", "json"])).is_ok());
+        assert!(check_inspect_args(&args(&[])).is_err());
+        assert!(check_inspect_args(&args(&["--sched"])).is_err());
+        assert!(check_inspect_args(&args(&["--core", "/etc/shadow"])).is_err());
+        assert!(check_inspect_args(&args(&["1234", "--all"])).is_err());
+        assert!(check_inspect_args(&args(&["1234", "--output"])).is_err());
+        assert!(check_inspect_args(&args(&["1234", "--output", "yaml"])).is_err());
+    }
+}
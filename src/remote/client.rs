@@ -0,0 +1,237 @@
+//! Workstation side of remote mode: forwards a vmsh command to an agent on the VM host.
+use log::info;
+use simple_error::{bail, require_with, try_with};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+use super::agent::ALLOWED_COMMANDS;
+use super::protocol::{self, Kind};
+use super::transfer::Download;
+use crate::result::Result;
+
+pub struct RemoteOptions {
+    /// `ssh://[user@]host[:port]`, `tcp://host:port` or a plain ssh destination
+    pub destination: String,
+    /// Path of vmsh on the remote host, used when connecting via ssh
+    pub remote_vmsh: String,
+    /// vmsh command line to execute on the remote host
+    pub command: Vec<String>,
+    /// Maximum rate in bytes per second at which files are transferred, 0 means unlimited
+    pub rate_limit: u64,
+    /// Continue an interrupted coredump transfer instead of taking a new coredump
+    pub resume: bool,
+    /// Token of the agent, required for tcp connections
+    pub token_file: Option<PathBuf>,
+}
+
+enum Connection {
+    Ssh(Child),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn open(opts: &RemoteOptions) -> Result<Connection> {
+        if let Some(addr) = opts.destination.strip_prefix("tcp://") {
+            let stream = try_with!(TcpStream::connect(addr), "cannot connect to {}", addr);
+            return Ok(Connection::Tcp(stream));
+        }
+        let dest = opts
+            .destination
+            .strip_prefix("ssh://")
+            .unwrap_or(&opts.destination);
+        let mut cmd = Command::new("ssh");
+        // ssh does not accept a port as part of the destination
+        let (dest, port) = ssh_destination(dest)?;
+        if let Some(port) = port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        cmd.arg(&dest)
+            .arg("--")
+            .arg(&opts.remote_vmsh)
+            .arg("agent")
+            .arg("--stdio")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        let child = try_with!(cmd.spawn(), "cannot spawn ssh to {}", dest);
+        Ok(Connection::Ssh(child))
+    }
+
+    fn streams(&mut self) -> Result<(Box<dyn Read + '_>, Box<dyn Write + '_>)> {
+        match self {
+            Connection::Tcp(stream) => {
+                let reader = try_with!(stream.try_clone(), "cannot clone tcp stream");
+                Ok((Box::new(reader), Box::new(stream)))
+            }
+            Connection::Ssh(child) => match (child.stdout.as_mut(), child.stdin.as_mut()) {
+                (Some(stdout), Some(stdin)) => Ok((Box::new(stdout), Box::new(stdin))),
+                _ => bail!("ssh was spawned without pipes"),
+            },
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Connection::Ssh(child) = self {
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Split `[user@]host[:port]` into the destination and port for ssh. Ipv6 addresses need brackets
+/// when a port is given, i.e. `[::1]:22`.
+fn ssh_destination(dest: &str) -> Result<(String, Option<u16>)> {
+    let (user, host) = match dest.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, dest),
+    };
+    let (host, port) = if let Ok(addr) = host.parse::<SocketAddr>() {
+        (addr.ip().to_string(), Some(addr.port()))
+    } else if host.parse::<IpAddr>().is_ok() {
+        (host.to_string(), None)
+    } else if let Some(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        (ip.to_string(), None)
+    } else {
+        match host.split_once(':') {
+            Some((name, port)) => {
+                let port = try_with!(port.parse::<u16>(), "invalid port in {}", dest);
+                (name.to_string(), Some(port))
+            }
+            None => (host.to_string(), None),
+        }
+    };
+    let dest = match user {
+        Some(user) => format!("{}@{}", user, host),
+        None => host,
+    };
+    Ok((dest, port))
+}
+
+/// Rewrite the command line for the agent. Returns the local file coredumps are written to.
+fn prepare_request(command: &[String]) -> Result<(Vec<String>, Option<PathBuf>)> {
+    let name = match command.first() {
+        Some(name) => name,
+        None => bail!("no command given"),
+    };
+    if !ALLOWED_COMMANDS.contains(&name.as_str()) {
+        bail!(
+            "command '{}' is not supported in remote mode (supported: {})",
+            name,
+            ALLOWED_COMMANDS.join(", ")
+        );
+    }
+    if name != "coredump" {
+        return Ok((command.to_vec(), None));
+    }
+    let pid = match command.get(1) {
+        Some(pid) => pid,
+        None => bail!("coredump requires a pid"),
+    };
+    let path = command
+        .get(2)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("core.{}", pid)));
+    Ok((vec![name.clone(), pid.clone()], Some(path)))
+}
+
+/// Execute the command on the remote host. Returns the exit code of the remote command.
+pub fn run(opts: &RemoteOptions) -> Result<i32> {
+    let (mut request, output_path) = prepare_request(&opts.command)?;
+    let mut download = match &output_path {
+        Some(path) if opts.resume => {
+            let (download, id) = Download::resume(path)?;
+            println!("Resume {} at offset {}", path.display(), download.offset());
+            request = vec![String::from("transfer"), id, download.offset().to_string()];
+            Some(download)
+        }
+        Some(path) => {
+            println!("Write {}", path.display());
+            Some(Download::create(path)?)
+        }
+        None if opts.resume => bail!("--resume is only supported for coredump"),
+        None => None,
+    };
+
+    // ssh authenticates its connections, the agent has no way to tell tcp clients apart
+    let token = if opts.destination.starts_with("tcp://") {
+        let path = require_with!(
+            opts.token_file.as_ref(),
+            "tcp connections to the agent need --token-file"
+        );
+        Some(protocol::read_token(path)?)
+    } else {
+        None
+    };
+
+    let mut conn = Connection::open(opts)?;
+    let (mut reader, mut writer) = conn.streams()?;
+    try_with!(
+        protocol::handshake(&mut reader, &mut writer),
+        "handshake with agent at {} failed",
+        opts.destination
+    );
+    if let Some(token) = &token {
+        protocol::write_frame(&mut writer, Kind::Auth, token)?;
+    }
+    if opts.rate_limit != 0 {
+        protocol::write_frame(&mut writer, Kind::Limit, &opts.rate_limit.to_le_bytes())?;
+    }
+    protocol::write_frame(&mut writer, Kind::Request, &protocol::encode_args(&request))?;
+    info!("sent request to {}", opts.destination);
+
+    let stderr = io::stderr();
+    loop {
+        let (kind, payload) = protocol::read_frame(&mut reader)?;
+        match kind {
+            Kind::Output => {
+                try_with!(stderr.lock().write_all(&payload), "cannot write output");
+            }
+            Kind::Transfer | Kind::Data => {
+                let download = match download.as_mut() {
+                    Some(d) => d,
+                    None => bail!("agent sent a file for a command without output file"),
+                };
+                if kind == Kind::Transfer {
+                    download.announce(&payload)?;
+                } else {
+                    download.write_chunk(&payload)?;
+                }
+            }
+            Kind::Exit if payload.len() == 4 => {
+                let code = i32::from_le_bytes(payload[..].try_into().unwrap());
+                if code == 0 {
+                    if let Some(d) = download.take() {
+                        d.finish()?;
+                        protocol::write_frame(&mut writer, Kind::Ack, &[])?;
+                    }
+                }
+                return Ok(code);
+            }
+            Kind::Error => bail!("agent: {}", String::from_utf8_lossy(&payload)),
+            _ => bail!("unexpected frame from agent: {:?}", kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_destination() {
+        let parse = |dest| ssh_destination(dest).unwrap();
+        assert_eq!(parse("host"), (String::from("host"), None));
+        assert_eq!(
+            parse("root@host:2222"),
+            (String::from("root@host"), Some(2222))
+        );
+        assert_eq!(parse("root@::1"), (String::from("root@::1"), None));
+        assert_eq!(parse("[fe80::1]:22"), (String::from("fe80::1"), Some(22)));
+        assert_eq!(parse("[::1]"), (String::from("::1"), None));
+        assert!(ssh_destination("host:ssh").is_err());
+    }
+}
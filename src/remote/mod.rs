@@ -0,0 +1,6 @@
+//! Remote mode: `vmsh remote` forwards commands to a `vmsh agent` running on the VM host, either
+//! spawned over ssh or listening on a tcp port.
+pub mod agent;
+pub mod client;
+pub mod protocol;
+pub mod transfer;
@@ -0,0 +1,175 @@
+//! Framing used between `vmsh remote` and `vmsh agent`.
+//!
+//! Every message is a frame of the form `[kind: u8][length: u32 le][payload]`. A session starts
+//! with both sides sending a `Hello` frame containing their protocol version, followed by an
+//! `Auth` frame on tcp connections, an optional `Limit` and a single `Request` from the client. The agent answers with any number of
+//! `Output` frames, possibly a file announced by `Transfer` and sent as `Data` frames, and
+//! finishes the session with either `Exit` or `Error`. Clients acknowledge received files with
+//! `Ack`.
+use num_derive::*;
+use num_traits as num;
+use simple_error::{bail, require_with, try_with};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::result::Result;
+
+/// Increment on every incompatible change of the framing or the request format.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Upper bound for a frame payload so that a corrupted length does not make us allocate gigabytes.
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Shorter tokens could be guessed by trying.
+const MIN_TOKEN_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum Kind {
+    /// payload: protocol version (u32 le)
+    Hello = 1,
+    /// payload: vmsh command line, NUL-separated
+    Request = 2,
+    /// payload: stdout/stderr of the command
+    Output = 3,
+    /// payload: offset (u64 le), crc32 (u32 le) and a chunk of the announced file
+    Data = 4,
+    /// payload: exit code (i32 le)
+    Exit = 5,
+    /// payload: error message of the agent
+    Error = 6,
+    /// payload: file size (u64 le) followed by the transfer id used to resume it
+    Transfer = 7,
+    /// payload: maximum transfer rate in bytes per second (u64 le)
+    Limit = 8,
+    /// payload: empty, the client has stored the transferred file
+    Ack = 9,
+    /// payload: token shared by client and agent, only sent on tcp connections
+    Auth = 10,
+}
+
+pub fn write_frame(w: &mut dyn Write, kind: Kind, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        bail!(
+            "frame of {} bytes exceeds maximum frame size",
+            payload.len()
+        );
+    }
+    let mut header = [0u8; 5];
+    header[0] = kind as u8;
+    header[1..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    try_with!(w.write_all(&header), "cannot write frame header");
+    try_with!(w.write_all(payload), "cannot write frame payload");
+    try_with!(w.flush(), "cannot flush frame");
+    Ok(())
+}
+
+pub fn read_frame(r: &mut dyn Read) -> Result<(Kind, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    try_with!(r.read_exact(&mut header), "cannot read frame header");
+    let kind: Option<Kind> = num::FromPrimitive::from_u8(header[0]);
+    let kind = require_with!(kind, "unknown frame kind {}", header[0]);
+    let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_SIZE {
+        bail!("frame of {} bytes exceeds maximum frame size", len);
+    }
+    let mut payload = vec![0u8; len];
+    try_with!(r.read_exact(&mut payload), "cannot read frame payload");
+    Ok((kind, payload))
+}
+
+/// Exchange `Hello` frames and ensure both sides speak the same protocol version.
+pub fn handshake(r: &mut dyn Read, w: &mut dyn Write) -> Result<()> {
+    write_frame(w, Kind::Hello, &PROTOCOL_VERSION.to_le_bytes())?;
+    let (kind, payload) = read_frame(r)?;
+    if kind != Kind::Hello || payload.len() != 4 {
+        bail!("expected hello frame, got {:?}", kind);
+    }
+    let version = u32::from_le_bytes(payload[..].try_into().unwrap());
+    if version != PROTOCOL_VERSION {
+        bail!(
+            "protocol version mismatch: we speak version {}, peer speaks version {}",
+            PROTOCOL_VERSION,
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Read the token that authenticates tcp sessions. Like ssh keys, the file must not be
+/// accessible by anyone but its owner.
+pub fn read_token(path: &Path) -> Result<Vec<u8>> {
+    let meta = try_with!(fs::metadata(path), "cannot stat {}", path.display());
+    if meta.permissions().mode() & 0o077 != 0 {
+        bail!(
+            "{} must not be accessible by group or others, run `chmod 600 {}`",
+            path.display(),
+            path.display()
+        );
+    }
+    let token = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    let token = token.trim();
+    if token.len() < MIN_TOKEN_LEN {
+        bail!(
+            "token in {} must be at least {} characters long",
+            path.display(),
+            MIN_TOKEN_LEN
+        );
+    }
+    Ok(token.as_bytes().to_vec())
+}
+
+/// Compare tokens in constant time, so that they cannot be guessed byte by byte.
+pub fn token_matches(expected: &[u8], got: &[u8]) -> bool {
+    expected.len() == got.len()
+        && expected
+            .iter()
+            .zip(got)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub fn encode_args(args: &[String]) -> Vec<u8> {
+    args.join("\0").into_bytes()
+}
+
+pub fn decode_args(payload: &[u8]) -> Result<Vec<String>> {
+    let s = try_with!(std::str::from_utf8(payload), "request is not valid utf-8");
+    Ok(s.split('\0').map(String::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, Kind::Output, b"hello").unwrap();
+        write_frame(&mut buf, Kind::Exit, &0i32.to_le_bytes()).unwrap();
+        let mut cursor = Cursor::new(buf);
+        let (kind, payload) = read_frame(&mut cursor).unwrap();
+        assert_eq!(kind, Kind::Output);
+        assert_eq!(payload, b"hello");
+        let (kind, payload) = read_frame(&mut cursor).unwrap();
+        assert_eq!(kind, Kind::Exit);
+        assert_eq!(payload, 0i32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches(b"0123456789abcdef", b"0123456789abcdef"));
+        assert!(!token_matches(b"0123456789abcdef", b"0123456789abcdeF"));
+        assert!(!token_matches(b"0123456789abcdef", b"0123456789abcde"));
+        assert!(!token_matches(b"0123456789abcdef", b""));
+    }
+
+    #[test]
+    fn test_args_roundtrip() {
+        let args = vec!["inspect".to_string(), "1234".to_string()];
+        assert_eq!(decode_args(&encode_args(&args)).unwrap(), args);
+    }
+}
@@ -0,0 +1,331 @@
+//! Chunked, checksummed and resumable file transfer used to stream coredumps off the VM host.
+//!
+//! The agent keeps the file in a spool directory until the client acknowledges that it received
+//! the complete file. If the connection drops, the client can request the remaining chunks
+//! starting at the last verified offset without taking another coredump.
+use simple_error::{bail, try_with};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, Kind};
+use crate::gc;
+use crate::result::Result;
+use crate::sha256;
+
+/// Files are sent in chunks of this size. Resumed transfers always start at a chunk boundary.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Size of offset and checksum in front of each `Data` frame.
+const CHUNK_HEADER_SIZE: usize = 8 + 4;
+
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+/// CRC-32 (IEEE 802.3) as used by zlib/gzip.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (CRC32_POLY & mask);
+        }
+    }
+    !crc
+}
+
+/// Limits throughput to a fixed number of bytes per second. 0 means unlimited.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    start: Instant,
+    sent: u64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Account for `bytes` and sleep if we are ahead of the configured rate.
+    pub fn throttle(&mut self, bytes: usize) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        self.sent += bytes as u64;
+        let due = Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Directory where the agent keeps files until the client acknowledged them.
+pub fn spool_dir() -> Result<PathBuf> {
+    gc::private_temp_dir("vmsh-transfers")
+}
+
+/// Id for a new transfer. Ids are random, so that clients cannot guess the transfers of others.
+pub fn new_id(name: &str) -> Result<String> {
+    let mut random = [0u8; 16];
+    let mut urandom = try_with!(File::open("/dev/urandom"), "cannot open /dev/urandom");
+    try_with!(urandom.read_exact(&mut random), "cannot read /dev/urandom");
+    Ok(format!("{}-{}", name, sha256::to_hex(&random)))
+}
+
+/// Resolve a transfer id sent by a client to a file in the spool directory.
+pub fn spool_path(id: &str) -> Result<PathBuf> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        || id.starts_with('.')
+    {
+        bail!("invalid transfer id '{}'", id);
+    }
+    Ok(spool_dir()?.join(id))
+}
+
+/// Announce the file with `Kind::Transfer` and send it from `offset` on.
+pub fn send(
+    w: &mut dyn Write,
+    id: &str,
+    path: &Path,
+    offset: u64,
+    limiter: &mut RateLimiter,
+) -> Result<()> {
+    let mut file = try_with!(File::open(path), "cannot open {}", path.display());
+    let size = try_with!(file.metadata(), "cannot stat {}", path.display()).len();
+    if offset > size || offset % CHUNK_SIZE as u64 != 0 {
+        bail!("cannot resume {} at offset {}", id, offset);
+    }
+    let mut announce = size.to_le_bytes().to_vec();
+    announce.extend_from_slice(id.as_bytes());
+    protocol::write_frame(w, Kind::Transfer, &announce)?;
+
+    try_with!(
+        file.seek(SeekFrom::Start(offset)),
+        "cannot seek in {}",
+        path.display()
+    );
+    let mut pos = offset;
+    let mut buf = vec![0u8; CHUNK_HEADER_SIZE + CHUNK_SIZE];
+    while pos < size {
+        let len = std::cmp::min(CHUNK_SIZE as u64, size - pos) as usize;
+        let data = &mut buf[CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + len];
+        try_with!(file.read_exact(data), "cannot read {}", path.display());
+        let checksum = crc32(data);
+        buf[..8].copy_from_slice(&pos.to_le_bytes());
+        buf[8..CHUNK_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        protocol::write_frame(w, Kind::Data, &buf[..CHUNK_HEADER_SIZE + len])?;
+        limiter.throttle(len);
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+/// Client side state of a transfer, kept next to the partial download.
+pub struct Download {
+    path: PathBuf,
+    part_path: PathBuf,
+    state_path: PathBuf,
+    file: File,
+    id: Option<String>,
+    size: u64,
+    pos: u64,
+}
+
+impl Download {
+    fn paths(path: &Path) -> (PathBuf, PathBuf) {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        let mut state = path.as_os_str().to_owned();
+        state.push(".transfer");
+        (PathBuf::from(part), PathBuf::from(state))
+    }
+
+    /// Start a new download to `path`.
+    pub fn create(path: &Path) -> Result<Download> {
+        let (part_path, state_path) = Self::paths(path);
+        let file = try_with!(
+            File::create(&part_path),
+            "cannot create {}",
+            part_path.display()
+        );
+        Ok(Download {
+            path: path.to_path_buf(),
+            part_path,
+            state_path,
+            file,
+            id: None,
+            size: 0,
+            pos: 0,
+        })
+    }
+
+    /// Continue an interrupted download to `path`. Returns the download and its transfer id.
+    pub fn resume(path: &Path) -> Result<(Download, String)> {
+        let (part_path, state_path) = Self::paths(path);
+        let state = try_with!(
+            fs::read_to_string(&state_path),
+            "no interrupted transfer found for {} ({})",
+            path.display(),
+            state_path.display()
+        );
+        let mut lines = state.lines();
+        let (id, size) = match (lines.next(), lines.next().map(|s| s.parse::<u64>())) {
+            (Some(id), Some(Ok(size))) => (id.to_string(), size),
+            _ => bail!("{} is corrupted", state_path.display()),
+        };
+        let file = try_with!(
+            OpenOptions::new().write(true).open(&part_path),
+            "cannot open {}",
+            part_path.display()
+        );
+        let len = try_with!(file.metadata(), "cannot stat {}", part_path.display()).len();
+        // the last chunk might have been written partially
+        let pos = len - len % CHUNK_SIZE as u64;
+        try_with!(file.set_len(pos), "cannot truncate {}", part_path.display());
+        let download = Download {
+            path: path.to_path_buf(),
+            part_path,
+            state_path,
+            file,
+            id: Some(id.clone()),
+            size,
+            pos,
+        };
+        Ok((download, id))
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.pos
+    }
+
+    /// Handle a `Kind::Transfer` frame.
+    pub fn announce(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() <= 8 {
+            bail!("invalid transfer announcement");
+        }
+        let size = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        let id = String::from_utf8_lossy(&payload[8..]).into_owned();
+        if let Some(old) = &self.id {
+            if *old != id || self.size != size {
+                bail!("agent resumed transfer {} instead of {}", id, old);
+            }
+        }
+        try_with!(
+            fs::write(&self.state_path, format!("{}\n{}\n", id, size)),
+            "cannot write {}",
+            self.state_path.display()
+        );
+        self.id = Some(id);
+        self.size = size;
+        Ok(())
+    }
+
+    /// Handle a `Kind::Data` frame.
+    pub fn write_chunk(&mut self, payload: &[u8]) -> Result<()> {
+        if self.id.is_none() || payload.len() < CHUNK_HEADER_SIZE {
+            bail!("received unexpected data frame");
+        }
+        let offset = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        let checksum = u32::from_le_bytes(payload[8..CHUNK_HEADER_SIZE].try_into().unwrap());
+        let data = &payload[CHUNK_HEADER_SIZE..];
+        if offset != self.pos {
+            bail!("expected chunk at offset {}, got {}", self.pos, offset);
+        }
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= self.size => {}
+            _ => bail!(
+                "chunk of {} bytes at offset {} exceeds the announced size of {} bytes",
+                data.len(),
+                offset,
+                self.size
+            ),
+        }
+        if crc32(data) != checksum {
+            bail!(
+                "checksum mismatch for chunk at offset {}, rerun with --resume",
+                offset
+            );
+        }
+        try_with!(
+            self.file.write_all(data),
+            "cannot write {}",
+            self.part_path.display()
+        );
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Move the completed download into place.
+    pub fn finish(self) -> Result<()> {
+        if self.id.is_none() || self.pos != self.size {
+            bail!(
+                "transfer incomplete: received {} of {} bytes",
+                self.pos,
+                self.size
+            );
+        }
+        try_with!(
+            self.file.sync_all(),
+            "cannot sync {}",
+            self.part_path.display()
+        );
+        try_with!(
+            fs::rename(&self.part_path, &self.path),
+            "cannot rename {} to {}",
+            self.part_path.display(),
+            self.path.display()
+        );
+        let _ = fs::remove_file(&self.state_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_write_chunk_past_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut download = Download::create(&dir.path().join("core")).unwrap();
+        let mut announcement = 4u64.to_le_bytes().to_vec();
+        announcement.extend_from_slice(b"1234-core");
+        download.announce(&announcement).unwrap();
+        let chunk = |offset: u64, data: &[u8]| {
+            let mut payload = offset.to_le_bytes().to_vec();
+            payload.extend_from_slice(&crc32(data).to_le_bytes());
+            payload.extend_from_slice(data);
+            payload
+        };
+        assert!(download.write_chunk(&chunk(0, b"12345")).is_err());
+        download.write_chunk(&chunk(0, b"123")).unwrap();
+        assert!(download.write_chunk(&chunk(3, b"45")).is_err());
+        download.write_chunk(&chunk(3, b"4")).unwrap();
+        download.finish().unwrap();
+        assert_eq!(fs::read(dir.path().join("core")).unwrap(), b"1234");
+    }
+
+    #[test]
+    fn test_spool_path() {
+        assert!(spool_path("1234-1626780000.core").is_ok());
+        assert!(spool_path("../etc/passwd").is_err());
+        assert!(spool_path("").is_err());
+    }
+}
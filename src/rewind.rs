@@ -0,0 +1,636 @@
+//! Record periodic checkpoints of a running guest and look at its state shortly before a crash,
+//! see `vmsh record` and `vmsh rewind-inspect`.
+//!
+//! `vmsh record` enables dirty page tracking on all memslots and copies guest memory once to
+//! `memory` in the recording directory, the memslots it covers are listed in `memslots`. Every
+//! interval it briefly stops the guest and writes a checkpoint with the registers of all vcpus and
+//! the pages written since the previous checkpoint. Checkpoints are kept in a ring of
+//! `checkpoint.<seq % n>` files. Before a checkpoint is overwritten its pages are merged into
+//! `memory`, so `memory` plus all remaining checkpoints up to `seq` is the guest memory at
+//! checkpoint `seq`.
+//!
+//! A checkpoint starts with `MAGIC`, the version of `format::REWIND`, `seq: u64`, the time since
+//! the start of the recording in milliseconds (u64), the number of vcpus (u32), the page size
+//! (u32) and the number of pages (u64). It is followed by `cpu::Regs` of each vcpu and the pages,
+//! each as guest physical address (u64) and content. Recordings can only be inspected on the
+//! architecture they were made on.
+//!
+//! This is no replay: the guest state is only known at checkpoints. Memory that the hypervisor
+//! writes itself, e.g. virtio buffers filled by its device models, is not tracked by kvm and
+//! shows up with the content it had when it was last written by the guest. Memslots that the
+//! hypervisor already tracks, i.e. during live migration, are not recorded. The recording needs
+//! as much disk space as guest memory plus the dirty pages of the ring.
+use log::{info, warn};
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::coredump::any_as_bytes;
+use crate::cpu::Regs;
+use crate::format::REWIND;
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::memslots::MemSlot;
+use crate::page_math::page_size;
+use crate::poke::hexdump_line;
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+use crate::step::format_regs;
+
+pub struct RecordOptions {
+    pub pid: Pid,
+    /// Directory for the recording, must not exist yet.
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// Number of checkpoints to keep.
+    pub checkpoints: u64,
+}
+
+pub struct RewindInspectOptions {
+    pub dir: PathBuf,
+    /// Show this checkpoint instead of listing all of them. Defaults to the latest one if `read`
+    /// is given.
+    pub checkpoint: Option<u64>,
+    /// Hexdump guest physical memory at this address and length as it was at the checkpoint.
+    pub read: Option<(usize, usize)>,
+}
+
+const MAGIC: &[u8; 8] = b"VMSHCKPT";
+/// Size of the fixed part of a checkpoint.
+const HEADER_SIZE: usize = 8 + 4 + 8 + 8 + 4 + 4 + 8;
+/// Size of reads of guest memory for the initial copy.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A memslot as stored in `memory`.
+#[derive(Debug, PartialEq)]
+struct RecordedSlot {
+    phys_addr: usize,
+    size: usize,
+    /// Position of the memslot in `memory`.
+    offset: u64,
+}
+
+struct CheckpointHeader {
+    seq: u64,
+    time: Duration,
+    regs: Vec<Regs>,
+    page_size: usize,
+    npages: u64,
+}
+
+fn checkpoint_path(dir: &Path, seq: u64, ring: u64) -> PathBuf {
+    dir.join(format!("checkpoint.{}", seq % ring))
+}
+
+fn format_slots(slots: &[RecordedSlot]) -> String {
+    let mut content = format!("{}\n", REWIND.text_header());
+    for s in slots {
+        content.push_str(&format!("{:x} {:x} {:x}\n", s.phys_addr, s.size, s.offset));
+    }
+    content
+}
+
+fn parse_slots(content: &str) -> Result<Vec<RecordedSlot>> {
+    let mut lines = content.lines();
+    let version = require_with!(
+        REWIND.header_version(lines.next().unwrap_or_default()),
+        "not a vmsh recording"
+    );
+    REWIND.check(version)?;
+    lines
+        .map(|line| {
+            let fields = line
+                .split(' ')
+                .map(|f| u64::from_str_radix(f, 16).ok())
+                .collect::<Option<Vec<_>>>();
+            match fields.as_deref() {
+                Some(&[phys_addr, size, offset]) => Ok(RecordedSlot {
+                    phys_addr: phys_addr as usize,
+                    size: size as usize,
+                    offset,
+                }),
+                _ => bail!("invalid memslot line '{}'", line),
+            }
+        })
+        .collect()
+}
+
+/// Position of the guest physical address `phys` in `memory` and the number of bytes that
+/// follow it in the same memslot.
+fn file_offset(slots: &[RecordedSlot], phys: usize) -> Option<(u64, usize)> {
+    slots
+        .iter()
+        .find(|s| s.phys_addr <= phys && phys < s.phys_addr + s.size)
+        .map(|s| {
+            let off = phys - s.phys_addr;
+            (s.offset + off as u64, s.size - off)
+        })
+}
+
+/// Copy the part of `page` at `page_phys` that overlaps `buf` at `addr`.
+fn overlay(buf: &mut [u8], addr: usize, page_phys: usize, page: &[u8]) {
+    let start = addr.max(page_phys);
+    let end = (addr + buf.len()).min(page_phys + page.len());
+    if start < end {
+        buf[start - addr..end - addr].copy_from_slice(&page[start - page_phys..end - page_phys]);
+    }
+}
+
+fn write_checkpoint(
+    out: &mut dyn Write,
+    seq: u64,
+    time: Duration,
+    regs: &[Regs],
+    pages: &[(usize, Vec<u8>)],
+) -> Result<()> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&REWIND.current.to_le_bytes());
+    header.extend_from_slice(&seq.to_le_bytes());
+    header.extend_from_slice(&(time.as_millis() as u64).to_le_bytes());
+    header.extend_from_slice(&(regs.len() as u32).to_le_bytes());
+    header.extend_from_slice(&(page_size() as u32).to_le_bytes());
+    header.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+    try_with!(out.write_all(&header), "cannot write checkpoint");
+    for r in regs {
+        try_with!(
+            out.write_all(unsafe { any_as_bytes(r) }),
+            "cannot write checkpoint"
+        );
+    }
+    for (phys, content) in pages {
+        try_with!(
+            out.write_all(&(*phys as u64).to_le_bytes())
+                .and_then(|_| out.write_all(content)),
+            "cannot write checkpoint"
+        );
+    }
+    Ok(())
+}
+
+fn read_checkpoint_header(input: &mut dyn Read) -> Result<CheckpointHeader> {
+    let mut header = [0u8; HEADER_SIZE];
+    try_with!(input.read_exact(&mut header), "cannot read checkpoint");
+    if &header[..8] != MAGIC {
+        bail!("not a vmsh checkpoint");
+    }
+    REWIND.check(u32::from_le_bytes(header[8..12].try_into().unwrap()))?;
+    let nvcpus = u32::from_le_bytes(header[28..32].try_into().unwrap());
+    let mut regs = Vec::with_capacity(nvcpus as usize);
+    let mut buf = [0u8; size_of::<Regs>()];
+    for _ in 0..nvcpus {
+        try_with!(input.read_exact(&mut buf), "cannot read checkpoint");
+        // safe, Regs is plain old data
+        regs.push(unsafe { ptr::read_unaligned(buf.as_ptr() as *const Regs) });
+    }
+    Ok(CheckpointHeader {
+        seq: u64::from_le_bytes(header[12..20].try_into().unwrap()),
+        time: Duration::from_millis(u64::from_le_bytes(header[20..28].try_into().unwrap())),
+        regs,
+        page_size: u32::from_le_bytes(header[32..36].try_into().unwrap()) as usize,
+        npages: u64::from_le_bytes(header[36..44].try_into().unwrap()),
+    })
+}
+
+/// Call `f` with the address and content of each page that follows `header` in `input`.
+fn read_checkpoint_pages(
+    input: &mut dyn Read,
+    header: &CheckpointHeader,
+    mut f: impl FnMut(usize, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0u8; 8 + header.page_size];
+    for _ in 0..header.npages {
+        try_with!(input.read_exact(&mut buf), "cannot read checkpoint");
+        let phys = u64::from_le_bytes(buf[..8].try_into().unwrap()) as usize;
+        f(phys, &buf[8..])?;
+    }
+    Ok(())
+}
+
+/// Headers of all checkpoints in `dir`, oldest first.
+fn list_checkpoints(dir: &Path) -> Result<Vec<(PathBuf, CheckpointHeader)>> {
+    let entries = try_with!(fs::read_dir(dir), "cannot read {}", dir.display());
+    let mut checkpoints = vec![];
+    for entry in entries {
+        let path = try_with!(entry, "cannot read {}", dir.display()).path();
+        let is_checkpoint = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("checkpoint."))
+            .map_or(false, |n| n.parse::<u64>().is_ok());
+        if !is_checkpoint {
+            continue;
+        }
+        let file = try_with!(File::open(&path), "cannot open {}", path.display());
+        let header = try_with!(
+            read_checkpoint_header(&mut BufReader::new(file)),
+            "cannot read {}",
+            path.display()
+        );
+        checkpoints.push((path, header));
+    }
+    checkpoints.sort_by_key(|(_, h)| h.seq);
+    Ok(checkpoints)
+}
+
+fn read_hv(pid: Pid, addr: usize, buf: &mut [u8]) -> Result<()> {
+    let len = buf.len();
+    try_with!(
+        process_vm_readv(
+            pid,
+            &[IoVec::from_mut_slice(buf)],
+            &[RemoteIoVec { base: addr, len }]
+        ),
+        "cannot read hypervisor memory"
+    );
+    Ok(())
+}
+
+/// Copy all memslots to `memory` while the guest keeps running, changes during the copy end up
+/// in the first checkpoint.
+fn copy_memory(
+    vm: &Hypervisor,
+    dir: &Path,
+    slots: &[MemSlot],
+    cancel: &Cancellation,
+) -> Result<Vec<RecordedSlot>> {
+    let path = dir.join("memory");
+    let file = try_with!(File::create(&path), "cannot create {}", path.display());
+    let mut out = BufWriter::new(file);
+    let mut recorded = vec![];
+    let mut offset = 0;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    for slot in slots {
+        let mut pos = 0;
+        while pos < slot.size() {
+            cancel.check()?;
+            let n = std::cmp::min(buf.len(), slot.size() - pos);
+            read_hv(vm.pid, slot.start() + pos, &mut buf[..n])?;
+            try_with!(out.write_all(&buf[..n]), "cannot write {}", path.display());
+            pos += n;
+        }
+        recorded.push(RecordedSlot {
+            phys_addr: slot.physical_start(),
+            size: slot.size(),
+            offset,
+        });
+        offset += slot.size() as u64;
+    }
+    try_with!(out.flush(), "cannot write {}", path.display());
+
+    let index = dir.join("memslots");
+    try_with!(
+        fs::write(&index, format_slots(&recorded)),
+        "cannot write {}",
+        index.display()
+    );
+    Ok(recorded)
+}
+
+/// Registers of all vcpus and the pages dirtied since the last call. Stops the guest meanwhile.
+fn take_checkpoint(
+    vm: &Hypervisor,
+    slots: &[MemSlot],
+) -> Result<(Vec<Regs>, Vec<(usize, Vec<u8>)>)> {
+    let _stopped = vm.stop_guard()?;
+    let regs = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| vm.get_regs(vcpu))
+        .collect::<Result<Vec<_>>>()?;
+    let mut pages = vec![];
+    for slot in slots {
+        let bitmap = vm.get_dirty_log(slot)?;
+        for range in bitmap.dirty_ranges() {
+            let mut buf = vec![0u8; range.len()];
+            read_hv(vm.pid, bitmap.host_addr(range.start), &mut buf)?;
+            for (i, page) in buf.chunks(page_size()).enumerate() {
+                pages.push((range.start + i * page_size(), page.to_vec()));
+            }
+        }
+    }
+    Ok((regs, pages))
+}
+
+/// Write the pages of the checkpoint at `path` into `memory` before it is overwritten.
+fn merge_checkpoint(path: &Path, memory: &mut File, slots: &[RecordedSlot]) -> Result<()> {
+    let file = try_with!(File::open(path), "cannot open {}", path.display());
+    let mut input = BufReader::new(file);
+    let header = read_checkpoint_header(&mut input)?;
+    read_checkpoint_pages(&mut input, &header, |phys, page| {
+        let (offset, _) = require_with!(
+            file_offset(slots, phys),
+            "page {:#x} is outside of the recorded memslots",
+            phys
+        );
+        try_with!(
+            memory
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| memory.write_all(page)),
+            "cannot update recorded memory"
+        );
+        Ok(())
+    })
+}
+
+fn record_checkpoints(
+    vm: &Hypervisor,
+    opts: &RecordOptions,
+    slots: &[MemSlot],
+    cancel: &Cancellation,
+) -> Result<()> {
+    let recorded = copy_memory(vm, &opts.dir, slots, cancel)?;
+    let memory_path = opts.dir.join("memory");
+    let mut memory = try_with!(
+        OpenOptions::new().write(true).open(&memory_path),
+        "cannot open {}",
+        memory_path.display()
+    );
+    info!(
+        "recording a checkpoint every {:?}, keeping the last {}",
+        opts.interval, opts.checkpoints
+    );
+    let start = Instant::now();
+    let mut seq = 0;
+    while !cancel.is_cancelled() {
+        thread::sleep(opts.interval);
+        let (regs, pages) = take_checkpoint(vm, slots)?;
+        let path = checkpoint_path(&opts.dir, seq, opts.checkpoints);
+        if seq >= opts.checkpoints {
+            merge_checkpoint(&path, &mut memory, &recorded)?;
+        }
+        let tmp = opts.dir.join("checkpoint.tmp");
+        let file = try_with!(File::create(&tmp), "cannot create {}", tmp.display());
+        let mut out = BufWriter::new(file);
+        write_checkpoint(&mut out, seq, start.elapsed(), &regs, &pages)?;
+        try_with!(out.flush(), "cannot write {}", tmp.display());
+        try_with!(
+            fs::rename(&tmp, &path),
+            "cannot rename {} to {}",
+            tmp.display(),
+            path.display()
+        );
+        seq += 1;
+    }
+    info!("recorded {} checkpoints", seq);
+    Ok(())
+}
+
+/// Records until Ctrl-C or until the vm goes away, the checkpoints recorded so far stay usable in
+/// both cases.
+pub fn record(opts: &RecordOptions) -> Result<()> {
+    if opts.checkpoints == 0 {
+        bail!("at least one checkpoint must be kept");
+    }
+    try_with!(
+        fs::create_dir(&opts.dir),
+        "cannot create {}",
+        opts.dir.display()
+    );
+    let cancel = Cancellation::setup()?;
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+
+    let mut slots = vec![];
+    {
+        let _stopped = vm.stop_guard()?;
+        for slot in vm.get_memslots()? {
+            if let Err(e) = vm.enable_dirty_log(&slot) {
+                warn!("not recording memslot {}: {}", slot, e);
+                continue;
+            }
+            slots.push(slot);
+        }
+        // start with a clean log, the content is copied afterwards
+        for slot in &slots {
+            vm.get_dirty_log(slot)?;
+        }
+    }
+    if slots.is_empty() {
+        bail!("no memslot can be recorded");
+    }
+
+    let res = record_checkpoints(&vm, opts, &slots, &cancel);
+
+    let disabled = vm.stop_guard().and_then(|_stopped| {
+        for slot in &slots {
+            vm.disable_dirty_log(slot)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = disabled {
+        warn!("cannot disable dirty logging: {}", e);
+    }
+    try_with!(res, "recording stopped");
+    Ok(())
+}
+
+fn print_checkpoints(checkpoints: &[(PathBuf, CheckpointHeader)]) {
+    println!("{:>8} {:>10} {:>11}  rip", "seq", "time", "dirty pages");
+    for (_, h) in checkpoints {
+        let rips = h
+            .regs
+            .iter()
+            .map(|r| format!("{:#x}", r.rip))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{:>8} {:>9.3}s {:>11}  {}",
+            h.seq,
+            h.time.as_secs_f64(),
+            h.npages,
+            rips
+        );
+    }
+}
+
+/// Guest physical memory at `addr` as it was at the checkpoint `seq`.
+fn read_memory(
+    dir: &Path,
+    checkpoints: &[(PathBuf, CheckpointHeader)],
+    seq: u64,
+    addr: usize,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let index = dir.join("memslots");
+    let content = try_with!(
+        fs::read_to_string(&index),
+        "cannot read {}",
+        index.display()
+    );
+    let slots = try_with!(parse_slots(&content), "cannot read {}", index.display());
+    let memory_path = dir.join("memory");
+    let mut memory = try_with!(
+        File::open(&memory_path),
+        "cannot open {}",
+        memory_path.display()
+    );
+
+    let mut buf = vec![0u8; len];
+    let mut pos = 0;
+    while pos < len {
+        let (offset, avail) = require_with!(
+            file_offset(&slots, addr + pos),
+            "{:#x} is not in a recorded memslot",
+            addr + pos
+        );
+        let n = std::cmp::min(avail, len - pos);
+        try_with!(
+            memory
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| memory.read_exact(&mut buf[pos..pos + n])),
+            "cannot read {}",
+            memory_path.display()
+        );
+        pos += n;
+    }
+
+    for (path, _) in checkpoints.iter().filter(|(_, h)| h.seq <= seq) {
+        let file = try_with!(File::open(path), "cannot open {}", path.display());
+        let mut input = BufReader::new(file);
+        let header = read_checkpoint_header(&mut input)?;
+        read_checkpoint_pages(&mut input, &header, |phys, page| {
+            overlay(&mut buf, addr, phys, page);
+            Ok(())
+        })?;
+    }
+    Ok(buf)
+}
+
+pub fn rewind_inspect(opts: &RewindInspectOptions) -> Result<()> {
+    let checkpoints = list_checkpoints(&opts.dir)?;
+    if checkpoints.is_empty() {
+        bail!("{} contains no checkpoints", opts.dir.display());
+    }
+    let seq = match (opts.checkpoint, opts.read) {
+        (None, None) => {
+            print_checkpoints(&checkpoints);
+            return Ok(());
+        }
+        (Some(seq), _) => seq,
+        (None, Some(_)) => checkpoints[checkpoints.len() - 1].1.seq,
+    };
+    let (_, header) = require_with!(
+        checkpoints.iter().find(|(_, h)| h.seq == seq),
+        "checkpoint {} is not in the recording, it has {} to {}",
+        seq,
+        checkpoints[0].1.seq,
+        checkpoints[checkpoints.len() - 1].1.seq
+    );
+
+    match opts.read {
+        Some((addr, len)) => {
+            require_with!(
+                addr.checked_add(len),
+                "address range {:#x}+{:#x} overflows",
+                addr,
+                len
+            );
+            let buf = read_memory(&opts.dir, &checkpoints, seq, addr, len)?;
+            for (i, line) in buf.chunks(16).enumerate() {
+                println!("{}", hexdump_line(addr + i * 16, line));
+            }
+        }
+        None => {
+            println!(
+                "checkpoint {} at {:.3}s, {} dirty pages",
+                header.seq,
+                header.time.as_secs_f64(),
+                header.npages
+            );
+            for (i, regs) in header.regs.iter().enumerate() {
+                println!("vcpu {}:\n{}", i, format_regs(regs));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_checkpoint() {
+        let mut regs: Regs = unsafe { std::mem::zeroed() };
+        regs.rip = 0xffff_ffff_8100_0000;
+        let pages = vec![
+            (0x1000, vec![1u8; page_size()]),
+            (0x5000, vec![2u8; page_size()]),
+        ];
+        let mut out = vec![];
+        write_checkpoint(&mut out, 7, Duration::from_millis(1500), &[regs], &pages).unwrap();
+
+        let mut input = Cursor::new(out);
+        let header = read_checkpoint_header(&mut input).unwrap();
+        assert_eq!(header.seq, 7);
+        assert_eq!(header.time, Duration::from_millis(1500));
+        assert_eq!(header.regs.len(), 1);
+        assert_eq!(header.regs[0].rip, regs.rip);
+        assert_eq!(header.npages, 2);
+        let mut read = vec![];
+        read_checkpoint_pages(&mut input, &header, |phys, page| {
+            read.push((phys, page.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read, pages);
+
+        assert!(read_checkpoint_header(&mut Cursor::new(b"VMSHSNAP".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_slots() {
+        let slots = vec![
+            RecordedSlot {
+                phys_addr: 0,
+                size: 0xa0000,
+                offset: 0,
+            },
+            RecordedSlot {
+                phys_addr: 0x100000,
+                size: 0x100000,
+                offset: 0xa0000,
+            },
+        ];
+        let content = format_slots(&slots);
+        assert!(content.starts_with("vmsh-rewind 1\n"));
+        assert_eq!(parse_slots(&content).unwrap(), slots);
+        assert!(parse_slots("vmsh-rewind 1\n1000 zz 0\n").is_err());
+        assert!(parse_slots("vmsh-dedup 1\n").is_err());
+
+        assert_eq!(file_offset(&slots, 0x1000), Some((0x1000, 0x9f000)));
+        assert_eq!(file_offset(&slots, 0x100010), Some((0xa0010, 0xffff0)));
+        assert_eq!(file_offset(&slots, 0xa0000), None);
+    }
+
+    #[test]
+    fn test_overlay() {
+        let mut buf = vec![0u8; 8];
+        overlay(&mut buf, 0x1004, 0x1000, &[1; 6]);
+        assert_eq!(buf, [1, 1, 0, 0, 0, 0, 0, 0]);
+        overlay(&mut buf, 0x1004, 0x100a, &[2; 4]);
+        assert_eq!(buf, [1, 1, 0, 0, 0, 0, 2, 2]);
+        overlay(&mut buf, 0x1004, 0x2000, &[3; 4]);
+        assert_eq!(buf, [1, 1, 0, 0, 0, 0, 2, 2]);
+    }
+
+    #[test]
+    fn test_checkpoint_path() {
+        let dir = Path::new("/rec");
+        assert_eq!(checkpoint_path(dir, 3, 10), Path::new("/rec/checkpoint.3"));
+        assert_eq!(checkpoint_path(dir, 13, 10), Path::new("/rec/checkpoint.3"));
+    }
+}
@@ -0,0 +1,514 @@
+//! IPv4 routes and ARP entries of a guest read from its kernel, see `vmsh route` and `vmsh arp`.
+//! Together with the sockets of `vmsh ps` this tells why a guest cannot reach the network when
+//! nobody can log in to run `ip route` or `ip neigh`.
+//!
+//! Routes are read from the fib tries of all routing tables of `init_net`, neighbours from the
+//! hash table of `arp_tbl`. Both symbols are exported. The profile (see `vmi`) needs:
+//!
+//! ```text
+//! net.ipv4.fib_table_hash 0x2c8
+//! # 256 with CONFIG_IP_MULTIPLE_TABLES (the default), 2 without
+//! fib_table_hashsz 256
+//! fib_table.tb_hlist 0x0
+//! fib_table.tb_id 0x10
+//! fib_table.tb_data 0x20
+//! key_vector.key 0x0
+//! key_vector.bits 0x5
+//! key_vector.leaf 0x8
+//! fib_alias.fa_list 0x0
+//! fib_alias.fa_info 0x10
+//! fib_alias.fa_type 0x19
+//! fib_alias.fa_slen 0x1b
+//! fib_alias.tb_id 0x1c
+//! fib_info.fib_priority 0x38
+//! fib_info.fib_nhs 0x70
+//! fib_info.nh 0x88
+//! fib_info.fib_nh 0x98
+//! struct_fib_nh_size 0x70
+//! fib_nh.nh_common.nhc_dev 0x0
+//! fib_nh.nh_common.nhc_gw_family 0x1e
+//! fib_nh.nh_common.nhc_gw 0x20
+//! net_device.name 0x0
+//! net_device.addr_len 0x26f
+//! neigh_table.nht 0x1e0
+//! neigh_hash_table.hash_buckets 0x0
+//! neigh_hash_table.hash_shift 0x8
+//! neighbour.nud_state 0x3a
+//! neighbour.ha 0x48
+//! neighbour.dev 0x70
+//! neighbour.primary_key 0xa0
+//! # linux < 6.13, buckets are singly linked lists of neighbours
+//! neighbour.next 0x0
+//! # linux >= 6.13 instead, buckets are hlists of neighbour.hash
+//! neighbour.hash 0x8
+//! ```
+use log::warn;
+use simple_error::{bail, try_with};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::result::Result;
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct RouteOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+    /// Also show the local table and other tables than main
+    pub all_tables: bool,
+}
+
+pub struct ArpOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+}
+
+const RT_TABLE_MAIN: u32 = 254;
+const RT_TABLE_LOCAL: u32 = 255;
+/// Bits of an IPv4 key in the fib trie
+const KEYLENGTH: u8 = 32;
+const IFNAMSIZ: usize = 16;
+/// Length of neighbour.ha, which is aligned to longs
+const MAX_ADDR_LEN: usize = 32;
+const AF_INET: u8 = 2;
+/// Stop at lists that loop, for example because of a wrong offset.
+const MAX_ENTRIES: usize = 1 << 20;
+
+pub struct Route {
+    pub table: u32,
+    pub dst: Ipv4Addr,
+    pub prefix_len: u8,
+    pub route_type: u8,
+    pub priority: u32,
+    /// Empty for routes without a device, i.e. unreachable ones
+    pub nexthops: Vec<Nexthop>,
+    /// Routes with a nexthop object (`ip nexthop`) instead of inline nexthops
+    pub nexthop_object: bool,
+}
+
+pub struct Nexthop {
+    pub gateway: Option<Ipv4Addr>,
+    pub dev: String,
+}
+
+pub struct Neighbour {
+    pub addr: Ipv4Addr,
+    pub hwaddr: Vec<u8>,
+    pub dev: String,
+    pub nud_state: u8,
+}
+
+/// Names of route types as `ip route` shows them.
+pub fn route_type_name(route_type: u8) -> &'static str {
+    match route_type {
+        1 => "unicast",
+        2 => "local",
+        3 => "broadcast",
+        4 => "anycast",
+        5 => "multicast",
+        6 => "blackhole",
+        7 => "unreachable",
+        8 => "prohibit",
+        9 => "throw",
+        10 => "nat",
+        _ => "unknown",
+    }
+}
+
+/// Names of neighbour states as `ip neigh` shows them, several if more than one bit is set.
+pub fn nud_state_name(state: u8) -> String {
+    const NAMES: [&str; 8] = [
+        "INCOMPLETE",
+        "REACHABLE",
+        "STALE",
+        "DELAY",
+        "PROBE",
+        "FAILED",
+        "NOARP",
+        "PERMANENT",
+    ];
+    if state == 0 {
+        return String::from("NONE");
+    }
+    NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| state & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn format_hwaddr(hwaddr: &[u8]) -> String {
+    hwaddr
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn table_name(table: u32) -> String {
+    match table {
+        RT_TABLE_MAIN => String::from("main"),
+        RT_TABLE_LOCAL => String::from("local"),
+        _ => table.to_string(),
+    }
+}
+
+/// Like `ip route`: `default` or the prefix, without the length of host routes.
+fn format_dst(route: &Route) -> String {
+    if route.prefix_len == 0 {
+        String::from("default")
+    } else if route.prefix_len == KEYLENGTH {
+        route.dst.to_string()
+    } else {
+        format!("{}/{}", route.dst, route.prefix_len)
+    }
+}
+
+fn device_name(k: &KernelMemory, p: &Profile, dev: usize) -> Result<String> {
+    if dev == 0 {
+        return Ok(String::new());
+    }
+    k.read_str(dev + p.get("net_device.name")?, IFNAMSIZ - 1)
+}
+
+fn read_nexthops(k: &KernelMemory, p: &Profile, fi: usize) -> Result<Vec<Nexthop>> {
+    let count = k.read_i32(fi + p.get("fib_info.fib_nhs")?)?;
+    if !(0..=256).contains(&count) {
+        bail!(
+            "fib_info at {:#x} has {} nexthops, is the offset of fib_info.fib_nhs right?",
+            fi,
+            count
+        );
+    }
+    let size = p.get("struct_fib_nh_size")?;
+    let dev_offset = p.get("fib_nh.nh_common.nhc_dev")?;
+    let family_offset = p.get("fib_nh.nh_common.nhc_gw_family")?;
+    let gw_offset = p.get("fib_nh.nh_common.nhc_gw")?;
+    let mut nexthops = vec![];
+    for i in 0..count as usize {
+        let nh = fi + p.get("fib_info.fib_nh")? + i * size;
+        let gateway = if k.read_u8(nh + family_offset)? == AF_INET {
+            let mut addr = [0u8; 4];
+            k.read_bytes(nh + gw_offset, &mut addr)?;
+            Some(Ipv4Addr::from(addr))
+        } else {
+            None
+        };
+        nexthops.push(Nexthop {
+            gateway,
+            dev: device_name(k, p, k.read_ptr(nh + dev_offset)?)?,
+        });
+    }
+    Ok(nexthops)
+}
+
+/// Routes of the fib leaf `leaf` that belong to `table`. The main table shares the trie of the
+/// local one as long as there are no policy rules, each alias records its table.
+fn read_leaf(
+    k: &KernelMemory,
+    p: &Profile,
+    table: u32,
+    leaf: usize,
+    routes: &mut Vec<Route>,
+) -> Result<()> {
+    let key = k.read_u32(leaf + p.get("key_vector.key")?)?;
+    let list_offset = p.get("fib_alias.fa_list")?;
+    let mut node = k.read_ptr(leaf + p.get("key_vector.leaf")?)?;
+    let mut seen = 0;
+    while node != 0 {
+        seen += 1;
+        if seen > MAX_ENTRIES {
+            bail!("aliases of fib leaf at {:#x} do not end", leaf);
+        }
+        let fa = node - list_offset;
+        node = k.read_ptr(node)?;
+        if k.read_u32(fa + p.get("fib_alias.tb_id")?)? != table {
+            continue;
+        }
+        let slen = k.read_u8(fa + p.get("fib_alias.fa_slen")?)?;
+        let fi = k.read_ptr(fa + p.get("fib_alias.fa_info")?)?;
+        let nexthop_object = k.read_ptr(fi + p.get("fib_info.nh")?)? != 0;
+        let nexthops = if nexthop_object {
+            vec![]
+        } else {
+            read_nexthops(k, p, fi)?
+        };
+        routes.push(Route {
+            table,
+            // keys are in host byte order
+            dst: Ipv4Addr::from(key),
+            prefix_len: KEYLENGTH.saturating_sub(slen),
+            route_type: k.read_u8(fa + p.get("fib_alias.fa_type")?)?,
+            priority: k.read_u32(fi + p.get("fib_info.fib_priority")?)?,
+            nexthops,
+            nexthop_object,
+        });
+    }
+    Ok(())
+}
+
+/// Walks the trie below the key vector `kv`, which is a leaf if it has no child bits.
+fn walk_trie(
+    k: &KernelMemory,
+    p: &Profile,
+    table: u32,
+    kv: usize,
+    depth: u8,
+    routes: &mut Vec<Route>,
+) -> Result<()> {
+    let bits = k.read_u8(kv + p.get("key_vector.bits")?)?;
+    if bits == 0 {
+        return read_leaf(k, p, table, kv, routes);
+    }
+    // every tnode consumes at least one bit of the key
+    if depth > KEYLENGTH || bits > KEYLENGTH {
+        bail!(
+            "fib trie node at {:#x} is too deep, are the offsets of key_vector right?",
+            kv
+        );
+    }
+    let children = kv + p.get("key_vector.leaf")?;
+    for i in 0..1usize << bits {
+        let child = k.read_ptr(children + i * 8)?;
+        if child != 0 {
+            walk_trie(k, p, table, child, depth + 1, routes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Routes of all routing tables of `init_net`, by table and prefix like `ip route show table all`.
+pub fn routes(k: &KernelMemory, p: &Profile) -> Result<Vec<Route>> {
+    let init_net = k.symbol("init_net")?;
+    let hash = k.read_ptr(init_net + p.get("net.ipv4.fib_table_hash")?)?;
+    let hash_size = p.optional("fib_table_hashsz").unwrap_or(256);
+    let hlist_offset = p.get("fib_table.tb_hlist")?;
+    let mut routes = vec![];
+    for bucket in 0..hash_size {
+        let mut node = k.read_ptr(hash + bucket * 8)?;
+        let mut seen = 0;
+        while node != 0 {
+            seen += 1;
+            if seen > MAX_ENTRIES {
+                bail!("fib table bucket {} does not end", bucket);
+            }
+            let tb = node - hlist_offset;
+            node = k.read_ptr(node)?;
+            let table = k.read_u32(tb + p.get("fib_table.tb_id")?)?;
+            // struct trie starts with the key vector whose only child is the root of the trie
+            let trie = k.read_ptr(tb + p.get("fib_table.tb_data")?)?;
+            let root = k.read_ptr(trie + p.get("key_vector.leaf")?)?;
+            if root == 0 {
+                continue;
+            }
+            try_with!(
+                walk_trie(k, p, table, root, 0, &mut routes),
+                "cannot read routing table {}",
+                table
+            );
+        }
+    }
+    sort_routes(&mut routes);
+    Ok(routes)
+}
+
+fn sort_routes(routes: &mut [Route]) {
+    routes.sort_by(|a, b| {
+        a.table
+            .cmp(&b.table)
+            .then(b.prefix_len.cmp(&a.prefix_len))
+            .then(a.dst.cmp(&b.dst))
+            .then(a.priority.cmp(&b.priority))
+    });
+}
+
+/// Neighbours of `arp_tbl`, which are the ARP cache of all devices.
+pub fn neighbours(k: &KernelMemory, p: &Profile) -> Result<Vec<Neighbour>> {
+    let nht = k.read_ptr(k.symbol("arp_tbl")? + p.get("neigh_table.nht")?)?;
+    let buckets = k.read_ptr(nht + p.get("neigh_hash_table.hash_buckets")?)?;
+    let shift = k.read_u32(nht + p.get("neigh_hash_table.hash_shift")?)?;
+    if shift > 24 {
+        bail!(
+            "arp hash table has 2^{} buckets, is the offset of neigh_hash_table.hash_shift right?",
+            shift
+        );
+    }
+    // the list entry is neighbour.hash since linux 6.13 and the neighbour itself before
+    let link_offset = match p.optional("neighbour.hash") {
+        Some(offset) => offset,
+        None => p.get("neighbour.next")?,
+    };
+    let mut neighbours = vec![];
+    for bucket in 0..1usize << shift {
+        let mut node = k.read_ptr(buckets + bucket * 8)?;
+        let mut seen = 0;
+        while node != 0 {
+            seen += 1;
+            if seen > MAX_ENTRIES {
+                bail!("arp hash bucket {} does not end", bucket);
+            }
+            let n = node - link_offset;
+            node = k.read_ptr(node)?;
+            match read_neighbour(k, p, n) {
+                Ok(neighbour) => neighbours.push(neighbour),
+                Err(e) => warn!("cannot read neighbour at {:#x}: {}", n, e),
+            }
+        }
+    }
+    neighbours.sort_by(|a, b| a.dev.cmp(&b.dev).then(a.addr.cmp(&b.addr)));
+    Ok(neighbours)
+}
+
+fn read_neighbour(k: &KernelMemory, p: &Profile, n: usize) -> Result<Neighbour> {
+    let mut addr = [0u8; 4];
+    k.read_bytes(n + p.get("neighbour.primary_key")?, &mut addr)?;
+    let dev = k.read_ptr(n + p.get("neighbour.dev")?)?;
+    let addr_len = match p.optional("net_device.addr_len") {
+        Some(offset) if dev != 0 => k.read_u8(dev + offset)? as usize,
+        _ => 6,
+    };
+    let mut hwaddr = vec![0u8; addr_len.min(MAX_ADDR_LEN)];
+    k.read_bytes(n + p.get("neighbour.ha")?, &mut hwaddr)?;
+    Ok(Neighbour {
+        addr: Ipv4Addr::from(addr),
+        hwaddr,
+        dev: device_name(k, p, dev)?,
+        nud_state: k.read_u8(n + p.get("neighbour.nud_state")?)?,
+    })
+}
+
+fn print_routes(routes: &[Route], all_tables: bool) {
+    let mut shown = 0;
+    for route in routes {
+        if !all_tables && route.table != RT_TABLE_MAIN {
+            continue;
+        }
+        shown += 1;
+        let mut line = String::new();
+        if route.route_type != 1 {
+            line.push_str(route_type_name(route.route_type));
+            line.push(' ');
+        }
+        line.push_str(&format_dst(route));
+        for nh in &route.nexthops {
+            if let Some(gw) = nh.gateway {
+                line.push_str(&format!(" via {}", gw));
+            }
+            if !nh.dev.is_empty() {
+                line.push_str(&format!(" dev {}", nh.dev));
+            }
+        }
+        if route.nexthop_object {
+            line.push_str(" nhid");
+        }
+        if all_tables {
+            line.push_str(&format!(" table {}", table_name(route.table)));
+        }
+        if route.priority != 0 {
+            line.push_str(&format!(" metric {}", route.priority));
+        }
+        println!("{}", line);
+    }
+    if shown == 0 {
+        println!("no routes");
+    }
+}
+
+fn print_neighbours(neighbours: &[Neighbour]) {
+    println!(
+        "{:<16} {:<18} {:<16} STATE",
+        "ADDRESS", "HWADDRESS", "DEVICE"
+    );
+    for n in neighbours {
+        println!(
+            "{:<16} {:<18} {:<16} {}",
+            n.addr.to_string(),
+            format_hwaddr(&n.hwaddr),
+            n.dev,
+            nud_state_name(n.nud_state)
+        );
+    }
+}
+
+fn with_kernel(
+    target: &GuestTarget,
+    profile: &Path,
+    f: &dyn Fn(&KernelMemory, &Profile) -> Result<()>,
+) -> Result<()> {
+    let run = |src: &dyn GuestAccess| -> Result<()> {
+        let k = KernelMemory::new(src)?;
+        let p = Profile::load_for_kernel(profile, &k)?;
+        f(&k, &p)
+    };
+    match target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            run(&vm)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            run(&core)
+        }
+    }
+}
+
+pub fn route(opts: &RouteOptions) -> Result<()> {
+    with_kernel(&opts.target, &opts.profile, &|k, p| {
+        print_routes(&routes(k, p)?, opts.all_tables);
+        Ok(())
+    })
+}
+
+pub fn arp(opts: &ArpOptions) -> Result<()> {
+    with_kernel(&opts.target, &opts.profile, &|k, p| {
+        print_neighbours(&neighbours(k, p)?);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nud_state_name() {
+        assert_eq!(nud_state_name(0x02), "REACHABLE");
+        assert_eq!(nud_state_name(0x80), "PERMANENT");
+        assert_eq!(nud_state_name(0x05), "INCOMPLETE,STALE");
+        assert_eq!(nud_state_name(0), "NONE");
+        assert_eq!(
+            format_hwaddr(&[0x52, 0x54, 0, 0x12, 0x34, 0xab]),
+            "52:54:00:12:34:ab"
+        );
+    }
+
+    #[test]
+    fn test_sort_and_format_routes() {
+        let route = |dst: [u8; 4], prefix_len, table| Route {
+            table,
+            dst: Ipv4Addr::from(dst),
+            prefix_len,
+            route_type: 1,
+            priority: 0,
+            nexthops: vec![],
+            nexthop_object: false,
+        };
+        let mut routes = vec![
+            route([0, 0, 0, 0], 0, RT_TABLE_MAIN),
+            route([10, 0, 2, 15], 32, RT_TABLE_LOCAL),
+            route([10, 0, 2, 0], 24, RT_TABLE_MAIN),
+        ];
+        sort_routes(&mut routes);
+        let dsts = routes.iter().map(format_dst).collect::<Vec<_>>();
+        assert_eq!(dsts, vec!["10.0.2.0/24", "default", "10.0.2.15"]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Sampling of what the vcpus of a running guest execute, for `vmsh lockstat` and
+//! `vmsh profile`, and the percentages shown by the sampling commands.
+//!
+//! The guest is stopped at a fixed interval to read the registers of every vcpu and resumed
+//! right after. If stopping and reading all vcpus takes longer than the interval, the next round
+//! starts immediately and the effective rate is lower than requested.
+use kvm_bindings as kvmb;
+use log::warn;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cpu::Regs;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+
+/// Share of `part` in `total` in percent, 0 if `total` is 0.
+pub fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Share of `interval` that `ns` make up, in percent.
+pub fn percent_of_interval(ns: u64, interval: Duration) -> f64 {
+    percent(ns, interval.as_nanos() as u64)
+}
+
+/// The registers of a stopped vcpu.
+pub struct VcpuSample<'a> {
+    vm: &'a Hypervisor,
+    pub vcpu: &'a VCPU,
+    pub regs: Regs,
+}
+
+/// Guest virtual memory as seen by a sampled vcpu.
+pub struct VcpuMemory<'a> {
+    vm: &'a Hypervisor,
+    sregs: kvmb::kvm_sregs,
+}
+
+impl<'a> VcpuSample<'a> {
+    /// The vcpu was in user mode.
+    pub fn is_user(&self) -> bool {
+        self.regs.cs & 3 != 0
+    }
+
+    /// Reading the segment registers is an injected ioctl, so only samples that look at guest
+    /// memory pay for it.
+    pub fn memory(&self) -> Result<VcpuMemory<'a>> {
+        Ok(VcpuMemory {
+            vm: self.vm,
+            sregs: self.vm.get_sregs(self.vcpu)?,
+        })
+    }
+}
+
+impl<'a> VcpuMemory<'a> {
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        self.vm.read_guest_virt(&self.sregs, addr, buf)
+    }
+}
+
+/// Stops the guest every `interval` until `duration` is over or `cancel` is set and passes the
+/// registers of each vcpu to `sample`. Returns the time sampling took.
+pub fn sample_vcpus(
+    vm: &Hypervisor,
+    interval: Duration,
+    duration: Duration,
+    cancel: &Cancellation,
+    mut sample: impl FnMut(VcpuSample) -> Result<()>,
+) -> Result<Duration> {
+    let start = Instant::now();
+    let mut next = start;
+    let mut late = false;
+    while start.elapsed() < duration && !cancel.is_cancelled() {
+        {
+            let _stopped = vm.stop_guard()?;
+            for vcpu in &vm.vcpus {
+                let regs: Regs = vm.get_regs(vcpu)?;
+                sample(VcpuSample { vm, vcpu, regs })?;
+            }
+        }
+        next += interval;
+        match next.checked_duration_since(Instant::now()) {
+            Some(wait) => thread::sleep(wait),
+            None => {
+                if !late {
+                    warn!(
+                        "sampling {} vcpus takes longer than {:?}, sampling less often than requested",
+                        vm.vcpus.len(),
+                        interval
+                    );
+                    late = true;
+                }
+                next = Instant::now();
+            }
+        }
+    }
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(percent(1, 4), 25.0);
+        assert_eq!(percent(1, 0), 0.0);
+        assert_eq!(
+            percent_of_interval(500_000_000, Duration::from_secs(2)),
+            25.0
+        );
+    }
+}
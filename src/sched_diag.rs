@@ -0,0 +1,354 @@
+//! Find out why vcpus do not get the cpu time they want: preemption by other host tasks and
+//! wasted halt polling.
+use libc::pid_t;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use crate::kvm;
+use crate::kvm::topology::{self, ContextSwitches, SchedStat, VcpuThread};
+use crate::result::Result;
+use crate::sampling::{percent, percent_of_interval};
+use crate::stats::{self, StatsFile};
+use crate::tracer::proc::pid_path;
+
+pub struct SchedDiagOptions {
+    pub pid: Pid,
+    pub interval: Duration,
+}
+
+/// Per vcpu counters in the binary statistics of KVM_GET_STATS_FD. The per vcpu files in debugfs
+/// that had them are gone since Linux 5.14.
+const HALT_POLL_STATS: &[&str] = &[
+    "halt_attempted_poll",
+    "halt_successful_poll",
+    "halt_poll_invalid",
+    "halt_wakeup",
+    "halt_poll_success_ns",
+    "halt_poll_fail_ns",
+];
+
+/// Steal time above this share of the interval is reported as preemption.
+const STEAL_THRESHOLD: f64 = 10.0;
+/// Polls that succeed less often than this are mostly burning cpu time.
+const POLL_SUCCESS_THRESHOLD: f64 = 10.0;
+
+#[derive(Clone, Default)]
+struct HaltPollStats(HashMap<&'static str, u64>);
+
+impl HaltPollStats {
+    fn get(&self, name: &str) -> u64 {
+        self.0.get(name).copied().unwrap_or(0)
+    }
+
+    fn delta(&self, before: &HaltPollStats) -> HaltPollStats {
+        HaltPollStats(
+            self.0
+                .iter()
+                .map(|(k, v)| (*k, v.saturating_sub(before.get(k))))
+                .collect(),
+        )
+    }
+}
+
+/// Counters of one vcpu at one point in time.
+#[derive(Clone, Default)]
+struct VcpuSample {
+    sched: SchedStat,
+    switches: ContextSwitches,
+    halt_poll: Option<HaltPollStats>,
+}
+
+/// A host thread that might compete with vcpus for cpu time.
+struct HostTask {
+    pid: Pid,
+    comm: String,
+    cpu: usize,
+    run_ns: u64,
+}
+
+fn halt_poll_stats(file: &StatsFile) -> Result<HaltPollStats> {
+    let values = file.read_named()?;
+    Ok(HaltPollStats(
+        HALT_POLL_STATS
+            .iter()
+            .filter_map(|name| Some((*name, *values.get(*name)?)))
+            .collect(),
+    ))
+}
+
+/// `vcpu_stats` is indexed by vcpu, None if kvm has no binary statistics.
+fn sample_vcpus(
+    pid: Pid,
+    threads: &[VcpuThread],
+    vcpu_stats: Option<&[StatsFile]>,
+) -> Result<Vec<VcpuSample>> {
+    threads
+        .iter()
+        .map(|t| {
+            let halt_poll = match vcpu_stats.and_then(|s| s.get(t.idx)) {
+                Some(file) => Some(halt_poll_stats(file)?),
+                None => None,
+            };
+            Ok(VcpuSample {
+                sched: topology::sched_stat(pid, t.tid)?,
+                switches: topology::context_switches(pid, t.tid)?,
+                halt_poll,
+            })
+        })
+        .collect()
+}
+
+fn parse_comm(stat: &str) -> Option<String> {
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    Some(stat.get(start + 1..end)?.to_string())
+}
+
+/// Runtime of all threads on the host by thread id. Threads that exit while we read them are
+/// skipped.
+fn host_tasks() -> HashMap<pid_t, HostTask> {
+    let mut tasks = HashMap::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(e) => {
+            warn!("cannot read /proc: {}", e);
+            return tasks;
+        }
+    };
+    for p in procs.flatten() {
+        let pid = match p.file_name().to_str().and_then(|s| s.parse::<pid_t>().ok()) {
+            Some(pid) => Pid::from_raw(pid),
+            None => continue,
+        };
+        let threads = match fs::read_dir(pid_path(pid).join("task")) {
+            Ok(threads) => threads,
+            Err(_) => continue,
+        };
+        for t in threads.flatten() {
+            let tid = match t.file_name().to_str().and_then(|s| s.parse::<pid_t>().ok()) {
+                Some(tid) => tid,
+                None => continue,
+            };
+            let stat = fs::read_to_string(t.path().join("stat")).unwrap_or_default();
+            let run_ns = fs::read_to_string(t.path().join("schedstat"))
+                .ok()
+                .and_then(|s| s.split_whitespace().next()?.parse::<u64>().ok());
+            if let (Some(comm), Some(cpu), Some(run_ns)) =
+                (parse_comm(&stat), topology::parse_last_cpu(&stat), run_ns)
+            {
+                tasks.insert(
+                    tid,
+                    HostTask {
+                        pid,
+                        comm,
+                        cpu,
+                        run_ns,
+                    },
+                );
+            }
+        }
+    }
+    tasks
+}
+
+/// The task that used most cpu time on `cpu` during the interval, ignoring `tid` itself.
+fn top_competitor<'a>(
+    before: &HashMap<pid_t, HostTask>,
+    after: &'a HashMap<pid_t, HostTask>,
+    cpu: usize,
+    tid: Pid,
+) -> Option<(&'a HostTask, u64)> {
+    after
+        .iter()
+        .filter(|(t, task)| **t != tid.as_raw() && task.cpu == cpu)
+        .map(|(t, task)| {
+            let run = before.get(t).map_or(0, |b| b.run_ns);
+            (task, task.run_ns.saturating_sub(run))
+        })
+        .filter(|(_, delta)| *delta > 0)
+        .max_by_key(|(_, delta)| *delta)
+}
+
+fn diagnose_halt_poll(t: &VcpuThread, delta: &HaltPollStats, interval: Duration) -> Option<String> {
+    let attempted = delta.get("halt_attempted_poll");
+    let successful = delta.get("halt_successful_poll");
+    if attempted == 0 {
+        return None;
+    }
+    let success_rate = percent(successful, attempted);
+    let fail_ns = delta.get("halt_poll_fail_ns");
+    if success_rate < POLL_SUCCESS_THRESHOLD && percent_of_interval(fail_ns, interval) > 1.0 {
+        return Some(format!(
+            "vCPU{} polled {} times before halting but only {:.1}% succeeded, wasting {:.1}% cpu time. Consider lowering the halt_poll_ns module parameter of kvm",
+            t.idx,
+            attempted,
+            success_rate,
+            percent_of_interval(fail_ns, interval)
+        ));
+    }
+    None
+}
+
+pub fn sched_diag(opts: &SchedDiagOptions) -> Result<()> {
+    if opts.interval.as_nanos() == 0 {
+        bail!("interval must be at least one second");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let threads = topology::vcpu_threads(vm.pid, &vm.vcpus)?;
+    if threads.len() < vm.vcpus.len() {
+        warn!(
+            "found threads for {} out of {} vcpus",
+            threads.len(),
+            vm.vcpus.len()
+        );
+    }
+    let vcpu_stats = {
+        let _stopped = vm.stop_guard()?;
+        match stats::open_stats(&vm) {
+            Ok((_, vcpu_stats)) => Some(vcpu_stats),
+            Err(e) => {
+                warn!("no halt polling statistics: {}", e);
+                None
+            }
+        }
+    };
+
+    info!("sampling for {}s", opts.interval.as_secs_f64());
+    let vcpus_before = sample_vcpus(vm.pid, &threads, vcpu_stats.as_deref())?;
+    let tasks_before = host_tasks();
+    thread::sleep(opts.interval);
+    let vcpus_after = sample_vcpus(vm.pid, &threads, vcpu_stats.as_deref())?;
+    let tasks_after = host_tasks();
+
+    println!(
+        "{:>4} {:>8} {:>4} {:>7} {:>7} {:>8} {:>8} {:>8} {:>8}",
+        "VCPU", "TID", "CPU", "RUN%", "STEAL%", "VOLCSW", "INVCSW", "POLLS", "POLLOK%"
+    );
+    let mut diagnosis = vec![];
+    for (t, (b, a)) in threads
+        .iter()
+        .zip(vcpus_before.iter().zip(vcpus_after.iter()))
+    {
+        let run_ns = a.sched.run_ns.saturating_sub(b.sched.run_ns);
+        let wait_ns = a.sched.wait_ns.saturating_sub(b.sched.wait_ns);
+        let voluntary = a.switches.voluntary.saturating_sub(b.switches.voluntary);
+        let nonvoluntary = a
+            .switches
+            .nonvoluntary
+            .saturating_sub(b.switches.nonvoluntary);
+        let halt_poll = match (&b.halt_poll, &a.halt_poll) {
+            (Some(b), Some(a)) => Some(a.delta(b)),
+            _ => None,
+        };
+        let cpu = tasks_after.get(&t.tid.as_raw()).map(|task| task.cpu);
+        let (polls, poll_ok) = match &halt_poll {
+            Some(h) if h.get("halt_attempted_poll") > 0 => (
+                h.get("halt_attempted_poll").to_string(),
+                format!(
+                    "{:.1}",
+                    percent(h.get("halt_successful_poll"), h.get("halt_attempted_poll"))
+                ),
+            ),
+            Some(_) => (String::from("0"), String::from("-")),
+            None => (String::from("-"), String::from("-")),
+        };
+        println!(
+            "{:>4} {:>8} {:>4} {:>7.1} {:>7.1} {:>8} {:>8} {:>8} {:>8}",
+            t.idx,
+            t.tid,
+            cpu.map_or_else(|| String::from("-"), |c| c.to_string()),
+            percent_of_interval(run_ns, opts.interval),
+            percent_of_interval(wait_ns, opts.interval),
+            voluntary,
+            nonvoluntary,
+            polls,
+            poll_ok
+        );
+
+        let steal = percent_of_interval(wait_ns, opts.interval);
+        if steal > STEAL_THRESHOLD && nonvoluntary > 0 {
+            let competitor =
+                cpu.and_then(|c| top_competitor(&tasks_before, &tasks_after, c, t.tid));
+            diagnosis.push(match competitor {
+                Some((task, _)) if task.pid == vm.pid => format!(
+                    "vCPU{} is waiting {:.1}% of the time for host cpu {}, which is shared with another thread of the hypervisor ({}). Consider pinning vcpus to dedicated cpus",
+                    t.idx,
+                    steal,
+                    task.cpu,
+                    task.comm
+                ),
+                Some((task, run)) => format!(
+                    "vCPU{} is being preempted by PID {} ({}), which ran {:.1}% of the time on host cpu {}",
+                    t.idx,
+                    task.pid,
+                    task.comm,
+                    percent_of_interval(run, opts.interval),
+                    task.cpu
+                ),
+                None => format!(
+                    "vCPU{} is waiting {:.1}% of the time for a host cpu and was preempted {} times",
+                    t.idx, steal, nonvoluntary
+                ),
+            });
+        }
+        if let Some(msg) = halt_poll
+            .as_ref()
+            .and_then(|h| diagnose_halt_poll(t, h, opts.interval))
+        {
+            diagnosis.push(msg);
+        }
+    }
+
+    println!();
+    if diagnosis.is_empty() {
+        println!("no preemption or halt polling problems found");
+    }
+    for d in diagnosis {
+        println!("{}", d);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_comm() {
+        assert_eq!(
+            parse_comm("42 (CPU 0/KVM) S 1").as_deref(),
+            Some("CPU 0/KVM")
+        );
+        assert_eq!(parse_comm("42 (a) b) R 1").as_deref(), Some("a) b"));
+        assert_eq!(parse_comm(""), None);
+    }
+
+    #[test]
+    fn test_diagnose_halt_poll() {
+        let t = VcpuThread {
+            idx: 2,
+            tid: Pid::from_raw(1),
+            name: String::from("CPU 2/KVM"),
+        };
+        let mut stats = HashMap::new();
+        stats.insert("halt_attempted_poll", 1000);
+        stats.insert("halt_successful_poll", 10);
+        stats.insert("halt_poll_fail_ns", 200_000_000);
+        let stats = HaltPollStats(stats);
+        let msg = diagnose_halt_poll(&t, &stats, Duration::from_secs(1)).unwrap();
+        assert!(msg.starts_with("vCPU2 polled 1000 times"));
+
+        let mut good = stats.0.clone();
+        good.insert("halt_successful_poll", 900);
+        assert!(diagnose_halt_poll(&t, &HaltPollStats(good), Duration::from_secs(1)).is_none());
+    }
+}
@@ -0,0 +1,380 @@
+//! Save what the display of a guest shows as PNG, see `vmsh screenshot`.
+//!
+//! The framebuffer is found through `screen_info`, in which the kernel keeps the linear
+//! framebuffer that the firmware or boot loader set up (efifb, vesafb and simplefb/simpledrm use
+//! it as well). The pixels are read from guest physical memory, usually the VRAM memslot of the
+//! emulated graphics card. A drm driver like bochs-drm may switch to a different mode later on;
+//! then, or if the guest booted with a text console, the framebuffer can be given explicitly
+//! with `--address` and `--mode`.
+//!
+//! Nothing is executed in the guest. PNG images are written uncompressed, so they are about as
+//! large as the visible part of the framebuffer.
+use log::info;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::bytes::{u16_at, u32_at};
+use crate::kvm;
+use crate::remote::transfer::crc32;
+use crate::result::Result;
+use crate::vmi::KernelMemory;
+
+/// `struct screen_info` of include/uapi/linux/screen_info.h, which is packed and part of the
+/// boot protocol, so its layout does not change.
+const SCREEN_INFO_SIZE: usize = 0x40;
+const ORIG_VIDEO_IS_VGA: usize = 0x0f;
+const LFB_WIDTH: usize = 0x12;
+const LFB_HEIGHT: usize = 0x14;
+const LFB_DEPTH: usize = 0x16;
+const LFB_BASE: usize = 0x18;
+const LFB_LINELENGTH: usize = 0x24;
+const RED_SIZE: usize = 0x26;
+const CAPABILITIES: usize = 0x36;
+const EXT_LFB_BASE: usize = 0x3a;
+/// Values of `orig_video_isVGA` for a linear framebuffer
+const VIDEO_TYPE_VLFB: u8 = 0x23;
+const VIDEO_TYPE_EFI: u8 = 0x70;
+const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// Largest block of uncompressed data in a deflate stream
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+/// Bit field of one color in a pixel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channel {
+    pub size: u8,
+    pub pos: u8,
+}
+
+impl Channel {
+    /// Value of the channel in `pixel`, scaled to 8 bits.
+    fn extract(&self, pixel: u32) -> u8 {
+        if self.size == 0 {
+            return 0;
+        }
+        let max = (1u64 << self.size) - 1;
+        let value = (pixel as u64 >> self.pos) & max;
+        (value * 255 / max) as u8
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer {
+    /// Guest physical address
+    pub base: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes per line
+    pub stride: usize,
+    pub bits_per_pixel: usize,
+    /// Red, green and blue
+    pub channels: [Channel; 3],
+}
+
+impl Framebuffer {
+    /// 32 bit pixels with 8 bits of red, green and blue from high to low, the default of most
+    /// emulated graphics cards.
+    pub fn xrgb8888(base: usize, width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            base,
+            width,
+            height,
+            stride: width * 4,
+            bits_per_pixel: 32,
+            channels: [
+                Channel { size: 8, pos: 16 },
+                Channel { size: 8, pos: 8 },
+                Channel { size: 8, pos: 0 },
+            ],
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel / 8
+    }
+
+    fn visible_size(&self) -> usize {
+        self.stride * (self.height - 1) + self.width * self.bytes_per_pixel()
+    }
+
+    /// Convert the content of the framebuffer to rows of 8 bit RGB.
+    fn to_rgb(&self, raw: &[u8]) -> Vec<u8> {
+        let bpp = self.bytes_per_pixel();
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for y in 0..self.height {
+            let line = &raw[y * self.stride..y * self.stride + self.width * bpp];
+            for pixel in line.chunks_exact(bpp) {
+                let mut bytes = [0u8; 4];
+                bytes[..bpp].copy_from_slice(pixel);
+                let pixel = u32::from_le_bytes(bytes);
+                rgb.extend(self.channels.iter().map(|c| c.extract(pixel)));
+            }
+        }
+        rgb
+    }
+}
+
+/// The linear framebuffer described by a `struct screen_info`.
+fn parse_screen_info(buf: &[u8]) -> Result<Framebuffer> {
+    let video_type = buf[ORIG_VIDEO_IS_VGA];
+    if video_type != VIDEO_TYPE_VLFB && video_type != VIDEO_TYPE_EFI {
+        bail!(
+            "the guest booted without a linear framebuffer (video type {:#x}), pass --address and --mode",
+            video_type
+        );
+    }
+    let u16_field = |offset| -> Result<usize> {
+        Ok(require_with!(u16_at(buf, offset), "screen_info is truncated") as usize)
+    };
+    let u32_field = |offset| -> Result<usize> {
+        Ok(require_with!(u32_at(buf, offset), "screen_info is truncated") as usize)
+    };
+    let mut base = u32_field(LFB_BASE)?;
+    if u32_field(CAPABILITIES)? & VIDEO_CAPABILITY_64BIT_BASE as usize != 0 {
+        base |= u32_field(EXT_LFB_BASE)? << 32;
+    }
+    let channel = |i: usize| Channel {
+        size: buf[RED_SIZE + 2 * i],
+        pos: buf[RED_SIZE + 2 * i + 1],
+    };
+    let fb = Framebuffer {
+        base,
+        width: u16_field(LFB_WIDTH)?,
+        height: u16_field(LFB_HEIGHT)?,
+        stride: u16_field(LFB_LINELENGTH)?,
+        bits_per_pixel: u16_field(LFB_DEPTH)?,
+        channels: [channel(0), channel(1), channel(2)],
+    };
+    check_framebuffer(&fb)?;
+    Ok(fb)
+}
+
+fn check_framebuffer(fb: &Framebuffer) -> Result<()> {
+    if ![16, 24, 32].contains(&fb.bits_per_pixel) {
+        bail!(
+            "framebuffer with {} bits per pixel is not supported",
+            fb.bits_per_pixel
+        );
+    }
+    if fb.width == 0 || fb.height == 0 || fb.stride < fb.width * fb.bytes_per_pixel() {
+        bail!(
+            "invalid framebuffer mode {}x{} with {} bytes per line",
+            fb.width,
+            fb.height,
+            fb.stride
+        );
+    }
+    Ok(())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // the sums cannot overflow within this many bytes
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// zlib stream of `data` in uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len() / MAX_STORED_BLOCK + 1;
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // deflate with a 32K window, no compression
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn write_chunk(out: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    let mut crc_input = Vec::with_capacity(kind.len() + data.len());
+    crc_input.extend_from_slice(kind);
+    crc_input.extend_from_slice(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(&crc_input)?;
+    out.write_all(&crc32(&crc_input).to_be_bytes())
+}
+
+/// Write `rgb`, rows of 8 bit RGB pixels, as PNG.
+fn write_png(out: &mut dyn Write, width: usize, height: usize, rgb: &[u8]) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, truecolor, deflate, no filter, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // each line starts with its filter type, 0 is none
+    let mut lines = Vec::with_capacity(height * (width * 3 + 1));
+    for line in rgb.chunks(width * 3) {
+        lines.push(0);
+        lines.extend_from_slice(line);
+    }
+
+    out.write_all(&PNG_SIGNATURE)?;
+    write_chunk(out, b"IHDR", &header)?;
+    write_chunk(out, b"IDAT", &zlib_stored(&lines))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+pub struct ScreenshotOptions {
+    pub pid: Pid,
+    pub path: PathBuf,
+    /// Used instead of the framebuffer in `screen_info`
+    pub framebuffer: Option<Framebuffer>,
+}
+
+/// Parse `WIDTHxHEIGHT`.
+pub fn parse_mode(s: &str) -> Option<(usize, usize)> {
+    let (width, height) = s.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+pub fn screenshot(opts: &ScreenshotOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let stopped = vm.stop_guard()?;
+    let kmem = KernelMemory::new(&vm)?;
+    let fb = match &opts.framebuffer {
+        Some(fb) => {
+            check_framebuffer(fb)?;
+            fb.clone()
+        }
+        None => {
+            let mut buf = [0u8; SCREEN_INFO_SIZE];
+            kmem.read_bytes(kmem.symbol("screen_info")?, &mut buf)?;
+            parse_screen_info(&buf)?
+        }
+    };
+    info!(
+        "framebuffer at {:#x}: {}x{}, {} bits per pixel, {} bytes per line",
+        fb.base, fb.width, fb.height, fb.bits_per_pixel, fb.stride
+    );
+    let mut raw = vec![0u8; fb.visible_size()];
+    let mapping = require_with!(
+        kmem.mem.phys_mapping(fb.base),
+        "framebuffer at {:#x} is not in a memslot of the vm",
+        fb.base
+    );
+    if fb.base + raw.len() > mapping.phys_end() {
+        bail!(
+            "framebuffer at {:#x} ends after its memslot at {:#x}",
+            fb.base,
+            mapping.phys_end()
+        );
+    }
+    kmem.mem.read_phys(fb.base, &mut raw)?;
+    drop(stopped);
+
+    let rgb = fb.to_rgb(&raw);
+    let file = try_with!(
+        File::create(&opts.path),
+        "cannot create {}",
+        opts.path.display()
+    );
+    let mut writer = BufWriter::new(file);
+    try_with!(
+        write_png(&mut writer, fb.width, fb.height, &rgb).and_then(|_| writer.flush()),
+        "cannot write {}",
+        opts.path.display()
+    );
+    info!("wrote {}", opts.path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_screen_info() {
+        let mut buf = [0u8; SCREEN_INFO_SIZE];
+        buf[ORIG_VIDEO_IS_VGA] = VIDEO_TYPE_EFI;
+        buf[LFB_WIDTH..LFB_WIDTH + 2].copy_from_slice(&1280u16.to_le_bytes());
+        buf[LFB_HEIGHT..LFB_HEIGHT + 2].copy_from_slice(&800u16.to_le_bytes());
+        buf[LFB_DEPTH..LFB_DEPTH + 2].copy_from_slice(&32u16.to_le_bytes());
+        buf[LFB_BASE..LFB_BASE + 4].copy_from_slice(&0xc000_0000u32.to_le_bytes());
+        buf[LFB_LINELENGTH..LFB_LINELENGTH + 2].copy_from_slice(&5120u16.to_le_bytes());
+        buf[RED_SIZE..RED_SIZE + 6].copy_from_slice(&[8, 16, 8, 8, 8, 0]);
+        assert_eq!(
+            parse_screen_info(&buf).unwrap(),
+            Framebuffer::xrgb8888(0xc000_0000, 1280, 800)
+        );
+
+        buf[CAPABILITIES..CAPABILITIES + 4]
+            .copy_from_slice(&VIDEO_CAPABILITY_64BIT_BASE.to_le_bytes());
+        buf[EXT_LFB_BASE..EXT_LFB_BASE + 4].copy_from_slice(&0x80u32.to_le_bytes());
+        assert_eq!(parse_screen_info(&buf).unwrap().base, 0x80_c000_0000);
+
+        // vga text mode
+        buf[ORIG_VIDEO_IS_VGA] = 1;
+        assert!(parse_screen_info(&buf).is_err());
+    }
+
+    #[test]
+    fn test_to_rgb() {
+        let mut fb = Framebuffer::xrgb8888(0, 2, 1);
+        // padding at the end of the line is skipped
+        fb.stride = 12;
+        let raw = [
+            0x30, 0x20, 0x10, 0xff, 0x00, 0x00, 0xff, 0x00, 0xaa, 0xaa, 0xaa, 0xaa,
+        ];
+        assert_eq!(fb.to_rgb(&raw), vec![0x10, 0x20, 0x30, 0xff, 0x00, 0x00]);
+
+        // rgb565
+        fb.bits_per_pixel = 16;
+        fb.stride = 2;
+        fb.width = 1;
+        fb.channels = [
+            Channel { size: 5, pos: 11 },
+            Channel { size: 6, pos: 5 },
+            Channel { size: 5, pos: 0 },
+        ];
+        assert_eq!(fb.to_rgb(&0xf81fu16.to_le_bytes()), vec![0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_zlib_stored() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(
+            zlib_stored(b"abc"),
+            vec![0x78, 0x01, 1, 3, 0, 0xfc, 0xff, b'a', b'b', b'c', 0x02, 0x4d, 0x01, 0x27]
+        );
+        let data = vec![7u8; MAX_STORED_BLOCK + 1];
+        let stream = zlib_stored(&data);
+        assert_eq!(stream.len(), 2 + 2 * 5 + data.len() + 4);
+        // the first block is not the last one
+        assert_eq!(stream[2], 0);
+        assert_eq!(stream[2 + 5 + MAX_STORED_BLOCK], 1);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("1024x768"), Some((1024, 768)));
+        assert_eq!(parse_mode("1024"), None);
+    }
+}
@@ -0,0 +1,533 @@
+//! Check from the outside that KASLR, stack canaries and page table isolation of guests are
+//! effective, see `vmsh security-audit`.
+//!
+//! Every vm is stopped while it is sampled:
+//!
+//! - KASLR: the kernel text and the memory regions randomized by CONFIG_RANDOMIZE_MEMORY are
+//!   compared against their fixed defaults.
+//! - Stack canaries: the canary of the task running on each vcpu is read from the per-cpu area.
+//!   Before linux 6.2 it is at `%gs:40`. Newer kernels need the per-cpu offset of
+//!   `__stack_chk_guard` (`nm vmlinux | grep __stack_chk_guard`) in a profile (see `vmi`):
+//!
+//!   ```text
+//!   percpu.__stack_chk_guard 0x2d7c8
+//!   ```
+//!
+//! - Page table isolation: a vcpu in user mode runs on the user page table, or the page after the
+//!   kernel page table is a user page table that does not map the direct map.
+//!
+//! When several vms are audited at once, kernels that share their layout or canaries are
+//! reported, which happens when vms are cloned from the same snapshot. Canary values themselves
+//! are never printed.
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{simple_error, try_with};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::cpu_report::{arch_capabilities, cpu_model, has_arch_capability, read_msr};
+use crate::guest_mem::get_page_table_addr;
+use crate::json::json_escape;
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::page_table::{self, PageTable, PageTableEntry, PageTableFlags};
+use crate::result::Result;
+use crate::tracer::proc::parse_kernel_release;
+use crate::vmi::{KernelMemory, Profile, PTI_USER_PGTABLE};
+
+pub struct SecurityAuditOptions {
+    pub pids: Vec<Pid>,
+    /// Only needed for stack canaries of linux 6.2 and newer, see module documentation.
+    pub profile: Option<PathBuf>,
+    /// Print one json object per vm instead of text.
+    pub json: bool,
+}
+
+/// Kernel text without KASLR: `__START_KERNEL_map + CONFIG_PHYSICAL_START`
+const DEFAULT_KERNEL_BASE: usize = 0xffff_ffff_8100_0000;
+/// Memory regions randomized by CONFIG_RANDOMIZE_MEMORY and their fixed bases with 4- and
+/// 5-level paging.
+const MEMORY_REGIONS: &[(&str, usize, usize)] = &[
+    (
+        "page_offset_base",
+        0xffff_8880_0000_0000,
+        0xff11_0000_0000_0000,
+    ),
+    ("vmalloc_base", 0xffff_c900_0000_0000, 0xffa0_0000_0000_0000),
+    ("vmemmap_base", 0xffff_ea00_0000_0000, 0xffd4_0000_0000_0000),
+];
+/// `fixed_percpu_data.stack_canary` before linux 6.2
+const LEGACY_CANARY_OFFSET: usize = 40;
+/// Random canaries have about half of their 56 random bits set.
+const MIN_CANARY_BITS: u32 = 12;
+const MAX_CANARY_BITS: u32 = 44;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+const KERNEL_HALF: usize = 0xffff_8000_0000_0000;
+
+struct Audit {
+    pid: Pid,
+    release: String,
+    kernel_base: usize,
+    kernel_phys: Option<usize>,
+    /// name, base and whether it differs from the default
+    regions: Vec<(&'static str, Option<usize>, bool)>,
+    stack_protector: bool,
+    /// per vcpu, None if it could not be read
+    canaries: Vec<Option<u64>>,
+    meltdown: bool,
+    pti: Option<bool>,
+    findings: Vec<String>,
+}
+
+impl Audit {
+    fn kaslr_offset(&self) -> usize {
+        self.kernel_base.wrapping_sub(DEFAULT_KERNEL_BASE)
+    }
+}
+
+/// Per-cpu offset of the stack canary.
+fn canary_offset(version: Option<(u32, u32)>, profile: Option<&Profile>) -> Option<usize> {
+    if let Some(offset) = profile.and_then(|p| p.optional("percpu.__stack_chk_guard")) {
+        return Some(offset);
+    }
+    match version {
+        Some(v) if v < (6, 2) => Some(LEGACY_CANARY_OFFSET),
+        _ => None,
+    }
+}
+
+/// Describes what is wrong with a canary. Linux clears the lowest byte of canaries, the other 56
+/// bits are random.
+fn weak_canary(canary: u64) -> Option<&'static str> {
+    if canary == 0 {
+        return Some("is zero");
+    }
+    if canary & 0xff != 0 {
+        return Some("does not look like a canary of linux, check the canary offset");
+    }
+    let bits = (canary >> 8).count_ones();
+    if !(MIN_CANARY_BITS..=MAX_CANARY_BITS).contains(&bits) {
+        return Some("has low entropy");
+    }
+    None
+}
+
+fn pgd_index(addr: usize, la57: bool) -> usize {
+    (addr >> if la57 { 48 } else { 39 }) & 511
+}
+
+/// With page table isolation, the user page table has the same kernel text mapping as the kernel
+/// page table, but no direct map.
+fn user_pgd_isolated(
+    kernel: &[PageTableEntry],
+    user: &[PageTableEntry],
+    direct_map: usize,
+    text: usize,
+) -> bool {
+    let present = |table: &[PageTableEntry], idx: usize| {
+        table
+            .get(idx)
+            .map_or(false, |e| e.flags().contains(PageTableFlags::PRESENT))
+    };
+    present(kernel, direct_map) && !present(user, direct_map) && present(user, text)
+}
+
+/// Values that more than one vm has, with the pids that have them.
+fn shared_values(values: &[(Pid, u64)]) -> Vec<(u64, Vec<Pid>)> {
+    let mut by_value: HashMap<u64, Vec<Pid>> = HashMap::new();
+    for (pid, value) in values {
+        by_value.entry(*value).or_default().push(*pid);
+    }
+    let mut shared = by_value
+        .into_iter()
+        .filter(|(_, pids)| pids.len() > 1)
+        .collect::<Vec<_>>();
+    shared.sort_by_key(|(_, pids)| pids[0]);
+    shared
+}
+
+fn kernel_gs_base(vm: &Hypervisor, vcpu: usize, gs_base: u64) -> Result<Option<usize>> {
+    // while the vcpu is in user space, the kernel gs base is swapped out
+    let base = if gs_base as usize >= KERNEL_HALF {
+        Some(gs_base)
+    } else {
+        read_msr(vm, &vm.vcpus[vcpu], MSR_KERNEL_GS_BASE)?
+    };
+    Ok(base.map(|b| b as usize).filter(|b| *b >= KERNEL_HALF))
+}
+
+fn audit(vm: &Hypervisor, pid: Pid, profile: Option<&Profile>) -> Result<Audit> {
+    let kernel = try_with!(KernelMemory::new(vm), "cannot inspect guest kernel");
+    let release = kernel.release().unwrap_or_else(|_| String::from("unknown"));
+    let version = parse_kernel_release(&release);
+    let sregs = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| vm.get_sregs(vcpu))
+        .collect::<Result<Vec<_>>>()?;
+    let la57 = page_table::paging_levels(sregs[0].cr4) == 5;
+    let mut findings = vec![];
+
+    let kernel_base = kernel.kernel.range.start;
+    let kernel_phys = kernel
+        .kernel
+        .memory_sections
+        .first()
+        .map(|s| s.phys_start.value);
+    if kernel_base == DEFAULT_KERNEL_BASE {
+        findings.push(String::from(
+            "kernel text is not randomized, KASLR is disabled (nokaslr or CONFIG_RANDOMIZE_BASE=n)",
+        ));
+    }
+    let mut regions = vec![];
+    for (name, default4, default5) in MEMORY_REGIONS {
+        let default = if la57 { *default5 } else { *default4 };
+        // without CONFIG_RANDOMIZE_MEMORY, the bases are constants and not exported
+        let base = match kernel.symbol(name) {
+            Ok(addr) => Some(kernel.read_u64(addr)? as usize),
+            Err(_) => None,
+        };
+        let randomized = base.map_or(false, |b| b != default);
+        if !randomized && kernel_base != DEFAULT_KERNEL_BASE {
+            findings.push(format!(
+                "{} is not randomized (CONFIG_RANDOMIZE_MEMORY=n)",
+                name
+            ));
+        }
+        regions.push((*name, base, randomized));
+    }
+
+    let stack_protector = kernel.kernel.symbols.contains_key("__stack_chk_fail");
+    let mut canaries = vec![];
+    if !stack_protector {
+        findings.push(String::from(
+            "kernel is built without stack protector (CONFIG_STACKPROTECTOR=n)",
+        ));
+    } else {
+        match canary_offset(version, profile) {
+            Some(offset) => {
+                for (i, s) in sregs.iter().enumerate() {
+                    let canary = match kernel_gs_base(vm, i, s.gs.base)? {
+                        Some(base) => kernel.read_u64(base + offset).ok(),
+                        None => None,
+                    };
+                    match canary {
+                        Some(c) => {
+                            if let Some(problem) = weak_canary(c) {
+                                findings.push(format!("stack canary of vcpu{} {}", i, problem));
+                            }
+                        }
+                        None => warn!("cannot read the stack canary of vcpu{}", i),
+                    }
+                    canaries.push(canary);
+                }
+            }
+            None => warn!(
+                "linux {} needs percpu.__stack_chk_guard in the profile to check stack canaries",
+                release
+            ),
+        }
+    }
+
+    let cpuid = try_with!(vm.get_cpuid2(&vm.vcpus[0]), "cannot get cpuid of the guest");
+    let entries = &cpuid.entries[..(cpuid.nent as usize).min(cpuid.entries.len())];
+    let intel = cpu_model(entries).map_or(false, |(vendor, ..)| vendor == "GenuineIntel");
+    let meltdown = intel && !has_arch_capability(arch_capabilities(vm, entries)?, "rdcl_no");
+
+    let user_mode = sregs.iter().find(|s| s.cs.selector & 3 == 3);
+    let pti = match user_mode {
+        Some(s) => Some(get_page_table_addr(s) & PTI_USER_PGTABLE != 0),
+        None => {
+            let direct_map = regions[0].1.unwrap_or(if la57 {
+                MEMORY_REGIONS[0].2
+            } else {
+                MEMORY_REGIONS[0].1
+            });
+            let kernel_pgd = PageTable::read(kernel.mem.src, &kernel.pml4, 0, 0)?;
+            // the pgd is not followed by a user pgd without pti, it might not even be memory
+            kernel
+                .mem
+                .page_table(kernel.pml4.value | PTI_USER_PGTABLE)
+                .and_then(|user_pgd| PageTable::read(kernel.mem.src, &user_pgd, 0, 0))
+                .ok()
+                .map(|user_pgd| {
+                    user_pgd_isolated(
+                        kernel_pgd.entries(),
+                        user_pgd.entries(),
+                        pgd_index(direct_map, la57),
+                        pgd_index(kernel_base, la57),
+                    )
+                })
+        }
+    };
+    if meltdown && pti != Some(true) {
+        findings.push(String::from(
+            "cpu is affected by meltdown, but the guest does not use page table isolation",
+        ));
+    }
+
+    Ok(Audit {
+        pid,
+        release,
+        kernel_base,
+        kernel_phys,
+        regions,
+        stack_protector,
+        canaries,
+        meltdown,
+        pti,
+        findings,
+    })
+}
+
+/// Findings of vms that share their kernel layout or stack canaries.
+fn cross_check(audits: &mut [Audit]) {
+    let offsets = audits
+        .iter()
+        .filter(|a| a.kernel_base != DEFAULT_KERNEL_BASE)
+        .map(|a| (a.pid, a.kaslr_offset() as u64))
+        .collect::<Vec<_>>();
+    let canaries = audits
+        .iter()
+        .flat_map(|a| a.canaries.iter().flatten().map(move |c| (a.pid, *c)))
+        .filter(|(_, c)| *c != 0)
+        .collect::<Vec<_>>();
+    let mut shared = vec![];
+    for (offset, pids) in shared_values(&offsets) {
+        shared.push((pids, format!("kaslr offset {:#x}", offset)));
+    }
+    for (_, mut pids) in shared_values(&canaries) {
+        pids.dedup();
+        if pids.len() > 1 {
+            shared.push((pids, String::from("stack canaries")));
+        }
+    }
+    for (pids, what) in shared {
+        let names = pids
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let finding = format!(
+            "vms {} share their {}, they were probably cloned from the same snapshot",
+            names, what
+        );
+        for a in audits.iter_mut().filter(|a| pids.contains(&a.pid)) {
+            a.findings.push(finding.clone());
+        }
+    }
+}
+
+fn yes_no_unknown(v: Option<bool>) -> &'static str {
+    match v {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+fn canary_state(canary: &Option<u64>) -> &'static str {
+    match canary {
+        Some(c) if weak_canary(*c).is_none() => "ok",
+        Some(_) => "weak",
+        None => "unknown",
+    }
+}
+
+fn print_text(a: &Audit) {
+    println!("pid {}: linux {}", a.pid, a.release);
+    println!(
+        "  {:<24} {:#x} (kernel text at {:#x})",
+        "kaslr offset",
+        a.kaslr_offset(),
+        a.kernel_base
+    );
+    if let Some(phys) = a.kernel_phys {
+        println!("  {:<24} {:#x}", "physical load address", phys);
+    }
+    for (name, base, randomized) in &a.regions {
+        match base {
+            Some(base) => println!(
+                "  {:<24} {:#x} (randomized: {})",
+                name,
+                base,
+                yes_no_unknown(Some(*randomized))
+            ),
+            None => println!("  {:<24} fixed", name),
+        }
+    }
+    println!(
+        "  {:<24} {}",
+        "stack protector",
+        yes_no_unknown(Some(a.stack_protector))
+    );
+    for (i, canary) in a.canaries.iter().enumerate() {
+        println!(
+            "  {:<24} {}",
+            format!("vcpu{} canary", i),
+            canary_state(canary)
+        );
+    }
+    println!(
+        "  {:<24} {}",
+        "affected by meltdown",
+        yes_no_unknown(Some(a.meltdown))
+    );
+    println!("  {:<24} {}", "page table isolation", yes_no_unknown(a.pti));
+    for f in &a.findings {
+        println!("  ! {}", f);
+    }
+}
+
+pub(crate) fn json_string_list<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    let items = items
+        .map(|s| format!("\"{}\"", json_escape(s)))
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+pub(crate) fn json_bool(v: Option<bool>) -> &'static str {
+    match v {
+        Some(true) => "true",
+        Some(false) => "false",
+        None => "null",
+    }
+}
+
+/// Addresses are hex strings, they don't fit into the numbers of most json parsers.
+fn print_json(a: &Audit) {
+    let regions = a
+        .regions
+        .iter()
+        .map(|(name, base, randomized)| {
+            let base = match base {
+                Some(b) => format!("\"{:#x}\"", b),
+                None => String::from("null"),
+            };
+            format!(
+                "\"{}\": {{\"base\": {}, \"randomized\": {}}}",
+                name, base, randomized
+            )
+        })
+        .collect::<Vec<_>>();
+    let kernel_phys = match a.kernel_phys {
+        Some(p) => format!("\"{:#x}\"", p),
+        None => String::from("null"),
+    };
+    println!(
+        "{{\"pid\": {}, \"release\": \"{}\", \"kaslr_offset\": \"{:#x}\", \"kernel_phys\": {}, \"regions\": {{{}}}, \"stack_protector\": {}, \"canaries\": {}, \"meltdown\": {}, \"pti\": {}, \"findings\": {}}}",
+        a.pid,
+        json_escape(&a.release),
+        a.kaslr_offset(),
+        kernel_phys,
+        regions.join(", "),
+        a.stack_protector,
+        json_string_list(a.canaries.iter().map(canary_state)),
+        a.meltdown,
+        json_bool(a.pti),
+        json_string_list(a.findings.iter().map(|f| f.as_str())),
+    );
+}
+
+fn audit_pid(pid: Pid, profile: Option<&Profile>) -> Result<Audit> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(pid),
+        "cannot get vms for process {}",
+        pid
+    );
+    let _stopped = vm.stop_guard()?;
+    audit(&vm, pid, profile)
+}
+
+/// Returns the number of vms with findings or that could not be audited.
+pub fn security_audit(opts: &SecurityAuditOptions) -> Result<usize> {
+    let profile = match &opts.profile {
+        Some(path) => Some(Profile::load(path)?),
+        None => None,
+    };
+    let mut audits = vec![];
+    let mut failed = 0;
+    for pid in &opts.pids {
+        match audit_pid(*pid, profile.as_ref()) {
+            Ok(a) => audits.push(a),
+            Err(e) => {
+                failed += 1;
+                if opts.json {
+                    println!(
+                        "{{\"pid\": {}, \"error\": \"{}\"}}",
+                        pid,
+                        json_escape(&e.to_string())
+                    );
+                } else {
+                    println!("pid {}: {}", pid, e);
+                }
+            }
+        }
+    }
+    cross_check(&mut audits);
+    for a in &audits {
+        if opts.json {
+            print_json(a);
+        } else {
+            print_text(a);
+        }
+    }
+    if audits.is_empty() && failed > 0 {
+        return Err(simple_error!("no vm could be audited"));
+    }
+    Ok(failed + audits.iter().filter(|a| !a.findings.is_empty()).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canary_offset() {
+        assert_eq!(canary_offset(Some((5, 15)), None), Some(40));
+        assert_eq!(canary_offset(Some((6, 2)), None), None);
+        assert_eq!(canary_offset(None, None), None);
+        let profile = Profile::parse("percpu.__stack_chk_guard 0x2d7c8\n").unwrap();
+        assert_eq!(canary_offset(Some((6, 6)), Some(&profile)), Some(0x2d7c8));
+    }
+
+    #[test]
+    fn test_weak_canary() {
+        assert_eq!(weak_canary(0x9a3c_51e2_07bd_4f00), None);
+        assert_eq!(weak_canary(0), Some("is zero"));
+        assert_eq!(weak_canary(0x100), Some("has low entropy"));
+        assert_eq!(weak_canary(0xffff_ffff_ffff_ff00), Some("has low entropy"));
+        assert!(weak_canary(0x9a3c_51e2_07bd_4f01).is_some());
+    }
+
+    #[test]
+    fn test_user_pgd_isolated() {
+        let text = pgd_index(0xffff_ffff_a1e0_0000, false);
+        let direct_map = pgd_index(0xffff_9d40_0000_0000, false);
+        assert_eq!(text, 511);
+        assert_eq!(direct_map, 314);
+        let unused = [PageTableEntry::default(); 512];
+        let mut kernel = unused;
+        kernel[text] = PageTableEntry::from(0x1a0_f067);
+        kernel[direct_map] = PageTableEntry::from(0x2c0_1067);
+        let mut user = unused;
+        user[text] = PageTableEntry::from(0x1b4_4067);
+        assert!(user_pgd_isolated(&kernel, &user, direct_map, text));
+        assert!(!user_pgd_isolated(&kernel, &kernel, direct_map, text));
+        assert!(!user_pgd_isolated(&kernel, &unused, direct_map, text));
+    }
+
+    #[test]
+    fn test_shared_values() {
+        let values = vec![
+            (Pid::from_raw(1), 0x1e00_0000),
+            (Pid::from_raw(2), 0x3a00_0000),
+            (Pid::from_raw(3), 0x1e00_0000),
+        ];
+        assert_eq!(
+            shared_values(&values),
+            vec![(0x1e00_0000, vec![Pid::from_raw(1), Pid::from_raw(3)])]
+        );
+        assert!(shared_values(&values[..2]).is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+//! Check whether this host supports vmsh without touching a real VM, see `vmsh selftest`.
+//!
+//! vmsh forks a child that creates a minimal KVM VM with raw ioctls, the way a VMM like qemu
+//! does: one memslot, an in-kernel irqchip and a single vcpu thread that halts in a real mode
+//! loop. The parent then attaches to the child like to any other hypervisor and runs the same
+//! code paths that `vmsh attach` depends on: syscall injection, access to hypervisor and guest
+//! memory, adding and removing memslots and registering the eventfds of devices. A host that
+//! fails here, e.g. because of ptrace restrictions, a missing bcc or an old kernel, will not
+//! be able to attach to production VMs either.
+use kvm_bindings as kvmb;
+use libc::{c_int, c_ulong, c_void};
+use log::info;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{close, fork, pipe, read, write, ForkResult, Pid};
+use simple_error::{bail, try_with};
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::thread;
+
+use crate::kvm::hypervisor::{get_hypervisor, Hypervisor};
+use crate::kvm::ioctls;
+use crate::result::Result;
+
+/// Guest physical memory of the scratch VM, starting at address 0.
+const GUEST_MEM_SIZE: usize = 2 * 1024 * 1024;
+/// Where the vcpu starts executing `GUEST_CODE`.
+const CODE_ADDR: usize = 0x1000;
+/// `cli; hlt; jmp -3`. With interrupts disabled the in-kernel irqchip never wakes the vcpu, so it
+/// sleeps in KVM_RUN like an idle guest.
+const GUEST_CODE: &[u8] = &[0xfa, 0xf4, 0xeb, 0xfd];
+/// Scratch memory of the guest written by the guest memory check.
+const DATA_ADDR: usize = 0x2000;
+/// Below the BIOS area, where qemu places it as well. Only needed on Intel cpus without
+/// unrestricted guest support.
+const TSS_ADDR: c_ulong = 0xfffb_d000;
+/// Outside of `GUEST_MEM_SIZE`, used for memslots and ioeventfds added by the checks.
+const EXTRA_SLOT_ADDR: u64 = 0xd000_0000;
+const MMIO_ADDR: u64 = 0xd000_1000;
+/// Pin of the ioapic the irqfd is connected to.
+const IRQ_GSI: u32 = 5;
+
+fn kvm_ioctl(fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+    let ret = unsafe { libc::ioctl(fd, request as _, arg) };
+    if ret < 0 {
+        bail!("ioctl {:#x} failed: {}", request, Errno::last());
+    }
+    Ok(ret)
+}
+
+fn map_shared(len: usize, fd: RawFd) -> Result<*mut c_void> {
+    let flags = if fd < 0 {
+        libc::MAP_SHARED | libc::MAP_ANONYMOUS
+    } else {
+        libc::MAP_SHARED
+    };
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        bail!("mmap failed: {}", Errno::last());
+    }
+    Ok(addr)
+}
+
+/// Runs the vcpu until the parent kills us. Signals of ptrace interrupt KVM_RUN, which is
+/// restarted afterwards like in any VMM.
+fn run_vcpu(vcpu: RawFd, run: usize) {
+    let run = run as *const kvmb::kvm_run;
+    loop {
+        let ret = unsafe { libc::ioctl(vcpu, ioctls::KVM_RUN() as _, 0) };
+        if ret < 0 {
+            match Errno::last() {
+                Errno::EINTR | Errno::EAGAIN => continue,
+                e => {
+                    eprintln!("scratch vm: KVM_RUN failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        let reason = unsafe { (*run).exit_reason };
+        match reason {
+            kvmb::KVM_EXIT_SHUTDOWN | kvmb::KVM_EXIT_FAIL_ENTRY | kvmb::KVM_EXIT_INTERNAL_ERROR => {
+                eprintln!("scratch vm: vcpu stopped with exit reason {}", reason);
+                std::process::exit(1);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sets up the VM in the child and reports to `ready` once its vcpu runs.
+fn scratch_vm(ready: RawFd) -> Result<()> {
+    let kvm = try_with!(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open("/dev/kvm"),
+        "cannot open /dev/kvm"
+    );
+    let vm = try_with!(
+        kvm_ioctl(kvm.as_raw_fd(), ioctls::KVM_CREATE_VM(), 0),
+        "cannot create vm"
+    );
+
+    let mem = try_with!(
+        map_shared(GUEST_MEM_SIZE, -1),
+        "cannot allocate guest memory"
+    );
+    unsafe {
+        ptr::copy_nonoverlapping(
+            GUEST_CODE.as_ptr(),
+            (mem as *mut u8).add(CODE_ADDR),
+            GUEST_CODE.len(),
+        )
+    };
+    let region = kvmb::kvm_userspace_memory_region {
+        slot: 0,
+        flags: 0,
+        guest_phys_addr: 0,
+        memory_size: GUEST_MEM_SIZE as u64,
+        userspace_addr: mem as u64,
+    };
+    try_with!(
+        kvm_ioctl(
+            vm,
+            ioctls::KVM_SET_USER_MEMORY_REGION(),
+            &region as *const _ as c_ulong
+        ),
+        "cannot add guest memory"
+    );
+    try_with!(
+        kvm_ioctl(vm, ioctls::KVM_SET_TSS_ADDR(), TSS_ADDR),
+        "cannot set tss address"
+    );
+    try_with!(
+        kvm_ioctl(vm, ioctls::KVM_CREATE_IRQCHIP(), 0),
+        "cannot create irqchip"
+    );
+
+    let vcpu = try_with!(
+        kvm_ioctl(vm, ioctls::KVM_CREATE_VCPU(), 0),
+        "cannot create vcpu"
+    );
+    let run_size = try_with!(
+        kvm_ioctl(kvm.as_raw_fd(), ioctls::KVM_GET_VCPU_MMAP_SIZE(), 0),
+        "cannot get size of kvm_run"
+    );
+    let run = try_with!(
+        map_shared(run_size as usize, vcpu),
+        "cannot map kvm_run of vcpu"
+    );
+
+    let mut sregs = kvmb::kvm_sregs::default();
+    try_with!(
+        kvm_ioctl(
+            vcpu,
+            ioctls::KVM_GET_SREGS(),
+            &mut sregs as *mut _ as c_ulong
+        ),
+        "cannot get special registers"
+    );
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    try_with!(
+        kvm_ioctl(vcpu, ioctls::KVM_SET_SREGS(), &sregs as *const _ as c_ulong),
+        "cannot set special registers"
+    );
+    let regs = kvmb::kvm_regs {
+        rip: CODE_ADDR as u64,
+        rflags: 0x2, // reserved bit that is always set
+        ..Default::default()
+    };
+    try_with!(
+        kvm_ioctl(vcpu, ioctls::KVM_SET_REGS(), &regs as *const _ as c_ulong),
+        "cannot set registers"
+    );
+
+    let run = run as usize;
+    // like qemu, the vcpu gets its own thread and the main thread waits
+    thread::spawn(move || run_vcpu(vcpu, run));
+    try_with!(write(ready, b"r"), "cannot signal readiness");
+    loop {
+        nix::unistd::pause();
+    }
+}
+
+/// The forked child running the VM. Killed on drop.
+struct ScratchVm {
+    pid: Pid,
+}
+
+impl ScratchVm {
+    fn spawn() -> Result<ScratchVm> {
+        let (ready_read, ready_write) = try_with!(pipe(), "cannot create pipe");
+        match try_with!(unsafe { fork() }, "cannot fork scratch vm") {
+            ForkResult::Child => {
+                let _ = close(ready_read);
+                if let Err(e) = scratch_vm(ready_write) {
+                    eprintln!("scratch vm: {}", e);
+                }
+                std::process::exit(1);
+            }
+            ForkResult::Parent { child } => {
+                let _ = close(ready_write);
+                let vm = ScratchVm { pid: child };
+                let mut buf = [0u8; 1];
+                let n = read(ready_read, &mut buf);
+                let _ = close(ready_read);
+                match n {
+                    Ok(1) => Ok(vm),
+                    Ok(_) => bail!("scratch vm exited before its vcpu was running"),
+                    Err(e) => bail!("cannot wait for scratch vm: {}", e),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ScratchVm {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+    }
+}
+
+fn check_injection(vm: &Hypervisor) -> Result<()> {
+    // every stop attaches to all threads and every resume lets the vcpu run again
+    for _ in 0..10 {
+        let _stopped = vm.stop_guard()?;
+        try_with!(
+            vm.check_extension(kvmb::KVM_CAP_USER_MEMORY as c_int),
+            "cannot query kvm extensions"
+        );
+    }
+    Ok(())
+}
+
+fn check_registers(vm: &Hypervisor) -> Result<()> {
+    let _stopped = vm.stop_guard()?;
+    let vcpu = &vm.vcpus[0];
+    let ip = vm.get_regs(vcpu)?.ip() as usize;
+    if ip < CODE_ADDR || ip >= CODE_ADDR + GUEST_CODE.len() {
+        bail!(
+            "vcpu is at {:#x}, expected it in the guest loop at {:#x}",
+            ip,
+            CODE_ADDR
+        );
+    }
+    let sregs = vm.get_sregs(vcpu)?;
+    if sregs.cs.base != 0 {
+        bail!("unexpected code segment base {:#x}", sregs.cs.base);
+    }
+    vm.get_fpu_regs(vcpu)?;
+    Ok(())
+}
+
+fn check_hypervisor_memory(vm: &Hypervisor) -> Result<()> {
+    let _stopped = vm.stop_guard()?;
+    let mem = try_with!(vm.alloc_mem::<u64>(), "cannot allocate hypervisor memory");
+    mem.write(&0xdead_beef)?;
+    let val = mem.read()?;
+    if val != 0xdead_beef {
+        bail!("read {:#x} after writing 0xdeadbeef", val);
+    }
+    Ok(())
+}
+
+fn check_guest_memory(vm: &Hypervisor) -> Result<()> {
+    let _stopped = vm.stop_guard()?;
+    let mut code = vec![0u8; GUEST_CODE.len()];
+    vm.read_guest_phys(CODE_ADDR, &mut code)?;
+    if code != GUEST_CODE {
+        bail!(
+            "guest code reads as {:x?}, expected {:x?}",
+            code,
+            GUEST_CODE
+        );
+    }
+    let data = [0xde, 0xad, 0xbe, 0xef];
+    vm.write_guest_phys(DATA_ADDR, &data)?;
+    let mut read = [0u8; 4];
+    vm.read_guest_phys(DATA_ADDR, &mut read)?;
+    if read != data {
+        bail!(
+            "guest memory reads as {:x?} after writing {:x?}",
+            read,
+            data
+        );
+    }
+    Ok(())
+}
+
+fn check_memslots(vm: &Hypervisor) -> Result<()> {
+    let _stopped = vm.stop_guard()?;
+    let before = vm.get_maps()?.len();
+    {
+        let mem = vm.vm_add_mem::<u64>(EXTRA_SLOT_ADDR, 8, false)?;
+        mem.mem.write(&0xdead_beef)?;
+        let after = vm.get_maps()?.len();
+        if after != before + 1 {
+            bail!("found {} memslots after adding one to {}", after, before);
+        }
+        let mut read = [0u8; 8];
+        vm.read_guest_phys(EXTRA_SLOT_ADDR as usize, &mut read)?;
+        if u64::from_ne_bytes(read) != 0xdead_beef {
+            bail!("added memslot is not visible at its guest address");
+        }
+    }
+    let removed = vm.get_maps()?.len();
+    if removed != before {
+        bail!(
+            "found {} memslots after removing the added one, expected {}",
+            removed,
+            before
+        );
+    }
+    Ok(())
+}
+
+fn check_device_fds(vm: &Hypervisor) -> Result<()> {
+    let _stopped = vm.stop_guard()?;
+    for (cap, name) in &[
+        (kvmb::KVM_CAP_IOEVENTFD, "KVM_CAP_IOEVENTFD"),
+        (kvmb::KVM_CAP_IRQFD, "KVM_CAP_IRQFD"),
+    ] {
+        if vm.check_extension(*cap as c_int)? == 0 {
+            bail!("kvm does not support {}", name);
+        }
+    }
+    let _ioeventfd = try_with!(vm.ioeventfd(MMIO_ADDR), "cannot register ioeventfd");
+    let _irqfd = try_with!(vm.irqfd(IRQ_GSI), "cannot register irqfd");
+    Ok(())
+}
+
+type Check = fn(&Hypervisor) -> Result<()>;
+
+const CHECKS: &[(&str, Check)] = &[
+    ("syscall injection", check_injection),
+    ("vcpu registers", check_registers),
+    ("hypervisor memory", check_hypervisor_memory),
+    ("guest memory", check_guest_memory),
+    ("memslots", check_memslots),
+    ("device eventfds", check_device_fds),
+];
+
+pub fn selftest() -> Result<()> {
+    let scratch = try_with!(
+        ScratchVm::spawn(),
+        "cannot start scratch vm, does this host support kvm?"
+    );
+    info!("started scratch vm in process {}", scratch.pid);
+    let vm = try_with!(
+        get_hypervisor(scratch.pid),
+        "cannot attach to scratch vm in process {}",
+        scratch.pid
+    );
+
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        match check(&vm) {
+            Ok(()) => println!("{}: ok", name),
+            Err(e) => {
+                println!("{}: FAILED: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} checks failed, vmsh will not work reliably on this host",
+            failed,
+            CHECKS.len()
+        );
+    }
+    println!("all checks passed");
+    Ok(())
+}
@@ -1,9 +1,11 @@
-use std::sync::{mpsc::SyncSender, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+use std::sync::Mutex;
 
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::{error, info, warn};
 use nix::sys::signal;
-use simple_error::try_with;
+use simple_error::{bail, try_with};
 
 use crate::result::Result;
 
@@ -39,12 +41,12 @@ extern "C" fn signal_handler(_: ::libc::c_int) {
 
 pub fn setup(sender: &SyncSender<()>) -> Result<()> {
     try_with!(SIGNAL_SENDER.lock(), "cannot get lock").replace(sender.clone());
+    set_handler(signal::SigHandler::Handler(signal_handler))
+}
 
-    let sig_action = signal::SigAction::new(
-        signal::SigHandler::Handler(signal_handler),
-        signal::SaFlags::empty(),
-        signal::SigSet::empty(),
-    );
+fn set_handler(handler: signal::SigHandler) -> Result<()> {
+    let sig_action =
+        signal::SigAction::new(handler, signal::SaFlags::empty(), signal::SigSet::empty());
 
     unsafe {
         try_with!(
@@ -58,3 +60,59 @@ pub fn setup(sender: &SyncSender<()>) -> Result<()> {
     }
     Ok(())
 }
+
+/// Lets long operations like coredumps stop early on SIGINT or SIGTERM, so that they can clean up
+/// and resume the guest instead of being killed while the guest is stopped.
+pub struct Cancellation {
+    /// Locked so that the threads of `vmsh coredump --group` can share one cancellation.
+    receiver: Mutex<Receiver<()>>,
+    cancelled: AtomicBool,
+}
+
+impl Cancellation {
+    /// Catch SIGINT and SIGTERM until the returned value is dropped.
+    pub fn setup() -> Result<Cancellation> {
+        let (sender, receiver) = sync_channel(1);
+        setup(&sender)?;
+        Ok(Cancellation {
+            receiver: Mutex::new(receiver),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return true;
+        }
+        let received = match self.receiver.lock() {
+            Ok(receiver) => receiver.try_recv(),
+            Err(_) => Err(TryRecvError::Disconnected),
+        };
+        match received {
+            Ok(()) | Err(TryRecvError::Disconnected) => {
+                self.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+        }
+    }
+
+    /// Cancellation point: fails once a signal was received.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("interrupted by signal");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Ok(mut sender) = SIGNAL_SENDER.lock() {
+            sender.take();
+        }
+        if let Err(e) = set_handler(signal::SigHandler::SigDfl) {
+            warn!("cannot restore signal handlers: {}", e);
+        }
+    }
+}
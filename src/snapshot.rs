@@ -0,0 +1,757 @@
+//! Save the state of a VM to a file and write it back, see `vmsh snapshot` and `vmsh restore`.
+//!
+//! A snapshot starts with `MAGIC`, the version of `format::SNAPSHOT` and the number of vcpus
+//! (u32 each), followed by records of `kind: u32, index: u32, len: u64` and `len` bytes of
+//! payload. The payload of kvm state records is the struct of the corresponding ioctl, so
+//! snapshots can only be restored on the same architecture. Memory records contain the guest
+//! physical address of a memslot (u64) followed by its content. Records are written in the order
+//! they are restored: vm state, vcpu state and memory.
+//!
+//! State of the device models in the hypervisor's userspace is not part of the snapshot, so a
+//! snapshot should only be restored into the VM it was taken from or one with the same hypervisor
+//! configuration.
+use kvm_bindings as kvmb;
+use log::{debug, info, warn};
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::ptr;
+
+use crate::coredump::{self, any_as_bytes};
+use crate::encrypt::Encryptor;
+use crate::format::SNAPSHOT;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::kvm::ioctls;
+use crate::kvm::memslots;
+use crate::kvm::tracee::kvm_msrs;
+use crate::manifest::{self, Provenance};
+use crate::result::Result;
+use crate::signal_handler::Cancellation;
+use crate::tracer::proc::Mapping;
+
+pub struct SnapshotOptions {
+    pub pid: Pid,
+    pub path: PathBuf,
+    /// age or gpg recipient the snapshot is encrypted for, see `encrypt`. Encrypted snapshots
+    /// have to be decrypted before they can be restored.
+    pub encrypt_to: Option<String>,
+}
+
+pub const MAGIC: &[u8; 8] = b"VMSHSNAP";
+
+/// Size of `kind`, `index` and `len` of a record.
+const RECORD_HEADER_SIZE: usize = 4 + 4 + 8;
+/// kvm state records are much smaller, the largest is xsave with 4 KiB.
+const MAX_STATE_SIZE: u64 = 64 * 1024;
+/// Size of reads and writes of guest memory.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of irqchips of the in-kernel irqchip on x86 (2 pics and the ioapic).
+const IRQCHIPS: u32 = 3;
+
+/// Architectural and paravirtual msrs that are not covered by the other vcpu state.
+const SNAPSHOT_MSRS: &[u32] = &[
+    0x10,        // IA32_TSC
+    0x174,       // IA32_SYSENTER_CS
+    0x175,       // IA32_SYSENTER_ESP
+    0x176,       // IA32_SYSENTER_EIP
+    0x1a0,       // IA32_MISC_ENABLE
+    0x277,       // IA32_PAT
+    0xc000_0080, // EFER
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SYSCALL_MASK
+    0xc000_0102, // KERNEL_GS_BASE
+    0xc000_0103, // TSC_AUX
+    0x4b56_4d00, // KVM_WALL_CLOCK_NEW
+    0x4b56_4d01, // KVM_SYSTEM_TIME_NEW
+    0x4b56_4d02, // KVM_ASYNC_PF_EN
+    0x4b56_4d03, // KVM_STEAL_TIME
+    0x4b56_4d04, // KVM_PV_EOI_EN
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+enum Kind {
+    Clock = 1,
+    Irqchip = 2,
+    Pit = 3,
+    Sregs = 10,
+    Msrs = 11,
+    Regs = 12,
+    Fpu = 13,
+    Xsave = 14,
+    Xcrs = 15,
+    Lapic = 16,
+    Events = 17,
+    MpState = 18,
+    Memory = 20,
+}
+
+impl Kind {
+    fn from_u32(kind: u32) -> Option<Kind> {
+        Some(match kind {
+            1 => Kind::Clock,
+            2 => Kind::Irqchip,
+            3 => Kind::Pit,
+            10 => Kind::Sregs,
+            11 => Kind::Msrs,
+            12 => Kind::Regs,
+            13 => Kind::Fpu,
+            14 => Kind::Xsave,
+            15 => Kind::Xcrs,
+            16 => Kind::Lapic,
+            17 => Kind::Events,
+            18 => Kind::MpState,
+            20 => Kind::Memory,
+            _ => return None,
+        })
+    }
+
+    /// Vcpu state records use the vcpu index as record index.
+    fn is_vcpu_state(self) -> bool {
+        (Kind::Sregs as u32..=Kind::MpState as u32).contains(&(self as u32))
+    }
+}
+
+struct RecordHeader {
+    kind: Kind,
+    index: u32,
+    len: u64,
+}
+
+fn write_record(out: &mut dyn Write, kind: Kind, index: u32, payload: &[u8]) -> Result<()> {
+    write_record_header(out, kind, index, payload.len() as u64)?;
+    try_with!(out.write_all(payload), "cannot write snapshot");
+    Ok(())
+}
+
+fn write_record_header(out: &mut dyn Write, kind: Kind, index: u32, len: u64) -> Result<()> {
+    let mut header = Vec::with_capacity(RECORD_HEADER_SIZE);
+    header.extend_from_slice(&(kind as u32).to_le_bytes());
+    header.extend_from_slice(&index.to_le_bytes());
+    header.extend_from_slice(&len.to_le_bytes());
+    try_with!(out.write_all(&header), "cannot write snapshot");
+    Ok(())
+}
+
+/// Returns None at the end of the file.
+fn read_record_header(input: &mut dyn Read) -> Result<Option<RecordHeader>> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    match input.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => bail!("cannot read snapshot: {}", e),
+    }
+    try_with!(
+        input.read_exact(&mut header[1..]),
+        "snapshot ends within a record header"
+    );
+    let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    Ok(Some(RecordHeader {
+        kind: require_with!(
+            Kind::from_u32(kind),
+            "unknown record {} in snapshot, it might be written by a newer vmsh",
+            kind
+        ),
+        index: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        len: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+    }))
+}
+
+fn write_file_header(out: &mut dyn Write, vcpus: u32) -> Result<()> {
+    try_with!(out.write_all(MAGIC), "cannot write snapshot");
+    try_with!(
+        out.write_all(&SNAPSHOT.current.to_le_bytes()),
+        "cannot write snapshot"
+    );
+    try_with!(out.write_all(&vcpus.to_le_bytes()), "cannot write snapshot");
+    Ok(())
+}
+
+/// Returns the number of vcpus.
+fn read_file_header(input: &mut dyn Read) -> Result<u32> {
+    let mut header = [0u8; 16];
+    try_with!(input.read_exact(&mut header), "cannot read snapshot header");
+    if &header[..8] != MAGIC {
+        bail!("not a vmsh snapshot, encrypted snapshots have to be decrypted first");
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    SNAPSHOT.check(version)?;
+    Ok(u32::from_le_bytes(header[12..16].try_into().unwrap()))
+}
+
+fn from_bytes<T: Copy>(payload: &[u8]) -> Result<T> {
+    if payload.len() != size_of::<T>() {
+        bail!(
+            "record has {} bytes, expected {}",
+            payload.len(),
+            size_of::<T>()
+        );
+    }
+    // safe, kvm structs are plain old data
+    Ok(unsafe { ptr::read_unaligned(payload.as_ptr() as *const T) })
+}
+
+/// Read a kvm struct with `request` and store it as record.
+fn save<T: Copy>(
+    out: &mut dyn Write,
+    vm: &Hypervisor,
+    vcpu: Option<&VCPU>,
+    request: libc::c_ulong,
+    kind: Kind,
+    index: u32,
+    arg: T,
+) -> Result<()> {
+    let (_, val) = vm.ioctl_with_copy(vcpu, request, &arg)?;
+    write_record(out, kind, index, unsafe { any_as_bytes(&val) })
+}
+
+/// Like `save`, but for state that depends on optional kvm features, i.e. the in-kernel irqchip.
+fn save_optional<T: Copy>(
+    out: &mut dyn Write,
+    vm: &Hypervisor,
+    vcpu: Option<&VCPU>,
+    request: libc::c_ulong,
+    kind: Kind,
+    index: u32,
+    arg: T,
+) -> Result<()> {
+    match vm.ioctl_with_copy(vcpu, request, &arg) {
+        Ok((_, val)) => write_record(out, kind, index, unsafe { any_as_bytes(&val) }),
+        Err(e) => {
+            debug!("skip {:?} {}: {}", kind, index, e);
+            Ok(())
+        }
+    }
+}
+
+fn save_msrs(out: &mut dyn Write, vm: &Hypervisor, vcpu: &VCPU) -> Result<()> {
+    let mut payload = vec![];
+    for index in SNAPSHOT_MSRS {
+        let msrs = kvm_msrs {
+            nmsrs: 1,
+            pad: 0,
+            entries: [kvmb::kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            }],
+        };
+        // returns the number of msrs read, which is 0 if kvm does not know the msr
+        let (n, msrs) = vm.ioctl_with_copy(Some(vcpu), ioctls::KVM_GET_MSRS(), &msrs)?;
+        if n == 1 {
+            payload.extend_from_slice(unsafe { any_as_bytes(&msrs.entries[0]) });
+        }
+    }
+    write_record(out, Kind::Msrs, vcpu.idx as u32, &payload)
+}
+
+fn save_vm_state(out: &mut dyn Write, vm: &Hypervisor) -> Result<()> {
+    save(
+        out,
+        vm,
+        None,
+        ioctls::KVM_GET_CLOCK(),
+        Kind::Clock,
+        0,
+        kvmb::kvm_clock_data::default(),
+    )?;
+    for chip in 0..IRQCHIPS {
+        let arg = kvmb::kvm_irqchip {
+            chip_id: chip,
+            ..Default::default()
+        };
+        save_optional(
+            out,
+            vm,
+            None,
+            ioctls::KVM_GET_IRQCHIP(),
+            Kind::Irqchip,
+            chip,
+            arg,
+        )?;
+    }
+    save_optional(
+        out,
+        vm,
+        None,
+        ioctls::KVM_GET_PIT2(),
+        Kind::Pit,
+        0,
+        kvmb::kvm_pit_state2::default(),
+    )
+}
+
+fn save_vcpu_state(out: &mut dyn Write, vm: &Hypervisor, vcpu: &VCPU) -> Result<()> {
+    let v = Some(vcpu);
+    let i = vcpu.idx as u32;
+    save(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_SREGS(),
+        Kind::Sregs,
+        i,
+        kvmb::kvm_sregs::default(),
+    )?;
+    save_msrs(out, vm, vcpu)?;
+    save(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_REGS(),
+        Kind::Regs,
+        i,
+        kvmb::kvm_regs::default(),
+    )?;
+    save(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_FPU(),
+        Kind::Fpu,
+        i,
+        kvmb::kvm_fpu::default(),
+    )?;
+    save_optional(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_XSAVE(),
+        Kind::Xsave,
+        i,
+        kvmb::kvm_xsave::default(),
+    )?;
+    save_optional(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_XCRS(),
+        Kind::Xcrs,
+        i,
+        kvmb::kvm_xcrs::default(),
+    )?;
+    save_optional(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_LAPIC(),
+        Kind::Lapic,
+        i,
+        kvmb::kvm_lapic_state::default(),
+    )?;
+    save(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_VCPU_EVENTS(),
+        Kind::Events,
+        i,
+        kvmb::kvm_vcpu_events::default(),
+    )?;
+    save(
+        out,
+        vm,
+        v,
+        ioctls::KVM_GET_MP_STATE(),
+        Kind::MpState,
+        i,
+        kvmb::kvm_mp_state::default(),
+    )
+}
+
+fn save_memory(
+    out: &mut dyn Write,
+    pid: Pid,
+    slot: u32,
+    m: &Mapping,
+    cancel: &Cancellation,
+) -> Result<()> {
+    write_record_header(out, Kind::Memory, slot, 8 + m.size() as u64)?;
+    try_with!(
+        out.write_all(&(m.phys_addr as u64).to_le_bytes()),
+        "cannot write snapshot"
+    );
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut pos = 0;
+    while pos < m.size() {
+        cancel.check()?;
+        let n = std::cmp::min(buf.len(), m.size() - pos);
+        let dst_iovs = [IoVec::from_mut_slice(&mut buf[..n])];
+        let src_iovs = [RemoteIoVec {
+            base: m.start + pos,
+            len: n,
+        }];
+        try_with!(
+            process_vm_readv(pid, &dst_iovs, &src_iovs),
+            "cannot read hypervisor memory"
+        );
+        try_with!(out.write_all(&buf[..n]), "cannot write snapshot");
+        pos += n;
+    }
+    Ok(())
+}
+
+/// Ctrl-C aborts the snapshot, removes the incomplete file if we created it and resumes the
+/// guest.
+pub fn snapshot(opts: &SnapshotOptions) -> Result<()> {
+    let cancel = Cancellation::setup()?;
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let stopped = vm.stop_guard()?;
+    let started = coredump::unix_time();
+    let (created, res) = match &opts.encrypt_to {
+        Some(recipient) => {
+            let created = !opts.path.exists();
+            let res = Encryptor::spawn(recipient, &opts.path).and_then(|mut encryptor| {
+                write_snapshot(opts, &vm, encryptor.stdin()?, &cancel)?;
+                encryptor.finish()
+            });
+            (created, res)
+        }
+        None => {
+            let (mut file, created) = coredump::create_output(&opts.path)?;
+            (created, write_snapshot(opts, &vm, &mut file, &cancel))
+        }
+    };
+    drop(stopped);
+    // checksums are computed once the guest runs again
+    let provenance = Provenance {
+        pid: opts.pid,
+        started,
+        finished: coredump::unix_time(),
+    };
+    let res = res.and_then(|()| manifest::write_manifest(&opts.path, &[], &provenance));
+    if res.is_err() {
+        if cancel.is_cancelled() {
+            warn!("snapshot cancelled");
+        }
+        // a manifest of an earlier snapshot would not match anymore
+        let mut partial = vec![manifest::manifest_path(&opts.path)];
+        if created {
+            partial.push(opts.path.clone());
+        }
+        for path in &partial {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("cannot remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Requires the hypervisor to be stopped.
+fn write_snapshot(
+    opts: &SnapshotOptions,
+    vm: &Hypervisor,
+    out: &mut dyn Write,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let maps = vm.get_maps()?;
+    let mut out = BufWriter::new(out);
+    write_file_header(&mut out, vm.vcpus.len() as u32)?;
+    save_vm_state(&mut out, vm)?;
+    for vcpu in &vm.vcpus {
+        try_with!(
+            save_vcpu_state(&mut out, vm, vcpu),
+            "cannot save state of vcpu {}",
+            vcpu.idx
+        );
+    }
+    for (slot, m) in maps.iter().enumerate() {
+        save_memory(&mut out, opts.pid, slot as u32, m, cancel)?;
+    }
+    try_with!(out.flush(), "cannot write snapshot");
+    info!(
+        "saved {} vcpus and {} memslots ({} MiB)",
+        vm.vcpus.len(),
+        maps.len(),
+        maps.iter().map(|m| m.size()).sum::<usize>() / 1024 / 1024
+    );
+    Ok(())
+}
+
+/// Guest memory in a snapshot, i.e. to checksum it for `manifest`.
+pub struct MemoryRegion {
+    pub phys_addr: u64,
+    /// File offset of the memory
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Memory records of the snapshot `input`, which is read from the start.
+pub fn memory_regions<R: Read + Seek>(input: &mut R) -> Result<Vec<MemoryRegion>> {
+    try_with!(input.seek(SeekFrom::Start(0)), "cannot read snapshot");
+    read_file_header(input)?;
+    let mut regions = vec![];
+    while let Some(header) = read_record_header(input)? {
+        let start = try_with!(input.seek(SeekFrom::Current(0)), "cannot read snapshot");
+        if header.kind == Kind::Memory && header.len >= 8 {
+            let mut phys_addr = [0u8; 8];
+            try_with!(input.read_exact(&mut phys_addr), "cannot read snapshot");
+            regions.push(MemoryRegion {
+                phys_addr: u64::from_le_bytes(phys_addr),
+                offset: start + 8,
+                size: header.len - 8,
+            });
+        }
+        try_with!(
+            input.seek(SeekFrom::Start(start + header.len)),
+            "cannot read snapshot"
+        );
+    }
+    Ok(regions)
+}
+
+/// Memslot of the VM that has the guest physical address and size of a memory record.
+fn find_memslot(maps: &[Mapping], phys_addr: usize, size: usize) -> Option<&Mapping> {
+    maps.iter()
+        .find(|m| m.phys_addr == phys_addr && m.size() == size)
+}
+
+/// Check that the snapshot fits into the VM before anything is modified.
+fn check_compatible(input: &mut BufReader<File>, vm: &Hypervisor, maps: &[Mapping]) -> Result<()> {
+    let vcpus = read_file_header(input)?;
+    if vcpus as usize != vm.vcpus.len() {
+        bail!(
+            "snapshot has {} vcpus, but the VM has {}",
+            vcpus,
+            vm.vcpus.len()
+        );
+    }
+    while let Some(header) = read_record_header(input)? {
+        if header.kind.is_vcpu_state() && !vm.vcpus.iter().any(|v| v.idx == header.index as usize) {
+            bail!(
+                "vcpu {} of the snapshot does not exist in the VM",
+                header.index
+            );
+        }
+        if header.kind != Kind::Memory && header.len > MAX_STATE_SIZE {
+            bail!("{:?} record is too large: {}", header.kind, header.len);
+        }
+        let mut skip = header.len;
+        if header.kind == Kind::Memory {
+            if header.len < 8 {
+                bail!("memory record is too short");
+            }
+            let mut phys_addr = [0u8; 8];
+            try_with!(input.read_exact(&mut phys_addr), "cannot read snapshot");
+            let phys_addr = u64::from_le_bytes(phys_addr) as usize;
+            let size = (header.len - 8) as usize;
+            if find_memslot(maps, phys_addr, size).is_none() {
+                bail!(
+                    "VM has no memslot at {:#x} with {:#x} bytes like the snapshot",
+                    phys_addr,
+                    size
+                );
+            }
+            skip = size as u64;
+        }
+        try_with!(
+            input.seek(SeekFrom::Current(skip as i64)),
+            "cannot read snapshot"
+        );
+    }
+    Ok(())
+}
+
+fn restore_memory(input: &mut dyn Read, pid: Pid, m: &Mapping) -> Result<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut pos = 0;
+    while pos < m.size() {
+        let n = std::cmp::min(buf.len(), m.size() - pos);
+        try_with!(
+            input.read_exact(&mut buf[..n]),
+            "cannot read memory from snapshot"
+        );
+        let src_iovs = [IoVec::from_slice(&buf[..n])];
+        let dst_iovs = [RemoteIoVec {
+            base: m.start + pos,
+            len: n,
+        }];
+        try_with!(
+            process_vm_writev(pid, &src_iovs, &dst_iovs),
+            "cannot write hypervisor memory"
+        );
+        pos += n;
+    }
+    Ok(())
+}
+
+fn restore_msrs(vm: &Hypervisor, vcpu: &VCPU, payload: &[u8]) -> Result<()> {
+    let entry_size = size_of::<kvmb::kvm_msr_entry>();
+    if payload.len() % entry_size != 0 {
+        bail!("msr record has an invalid size");
+    }
+    for entry in payload.chunks(entry_size) {
+        let entry = from_bytes::<kvmb::kvm_msr_entry>(entry)?;
+        let msrs = kvm_msrs {
+            nmsrs: 1,
+            pad: 0,
+            entries: [entry],
+        };
+        let (n, _) = vm.ioctl_with_copy(Some(vcpu), ioctls::KVM_SET_MSRS(), &msrs)?;
+        if n != 1 {
+            warn!("vcpu {}: cannot set msr {:#x}", vcpu.idx, entry.index);
+        }
+    }
+    Ok(())
+}
+
+/// Write a kvm state record back with the matching set ioctl.
+fn restore_state(vm: &Hypervisor, header: &RecordHeader, payload: &[u8]) -> Result<()> {
+    let vcpu = if header.kind.is_vcpu_state() {
+        // checked by check_compatible
+        vm.vcpus.iter().find(|v| v.idx == header.index as usize)
+    } else {
+        None
+    };
+    fn set<T: Copy>(
+        vm: &Hypervisor,
+        vcpu: Option<&VCPU>,
+        request: libc::c_ulong,
+        payload: &[u8],
+    ) -> Result<()> {
+        vm.ioctl_with_copy(vcpu, request, &from_bytes::<T>(payload)?)?;
+        Ok(())
+    }
+    match header.kind {
+        Kind::Clock => {
+            let mut clock = from_bytes::<kvmb::kvm_clock_data>(payload)?;
+            // older kernels reject all flags
+            clock.flags = 0;
+            vm.ioctl_with_copy(None, ioctls::KVM_SET_CLOCK(), &clock)?;
+            Ok(())
+        }
+        Kind::Irqchip => set::<kvmb::kvm_irqchip>(vm, None, ioctls::KVM_SET_IRQCHIP(), payload),
+        Kind::Pit => set::<kvmb::kvm_pit_state2>(vm, None, ioctls::KVM_SET_PIT2(), payload),
+        Kind::Sregs => set::<kvmb::kvm_sregs>(vm, vcpu, ioctls::KVM_SET_SREGS(), payload),
+        Kind::Msrs => restore_msrs(vm, vcpu.unwrap(), payload),
+        Kind::Regs => set::<kvmb::kvm_regs>(vm, vcpu, ioctls::KVM_SET_REGS(), payload),
+        Kind::Fpu => set::<kvmb::kvm_fpu>(vm, vcpu, ioctls::KVM_SET_FPU(), payload),
+        Kind::Xsave => set::<kvmb::kvm_xsave>(vm, vcpu, ioctls::KVM_SET_XSAVE(), payload),
+        Kind::Xcrs => set::<kvmb::kvm_xcrs>(vm, vcpu, ioctls::KVM_SET_XCRS(), payload),
+        Kind::Lapic => set::<kvmb::kvm_lapic_state>(vm, vcpu, ioctls::KVM_SET_LAPIC(), payload),
+        Kind::Events => {
+            set::<kvmb::kvm_vcpu_events>(vm, vcpu, ioctls::KVM_SET_VCPU_EVENTS(), payload)
+        }
+        Kind::MpState => set::<kvmb::kvm_mp_state>(vm, vcpu, ioctls::KVM_SET_MP_STATE(), payload),
+        Kind::Memory => unreachable!("memory is not kvm state"),
+    }
+}
+
+pub fn restore(opts: &SnapshotOptions) -> Result<()> {
+    memslots::check_writable()?;
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    let maps = vm.get_maps()?;
+    let file = try_with!(
+        File::open(&opts.path),
+        "cannot open {}",
+        opts.path.display()
+    );
+    let mut input = BufReader::new(file);
+    check_compatible(&mut input, &vm, &maps)?;
+
+    try_with!(input.seek(SeekFrom::Start(0)), "cannot read snapshot");
+    read_file_header(&mut input)?;
+    while let Some(header) = read_record_header(&mut input)? {
+        if header.kind == Kind::Memory {
+            let mut phys_addr = [0u8; 8];
+            try_with!(input.read_exact(&mut phys_addr), "cannot read snapshot");
+            let phys_addr = u64::from_le_bytes(phys_addr) as usize;
+            let m = find_memslot(&maps, phys_addr, header.len as usize - 8).unwrap();
+            restore_memory(&mut input, opts.pid, m)?;
+            continue;
+        }
+        let mut payload = vec![0u8; header.len as usize];
+        try_with!(input.read_exact(&mut payload), "cannot read snapshot");
+        try_with!(
+            restore_state(&vm, &header, &payload),
+            "cannot restore {:?} {}",
+            header.kind,
+            header.index
+        );
+    }
+    info!("restored {}", opts.path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_records() {
+        let mut buf = vec![];
+        write_file_header(&mut buf, 2).unwrap();
+        write_record(&mut buf, Kind::Regs, 1, &[1, 2, 3]).unwrap();
+        write_record(&mut buf, Kind::Clock, 0, &[]).unwrap();
+
+        let mut input = Cursor::new(buf);
+        assert_eq!(read_file_header(&mut input).unwrap(), 2);
+        let header = read_record_header(&mut input).unwrap().unwrap();
+        assert_eq!(header.kind, Kind::Regs);
+        assert_eq!(header.index, 1);
+        assert_eq!(header.len, 3);
+        input.seek(SeekFrom::Current(3)).unwrap();
+        let header = read_record_header(&mut input).unwrap().unwrap();
+        assert_eq!(header.kind, Kind::Clock);
+        assert_eq!(header.len, 0);
+        assert!(read_record_header(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        let mut buf = vec![];
+        write_file_header(&mut buf, 1).unwrap();
+        write_record(&mut buf, Kind::Regs, 0, &[1, 2, 3]).unwrap();
+        let mut memory = 0x1000u64.to_le_bytes().to_vec();
+        memory.extend_from_slice(&[4, 5, 6, 7]);
+        write_record(&mut buf, Kind::Memory, 0, &memory).unwrap();
+
+        let regions = memory_regions(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].phys_addr, 0x1000);
+        assert_eq!(regions[0].offset, 16 + 16 + 3 + 16 + 8);
+        assert_eq!(regions[0].size, 4);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut input = Cursor::new(b"VMSHSNAP\x02\x00\x00\x00\x01\x00\x00\x00".to_vec());
+        assert!(read_file_header(&mut input).is_err());
+        let mut input = Cursor::new(
+            b"\x63\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec(),
+        );
+        assert!(read_record_header(&mut input).is_err());
+        // truncated header
+        let mut input = Cursor::new(vec![1u8, 0, 0]);
+        assert!(read_record_header(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_vcpu_state_kinds() {
+        assert!(Kind::Regs.is_vcpu_state());
+        assert!(Kind::MpState.is_vcpu_state());
+        assert!(!Kind::Clock.is_vcpu_state());
+        assert!(!Kind::Memory.is_vcpu_state());
+    }
+}
@@ -1,10 +1,11 @@
 #![no_std]
 
-use chlorine::{c_char, c_ulonglong};
+use chlorine::{c_char, c_int, c_longlong, c_uint, c_ulonglong, c_void};
 
-/// Holds the device we create by this code, so we can unregister it later
-pub const MAX_DEVICES: usize = 3;
-pub const MAX_ARGV: usize = 256;
+/// Value of `Stage1Args::magic`, "VMSH" in little endian
+pub const STAGE1_ARGS_MAGIC: c_uint = 0x4853_4d56;
+/// Incremented whenever the layout or the meaning of `Stage1Args` changes
+pub const STAGE1_ABI_VERSION: c_uint = 6;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 #[repr(C)]
@@ -16,13 +17,104 @@ pub enum DeviceState {
     Error = 4,
 }
 
+/// What stage1 does with `HotplugMemory`
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub enum HotplugAction {
+    /// Add the memory on attach and remove it again on detach
+    Attach = 0,
+    /// Only add the memory and keep it, see `vmsh mem add`
+    Add = 1,
+    /// Only remove memory of a previous `vmsh mem add`
+    Remove = 2,
+}
+
+/// What stage1 does with the devices in `Stage1Args`
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub enum DeviceAction {
+    /// Register the devices, start stage2 and unregister them again on detach
+    Attach = 0,
+    /// Only register the devices and keep them, see `vmsh device add-blk`
+    Add = 1,
+    /// Only unregister the devices in `device_handles`, see `vmsh device remove`
+    Remove = 2,
+}
+
+/// Guest physical memory that stage1 adds to the guest as hotplugged memory
+#[repr(C)]
+pub struct HotplugMemory {
+    /// physical address, memory is only hotplugged if `size` is not 0
+    pub start: c_ulonglong,
+    pub size: c_ulonglong,
+    /// numa node the memory is added to
+    pub node: c_int,
+    /// Address of `add_memory_driver_managed()`. Resolved by vmsh, because the function only
+    /// exists in kernels with CONFIG_MEMORY_HOTPLUG.
+    pub add_memory: c_ulonglong,
+    /// Address of `offline_and_remove_memory()`
+    pub remove_memory: c_ulonglong,
+    /// `offline_and_remove_memory()` takes the numa node as first argument before linux 5.15
+    pub remove_with_node: bool,
+    pub action: HotplugAction,
+    /// Ready while the memory is plugged, Terminating once it was removed again
+    pub status: DeviceState,
+}
+
+/// Arguments vmsh writes to `VMSH_STAGE1_ARGS` of stage1. The arrays it points to live in a
+/// separate mapping that vmsh sizes for the command and devices of each attach.
 #[repr(C)]
 pub struct Stage1Args {
-    /// physical mmio addresses
-    pub device_addrs: [c_ulonglong; MAX_DEVICES],
+    /// Always `STAGE1_ARGS_MAGIC`. `magic` and `abi_version` keep their offsets in all
+    /// versions, so that vmsh can tell whether stage1 was built with the same layout.
+    pub magic: c_uint,
+    /// `STAGE1_ABI_VERSION` of the build that initialized the struct
+    pub abi_version: c_uint,
+    /// Keeps its offset in all versions as well, so that stage1 can report arguments of
+    /// another version to vmsh.
+    pub driver_status: DeviceState,
+    /// physical mmio addresses, `device_count` entries
+    pub device_addrs: *const c_ulonglong,
+    /// guest irq of each device, `device_count` entries
+    pub device_irqs: *const c_ulonglong,
+    /// cpu the irq of each device is pinned to or -1, `device_count` entries
+    pub device_cpus: *const c_longlong,
+    /// id of the platform device of each device, `device_count` entries. Devices of different
+    /// stage1 runs need different ids.
+    pub device_ids: *const c_ulonglong,
+    /// Holds the devices stage1 creates for `device_addrs`, so it can unregister them later.
+    /// `device_count` entries, zeroed by vmsh unless the devices are removed.
+    pub device_handles: *mut *mut c_void,
+    pub device_count: c_ulonglong,
+    pub device_action: DeviceAction,
+    /// Address of `irq_set_affinity()`, only resolved by vmsh if `device_cpus` pins a device.
+    pub irq_set_affinity: c_ulonglong,
     /// null terminated array
     /// the first argument is always stage2_path, the actual arguments come after
-    pub argv: [*mut c_char; MAX_ARGV],
+    pub argv: *mut *mut c_char,
+    /// null terminated array, the environment of stage2
+    pub envp: *mut *mut c_char,
     pub device_status: DeviceState,
-    pub driver_status: DeviceState,
+    pub hotplug: HotplugMemory,
+    /// Pairs of the address to write and the ifunc resolver of each IRELATIVE relocation,
+    /// `irelative_count` pairs. Resolvers can only run in the guest, so stage1 applies them.
+    pub irelative: *const c_ulonglong,
+    pub irelative_count: c_ulonglong,
+}
+
+impl Stage1Args {
+    /// Whether the struct was initialized by a build with the layout of this one
+    pub fn abi_matches(&self) -> bool {
+        self.magic == STAGE1_ARGS_MAGIC && self.abi_version == STAGE1_ABI_VERSION
+    }
+
+    /// Like `abi_matches`, but also sets `driver_status` to `Error` on a mismatch, so that vmsh
+    /// stops waiting for stage1.
+    pub fn check_abi(&mut self) -> bool {
+        if self.abi_matches() {
+            return true;
+        }
+        self.driver_status = DeviceState::Error;
+        false
+    }
 }
@@ -2,12 +2,12 @@ use libc::c_void;
 use log::{debug, info};
 /// This module loads kernel code into the VM that we want to attach to.
 use simple_error::bail;
-use simple_error::try_with;
-use stage1_interface::DeviceState;
+use simple_error::{require_with, try_with};
+use stage1_interface::{DeviceAction, DeviceState, HotplugAction};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::interrutable_thread::InterrutableThread;
 use crate::kernel::find_kernel;
@@ -16,17 +16,47 @@ use crate::kvm::hypervisor::{memory::process_read, memory::process_write, Hyperv
 use crate::loader::Loader;
 use crate::page_table::VirtMem;
 use crate::result::Result;
+use crate::symbolizer::SymbolSource;
 
 const STAGE1_LIB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libstage1.so"));
 
+/// How long device and memory changes may take. Offlining memory migrates the pages that are
+/// still in use, which can take a while.
+const STAGE1_TIMEOUT: Duration = Duration::from_secs(120);
+
 pub struct Stage1 {
     #[allow(unused)]
     virt_mem: VirtMem,
     pub device_status: Option<DeviceStatus>,
     pub driver_status: Option<DriverStatus>,
+    /// Set if stage1 hotplugs memory into the guest
+    pub hotplug_status: Option<DriverStatus>,
+    pub device_handles: DeviceHandles,
     init_func: usize,
 }
 
+/// Guest physical memory that stage1 adds to the guest with `add_memory_driver_managed()`
+pub struct HotplugRegion {
+    pub start: u64,
+    pub size: u64,
+    pub node: i32,
+    /// `offline_and_remove_memory()` of linux before 5.15 takes the numa node
+    pub remove_with_node: bool,
+    pub action: HotplugAction,
+}
+
+/// A virtio-mmio device that stage1 registers in the guest
+pub struct Stage1Device {
+    pub mmio_addr: u64,
+    pub irq: u32,
+    /// Guest cpu the interrupt is pinned to, see `vmsh attach --irq-affinity`
+    pub cpu: Option<u32>,
+    /// Unique among all devices of a guest, it names the platform device in the guest
+    pub id: u32,
+    /// Platform device in the guest, only set to remove the device with `DeviceAction::Remove`
+    pub handle: u64,
+}
+
 pub struct DeviceStatus {
     pub host_addr: usize,
 }
@@ -52,13 +82,34 @@ impl DriverStatus {
     }
 }
 
+/// The platform devices stage1 registered in the guest, readable once it is done with them.
+pub struct DeviceHandles {
+    pub host_addr: usize,
+    pub count: usize,
+}
+
+impl DeviceHandles {
+    pub fn read(&self, hv: &Hypervisor) -> Result<Vec<u64>> {
+        (0..self.count)
+            .map(|i| {
+                let addr = self.host_addr + i * std::mem::size_of::<u64>();
+                process_read(hv.pid, addr as *mut c_void)
+            })
+            .collect()
+    }
+}
+
 impl Stage1 {
     pub fn new(
         mut allocator: kvm::PhysMemAllocator,
         command: &[String],
-        mmio_ranges: Vec<u64>,
+        environment: &[String],
+        devices: Vec<Stage1Device>,
+        device_action: DeviceAction,
+        hotplug: Option<&HotplugRegion>,
+        symbols: &[SymbolSource],
     ) -> Result<Stage1> {
-        let kernel = find_kernel(&allocator.guest_mem, &allocator.hv)?;
+        let kernel = find_kernel(&allocator.guest_mem, allocator.hv.as_ref(), symbols)?;
 
         let mut loader = try_with!(
             Loader::new(STAGE1_LIB, &kernel, &mut allocator),
@@ -67,8 +118,8 @@ impl Stage1 {
 
         let init_func = loader.init_func;
 
-        let (virt_mem, device_status, driver_status) = try_with!(
-            loader.load_binary(command, mmio_ranges),
+        let (virt_mem, device_status, driver_status, hotplug_status, device_handles) = try_with!(
+            loader.load_binary(command, environment, devices, device_action, hotplug),
             "cannot load stage1"
         );
 
@@ -82,16 +133,14 @@ impl Stage1 {
             virt_mem,
             device_status: Some(device_status),
             driver_status: Some(driver_status),
+            hotplug_status,
+            device_handles,
             init_func,
         })
     }
 
-    pub fn spawn(
-        &self,
-        hv: Arc<Hypervisor>,
-        driver_status: DriverStatus,
-        result_sender: &SyncSender<()>,
-    ) -> Result<InterrutableThread<(), ()>> {
+    /// Let the first vcpu jump into stage1 once the vm is resumed.
+    pub fn start(&self, hv: &Hypervisor) -> Result<()> {
         let mut regs = try_with!(hv.get_regs(&hv.vcpus[0]), "failed to get cpu registers");
         if regs.is_userspace() {
             bail!("vcpu was stopped in userspace. This is not supported");
@@ -101,6 +150,33 @@ impl Stage1 {
             hv.set_regs(&hv.vcpus[0], &regs),
             "failed to set cpu registers"
         );
+        Ok(())
+    }
+
+    /// Waits until stage1 is done with a device or memory change in the resumed vm. Returns the
+    /// final driver status, `Terminating` on success or `Error`.
+    pub fn wait(&mut self, hv: &Hypervisor) -> Result<DeviceState> {
+        let driver_status = require_with!(self.driver_status.take(), "no driver status set");
+        let start = Instant::now();
+        loop {
+            let state = try_with!(driver_status.check(hv), "cannot check driver state");
+            if state == DeviceState::Terminating || state == DeviceState::Error {
+                return Ok(state);
+            }
+            if start.elapsed() > STAGE1_TIMEOUT {
+                bail!("stage1 did not finish within {:?}", STAGE1_TIMEOUT);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn spawn(
+        &self,
+        hv: Arc<Hypervisor>,
+        driver_status: DriverStatus,
+        result_sender: &SyncSender<()>,
+    ) -> Result<InterrutableThread<(), ()>> {
+        self.start(&hv)?;
 
         let res = InterrutableThread::spawn(
             "stage1",
@@ -7,6 +7,7 @@ pub const IORESOURCE_MEM: c_ulong = 0x00000200;
 pub const IORESOURCE_IRQ: c_ulong = 0x00000400;
 pub const MAX_ERRNO: c_ulong = 4095;
 pub const UMH_WAIT_EXEC: c_int = 1;
+pub const MHP_NONE: c_int = 0;
 
 // errno.h
 pub const EPERM: c_int = 1;
@@ -100,6 +101,22 @@ pub struct platform_device_info {
     pub properties: *const property_entry,
 }
 
+/// The `mhp_flags` argument was added in linux 5.10, older kernels ignore it.
+pub type add_memory_driver_managed_t = unsafe extern "C" fn(
+    nid: c_int,
+    start: u64,
+    size: u64,
+    resource_name: *const c_char,
+    mhp_flags: c_int,
+) -> c_int;
+/// Since linux 5.15
+pub type offline_and_remove_memory_t = unsafe extern "C" fn(start: u64, size: u64) -> c_int;
+/// Before linux 5.15
+pub type offline_and_remove_memory_nid_t =
+    unsafe extern "C" fn(nid: c_int, start: u64, size: u64) -> c_int;
+/// `irq_set_affinity()` or `irq_set_affinity_hint()` before linux 5.13
+pub type irq_set_affinity_t = unsafe extern "C" fn(irq: c_uint, cpumask: *const c_ulong) -> c_int;
+
 extern "C" {
     pub fn platform_device_register_full(
         pdevinfo: *const platform_device_info,
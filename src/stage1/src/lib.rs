@@ -7,14 +7,16 @@ mod printk;
 use core::include_bytes;
 use core::panic::PanicInfo;
 use core::ptr;
-use stage1_interface::{DeviceState, Stage1Args, MAX_ARGV, MAX_DEVICES};
+use stage1_interface::{
+    DeviceAction, DeviceState, HotplugAction, HotplugMemory, Stage1Args, STAGE1_ABI_VERSION,
+    STAGE1_ARGS_MAGIC,
+};
 
-use chlorine::{c_char, c_int, c_long, c_void, size_t};
+use chlorine::{c_char, c_int, c_long, c_uint, c_ulong, c_ulonglong, c_void, size_t};
 use ffi::loff_t;
 
 // used by our driver
 const MMIO_SIZE: usize = 0x1000;
-const MMIO_IRQ: usize = 5;
 // chosen randomly, hopefully unused
 const MMIO_DEVICE_ID: i32 = 1863406883;
 
@@ -22,10 +24,32 @@ const STAGE2_EXE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/stage2"));
 
 #[no_mangle]
 static mut VMSH_STAGE1_ARGS: Stage1Args = Stage1Args {
-    device_addrs: [0; MAX_DEVICES],
-    argv: [ptr::null_mut(); MAX_ARGV],
-    device_status: DeviceState::Undefined,
+    magic: STAGE1_ARGS_MAGIC,
+    abi_version: STAGE1_ABI_VERSION,
     driver_status: DeviceState::Undefined,
+    device_addrs: ptr::null(),
+    device_irqs: ptr::null(),
+    device_cpus: ptr::null(),
+    device_ids: ptr::null(),
+    device_handles: ptr::null_mut(),
+    device_count: 0,
+    device_action: DeviceAction::Attach,
+    irq_set_affinity: 0,
+    argv: ptr::null_mut(),
+    envp: ptr::null_mut(),
+    device_status: DeviceState::Undefined,
+    hotplug: HotplugMemory {
+        start: 0,
+        size: 0,
+        node: 0,
+        add_memory: 0,
+        remove_memory: 0,
+        remove_with_node: false,
+        action: HotplugAction::Attach,
+        status: DeviceState::Undefined,
+    },
+    irelative: ptr::null(),
+    irelative_count: 0,
 };
 
 /// This function is called on panic.
@@ -47,16 +71,6 @@ fn panic(_info: &PanicInfo) -> ! {
     unsafe { never_panic() }
 }
 
-struct PlatformDevice {
-    dev: *mut ffi::platform_device,
-}
-
-impl Drop for PlatformDevice {
-    fn drop(&mut self) {
-        unsafe { ffi::platform_device_unregister(self.dev) }
-    }
-}
-
 // we put this in stack to avoid stack overflows
 static mut RESOURCES: [ffi::resource; 2] = [
     ffi::resource {
@@ -103,7 +117,7 @@ unsafe fn register_virtio_mmio(
     base: usize,
     size: usize,
     irq: usize,
-) -> Result<PlatformDevice, c_int> {
+) -> Result<*mut ffi::platform_device, c_int> {
     // we need to use static here to no got out of stack memory
     RESOURCES[0].start = base;
     RESOURCES[0].end = base + size - 1;
@@ -115,7 +129,29 @@ unsafe fn register_virtio_mmio(
     if is_err_value(dev) {
         return Err(err_value(dev) as c_int);
     }
-    Ok(PlatformDevice { dev })
+    Ok(dev)
+}
+
+/// Enough bits for NR_CPUS of any x86 config, the kernel only reads nr_cpu_ids of them.
+const CPUMASK_LONGS: usize = 8192 / 64;
+// static, too large for the kernel stack
+static mut CPUMASK: [c_ulong; CPUMASK_LONGS] = [0; CPUMASK_LONGS];
+
+unsafe fn pin_irq(irq: c_uint, cpu: usize) -> Result<(), c_int> {
+    let set_affinity: ffi::irq_set_affinity_t =
+        core::mem::transmute(VMSH_STAGE1_ARGS.irq_set_affinity as usize);
+    for bits in CPUMASK.iter_mut() {
+        *bits = 0;
+    }
+    match CPUMASK.get_mut(cpu / 64) {
+        Some(bits) => *bits = (1 as c_ulong).wrapping_shl((cpu % 64) as u32),
+        None => return Err(ffi::EINVAL),
+    }
+    let res = set_affinity(irq, CPUMASK.as_ptr());
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(())
 }
 
 /// re-implementation of IS_ERR_VALUE
@@ -179,46 +215,112 @@ impl Drop for KFile {
     }
 }
 
-// cannot put this onto the stack without stackoverflows?
-static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [None, None, None];
+unsafe fn hotplug_memory() -> Result<(), ()> {
+    let hotplug = &mut VMSH_STAGE1_ARGS.hotplug;
+    if hotplug.size == 0 {
+        return Ok(());
+    }
+    let add_memory: ffi::add_memory_driver_managed_t =
+        core::mem::transmute(hotplug.add_memory as usize);
+    // the kernel requires this name for driver managed memory
+    let res = add_memory(
+        hotplug.node,
+        hotplug.start,
+        hotplug.size,
+        c_str!("System RAM (vmsh)").as_ptr() as *const c_char,
+        ffi::MHP_NONE,
+    );
+    if res != 0 {
+        printkln!("stage1: failed to hotplug memory: %d", res);
+        return Err(());
+    }
+    printkln!(
+        "stage1: hotplugged memory at 0x%llx (%llu MiB) to node %d",
+        hotplug.start,
+        hotplug.size >> 20,
+        hotplug.node
+    );
+    hotplug.status = DeviceState::Ready;
+    Ok(())
+}
 
-unsafe fn run_stage2() -> Result<(), ()> {
-    for (i, addr) in VMSH_STAGE1_ARGS.device_addrs.iter().enumerate() {
-        if *addr == 0 {
+/// Memory that cannot be offlined stays plugged and vmsh leaves its memslot behind.
+unsafe fn unplug_memory() {
+    let hotplug = &mut VMSH_STAGE1_ARGS.hotplug;
+    if hotplug.status != DeviceState::Ready {
+        return;
+    }
+    let res = if hotplug.remove_with_node {
+        let remove_memory: ffi::offline_and_remove_memory_nid_t =
+            core::mem::transmute(hotplug.remove_memory as usize);
+        remove_memory(hotplug.node, hotplug.start, hotplug.size)
+    } else {
+        let remove_memory: ffi::offline_and_remove_memory_t =
+            core::mem::transmute(hotplug.remove_memory as usize);
+        remove_memory(hotplug.start, hotplug.size)
+    };
+    if res != 0 {
+        printkln!("stage1: failed to remove hotplugged memory: %d", res);
+        return;
+    }
+    hotplug.status = DeviceState::Terminating;
+}
+
+/// Unregister the devices that `register_devices` registered, or whose handles vmsh passed.
+unsafe fn unregister_devices() {
+    for i in 0..VMSH_STAGE1_ARGS.device_count as usize {
+        let handle = VMSH_STAGE1_ARGS.device_handles.add(i);
+        if !(*handle).is_null() {
+            ffi::platform_device_unregister(*handle as *mut ffi::platform_device);
+            *handle = ptr::null_mut();
+        }
+    }
+}
+
+/// Register the devices of `device_addrs`, their handles are stored in `device_handles`.
+unsafe fn register_devices() -> Result<(), ()> {
+    for i in 0..VMSH_STAGE1_ARGS.device_count as usize {
+        let addr = *VMSH_STAGE1_ARGS.device_addrs.add(i);
+        if addr == 0 {
             continue;
         }
-        printkln!("stage1: init dev at 0x%llx", *addr);
+        let irq = *VMSH_STAGE1_ARGS.device_irqs.add(i);
+        let id = *VMSH_STAGE1_ARGS.device_ids.add(i);
+        printkln!("stage1: init dev at 0x%llx with irq %llu", addr, irq);
         match register_virtio_mmio(
-            MMIO_DEVICE_ID + (i as i32),
-            *addr as usize,
+            MMIO_DEVICE_ID + (id as i32),
+            addr as usize,
             MMIO_SIZE,
-            MMIO_IRQ,
+            irq as usize,
         ) {
-            Ok(v) => {
-                if let Some(elem) = DEVICES.get_mut(i) {
-                    *elem = Some(v);
-                } else {
-                    printkln!("stage1: out-of-bound write to devs");
-                    return Err(());
-                }
-            }
+            Ok(dev) => *VMSH_STAGE1_ARGS.device_handles.add(i) = dev as *mut c_void,
             Err(res) => {
                 printkln!("stage1: failed to register block mmio device: %d", res);
                 return Err(());
             }
         };
+        let cpu = *VMSH_STAGE1_ARGS.device_cpus.add(i);
+        if cpu >= 0 {
+            // the device still works with the default affinity
+            if let Err(res) = pin_irq(irq as c_uint, cpu as usize) {
+                printkln!("stage1: cannot pin irq %llu to cpu %lld: %d", irq, cpu, res);
+            }
+        }
     }
+    Ok(())
+}
+
+unsafe fn run_stage2() -> Result<(), ()> {
+    hotplug_memory()?;
+    register_devices()?;
 
+    let stage2_path = *VMSH_STAGE1_ARGS.argv;
     // we never delete this file, however deleting files is complex and requires accessing
     // internal structs that might change.
-    let mut file = match KFile::open(
-        VMSH_STAGE1_ARGS.argv[0],
-        ffi::O_WRONLY | ffi::O_CREAT,
-        0o755,
-    ) {
+    let mut file = match KFile::open(stage2_path, ffi::O_WRONLY | ffi::O_CREAT, 0o755) {
         Ok(f) => f,
         Err(e) => {
-            printkln!("stage1: cannot open %s: %d", VMSH_STAGE1_ARGS.argv[0], e);
+            printkln!("stage1: cannot open %s: %d", stage2_path, e);
             return Err(());
         }
     };
@@ -227,7 +329,7 @@ unsafe fn run_stage2() -> Result<(), ()> {
             if n != STAGE2_EXE.len() {
                 printkln!(
                     "%s: incomplete write (%zu != %zu)",
-                    VMSH_STAGE1_ARGS.argv[0],
+                    stage2_path,
                     n,
                     STAGE2_EXE.len()
                 );
@@ -235,19 +337,17 @@ unsafe fn run_stage2() -> Result<(), ()> {
             }
         }
         Err(res) => {
-            printkln!("stage1: cannot write %s: %d", VMSH_STAGE1_ARGS.argv[0], res);
+            printkln!("stage1: cannot write %s: %d", stage2_path, res);
             return Err(());
         }
     }
     drop(file);
     ffi::flush_delayed_fput();
 
-    let mut envp: [*mut c_char; 1] = [ptr::null_mut()];
-
     let res = ffi::call_usermodehelper(
-        VMSH_STAGE1_ARGS.argv[0],
-        VMSH_STAGE1_ARGS.argv.as_mut_ptr(),
-        envp.as_mut_ptr(),
+        stage2_path,
+        VMSH_STAGE1_ARGS.argv,
+        VMSH_STAGE1_ARGS.envp,
         ffi::UMH_WAIT_EXEC,
     );
     if res != 0 {
@@ -257,7 +357,60 @@ unsafe fn run_stage2() -> Result<(), ()> {
     Ok(())
 }
 
+/// `vmsh mem add` and `vmsh mem remove` neither have devices nor a stage2.
+unsafe fn change_memory() {
+    match VMSH_STAGE1_ARGS.hotplug.action {
+        HotplugAction::Add => {
+            if hotplug_memory().is_err() {
+                VMSH_STAGE1_ARGS.hotplug.status = DeviceState::Error;
+            }
+        }
+        HotplugAction::Remove => {
+            unplug_memory();
+            if VMSH_STAGE1_ARGS.hotplug.status != DeviceState::Terminating {
+                VMSH_STAGE1_ARGS.hotplug.status = DeviceState::Error;
+            }
+        }
+        HotplugAction::Attach => {}
+    }
+    VMSH_STAGE1_ARGS.driver_status = DeviceState::Terminating;
+}
+
+/// `vmsh device add-blk` and `vmsh device remove` change devices of a running attach, they do
+/// not start stage2 either.
+unsafe fn change_devices() {
+    match VMSH_STAGE1_ARGS.device_action {
+        DeviceAction::Add => {
+            if register_devices().is_err() {
+                unregister_devices();
+                VMSH_STAGE1_ARGS.driver_status = DeviceState::Error;
+                return;
+            }
+        }
+        DeviceAction::Remove => unregister_devices(),
+        DeviceAction::Attach => {}
+    }
+    VMSH_STAGE1_ARGS.driver_status = DeviceState::Terminating;
+}
+
 unsafe extern "C" fn spawn_stage2(_arg: *mut c_void) -> c_int {
+    // vmsh rejects stage1 builds of another abi version, this is the check in the other direction
+    if !VMSH_STAGE1_ARGS.check_abi() {
+        printkln!(
+            "stage1: arguments have abi version %u, expected %u, stopping...",
+            VMSH_STAGE1_ARGS.abi_version,
+            STAGE1_ABI_VERSION
+        );
+        return 0;
+    }
+    if VMSH_STAGE1_ARGS.hotplug.action != HotplugAction::Attach {
+        change_memory();
+        return 0;
+    }
+    if VMSH_STAGE1_ARGS.device_action != DeviceAction::Attach {
+        change_devices();
+        return 0;
+    }
     //for (i, a) in VMSH_STAGE1_ARGS.argv.iter().enumerate() {
     //    if *a == ptr::null_mut() {
     //        break;
@@ -287,9 +440,8 @@ unsafe extern "C" fn spawn_stage2(_arg: *mut c_void) -> c_int {
     if res.is_ok() {
         VMSH_STAGE1_ARGS.driver_status = DeviceState::Ready;
     } else {
-        DEVICES.iter_mut().for_each(|d| {
-            d.take();
-        });
+        unregister_devices();
+        unplug_memory();
         VMSH_STAGE1_ARGS.driver_status = DeviceState::Error;
         return 0;
     };
@@ -298,16 +450,30 @@ unsafe extern "C" fn spawn_stage2(_arg: *mut c_void) -> c_int {
         ffi::usleep_range(300, 1000);
     }
 
-    DEVICES.iter_mut().for_each(|d| {
-        d.take();
-    });
+    unregister_devices();
+    unplug_memory();
     VMSH_STAGE1_ARGS.driver_status = DeviceState::Terminating;
     0
 }
 
+/// vmsh cannot run ifunc resolvers on the host, it left their addresses in place of the
+/// functions they pick.
+unsafe fn apply_irelative() {
+    for i in 0..VMSH_STAGE1_ARGS.irelative_count as usize {
+        let place = *VMSH_STAGE1_ARGS.irelative.add(2 * i) as *mut c_ulonglong;
+        let resolver: extern "C" fn() -> c_ulonglong =
+            core::mem::transmute(*VMSH_STAGE1_ARGS.irelative.add(2 * i + 1) as usize);
+        *place = resolver();
+    }
+}
+
 #[no_mangle]
 fn init_vmsh_stage1() -> c_int {
     printkln!("stage1: init");
+    // spawn_stage2 reports a mismatch, the arguments cannot be trusted then
+    if unsafe { VMSH_STAGE1_ARGS.abi_matches() } {
+        unsafe { apply_irelative() };
+    }
 
     // We cannot close a file synchronusly outside of a kthread
     // Within a kthread we can use `flush_delayed_fput`
@@ -119,6 +119,10 @@ fn dump_dmesg() -> Result<()> {
 }
 
 impl BlockDevice {
+    pub fn major_minor(&self) -> (u32, u32) {
+        unsafe { (libc::major(self.dev_type), libc::minor(self.dev_type)) }
+    }
+
     pub fn mount(&self, mountpoint: &Path, selinux_context: &Option<String>) -> Result<()> {
         let dev_file = try_with!(
             DeviceFile::new(mountpoint, self),
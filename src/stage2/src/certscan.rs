@@ -0,0 +1,406 @@
+//! Report certificates and tokens of the guest that expired or expire soon, see
+//! `vmsh cert-scan`.
+//!
+//! Files are read in the mount namespace of the container. PEM certificates, DER certificates,
+//! certificates embedded in kubeconfigs (`client-certificate-data:`) and JWTs like kubernetes
+//! service account tokens are recognized by their content. The guest's clock decides what is
+//! expired. Results are printed one per line, prefixed with `expired`, `expiring` or `ok`.
+use simple_error::{bail, try_with};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::result::Result;
+
+/// Scanned if no paths are given. The trust store in /etc/ssl/certs is left out, its CAs are
+/// not managed by the guest.
+const DEFAULT_PATHS: &[&str] = &[
+    "/etc/kubernetes",
+    "/var/lib/kubelet/pki",
+    "/etc/letsencrypt/live",
+    "/etc/ssl/private",
+    "/etc/pki/tls/private",
+    "/etc/nginx",
+    "/etc/apache2",
+    "/etc/httpd",
+    "/etc/haproxy",
+    "/etc/docker/certs.d",
+    "/etc/etcd",
+    "/run/secrets",
+    "/var/run/secrets",
+];
+
+/// Larger files are no certificates.
+const MAX_FILE_SIZE: u64 = 1 << 20;
+/// Secrets mounted by kubernetes are nested a few levels deep, package trees are not scanned.
+const MAX_DEPTH: usize = 8;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// Exit code if certificates or tokens need to be renewed, like `vmsh security-audit`
+const FOUND_EXPIRING: i32 = 2;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+/// Value of a kubeconfig key that holds a base64 encoded PEM certificate
+const KUBECONFIG_CERT_KEY: &str = "certificate-data:";
+/// Object identifier of the common name (2.5.4.3)
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_OID: u8 = 0x06;
+const DER_UTC_TIME: u8 = 0x17;
+const DER_GENERALIZED_TIME: u8 = 0x18;
+/// `[0] EXPLICIT Version` of the tbsCertificate
+const DER_VERSION: u8 = 0xa0;
+
+struct Expiry {
+    /// Common name of a certificate, subject of a token
+    name: String,
+    /// Seconds since the epoch
+    not_after: i64,
+}
+
+struct Report {
+    warn_before: i64,
+    now: i64,
+    checked: usize,
+    expired: usize,
+    expiring: usize,
+}
+
+impl Report {
+    fn add(&mut self, what: &str, location: &str, expiry: &Expiry) {
+        self.checked += 1;
+        let days = (expiry.not_after - self.now).div_euclid(SECONDS_PER_DAY);
+        let date = format_date(expiry.not_after);
+        if expiry.not_after <= self.now {
+            self.expired += 1;
+            println!(
+                "expired  {}: {} {} expired {} ({} days ago)",
+                location, what, expiry.name, date, -days
+            );
+        } else if expiry.not_after - self.now <= self.warn_before {
+            self.expiring += 1;
+            println!(
+                "expiring {}: {} {} expires {} (in {} days)",
+                location, what, expiry.name, date, days
+            );
+        } else {
+            println!(
+                "ok       {}: {} {} expires {}",
+                location, what, expiry.name, date
+            );
+        }
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn format_date(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Decodes standard and url-safe base64, ignoring whitespace and padding.
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Splits the first DER element off `buf`: its tag, its content and what follows it.
+fn der_element(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.get(0)?;
+    let first = *buf.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = buf
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let content = buf.get(header..header.checked_add(len)?)?;
+    Some((tag, content, &buf[header + len..]))
+}
+
+fn digits(s: &[u8]) -> Option<i64> {
+    if s.is_empty() || !s.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+/// UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`) in seconds since the epoch.
+fn der_time(tag: u8, content: &[u8]) -> Option<i64> {
+    let (year, rest) = match tag {
+        DER_UTC_TIME => {
+            let yy = digits(content.get(..2)?)?;
+            // RFC 5280: years before 50 are in the 21st century
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &content[2..])
+        }
+        DER_GENERALIZED_TIME => (digits(content.get(..4)?)?, &content[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| digits(rest.get(i..i + 2)?);
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    Some(days * SECONDS_PER_DAY + field(4)? * 3600 + field(6)? * 60 + field(8)?)
+}
+
+fn common_name(mut name: &[u8]) -> Option<String> {
+    while let Some((DER_SET, set, rest)) = der_element(name) {
+        name = rest;
+        let (_, attribute, _) = der_element(set)?;
+        if let Some((DER_OID, oid, value)) = der_element(attribute) {
+            if oid == OID_COMMON_NAME {
+                let (_, value, _) = der_element(value)?;
+                return Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Expiry and common name of a DER encoded X.509 certificate.
+fn parse_certificate(der: &[u8]) -> Option<Expiry> {
+    let (tag, cert, _) = der_element(der)?;
+    if tag != DER_SEQUENCE {
+        return None;
+    }
+    let (_, tbs, _) = der_element(cert)?;
+    let (tag, _, mut rest) = der_element(tbs)?;
+    if tag == DER_VERSION {
+        // serial number
+        rest = der_element(rest)?.2;
+    }
+    // signature algorithm and issuer
+    let rest = der_element(rest)?.2;
+    let (_, _, rest) = der_element(rest)?;
+    let (_, validity, rest) = der_element(rest)?;
+    let (_, _, validity) = der_element(validity)?;
+    let (tag, not_after, _) = der_element(validity)?;
+    let (_, subject, _) = der_element(rest)?;
+    Some(Expiry {
+        name: common_name(subject).unwrap_or_else(|| String::from("<no common name>")),
+        not_after: der_time(tag, not_after)?,
+    })
+}
+
+/// Certificates of all PEM blocks in `text`.
+fn pem_certificates(text: &str) -> Vec<Option<Expiry>> {
+    let mut certs = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        rest = &rest[start + PEM_BEGIN.len()..];
+        let end = match rest.find(PEM_END) {
+            Some(end) => end,
+            None => break,
+        };
+        certs.push(base64_decode(&rest[..end]).and_then(|der| parse_certificate(&der)));
+        rest = &rest[end + PEM_END.len()..];
+    }
+    certs
+}
+
+/// String or number after `"key":` in a flat json object.
+fn json_field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let quoted = format!("\"{}\"", key);
+    let value = json[json.find(&quoted)? + quoted.len()..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start();
+    match value.strip_prefix('"') {
+        Some(s) => Some(&s[..s.find('"')?]),
+        None => {
+            let end = value
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(value.len());
+            Some(&value[..end])
+        }
+    }
+}
+
+/// Expiry of a JWT, `None` if `text` is no JWT or does not expire.
+fn parse_jwt(text: &str) -> Option<Expiry> {
+    let text = text.trim();
+    let parts = text.split('.').collect::<Vec<_>>();
+    // the header is a base64 encoded json object
+    if parts.len() != 3 || !text.starts_with("eyJ") {
+        return None;
+    }
+    let payload = String::from_utf8(base64_decode(parts[1])?).ok()?;
+    Some(Expiry {
+        name: json_field(&payload, "sub")
+            .unwrap_or("<no subject>")
+            .to_string(),
+        not_after: json_field(&payload, "exp")?.parse().ok()?,
+    })
+}
+
+fn scan_file(report: &mut Report, path: &Path) {
+    let mut content = vec![];
+    let res = File::open(path).and_then(|f| f.take(MAX_FILE_SIZE).read_to_end(&mut content));
+    if let Err(e) = res {
+        eprintln!("cannot read {}: {}", path.display(), e);
+        return;
+    }
+    let display = path.display().to_string();
+    let text = String::from_utf8_lossy(&content);
+    let mut certs = pem_certificates(&text);
+    for line in text.lines() {
+        if let Some(pos) = line.find(KUBECONFIG_CERT_KEY) {
+            let value = &line[pos + KUBECONFIG_CERT_KEY.len()..];
+            if let Some(pem) = base64_decode(value.trim()) {
+                certs.extend(pem_certificates(&String::from_utf8_lossy(&pem)));
+            }
+        }
+    }
+    if certs.is_empty() {
+        if content.first() == Some(&DER_SEQUENCE) {
+            if let Some(cert) = parse_certificate(&content) {
+                report.add("certificate", &display, &cert);
+            }
+        } else if let Some(token) = parse_jwt(&text) {
+            report.add("token", &display, &token);
+        }
+        return;
+    }
+    let many = certs.len() > 1;
+    for (i, cert) in certs.iter().enumerate() {
+        let location = if many {
+            format!("{}[{}]", display, i)
+        } else {
+            display.clone()
+        };
+        match cert {
+            Some(cert) => report.add("certificate", &location, cert),
+            None => eprintln!("{}: cannot parse certificate", location),
+        }
+    }
+}
+
+fn scan(report: &mut Report, visited: &mut HashSet<PathBuf>, path: &Path, depth: usize) {
+    // symlinks are followed, kubernetes and letsencrypt link to the current version of a secret
+    let canonical = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if !visited.insert(canonical.clone()) {
+        return;
+    }
+    let metadata = match fs::metadata(&canonical) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    if metadata.is_file() {
+        if metadata.len() <= MAX_FILE_SIZE {
+            scan_file(report, path);
+        }
+        return;
+    }
+    if !metadata.is_dir() || depth >= MAX_DEPTH {
+        return;
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("cannot list {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut paths = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    for p in paths {
+        scan(report, visited, &p, depth + 1);
+    }
+}
+
+/// `args` are the days before expiry to warn about, followed by the paths to scan. Returns
+/// `FOUND_EXPIRING` if anything expired or expires within these days.
+pub fn run(args: &[String]) -> Result<i32> {
+    let warn_days = match args.first() {
+        Some(days) => try_with!(days.parse::<i64>(), "invalid number of days: {}", days),
+        None => bail!("number of days is missing"),
+    };
+    let now = try_with!(
+        SystemTime::now().duration_since(UNIX_EPOCH),
+        "clock of the guest is before 1970"
+    );
+    let mut report = Report {
+        warn_before: warn_days * SECONDS_PER_DAY,
+        now: now.as_secs() as i64,
+        checked: 0,
+        expired: 0,
+        expiring: 0,
+    };
+    let paths = if args.len() > 1 {
+        args[1..].to_vec()
+    } else {
+        DEFAULT_PATHS.iter().map(|p| p.to_string()).collect()
+    };
+    let mut visited = HashSet::new();
+    for path in &paths {
+        scan(&mut report, &mut visited, Path::new(path), 0);
+    }
+    println!(
+        "{} certificates and tokens checked, {} expired, {} expire within {} days",
+        report.checked, report.expired, report.expiring, warn_days
+    );
+    Ok(if report.expired + report.expiring > 0 {
+        FOUND_EXPIRING
+    } else {
+        0
+    })
+}
@@ -6,17 +6,22 @@ use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Child;
 use std::process::Command;
 
 use crate::procfs;
 use crate::result::Result;
 
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
 pub struct Cmd {
     environment: HashMap<OsString, OsString>,
     command: String,
     arguments: Vec<String>,
     home: Option<OsString>,
+    cwd: Option<PathBuf>,
 }
 
 fn read_environment(pid: unistd::Pid) -> Result<HashMap<OsString, OsString>> {
@@ -50,6 +55,7 @@ impl Cmd {
         args: Vec<String>,
         pid: unistd::Pid,
         home: Option<OsString>,
+        cwd: Option<PathBuf>,
     ) -> Result<Cmd> {
         let arguments = if command.is_none() {
             vec![String::from("-l")]
@@ -67,25 +73,38 @@ impl Cmd {
             command,
             arguments,
             home,
+            cwd,
             environment: variables,
         })
     }
     pub fn spawn(mut self) -> Result<Child> {
-        let default_path =
-            OsString::from("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
         self.environment.insert(
             OsString::from("PATH"),
-            env::var_os("PATH").unwrap_or(default_path),
+            env::var_os("PATH").unwrap_or_else(|| OsString::from(DEFAULT_PATH)),
         );
 
         if let Some(path) = self.home {
             self.environment.insert(OsString::from("HOME"), path);
         }
+        // stage1 only passes the variables of `vmsh attach --env` to us
+        self.environment.extend(env::vars_os());
 
-        let child = Command::new(&self.command)
-            .args(&self.arguments)
-            .envs(self.environment)
-            .spawn();
+        let mut command = Command::new(&self.command);
+        command.args(&self.arguments).envs(self.environment);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        unsafe {
+            // Make the console our controlling terminal, so that shells enable job control and
+            // Ctrl-C reaches the foreground process.
+            command.pre_exec(|| {
+                if unistd::setsid().is_ok() {
+                    libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0);
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn();
         Ok(try_with!(
             child,
             "failed to spawn {} {}",
@@ -1,5 +1,6 @@
 use std::collections::BinaryHeap;
 use std::fs::{self, File};
+use std::io::{self, Write};
 use std::os::unix::prelude::{AsRawFd, FromRawFd};
 use std::path::PathBuf;
 
@@ -11,6 +12,11 @@ use nix::sys::stat;
 use nix::{fcntl, unistd};
 use simple_error::{bail, try_with};
 
+/// Tells vmsh that stage2 is done, followed by `;<exit code>` and a BEL. Must match
+/// `SESSION_END_MARKER` in vmsh's console device. Terminals ignore it as an unknown operating
+/// system command.
+const SESSION_END_MARKER: &str = "\x1b]vmsh;exit";
+
 // Linux assigns consoles linear so later added devices get a higher number.
 // In theory just assuming vmsh is the last console added is racy however
 // in practice it seems unlikely to have consoles added at runtime (famous last words).
@@ -46,6 +52,10 @@ pub fn find_vmsh_consoles() -> Result<File> {
 
 pub fn setup() -> Result<()> {
     let monitor_console = find_vmsh_consoles()?;
+    try_with!(
+        unistd::dup2(monitor_console.as_raw_fd(), libc::STDIN_FILENO),
+        "cannot replace stdin with monitor connection"
+    );
     try_with!(
         unistd::dup2(monitor_console.as_raw_fd(), libc::STDOUT_FILENO),
         "cannot replace stdout with monitor connection"
@@ -57,3 +67,10 @@ pub fn setup() -> Result<()> {
 
     Ok(())
 }
+
+/// Ends the session, vmsh exits with `exit_code`.
+pub fn end_session(exit_code: i32) {
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "{};{}\x07", SESSION_END_MARKER, exit_code);
+    let _ = stdout.flush();
+}
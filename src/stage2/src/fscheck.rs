@@ -0,0 +1,223 @@
+//! Read-only consistency checks of the guest's filesystems, see `vmsh fscheck`.
+//!
+//! The checkers are executed from the vmsh image and not from the guest, so this works even if
+//! the userland of the guest is broken.
+use simple_error::{bail, try_with};
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::block::BlockDevice;
+use crate::cmd::DEFAULT_PATH;
+use crate::procfs;
+use crate::result::Result;
+
+struct Checker {
+    filesystem: &'static str,
+    command: &'static str,
+    /// Arguments that make sure the checker does not write to the device
+    args: &'static [&'static str],
+}
+
+const CHECKERS: &[Checker] = &[
+    Checker {
+        filesystem: "ext4",
+        command: "e2fsck",
+        args: &["-n", "-f"],
+    },
+    Checker {
+        filesystem: "xfs",
+        command: "xfs_repair",
+        args: &["-n"],
+    },
+    Checker {
+        filesystem: "btrfs",
+        command: "btrfs",
+        args: &["check", "--readonly"],
+    },
+    Checker {
+        filesystem: "vfat",
+        command: "fsck.vfat",
+        args: &["-n"],
+    },
+];
+
+/// Block devices that can not contain a guest filesystem.
+const IGNORED_DEVICES: &[&str] = &["loop", "ram", "zram", "sr", "fd"];
+
+/// The btrfs superblock is the furthest from the start of the device.
+const PROBE_SIZE: usize = 0x10048;
+
+/// Detect the filesystem from its superblock magic.
+fn probe_filesystem(buf: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| buf.get(offset..offset + magic.len()) == Some(magic);
+    if at(1080, &[0x53, 0xef]) {
+        // ext2 and ext3 are checked with the same tool
+        Some("ext4")
+    } else if at(0, b"XFSB") {
+        Some("xfs")
+    } else if at(0x10040, b"_BHRfS_M") {
+        Some("btrfs")
+    } else if at(510, &[0x55, 0xaa]) && (at(54, b"FAT1") || at(82, b"FAT32")) {
+        Some("vfat")
+    } else {
+        None
+    }
+}
+
+fn read_start(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(PROBE_SIZE);
+    File::open(path)?
+        .take(PROBE_SIZE as u64)
+        .read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// All block devices of the guest except the one of vmsh.
+fn guest_devices(vmsh_dev: &BlockDevice) -> Result<Vec<PathBuf>> {
+    let dir = try_with!(
+        fs::read_dir("/sys/class/block"),
+        "failed to read /sys/class/block"
+    );
+    let (major, minor) = vmsh_dev.major_minor();
+    let vmsh_dev_num = format!("{}:{}", major, minor);
+    let mut devices = vec![];
+    for entry in dir {
+        let entry = try_with!(entry, "failed to read /sys/class/block");
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if IGNORED_DEVICES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        // partitions of the vmsh device have a different number, but the device as parent
+        let dev_num = fs::read_to_string(entry.path().join("dev")).unwrap_or_default();
+        let parent_num = fs::read_to_string(entry.path().join("../dev")).unwrap_or_default();
+        if dev_num.trim() == vmsh_dev_num || parent_num.trim() == vmsh_dev_num {
+            continue;
+        }
+        devices.push(PathBuf::from("/dev").join(name));
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+/// Devices that are mounted read-write. Checking them can report errors that are only
+/// caused by concurrent modifications.
+fn mounted_rw() -> HashSet<PathBuf> {
+    let content = fs::read_to_string(procfs::get_path().join("mounts")).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let options = fields.get(3)?;
+            if options.split(',').any(|o| o == "rw") {
+                Some(PathBuf::from(fields[0]))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Check `devices` or all guest devices if empty. Fails if any filesystem has errors.
+pub fn run(devices: &[String], vmsh_dev: &BlockDevice) -> Result<()> {
+    let devices = if devices.is_empty() {
+        guest_devices(vmsh_dev)?
+    } else {
+        devices.iter().map(PathBuf::from).collect()
+    };
+    let mounted = mounted_rw();
+    let (mut clean, mut broken, mut skipped) = (0, 0, 0);
+
+    for dev in &devices {
+        let start = match read_start(dev) {
+            Ok(start) => start,
+            Err(e) => {
+                println!("skip {}: cannot read: {}", dev.display(), e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let filesystem = match probe_filesystem(&start) {
+            Some(fs) => fs,
+            None => {
+                println!("skip {}: no supported filesystem found", dev.display());
+                skipped += 1;
+                continue;
+            }
+        };
+        let checker = CHECKERS
+            .iter()
+            .find(|c| c.filesystem == filesystem)
+            .expect("no checker for probed filesystem");
+        if mounted.contains(dev) {
+            println!(
+                "warn {}: mounted read-write, errors might be false positives",
+                dev.display()
+            );
+        }
+        println!(
+            "check {} ({}): {} {} {}",
+            dev.display(),
+            filesystem,
+            checker.command,
+            checker.args.join(" "),
+            dev.display()
+        );
+        let status = Command::new(checker.command)
+            .env(
+                "PATH",
+                env::var_os("PATH").unwrap_or_else(|| OsString::from(DEFAULT_PATH)),
+            )
+            .args(checker.args)
+            .arg(dev)
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                println!("ok   {}", dev.display());
+                clean += 1;
+            }
+            Ok(status) => {
+                println!(
+                    "fail {}: {} exited with {}",
+                    dev.display(),
+                    checker.command,
+                    status
+                );
+                broken += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                println!(
+                    "skip {}: {} is not installed in the vmsh image",
+                    dev.display(),
+                    checker.command
+                );
+                skipped += 1;
+            }
+            Err(e) => {
+                println!(
+                    "fail {}: cannot run {}: {}",
+                    dev.display(),
+                    checker.command,
+                    e
+                );
+                broken += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} filesystems clean, {} with errors, {} skipped",
+        clean, broken, skipped
+    );
+    if broken > 0 {
+        bail!("{} filesystems have errors", broken);
+    }
+    Ok(())
+}
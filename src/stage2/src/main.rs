@@ -7,6 +7,8 @@ use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::exit;
 use user_namespace::IdMap;
 
@@ -16,15 +18,20 @@ use crate::result::Result;
 
 mod block;
 mod capabilities;
+mod certscan;
 mod cmd;
 mod console;
+mod fscheck;
 mod lsm;
 mod mount_context;
 mod mountns;
 mod namespace;
+mod netcheck;
 mod procfs;
+mod publish;
 mod result;
 mod sys_ext;
+mod trace;
 mod user_namespace;
 
 struct Options {
@@ -32,9 +39,34 @@ struct Options {
     command: Option<String>,
     args: Vec<String>,
     home: Option<OsString>,
+    mode: Mode,
+    /// Where the directory shared by `vmsh attach --share` is mounted
+    share: Option<PathBuf>,
+    /// Working directory of the command, see `vmsh attach --cwd`
+    cwd: Option<PathBuf>,
+    /// Ports forwarded from the host, see `vmsh attach --publish`
+    publish: Vec<u16>,
 }
 
-fn run_stage2(opts: &Options) -> Result<()> {
+/// What stage2 runs instead of a command
+enum Mode {
+    Command,
+    /// Network checks against these targets
+    NetCheck(Vec<String>),
+    /// Filesystem checks of these devices, all if empty
+    FsCheck(Vec<String>),
+    /// Trace events of the guest kernel with these options
+    Trace(Vec<String>),
+    /// Days to warn before expiry, followed by the paths to scan for certificates
+    CertScan(Vec<String>),
+}
+
+/// Reported to vmsh if stage2 itself fails, like `docker run` does, so that scripts can tell it
+/// apart from a failing command.
+const STAGE2_FAILED: i32 = 125;
+
+/// Returns the exit code for vmsh, the one of the command in `Mode::Command`.
+fn run_stage2(opts: &Options) -> Result<i32> {
     // get a console to report errors as quick as possible
     try_with!(console::setup(), "failed to setup console");
 
@@ -114,13 +146,15 @@ fn run_stage2(opts: &Options) -> Result<()> {
 
     try_with!(mount_namespace.apply(), "failed to apply mount namespace");
 
-    let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label)?;
+    let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label, opts.share.as_deref())?;
     let dropped_groups = if supported_namespaces.contains(namespace::USER.name) {
         unistd::setgroups(&[]).is_ok()
     } else {
         false
     };
 
+    let publish_listeners = publish::listen(&opts.publish)?;
+
     for ns in other_namespaces {
         try_with!(ns.apply(), "failed to apply namespace");
     }
@@ -144,20 +178,50 @@ fn run_stage2(opts: &Options) -> Result<()> {
         try_with!(profile.inherit_profile(), "failed to inherit lsm profile");
     }
 
+    match &opts.mode {
+        Mode::Command => {}
+        Mode::NetCheck(targets) => {
+            drop(mount_ns);
+            return netcheck::run(targets).map(|_| 0);
+        }
+        Mode::FsCheck(devices) => {
+            let res = fscheck::run(devices, &dev);
+            drop(mount_ns);
+            return res.map(|_| 0);
+        }
+        Mode::Trace(args) => {
+            // tracefs is mounted in our private mount namespace
+            let res = trace::run(args);
+            drop(mount_ns);
+            return res.map(|_| 0);
+        }
+        Mode::CertScan(args) => {
+            drop(mount_ns);
+            return certscan::run(args);
+        }
+    }
+
     let cmd = Cmd::new(
         opts.command.clone(),
         opts.args.clone(),
         opts.target_pid,
         opts.home.clone(),
+        opts.cwd.clone(),
     )?;
 
     let mut child = cmd.spawn()?;
+    publish::forward_all(publish_listeners);
     // now that we have our child, we can drop temporary mount points
 
     drop(mount_ns);
     let status = try_with!(child.wait(), "failed to wait for child process");
     eprintln!("process finished with {}", status);
-    Ok(())
+    // like a shell, 128 + the signal for killed commands
+    Ok(match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => STAGE2_FAILED,
+    })
 }
 
 fn log_to_kmsg(msg: &str) {
@@ -170,7 +234,29 @@ fn log_to_kmsg(msg: &str) {
 
 fn main() {
     log_to_kmsg("[stage2] start\n");
-    let args = env::args().collect::<Vec<_>>();
+    let mut args = env::args().collect::<Vec<_>>();
+    // `--share <dir>`, `--cwd <dir>` and `--publish <port>` come before the command
+    let mut share = None;
+    let mut cwd = None;
+    let mut publish = vec![];
+    while args.len() > 2 && ["--share", "--cwd", "--publish"].contains(&args[1].as_str()) {
+        let value = args.remove(2);
+        match args.remove(1).as_str() {
+            "--share" => share = Some(PathBuf::from(value)),
+            "--cwd" => cwd = Some(PathBuf::from(value)),
+            _ => match value.parse::<u16>() {
+                Ok(port) => publish.push(port),
+                Err(_) => eprintln!("[stage2] ignore invalid published port {}", value),
+            },
+        }
+    }
+    let mode = match args.get(1).map(|a| a.as_str()) {
+        Some("--net-check") => Mode::NetCheck((&args[2..]).to_vec()),
+        Some("--fscheck") => Mode::FsCheck((&args[2..]).to_vec()),
+        Some("--trace") => Mode::Trace((&args[2..]).to_vec()),
+        Some("--cert-scan") => Mode::CertScan((&args[2..]).to_vec()),
+        _ => Mode::Command,
+    };
     let command = if args.len() > 2 {
         Some(args[1].clone())
     } else {
@@ -182,11 +268,19 @@ fn main() {
         target_pid: Pid::from_raw(1),
         args: (&args[2..]).to_vec(),
         home: None,
+        mode,
+        share,
+        cwd,
+        publish,
     };
-    if let Err(e) = run_stage2(&opts) {
+    let res = run_stage2(&opts);
+    if let Err(e) = &res {
         // print to both allocated pty and kmsg
         log_to_kmsg(&format!("[stage2] {}\n", e));
-        eprintln!("{}", &e);
+        eprintln!("{}", e);
+    }
+    console::end_session(res.as_ref().map_or(STAGE2_FAILED, |code| *code));
+    if res.is_err() {
         exit(1);
     }
 }
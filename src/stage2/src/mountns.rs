@@ -39,6 +39,9 @@ const MOUNTS: &[&str] = &[
 
 const VMSH_MOUNT_POINT: &str = "var/lib/vmsh";
 
+/// Mount tag of the virtio-9p device of `vmsh attach --share`
+const SHARE_MOUNT_TAG: &str = "vmsh-share";
+
 impl MountNamespace {
     fn new(old_namespace: namespace::Namespace) -> Result<MountNamespace> {
         // Find some other writeable mountpoint if / is readonly? /dev/shm fallback?
@@ -198,10 +201,31 @@ pub fn setup_bindmounts(mounts: &[&str]) -> Result<()> {
     Ok(())
 }
 
+fn mount_share(dir: &Path) -> Result<()> {
+    try_with!(
+        mkdir_p(&dir),
+        "cannot create share mountpoint {}",
+        dir.display()
+    );
+    try_with!(
+        mount::mount(
+            Some(SHARE_MOUNT_TAG),
+            dir,
+            Some("9p"),
+            MsFlags::empty(),
+            Some("trans=virtio,version=9p2000.L,msize=131072"),
+        ),
+        "cannot mount shared directory at {}. Does the kernel support CONFIG_NET_9P_VIRTIO and CONFIG_9P_FS?",
+        dir.display()
+    );
+    Ok(())
+}
+
 pub fn setup(
     device: &BlockDevice,
     container_namespace: namespace::Namespace,
     mount_label: &Option<String>,
+    share: Option<&Path>,
 ) -> Result<MountNamespace> {
     let ns = MountNamespace::new(container_namespace)?;
 
@@ -276,5 +300,9 @@ pub fn setup(
 
     try_with!(setup_bindmounts(MOUNTS), "failed to setup bind mounts");
 
+    if let Some(dir) = share {
+        mount_share(dir)?;
+    }
+
     Ok(ns)
 }
@@ -0,0 +1,217 @@
+//! Diagnose the network of the guest from the inside, see `vmsh net-check`.
+//!
+//! Results are printed to the vmsh console one check per line, prefixed with `ok`, `fail` or
+//! `info`, so that they can be read by humans and scripts alike.
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::SockAddr;
+use simple_error::bail;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::procfs;
+use crate::result::Result;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time the kernel gets to resolve the gateway with arp.
+const ARP_TIMEOUT: Duration = Duration::from_millis(500);
+/// Route flag: route is usable
+const RTF_UP: u32 = 0x1;
+/// Arp flag: entry is complete
+const ATF_COM: u32 = 0x2;
+
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: &str, msg: String) {
+        println!("ok   {}: {}", check, msg);
+    }
+    fn fail(&mut self, check: &str, msg: String) {
+        self.failed += 1;
+        println!("fail {}: {}", check, msg);
+    }
+    fn info(&mut self, check: &str, msg: String) {
+        println!("info {}: {}", check, msg);
+    }
+}
+
+fn check_interfaces(report: &mut Report) {
+    let mut addrs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    match getifaddrs() {
+        Ok(ifaddrs) => {
+            for ifaddr in ifaddrs {
+                let entry = addrs.entry(ifaddr.interface_name.clone()).or_default();
+                if let Some(SockAddr::Inet(addr)) = ifaddr.address {
+                    entry.push(addr.ip().to_string());
+                }
+            }
+        }
+        Err(e) => {
+            report.fail("interfaces", format!("cannot list interfaces: {}", e));
+            return;
+        }
+    }
+    let mut up = 0;
+    for (name, addrs) in &addrs {
+        if name == "lo" {
+            continue;
+        }
+        let state = fs::read_to_string(format!("/sys/class/net/{}/operstate", name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| String::from("unknown"));
+        if state == "up" {
+            up += 1;
+        }
+        report.info(
+            "interface",
+            format!("{} is {}, addresses: {}", name, state, addrs.join(" ")),
+        );
+    }
+    if up == 0 {
+        report.fail("interfaces", String::from("no interface is up"));
+    }
+}
+
+/// Gateway and interface of the ipv4 default route from /proc/net/route.
+fn default_route() -> Result<Option<(Ipv4Addr, String)>> {
+    let path = procfs::get_path().join("net/route");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => bail!("cannot read {}: {}", path.display(), e),
+    };
+    for line in content.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 8 {
+            continue;
+        }
+        let hex = |s: &str| u32::from_str_radix(s, 16).ok();
+        let (dest, gateway, flags, mask) = match (
+            hex(fields[1]),
+            hex(fields[2]),
+            hex(fields[3]),
+            hex(fields[7]),
+        ) {
+            (Some(d), Some(g), Some(f), Some(m)) => (d, g, f, m),
+            _ => continue,
+        };
+        if dest == 0 && mask == 0 && flags & RTF_UP != 0 {
+            // addresses are in network byte order
+            let gateway = Ipv4Addr::from(u32::from_be(gateway));
+            return Ok(Some((gateway, fields[0].to_string())));
+        }
+    }
+    Ok(None)
+}
+
+fn arp_entry(addr: &Ipv4Addr) -> Option<String> {
+    let content = fs::read_to_string(procfs::get_path().join("net/arp")).ok()?;
+    content.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let flags = u32::from_str_radix(fields.get(2)?.trim_start_matches("0x"), 16).ok()?;
+        if fields[0] == addr.to_string() && flags & ATF_COM != 0 {
+            Some(fields.get(3)?.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn check_gateway(report: &mut Report) {
+    let (gateway, iface) = match default_route() {
+        Ok(Some(route)) => route,
+        Ok(None) => {
+            report.fail("route", String::from("no ipv4 default route"));
+            return;
+        }
+        Err(e) => {
+            report.fail("route", e.to_string());
+            return;
+        }
+    };
+    report.ok("route", format!("default via {} dev {}", gateway, iface));
+
+    if gateway.is_unspecified() {
+        // point-to-point link without a gateway
+        return;
+    }
+    // Sending any packet makes the kernel resolve the gateway, ping needs privileges we might
+    // not have.
+    if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
+        let _ = socket.send_to(&[0], SocketAddr::new(IpAddr::V4(gateway), 9));
+    }
+    thread::sleep(ARP_TIMEOUT);
+    match arp_entry(&gateway) {
+        Some(mac) => report.ok("gateway", format!("{} is reachable at {}", gateway, mac)),
+        None => report.fail(
+            "gateway",
+            format!("{} does not answer arp requests", gateway),
+        ),
+    }
+}
+
+fn nameservers() -> Vec<String> {
+    fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .map(|s| s.trim().to_string())
+        .collect()
+}
+
+fn check_target(report: &mut Report, target: &str) {
+    let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+    let is_ip = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+        .is_ok();
+    let addrs = match target.to_socket_addrs() {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        Err(e) => {
+            report.fail("dns", format!("cannot resolve {}: {}", host, e));
+            return;
+        }
+    };
+    let addr = match addrs.first() {
+        Some(addr) => *addr,
+        None => {
+            report.fail("dns", format!("{} has no addresses", host));
+            return;
+        }
+    };
+    if !is_ip {
+        report.ok("dns", format!("{} resolves to {}", host, addr.ip()));
+    }
+    let start = Instant::now();
+    match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+        Ok(_) => report.ok(
+            "connect",
+            format!("{} ({}) in {}ms", target, addr, start.elapsed().as_millis()),
+        ),
+        Err(e) => report.fail("connect", format!("{} ({}): {}", target, addr, e)),
+    }
+}
+
+/// Run all checks and print their results. Fails if any check failed.
+pub fn run(targets: &[String]) -> Result<()> {
+    let mut report = Report { failed: 0 };
+    check_interfaces(&mut report);
+    check_gateway(&mut report);
+    let servers = nameservers();
+    if servers.is_empty() {
+        report.fail("dns", String::from("no nameserver in /etc/resolv.conf"));
+    } else {
+        report.info("dns", format!("nameservers: {}", servers.join(" ")));
+    }
+    for target in targets {
+        check_target(&mut report, target);
+    }
+    if report.failed > 0 {
+        bail!("{} network checks failed", report.failed);
+    }
+    println!("all network checks passed");
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Guest side of `vmsh attach --publish`: vmsh connects to a vsock port for every published
+//! port and we forward the connection to the tcp port of the same number on localhost. The
+//! connection is made from the network namespace of the container, so the command can listen on
+//! localhost as usual.
+use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType};
+use simple_error::try_with;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use crate::result::Result;
+
+const BACKLOG: usize = 16;
+
+pub struct Listener {
+    fd: RawFd,
+    port: u16,
+}
+
+/// Listens on the vsock ports of `ports`. Vsock ports below 1024 need CAP_NET_BIND_SERVICE in
+/// the initial user namespace, so this is done before we enter the namespaces of the container.
+pub fn listen(ports: &[u16]) -> Result<Vec<Listener>> {
+    ports
+        .iter()
+        .map(|port| {
+            let fd = try_with!(
+                socket::socket(
+                    AddressFamily::Vsock,
+                    SockType::Stream,
+                    SockFlag::SOCK_CLOEXEC,
+                    None
+                ),
+                "cannot create vsock socket"
+            );
+            let addr = SockAddr::new_vsock(libc::VMADDR_CID_ANY, u32::from(*port));
+            try_with!(socket::bind(fd, &addr), "cannot bind vsock port {}", port);
+            try_with!(
+                socket::listen(fd, BACKLOG),
+                "cannot listen on vsock port {}",
+                port
+            );
+            Ok(Listener { fd, port: *port })
+        })
+        .collect()
+}
+
+/// Copies both directions until both sides have closed them.
+fn splice(vsock: UnixStream, tcp: TcpStream) -> io::Result<()> {
+    let mut vsock_read = vsock.try_clone()?;
+    let mut tcp_write = tcp.try_clone()?;
+    let upstream = thread::spawn(move || {
+        let res = io::copy(&mut vsock_read, &mut tcp_write);
+        let _ = tcp_write.shutdown(Shutdown::Write);
+        res
+    });
+    let (mut vsock_write, mut tcp_read) = (vsock, tcp);
+    let res = io::copy(&mut tcp_read, &mut vsock_write);
+    let _ = vsock_write.shutdown(Shutdown::Write);
+    let upstream = upstream
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "copy thread panicked")));
+    res.and(upstream).map(|_| ())
+}
+
+fn forward(vsock: UnixStream, port: u16) -> io::Result<()> {
+    let addrs = [
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+    ];
+    let tcp = TcpStream::connect(&addrs[..])?;
+    splice(vsock, tcp)
+}
+
+fn accept_loop(listener: Listener) {
+    loop {
+        let fd = match socket::accept(listener.fd) {
+            Ok(fd) => fd,
+            Err(e) => {
+                eprintln!("cannot accept on vsock port {}: {}", listener.port, e);
+                return;
+            }
+        };
+        // std has no vsock type, but read, write and shutdown of unix streams work on any
+        // stream socket
+        let vsock = unsafe { UnixStream::from_raw_fd(fd) };
+        let port = listener.port;
+        thread::spawn(move || {
+            if let Err(e) = forward(vsock, port) {
+                eprintln!("cannot forward connection to port {}: {}", port, e);
+            }
+        });
+    }
+}
+
+/// Forwards connections on `listeners` in the background. Must be called from the network
+/// namespace of the container, which the threads inherit.
+pub fn forward_all(listeners: Vec<Listener>) {
+    for listener in listeners {
+        thread::spawn(move || accept_loop(listener));
+    }
+}
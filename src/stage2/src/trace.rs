@@ -0,0 +1,235 @@
+//! Trace syscalls, kprobes and tracepoints of the guest with ftrace, see `vmsh guest-trace`.
+//!
+//! Events are recorded in a trace instance of our own, so that tracing the guest has set up
+//! itself is not disturbed, and copied to the vmsh console as they arrive. The instance and our
+//! kprobes are removed again once the trace ends.
+use nix::mount::{self, MsFlags};
+use simple_error::{bail, try_with, SimpleError};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::result::Result;
+
+/// Our own tracefs mount, we are in a private mount namespace.
+const TRACEFS: &str = "/run/vmsh-tracefs";
+/// Group of the kprobe events we add
+const KPROBE_GROUP: &str = "vmsh";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const NONE: Option<&'static [u8]> = None;
+
+struct TraceOptions {
+    duration: Duration,
+    /// Only events of this guest thread
+    pid: Option<u32>,
+    syscalls: bool,
+    /// Kernel functions
+    kprobes: Vec<String>,
+    /// Tracepoints as `subsystem/event`
+    events: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<TraceOptions> {
+    let mut opts = TraceOptions {
+        duration: Duration::from_secs(10),
+        pid: None,
+        syscalls: false,
+        kprobes: vec![],
+        events: vec![],
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => Ok(value.as_str()),
+            None => Err(SimpleError::new(format!("{} needs a value", arg))),
+        };
+        match arg.as_str() {
+            "--duration" => {
+                let secs = try_with!(value()?.parse(), "invalid --duration");
+                opts.duration = Duration::from_secs(secs);
+            }
+            "--pid" => opts.pid = Some(try_with!(value()?.parse(), "invalid --pid")),
+            "--syscalls" => opts.syscalls = true,
+            "--kprobe" => opts.kprobes.push(value()?.to_string()),
+            "--event" => opts.events.push(value()?.to_string()),
+            _ => bail!("unknown trace option {}", arg),
+        }
+    }
+    Ok(opts)
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    try_with!(
+        fs::write(path, content),
+        "cannot write '{}' to {}",
+        content.trim_end(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn mount_tracefs() -> Result<PathBuf> {
+    let tracefs = PathBuf::from(TRACEFS);
+    if tracefs.join("instances").exists() {
+        return Ok(tracefs);
+    }
+    try_with!(
+        fs::create_dir_all(&tracefs),
+        "cannot create {}",
+        tracefs.display()
+    );
+    try_with!(
+        mount::mount(
+            Some("tracefs"),
+            &tracefs,
+            Some("tracefs"),
+            MsFlags::empty(),
+            NONE
+        ),
+        "cannot mount tracefs. Does the kernel support CONFIG_FTRACE?"
+    );
+    Ok(tracefs)
+}
+
+/// Kprobe event name for a kernel function, i.e. `do_sys_openat2`.
+fn kprobe_name(function: &str) -> String {
+    function.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_")
+}
+
+/// Removes the instance and our kprobes when tracing ends or fails.
+struct Session {
+    tracefs: PathBuf,
+    instance: PathBuf,
+    kprobes: Vec<String>,
+}
+
+impl Session {
+    fn new(tracefs: PathBuf) -> Result<Session> {
+        let instance = tracefs
+            .join("instances")
+            .join(format!("vmsh-{}", std::process::id()));
+        try_with!(
+            fs::create_dir(&instance),
+            "cannot create trace instance {}",
+            instance.display()
+        );
+        Ok(Session {
+            tracefs,
+            instance,
+            kprobes: vec![],
+        })
+    }
+
+    fn add_kprobe(&mut self, function: &str) -> Result<String> {
+        let name = kprobe_name(function);
+        let path = self.tracefs.join("kprobe_events");
+        // without O_APPEND the write would remove all other kprobes of the guest
+        let mut file = try_with!(
+            OpenOptions::new().append(true).open(&path),
+            "cannot open {}",
+            path.display()
+        );
+        try_with!(
+            file.write_all(format!("p:{}/{} {}\n", KPROBE_GROUP, name, function).as_bytes()),
+            "cannot add kprobe for {}. Does the kernel support CONFIG_KPROBE_EVENTS and export this function?",
+            function
+        );
+        self.kprobes.push(name.clone());
+        Ok(format!("{}/{}", KPROBE_GROUP, name))
+    }
+
+    /// Enables the event, or the whole subsystem for `syscalls`, in our instance.
+    fn enable(&self, event: &str, pid: Option<u32>) -> Result<()> {
+        if event.split('/').any(|part| part.is_empty() || part == "..") {
+            bail!("invalid event {}, expected subsystem/event", event);
+        }
+        let dir = self.instance.join("events").join(event);
+        if !dir.exists() {
+            bail!("guest kernel has no trace event {}", event);
+        }
+        if let Some(pid) = pid {
+            write_file(&dir.join("filter"), &format!("common_pid == {}\n", pid))?;
+        }
+        write_file(&dir.join("enable"), "1\n")
+    }
+
+    fn stream(&self, duration: Duration) -> Result<()> {
+        let path = self.instance.join("trace_pipe");
+        let mut pipe = try_with!(
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path),
+            "cannot open {}",
+            path.display()
+        );
+        let mut buf = vec![0u8; 64 * 1024];
+        let stdout = io::stdout();
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            match pipe.read(&mut buf) {
+                Ok(0) => thread::sleep(POLL_INTERVAL),
+                Ok(n) => {
+                    let mut out = stdout.lock();
+                    try_with!(out.write_all(&buf[..n]), "cannot write trace");
+                    try_with!(out.flush(), "cannot write trace");
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => bail!("cannot read {}: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // kprobes that are still enabled in the instance cannot be removed
+        if let Err(e) = fs::remove_dir(&self.instance) {
+            eprintln!(
+                "cannot remove trace instance {}: {}",
+                self.instance.display(),
+                e
+            );
+        }
+        let path = self.tracefs.join("kprobe_events");
+        for name in &self.kprobes {
+            let res = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .and_then(|mut f| f.write_all(format!("-:{}/{}\n", KPROBE_GROUP, name).as_bytes()));
+            if let Err(e) = res {
+                eprintln!("cannot remove kprobe {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Trace what `args` asks for and print the events until the duration is over.
+pub fn run(args: &[String]) -> Result<()> {
+    let opts = parse_args(args)?;
+    if !opts.syscalls && opts.kprobes.is_empty() && opts.events.is_empty() {
+        bail!("nothing to trace, expected --syscalls, --kprobe or --event");
+    }
+    let mut session = Session::new(mount_tracefs()?)?;
+    if opts.syscalls {
+        // formats syscalls with their names and arguments, unlike raw_syscalls
+        try_with!(
+            session.enable("syscalls", opts.pid),
+            "cannot trace syscalls. Does the kernel support CONFIG_FTRACE_SYSCALLS?"
+        );
+    }
+    for function in &opts.kprobes {
+        let event = session.add_kprobe(function)?;
+        session.enable(&event, opts.pid)?;
+    }
+    for event in &opts.events {
+        session.enable(event, opts.pid)?;
+    }
+    eprintln!("tracing for {}s", opts.duration.as_secs());
+    session.stream(opts.duration)
+}
@@ -0,0 +1,370 @@
+//! Print the statistics KVM keeps for a vm and its vcpus, i.e. exits, injected interrupts and
+//! halt polling, see `vmsh stats`.
+//!
+//! Since Linux 5.14, KVM_GET_STATS_FD returns a file descriptor with the statistics in a
+//! self-describing binary format (Documentation/virt/kvm/api.rst): a header, an id string, one
+//! descriptor per statistic and the values. The descriptors never change, so they are read once
+//! and only the values are read again for `--watch`. The file descriptors are opened in the
+//! hypervisor and moved to vmsh, so the vm only needs to be stopped while they are opened.
+//!
+//! Histograms are shown as the sum of their buckets, the number of samples.
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::thread;
+use std::time::Duration;
+
+use crate::bytes::{u16_at, u32_at, u64_at};
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::ioctls::KVM_CAP_BINARY_STATS_FD;
+use crate::result::Result;
+
+pub struct StatsOptions {
+    pub pid: Pid,
+    /// Print the change of the statistics in this interval until interrupted.
+    pub watch: Option<Duration>,
+}
+
+/// flags, name_size, num_desc, id_offset, desc_offset, data_offset
+const HEADER_SIZE: usize = 6 * 4;
+/// flags, exponent, size, offset and bucket_size in front of the name
+const DESC_HEADER_SIZE: usize = 16;
+
+const KVM_STATS_TYPE_MASK: u32 = 0xf;
+const KVM_STATS_TYPE_INSTANT: u32 = 1;
+const KVM_STATS_TYPE_PEAK: u32 = 2;
+const KVM_STATS_UNIT_MASK: u32 = 0xf << 4;
+const KVM_STATS_UNIT_BYTES: u32 = 1 << 4;
+const KVM_STATS_UNIT_SECONDS: u32 = 2 << 4;
+const KVM_STATS_UNIT_CYCLES: u32 = 3 << 4;
+const KVM_STATS_BASE_MASK: u32 = 0xf << 8;
+const KVM_STATS_BASE_POW2: u32 = 1 << 8;
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    name_size: usize,
+    num_desc: usize,
+    id_offset: u64,
+    desc_offset: u64,
+    data_offset: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct StatDesc {
+    name: String,
+    flags: u32,
+    exponent: i16,
+    /// Number of u64 values, more than one for histograms
+    size: usize,
+    /// Offset of the values from the start of the data
+    offset: usize,
+}
+
+impl StatDesc {
+    /// Counters and histograms only grow, the others are current values or peaks.
+    fn grows(&self) -> bool {
+        let kind = self.flags & KVM_STATS_TYPE_MASK;
+        kind != KVM_STATS_TYPE_INSTANT && kind != KVM_STATS_TYPE_PEAK
+    }
+
+    fn unit(&self) -> String {
+        let pow2 = self.flags & KVM_STATS_BASE_MASK == KVM_STATS_BASE_POW2;
+        match (self.flags & KVM_STATS_UNIT_MASK, pow2, self.exponent) {
+            (KVM_STATS_UNIT_SECONDS, false, -9) => String::from("ns"),
+            (KVM_STATS_UNIT_SECONDS, false, -6) => String::from("us"),
+            (KVM_STATS_UNIT_SECONDS, false, -3) => String::from("ms"),
+            (KVM_STATS_UNIT_SECONDS, false, 0) => String::from("s"),
+            (KVM_STATS_UNIT_BYTES, true, 0) => String::from("B"),
+            (KVM_STATS_UNIT_BYTES, true, 10) => String::from("KiB"),
+            (KVM_STATS_UNIT_BYTES, true, 20) => String::from("MiB"),
+            (KVM_STATS_UNIT_CYCLES, _, 0) => String::from("cycles"),
+            (_, _, 0) => String::new(),
+            (_, pow2, exponent) => format!("x{}^{}", if pow2 { 2 } else { 10 }, exponent),
+        }
+    }
+}
+
+fn parse_header(buf: &[u8]) -> Option<Header> {
+    Some(Header {
+        name_size: u32_at(buf, 4)? as usize,
+        num_desc: u32_at(buf, 8)? as usize,
+        id_offset: u32_at(buf, 12)? as u64,
+        desc_offset: u32_at(buf, 16)? as u64,
+        data_offset: u32_at(buf, 20)? as u64,
+    })
+}
+
+/// Nul-terminated string at the start of `buf`.
+fn c_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn parse_descs(buf: &[u8], num_desc: usize, name_size: usize) -> Result<Vec<StatDesc>> {
+    let desc_size = DESC_HEADER_SIZE + name_size;
+    if buf.len() < num_desc * desc_size {
+        bail!("descriptors are truncated");
+    }
+    let descs = buf
+        .chunks_exact(desc_size)
+        .take(num_desc)
+        .map(|d| {
+            Some(StatDesc {
+                name: c_string(&d[DESC_HEADER_SIZE..]),
+                flags: u32_at(d, 0)?,
+                exponent: u16_at(d, 4)? as i16,
+                size: u16_at(d, 6)? as usize,
+                offset: u32_at(d, 8)? as usize,
+            })
+        })
+        .collect::<Option<Vec<_>>>();
+    Ok(require_with!(descs, "descriptors are truncated"))
+}
+
+/// Value of each statistic in `data`, the sum of the buckets for histograms.
+fn parse_values(data: &[u8], descs: &[StatDesc]) -> Result<Vec<u64>> {
+    descs
+        .iter()
+        .map(|d| {
+            let values = require_with!(
+                data.get(d.offset..d.offset + d.size * 8),
+                "values of {} are outside of the data",
+                d.name
+            );
+            Ok(values
+                .chunks_exact(8)
+                .filter_map(|v| u64_at(v, 0))
+                .fold(0u64, |sum, v| sum.wrapping_add(v)))
+        })
+        .collect()
+}
+
+/// The statistics of the vm or one vcpu.
+pub(crate) struct StatsFile {
+    file: File,
+    /// i.e. `kvm-1234/vcpu-0`
+    id: String,
+    descs: Vec<StatDesc>,
+    data_offset: u64,
+    data_size: usize,
+}
+
+impl StatsFile {
+    fn new(file: File) -> Result<StatsFile> {
+        let mut buf = [0u8; HEADER_SIZE];
+        try_with!(file.read_exact_at(&mut buf, 0), "cannot read stats header");
+        let header = require_with!(parse_header(&buf), "stats header is truncated");
+        let mut id = vec![0u8; header.name_size];
+        try_with!(
+            file.read_exact_at(&mut id, header.id_offset),
+            "cannot read stats id"
+        );
+        let mut descs = vec![0u8; header.num_desc * (DESC_HEADER_SIZE + header.name_size)];
+        try_with!(
+            file.read_exact_at(&mut descs, header.desc_offset),
+            "cannot read stats descriptors"
+        );
+        let descs = parse_descs(&descs, header.num_desc, header.name_size)?;
+        let data_size = descs
+            .iter()
+            .map(|d| d.offset + d.size * 8)
+            .max()
+            .unwrap_or(0);
+        Ok(StatsFile {
+            file,
+            id: c_string(&id),
+            descs,
+            data_offset: header.data_offset,
+            data_size,
+        })
+    }
+
+    fn read(&self) -> Result<Vec<u64>> {
+        let mut data = vec![0u8; self.data_size];
+        try_with!(
+            self.file.read_exact_at(&mut data, self.data_offset),
+            "cannot read statistics of {}",
+            self.id
+        );
+        parse_values(&data, &self.descs)
+    }
+
+    /// Current value of each statistic by name.
+    pub(crate) fn read_named(&self) -> Result<HashMap<String, u64>> {
+        let values = self.read()?;
+        Ok(self
+            .descs
+            .iter()
+            .map(|d| d.name.clone())
+            .zip(values)
+            .collect())
+    }
+}
+
+/// Statistics of the vm and of each vcpu. `vm` must be stopped.
+pub(crate) fn open_stats(vm: &Hypervisor) -> Result<(StatsFile, Vec<StatsFile>)> {
+    if vm.check_extension(KVM_CAP_BINARY_STATS_FD)? == 0 {
+        bail!("kvm does not support binary statistics, they need Linux 5.14");
+    }
+    let vm_stats = StatsFile::new(vm.stats_fd(None)?)?;
+    let vcpu_stats = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| StatsFile::new(vm.stats_fd(Some(vcpu))?))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((vm_stats, vcpu_stats))
+}
+
+/// Print one line per statistic with the value of each file. If `before` is given, growing
+/// statistics show how much they grew since.
+fn print_stats(files: &[StatsFile], now: &[Vec<u64>], before: Option<&[Vec<u64>]>) {
+    let descs = match files.first() {
+        Some(f) => &f.descs,
+        None => return,
+    };
+    let mut header = format!("{:<32} {:>14}", "NAME", "TOTAL");
+    if files.len() > 1 {
+        for f in files {
+            header.push_str(&format!(
+                " {:>14}",
+                f.id.rsplit('/').next().unwrap_or(&f.id)
+            ));
+        }
+    }
+    println!("{} UNIT", header);
+    for (i, desc) in descs.iter().enumerate() {
+        let values = now
+            .iter()
+            .enumerate()
+            .map(|(f, values)| match before {
+                Some(before) if desc.grows() => values[i].wrapping_sub(before[f][i]),
+                _ => values[i],
+            })
+            .collect::<Vec<_>>();
+        let total = if desc.grows() {
+            values.iter().fold(0u64, |sum, v| sum.wrapping_add(*v))
+        } else {
+            values.iter().copied().max().unwrap_or(0)
+        };
+        let mut line = format!("{:<32} {:>14}", desc.name, total);
+        if files.len() > 1 {
+            for v in &values {
+                line.push_str(&format!(" {:>14}", v));
+            }
+        }
+        println!("{} {}", line, desc.unit());
+    }
+}
+
+fn read_all(files: &[StatsFile]) -> Result<Vec<Vec<u64>>> {
+    files.iter().map(|f| f.read()).collect()
+}
+
+pub fn stats(opts: &StatsOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let (vm_stats, vcpu_stats) = {
+        let _stopped = vm.stop_guard()?;
+        let (vm_stats, vcpu_stats) = open_stats(&vm)?;
+        (vec![vm_stats], vcpu_stats)
+    };
+    // vmsh does not need the hypervisor anymore
+    drop(vm);
+
+    let mut before = (read_all(&vm_stats)?, read_all(&vcpu_stats)?);
+    print_stats(&vm_stats, &before.0, None);
+    println!();
+    print_stats(&vcpu_stats, &before.1, None);
+    let interval = match opts.watch {
+        Some(interval) => interval,
+        None => return Ok(()),
+    };
+    loop {
+        thread::sleep(interval);
+        let now = (read_all(&vm_stats)?, read_all(&vcpu_stats)?);
+        println!("\n--- last {:.1}s", interval.as_secs_f64());
+        print_stats(&vm_stats, &now.0, Some(&before.0));
+        println!();
+        print_stats(&vcpu_stats, &now.1, Some(&before.1));
+        before = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(name: &str, flags: u32, exponent: i16, size: u16, offset: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&exponent.to_ne_bytes());
+        buf.extend_from_slice(&size.to_ne_bytes());
+        buf.extend_from_slice(&offset.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        let mut name = name.as_bytes().to_vec();
+        name.resize(48, 0);
+        buf.extend_from_slice(&name);
+        buf
+    }
+
+    #[test]
+    fn test_parse_descs() {
+        let mut buf = desc("exits", 0, 0, 1, 0);
+        // halt_poll_success_ns: cumulative, seconds, 10^-9
+        buf.extend(desc(
+            "halt_poll_success_ns",
+            KVM_STATS_UNIT_SECONDS,
+            -9,
+            1,
+            8,
+        ));
+        // log histogram with 2 buckets
+        buf.extend(desc(
+            "halt_wait_hist",
+            4 | KVM_STATS_UNIT_SECONDS,
+            -9,
+            2,
+            16,
+        ));
+        let descs = parse_descs(&buf, 3, 48).unwrap();
+        assert_eq!(descs[1].name, "halt_poll_success_ns");
+        assert_eq!(descs[1].unit(), "ns");
+        assert_eq!(descs[0].unit(), "");
+        assert!(descs[0].grows());
+        assert!(descs[2].grows());
+        // the peak of halt polling
+        assert!(!parse_descs(&desc("max_halt_poll_ns", 2, -9, 1, 0), 1, 48).unwrap()[0].grows());
+        assert!(parse_descs(&buf, 4, 48).is_err());
+
+        let data = [7u64, 100, 3, 4]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(parse_values(&data, &descs).unwrap(), vec![7, 100, 7]);
+        assert!(parse_values(&data[..24], &descs).is_err());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let buf = [0u32, 48, 3, 24, 72, 264]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parse_header(&buf).unwrap(),
+            Header {
+                name_size: 48,
+                num_desc: 3,
+                id_offset: 24,
+                desc_offset: 72,
+                data_offset: 264,
+            }
+        );
+    }
+}
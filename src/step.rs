@@ -0,0 +1,140 @@
+//! Single-step a vcpu and print its registers after each instruction, see `vmsh step`.
+//!
+//! vmsh enables single stepping with KVM_SET_GUEST_DEBUG and runs the vcpu itself with an
+//! injected KVM_RUN while the hypervisor is stopped, so each step exits to vmsh and never reaches
+//! the hypervisor. Stepping ends early at any other exit, e.g. an instruction that accesses mmio
+//! or io ports: only the hypervisor can complete it. It does so once the vm resumes, but a read
+//! completes with whatever data is left in kvm_run.
+//!
+//! Interrupts that are pending while stepping are delivered as usual, so a step may end in the
+//! interrupt handler instead of at the next instruction.
+use kvm_bindings as kvmb;
+use log::warn;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+
+use crate::cpu::Regs;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::kvm::ioctls;
+use crate::result::Result;
+
+pub struct StepOptions {
+    pub pid: Pid,
+    pub vcpu: usize,
+    /// Number of instructions to step.
+    pub count: usize,
+}
+
+/// rip and rflags, followed by the general purpose registers, four per line.
+pub fn format_regs(regs: &Regs) -> String {
+    let gprs = [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rbp", regs.rbp),
+        ("rsp", regs.rsp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+    ];
+    let mut out = format!("rip={:016x} rflags={:08x}", regs.rip, regs.eflags);
+    for (i, (name, value)) in gprs.iter().enumerate() {
+        out.push(if i % 4 == 0 { '\n' } else { ' ' });
+        out.push_str(&format!("{:>3}={:016x}", name, value));
+    }
+    out
+}
+
+fn guest_debug(control: u32) -> kvmb::kvm_guest_debug {
+    kvmb::kvm_guest_debug {
+        control,
+        ..Default::default()
+    }
+}
+
+fn run_steps(
+    vm: &Hypervisor,
+    vcpu: &VCPU,
+    count: usize,
+    f: &mut dyn FnMut(usize) -> Result<()>,
+) -> Result<()> {
+    for i in 1..=count {
+        let run = vm.run_vcpu(vcpu)?;
+        if run.exit_reason != kvmb::KVM_EXIT_DEBUG {
+            bail!(
+                "vcpu {} exited with reason {} instead of a debug exit after {} steps. \
+                 The hypervisor completes this exit when the vm resumes",
+                vcpu.idx,
+                run.exit_reason,
+                i - 1
+            );
+        }
+        f(i)?;
+    }
+    Ok(())
+}
+
+/// Execute `count` instructions of `vcpu` and call `f` with the number of the step after each.
+/// The vm must be stopped.
+pub fn step_vcpu(
+    vm: &Hypervisor,
+    vcpu: &VCPU,
+    count: usize,
+    mut f: impl FnMut(usize) -> Result<()>,
+) -> Result<()> {
+    // KVM_RUN of a halted vcpu blocks until the next interrupt, with the whole vm stopped
+    let (_, mp_state) = vm.ioctl_with_copy(
+        Some(vcpu),
+        ioctls::KVM_GET_MP_STATE(),
+        &kvmb::kvm_mp_state::default(),
+    )?;
+    if mp_state.mp_state != kvmb::KVM_MP_STATE_RUNNABLE {
+        bail!(
+            "vcpu {} is not runnable (mp_state {}), e.g. halted",
+            vcpu.idx,
+            mp_state.mp_state
+        );
+    }
+
+    vm.set_guest_debug(
+        vcpu,
+        &guest_debug(kvmb::KVM_GUESTDBG_ENABLE | kvmb::KVM_GUESTDBG_SINGLESTEP),
+    )?;
+    let res = run_steps(vm, vcpu, count, &mut f);
+    if let Err(e) = vm.set_guest_debug(vcpu, &guest_debug(0)) {
+        warn!("cannot disable single stepping of vcpu {}: {}", vcpu.idx, e);
+    }
+    res
+}
+
+pub fn step(opts: &StepOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    let vcpu = require_with!(
+        vm.vcpus.get(opts.vcpu),
+        "vm has no vcpu {}, it has {}",
+        opts.vcpu,
+        vm.vcpus.len()
+    );
+
+    let regs = vm.get_regs(vcpu)?;
+    println!("start: {}", format_regs(&regs));
+    step_vcpu(&vm, vcpu, opts.count, |i| {
+        let regs = vm.get_regs(vcpu)?;
+        println!("step {}: {}", i, format_regs(&regs));
+        Ok(())
+    })
+}
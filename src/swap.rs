@@ -0,0 +1,375 @@
+//! Swap usage of a guest read from its kernel, see `vmsh swap`. Meant for guests that thrash so
+//! badly that nobody can log in to look at `/proc/swaps`.
+//!
+//! Reports the swap areas like `/proc/swaps`, the compression ratio of zram devices among them
+//! and the processes with the most swapped out pages. Block devices are shown as `/dev/<name>`,
+//! swap files with their path inside of their filesystem. Besides the offsets `vmsh ps` needs,
+//! the profile (see `vmi`) needs:
+//!
+//! ```text
+//! # address of swap_info minus address of init_task, from System.map. swap_info is not
+//! # exported, but the distance is the same for every boot of the same kernel build.
+//! init_task_to_swap_info 0x1a2b3c0
+//! swap_info_struct.flags 0x0
+//! swap_info_struct.prio 0x8
+//! swap_info_struct.pages 0x94
+//! swap_info_struct.inuse_pages 0x98
+//! swap_info_struct.swap_file 0xa8
+//! file.f_path.dentry 0x18
+//! dentry.d_parent 0x18
+//! dentry.d_name.name 0x28
+//! task_struct.mm 0x518
+//! # linux >= 6.2, percpu counters of which only the shared part is read
+//! mm_struct.rss_stat 0x2c0
+//! percpu_counter.count 0x8
+//! struct_percpu_counter_size 0x28
+//! # linux < 6.2 instead
+//! mm_struct.rss_stat.count 0x2c0
+//! # optional, for zram. The zram offsets are in the BTF of the zram module,
+//! # /sys/kernel/btf/zram, not in the one of vmlinux.
+//! swap_info_struct.bdev 0xa0
+//! block_device.bd_disk 0x48
+//! gendisk.disk_name 0xc
+//! gendisk.private_data 0x3f0
+//! zram.disksize 0x28
+//! zram.stats.compr_data_size 0x40
+//! zram.stats.pages_stored 0x58
+//! ```
+use log::warn;
+use simple_error::{bail, try_with};
+use std::path::PathBuf;
+
+use crate::core_file::CoreFile;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::page_math::page_size;
+use crate::ps;
+use crate::result::Result;
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct SwapOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+    /// Number of processes to list
+    pub top: usize,
+}
+
+/// Size of the swap_info array, the kernel allows fewer.
+const MAX_SWAPFILES: usize = 32;
+const SWP_USED: u64 = 1 << 0;
+const SWP_WRITEOK: u64 = 1 << 1;
+const SWP_BLKDEV: u64 = 1 << 6;
+/// Index of the swap entries in mm_struct.rss_stat
+const MM_SWAPENTS: usize = 2;
+/// Length of gendisk.disk_name
+const DISK_NAME_LEN: usize = 32;
+const NAME_MAX: usize = 255;
+/// Stop walking dentries that do not reach the root of their filesystem.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub struct Zram {
+    pub disk: String,
+    pub disksize: u64,
+    /// Pages written to the device
+    pub pages_stored: u64,
+    /// Bytes the stored pages take after compression
+    pub compr_data_size: u64,
+}
+
+impl Zram {
+    /// Size of the stored data divided by its compressed size, like the `mm_stat` of zram
+    /// shows it. None if nothing was stored yet.
+    pub fn ratio(&self, page_size: u64) -> Option<f64> {
+        if self.compr_data_size == 0 {
+            return None;
+        }
+        Some((self.pages_stored * page_size) as f64 / self.compr_data_size as f64)
+    }
+}
+
+pub struct SwapArea {
+    pub name: String,
+    pub blkdev: bool,
+    /// In pages, like the following fields
+    pub size: u64,
+    pub used: u64,
+    pub prio: i16,
+    /// None if the area is not on zram or the profile has no zram offsets
+    pub zram: Option<Zram>,
+}
+
+pub struct SwappedProcess {
+    pub pid: i32,
+    pub comm: String,
+    /// Swapped out pages
+    pub pages: u64,
+}
+
+/// Path of `dentry` inside of its filesystem.
+fn dentry_path(k: &KernelMemory, p: &Profile, mut dentry: usize) -> Result<String> {
+    let parent_offset = p.get("dentry.d_parent")?;
+    let name_offset = p.get("dentry.d_name.name")?;
+    let mut names = vec![];
+    loop {
+        let parent = k.read_ptr(dentry + parent_offset)?;
+        // the root of a filesystem is its own parent
+        if parent == dentry || parent == 0 {
+            break;
+        }
+        if names.len() == MAX_DEPTH {
+            bail!(
+                "dentry at {:#x} is nested too deep, is the offset of dentry.d_parent right?",
+                dentry
+            );
+        }
+        names.push(k.read_str(k.read_ptr(dentry + name_offset)?, NAME_MAX)?);
+        dentry = parent;
+    }
+    names.reverse();
+    Ok(format!("/{}", names.join("/")))
+}
+
+/// The zram device behind the swap area `si`, None if it is another kind of block device.
+fn read_zram(k: &KernelMemory, p: &Profile, si: usize) -> Result<Option<Zram>> {
+    let bdev = k.read_ptr(si + p.get("swap_info_struct.bdev")?)?;
+    if bdev == 0 {
+        return Ok(None);
+    }
+    let disk = k.read_ptr(bdev + p.get("block_device.bd_disk")?)?;
+    let name = k.read_str(disk + p.get("gendisk.disk_name")?, DISK_NAME_LEN)?;
+    if !name.starts_with("zram") {
+        return Ok(None);
+    }
+    let zram = k.read_ptr(disk + p.get("gendisk.private_data")?)?;
+    Ok(Some(Zram {
+        disk: name,
+        disksize: k.read_u64(zram + p.get("zram.disksize")?)?,
+        pages_stored: k.read_u64(zram + p.get("zram.stats.pages_stored")?)?,
+        compr_data_size: k.read_u64(zram + p.get("zram.stats.compr_data_size")?)?,
+    }))
+}
+
+fn read_area(k: &KernelMemory, p: &Profile, si: usize, flags: u64) -> Result<SwapArea> {
+    let blkdev = flags & SWP_BLKDEV != 0;
+    let file = k.read_ptr(si + p.get("swap_info_struct.swap_file")?)?;
+    let path = dentry_path(k, p, k.read_ptr(file + p.get("file.f_path.dentry")?)?)?;
+    // swap partitions are opened through devtmpfs
+    let name = if blkdev {
+        format!("/dev{}", path)
+    } else {
+        path
+    };
+    let mut prio = [0u8; 2];
+    k.read_bytes(si + p.get("swap_info_struct.prio")?, &mut prio)?;
+    let zram = if blkdev && p.optional("swap_info_struct.bdev").is_some() {
+        match read_zram(k, p, si) {
+            Ok(zram) => zram,
+            Err(e) => {
+                warn!("cannot read zram statistics of {}: {}", name, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    Ok(SwapArea {
+        name,
+        blkdev,
+        size: u64::from(k.read_u32(si + p.get("swap_info_struct.pages")?)?),
+        // atomic_long_t since linux 6.12, the low half is enough for every realistic size
+        used: u64::from(k.read_u32(si + p.get("swap_info_struct.inuse_pages")?)?),
+        prio: i16::from_ne_bytes(prio),
+        zram,
+    })
+}
+
+/// Swap areas that are currently enabled, in the order of `swapon`.
+pub fn swap_areas(k: &KernelMemory, p: &Profile) -> Result<Vec<SwapArea>> {
+    let swap_info = k
+        .symbol("init_task")?
+        .wrapping_add(p.get("init_task_to_swap_info")?);
+    let flags_offset = p.get("swap_info_struct.flags")?;
+    let mut areas = vec![];
+    // slots are allocated in order and never freed, swapoff only clears their flags
+    for i in 0..MAX_SWAPFILES {
+        let si = k.read_ptr(swap_info + i * 8)?;
+        if si == 0 {
+            break;
+        }
+        let flags = k.read_u64(si + flags_offset)?;
+        if flags & (SWP_USED | SWP_WRITEOK) != SWP_USED | SWP_WRITEOK {
+            continue;
+        }
+        areas.push(try_with!(
+            read_area(k, p, si, flags),
+            "cannot read swap area {}",
+            i
+        ));
+    }
+    Ok(areas)
+}
+
+/// Swapped out pages of the address space `mm`.
+fn swap_entries(k: &KernelMemory, p: &Profile, mm: usize) -> Result<u64> {
+    let count = match p.optional("mm_struct.rss_stat.count") {
+        // atomic_long_t count[NR_MM_COUNTERS]
+        Some(count) => k.read_u64(mm + count + MM_SWAPENTS * 8)?,
+        // struct percpu_counter rss_stat[NR_MM_COUNTERS], without what the cpus did not fold
+        // into the shared count yet
+        None => {
+            let size = p.get("struct_percpu_counter_size")?;
+            let counter = mm + p.get("mm_struct.rss_stat")? + MM_SWAPENTS * size;
+            k.read_u64(counter + p.get("percpu_counter.count")?)?
+        }
+    };
+    // the shared part of a percpu counter can be negative while cpus hold the rest
+    Ok(if (count as i64) < 0 { 0 } else { count })
+}
+
+/// Processes with swapped out pages, most pages first.
+pub fn swapped_processes(k: &KernelMemory, p: &Profile) -> Result<Vec<SwappedProcess>> {
+    let mm_offset = p.get("task_struct.mm")?;
+    let mut swapped = vec![];
+    for process in ps::processes(k, p)? {
+        let mm = k.read_ptr(process.task + mm_offset)?;
+        if mm == 0 {
+            continue;
+        }
+        let pages = try_with!(
+            swap_entries(k, p, mm),
+            "cannot read swap entries of pid {}",
+            process.pid
+        );
+        if pages > 0 {
+            swapped.push(SwappedProcess {
+                pid: process.pid,
+                comm: process.comm,
+                pages,
+            });
+        }
+    }
+    sort_swapped(&mut swapped);
+    Ok(swapped)
+}
+
+fn sort_swapped(swapped: &mut [SwappedProcess]) {
+    swapped.sort_by(|a, b| b.pages.cmp(&a.pages).then(a.pid.cmp(&b.pid)));
+}
+
+fn print_swap(areas: &[SwapArea], swapped: &[SwappedProcess], top: usize) {
+    let kib = (page_size() / 1024) as u64;
+    println!(
+        "{:<40} {:<10} {:>10} {:>10} {:>8}",
+        "Filename", "Type", "Size", "Used", "Priority"
+    );
+    for area in areas {
+        println!(
+            "{:<40} {:<10} {:>10} {:>10} {:>8}",
+            area.name,
+            if area.blkdev { "partition" } else { "file" },
+            area.size * kib,
+            area.used * kib,
+            area.prio
+        );
+    }
+    if areas.is_empty() {
+        println!("no swap enabled");
+    }
+
+    let zrams = areas
+        .iter()
+        .filter_map(|a| a.zram.as_ref())
+        .collect::<Vec<_>>();
+    if !zrams.is_empty() {
+        println!();
+        println!(
+            "{:<8} {:>12} {:>12} {:>12} {:>6}",
+            "ZRAM", "DISKSIZE", "STORED", "COMPRESSED", "RATIO"
+        );
+        for zram in zrams {
+            let ratio = match zram.ratio(page_size() as u64) {
+                Some(ratio) => format!("{:.2}", ratio),
+                None => String::from("-"),
+            };
+            println!(
+                "{:<8} {:>12} {:>12} {:>12} {:>6}",
+                zram.disk,
+                zram.disksize,
+                zram.pages_stored * page_size() as u64,
+                zram.compr_data_size,
+                ratio
+            );
+        }
+    }
+
+    println!();
+    println!("{:>7} {:>10} COMMAND", "PID", "SWAP(KiB)");
+    for process in swapped.iter().take(top) {
+        println!(
+            "{:>7} {:>10} {}",
+            process.pid,
+            process.pages * kib,
+            process.comm
+        );
+    }
+}
+
+fn swap_guest(src: &dyn GuestAccess, opts: &SwapOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = Profile::load_for_kernel(&opts.profile, &k)?;
+    let areas = swap_areas(&k, &p)?;
+    let swapped = swapped_processes(&k, &p)?;
+    print_swap(&areas, &swapped, opts.top);
+    Ok(())
+}
+
+pub fn swap(opts: &SwapOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            swap_guest(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            swap_guest(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zram_ratio() {
+        let mut zram = Zram {
+            disk: String::from("zram0"),
+            disksize: 1 << 30,
+            pages_stored: 1000,
+            compr_data_size: 1000 * 4096 / 3,
+        };
+        let ratio = zram.ratio(4096).unwrap();
+        assert!((ratio - 3.0).abs() < 0.01);
+        zram.compr_data_size = 0;
+        assert_eq!(zram.ratio(4096), None);
+    }
+
+    #[test]
+    fn test_sort_swapped() {
+        let process = |pid, pages| SwappedProcess {
+            pid,
+            comm: String::from("postgres"),
+            pages,
+        };
+        let mut swapped = vec![process(3, 10), process(2, 500), process(1, 10)];
+        sort_swapped(&mut swapped);
+        let order = swapped.iter().map(|p| p.pid).collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 1, 3]);
+    }
+}
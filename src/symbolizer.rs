@@ -0,0 +1,443 @@
+//! Resolve guest kernel addresses to symbols and back for all commands that show or need kernel
+//! symbols, i.e. `vmsh watch`, `vmsh gdbserver` and `vmsh coredump --symbols`.
+//!
+//! The source of the symbols is selected at runtime, see `SymbolSource`:
+//!
+//! - `ksymtab`: the symbols the guest kernel exports to modules, found in guest memory. Needs no
+//!   files, but static functions are missing.
+//! - `system-map:PATH`: the `System.map` of the guest kernel.
+//! - `vmlinux:PATH`: the symbol table of the unstripped `vmlinux` of the guest kernel.
+//! - `kallsyms:PATH`: a copy of `/proc/kallsyms` of the running guest, read as root.
+//! - `none`: no symbols at all.
+//!
+//! Addresses in `System.map` and `vmlinux` are link-time addresses. They are moved by the KASLR
+//! offset, which is found by comparing them with the exported symbols in guest memory, or with
+//! the start of the kernel image if there are none. `/proc/kallsyms` has runtime addresses.
+use log::{info, warn};
+use simple_error::{bail, try_with};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+use crate::kernel::{Kernel, LINUX_KERNEL_KASLR_RANGE};
+use crate::result::Result;
+use crate::try_core_res;
+
+pub trait Symbolizer {
+    /// Address of the symbol `name`.
+    fn address(&self, name: &str) -> Option<usize>;
+
+    /// Symbol that contains `addr` and the offset of `addr` in it.
+    fn symbolize(&self, addr: usize) -> Option<(&str, usize)>;
+
+    /// Address range of a symbol, approximated by the start of the next symbol.
+    fn symbol_range(&self, name: &str) -> Option<Range<usize>>;
+}
+
+/// `name+0x12` or just the address if it has no symbol.
+pub fn describe(symbolizer: &dyn Symbolizer, addr: usize) -> String {
+    match symbolizer.symbolize(addr) {
+        Some((name, 0)) => name.to_string(),
+        Some((name, offset)) => format!("{}+{:#x}", name, offset),
+        None => format!("{:#x}", addr),
+    }
+}
+
+pub fn annotations_path(core_path: &Path) -> PathBuf {
+    let mut path = core_path.as_os_str().to_owned();
+    path.push(".symbols");
+    PathBuf::from(path)
+}
+
+/// Record where each vcpu was executing, one line per vcpu, e.g. `0 0xffffffff81c2a4b6
+/// default_idle+0x16`.
+pub fn write_annotations(
+    core_path: &Path,
+    symbolizer: &dyn Symbolizer,
+    rips: &[usize],
+) -> Result<PathBuf> {
+    let path = annotations_path(core_path);
+    let mut content = String::new();
+    for (idx, rip) in rips.iter().enumerate() {
+        content.push_str(&format!(
+            "{} {:#x} {}\n",
+            idx,
+            rip,
+            describe(symbolizer, *rip)
+        ));
+    }
+    try_with!(fs::write(&path, content), "cannot write {}", path.display());
+    Ok(path)
+}
+
+/// Symbols of the kernel image, used for all sources that have symbols.
+pub struct SymbolTable {
+    by_name: HashMap<String, usize>,
+    /// sorted by address
+    by_addr: Vec<(usize, String)>,
+    /// addresses outside of the kernel image are not symbolized
+    range: Range<usize>,
+}
+
+impl SymbolTable {
+    pub fn new(symbols: HashMap<String, usize>, range: Range<usize>) -> SymbolTable {
+        let mut by_addr = symbols
+            .iter()
+            .map(|(name, addr)| (*addr, name.clone()))
+            .collect::<Vec<_>>();
+        by_addr.sort_unstable();
+        SymbolTable {
+            by_name: symbols,
+            by_addr,
+            range,
+        }
+    }
+
+    /// Index of the last symbol at or before `addr`.
+    fn index(&self, addr: usize) -> Option<usize> {
+        if !self.range.contains(&addr) {
+            return None;
+        }
+        match self.by_addr.binary_search_by(|(a, _)| a.cmp(&addr)) {
+            Ok(mut idx) => {
+                // prefer the first of several symbols at the same address
+                while idx > 0 && self.by_addr[idx - 1].0 == addr {
+                    idx -= 1;
+                }
+                Some(idx)
+            }
+            Err(0) => None,
+            Err(idx) => Some(idx - 1),
+        }
+    }
+}
+
+impl Symbolizer for SymbolTable {
+    fn address(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    fn symbolize(&self, addr: usize) -> Option<(&str, usize)> {
+        let (start, name) = &self.by_addr[self.index(addr)?];
+        Some((name, addr - start))
+    }
+
+    fn symbol_range(&self, name: &str) -> Option<Range<usize>> {
+        let start = self.address(name)?;
+        let end = self.by_addr.iter().find(|(a, _)| *a > start)?.0;
+        Some(start..end)
+    }
+}
+
+/// `--symbols none`
+pub struct NoSymbols;
+
+impl Symbolizer for NoSymbols {
+    fn address(&self, _name: &str) -> Option<usize> {
+        None
+    }
+
+    fn symbolize(&self, _addr: usize) -> Option<(&str, usize)> {
+        None
+    }
+
+    fn symbol_range(&self, _name: &str) -> Option<Range<usize>> {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SymbolSource {
+    Ksymtab,
+    SystemMap(PathBuf),
+    Vmlinux(PathBuf),
+    Kallsyms(PathBuf),
+    None,
+}
+
+impl SymbolSource {
+    /// Parses `ksymtab`, `system-map:PATH`, `vmlinux:PATH`, `kallsyms:PATH` or `none`.
+    pub fn parse(arg: &str) -> Result<SymbolSource> {
+        let (kind, path) = match arg.split_once(':') {
+            Some((kind, path)) => (kind, Some(PathBuf::from(path))),
+            None => (arg, None),
+        };
+        Ok(match (kind, path) {
+            ("ksymtab", None) => SymbolSource::Ksymtab,
+            ("none", None) => SymbolSource::None,
+            ("system-map", Some(path)) => SymbolSource::SystemMap(path),
+            ("vmlinux", Some(path)) => SymbolSource::Vmlinux(path),
+            ("kallsyms", Some(path)) => SymbolSource::Kallsyms(path),
+            _ => bail!(
+                "invalid symbol source {}, expected ksymtab, system-map:PATH, vmlinux:PATH, kallsyms:PATH or none",
+                arg
+            ),
+        })
+    }
+
+    /// Symbols as read from the file, None for the sources without a file.
+    fn read(&self) -> Result<Option<HashMap<String, usize>>> {
+        let symbols = match self {
+            SymbolSource::None | SymbolSource::Ksymtab => return Ok(None),
+            SymbolSource::SystemMap(path) | SymbolSource::Kallsyms(path) => {
+                let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+                parse_system_map(&content)
+            }
+            SymbolSource::Vmlinux(path) => {
+                let content = try_with!(fs::read(path), "cannot read {}", path.display());
+                try_with!(
+                    vmlinux_symbols(&content),
+                    "invalid vmlinux {}",
+                    path.display()
+                )
+            }
+        };
+        if symbols.is_empty() {
+            match self {
+                SymbolSource::Kallsyms(_) => bail!(
+                    "no kernel symbols found in {:?}, was it read as root?",
+                    self
+                ),
+                _ => bail!("no kernel symbols found in {:?}", self),
+            }
+        }
+        Ok(Some(symbols))
+    }
+
+    /// `kernel` provides the exported symbols and the KASLR offset.
+    pub fn open(&self, kernel: &Kernel) -> Result<Box<dyn Symbolizer>> {
+        let symbols = match self.read()? {
+            None if *self == SymbolSource::None => return Ok(Box::new(NoSymbols)),
+            None => kernel.symbols.clone(),
+            Some(symbols) if matches!(self, SymbolSource::Kallsyms(_)) => symbols,
+            Some(link_time) => {
+                let slide = match kaslr_slide(&kernel.symbols, &link_time) {
+                    Some(slide) => slide,
+                    None => {
+                        warn!("no exported symbols to compare with, assume the kernel is not relocated");
+                        0
+                    }
+                };
+                relocate(link_time, slide)
+            }
+        };
+        Ok(Box::new(SymbolTable::new(symbols, kernel.range.clone())))
+    }
+
+    /// Sources `find_kernel` can fall back to for this source: itself if it reads a file.
+    pub fn fallbacks(&self) -> &[SymbolSource] {
+        match self {
+            SymbolSource::Ksymtab | SymbolSource::None => &[],
+            _ => std::slice::from_ref(self),
+        }
+    }
+
+    /// Runtime addresses of the kernel symbols from the first source in `chain` that works, used
+    /// if the exported symbols cannot be found in guest memory for `reason`.
+    pub fn fallback_symbols(
+        chain: &[SymbolSource],
+        kernel_start: usize,
+        reason: &str,
+    ) -> Result<HashMap<String, usize>> {
+        if chain.is_empty() {
+            bail!(
+                "{}. Pass --symbols system-map:PATH, kallsyms:PATH or vmlinux:PATH of the guest kernel",
+                reason
+            );
+        }
+        warn!("{}, falling back to other symbol sources", reason);
+        for source in chain {
+            match source.kernel_symbols(kernel_start) {
+                Ok(symbols) => {
+                    info!("using {} kernel symbols from {:?}", symbols.len(), source);
+                    return Ok(symbols);
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+        bail!("{} and no fallback symbol source worked", reason)
+    }
+
+    /// Runtime addresses of all symbols for a kernel image that starts at `kernel_start`, used if
+    /// the exported symbols cannot be found in guest memory.
+    pub fn kernel_symbols(&self, kernel_start: usize) -> Result<HashMap<String, usize>> {
+        let symbols = match self.read()? {
+            Some(symbols) => symbols,
+            None => bail!("{:?} cannot replace the exported symbols", self),
+        };
+        if let SymbolSource::Kallsyms(_) = self {
+            return Ok(symbols);
+        }
+        let slide = match text_slide(&symbols, kernel_start) {
+            Some(slide) => slide,
+            None => bail!("{:?} has no _text symbol to find the kaslr offset", self),
+        };
+        Ok(relocate(symbols, slide))
+    }
+}
+
+fn relocate(link_time: HashMap<String, usize>, slide: usize) -> HashMap<String, usize> {
+    link_time
+        .into_iter()
+        .map(|(name, addr)| (name, addr.wrapping_add(slide)))
+        .collect()
+}
+
+/// KASLR offset if the kernel image mapped at `kernel_start` starts with `_text`.
+fn text_slide(link_time: &HashMap<String, usize>, kernel_start: usize) -> Option<usize> {
+    let text = link_time.get("_text").or_else(|| link_time.get("_stext"))?;
+    Some(kernel_start.wrapping_sub(*text))
+}
+
+/// Symbols in the kernel image from lines like `ffffffff81000000 T _text`. Per-cpu variables and
+/// absolute symbols are left out.
+fn parse_system_map(content: &str) -> HashMap<String, usize> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            if kind.eq_ignore_ascii_case("a") || !LINUX_KERNEL_KASLR_RANGE.contains(&addr) {
+                return None;
+            }
+            Some((name.to_string(), addr))
+        })
+        .collect()
+}
+
+/// Functions and objects from the `.symtab` of a vmlinux.
+fn vmlinux_symbols(content: &[u8]) -> Result<HashMap<String, usize>> {
+    let elf = try_core_res!(ElfFile::new(content), "cannot parse elf");
+    let section = match elf.find_section_by_name(".symtab") {
+        Some(section) => section,
+        None => bail!("vmlinux has no symbol table, is it stripped?"),
+    };
+    let data = try_core_res!(section.get_data(&elf), "cannot read .symtab");
+    let entries = match data {
+        SectionData::SymbolTable64(entries) => entries,
+        _ => bail!("expected .symtab to be a SymbolTable64"),
+    };
+    let mut symbols = HashMap::new();
+    for entry in entries {
+        match entry.get_type() {
+            Ok(Type::Func) | Ok(Type::Object) => {}
+            _ => continue,
+        }
+        let addr = entry.value() as usize;
+        if !LINUX_KERNEL_KASLR_RANGE.contains(&addr) {
+            continue;
+        }
+        if let Ok(name) = entry.get_name(&elf) {
+            symbols.insert(name.to_string(), addr);
+        }
+    }
+    Ok(symbols)
+}
+
+/// Difference between runtime and link-time addresses that most common symbols agree on. None
+/// if there are no common symbols.
+fn kaslr_slide(
+    runtime: &HashMap<String, usize>,
+    link_time: &HashMap<String, usize>,
+) -> Option<usize> {
+    let mut votes: HashMap<usize, usize> = HashMap::new();
+    for (name, addr) in runtime {
+        if let Some(link_addr) = link_time.get(name) {
+            *votes.entry(addr.wrapping_sub(*link_addr)).or_insert(0) += 1;
+        }
+    }
+    votes
+        .into_iter()
+        .max_by_key(|(slide, count)| (*count, *slide))
+        .map(|(slide, _)| slide)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols(list: &[(&str, usize)]) -> HashMap<String, usize> {
+        list.iter().map(|(n, a)| (n.to_string(), *a)).collect()
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let table = SymbolTable::new(
+            symbols(&[
+                ("_text", 0xffff_ffff_8100_0000),
+                ("_stext", 0xffff_ffff_8100_0000),
+                ("panic", 0xffff_ffff_8100_1000),
+                ("do_idle", 0xffff_ffff_8100_2000),
+            ]),
+            0xffff_ffff_8100_0000..0xffff_ffff_8200_0000,
+        );
+        assert_eq!(
+            table.symbolize(0xffff_ffff_8100_1010),
+            Some(("panic", 0x10))
+        );
+        assert_eq!(table.symbolize(0xffff_ffff_8100_0000), Some(("_stext", 0)));
+        assert_eq!(
+            table.symbolize(0xffff_ffff_8100_3000),
+            Some(("do_idle", 0x1000))
+        );
+        assert_eq!(table.symbolize(0x7fff_0000_0000), None);
+        assert_eq!(
+            table.symbol_range("panic"),
+            Some(0xffff_ffff_8100_1000..0xffff_ffff_8100_2000)
+        );
+        assert_eq!(table.symbol_range("do_idle"), None);
+        assert_eq!(describe(&table, 0xffff_ffff_8100_2000), "do_idle");
+        assert_eq!(describe(&NoSymbols, 0x1000), "0x1000");
+    }
+
+    #[test]
+    fn test_system_map() {
+        let map = "0000000000000000 D __per_cpu_start\nffffffff81000000 T _text\nffffffff81001000 t panic\n0000000000000400 A some_abs\nffffffff82000000 d ignored extra\n";
+        let link_time = parse_system_map(map);
+        assert_eq!(
+            link_time,
+            symbols(&[
+                ("_text", 0xffff_ffff_8100_0000),
+                ("panic", 0xffff_ffff_8100_1000),
+                ("ignored", 0xffff_ffff_8200_0000),
+            ])
+        );
+        let runtime = symbols(&[("_text", 0xffff_ffff_8a00_0000), ("unknown", 0x1)]);
+        assert_eq!(kaslr_slide(&runtime, &link_time), Some(0x900_0000));
+        assert_eq!(kaslr_slide(&HashMap::new(), &link_time), None);
+    }
+
+    #[test]
+    fn test_symbol_source() {
+        assert_eq!(
+            SymbolSource::parse("ksymtab").unwrap(),
+            SymbolSource::Ksymtab
+        );
+        assert_eq!(
+            SymbolSource::parse("system-map:/boot/System.map").unwrap(),
+            SymbolSource::SystemMap(PathBuf::from("/boot/System.map"))
+        );
+        assert!(SymbolSource::parse("vmlinux").is_err());
+        assert!(SymbolSource::parse("kallsyms").is_err());
+    }
+
+    #[test]
+    fn test_text_slide() {
+        let link_time = symbols(&[
+            ("_text", 0xffff_ffff_8100_0000),
+            ("printk", 0xffff_ffff_8110_0000),
+        ]);
+        let slide = text_slide(&link_time, 0xffff_ffff_9e00_0000).unwrap();
+        assert_eq!(
+            relocate(link_time, slide).get("printk"),
+            Some(&0xffff_ffff_9e10_0000)
+        );
+        assert_eq!(text_slide(&HashMap::new(), 0xffff_ffff_9e00_0000), None);
+    }
+}
@@ -258,6 +258,18 @@ impl Process {
         self.syscall(&args).map(drop)
     }
 
+    pub fn madvise(&self, addr: *mut c_void, length: size_t, advice: c_int) -> Result<c_int> {
+        let args = syscall_args!(
+            self.saved_regs,
+            libc::SYS_madvise as c_ulong,
+            addr,
+            length,
+            advice
+        );
+
+        self.syscall(&args).map(|v| v as c_int)
+    }
+
     pub fn socket(&self, domain: c_int, ty: c_int, protocol: c_int) -> Result<c_int> {
         let args = syscall_args!(
             self.saved_regs,
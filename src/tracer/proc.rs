@@ -3,7 +3,7 @@ use nix::fcntl::{self, OFlag};
 use nix::sys::mman::{MapFlags, ProtFlags};
 use nix::sys::stat;
 use nix::unistd::{getpid, Pid};
-use simple_error::{require_with, try_with};
+use simple_error::{bail, require_with, try_with};
 use std::fs::{read_dir, read_link, File};
 use std::io::{BufRead, BufReader};
 use std::os::unix::io::{AsRawFd, FromRawFd};
@@ -62,6 +62,43 @@ pub fn pid_path(pid: Pid) -> PathBuf {
     PathBuf::from("/proc").join(pid.as_raw().to_string())
 }
 
+/// Major and minor version of a kernel release string such as `5.10.0-8-amd64`.
+pub fn parse_kernel_release(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse::<u32>().ok()?;
+    let minor = parts.next()?.parse::<u32>().ok()?;
+    Some((major, minor))
+}
+
+/// Major and minor version of the host kernel.
+pub fn kernel_version() -> Option<(u32, u32)> {
+    parse_kernel_release(nix::sys::utsname::uname().release())
+}
+
+/// Duplicate file descriptor `fd` of process `pid` into vmsh with pidfd_getfd(2), available
+/// since Linux 5.6. Unlike opening `/proc/<pid>/fd/<fd>`, this works for anonymous inodes as well.
+pub fn copy_fd(pid: Pid, fd: RawFd) -> Result<File> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if pidfd < 0 {
+        bail!(
+            "pidfd_open of {} failed: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+    let pidfd = unsafe { File::from_raw_fd(pidfd as RawFd) };
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+    if ret < 0 {
+        bail!(
+            "pidfd_getfd of fd {} in {} failed: {}",
+            fd,
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(unsafe { File::from_raw_fd(ret as RawFd) })
+}
+
 pub fn openpid(pid: Pid) -> Result<PidHandle> {
     let path = pid_path(pid);
     let fd = try_with!(
@@ -5,6 +5,8 @@ use simple_error::try_with;
 use std::mem::size_of;
 use std::mem::MaybeUninit;
 
+use crate::tracer::proc::kernel_version;
+
 #[cfg(all(target_os = "linux", target_env = "gnu"))]
 const PTRACE_GET_SYSCALL_INFO: u32 = 0x420e;
 
@@ -125,12 +127,37 @@ fn parse_raw_info(raw: RawInfo) -> Result<SyscallInfo> {
     Ok(info)
 }
 
+/// Size of the header before `data: RawData`
+const HEADER_SIZE: usize = size_of::<RawInfo>() - size_of::<RawData>();
+
+/// Number of bytes the kernel reports for `op`.
+///
+/// Until v5.10 the kernel always reported the size of the whole struct if any data was written.
+/// Since v5.11 only the fields used by the op are counted, i.e. up to the end of the last field.
+/// If the kernel version is unknown, both variants are accepted.
+fn valid_size(op: OpType, ret: usize, version: Option<(u32, u32)>) -> bool {
+    let used = match op {
+        OpType::PTRACE_SYSCALL_INFO_NONE => return ret == HEADER_SIZE,
+        OpType::PTRACE_SYSCALL_INFO_ENTRY => HEADER_SIZE + size_of::<Entry>(),
+        // rval + is_error
+        OpType::PTRACE_SYSCALL_INFO_EXIT => HEADER_SIZE + 9,
+        // nr + args + ret_data
+        OpType::PTRACE_SYSCALL_INFO_SECCOMP => HEADER_SIZE + 60,
+        OpType::unknown => return false,
+    };
+    match version {
+        Some(v) if v < (5, 11) => ret == size_of::<RawInfo>(),
+        Some(_) => ret == used,
+        None => ret == size_of::<RawInfo>() || ret == used,
+    }
+}
+
 pub fn get_syscall_info(pid: Pid) -> Result<SyscallInfo> {
     let mut info = MaybeUninit::<RawInfo>::zeroed();
     // Safe, because the kernel writes at most size_of::<RawInfo>() bytes and at least `ret` bytes.
-    // We check he has written size_of::<RawInfo>() bytes. We also allow him to omit the trailing
-    // `data: RawData` field if he marks its absence in the op field, because in that case the
-    // parser (`parse_raw_info()`) will ignore the data and never access it.
+    // We check that `ret` matches the size the kernel reports for the op (see `valid_size()`).
+    // Fields of `data: RawData` not written by the kernel stay zeroed and are not used by the
+    // parser (`parse_raw_info()`) for the given op.
     let ret = unsafe {
         libc::ptrace(
             PTRACE_GET_SYSCALL_INFO,
@@ -143,11 +170,12 @@ pub fn get_syscall_info(pid: Pid) -> Result<SyscallInfo> {
         bail!("ptrace get syscall info error: {}", ret);
     }
     let info = unsafe { info.assume_init() };
-    if !((info.op == OpType::PTRACE_SYSCALL_INFO_NONE
-        && size_of::<RawInfo>() - size_of::<RawData>() == ret as usize)
-        || (size_of::<RawInfo>() == ret as usize))
-    {
-        bail!("ptrace wrote unexpected number of bytes");
+    if !valid_size(info.op, ret as usize, kernel_version()) {
+        bail!(
+            "ptrace wrote unexpected number of bytes for {:?}: {}",
+            info.op,
+            ret
+        );
     }
     let info = try_with!(
         parse_raw_info(info),
@@ -158,21 +186,37 @@ pub fn get_syscall_info(pid: Pid) -> Result<SyscallInfo> {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::tracer::proc::parse_kernel_release;
+
     #[test]
     fn assert_struct_sizes() {
-        use super::*;
-        //assert_eq!(size_of::<RawInfo>(), 0); // for linux <= v5.2
-
-        assert_eq!(size_of::<RawInfo>(), 88); // for linux <= v5.10
-
-        //assert_eq!(size_of::<RawInfo>(), 84); // for linux >= v5.11
+        assert_eq!(size_of::<RawInfo>(), 88);
+        assert_eq!(HEADER_SIZE, 24);
     }
 
     #[test]
     fn check_linux_version() {
-        // TODO add a build.rs script which uses
-        // https://docs.rs/linux-version/0.1.1/linux_version/
-        // to detect linux version and enables the corresponding feature via
-        // https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-cfg
+        assert_eq!(parse_kernel_release("5.10.0-8-amd64"), Some((5, 10)));
+        assert_eq!(parse_kernel_release("6.1.12-arch1-1"), Some((6, 1)));
+        assert_eq!(parse_kernel_release("5.4"), Some((5, 4)));
+        assert_eq!(parse_kernel_release("linux"), None);
+    }
+
+    #[test]
+    fn check_valid_size() {
+        use OpType::*;
+        let old = Some((5, 10));
+        let new = Some((5, 11));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_NONE, 24, old));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_NONE, 24, new));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_ENTRY, 88, old));
+        assert!(!valid_size(PTRACE_SYSCALL_INFO_ENTRY, 80, old));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_ENTRY, 80, new));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_EXIT, 33, new));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_SECCOMP, 84, new));
+        assert!(!valid_size(PTRACE_SYSCALL_INFO_SECCOMP, 88, new));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_EXIT, 88, None));
+        assert!(valid_size(PTRACE_SYSCALL_INFO_EXIT, 33, None));
     }
 }
@@ -12,6 +12,7 @@ use simple_error::bail;
 use simple_error::try_with;
 use std::{
     fmt,
+    os::unix::io::RawFd,
     thread::{current, ThreadId},
 };
 
@@ -24,6 +25,41 @@ use crate::tracer::ptrace;
 type MmioRwRaw = kvmb::kvm_run__bindgen_ty_1__bindgen_ty_6;
 pub const MMIO_RW_DATA_MAX: usize = 8;
 
+/// The start of `struct kvm_run` up to the end of the mmio exit information. On every exit we
+/// only read these 56 bytes instead of the whole struct.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct KvmRunMmio {
+    /// request_interrupt_window, immediate_exit and padding1
+    _request: [u8; 8],
+    exit_reason: u32,
+    /// ready_for_interrupt_injection, if_flag, flags, cr8 and apic_base
+    _state: [u8; 20],
+    mmio: MmioRwRaw,
+}
+
+/// Like `KvmRunMmio`, but up to the end of the debug exit information.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct KvmRunDebug {
+    _request: [u8; 8],
+    exit_reason: u32,
+    _state: [u8; 20],
+    debug: kvmb::kvm_debug_exit_arch,
+}
+
+/// A KVM_EXIT_DEBUG of a vcpu with guest debugging enabled, see `wait_for_debug_exit`.
+#[derive(Debug)]
+pub struct DebugExit {
+    /// vcpu fd in the hypervisor
+    pub vcpu_fd: RawFd,
+    /// 1 (#DB) for hardware breakpoints and single steps, 3 (#BP) for int3
+    pub exception: u32,
+    /// Guest virtual address of the instruction
+    pub pc: u64,
+    pub dr6: u64,
+}
+
 pub struct MmioRw {
     /// address in the guest physical memory
     pub addr: u64,
@@ -32,35 +68,33 @@ pub struct MmioRw {
     data: [u8; MMIO_RW_DATA_MAX],
     len: usize,
     pid: Pid,
-    vcpu_map: Mapping,
+    /// address of `kvm_run` in the hypervisor
+    kvm_run: usize,
 }
 
 impl MmioRw {
     #[must_use]
-    pub fn new(raw: &MmioRwRaw, pid: Pid, vcpu_map: Mapping) -> MmioRw {
+    pub fn new(raw: &MmioRwRaw, pid: Pid, kvm_run: usize) -> MmioRw {
         // should we sanity check len here in order to not crash on out of bounds?
-        // should we check that vcpu_map is big enough for kvm_run?
         MmioRw {
             addr: raw.phys_addr,
             is_write: raw.is_write != 0,
             data: raw.data,
             len: raw.len as usize,
             pid,
-            vcpu_map,
+            kvm_run,
         }
     }
 
-    #[must_use]
-    pub fn from(kvm_run: &kvmb::kvm_run, pid: Pid, vcpu_map: Mapping) -> Option<MmioRw> {
-        match kvm_run.exit_reason {
-            kvmb::KVM_EXIT_MMIO => {
-                // Safe because the exit_reason (which comes from the kernel) told us which
-                // union field to use.
-                let mmio: &MmioRwRaw = unsafe { &kvm_run.__bindgen_anon_1.mmio };
-                Some(MmioRw::new(mmio, pid, vcpu_map))
-            }
-            _ => None,
+    /// Reads the mmio exit of the vcpu whose `kvm_run` is at `kvm_run` in the hypervisor, if the
+    /// last exit was one.
+    fn read(pid: Pid, kvm_run: usize) -> Result<Option<MmioRw>> {
+        let run: KvmRunMmio =
+            hypervisor::memory::process_read(pid, kvm_run as *const libc::c_void)?;
+        if run.exit_reason != kvmb::KVM_EXIT_MMIO {
+            return Ok(None);
         }
+        Ok(Some(MmioRw::new(&run.mmio, pid, kvm_run)))
     }
 
     #[must_use]
@@ -92,9 +126,8 @@ impl MmioRw {
         }
         self.data_mut().clone_from_slice(data);
 
-        let kvm_run_ptr = self.vcpu_map.start as *mut kvm_bindings::kvm_run;
-        // safe because those pointers will not be used in our process :) and additionally Self::new
-        // may or may not perform vcpu_map size assertions.
+        let kvm_run_ptr = self.kvm_run as *mut kvm_bindings::kvm_run;
+        // safe because those pointers will not be used in our process :)
         let mmio_ptr: *mut MmioRwRaw = unsafe { &mut ((*kvm_run_ptr).__bindgen_anon_1.mmio) };
         let data_ptr: *mut [u8; MMIO_RW_DATA_MAX] = unsafe { &mut ((*mmio_ptr).data) };
         hypervisor::memory::process_write(self.pid, data_ptr.cast::<libc::c_void>(), &self.data)?;
@@ -328,6 +361,124 @@ impl KvmRunWrapper {
         Ok(mmio)
     }
 
+    /// Let the hypervisor run until one of `vcpus` (fd and address of its `kvm_run`) exits with
+    /// a KVM_EXIT_DEBUG that `ours` accepts. Its ioctl(KVM_RUN) then fails with EINTR instead, so
+    /// the hypervisor does not inject the exception into the guest but enters the vcpu again.
+    /// Other debug exits, e.g. of int3 in the guest kernel, are left to the hypervisor.
+    ///
+    /// Returns None once `cancelled` is true, which is checked after every stop.
+    pub fn wait_for_debug_exit(
+        &mut self,
+        vcpus: &[(RawFd, usize)],
+        ours: &dyn Fn(&DebugExit) -> bool,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Option<DebugExit>> {
+        self.check_owner()?;
+        loop {
+            if cancelled() {
+                return Ok(None);
+            }
+            self.stop_on_syscall()?;
+            let status = match self.waitpid() {
+                Ok(status) => status,
+                // signals interrupt waitpid
+                Err(_) if cancelled() => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if let Some(exit) = self.process_debug_status(status, vcpus, ours)? {
+                return Ok(Some(exit));
+            }
+        }
+    }
+
+    /// Like `wait_for_debug_exit`, but only handles stops that are already pending, without
+    /// resuming threads. Collects exits of vcpus that hit a breakpoint at the same time.
+    pub fn drain_debug_exits(
+        &mut self,
+        vcpus: &[(RawFd, usize)],
+        ours: &dyn Fn(&DebugExit) -> bool,
+    ) -> Result<Vec<DebugExit>> {
+        self.check_owner()?;
+        let mut exits = vec![];
+        while let Some(status) = self.try_waitpid()? {
+            if let Some(exit) = self.process_debug_status(status, vcpus, ours)? {
+                exits.push(exit);
+            }
+        }
+        Ok(exits)
+    }
+
+    fn process_debug_status(
+        &mut self,
+        status: WaitStatus,
+        vcpus: &[(RawFd, usize)],
+        ours: &dyn Fn(&DebugExit) -> bool,
+    ) -> Result<Option<DebugExit>> {
+        let pid = match status {
+            WaitStatus::PtraceSyscall(pid) => pid,
+            WaitStatus::Exited(tid, status) => {
+                warn!("thread {} exited with: {}", tid, status);
+                self.drop_thread(tid);
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+        let (idx, vcpu_fd) = match self.kvm_run_exit(pid)? {
+            Some(exit) => exit,
+            None => return Ok(None),
+        };
+        let kvm_run = match vcpus.iter().find(|(fd, _)| *fd == vcpu_fd) {
+            Some((_, kvm_run)) => *kvm_run,
+            None => return Ok(None),
+        };
+        let run: KvmRunDebug =
+            hypervisor::memory::process_read(pid, kvm_run as *const libc::c_void)?;
+        if run.exit_reason != kvmb::KVM_EXIT_DEBUG {
+            return Ok(None);
+        }
+        let exit = DebugExit {
+            vcpu_fd,
+            exception: run.debug.exception,
+            pc: run.debug.pc,
+            dr6: run.debug.dr6,
+        };
+        if !ours(&exit) {
+            return Ok(None);
+        }
+        let thread = &self.threads[idx].ptthread;
+        let mut regs = try_with!(thread.getregs(), "cannot get registers of {}", pid);
+        regs.rax = -libc::EINTR as u64;
+        try_with!(thread.setregs(&regs), "cannot set registers of {}", pid);
+        Ok(Some(exit))
+    }
+
+    /// Like `waitpid`, but returns None if no thread has stopped.
+    fn try_waitpid(&mut self) -> Result<Option<WaitStatus>> {
+        loop {
+            let status = try_with!(
+                waitpid(
+                    Some(Pid::from_raw(-self.process_group.as_raw())),
+                    Some(
+                        nix::sys::wait::WaitPidFlag::__WALL | nix::sys::wait::WaitPidFlag::WNOHANG
+                    )
+                ),
+                "cannot wait for ioctl syscall"
+            );
+            let pid = match status.pid() {
+                Some(pid) => pid,
+                None => return Ok(None),
+            };
+            if let Some(thread) = self
+                .threads
+                .iter_mut()
+                .find(|thread| thread.ptthread.tid == pid)
+            {
+                thread.is_running = false;
+                return Ok(Some(status));
+            }
+        }
+    }
+
     fn waitpid(&mut self) -> Result<WaitStatus> {
         loop {
             let status = try_with!(
@@ -384,18 +535,31 @@ impl KvmRunWrapper {
     }
 
     fn stopped(&mut self, pid: Pid) -> Result<Option<MmioRw>> {
-        let thread: &mut Thread = match self
+        match self.kvm_run_exit(pid)? {
+            // fulfilled precondition: ioctl(KVM_RUN) just returned
+            Some((idx, _)) => {
+                let thread = &self.threads[idx];
+                MmioRw::read(thread.ptthread.tid, thread.vcpu_map.start)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Index of the thread and the vcpu fd, if thread `pid` stopped because ioctl(KVM_RUN)
+    /// returned successfully.
+    fn kvm_run_exit(&mut self, pid: Pid) -> Result<Option<(usize, RawFd)>> {
+        let idx = match self
             .threads
-            .iter_mut()
-            .find(|thread| thread.ptthread.tid == pid)
+            .iter()
+            .position(|thread| thread.ptthread.tid == pid)
         {
-            Some(t) => t,
+            Some(idx) => idx,
             None => bail!("received stop for unkown process: {}", pid),
         };
+        let thread = &mut self.threads[idx];
 
         let regs = try_with!(thread.ptthread.getregs(), "cannot syscall results");
-        // TODO check for matching ioctlfd
-        let (syscall_nr, _ioctl_fd, ioctl_request, _, _, _, _) = regs.get_syscall_params();
+        let (syscall_nr, ioctl_fd, ioctl_request, _, _, _, _) = regs.get_syscall_params();
         // SYS_ioctl = 16
         if syscall_nr != libc::SYS_ioctl as u64 {
             return Ok(None);
@@ -425,13 +589,7 @@ impl KvmRunWrapper {
             }
         }
 
-        // fulfilled precondition: ioctl(KVM_RUN) just returned
-        let map_ptr = thread.vcpu_map.start as *const kvm_bindings::kvm_run;
-        let kvm_run: kvm_bindings::kvm_run =
-            hypervisor::memory::process_read(pid, map_ptr.cast::<libc::c_void>())?;
-        let mmio = MmioRw::from(&kvm_run, thread.ptthread.tid, thread.vcpu_map.clone());
-
-        Ok(mmio)
+        Ok(Some((idx, ioctl_fd as RawFd)))
     }
 
     fn _check_siginfo(thread: &Thread) -> Result<()> {
@@ -448,3 +606,58 @@ impl KvmRunWrapper {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn test_kvm_run_mmio_layout() {
+        let run = kvmb::kvm_run::default();
+        let start = &run as *const kvmb::kvm_run as usize;
+        let exit_reason = &run.exit_reason as *const u32 as usize;
+        let mmio = unsafe { &run.__bindgen_anon_1.mmio as *const MmioRwRaw as usize };
+
+        let head = KvmRunMmio {
+            _request: [0; 8],
+            exit_reason: 0,
+            _state: [0; 20],
+            mmio: MmioRwRaw::default(),
+        };
+        let head_start = &head as *const KvmRunMmio as usize;
+        assert_eq!(
+            &head.exit_reason as *const u32 as usize - head_start,
+            exit_reason - start
+        );
+        assert_eq!(
+            &head.mmio as *const MmioRwRaw as usize - head_start,
+            mmio - start
+        );
+        assert_eq!(
+            size_of::<KvmRunMmio>(),
+            mmio - start + size_of::<MmioRwRaw>()
+        );
+    }
+
+    #[test]
+    fn test_kvm_run_debug_layout() {
+        let run = kvmb::kvm_run::default();
+        let start = &run as *const kvmb::kvm_run as usize;
+        let debug = unsafe {
+            &run.__bindgen_anon_1.debug.arch as *const kvmb::kvm_debug_exit_arch as usize
+        };
+
+        let head = KvmRunDebug {
+            _request: [0; 8],
+            exit_reason: 0,
+            _state: [0; 20],
+            debug: kvmb::kvm_debug_exit_arch::default(),
+        };
+        let head_start = &head as *const KvmRunDebug as usize;
+        assert_eq!(
+            &head.debug as *const kvmb::kvm_debug_exit_arch as usize - head_start,
+            debug - start
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Read a file of the guest from its page cache, see `vmsh vcat`.
+//!
+//! The path is resolved through the dentry cache, starting at the root directory of the init
+//! process, and the content is read from the pages the guest has cached. Nothing is executed in
+//! the guest, but only files that were accessed recently enough to still be cached can be read.
+//!
+//! The profile (see `vmi`) needs these offsets:
+//!
+//! ```text
+//! task_struct.fs 0x6f8
+//! # struct path with vfsmount and dentry
+//! fs_struct.root 0x18
+//! # struct qstr
+//! dentry.d_name 0x20
+//! dentry.d_inode 0x30
+//! # linux < 6.8 links children in a list
+//! dentry.d_subdirs 0xa0
+//! dentry.d_child 0x90
+//! # newer kernels in a hlist instead
+//! # dentry.d_children 0xa0
+//! # dentry.d_sib 0x90
+//! inode.i_size 0x50
+//! inode.i_mapping 0x30
+//! address_space.i_pages.xa_head 0x10
+//! # optional, to follow mount points
+//! task_struct.nsproxy 0x738
+//! nsproxy.mnt_ns 0x18
+//! mnt_namespace.list 0x18
+//! mount.mnt_list 0x70
+//! mount.mnt_parent 0x10
+//! mount.mnt_mountpoint 0x18
+//! mount.mnt 0x20
+//! # optional
+//! inode.i_mode 0x0
+//! struct_page_size 0x40
+//! ```
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::guest_access::GuestAccess;
+use crate::kvm;
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::vmi::{KernelMemory, Profile};
+
+pub struct VcatOptions {
+    pub pid: Pid,
+    /// Absolute path of the file in the guest.
+    pub path: String,
+    pub profile: PathBuf,
+}
+
+/// Stop walking lists that do not lead back to their head.
+const MAX_ENTRIES: usize = 1 << 20;
+const STRUCT_PAGE_SIZE: usize = 64;
+const NAME_MAX: usize = 255;
+/// Start of the array of `struct page` without memory randomization.
+const VMEMMAP_START: usize = 0xffff_ea00_0000_0000;
+
+// xarray encoding, see include/linux/xarray.h
+const XA_CHUNK_SHIFT: u64 = 6;
+const XA_CHUNK_MASK: u64 = (1 << XA_CHUNK_SHIFT) - 1;
+/// shift, offset, count, nr_values, parent, array, private_list
+const XA_NODE_SLOTS: usize = 8 + 8 + 8 + 16;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+
+#[derive(Debug, PartialEq)]
+enum XaEntry {
+    Empty,
+    Node(usize),
+    /// Refers to the canonical slot of a multi-index entry in the same node.
+    Sibling(u64),
+    Page(usize),
+    /// Shadow and swap entries, the page is not cached.
+    Value,
+}
+
+fn xa_entry(entry: u64) -> XaEntry {
+    if entry == 0 {
+        return XaEntry::Empty;
+    }
+    match entry & 3 {
+        0 => XaEntry::Page(entry as usize),
+        2 if entry > 4096 => XaEntry::Node(entry as usize - 2),
+        2 if entry >> 2 < XA_CHUNK_MASK => XaEntry::Sibling(entry >> 2),
+        // retry and zero entries
+        2 => XaEntry::Empty,
+        _ => XaEntry::Value,
+    }
+}
+
+/// Address of the `struct page` that caches page `index` of a file, if any.
+fn lookup_page(
+    k: &KernelMemory,
+    head: u64,
+    index: u64,
+    page_struct: usize,
+) -> Result<Option<usize>> {
+    // without a node the head is the entry of index 0
+    match xa_entry(head) {
+        XaEntry::Node(_) => {}
+        XaEntry::Page(page) if index == 0 => return Ok(Some(page)),
+        _ => return Ok(None),
+    }
+    let mut entry = head;
+    // pages from the start of a large folio
+    let mut delta = 0;
+    let mut shift = 0;
+    for _ in 0..64 / XA_CHUNK_SHIFT + 1 {
+        let node = match xa_entry(entry) {
+            XaEntry::Node(node) => node,
+            XaEntry::Page(page) => {
+                let delta = delta + (index & ((1 << shift) - 1));
+                return Ok(Some(page + delta as usize * page_struct));
+            }
+            XaEntry::Empty | XaEntry::Value | XaEntry::Sibling(_) => return Ok(None),
+        };
+        shift = u64::from(k.read_u8(node)?);
+        if shift % XA_CHUNK_SHIFT != 0 || shift >= 64 {
+            bail!("corrupted page cache node at {:#x}", node);
+        }
+        let mut offset = (index >> shift) & XA_CHUNK_MASK;
+        entry = k.read_u64(node + XA_NODE_SLOTS + offset as usize * 8)?;
+        if let XaEntry::Sibling(canonical) = xa_entry(entry) {
+            delta += (offset - canonical) << shift;
+            offset = canonical;
+            entry = k.read_u64(node + XA_NODE_SLOTS + offset as usize * 8)?;
+        }
+    }
+    bail!("page cache of the file is too deep, is it corrupted?")
+}
+
+struct Mount {
+    addr: usize,
+    parent: usize,
+    mountpoint: usize,
+    root: usize,
+}
+
+/// A dentry and the mount it was reached through.
+#[derive(Clone, Copy)]
+struct Location {
+    mount: usize,
+    dentry: usize,
+}
+
+fn mounts(k: &KernelMemory, p: &Profile, init_task: usize) -> Result<Vec<Mount>> {
+    let list = match p.optional("mnt_namespace.list") {
+        Some(list) => list,
+        None => {
+            info!("profile has no mount offsets, mount points are not followed");
+            return Ok(vec![]);
+        }
+    };
+    let nsproxy = k.read_ptr(init_task + p.get("task_struct.nsproxy")?)?;
+    let mnt_ns = k.read_ptr(nsproxy + p.get("nsproxy.mnt_ns")?)?;
+    let mnt_list = p.get("mount.mnt_list")?;
+    let head = mnt_ns + list;
+    let mut mounts = vec![];
+    let mut node = k.read_ptr(head)?;
+    while node != head {
+        if mounts.len() == MAX_ENTRIES {
+            bail!("mount list does not end, is the offset of mount.mnt_list right?");
+        }
+        let addr = node - mnt_list;
+        mounts.push(Mount {
+            addr,
+            parent: k.read_ptr(addr + p.get("mount.mnt_parent")?)?,
+            mountpoint: k.read_ptr(addr + p.get("mount.mnt_mountpoint")?)?,
+            // mnt_root is the first field of struct vfsmount
+            root: k.read_ptr(addr + p.get("mount.mnt")?)?,
+        });
+        node = k.read_ptr(node)?;
+    }
+    Ok(mounts)
+}
+
+/// Move to the root of filesystems mounted on `loc`, stacked mounts included.
+fn follow_mounts(mounts: &[Mount], mut loc: Location) -> Location {
+    while let Some(m) = mounts
+        .iter()
+        .find(|m| m.parent == loc.mount && m.mountpoint == loc.dentry && m.addr != loc.mount)
+    {
+        loc = Location {
+            mount: m.addr,
+            dentry: m.root,
+        };
+    }
+    loc
+}
+
+fn dentry_name(k: &KernelMemory, p: &Profile, dentry: usize) -> Result<Vec<u8>> {
+    // struct qstr { u32 hash; u32 len; const unsigned char *name; }
+    let qstr = dentry + p.get("dentry.d_name")?;
+    let len = k.read_u32(qstr + 4)? as usize;
+    if len > NAME_MAX {
+        bail!("dentry at {:#x} has an invalid name", dentry);
+    }
+    let mut name = vec![0u8; len];
+    k.read_bytes(k.read_ptr(qstr + 8)?, &mut name)?;
+    Ok(name)
+}
+
+/// Cached children of a directory dentry.
+fn children(k: &KernelMemory, p: &Profile, dentry: usize) -> Result<Vec<usize>> {
+    let mut children = vec![];
+    if let Some(subdirs) = p.optional("dentry.d_subdirs") {
+        let d_child = p.get("dentry.d_child")?;
+        let head = dentry + subdirs;
+        let mut node = k.read_ptr(head)?;
+        while node != head && children.len() < MAX_ENTRIES {
+            children.push(node - d_child);
+            node = k.read_ptr(node)?;
+        }
+    } else {
+        let d_sib = p.get("dentry.d_sib")?;
+        let mut node = k.read_ptr(dentry + p.get("dentry.d_children")?)?;
+        while node != 0 && children.len() < MAX_ENTRIES {
+            children.push(node - d_sib);
+            node = k.read_ptr(node)?;
+        }
+    }
+    Ok(children)
+}
+
+fn path_components(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        bail!("{} is not an absolute path", path);
+    }
+    let components = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect::<Vec<_>>();
+    if components.contains(&"..") {
+        bail!("{}: '..' is not supported", path);
+    }
+    Ok(components)
+}
+
+/// Inode of the file at `path`.
+fn resolve(k: &KernelMemory, p: &Profile, path: &str) -> Result<usize> {
+    let components = path_components(path)?;
+    let init_task = k.symbol("init_task")?;
+    let fs = k.read_ptr(init_task + p.get("task_struct.fs")?)?;
+    // struct path { struct vfsmount *mnt; struct dentry *dentry; }
+    let root = fs + p.get("fs_struct.root")?;
+    let mounts = mounts(k, p, init_task)?;
+    // struct vfsmount is embedded in struct mount
+    let vfsmount = k.read_ptr(root)?;
+    let mount = require_with!(
+        vfsmount.checked_sub(p.optional("mount.mnt").unwrap_or(0)),
+        "root mount {:#x} of init_task is not a struct mount, is the offset of mount.mnt right?",
+        vfsmount
+    );
+    let mut loc = follow_mounts(
+        &mounts,
+        Location {
+            mount,
+            dentry: k.read_ptr(root + 8)?,
+        },
+    );
+    let d_inode = p.get("dentry.d_inode")?;
+
+    for (i, component) in components.iter().enumerate() {
+        let mut found = None;
+        for child in children(k, p, loc.dentry)? {
+            if dentry_name(k, p, child)? != component.as_bytes() {
+                continue;
+            }
+            // unlinked files can leave negative dentries with the same name behind
+            if k.read_ptr(child + d_inode)? != 0 {
+                found = Some(child);
+                break;
+            }
+        }
+        let dentry = match found {
+            Some(dentry) => dentry,
+            None => bail!(
+                "/{} is not in the dentry cache of the guest",
+                components[..=i].join("/")
+            ),
+        };
+        loc = follow_mounts(
+            &mounts,
+            Location {
+                mount: loc.mount,
+                dentry,
+            },
+        );
+    }
+    Ok(k.read_ptr(loc.dentry + d_inode)?)
+}
+
+fn cat(src: &dyn GuestAccess, opts: &VcatOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = &Profile::load_for_kernel(&opts.profile, &k)?;
+    let inode = resolve(&k, p, &opts.path)?;
+    if let Some(i_mode) = p.optional("inode.i_mode") {
+        let mode = u16::from_ne_bytes([k.read_u8(inode + i_mode)?, k.read_u8(inode + i_mode + 1)?]);
+        if mode & S_IFMT != S_IFREG {
+            bail!("{} is not a regular file", opts.path);
+        }
+    }
+    let size = k.read_u64(inode + p.get("inode.i_size")?)? as usize;
+    let mapping = k.read_ptr(inode + p.get("inode.i_mapping")?)?;
+    let head = k.read_u64(mapping + p.get("address_space.i_pages.xa_head")?)?;
+    let page_struct = p.optional("struct_page_size").unwrap_or(STRUCT_PAGE_SIZE);
+    let vmemmap = k
+        .symbol("vmemmap_base")
+        .and_then(|addr| k.read_ptr(addr))
+        .unwrap_or(VMEMMAP_START);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut buf = vec![0u8; page_size()];
+    let mut missing = 0;
+    for index in 0..(size + page_size() - 1) / page_size() {
+        let len = std::cmp::min(page_size(), size - index * page_size());
+        let page = lookup_page(&k, head, index as u64, page_struct)?;
+        let cached = match page {
+            Some(page) if page >= vmemmap => {
+                let phys = (page - vmemmap) / page_struct * page_size();
+                k.mem.read_phys(phys, &mut buf[..len]).is_ok()
+            }
+            _ => false,
+        };
+        if !cached {
+            buf[..len].iter_mut().for_each(|b| *b = 0);
+            missing += 1;
+        }
+        try_with!(out.write_all(&buf[..len]), "cannot write to stdout");
+    }
+    try_with!(out.flush(), "cannot write to stdout");
+    if missing > 0 {
+        warn!(
+            "{} of {} pages of {} are not in the page cache and were printed as zeros",
+            missing,
+            (size + page_size() - 1) / page_size(),
+            opts.path
+        );
+    }
+    Ok(())
+}
+
+pub fn vcat(opts: &VcatOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let _stopped = vm.stop_guard()?;
+    cat(&vm, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xa_entry() {
+        assert_eq!(xa_entry(0), XaEntry::Empty);
+        assert_eq!(
+            xa_entry(0xffff_ea00_0004_0000),
+            XaEntry::Page(0xffff_ea00_0004_0000)
+        );
+        assert_eq!(
+            xa_entry(0xffff_8880_0400_0002),
+            XaEntry::Node(0xffff_8880_0400_0000)
+        );
+        // xa_mk_sibling(3)
+        assert_eq!(xa_entry((3 << 2) | 2), XaEntry::Sibling(3));
+        // XA_RETRY_ENTRY
+        assert_eq!(xa_entry((256 << 2) | 2), XaEntry::Empty);
+        // shadow entry of an evicted page
+        assert_eq!(xa_entry(0x1234_5679), XaEntry::Value);
+    }
+
+    #[test]
+    fn test_path_components() {
+        assert_eq!(
+            path_components("/etc//./hostname").unwrap(),
+            vec!["etc", "hostname"]
+        );
+        assert_eq!(path_components("/").unwrap(), Vec::<&str>::new());
+        assert!(path_components("etc/hostname").is_err());
+        assert!(path_components("/etc/../hostname").is_err());
+    }
+}
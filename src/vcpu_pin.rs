@@ -0,0 +1,164 @@
+//! Change cpu affinity and priority of vcpu threads without support from the hypervisor.
+use log::info;
+use nix::errno::Errno;
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+
+use crate::kvm;
+use crate::kvm::topology::{self, VcpuThread};
+use crate::result::Result;
+
+pub struct VcpuPinOptions {
+    pub pid: Pid,
+    /// Vcpus to change, all if empty.
+    pub vcpus: Vec<usize>,
+    /// Host cpus the vcpus may run on.
+    pub cpus: Option<Vec<usize>>,
+    /// Pin the n-th vcpu to the n-th cpu of `cpus` instead of allowing all of them.
+    pub spread: bool,
+    /// Nice value of the vcpu threads.
+    pub nice: Option<i32>,
+}
+
+/// Parse a cpu list as used in sysfs and by taskset, i.e. `0-3,8`.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in list.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start = start.trim().parse::<usize>().ok()?;
+                let end = end.trim().parse::<usize>().ok()?;
+                if start > end {
+                    return None;
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.trim().parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+fn format_cpu_set(set: &CpuSet) -> String {
+    let cpus = (0..CpuSet::count())
+        .filter(|c| set.is_set(*c).unwrap_or(false))
+        .collect::<Vec<_>>();
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for c in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == c => *end = c,
+            _ => ranges.push((c, c)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn cpu_set(cpus: &[usize]) -> Result<CpuSet> {
+    let mut set = CpuSet::new();
+    for c in cpus {
+        try_with!(set.set(*c), "invalid cpu {}", c);
+    }
+    Ok(set)
+}
+
+fn set_nice(t: &VcpuThread, nice: i32) -> Result<()> {
+    // PRIO_PROCESS applies to single threads on Linux
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, t.tid.as_raw() as libc::id_t, nice) };
+    if ret != 0 {
+        bail!(
+            "cannot set nice value of thread {}: {}",
+            t.tid,
+            Errno::last()
+        );
+    }
+    Ok(())
+}
+
+pub fn vcpu_pin(opts: &VcpuPinOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let mut threads = topology::vcpu_threads(opts.pid, &vm.vcpus)?;
+    for idx in &opts.vcpus {
+        if !threads.iter().any(|t| t.idx == *idx) {
+            bail!("no thread found for vcpu {}", idx);
+        }
+    }
+    if !opts.vcpus.is_empty() {
+        threads.retain(|t| opts.vcpus.contains(&t.idx));
+    }
+    if let Some(cpus) = &opts.cpus {
+        if cpus.is_empty() || (opts.spread && cpus.len() < threads.len()) {
+            bail!(
+                "{} cpus given, but {} vcpus have to be pinned",
+                cpus.len(),
+                threads.len()
+            );
+        }
+    }
+
+    for (i, t) in threads.iter().enumerate() {
+        if let Some(cpus) = &opts.cpus {
+            let old = try_with!(
+                sched_getaffinity(t.tid),
+                "cannot get affinity of thread {}",
+                t.tid
+            );
+            let new = if opts.spread {
+                cpu_set(&cpus[i..=i])?
+            } else {
+                cpu_set(cpus)?
+            };
+            try_with!(
+                sched_setaffinity(t.tid, &new),
+                "cannot set affinity of thread {}",
+                t.tid
+            );
+            println!(
+                "vcpu {} (tid {}): cpus {} -> {}",
+                t.idx,
+                t.tid,
+                format_cpu_set(&old),
+                format_cpu_set(&new)
+            );
+        }
+        if let Some(nice) = opts.nice {
+            set_nice(t, nice)?;
+            println!("vcpu {} (tid {}): nice {}", t.idx, t.tid, nice);
+        }
+    }
+    info!("updated {} vcpu threads", threads.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8"), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+    }
+
+    #[test]
+    fn test_format_cpu_set() {
+        let set = cpu_set(&[0, 1, 2, 3, 8]).unwrap();
+        assert_eq!(format_cpu_set(&set), "0-3,8");
+    }
+}
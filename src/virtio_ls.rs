@@ -0,0 +1,471 @@
+//! List the virtio devices of a guest with their transport, memory regions and negotiated
+//! features, see `vmsh virtio-ls`.
+//!
+//! Devices of the hypervisor and those vmsh added are found the same way: by walking the device
+//! tree of the guest kernel from the platform bus and the pci root buses. Each virtio device is
+//! a child of its transport, a virtio-mmio platform device or a pci device, whose regions are
+//! the ranges of the same name in the guest's `/proc/iomem`. virtio-mmio devices that have no
+//! virtio device, i.e. because the driver rejected them, are listed as well.
+//!
+//! The config space of a device is emulated by whoever implements the device and cannot be read
+//! from guest memory, only the features the driver negotiated are decoded. The profile (see
+//! `vmi`) needs:
+//!
+//! ```text
+//! device.kobj 0x0
+//! device.p 0x48
+//! device.driver 0x68
+//! device_private.klist_children 0x0
+//! device_private.knode_parent 0x28
+//! device_private.device 0x88
+//! klist.k_list 0x8
+//! klist_node.n_node 0x8
+//! virtio_device.dev 0x10
+//! virtio_device.id 0x8
+//! virtio_device.features 0x348
+//! # optional, for guests with pci
+//! pci_bus.node 0x0
+//! pci_bus.bridge 0x50
+//! ```
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::core_file::CoreFile;
+use crate::devices::virtio::block::BLOCK_DEVICE_ID;
+use crate::devices::virtio::console::CONSOLE_DEVICE_ID;
+use crate::devices::virtio::p9::P9_DEVICE_ID;
+use crate::devices::virtio::vsock::VSOCK_DEVICE_ID;
+use crate::guest_access::{GuestAccess, GuestTarget};
+use crate::kvm;
+use crate::result::Result;
+use crate::vmi::{IoMemResource, KernelMemory, Profile};
+
+pub struct VirtioLsOptions {
+    pub target: GuestTarget,
+    pub profile: PathBuf,
+}
+
+/// Longer device names are not expected, sysfs limits them to the length of a file name.
+const NAME_MAX: usize = 255;
+/// Stop walking device trees that do not end.
+const MAX_DEVICES: usize = 1 << 14;
+/// `name` is the first field of `struct device_driver`.
+const DRIVER_NAME: usize = 0;
+
+/// Device-independent feature bits, named as in the virtio specification without `VIRTIO_F_`.
+const COMMON_FEATURES: &[(u32, &str)] = &[
+    (24, "NOTIFY_ON_EMPTY"),
+    (27, "ANY_LAYOUT"),
+    (28, "RING_INDIRECT_DESC"),
+    (29, "RING_EVENT_IDX"),
+    (32, "VERSION_1"),
+    (33, "ACCESS_PLATFORM"),
+    (34, "RING_PACKED"),
+    (35, "IN_ORDER"),
+    (36, "ORDER_PLATFORM"),
+    (37, "SR_IOV"),
+    (38, "NOTIFICATION_DATA"),
+    (39, "NOTIF_CONFIG_DATA"),
+    (40, "RING_RESET"),
+];
+
+const NET_FEATURES: &[(u32, &str)] = &[
+    (0, "CSUM"),
+    (1, "GUEST_CSUM"),
+    (2, "CTRL_GUEST_OFFLOADS"),
+    (3, "MTU"),
+    (5, "MAC"),
+    (7, "GUEST_TSO4"),
+    (8, "GUEST_TSO6"),
+    (9, "GUEST_ECN"),
+    (10, "GUEST_UFO"),
+    (11, "HOST_TSO4"),
+    (12, "HOST_TSO6"),
+    (13, "HOST_ECN"),
+    (14, "HOST_UFO"),
+    (15, "MRG_RXBUF"),
+    (16, "STATUS"),
+    (17, "CTRL_VQ"),
+    (18, "CTRL_RX"),
+    (19, "CTRL_VLAN"),
+    (21, "GUEST_ANNOUNCE"),
+    (22, "MQ"),
+    (23, "CTRL_MAC_ADDR"),
+];
+
+const BLOCK_FEATURES: &[(u32, &str)] = &[
+    (1, "SIZE_MAX"),
+    (2, "SEG_MAX"),
+    (4, "GEOMETRY"),
+    (5, "RO"),
+    (6, "BLK_SIZE"),
+    (9, "FLUSH"),
+    (10, "TOPOLOGY"),
+    (11, "CONFIG_WCE"),
+    (12, "MQ"),
+    (13, "DISCARD"),
+    (14, "WRITE_ZEROES"),
+    (15, "LIFETIME"),
+    (16, "SECURE_ERASE"),
+    (17, "ZONED"),
+];
+
+const CONSOLE_FEATURES: &[(u32, &str)] = &[(0, "SIZE"), (1, "MULTIPORT"), (2, "EMERG_WRITE")];
+
+const BALLOON_FEATURES: &[(u32, &str)] = &[
+    (0, "MUST_TELL_HOST"),
+    (1, "STATS_VQ"),
+    (2, "DEFLATE_ON_OOM"),
+    (3, "FREE_PAGE_HINT"),
+    (4, "PAGE_POISON"),
+    (5, "REPORTING"),
+];
+
+const P9_FEATURES: &[(u32, &str)] = &[(0, "MOUNT_TAG")];
+
+const VSOCK_FEATURES: &[(u32, &str)] = &[(0, "STREAM"), (1, "SEQPACKET")];
+
+/// Name and feature bits of a virtio device type
+fn device_type(id: u32) -> (Option<&'static str>, &'static [(u32, &'static str)]) {
+    match id {
+        1 => (Some("net"), NET_FEATURES),
+        BLOCK_DEVICE_ID => (Some("block"), BLOCK_FEATURES),
+        CONSOLE_DEVICE_ID => (Some("console"), CONSOLE_FEATURES),
+        4 => (Some("rng"), &[]),
+        5 => (Some("balloon"), BALLOON_FEATURES),
+        8 => (Some("scsi"), &[]),
+        P9_DEVICE_ID => (Some("9p"), P9_FEATURES),
+        16 => (Some("gpu"), &[]),
+        18 => (Some("input"), &[]),
+        VSOCK_DEVICE_ID => (Some("vsock"), VSOCK_FEATURES),
+        20 => (Some("crypto"), &[]),
+        23 => (Some("iommu"), &[]),
+        24 => (Some("mem"), &[]),
+        25 => (Some("sound"), &[]),
+        26 => (Some("fs"), &[]),
+        27 => (Some("pmem"), &[]),
+        _ => (None, &[]),
+    }
+}
+
+fn device_type_name(id: u32) -> String {
+    match device_type(id).0 {
+        Some(name) => name.to_string(),
+        None => format!("type{}", id),
+    }
+}
+
+/// Names of the bits set in `features`, bits unknown for the device type as `bit<n>`.
+fn feature_names(device_id: u32, features: u64) -> Vec<String> {
+    let (_, device_features) = device_type(device_id);
+    (0..64)
+        .filter(|bit| features & (1 << bit) != 0)
+        .map(|bit| {
+            device_features
+                .iter()
+                .chain(COMMON_FEATURES.iter())
+                .find(|(b, _)| *b == bit)
+                .map_or_else(|| format!("bit{}", bit), |(_, name)| name.to_string())
+        })
+        .collect()
+}
+
+/// Devices of the virtio bus are called `virtio<index>`.
+fn is_virtio_device(name: &str) -> bool {
+    name.strip_prefix("virtio").map_or(false, |i| {
+        !i.is_empty() && i.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+/// virtio-mmio transports from the kernel command line, device tree or acpi (`LNRO0005`).
+fn is_mmio_transport(name: &str) -> bool {
+    name.starts_with("virtio-mmio")
+        || name.ends_with(".virtio_mmio")
+        || name.starts_with("LNRO0005:")
+}
+
+/// Pci devices are called `<domain>:<bus>:<slot>.<function>`, i.e. `0000:00:03.0`.
+fn is_pci_name(name: &str) -> bool {
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let parts = name.split(':').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [domain, bus, devfn] => match devfn.split_once('.') {
+            Some((slot, function)) => {
+                hex(domain, 4) && hex(bus, 2) && hex(slot, 2) && hex(function, 1)
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+pub struct VirtioDevice {
+    /// Name on the virtio bus, i.e. `virtio0`
+    pub name: String,
+    pub device_id: u32,
+    pub vendor_id: u32,
+    /// Features both the device and the driver support
+    pub features: u64,
+    pub driver: Option<String>,
+    /// Name of the transport device, i.e. `virtio-mmio.0` or `0000:00:03.0`
+    pub transport: String,
+    pub regions: Vec<Range<usize>>,
+}
+
+/// virtio-mmio device the driver did not create a virtio device for
+pub struct UnboundTransport {
+    pub name: String,
+    pub regions: Vec<Range<usize>>,
+}
+
+struct DeviceNode {
+    addr: usize,
+    name: String,
+    children: Vec<DeviceNode>,
+}
+
+fn device_name(k: &KernelMemory, p: &Profile, dev: usize) -> Result<String> {
+    // `name` is the first field of `struct kobject`
+    k.read_str(k.read_ptr(dev + p.get("device.kobj")?)?, NAME_MAX)
+}
+
+fn device_children(k: &KernelMemory, p: &Profile, dev: usize) -> Result<Vec<usize>> {
+    let private = k.read_ptr(dev + p.get("device.p")?)?;
+    if private == 0 {
+        // allocated when the device is registered
+        return Ok(vec![]);
+    }
+    let head = private + p.get("device_private.klist_children")? + p.get("klist.k_list")?;
+    let to_private = p.get("device_private.knode_parent")? + p.get("klist_node.n_node")?;
+    let device_offset = p.get("device_private.device")?;
+    let mut children = vec![];
+    let mut node = k.read_ptr(head)?;
+    while node != head {
+        if children.len() == MAX_DEVICES {
+            bail!(
+                "children of device {:#x} do not end, check the offsets of device_private",
+                dev
+            );
+        }
+        children.push(k.read_ptr(node.wrapping_sub(to_private) + device_offset)?);
+        node = k.read_ptr(node)?;
+    }
+    Ok(children)
+}
+
+fn device_tree(k: &KernelMemory, p: &Profile, dev: usize, count: &mut usize) -> Result<DeviceNode> {
+    *count += 1;
+    if *count > MAX_DEVICES {
+        bail!("guest has more than {} devices", MAX_DEVICES);
+    }
+    let mut children = vec![];
+    for child in device_children(k, p, dev)? {
+        children.push(device_tree(k, p, child, count)?);
+    }
+    Ok(DeviceNode {
+        addr: dev,
+        name: device_name(k, p, dev)?,
+        children,
+    })
+}
+
+/// The host bridges of all pci root buses, an empty list for guests without pci.
+fn pci_roots(k: &KernelMemory, p: &Profile) -> Result<Vec<usize>> {
+    let (head, node, bridge) = match (
+        k.symbol("pci_root_buses"),
+        p.optional("pci_bus.node"),
+        p.optional("pci_bus.bridge"),
+    ) {
+        (Ok(head), Some(node), Some(bridge)) => (head, node, bridge),
+        _ => return Ok(vec![]),
+    };
+    let mut roots = vec![];
+    let mut bus = k.read_ptr(head)?;
+    while bus != head {
+        if roots.len() == MAX_DEVICES {
+            bail!("pci_root_buses does not end, check the offset of pci_bus.node");
+        }
+        roots.push(k.read_ptr(bus.wrapping_sub(node) + bridge)?);
+        bus = k.read_ptr(bus)?;
+    }
+    Ok(roots)
+}
+
+fn read_virtio_device(
+    k: &KernelMemory,
+    p: &Profile,
+    node: &DeviceNode,
+    transport: &str,
+    iomem: &[IoMemResource],
+) -> Result<VirtioDevice> {
+    let vdev = node.addr.wrapping_sub(p.get("virtio_device.dev")?);
+    let id = vdev + p.get("virtio_device.id")?;
+    let driver = match k.read_ptr(node.addr + p.get("device.driver")?)? {
+        0 => None,
+        driver => Some(k.read_str(k.read_ptr(driver + DRIVER_NAME)?, NAME_MAX)?),
+    };
+    Ok(VirtioDevice {
+        name: node.name.clone(),
+        device_id: k.read_u32(id)?,
+        vendor_id: k.read_u32(id + 4)?,
+        features: k.read_u64(vdev + p.get("virtio_device.features")?)?,
+        driver,
+        transport: transport.to_string(),
+        regions: regions(iomem, transport),
+    })
+}
+
+fn regions(iomem: &[IoMemResource], name: &str) -> Vec<Range<usize>> {
+    iomem
+        .iter()
+        .filter(|r| r.name == name)
+        .map(|r| r.range.clone())
+        .collect()
+}
+
+/// Virtio devices of the guest and virtio-mmio transports without a virtio device
+pub fn virtio_devices(
+    k: &KernelMemory,
+    p: &Profile,
+) -> Result<(Vec<VirtioDevice>, Vec<UnboundTransport>)> {
+    let iomem = k.iomem_resources()?;
+    let mut roots = vec![k.symbol("platform_bus")?];
+    roots.extend(pci_roots(k, p)?);
+
+    let mut count = 0;
+    let mut stack = vec![];
+    for root in roots {
+        stack.push(device_tree(k, p, root, &mut count)?);
+    }
+    let mut devices = vec![];
+    let mut unbound = vec![];
+    while let Some(node) = stack.pop() {
+        let virtio = node
+            .children
+            .iter()
+            .filter(|c| is_virtio_device(&c.name))
+            .collect::<Vec<_>>();
+        if virtio.is_empty() && is_mmio_transport(&node.name) {
+            unbound.push(UnboundTransport {
+                regions: regions(&iomem, &node.name),
+                name: node.name.clone(),
+            });
+        }
+        for child in virtio {
+            devices.push(read_virtio_device(k, p, child, &node.name, &iomem)?);
+        }
+        stack.extend(node.children);
+    }
+    devices.sort_by_key(|d| d.name[b"virtio".len()..].parse::<u32>().unwrap_or(u32::MAX));
+    unbound.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((devices, unbound))
+}
+
+fn format_regions(regions: &[Range<usize>]) -> String {
+    if regions.is_empty() {
+        return String::from("-");
+    }
+    regions
+        .iter()
+        .map(|r| format!("{:#x}-{:#x}", r.start, r.end - 1))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn print_devices(devices: &[VirtioDevice], unbound: &[UnboundTransport]) {
+    println!(
+        "{:<9} {:<8} {:<9} {:<16} {:<14} REGIONS",
+        "DEVICE", "TYPE", "TRANSPORT", "PARENT", "DRIVER"
+    );
+    for device in devices {
+        let transport = if is_pci_name(&device.transport) {
+            "pci"
+        } else {
+            "mmio"
+        };
+        println!(
+            "{:<9} {:<8} {:<9} {:<16} {:<14} {}",
+            device.name,
+            device_type_name(device.device_id),
+            transport,
+            device.transport,
+            device.driver.as_deref().unwrap_or("-"),
+            format_regions(&device.regions)
+        );
+        println!(
+            "  vendor {:#x}, features {:#x}: {}",
+            device.vendor_id,
+            device.features,
+            feature_names(device.device_id, device.features).join(" ")
+        );
+    }
+    for transport in unbound {
+        println!(
+            "{} at {} has no virtio device, see the kernel log of the guest",
+            transport.name,
+            format_regions(&transport.regions)
+        );
+    }
+}
+
+fn virtio_ls_guest(src: &dyn GuestAccess, opts: &VirtioLsOptions) -> Result<()> {
+    let k = KernelMemory::new(src)?;
+    let p = Profile::load_for_kernel(&opts.profile, &k)?;
+    let (devices, unbound) = virtio_devices(&k, &p)?;
+    print_devices(&devices, &unbound);
+    Ok(())
+}
+
+pub fn virtio_ls(opts: &VirtioLsOptions) -> Result<()> {
+    match &opts.target {
+        GuestTarget::Pid(pid) => {
+            let vm = try_with!(
+                kvm::hypervisor::get_hypervisor(*pid),
+                "cannot get vms for process {}",
+                pid
+            );
+            let _stopped = vm.stop_guard()?;
+            virtio_ls_guest(&vm, opts)
+        }
+        GuestTarget::Core(path) => {
+            let core = try_with!(CoreFile::open(path), "cannot load coredump");
+            virtio_ls_guest(&core, opts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feature_names() {
+        // VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_FLUSH, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1
+        let features = 1 << 2 | 1 << 9 | 1 << 29 | 1 << 32;
+        assert_eq!(
+            feature_names(BLOCK_DEVICE_ID, features),
+            vec!["SEG_MAX", "FLUSH", "RING_EVENT_IDX", "VERSION_1"]
+        );
+        // device specific bits depend on the type
+        assert_eq!(feature_names(1, 1 << 5), vec!["MAC"]);
+        assert_eq!(feature_names(42, 1 << 5 | 1 << 63), vec!["bit5", "bit63"]);
+        assert_eq!(device_type_name(VSOCK_DEVICE_ID), "vsock");
+        assert_eq!(device_type_name(42), "type42");
+    }
+
+    #[test]
+    fn test_device_names() {
+        assert!(is_virtio_device("virtio0"));
+        assert!(is_virtio_device("virtio12"));
+        assert!(!is_virtio_device("virtio"));
+        assert!(!is_virtio_device("virtio-mmio.0"));
+        assert!(is_mmio_transport("virtio-mmio.0"));
+        assert!(is_mmio_transport("a003e00.virtio_mmio"));
+        assert!(is_mmio_transport("LNRO0005:01"));
+        assert!(!is_mmio_transport("serial8250"));
+        assert!(is_pci_name("0000:00:03.0"));
+        assert!(!is_pci_name("0000:00:03"));
+        assert!(!is_pci_name("virtio-mmio.0"));
+    }
+}
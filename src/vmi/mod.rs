@@ -0,0 +1,317 @@
+//! Access to data structures of the guest kernel for introspection commands like
+//! `vmsh process-dump` and `vmsh vcat`.
+//!
+//! vmsh has no debug information of the guest kernel, so the offsets of the struct fields these
+//! commands need are read from a profile. It has one offset per line, relative to the start of
+//! the outermost struct, as printed by `pahole` or `gdb -ex 'ptype /o struct task_struct'
+//! vmlinux`:
+//!
+//! ```text
+//! # struct.field offset
+//! task_struct.tasks 0x4c8
+//! mm_struct.mm_mt.ma_root 0x48
+//! thread_size 0x4000
+//! ```
+//!
+//! The fields each command needs are listed in its documentation. Profiles can also be derived
+//! from BTF and picked by kernel release from a directory, see `profiles`.
+use simple_error::{bail, require_with, try_with};
+use std::ops::Range;
+
+use crate::guest_access::GuestAccess;
+use crate::guest_mem::{get_page_table_addr, GuestMem};
+use crate::kernel::{find_kernel, Kernel};
+use crate::page_math::page_size;
+use crate::page_table::{self, PhysAddr};
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+use crate::tracer::proc::Mapping;
+
+pub mod profiles;
+
+pub use profiles::Profile;
+
+/// Bit 12 selects the user page table when page table isolation is enabled.
+pub const PTI_USER_PGTABLE: usize = 1 << 12;
+
+/// Length of each field in `struct new_utsname`
+const UTS_LEN: usize = 65;
+
+/// Offsets in `struct resource`, which did not change since linux 4.6.
+const RESOURCE_START: usize = 0;
+const RESOURCE_END: usize = 8;
+const RESOURCE_NAME: usize = 16;
+const RESOURCE_SIBLING: usize = 48;
+const RESOURCE_CHILD: usize = 56;
+/// Stop following sibling pointers after this many resources in case they form a loop.
+const MAX_RESOURCES: usize = 4096;
+/// Longer names are not expected, they are device and driver names.
+const RESOURCE_NAME_MAX: usize = 255;
+
+/// Find the release in the beginning of `struct uts_namespace`. Older kernels have a `kref` in
+/// front of the `struct new_utsname`, so we look for the sysname instead of using an offset.
+fn utsname_release(buf: &[u8]) -> Option<&str> {
+    let sysname = buf.windows(6).position(|w| w == b"Linux\0")?;
+    // sysname, nodename, release
+    let release = buf.get(sysname + 2 * UTS_LEN..sysname + 3 * UTS_LEN)?;
+    let len = release.iter().position(|b| *b == 0)?;
+    std::str::from_utf8(&release[..len]).ok()
+}
+
+/// Node of the guest's `/proc/iomem` tree
+pub struct IoMemResource {
+    pub name: String,
+    pub range: Range<usize>,
+}
+
+/// Reads guest memory by virtual address.
+pub struct GuestMemory<'a> {
+    pub src: &'a dyn GuestAccess,
+    pub maps: Vec<Mapping>,
+    /// Page table levels of the guest, see `page_table::paging_levels`
+    pub levels: u8,
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(src: &'a dyn GuestAccess) -> Result<GuestMemory<'a>> {
+        // all vcpus use the same paging mode
+        let sregs = src.vcpu_sregs(0)?;
+        Ok(GuestMemory {
+            src,
+            maps: src.memory_maps()?,
+            levels: page_table::paging_levels(sregs.cr4),
+        })
+    }
+
+    pub fn phys_mapping(&self, phys: usize) -> Option<&Mapping> {
+        self.maps
+            .iter()
+            .find(|m| m.phys_addr <= phys && phys < m.phys_end())
+    }
+
+    pub fn page_table(&self, phys: usize) -> Result<PhysAddr> {
+        let m = require_with!(
+            self.phys_mapping(phys),
+            "page table at {:#x} is not in guest memory",
+            phys
+        );
+        Ok(PhysAddr {
+            value: phys,
+            host_offset: m.phys_to_host_offset(),
+        })
+    }
+
+    pub fn read_phys(&self, phys: usize, buf: &mut [u8]) -> Result<()> {
+        let m = require_with!(
+            self.phys_mapping(phys),
+            "{:#x} is not in guest memory",
+            phys
+        );
+        self.src.read_bytes(m.start + (phys - m.phys_addr), buf)
+    }
+
+    pub fn read_bytes(&self, pml4: &PhysAddr, addr: usize, buf: &mut [u8]) -> Result<()> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let pos = addr + offset;
+            let n = std::cmp::min(page_size() - (pos & (page_size() - 1)), buf.len() - offset);
+            let phys = require_with!(
+                page_table::translate(self.src, pml4, self.levels, pos)?,
+                "{:#x} is not mapped",
+                pos
+            );
+            self.read_phys(phys, &mut buf[offset..offset + n])?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    pub fn read_u64(&self, pml4: &PhysAddr, addr: usize) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_bytes(pml4, addr, &mut buf)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    pub fn read_i32(&self, pml4: &PhysAddr, addr: usize) -> Result<i32> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(pml4, addr, &mut buf)?;
+        Ok(i32::from_ne_bytes(buf))
+    }
+}
+
+/// Memory of a stopped guest as seen by its kernel.
+pub struct KernelMemory<'a> {
+    pub mem: GuestMemory<'a>,
+    pub kernel: Kernel,
+    /// The kernel half of all page tables is the same, we use the one of the first vcpu.
+    pub pml4: PhysAddr,
+}
+
+impl<'a> KernelMemory<'a> {
+    pub fn new(src: &'a dyn GuestAccess) -> Result<KernelMemory<'a>> {
+        KernelMemory::with_fallbacks(src, &[])
+    }
+
+    /// Like `new`, but tries the symbols of `fallbacks` if the exported symbols cannot be found.
+    pub fn with_fallbacks(
+        src: &'a dyn GuestAccess,
+        fallbacks: &[SymbolSource],
+    ) -> Result<KernelMemory<'a>> {
+        let guest_mem = GuestMem::new(src)?;
+        let kernel = try_with!(
+            find_kernel(&guest_mem, src, fallbacks),
+            "could not find kernel"
+        );
+        let mem = GuestMemory::new(src)?;
+        // the user page table does not map the kernel
+        let sregs = src.vcpu_sregs(0)?;
+        let pml4 = mem.page_table(get_page_table_addr(&sregs) & !PTI_USER_PGTABLE)?;
+        Ok(KernelMemory { mem, kernel, pml4 })
+    }
+
+    /// Address of an exported kernel symbol.
+    pub fn symbol(&self, name: &str) -> Result<usize> {
+        Ok(*require_with!(
+            self.kernel.symbols.get(name),
+            "{} not found in kernel symbols",
+            name
+        ))
+    }
+
+    pub fn virt_to_phys(&self, addr: usize) -> Result<usize> {
+        Ok(require_with!(
+            page_table::translate(self.mem.src, &self.pml4, self.mem.levels, addr)?,
+            "{:#x} is not mapped",
+            addr
+        ))
+    }
+
+    pub fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        self.mem.read_bytes(&self.pml4, addr, buf)
+    }
+
+    pub fn read_u64(&self, addr: usize) -> Result<u64> {
+        self.mem.read_u64(&self.pml4, addr)
+    }
+
+    pub fn read_i32(&self, addr: usize) -> Result<i32> {
+        self.mem.read_i32(&self.pml4, addr)
+    }
+
+    pub fn read_u32(&self, addr: usize) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    pub fn read_u8(&self, addr: usize) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Null-terminated string of at most `max_len` bytes at `addr`. Only reads up to the end of
+    /// the page with the terminator.
+    pub fn read_str(&self, addr: usize, max_len: usize) -> Result<String> {
+        let mut s = vec![];
+        let mut pos = addr;
+        while s.len() <= max_len {
+            let len = std::cmp::min(page_size() - (pos % page_size()), max_len + 1 - s.len());
+            let mut chunk = vec![0u8; len];
+            self.read_bytes(pos, &mut chunk)?;
+            if let Some(end) = chunk.iter().position(|b| *b == 0) {
+                s.extend_from_slice(&chunk[..end]);
+                return Ok(String::from_utf8_lossy(&s).into_owned());
+            }
+            s.extend_from_slice(&chunk);
+            pos += len;
+        }
+        bail!("string at {:#x} is not terminated", addr)
+    }
+
+    /// Read a pointer, which is how kernel structs refer to each other.
+    pub fn read_ptr(&self, addr: usize) -> Result<usize> {
+        Ok(self.read_u64(addr)? as usize)
+    }
+
+    /// Top-level ranges of physical memory called `name` in the guest's `/proc/iomem`.
+    pub fn iomem_ranges(&self, name: &str) -> Result<Vec<Range<usize>>> {
+        let root = self.symbol("iomem_resource")?;
+        let mut ranges = vec![];
+        let mut res = self.read_ptr(root + RESOURCE_CHILD)?;
+        for _ in 0..MAX_RESOURCES {
+            if res == 0 {
+                return Ok(ranges);
+            }
+            let name_ptr = self.read_ptr(res + RESOURCE_NAME)?;
+            if name_ptr != 0 {
+                let mut buf = vec![0u8; name.len() + 1];
+                self.read_bytes(name_ptr, &mut buf)?;
+                if &buf[..name.len()] == name.as_bytes() && buf[name.len()] == 0 {
+                    let start = self.read_u64(res + RESOURCE_START)? as usize;
+                    // the end is inclusive
+                    let end = self.read_u64(res + RESOURCE_END)? as usize + 1;
+                    ranges.push(start..end);
+                }
+            }
+            res = self.read_ptr(res + RESOURCE_SIBLING)?;
+        }
+        bail!("iomem_resource has more than {} entries", MAX_RESOURCES)
+    }
+
+    /// All ranges in the guest's `/proc/iomem`, parents before their children.
+    pub fn iomem_resources(&self) -> Result<Vec<IoMemResource>> {
+        let root = self.symbol("iomem_resource")?;
+        let mut resources = vec![];
+        let mut next = vec![self.read_ptr(root + RESOURCE_CHILD)?];
+        while let Some(res) = next.pop() {
+            if res == 0 {
+                continue;
+            }
+            if resources.len() == MAX_RESOURCES {
+                bail!("iomem_resource has more than {} entries", MAX_RESOURCES);
+            }
+            let name_ptr = self.read_ptr(res + RESOURCE_NAME)?;
+            let name = if name_ptr == 0 {
+                String::new()
+            } else {
+                self.read_str(name_ptr, RESOURCE_NAME_MAX)?
+            };
+            let start = self.read_u64(res + RESOURCE_START)? as usize;
+            // the end is inclusive
+            let end = self.read_u64(res + RESOURCE_END)? as usize + 1;
+            resources.push(IoMemResource {
+                name,
+                range: start..end,
+            });
+            next.push(self.read_ptr(res + RESOURCE_SIBLING)?);
+            next.push(self.read_ptr(res + RESOURCE_CHILD)?);
+        }
+        Ok(resources)
+    }
+
+    /// Release of the guest kernel as in `uname -r`, i.e. `5.15.0-1-amd64`.
+    pub fn release(&self) -> Result<String> {
+        let mut buf = [0u8; 4 * UTS_LEN];
+        self.read_bytes(self.symbol("init_uts_ns")?, &mut buf)?;
+        Ok(require_with!(utsname_release(&buf), "cannot parse init_uts_ns").to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utsname_release() {
+        let mut buf = vec![0u8; 4 + 4 * UTS_LEN];
+        // kref
+        buf[0] = 3;
+        buf[4..9].copy_from_slice(b"Linux");
+        buf[4 + UTS_LEN..4 + UTS_LEN + 6].copy_from_slice(b"guest1");
+        buf[4 + 2 * UTS_LEN..4 + 2 * UTS_LEN + 6].copy_from_slice(b"5.10.0");
+        assert_eq!(utsname_release(&buf), Some("5.10.0"));
+        assert_eq!(utsname_release(&buf[10..]), None);
+        assert_eq!(utsname_release(&buf[..4 + 2 * UTS_LEN]), None);
+    }
+}
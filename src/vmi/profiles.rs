@@ -0,0 +1,538 @@
+//! Struct offsets of guest kernels, shared by the introspection commands.
+//!
+//! A profile is either a text file with one `struct.field offset` per line (see `vmi`) or the
+//! raw BTF of the guest kernel, i.e. a copy of `/sys/kernel/btf/vmlinux`, from which any
+//! `struct.field` offset is derived. `struct_<name>_size` keys are derived from BTF as the size
+//! of `struct <name>`.
+//!
+//! Instead of a single file, commands accept a directory of profiles named after the kernel
+//! release, e.g. `6.1.0-13-amd64.profile` or `6.1.btf`. The file with the longest name that
+//! matches the release of the guest (`uname -r`) is used, so `6.1.btf` serves all 6.1 kernels
+//! that do not have a more specific profile. If both a `.profile` and a `.btf` file exist for
+//! the same name, the offsets in the text file take precedence over BTF. Supporting a new
+//! kernel is a matter of adding a file to this directory.
+//!
+//! The `profile` option of each introspection command is such a file or directory. The offsets
+//! a command needs are listed in the documentation of its module.
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::result::Result;
+use crate::vmi::KernelMemory;
+
+/// Used by commands when no profile is given.
+pub const DEFAULT_PROFILE_DIR: &str = "/etc/vmsh/profiles";
+
+const PROFILE_EXTENSION: &str = "profile";
+const BTF_EXTENSION: &str = "btf";
+
+pub struct Profile {
+    offsets: HashMap<String, usize>,
+    btf: Option<Btf>,
+}
+
+/// Decimal number or hexadecimal with `0x`, as used for offsets in profiles.
+pub(crate) fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl Profile {
+    pub fn parse(content: &str) -> Result<Profile> {
+        let mut offsets = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match (fields.len(), fields.get(1).and_then(|v| parse_number(v))) {
+                (2, Some(offset)) => {
+                    offsets.insert(fields[0].to_string(), offset);
+                }
+                _ => bail!("invalid offset in line {}: {}", i + 1, line),
+            }
+        }
+        Ok(Profile { offsets, btf: None })
+    }
+
+    /// Text profile or raw BTF, told apart by the BTF magic.
+    pub fn load(path: &Path) -> Result<Profile> {
+        let content = try_with!(fs::read(path), "cannot read {}", path.display());
+        if content.starts_with(&BTF_MAGIC.to_le_bytes()) {
+            let btf = try_with!(Btf::parse(content), "invalid btf in {}", path.display());
+            return Ok(Profile {
+                offsets: HashMap::new(),
+                btf: Some(btf),
+            });
+        }
+        let content = try_with!(
+            String::from_utf8(content),
+            "{} is neither a text profile nor btf",
+            path.display()
+        );
+        Ok(try_with!(
+            Profile::parse(&content),
+            "invalid profile {}",
+            path.display()
+        ))
+    }
+
+    /// Like `load`, but `path` can also be a directory of profiles, from which the one for the
+    /// release of the guest kernel is picked.
+    pub fn load_for_kernel(path: &Path, k: &KernelMemory) -> Result<Profile> {
+        if !path.is_dir() {
+            return Profile::load(path);
+        }
+        let release = try_with!(k.release(), "cannot get release of the guest kernel");
+        let names = try_with!(
+            profile_names(path),
+            "cannot list profiles in {}",
+            path.display()
+        );
+        let name = require_with!(
+            best_match(&names, &release),
+            "no profile for linux {} in {}",
+            release,
+            path.display()
+        );
+        let text = path.join(format!("{}.{}", name, PROFILE_EXTENSION));
+        let btf = path.join(format!("{}.{}", name, BTF_EXTENSION));
+        let mut profile = if btf.exists() {
+            Profile::load(&btf)?
+        } else {
+            Profile {
+                offsets: HashMap::new(),
+                btf: None,
+            }
+        };
+        if text.exists() {
+            profile.offsets = Profile::load(&text)?.offsets;
+        }
+        Ok(profile)
+    }
+
+    pub fn get(&self, field: &str) -> Result<usize> {
+        Ok(require_with!(
+            self.optional(field),
+            "profile has no offset for {}",
+            field
+        ))
+    }
+
+    pub fn optional(&self, field: &str) -> Option<usize> {
+        if let Some(offset) = self.offsets.get(field) {
+            return Some(*offset);
+        }
+        let btf = self.btf.as_ref()?;
+        match field
+            .strip_prefix("struct_")
+            .and_then(|f| f.strip_suffix("_size"))
+        {
+            Some(name) => btf.struct_size(name),
+            None => btf.offset_of(field),
+        }
+    }
+}
+
+/// File names without extension of all profiles in `dir`.
+fn profile_names(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|e| e.to_str());
+        if ext != Some(PROFILE_EXTENSION) && ext != Some(BTF_EXTENSION) {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            names.push(stem.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// The longest name that is the release itself or a prefix of it that ends before a `.` or `-`,
+/// i.e. `6.1` matches `6.1.0-13-amd64` but not `6.10.2`.
+fn best_match<'a>(names: &'a [String], release: &str) -> Option<&'a str> {
+    names
+        .iter()
+        .filter(|name| match release.strip_prefix(name.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with('-'),
+            None => false,
+        })
+        .max_by_key(|name| name.len())
+        .map(|name| name.as_str())
+}
+
+const BTF_MAGIC: u16 = 0xeb9f;
+/// magic, version, flags, hdr_len, type_off, type_len, str_off, str_len
+const BTF_HEADER_SIZE: usize = 24;
+
+// Kinds of btf types, see include/uapi/linux/btf.h
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_FWD: u32 = 7;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+/// Stop following typedefs and qualifiers that form a loop.
+const MAX_TYPE_DEPTH: usize = 32;
+
+struct BtfMember {
+    name_off: u32,
+    type_id: u32,
+    /// in bits
+    offset: u32,
+}
+
+struct BtfType {
+    name_off: u32,
+    kind: u32,
+    /// size for structs and unions, referenced type for typedefs and qualifiers
+    size_or_type: u32,
+    members: Vec<BtfMember>,
+}
+
+/// The struct layouts of a kernel in the BPF Type Format.
+pub struct Btf {
+    /// index 0 is `void`
+    types: Vec<BtfType>,
+    strings: Vec<u8>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32> {
+    let bytes = require_with!(buf.get(offset..offset + 4), "btf is truncated");
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl Btf {
+    pub fn parse(buf: Vec<u8>) -> Result<Btf> {
+        Btf::parse_with_base(buf, None)
+    }
+
+    /// Split BTF of a kernel module, i.e. `/sys/kernel/btf/kvm`, whose type ids and string
+    /// offsets continue those of `base`, the BTF of the kernel itself.
+    pub fn parse_split(buf: Vec<u8>, base: Btf) -> Result<Btf> {
+        Btf::parse_with_base(buf, Some(base))
+    }
+
+    fn parse_with_base(buf: Vec<u8>, base: Option<Btf>) -> Result<Btf> {
+        if buf.len() < BTF_HEADER_SIZE || buf[0..2] != BTF_MAGIC.to_le_bytes() {
+            bail!("no little endian btf");
+        }
+        let hdr_len = read_u32(&buf, 4)? as usize;
+        let type_off = hdr_len + read_u32(&buf, 8)? as usize;
+        let type_len = read_u32(&buf, 12)? as usize;
+        let str_off = hdr_len + read_u32(&buf, 16)? as usize;
+        let str_len = read_u32(&buf, 20)? as usize;
+        let own_strings = require_with!(
+            buf.get(str_off..str_off + str_len),
+            "btf string section is truncated"
+        );
+        let type_data = require_with!(
+            buf.get(type_off..type_off + type_len),
+            "btf type section is truncated"
+        );
+
+        // appending to the base keeps the ids and offsets of split btf valid
+        let (mut types, mut strings) = match base {
+            Some(base) => (base.types, base.strings),
+            None => (
+                vec![BtfType {
+                    name_off: 0,
+                    kind: 0,
+                    size_or_type: 0,
+                    members: vec![],
+                }],
+                vec![],
+            ),
+        };
+        strings.extend_from_slice(own_strings);
+        let mut pos = 0;
+        while pos < type_data.len() {
+            let name_off = read_u32(type_data, pos)?;
+            let info = read_u32(type_data, pos + 4)?;
+            let size_or_type = read_u32(type_data, pos + 8)?;
+            pos += 12;
+            let vlen = (info & 0xffff) as usize;
+            let kind = (info >> 24) & 0x1f;
+            let kind_flag = info >> 31 != 0;
+            let mut members = vec![];
+            match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => pos += 4,
+                BTF_KIND_ARRAY => pos += 12,
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for i in 0..vlen {
+                        let m = pos + i * 12;
+                        let offset = read_u32(type_data, m + 8)?;
+                        members.push(BtfMember {
+                            name_off: read_u32(type_data, m)?,
+                            type_id: read_u32(type_data, m + 4)?,
+                            // the upper 8 bits hold the size of bitfields
+                            offset: if kind_flag {
+                                offset & 0xff_ffff
+                            } else {
+                                offset
+                            },
+                        });
+                    }
+                    pos += vlen * 12;
+                }
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => pos += vlen * 8,
+                BTF_KIND_DATASEC | BTF_KIND_ENUM64 => pos += vlen * 12,
+                // no data after the type
+                BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE
+                | BTF_KIND_CONST | BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT
+                | BTF_KIND_TYPE_TAG => {}
+                _ => bail!("unknown btf kind {}", kind),
+            }
+            types.push(BtfType {
+                name_off,
+                kind,
+                size_or_type,
+                members,
+            });
+        }
+        if pos != type_data.len() {
+            bail!("btf type section is truncated");
+        }
+        Ok(Btf { types, strings })
+    }
+
+    fn name(&self, name_off: u32) -> &[u8] {
+        let tail = self.strings.get(name_off as usize..).unwrap_or(&[]);
+        let len = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+        &tail[..len]
+    }
+
+    fn find_struct(&self, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|t| {
+                (t.kind == BTF_KIND_STRUCT || t.kind == BTF_KIND_UNION)
+                    && !t.members.is_empty()
+                    && self.name(t.name_off) == name.as_bytes()
+            })
+            .map(|idx| idx as u32)
+    }
+
+    /// Skips typedefs and qualifiers.
+    fn resolve(&self, mut type_id: u32) -> Option<&BtfType> {
+        for _ in 0..MAX_TYPE_DEPTH {
+            let t = self.types.get(type_id as usize)?;
+            match t.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+                | BTF_KIND_TYPE_TAG => type_id = t.size_or_type,
+                _ => return Some(t),
+            }
+        }
+        None
+    }
+
+    /// Bit offset and type of a member, which can be nested in anonymous structs and unions.
+    fn member(&self, t: &BtfType, name: &str, depth: usize) -> Option<(u32, u32)> {
+        if depth > MAX_TYPE_DEPTH {
+            return None;
+        }
+        for m in &t.members {
+            if self.name(m.name_off) == name.as_bytes() {
+                return Some((m.offset, m.type_id));
+            }
+            if m.name_off == 0 {
+                let inner = self.resolve(m.type_id)?;
+                if let Some((offset, type_id)) = self.member(inner, name, depth + 1) {
+                    return Some((m.offset + offset, type_id));
+                }
+            }
+        }
+        None
+    }
+
+    /// Bit offset of `struct.field.subfield` from the start of the struct and its type.
+    fn field(&self, path: &str) -> Option<(usize, &BtfType)> {
+        let mut fields = path.split('.');
+        let mut t = &self.types[self.find_struct(fields.next()?)? as usize];
+        let mut bits = 0;
+        let mut found = false;
+        for field in fields {
+            if t.kind != BTF_KIND_STRUCT && t.kind != BTF_KIND_UNION {
+                return None;
+            }
+            let (offset, type_id) = self.member(t, field, 0)?;
+            bits += offset as usize;
+            t = self.resolve(type_id)?;
+            found = true;
+        }
+        if !found {
+            return None;
+        }
+        Some((bits, t))
+    }
+
+    /// Byte offset of `struct.field.subfield` from the start of the struct.
+    pub fn offset_of(&self, path: &str) -> Option<usize> {
+        let (bits, _) = self.field(path)?;
+        if bits % 8 != 0 {
+            return None;
+        }
+        Some(bits / 8)
+    }
+
+    /// Size of the type of `struct.field`, None for arrays and other types without a size.
+    pub fn member_size(&self, path: &str) -> Option<usize> {
+        let (_, t) = self.field(path)?;
+        match t.kind {
+            BTF_KIND_PTR => Some(std::mem::size_of::<usize>()),
+            BTF_KIND_INT | BTF_KIND_ENUM | BTF_KIND_ENUM64 | BTF_KIND_FLOAT | BTF_KIND_STRUCT
+            | BTF_KIND_UNION => Some(t.size_or_type as usize),
+            _ => None,
+        }
+    }
+
+    pub fn struct_size(&self, name: &str) -> Option<usize> {
+        let idx = self.find_struct(name)?;
+        Some(self.types[idx as usize].size_or_type as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let content = "# linux 6.1\ntask_struct.pid 0x5c0\n\nthread_size 16384\n";
+        let profile = Profile::parse(content).unwrap();
+        assert_eq!(profile.get("task_struct.pid").unwrap(), 0x5c0);
+        assert_eq!(profile.optional("thread_size"), Some(0x4000));
+        assert!(profile.get("task_struct.mm").is_err());
+        assert!(Profile::parse("task_struct.pid").is_err());
+        assert!(Profile::parse("task_struct.pid 0x5c0 0x8").is_err());
+    }
+
+    #[test]
+    fn test_best_match() {
+        let names = ["6.1", "6.1.0-13-amd64", "5.15", "6"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(best_match(&names, "6.1.0-13-amd64"), Some("6.1.0-13-amd64"));
+        assert_eq!(best_match(&names, "6.1.0-12-amd64"), Some("6.1"));
+        assert_eq!(best_match(&names, "6.10.2"), Some("6"));
+        assert_eq!(best_match(&names, "5.4.0"), None);
+    }
+
+    /// Encodes types as (name, info, size_or_type, extra u32s).
+    fn btf(types: &[(u32, u32, u32, Vec<u32>)], strings: &[u8]) -> Vec<u8> {
+        let mut type_data = vec![];
+        for (name, info, size, extra) in types {
+            for v in [*name, *info, *size].iter().chain(extra.iter()) {
+                type_data.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&[1, 0]);
+        for v in &[
+            BTF_HEADER_SIZE as u32,
+            0,
+            type_data.len() as u32,
+            type_data.len() as u32,
+            strings.len() as u32,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&type_data);
+        buf.extend_from_slice(strings);
+        buf
+    }
+
+    #[test]
+    fn test_btf() {
+        // 1: unsigned long, 2: struct inner { long a; long b; }, 3: typedef inner_t,
+        // 4: struct outer { int x; union { inner_t in; }; unsigned flag:1; }, 5: the union
+        let strings = b"\0long\0inner\0a\0b\0inner_t\0outer\0x\0in\0flag\0";
+        let s = |name: &str| {
+            let needle = format!("\0{}\0", name);
+            (strings
+                .windows(needle.len())
+                .position(|w| w == needle.as_bytes())
+                .unwrap()
+                + 1) as u32
+        };
+        let info = |kind: u32, vlen: u32| (kind << 24) | vlen;
+        let types = vec![
+            (s("long"), info(BTF_KIND_INT, 0), 8, vec![64]),
+            (
+                s("inner"),
+                info(BTF_KIND_STRUCT, 2),
+                16,
+                vec![s("a"), 1, 0, s("b"), 1, 64],
+            ),
+            (s("inner_t"), info(BTF_KIND_TYPEDEF, 0), 2, vec![]),
+            (
+                s("outer"),
+                info(BTF_KIND_STRUCT, 3) | 1 << 31,
+                32,
+                // bitfield of size 1 at bit 192
+                vec![s("x"), 1, 0, 0, 5, 64, s("flag"), 1, (1 << 24) | 192],
+            ),
+            (0, info(BTF_KIND_UNION, 1), 16, vec![s("in"), 3, 0]),
+        ];
+        let btf = Btf::parse(btf(&types, strings)).unwrap();
+        assert_eq!(btf.offset_of("outer.x"), Some(0));
+        assert_eq!(btf.offset_of("outer.in"), Some(8));
+        assert_eq!(btf.offset_of("outer.in.b"), Some(16));
+        assert_eq!(btf.offset_of("outer.flag"), Some(24));
+        assert_eq!(btf.offset_of("outer.missing"), None);
+        assert_eq!(btf.offset_of("outer"), None);
+        assert_eq!(btf.offset_of("outer.x.y"), None);
+        assert_eq!(btf.struct_size("inner"), Some(16));
+        assert_eq!(btf.member_size("outer.x"), Some(8));
+        assert_eq!(btf.member_size("outer.in"), Some(16));
+
+        let profile = Profile {
+            offsets: HashMap::new(),
+            btf: Some(btf),
+        };
+        assert_eq!(profile.optional("struct_outer_size"), Some(32));
+        assert_eq!(profile.get("inner.b").unwrap(), 8);
+        assert!(Btf::parse(vec![0; BTF_HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_split_btf() {
+        let base_strings = b"\0long\0";
+        let info = |kind: u32, vlen: u32| (kind << 24) | vlen;
+        let base = btf(&[(1, info(BTF_KIND_INT, 0), 8, vec![64])], base_strings);
+        let base = Btf::parse(base).unwrap();
+        // the module refers to `long` of the base, its strings start after those of the base
+        let strings = b"\0kvm\0nr\0";
+        let off = |o: u32| base_strings.len() as u32 + o;
+        let module = btf(
+            &[(off(1), info(BTF_KIND_STRUCT, 1), 8, vec![off(5), 1, 0])],
+            strings,
+        );
+        let btf = Btf::parse_split(module, base).unwrap();
+        assert_eq!(btf.offset_of("kvm.nr"), Some(0));
+        assert_eq!(btf.member_size("kvm.nr"), Some(8));
+        assert_eq!(btf.struct_size("kvm"), Some(8));
+    }
+}
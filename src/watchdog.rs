@@ -0,0 +1,267 @@
+//! Lightweight guest watchdog: periodically looks for signs of a kernel panic or OOM kill in the
+//! guest and runs configured actions once one is found.
+use log::{info, warn};
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use crate::coredump::{self, CoreFormat, CoredumpOptions};
+use crate::guest_access::GuestAccess;
+use crate::guest_mem::{GuestMem, MappedMemory};
+use crate::json::json_escape;
+use crate::kernel::find_kernel;
+use crate::kvm;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::symbolizer::SymbolSource;
+
+/// Messages the kernel writes to its log buffer. Each is counted in the kernel data sections;
+/// a new occurrence means the guest printed it after we started watching.
+const KMSG_MARKERS: &[(&str, &[u8])] = &[
+    ("panic", b"Kernel panic - not syncing"),
+    ("oom", b"Out of memory: Killed process"),
+];
+
+/// A vcpu executing one of these functions is about to halt.
+const PANIC_FUNCTIONS: &[&str] = &["panic", "nmi_panic"];
+
+pub enum Action {
+    Coredump(PathBuf),
+    Nmi,
+    Webhook(String),
+}
+
+pub struct WatchOptions {
+    pub pid: Pid,
+    pub interval: Duration,
+    pub actions: Vec<Action>,
+    /// Where to find the panic functions.
+    pub symbols: SymbolSource,
+}
+
+#[derive(Debug)]
+pub struct Event {
+    /// `panic` or `oom`
+    pub kind: &'static str,
+    pub description: String,
+}
+
+struct Watcher {
+    vm: Hypervisor,
+    data_sections: Vec<MappedMemory>,
+    panic_functions: Vec<(&'static str, Range<usize>)>,
+    marker_counts: Vec<usize>,
+}
+
+/// Count occurrences of `marker` that are not format strings, i.e. followed by `%s`.
+fn count_marker(haystack: &[u8], marker: &[u8]) -> usize {
+    haystack
+        .windows(marker.len())
+        .enumerate()
+        .filter(|(i, w)| {
+            let next = &haystack[i + marker.len()..];
+            *w == marker && !next.iter().take(3).any(|c| *c == b'%')
+        })
+        .count()
+}
+
+fn count_markers(src: &dyn GuestAccess, sections: &[MappedMemory]) -> Result<Vec<usize>> {
+    let mut counts = vec![0; KMSG_MARKERS.len()];
+    for s in sections {
+        let mut mem = vec![0; s.len];
+        src.read_bytes(s.phys_start.host_addr(), &mut mem)?;
+        for (i, (_, marker)) in KMSG_MARKERS.iter().enumerate() {
+            counts[i] += count_marker(&mem, marker);
+        }
+    }
+    Ok(counts)
+}
+
+impl Watcher {
+    fn new(pid: Pid, symbols: &SymbolSource) -> Result<Watcher> {
+        let vm = try_with!(
+            kvm::hypervisor::get_hypervisor(pid),
+            "cannot get vms for process {}",
+            pid
+        );
+        let stopped = vm.stop_guard()?;
+        let mem = GuestMem::new(&vm)?;
+        let kernel = try_with!(
+            find_kernel(&mem, &vm, symbols.fallbacks()),
+            "could not find kernel"
+        );
+        let symbolizer = symbols.open(&kernel)?;
+        let data_sections = kernel
+            .memory_sections
+            .into_iter()
+            .filter(|s| !s.prot.contains(ProtFlags::PROT_EXEC))
+            .collect::<Vec<_>>();
+        let panic_functions = PANIC_FUNCTIONS
+            .iter()
+            .filter_map(|name| Some((*name, symbolizer.symbol_range(name)?)))
+            .collect::<Vec<_>>();
+        if panic_functions.is_empty() {
+            warn!("panic functions not found in kernel symbols, only watching kernel log");
+        }
+        let marker_counts = count_markers(&vm, &data_sections)?;
+        drop(stopped);
+        Ok(Watcher {
+            vm,
+            data_sections,
+            panic_functions,
+            marker_counts,
+        })
+    }
+
+    fn check_vcpus(&self) -> Result<Option<Event>> {
+        if self.panic_functions.is_empty() {
+            return Ok(None);
+        }
+        let _stopped = self.vm.stop_guard()?;
+        self.vm
+            .vcpus
+            .iter()
+            .find_map(|vcpu| {
+                let rip = match self.vm.get_regs(vcpu) {
+                    Ok(regs) => regs.rip as usize,
+                    Err(e) => return Some(Err(e)),
+                };
+                let (name, _) = self
+                    .panic_functions
+                    .iter()
+                    .find(|(_, range)| range.contains(&rip))?;
+                Some(Ok(Event {
+                    kind: "panic",
+                    description: format!("vcpu {} executes {} (rip: {:#x})", vcpu.idx, name, rip),
+                }))
+            })
+            .transpose()
+    }
+
+    fn check_kmsg(&mut self) -> Result<Option<Event>> {
+        let counts = count_markers(&self.vm, &self.data_sections)?;
+        for (i, (kind, marker)) in KMSG_MARKERS.iter().enumerate() {
+            if counts[i] > self.marker_counts[i] {
+                self.marker_counts = counts;
+                return Ok(Some(Event {
+                    kind: *kind,
+                    description: format!(
+                        "guest kernel logged '{}'",
+                        String::from_utf8_lossy(marker)
+                    ),
+                }));
+            }
+        }
+        self.marker_counts = counts;
+        Ok(None)
+    }
+
+    fn check(&mut self) -> Result<Option<Event>> {
+        if let Some(event) = self.check_vcpus()? {
+            return Ok(Some(event));
+        }
+        self.check_kmsg()
+    }
+}
+
+fn notify_webhook(url: &str, pid: Pid, event: &Event) -> Result<()> {
+    let body = format!(
+        "{{\"pid\": {}, \"event\": \"{}\", \"description\": \"{}\"}}",
+        pid,
+        event.kind,
+        json_escape(&event.description)
+    );
+    let status = try_with!(
+        Command::new("curl")
+            .args(&["-fsS", "-X", "POST", "-H", "Content-Type: application/json"])
+            .arg("--data")
+            .arg(&body)
+            .arg(url)
+            .status(),
+        "cannot run curl"
+    );
+    if !status.success() {
+        bail!("curl failed to notify {}: {}", url, status);
+    }
+    Ok(())
+}
+
+fn run_action(
+    watcher: &Watcher,
+    opts: &WatchOptions,
+    action: &Action,
+    event: &Event,
+) -> Result<()> {
+    let pid = opts.pid;
+    match action {
+        Action::Coredump(path) => {
+            info!("write coredump to {}", path.display());
+            let opts = CoredumpOptions {
+                pid,
+                path: path.clone(),
+                skip_swapped: false,
+                dedup_store: None,
+                encrypt_to: None,
+                scrub: None,
+                adaptive: false,
+                max_host_pressure: None,
+                // show which vcpu was stuck in which panic function
+                symbols: Some(opts.symbols.clone()),
+                format: CoreFormat::Elf,
+            };
+            coredump::generate_coredump(&opts)
+        }
+        Action::Nmi => {
+            info!("inject nmi into all vcpus");
+            let _stopped = watcher.vm.stop_guard()?;
+            watcher
+                .vm
+                .vcpus
+                .iter()
+                .try_for_each(|vcpu| watcher.vm.nmi(vcpu))
+        }
+        Action::Webhook(url) => {
+            info!("notify {}", url);
+            notify_webhook(url, pid, event)
+        }
+    }
+}
+
+/// Watch the VM until a panic or oom is detected and run all configured actions.
+pub fn watch(opts: &WatchOptions) -> Result<Event> {
+    let mut watcher = Watcher::new(opts.pid, &opts.symbols)?;
+    info!(
+        "watching vm of process {} every {:?}",
+        opts.pid, opts.interval
+    );
+    let event = loop {
+        thread::sleep(opts.interval);
+        if let Some(event) = watcher.check()? {
+            break event;
+        }
+    };
+    warn!("detected guest {}: {}", event.kind, event.description);
+
+    for action in &opts.actions {
+        if let Err(e) = run_action(&watcher, opts, action, &event) {
+            warn!("action failed: {}", e);
+        }
+    }
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_marker_skips_format_strings() {
+        let mem = b"Kernel panic - not syncing: %s\n\0Kernel panic - not syncing: VFS";
+        assert_eq!(count_marker(mem, b"Kernel panic - not syncing"), 1);
+    }
+}
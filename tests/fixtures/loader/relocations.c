@@ -0,0 +1,25 @@
+/* Fixture for the relocation tests in src/loader.rs. It uses each relocation type that the
+ * loader supports for stage1. Rebuild with:
+ *
+ *   gcc -O2 -fPIE -fno-asynchronous-unwind-tables -c relocations.c -o relocations.o
+ *   gcc -O2 -fPIC -shared -nostdlib -s -fno-asynchronous-unwind-tables relocations.c -o relocations.so
+ */
+extern int printk(const char *fmt, ...);
+extern int kernel_value;
+
+static int fast(void) { return 1; }
+static int slow(void) { return 2; }
+/* R_X86_64_IRELATIVE */
+static int (*pick(void))(void) { return fast; }
+static int choose(void) __attribute__((ifunc("pick")));
+
+/* R_X86_64_64, R_X86_64_RELATIVE in the shared object */
+void *table[] = { (void *)printk, (void *)slow };
+
+int run(void)
+{
+	/* R_X86_64_PLT32 in the object, R_X86_64_JUMP_SLOT for printk in the shared object */
+	printk("%d\n", choose());
+	/* R_X86_64_PC32 in the object, R_X86_64_GLOB_DAT in the shared object */
+	return kernel_value;
+}
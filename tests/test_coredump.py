@@ -49,3 +49,21 @@ def test_coredump(helpers: conftest.Helpers) -> None:
         helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
         with open(core_path, "rb") as fd:
             check_coredump(fd, qemu_regs, vm)
+
+
+def test_verify_core(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
+        assert os.path.exists(f"{core_path}.manifest")
+        helpers.run_vmsh_command(["verify-core", core_path])
+
+        # flip a byte in the memory of the guest
+        with open(core_path, "r+b") as fd:
+            fd.seek(-1, os.SEEK_END)
+            byte = fd.read(1)
+            fd.seek(-1, os.SEEK_END)
+            fd.write(bytes([byte[0] ^ 0xFF]))
+        proc = helpers.spawn_vmsh_command(["verify-core", core_path])
+        assert proc.wait() != 0
@@ -1,3 +1,6 @@
+import os
+from tempfile import TemporaryDirectory
+
 import conftest
 
 
@@ -14,3 +17,37 @@ def test_inspect(helpers: conftest.Helpers) -> None:
                 found = True
                 break
         assert found, "could not find kernel"
+
+
+def test_inspect_coredump(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(
+        helpers.notos_image()
+    ) as vm:
+        vm.wait_for_ssh()
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
+        proc = helpers.run_vmsh_command(["inspect", "--core", core_path])
+        found = False
+        while not proc.lines.empty():
+            line = proc.lines.get()
+            if isinstance(line, int):
+                break
+            if "found kernel at" in line:
+                found = True
+                break
+        assert found, "could not find kernel in coredump"
+
+
+def test_memreport(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        proc = helpers.run_vmsh_command(["memreport", str(vm.pid)])
+        found = False
+        while not proc.lines.empty():
+            line = proc.lines.get()
+            if isinstance(line, int):
+                break
+            if "guest physical" in line:
+                found = True
+                break
+        assert found, "no memory report printed"